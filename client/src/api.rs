@@ -0,0 +1,525 @@
+//! HTTP client for the server's sync API
+//!
+//! Requests and responses are the protocol crate's types; failures come back
+//! as [`ClientError::Api`] carrying the server's structured error when it
+//! sent one.
+
+use crate::error::ClientError;
+use protocol::errors::{ApiError, ErrorResponse};
+use protocol::file::{CreateShareRequest, ShareLink};
+use protocol::sync::{
+    AddFolderKeysRequest, CreateEncryptedFolderRequest, Device, DeviceKey, EncryptedFolder,
+    FileState, RecoveredFolderKey, RecoveryKey, RegisterDeviceRequest, RegisterDeviceResponse,
+    RestoreFileRequest, SetRecoveryKeyRequest, SyncRequest, SyncResponse, UpdateDeviceRequest,
+};
+use reqwest::header::{CONTENT_RANGE, ETAG, RANGE};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sync_core::FileSignature;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+struct LoginRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    token: Option<String>,
+    message: String,
+}
+
+/// Block signature of a stored version of a file.
+#[derive(Debug, Deserialize)]
+pub struct RemoteSignature {
+    pub path: String,
+    pub version: u64,
+    pub signature: FileSignature,
+}
+
+/// A download response and where its body starts in the file.
+pub struct Download {
+    pub response: Response,
+    pub offset: u64,
+    /// Checksum of the whole file, from the `ETag`.
+    pub checksum: Option<String>,
+    pub version: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct ApiClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(server_url: &str) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("rouillecloud-client/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Self {
+            http,
+            base_url: format!("{}/api/v1", server_url.trim_end_matches('/')),
+            token: None,
+        })
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Turn an error status into [`ClientError::Api`], reading the server's
+    /// structured error or its `{"error": "..."}` body.
+    async fn check(response: Response) -> Result<Response, ClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        if let Ok(parsed) = serde_json::from_str::<ErrorResponse>(&body) {
+            return Err(ClientError::Api {
+                status,
+                error: Some(parsed.error),
+                message: parsed.message,
+            });
+        }
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|value| {
+                let error = value.get("error").or(value.get("message"))?;
+                error.as_str().map(str::to_string)
+            })
+            .unwrap_or(body);
+        Err(ClientError::Api {
+            status,
+            error: None,
+            message,
+        })
+    }
+
+    /// Exchange credentials for an account token.
+    pub async fn login(&self, username: &str, password: &str) -> Result<String, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/login")
+            .json(&LoginRequest { username, password })
+            .send()
+            .await?;
+        let response: LoginResponse = Self::check(response).await?.json().await?;
+        response.token.ok_or(ClientError::Api {
+            status: StatusCode::UNAUTHORIZED,
+            error: Some(ApiError::InvalidCredentials),
+            message: response.message,
+        })
+    }
+
+    pub async fn register_device(
+        &self,
+        request: &RegisterDeviceRequest,
+    ) -> Result<RegisterDeviceResponse, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/devices")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn update_device(
+        &self,
+        device_id: Uuid,
+        request: &UpdateDeviceRequest,
+    ) -> Result<Device, ClientError> {
+        let response = self
+            .request(reqwest::Method::PATCH, &format!("/devices/{}", device_id))
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// The account's devices, revoked ones included.
+    pub async fn devices(&self) -> Result<Vec<Device>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/devices")
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Tell the server this device deleted its synced files.
+    pub async fn confirm_wipe(&self, device_id: Uuid) -> Result<(), ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/devices/{}/wipe/confirm", device_id),
+            )
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    pub async fn sync(&self, request: &SyncRequest) -> Result<SyncResponse, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/sync")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Signature of the latest stored version of `path`, or `None` when the
+    /// server has no content for it.
+    pub async fn signature(&self, path: &str) -> Result<Option<RemoteSignature>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/files/signature")
+            .query(&[("path", path)])
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(Self::check(response).await?.json().await?))
+    }
+
+    /// Start downloading `version` of `path`, or its latest version, from
+    /// byte `offset`. The server may ignore the range, in which case the
+    /// returned offset is 0.
+    pub async fn download(
+        &self,
+        path: &str,
+        version: Option<u64>,
+        offset: u64,
+    ) -> Result<Download, ClientError> {
+        let mut query = vec![("path", path.to_string())];
+        if let Some(version) = version {
+            query.push(("version", version.to_string()));
+        }
+        let mut request = self
+            .request(reqwest::Method::GET, "/sync/files/content")
+            .query(&query);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = Self::check(request.send().await?).await?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let offset = if response.status() == StatusCode::PARTIAL_CONTENT {
+            header(CONTENT_RANGE)
+                .and_then(|value| value.strip_prefix("bytes "))
+                .and_then(|value| value.split('-').next())
+                .and_then(|start| start.parse().ok())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let checksum = header(ETAG).map(|etag| etag.trim_matches('"').to_string());
+        let version = header(reqwest::header::HeaderName::from_static("x-file-version"))
+            .and_then(|value| value.parse().ok());
+        Ok(Download {
+            response,
+            offset,
+            checksum,
+            version,
+        })
+    }
+
+    /// The first `len` bytes of the latest version of `path`, with the
+    /// checksum of the whole file.
+    pub async fn read_prefix(
+        &self,
+        path: &str,
+        len: usize,
+    ) -> Result<(Vec<u8>, Option<String>), ClientError> {
+        let request = self
+            .request(reqwest::Method::GET, "/sync/files/content")
+            .query(&[("path", path)])
+            .header(RANGE, format!("bytes=0-{}", len.saturating_sub(1)));
+        let mut response = Self::check(request.send().await?).await?;
+        let checksum = response
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(|etag| etag.trim_matches('"').to_string());
+        // The server may send the whole file; stop reading once enough came.
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let Some(chunk) = response.chunk().await? else {
+                break;
+            };
+            data.extend_from_slice(&chunk);
+        }
+        data.truncate(len);
+        Ok((data, checksum))
+    }
+
+    /// Upload the whole content of a file, replacing `base_version`.
+    pub async fn upload(
+        &self,
+        device_id: Uuid,
+        path: &str,
+        base_version: Option<u64>,
+        content: impl Into<reqwest::Body>,
+    ) -> Result<FileState, ClientError> {
+        let mut query = vec![
+            ("device_id", device_id.to_string()),
+            ("path", path.to_string()),
+        ];
+        if let Some(base) = base_version {
+            query.push(("base_version", base.to_string()));
+        }
+        let response = self
+            .request(reqwest::Method::PUT, "/sync/files/content")
+            .query(&query)
+            .body(content)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Upload a binary encoded delta against `base_version`.
+    pub async fn upload_delta(
+        &self,
+        device_id: Uuid,
+        path: &str,
+        base_version: u64,
+        encoded: impl Into<reqwest::Body>,
+    ) -> Result<FileState, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/sync/files/delta/binary")
+            .query(&[
+                ("device_id", device_id.to_string()),
+                ("path", path.to_string()),
+                ("base_version", base_version.to_string()),
+            ])
+            .body(encoded)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Live files at or under `path`, or all of them.
+    pub async fn list_files(&self, path: Option<&str>) -> Result<Vec<FileState>, ClientError> {
+        let mut request = self.request(reqwest::Method::GET, "/sync/files");
+        if let Some(path) = path {
+            request = request.query(&[("path", path)]);
+        }
+        Ok(Self::check(request.send().await?).await?.json().await?)
+    }
+
+    /// Stored versions of a file, latest first.
+    pub async fn versions(&self, path: &str) -> Result<Vec<FileState>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/files/versions")
+            .query(&[("path", path)])
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Deleted files at or under `path`, or all of them.
+    pub async fn trash(&self, path: Option<&str>) -> Result<Vec<FileState>, ClientError> {
+        let mut request = self.request(reqwest::Method::GET, "/sync/trash");
+        if let Some(path) = path {
+            request = request.query(&[("path", path)]);
+        }
+        Ok(Self::check(request.send().await?).await?.json().await?)
+    }
+
+    pub async fn restore(&self, request: &RestoreFileRequest) -> Result<FileState, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/sync/trash/restore")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn create_share(
+        &self,
+        request: &CreateShareRequest,
+    ) -> Result<ShareLink, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/sync/shares")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn shares(&self) -> Result<Vec<ShareLink>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/shares")
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn revoke_share(&self, id: Uuid) -> Result<(), ClientError> {
+        let response = self
+            .request(reqwest::Method::DELETE, &format!("/sync/shares/{}", id))
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    /// Public keys of a user's active devices.
+    pub async fn device_keys(&self, user_id: Uuid) -> Result<Vec<DeviceKey>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/sync/keys/{}", user_id))
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Encrypted folders the account owns or was given.
+    pub async fn encrypted_folders(&self) -> Result<Vec<EncryptedFolder>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/encrypted")
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn create_encrypted_folder(
+        &self,
+        request: &CreateEncryptedFolderRequest,
+    ) -> Result<EncryptedFolder, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/sync/encrypted")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn add_folder_keys(
+        &self,
+        folder_id: Uuid,
+        request: &AddFolderKeysRequest,
+    ) -> Result<(), ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                &format!("/sync/encrypted/{}/keys", folder_id),
+            )
+            .json(request)
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    pub async fn remove_folder_member(
+        &self,
+        folder_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<(), ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::DELETE,
+                &format!("/sync/encrypted/{}/members/{}", folder_id, user_id),
+            )
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    /// Files of an encrypted folder, with encrypted names.
+    pub async fn encrypted_files(&self, folder_id: Uuid) -> Result<Vec<FileState>, ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                &format!("/sync/encrypted/{}/files", folder_id),
+            )
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Encrypted content of a file in an encrypted folder, which may belong
+    /// to another user.
+    pub async fn download_encrypted(
+        &self,
+        folder_id: Uuid,
+        path: &str,
+    ) -> Result<Vec<u8>, ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::GET,
+                &format!("/sync/encrypted/{}/content", folder_id),
+            )
+            .query(&[("path", path)])
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.bytes().await?.to_vec())
+    }
+
+    /// The account's recovery key, if it has one.
+    pub async fn recovery_key(&self) -> Result<Option<RecoveryKey>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/recovery-key")
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(Self::check(response).await?.json().await?))
+    }
+
+    /// Replace the account's recovery key.
+    pub async fn set_recovery_key(
+        &self,
+        request: &SetRecoveryKeyRequest,
+    ) -> Result<RecoveryKey, ClientError> {
+        let response = self
+            .request(reqwest::Method::PUT, "/sync/recovery-key")
+            .json(request)
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn remove_recovery_key(&self) -> Result<(), ClientError> {
+        let response = self
+            .request(reqwest::Method::DELETE, "/sync/recovery-key")
+            .send()
+            .await?;
+        Self::check(response).await?;
+        Ok(())
+    }
+
+    /// Folder keys sealed for the account's recovery key.
+    pub async fn recovery_folder_keys(&self) -> Result<Vec<RecoveredFolderKey>, ClientError> {
+        let response = self
+            .request(reqwest::Method::GET, "/sync/recovery-key/folder-keys")
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    /// Absolute URL of a server-relative path, such as a share link's.
+    pub fn url(&self, path: &str) -> String {
+        let origin = self.base_url.trim_end_matches("/api/v1");
+        format!("{}{}", origin, path)
+    }
+}
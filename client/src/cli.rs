@@ -0,0 +1,1536 @@
+//! Command-line interface
+//!
+//! Every command but `login` runs as the device registered in the client's
+//! database, the same one the sync daemon uses. Remote paths are relative
+//! to the user's root. Directories are implicit: a path is a directory when
+//! files live under it, and `mkdir` creates an empty hidden placeholder file
+//! so a directory can exist before anything is put in it.
+//!
+//! `mount` shows the remote files as a directory whose contents are
+//! fetched when opened, and `pin` keeps some of them cached for offline
+//! use; see [`fileshare_client::vfs`].
+//!
+//! `encrypted` turns an empty directory into an end-to-end encrypted folder
+//! that the sync daemon encrypts and decrypts, shares it with other users,
+//! and reads folders shared by them; see [`fileshare_client::e2ee`]. A
+//! folder key is only given to a device, the account's own or another
+//! user's, after its key fingerprint was confirmed.
+//! `recovery` sets up a recovery passphrase, with which a new device gets
+//! the keys of those folders back once every other device is lost. Only
+//! the device that created the recovery key seals folder keys for it
+//! unasked; the others approve it by its fingerprint like a device.
+//!
+//! With `--json`, results are printed as JSON on stdout and errors as JSON
+//! on stderr. Exit codes follow `sysexits.h`, see [`ClientError::exit_code`].
+
+use chrono::{DateTime, Duration, Utc};
+use clap::{Args, Parser, Subcommand};
+use crypto::{DeviceKeyPair, EncryptionKey, FileHasher, PasswordKdfCost, PasswordKeyParams};
+use fileshare_client::api::ApiClient;
+use fileshare_client::db::{LocalDb, SETTING_DEVICE_ID, SETTING_RECOVERY_KEY, SETTING_SYNC_TOKEN};
+use fileshare_client::e2ee::{self, EncryptedFolders, FolderKey};
+use fileshare_client::transfer::{apply_pushed, remote_path, upload_file};
+use fileshare_client::vfs::DIRECTORY_PLACEHOLDER;
+use fileshare_client::{ClientConfig, ClientError, Daemon};
+use protocol::file::{CreateShareRequest, ShareLink};
+use protocol::sync::{
+    AddFolderKeysRequest, ChangeType, CreateEncryptedFolderRequest, DeviceKey, FileChange,
+    FileState, RestoreFileRequest, SetRecoveryKeyRequest, SyncRequest,
+};
+use serde::Serialize;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Shortest recovery passphrase accepted; Argon2id slows down guessing it
+/// but cannot make up for a short one.
+const MIN_RECOVERY_PASSPHRASE_LEN: usize = 12;
+
+#[derive(Parser)]
+#[command(name = "rouillecloud", version, about = "rouillecloud sync client")]
+pub struct Cli {
+    /// Configuration file; defaults to client.toml in the user's
+    /// configuration directory.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Server URL, overriding the configuration.
+    #[arg(long, global = true)]
+    pub server: Option<String>,
+    /// Print results and errors as JSON.
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Register this machine as a device of your account.
+    Login {
+        #[arg(long)]
+        username: Option<String>,
+        /// Read the password from the first line of stdin.
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// List the files at or under a path.
+    Ls { path: Option<String> },
+    /// Download a file; `-` writes it to stdout.
+    Get {
+        remote: String,
+        local: Option<PathBuf>,
+        /// Version to download instead of the latest.
+        #[arg(long)]
+        version: Option<u64>,
+    },
+    /// Upload a file; a remote path ending in `/` is a directory.
+    Put {
+        local: PathBuf,
+        remote: Option<String>,
+    },
+    /// Create a directory.
+    Mkdir { path: String },
+    /// Move or rename a file or directory.
+    Mv { from: String, to: String },
+    /// Delete a file, or a directory with `-r`; deleted files go to the
+    /// trash.
+    Rm {
+        path: String,
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Manage public links to files.
+    #[command(subcommand)]
+    Share(ShareCommand),
+    /// List the stored versions of a file.
+    Versions { path: String },
+    /// List or restore deleted files.
+    #[command(subcommand)]
+    Trash(TrashCommand),
+    /// Run the configured folder sync.
+    #[command(subcommand)]
+    Sync(SyncCommand),
+    /// Show the remote files in a directory, fetching contents as they are
+    /// opened, until interrupted.
+    #[cfg(target_os = "linux")]
+    Mount {
+        /// Defaults to the configured mount point.
+        mount_point: Option<PathBuf>,
+    },
+    /// Keep files cached for offline use in virtual files.
+    #[command(subcommand)]
+    Pin(PinCommand),
+    /// Manage end-to-end encrypted folders.
+    #[command(subcommand)]
+    Encrypted(EncryptedCommand),
+    /// Manage the recovery key of encrypted folders.
+    #[command(subcommand)]
+    Recovery(RecoveryCommand),
+}
+
+#[derive(Subcommand)]
+pub enum ShareCommand {
+    /// Publish a file through a new link.
+    Create(ShareOptions),
+    /// List active links.
+    List,
+    /// Revoke a link.
+    Revoke { id: uuid::Uuid },
+}
+
+#[derive(Args)]
+pub struct ShareOptions {
+    path: String,
+    /// Expiry, as an RFC 3339 date or a duration such as `12h` or `7d`.
+    #[arg(long)]
+    expires: Option<String>,
+    /// Ask for a password the link will require.
+    #[arg(long)]
+    password: bool,
+    #[arg(long)]
+    max_downloads: Option<u64>,
+}
+
+#[derive(Subcommand)]
+pub enum TrashCommand {
+    /// List deleted files at or under a path.
+    List { path: Option<String> },
+    /// Bring a deleted file back.
+    Restore { path: String },
+}
+
+#[derive(Subcommand)]
+pub enum PinCommand {
+    /// Fetch a file or directory and keep it cached.
+    Add { path: String },
+    /// Let a file or directory leave the cache again.
+    Remove { path: String },
+    /// List pinned paths.
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum EncryptedCommand {
+    /// Make a directory without files an encrypted folder.
+    Create { path: String },
+    /// List the encrypted folders you own or were given.
+    List,
+    /// Give the key to one of your folders to another user's devices.
+    Share {
+        path: String,
+        user_id: uuid::Uuid,
+        /// Key fingerprint of a device to share with, as `encrypted
+        /// fingerprint` shows it there; asked for each device otherwise.
+        #[arg(long = "fingerprint")]
+        fingerprints: Vec<String>,
+    },
+    /// Stop sharing one of your folders with a user.
+    Unshare { path: String, user_id: uuid::Uuid },
+    /// List the files of an encrypted folder, given by path or id.
+    Ls { folder: String },
+    /// Download and decrypt a file of an encrypted folder; `-` writes it to
+    /// stdout.
+    Get {
+        folder: String,
+        /// Path of the file in the folder.
+        path: String,
+        local: Option<PathBuf>,
+    },
+    /// Show this device's key fingerprint, to compare when approving it or
+    /// sharing with it from another device.
+    Fingerprint,
+    /// List the account's devices waiting for the keys this device holds.
+    Pending,
+    /// Give the keys this device holds to another device of the account,
+    /// once its fingerprint is confirmed.
+    Approve {
+        device_id: uuid::Uuid,
+        /// The fingerprint the device shows; asked for otherwise.
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RecoveryCommand {
+    /// Create a recovery key from a passphrase, replacing any earlier one.
+    Create {
+        /// Read the passphrase from stdin instead of prompting.
+        #[arg(long)]
+        passphrase_stdin: bool,
+    },
+    /// Show whether the account has a recovery key.
+    Show,
+    /// Give this device the keys of the encrypted folders the recovery key
+    /// holds.
+    Restore {
+        /// Read the passphrase, or the recovery code of an older recovery
+        /// key, from stdin instead of prompting.
+        #[arg(long)]
+        passphrase_stdin: bool,
+    },
+    /// Remove the recovery key.
+    Remove,
+}
+
+#[derive(Subcommand)]
+pub enum SyncCommand {
+    /// Sync every folder once.
+    Once,
+    /// Sync continuously, on file changes and periodically.
+    Watch,
+}
+
+/// Prints results as text or JSON.
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn emit<T: Serialize>(&self, value: &T, text: impl FnOnce(&T)) -> Result<(), ClientError> {
+        self.emit_to(&mut std::io::stdout().lock(), value, text)
+    }
+
+    fn emit_to<T: Serialize>(
+        &self,
+        out: &mut impl Write,
+        value: &T,
+        text: impl FnOnce(&T),
+    ) -> Result<(), ClientError> {
+        if self.json {
+            writeln!(out, "{}", serde_json::to_string_pretty(value)?)?;
+        } else {
+            text(value);
+        }
+        Ok(())
+    }
+}
+
+/// A failure as printed with `--json`.
+fn error_json(error: &ClientError) -> serde_json::Value {
+    serde_json::json!({
+        "error": error.api_error(),
+        "status": error.status().map(|status| status.as_u16()),
+        "message": error.to_string(),
+        "exit_code": error.exit_code(),
+    })
+}
+
+/// Print a command's failure on stderr.
+pub fn report(error: &ClientError, json: bool) {
+    if json {
+        eprintln!("{}", error_json(error));
+    } else {
+        eprintln!("error: {}", error);
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+fn print_files(files: &[FileState]) {
+    for file in files {
+        println!(
+            "{:>12}  {}  {}",
+            file.size,
+            file.modified_at.format("%Y-%m-%d %H:%M"),
+            file.path
+        );
+    }
+}
+
+fn print_link(link: &ShareLink) {
+    let mut limits = Vec::new();
+    if let Some(expires) = link.expires_at {
+        limits.push(format!("expires {}", expires.format("%Y-%m-%d %H:%M")));
+    }
+    if let Some(max) = link.max_downloads {
+        limits.push(format!("{}/{} downloads", link.download_count, max));
+    }
+    if link.password_protected {
+        limits.push("password".to_string());
+    }
+    if !link.is_active {
+        limits.push("inactive".to_string());
+    }
+    println!("{}  {}  {}", link.id, link.path, link.url);
+    if !limits.is_empty() {
+        println!("    {}", limits.join(", "));
+    }
+}
+
+/// An RFC 3339 date, or a number of minutes, hours, days or weeks from now.
+fn parse_expiry(value: &str) -> Result<DateTime<Utc>, ClientError> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    let invalid = || ClientError::Usage(format!("invalid expiry: {}", value));
+    let (count, unit) = value.split_at(value.trim_end_matches(char::is_alphabetic).len());
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let duration = match unit {
+        "m" => Duration::try_minutes(count),
+        "h" => Duration::try_hours(count),
+        "d" => Duration::try_days(count),
+        "w" => Duration::try_weeks(count),
+        _ => None,
+    };
+    duration
+        .and_then(|duration| Utc::now().checked_add_signed(duration))
+        .ok_or_else(invalid)
+}
+
+fn read_stdin_line() -> Result<String, ClientError> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Ask a yes or no question on the terminal; no unless answered yes.
+fn confirm(question: &str) -> Result<bool, ClientError> {
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    Ok(matches!(
+        read_stdin_line()?.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Check a device's key fingerprint against `expected`, or have the user
+/// confirm it, before a folder key is sealed for the device. `recovery`
+/// when the device is the account's recovery key.
+fn check_fingerprint(
+    device: &DeviceKey,
+    expected: Option<&str>,
+    recovery: bool,
+) -> Result<String, ClientError> {
+    let fingerprint = e2ee::fingerprint(device)?;
+    let confirmed = match expected {
+        Some(expected) => expected.trim().eq_ignore_ascii_case(&fingerprint),
+        None if recovery => confirm(&format!(
+            "recovery key {} has the key fingerprint\n  {}\nis it the one `recovery create` showed?",
+            device.device_id, fingerprint
+        ))?,
+        None => confirm(&format!(
+            "device {} has the key fingerprint\n  {}\nis it the one `encrypted fingerprint` shows there?",
+            device.device_id, fingerprint
+        ))?,
+    };
+    if !confirmed {
+        return Err(ClientError::Usage(format!(
+            "the key fingerprint of device {} was not confirmed",
+            device.device_id
+        )));
+    }
+    Ok(fingerprint)
+}
+
+fn load_config(cli: &Cli) -> Result<ClientConfig, ClientError> {
+    let path = cli
+        .config
+        .clone()
+        .unwrap_or_else(ClientConfig::default_path);
+    ClientConfig::load_with_server(&path, cli.server.as_deref())
+}
+
+pub async fn run(cli: Cli) -> Result<(), ClientError> {
+    let out = Output { json: cli.json };
+    let mut config = load_config(&cli)?;
+    let command = match cli.command {
+        Command::Login {
+            username,
+            password_stdin,
+        } => return login(&mut config, username, password_stdin, &out).await,
+        command => command,
+    };
+
+    let daemon = Daemon::start(config).await?;
+    let session = Session {
+        daemon: &daemon,
+        out: &out,
+    };
+    match command {
+        Command::Login { .. } => Ok(()),
+        Command::Ls { path } => session.ls(path).await,
+        Command::Get {
+            remote,
+            local,
+            version,
+        } => session.get(&remote, local, version).await,
+        Command::Put { local, remote } => session.put(&local, remote).await,
+        Command::Mkdir { path } => session.mkdir(&path).await,
+        Command::Mv { from, to } => session.mv(&from, &to).await,
+        Command::Rm { path, recursive } => session.rm(&path, recursive).await,
+        Command::Share(command) => session.share(command).await,
+        Command::Versions { path } => {
+            let versions = session.api().versions(&normalize(&path)).await?;
+            out.emit(&versions, |versions| {
+                for version in versions {
+                    println!(
+                        "{:>6}  {:>12}  {}  {}",
+                        version.version,
+                        version.size,
+                        version.modified_at.format("%Y-%m-%d %H:%M"),
+                        version.checksum
+                    );
+                }
+            })
+        }
+        Command::Trash(TrashCommand::List { path }) => {
+            let path = path.map(|path| normalize(&path));
+            let files = session.api().trash(path.as_deref()).await?;
+            out.emit(&files, |files| print_files(files))
+        }
+        Command::Trash(TrashCommand::Restore { path }) => {
+            let state = session
+                .api()
+                .restore(&RestoreFileRequest {
+                    device_id: daemon.device_id(),
+                    path: normalize(&path),
+                })
+                .await?;
+            daemon.db().set_remote(&state)?;
+            out.emit(&state, |state| println!("restored {}", state.path))
+        }
+        Command::Sync(SyncCommand::Once) => {
+            let count = daemon.sync_once().await?;
+            out.emit(&serde_json::json!({ "actions": count }), |_| {
+                println!("sync complete: {} actions", count)
+            })
+        }
+        Command::Sync(SyncCommand::Watch) => daemon.run().await,
+        #[cfg(target_os = "linux")]
+        Command::Mount { mount_point } => session.mount(mount_point).await,
+        Command::Pin(command) => session.pin(command).await,
+        Command::Encrypted(command) => session.encrypted(command).await,
+        Command::Recovery(command) => session.recovery(command).await,
+    }
+}
+
+async fn login(
+    config: &mut ClientConfig,
+    username: Option<String>,
+    password_stdin: bool,
+    out: &Output,
+) -> Result<(), ClientError> {
+    let db = LocalDb::open(&config.database_path())?;
+    if let Some(device_id) = db.setting(SETTING_DEVICE_ID)? {
+        return Err(ClientError::Usage(format!(
+            "already registered as device {}; delete {} to register again",
+            device_id,
+            config.database_path().display()
+        )));
+    }
+    if let Some(username) = username {
+        config.username = Some(username);
+    }
+    if config.token.is_none() {
+        if config.username.is_none() {
+            eprint!("Username: ");
+            std::io::stderr().flush()?;
+            config.username = Some(read_stdin_line()?);
+        }
+        if password_stdin {
+            config.password = Some(read_stdin_line()?);
+        } else if config.password.is_none() {
+            config.password = Some(rpassword::prompt_password("Password: ")?);
+        }
+    }
+    let (device_id, _) = Daemon::register(config, &db).await?;
+    out.emit(&serde_json::json!({ "device_id": device_id }), |_| {
+        println!("registered as device {}", device_id)
+    })
+}
+
+/// A device waiting for the key of a folder, as `encrypted pending` shows
+/// it.
+#[derive(Serialize)]
+struct PendingDevice {
+    device_id: uuid::Uuid,
+    fingerprint: String,
+    folder: String,
+    /// The account's recovery key rather than a device.
+    recovery: bool,
+}
+
+/// A file of an encrypted folder, as `encrypted ls` shows it.
+#[derive(Serialize)]
+struct EncryptedEntry {
+    path: String,
+    /// Size of the plaintext, when this device knows it.
+    size: Option<u64>,
+    modified_at: DateTime<Utc>,
+    version: u64,
+}
+
+/// The folder named by `name`, an id or a path, that this device can
+/// decrypt; the account's own folders come first.
+fn find_folder<'a>(
+    folders: &'a EncryptedFolders,
+    name: &str,
+    user_id: uuid::Uuid,
+) -> Result<&'a FolderKey, ClientError> {
+    let path = normalize(name);
+    let mut matching: Vec<&FolderKey> = folders
+        .folders()
+        .iter()
+        .filter(|folder| folder.id.to_string() == name || folder.path == path)
+        .collect();
+    matching.sort_by_key(|folder| folder.owner_id != user_id);
+    matching.first().copied().ok_or_else(|| {
+        ClientError::NotFound(format!(
+            "no encrypted folder {} this device has the key to",
+            name
+        ))
+    })
+}
+
+/// A registered device running one command.
+struct Session<'a> {
+    daemon: &'a Daemon,
+    out: &'a Output,
+}
+
+impl Session<'_> {
+    fn api(&self) -> &ApiClient {
+        self.daemon.api()
+    }
+
+    /// This device's public key, as the server lists it.
+    fn device_key(&self) -> DeviceKey {
+        DeviceKey {
+            device_id: self.daemon.device_id(),
+            user_id: self.daemon.user_id(),
+            public_key: crypto::to_base64(&self.daemon.device_keys().public_key()),
+        }
+    }
+
+    /// The account's recovery key, and whether it was created on this
+    /// device, which then seals folder keys for it without asking.
+    async fn recovery_key(&self) -> Result<Option<(DeviceKey, bool)>, ClientError> {
+        let Some(recovery) = self.api().recovery_key().await? else {
+            return Ok(None);
+        };
+        let created_here = self.daemon.db().setting(SETTING_RECOVERY_KEY)?.as_deref()
+            == Some(recovery.public_key.as_str());
+        let key = DeviceKey {
+            device_id: recovery.id,
+            user_id: self.daemon.user_id(),
+            public_key: recovery.public_key,
+        };
+        Ok(Some((key, created_here)))
+    }
+
+    /// Files at or under `path`; an error when there are none.
+    async fn existing(&self, path: &str) -> Result<Vec<FileState>, ClientError> {
+        let files = self.api().list_files(Some(path)).await?;
+        if files.is_empty() {
+            return Err(ClientError::NotFound(path.to_string()));
+        }
+        Ok(files)
+    }
+
+    /// Push changes made by this command after catching up with the other
+    /// devices, so only real conflicts are reported.
+    async fn push(&self, changes: Vec<FileChange>) -> Result<(), ClientError> {
+        self.daemon.pull().await?;
+        let db = self.daemon.db();
+        let response = self
+            .api()
+            .sync(&SyncRequest {
+                device_id: self.daemon.device_id(),
+                last_sync_token: db.setting(SETTING_SYNC_TOKEN)?,
+                changes,
+            })
+            .await?;
+        apply_pushed(db, &response)
+    }
+
+    async fn ls(&self, path: Option<String>) -> Result<(), ClientError> {
+        let path = path.map(|path| normalize(&path)).filter(|p| !p.is_empty());
+        let files = self.api().list_files(path.as_deref()).await?;
+        let files: Vec<FileState> = files
+            .into_iter()
+            .filter(|file| file.path.rsplit('/').next() != Some(DIRECTORY_PLACEHOLDER))
+            .collect();
+        self.out.emit(&files, |files| print_files(files))
+    }
+
+    async fn get(
+        &self,
+        remote: &str,
+        local: Option<PathBuf>,
+        version: Option<u64>,
+    ) -> Result<(), ClientError> {
+        let remote = normalize(remote);
+        let name = remote.rsplit('/').next().unwrap_or(&remote).to_string();
+        let mut download = self.api().download(&remote, version, 0).await?;
+        let mut hasher = FileHasher::new();
+
+        let to_stdout = local.as_deref() == Some(Path::new("-"));
+        let (target, partial) = if to_stdout {
+            (None, None)
+        } else {
+            let target = match local {
+                Some(local) if local.is_dir() => local.join(&name),
+                Some(local) => local,
+                None => PathBuf::from(&name),
+            };
+            let mut partial = target.clone().into_os_string();
+            partial.push(".part");
+            (Some(target), Some(PathBuf::from(partial)))
+        };
+        let mut sink: Box<dyn Write> = match &partial {
+            Some(partial) => Box::new(fs::File::create(partial)?),
+            None => Box::new(std::io::stdout().lock()),
+        };
+        let mut size = 0u64;
+        let written: Result<(), ClientError> = async {
+            while let Some(chunk) = download.response.chunk().await? {
+                hasher.update(&chunk);
+                sink.write_all(&chunk)?;
+                size += chunk.len() as u64;
+            }
+            sink.flush()?;
+            Ok(())
+        }
+        .await;
+        drop(sink);
+        let checksum = hasher.finalize();
+        let verified = written.and_then(|()| match &download.checksum {
+            Some(expected) if *expected != checksum => {
+                Err(ClientError::Sync(sync_core::SyncError::ChecksumMismatch))
+            }
+            _ => Ok(()),
+        });
+        if let Err(e) = verified {
+            if let Some(partial) = &partial {
+                let _ = fs::remove_file(partial);
+            }
+            return Err(e);
+        }
+        if let (Some(target), Some(partial)) = (&target, &partial) {
+            fs::rename(partial, target)?;
+        }
+        if to_stdout {
+            return Ok(());
+        }
+        let result = serde_json::json!({
+            "path": remote,
+            "local_path": target,
+            "version": download.version,
+            "checksum": checksum,
+            "size": size,
+        });
+        self.out.emit(&result, |_| {
+            if let Some(target) = &target {
+                println!("{} -> {} ({} bytes)", remote, target.display(), size);
+            }
+        })
+    }
+
+    async fn put(&self, local: &Path, remote: Option<String>) -> Result<(), ClientError> {
+        let name = local
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ClientError::Usage(format!("{} is not a file", local.display())))?;
+        let remote = match remote {
+            Some(remote) if remote.ends_with('/') || remote.is_empty() => {
+                normalize(&format!("{}{}", remote, name))
+            }
+            Some(remote) => normalize(&remote),
+            None => name.to_string(),
+        };
+        let metadata = fs::metadata(local)?;
+        if !metadata.is_file() {
+            return Err(ClientError::Usage(format!(
+                "{} is not a file",
+                local.display()
+            )));
+        }
+        let hash = FileHasher::hash_file(local)?;
+        let state = upload_file(
+            self.api(),
+            self.daemon.device_id(),
+            local,
+            &remote,
+            &hash,
+            metadata.len(),
+            true,
+            None,
+        )
+        .await?;
+        self.daemon.db().set_remote(&state)?;
+        self.out.emit(&state, |state| {
+            println!(
+                "{} -> {} (version {})",
+                local.display(),
+                state.path,
+                state.version
+            )
+        })
+    }
+
+    async fn mkdir(&self, path: &str) -> Result<(), ClientError> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(ClientError::Usage("missing directory name".to_string()));
+        }
+        if self.api().list_files(Some(&path)).await?.is_empty() {
+            let state = self
+                .api()
+                .upload(
+                    self.daemon.device_id(),
+                    &format!("{}/{}", path, DIRECTORY_PLACEHOLDER),
+                    None,
+                    Vec::new(),
+                )
+                .await?;
+            self.daemon.db().set_remote(&state)?;
+        }
+        self.out.emit(&serde_json::json!({ "path": path }), |_| {
+            println!("{}/", path)
+        })
+    }
+
+    async fn mv(&self, from: &str, to: &str) -> Result<(), ClientError> {
+        let from = normalize(from);
+        let name = from.rsplit('/').next().unwrap_or(&from);
+        let to = match to {
+            to if to.ends_with('/') || to.is_empty() => normalize(&format!("{}{}", to, name)),
+            to => normalize(to),
+        };
+        if from.is_empty() || to.is_empty() || from == to {
+            return Err(ClientError::Usage("nothing to move".to_string()));
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(ClientError::Usage(format!(
+                "cannot move {} into itself",
+                from
+            )));
+        }
+        let files = self.existing(&from).await?;
+        let moves: Vec<(FileState, String)> = files
+            .into_iter()
+            .map(|file| {
+                let target = format!("{}{}", to, &file.path[from.len()..]);
+                (file, target)
+            })
+            .collect();
+        // Without a checksum the server keeps the content it has.
+        let changes = moves
+            .iter()
+            .map(|(file, target)| FileChange {
+                path: target.clone(),
+                change_type: ChangeType::Moved {
+                    old_path: file.path.clone(),
+                },
+                metadata: None,
+                checksum: None,
+                delta: None,
+            })
+            .collect();
+        self.push(changes).await?;
+        let db = self.daemon.db();
+        for (file, target) in &moves {
+            db.remove_remote(&file.path)?;
+            db.set_remote(&FileState {
+                path: target.clone(),
+                ..file.clone()
+            })?;
+        }
+        let result: Vec<_> = moves
+            .iter()
+            .map(|(file, target)| serde_json::json!({ "from": file.path, "to": target }))
+            .collect();
+        self.out.emit(&result, |_| {
+            for (file, target) in &moves {
+                println!("{} -> {}", file.path, target);
+            }
+        })
+    }
+
+    async fn rm(&self, path: &str, recursive: bool) -> Result<(), ClientError> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(ClientError::Usage(
+                "refusing to delete the root".to_string(),
+            ));
+        }
+        let files = self.existing(&path).await?;
+        let is_file = files.len() == 1 && files[0].path == path;
+        if !is_file && !recursive {
+            return Err(ClientError::Usage(format!(
+                "{} is a directory; use -r to delete it",
+                path
+            )));
+        }
+        let changes = files
+            .iter()
+            .map(|file| FileChange {
+                path: file.path.clone(),
+                change_type: ChangeType::Deleted,
+                metadata: None,
+                checksum: Some(file.checksum.clone()),
+                delta: None,
+            })
+            .collect();
+        self.push(changes).await?;
+        for file in &files {
+            self.daemon.db().remove_remote(&file.path)?;
+        }
+        self.out.emit(&files, |files| {
+            for file in files {
+                println!("deleted {}", file.path);
+            }
+        })
+    }
+
+    /// Serve the virtual files until interrupted or unmounted, pulling
+    /// other devices' changes and fetching pinned files every poll
+    /// interval.
+    #[cfg(target_os = "linux")]
+    async fn mount(&self, mount_point: Option<PathBuf>) -> Result<(), ClientError> {
+        use fileshare_client::fuse::Mount;
+        use fileshare_client::vfs::VirtualFs;
+
+        let config = self.daemon.config();
+        let mount_point = mount_point
+            .or_else(|| config.virtual_files.mount_point.clone())
+            .ok_or_else(|| ClientError::Usage("no mount point given or configured".to_string()))?;
+        self.daemon.pull().await?;
+        let fs = Arc::new(VirtualFs::new(
+            tokio::runtime::Handle::current(),
+            self.api().clone(),
+            self.daemon.db().clone(),
+            self.daemon.device_id(),
+            Arc::new(self.daemon.content_cache()),
+            config.staging_dir(),
+        )?);
+        let mount = Arc::new(Mount::new(&mount_point)?);
+        tracing::info!("mounted on {}", mount.mount_point().display());
+
+        let serving = tokio::task::spawn_blocking({
+            let (mount, fs) = (mount.clone(), fs.clone());
+            move || mount.serve(&fs)
+        });
+        tokio::pin!(serving);
+        let mut poll = tokio::time::interval(std::time::Duration::from_secs(
+            config.poll_interval_secs.max(1),
+        ));
+        loop {
+            tokio::select! {
+                served = &mut serving => {
+                    tracing::info!("unmounted");
+                    return served.map_err(|e| ClientError::Io(std::io::Error::other(e)))?;
+                }
+                _ = poll.tick() => {
+                    let refreshed: Result<usize, ClientError> = async {
+                        self.daemon.pull().await?;
+                        fs.refresh()?;
+                        fs.cache().fetch_pinned(self.api()).await
+                    }
+                    .await;
+                    if let Err(e) = refreshed {
+                        tracing::warn!("refresh failed, retrying later: {}", e);
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("unmounting");
+                    mount.unmount()?;
+                }
+            }
+        }
+    }
+
+    async fn pin(&self, command: PinCommand) -> Result<(), ClientError> {
+        let db = self.daemon.db();
+        let cache = self.daemon.content_cache();
+        match command {
+            PinCommand::Add { path } => {
+                let path = normalize(&path);
+                if path.is_empty() {
+                    return Err(ClientError::Usage(
+                        "pin a file or directory, not the root".to_string(),
+                    ));
+                }
+                self.existing(&path).await?;
+                db.pin(&path)?;
+                self.daemon.pull().await?;
+                let fetched = cache.fetch_pinned(self.api()).await?;
+                self.out.emit(
+                    &serde_json::json!({ "path": path, "fetched": fetched }),
+                    |_| println!("pinned {} ({} files fetched)", path, fetched),
+                )
+            }
+            PinCommand::Remove { path } => {
+                let path = normalize(&path);
+                if !db.unpin(&path)? {
+                    return Err(ClientError::NotFound(format!("{} is not pinned", path)));
+                }
+                cache.trim()?;
+                self.out.emit(&serde_json::json!({ "path": path }), |_| {
+                    println!("unpinned {}", path)
+                })
+            }
+            PinCommand::List => {
+                let pinned = db.pinned()?;
+                self.out.emit(&pinned, |pinned| {
+                    pinned.iter().for_each(|p| println!("{}", p))
+                })
+            }
+        }
+    }
+
+    async fn encrypted(&self, command: EncryptedCommand) -> Result<(), ClientError> {
+        let user_id = self.daemon.user_id();
+        match command {
+            EncryptedCommand::Create { path } => {
+                let path = normalize(&path);
+                if path.is_empty() {
+                    return Err(ClientError::Usage(
+                        "the root cannot be an encrypted folder".to_string(),
+                    ));
+                }
+                // Only this device and the recovery key created on it get
+                // the key; the others wait for the user to approve them.
+                let mut devices = vec![self.device_key()];
+                if let Some((recovery, true)) = self.recovery_key().await? {
+                    devices.push(recovery);
+                }
+                let key = EncryptionKey::generate();
+                let folder = self
+                    .api()
+                    .create_encrypted_folder(&CreateEncryptedFolderRequest {
+                        path,
+                        keys: e2ee::seal_for(&key, &devices)?,
+                    })
+                    .await?;
+                self.out.emit(&folder, |folder| {
+                    println!("encrypted {} ({})", folder.path, folder.id);
+                    if !folder.pending_devices.is_empty() {
+                        println!(
+                            "{} other devices need approving with `encrypted approve`",
+                            folder.pending_devices.len()
+                        );
+                    }
+                })
+            }
+            EncryptedCommand::List => {
+                let folders = self.api().encrypted_folders().await?;
+                self.out.emit(&folders, |folders| {
+                    for folder in folders {
+                        let status = match (&folder.sealed_key, folder.owner_id == user_id) {
+                            (None, _) => "no key on this device yet".to_string(),
+                            (Some(_), true) => "owned".to_string(),
+                            (Some(_), false) => format!("shared by {}", folder.owner_id),
+                        };
+                        println!("{}  {}  {}", folder.id, folder.path, status);
+                    }
+                })
+            }
+            EncryptedCommand::Share {
+                path,
+                user_id: to,
+                fingerprints,
+            } => {
+                let folders = self.daemon.encrypted_folders().await?;
+                let folder = find_folder(&folders, &path, user_id)?;
+                if folder.owner_id != user_id {
+                    return Err(ClientError::Usage(format!(
+                        "{} belongs to another user",
+                        folder.path
+                    )));
+                }
+                let listed = self.api().device_keys(to).await?;
+                if listed.is_empty() {
+                    return Err(ClientError::NotFound(format!(
+                        "user {} has no device to share with",
+                        to
+                    )));
+                }
+                let mut devices = Vec::new();
+                if fingerprints.is_empty() {
+                    for device in listed {
+                        let fingerprint = e2ee::fingerprint(&device)?;
+                        if confirm(&format!(
+                            "share with device {} of {}, key fingerprint\n  {}\nas `encrypted fingerprint` shows there?",
+                            device.device_id, to, fingerprint
+                        ))? {
+                            devices.push(device);
+                        }
+                    }
+                } else {
+                    for expected in &fingerprints {
+                        let mut matching = Vec::new();
+                        for device in &listed {
+                            if e2ee::fingerprint(device)?.eq_ignore_ascii_case(expected.trim()) {
+                                matching.push(device.clone());
+                            }
+                        }
+                        if matching.is_empty() {
+                            return Err(ClientError::NotFound(format!(
+                                "user {} has no device with the key fingerprint {}",
+                                to, expected
+                            )));
+                        }
+                        devices.extend(matching);
+                    }
+                }
+                if devices.is_empty() {
+                    return Err(ClientError::Usage("no device was confirmed".to_string()));
+                }
+                self.api()
+                    .add_folder_keys(
+                        folder.id,
+                        &AddFolderKeysRequest {
+                            keys: e2ee::seal_for(folder.key(), &devices)?,
+                        },
+                    )
+                    .await?;
+                let result = serde_json::json!({
+                    "path": folder.path,
+                    "user_id": to,
+                    "devices": devices.len(),
+                });
+                self.out.emit(&result, |_| {
+                    println!(
+                        "shared {} with {} ({} devices)",
+                        folder.path,
+                        to,
+                        devices.len()
+                    )
+                })
+            }
+            EncryptedCommand::Unshare {
+                path,
+                user_id: from,
+            } => {
+                let path = normalize(&path);
+                let folder = self
+                    .api()
+                    .encrypted_folders()
+                    .await?
+                    .into_iter()
+                    .find(|folder| folder.owner_id == user_id && folder.path == path)
+                    .ok_or_else(|| {
+                        ClientError::NotFound(format!("you have no encrypted folder {}", path))
+                    })?;
+                self.api().remove_folder_member(folder.id, from).await?;
+                self.out.emit(
+                    &serde_json::json!({ "path": path, "user_id": from }),
+                    |_| println!("stopped sharing {} with {}", path, from),
+                )
+            }
+            EncryptedCommand::Ls { folder } => {
+                let folders = self.daemon.encrypted_folders().await?;
+                let folder = find_folder(&folders, &folder, user_id)?;
+                let db = self.daemon.db();
+                let mut entries = Vec::new();
+                for file in self.api().encrypted_files(folder.id).await? {
+                    let path = folder.decrypt_path(&file.path)?;
+                    let relative = path[folder.path.len()..].trim_start_matches('/');
+                    if relative.rsplit('/').next() == Some(DIRECTORY_PLACEHOLDER) {
+                        continue;
+                    }
+                    entries.push(EncryptedEntry {
+                        path: relative.to_string(),
+                        size: db.encrypted_content(&file.checksum)?.map(|(_, size)| size),
+                        modified_at: file.modified_at,
+                        version: file.version,
+                    });
+                }
+                self.out.emit(&entries, |entries| {
+                    for entry in entries {
+                        println!(
+                            "{:>12}  {}  {}",
+                            entry.size.map_or("-".to_string(), |size| size.to_string()),
+                            entry.modified_at.format("%Y-%m-%d %H:%M"),
+                            entry.path
+                        );
+                    }
+                })
+            }
+            EncryptedCommand::Get {
+                folder,
+                path,
+                local,
+            } => {
+                let folders = self.daemon.encrypted_folders().await?;
+                let folder = find_folder(&folders, &folder, user_id)?;
+                self.get_encrypted(folder, &normalize(&path), local).await
+            }
+            EncryptedCommand::Fingerprint => {
+                let device = self.device_key();
+                let fingerprint = e2ee::fingerprint(&device)?;
+                self.out.emit(
+                    &serde_json::json!({
+                        "device_id": device.device_id,
+                        "fingerprint": fingerprint,
+                    }),
+                    |_| println!("{}", fingerprint),
+                )
+            }
+            EncryptedCommand::Pending => {
+                let folders = self.daemon.encrypted_folders().await?;
+                let recovery = self.recovery_key().await?;
+                let mut pending = Vec::new();
+                for folder in folders.folders() {
+                    for device in &folder.pending_devices {
+                        pending.push(PendingDevice {
+                            device_id: device.device_id,
+                            fingerprint: e2ee::fingerprint(device)?,
+                            folder: folder.path.clone(),
+                            recovery: recovery
+                                .as_ref()
+                                .is_some_and(|(key, _)| key.device_id == device.device_id),
+                        });
+                    }
+                }
+                self.out.emit(&pending, |pending| {
+                    for entry in pending {
+                        let kind = if entry.recovery {
+                            "  (recovery key)"
+                        } else {
+                            ""
+                        };
+                        println!(
+                            "{}  {}  {}{}",
+                            entry.device_id, entry.fingerprint, entry.folder, kind
+                        );
+                    }
+                })
+            }
+            EncryptedCommand::Approve {
+                device_id,
+                fingerprint,
+            } => {
+                let folders = self.daemon.encrypted_folders().await?;
+                let waiting: Vec<(&FolderKey, &DeviceKey)> = folders
+                    .folders()
+                    .iter()
+                    .filter_map(|folder| {
+                        folder
+                            .pending_devices
+                            .iter()
+                            .find(|device| device.device_id == device_id)
+                            .map(|device| (folder, device))
+                    })
+                    .collect();
+                let Some((_, device)) = waiting.first() else {
+                    return Err(ClientError::NotFound(format!(
+                        "device {} is not waiting for a key this device holds",
+                        device_id
+                    )));
+                };
+                // The server lists the recovery key like a device, so it is
+                // only trusted without asking when it was created here.
+                let (recovery, created_here) = match self.recovery_key().await? {
+                    Some((key, created_here)) if key.device_id == device_id => {
+                        (true, created_here && key.public_key == device.public_key)
+                    }
+                    _ => (false, false),
+                };
+                let fingerprint = if recovery && created_here {
+                    e2ee::fingerprint(device)?
+                } else {
+                    check_fingerprint(device, fingerprint.as_deref(), recovery)?
+                };
+                let mut approved = Vec::new();
+                for (folder, device) in &waiting {
+                    self.api()
+                        .add_folder_keys(
+                            folder.id,
+                            &AddFolderKeysRequest {
+                                keys: e2ee::seal_for(folder.key(), std::slice::from_ref(device))?,
+                            },
+                        )
+                        .await?;
+                    approved.push(folder.path.clone());
+                }
+                let result = serde_json::json!({
+                    "device_id": device_id,
+                    "fingerprint": fingerprint,
+                    "folders": approved,
+                });
+                self.out.emit(&result, |_| {
+                    for path in &approved {
+                        println!("gave the key of {} to {}", path, device_id);
+                    }
+                })
+            }
+        }
+    }
+
+    async fn recovery(&self, command: RecoveryCommand) -> Result<(), ClientError> {
+        match command {
+            RecoveryCommand::Create { passphrase_stdin } => {
+                let passphrase = if passphrase_stdin {
+                    read_stdin_line()?
+                } else {
+                    let passphrase = rpassword::prompt_password("Recovery passphrase: ")?;
+                    if rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+                        return Err(ClientError::Usage("the passphrases differ".to_string()));
+                    }
+                    passphrase
+                };
+                if passphrase.chars().count() < MIN_RECOVERY_PASSPHRASE_LEN {
+                    return Err(ClientError::Usage(format!(
+                        "the passphrase needs at least {} characters",
+                        MIN_RECOVERY_PASSPHRASE_LEN
+                    )));
+                }
+                let params = PasswordKeyParams::generate(PasswordKdfCost::default());
+                let keys = e2ee::recovery_keys(&passphrase, &params)?;
+                let public_key = crypto::to_base64(&keys.public_key());
+                let recovery = self
+                    .api()
+                    .set_recovery_key(&SetRecoveryKeyRequest {
+                        public_key: public_key.clone(),
+                        kdf_params: params.to_string(),
+                    })
+                    .await?;
+                self.daemon
+                    .db()
+                    .set_setting(SETTING_RECOVERY_KEY, &public_key)?;
+                // Sealed with the key derived here, not the one the server
+                // lists, for the folders it waits for.
+                let key = DeviceKey {
+                    device_id: recovery.id,
+                    user_id: self.daemon.user_id(),
+                    public_key,
+                };
+                let fingerprint = e2ee::fingerprint(&key)?;
+                let mut sealed = 0;
+                for folder in self.daemon.encrypted_folders().await?.folders() {
+                    if !folder
+                        .pending_devices
+                        .iter()
+                        .any(|device| device.device_id == recovery.id)
+                    {
+                        continue;
+                    }
+                    self.api()
+                        .add_folder_keys(
+                            folder.id,
+                            &AddFolderKeysRequest {
+                                keys: e2ee::seal_for(folder.key(), std::slice::from_ref(&key))?,
+                            },
+                        )
+                        .await?;
+                    sealed += 1;
+                }
+                let result = serde_json::json!({
+                    "id": recovery.id,
+                    "fingerprint": fingerprint,
+                    "folders": sealed,
+                });
+                self.out.emit(&result, |_| {
+                    println!("recovery key {}, key fingerprint", recovery.id);
+                    println!("  {}", fingerprint);
+                    println!(
+                        "the recovery passphrase holds the keys of {} folders; keep it safe",
+                        sealed
+                    );
+                    println!(
+                        "other devices give it theirs with `encrypted approve {}`",
+                        recovery.id
+                    )
+                })
+            }
+            RecoveryCommand::Show => {
+                let recovery = self.api().recovery_key().await?.ok_or_else(|| {
+                    ClientError::NotFound("the account has no recovery key".to_string())
+                })?;
+                let fingerprint = e2ee::fingerprint(&DeviceKey {
+                    device_id: recovery.id,
+                    user_id: self.daemon.user_id(),
+                    public_key: recovery.public_key.clone(),
+                })?;
+                let result = serde_json::json!({
+                    "id": recovery.id,
+                    "fingerprint": fingerprint,
+                    "created_at": recovery.created_at,
+                });
+                self.out.emit(&result, |_| {
+                    println!(
+                        "{}  {}  created {}",
+                        recovery.id,
+                        fingerprint,
+                        recovery.created_at.format("%Y-%m-%d %H:%M")
+                    )
+                })
+            }
+            RecoveryCommand::Restore { passphrase_stdin } => {
+                let recovery = self.api().recovery_key().await?.ok_or_else(|| {
+                    ClientError::NotFound("the account has no recovery key".to_string())
+                })?;
+                let prompt = match recovery.kdf_params {
+                    Some(_) => "Recovery passphrase: ",
+                    None => "Recovery code: ",
+                };
+                let secret = if passphrase_stdin {
+                    read_stdin_line()?
+                } else {
+                    rpassword::prompt_password(prompt)?
+                };
+                let keys = match &recovery.kdf_params {
+                    Some(params) => e2ee::recovery_keys(&secret, &params.parse()?)?,
+                    // Recovery keys created before passphrases were raw
+                    // X25519 secrets.
+                    None => crypto::from_base64(secret.trim())
+                        .ok()
+                        .and_then(|secret| secret.try_into().ok())
+                        .map(DeviceKeyPair::from_secret)
+                        .ok_or_else(|| ClientError::Usage("invalid recovery code".to_string()))?,
+                };
+                if recovery.public_key != crypto::to_base64(&keys.public_key()) {
+                    return Err(ClientError::Usage(
+                        "this is not the account's recovery passphrase".to_string(),
+                    ));
+                }
+                let device = self.device_key();
+                let mut restored = Vec::new();
+                for folder in self.api().recovery_folder_keys().await? {
+                    let key = keys.open_key(&crypto::from_base64(&folder.sealed_key)?)?;
+                    self.api()
+                        .add_folder_keys(
+                            folder.folder_id,
+                            &AddFolderKeysRequest {
+                                keys: e2ee::seal_for(&key, std::slice::from_ref(&device))?,
+                            },
+                        )
+                        .await?;
+                    restored.push(folder.path);
+                }
+                self.out.emit(&restored, |restored| {
+                    restored
+                        .iter()
+                        .for_each(|path| println!("restored {}", path))
+                })
+            }
+            RecoveryCommand::Remove => {
+                self.api().remove_recovery_key().await?;
+                self.out.emit(&serde_json::json!({ "removed": true }), |_| {
+                    println!("removed the recovery key")
+                })
+            }
+        }
+    }
+
+    async fn get_encrypted(
+        &self,
+        folder: &FolderKey,
+        path: &str,
+        local: Option<PathBuf>,
+    ) -> Result<(), ClientError> {
+        if path.is_empty() {
+            return Err(ClientError::Usage("missing file name".to_string()));
+        }
+        let stored = folder.encrypt_path(&remote_path(&folder.path, path))?;
+        let content = self.api().download_encrypted(folder.id, &stored).await?;
+        if local.as_deref() == Some(Path::new("-")) {
+            crypto::decrypt_content(
+                folder.key(),
+                &mut content.as_slice(),
+                &mut std::io::stdout().lock(),
+            )?;
+            return Ok(());
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let target = match local {
+            Some(local) if local.is_dir() => local.join(name),
+            Some(local) => local,
+            None => PathBuf::from(name),
+        };
+        let mut partial = target.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        let decrypted = fs::File::create(&partial)
+            .map_err(ClientError::from)
+            .and_then(|mut file| {
+                let info =
+                    crypto::decrypt_content(folder.key(), &mut content.as_slice(), &mut file)?;
+                file.flush()?;
+                Ok(info)
+            });
+        let info = match decrypted {
+            Ok(info) => info,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e);
+            }
+        };
+        fs::rename(&partial, &target)?;
+        let result = serde_json::json!({
+            "path": path,
+            "local_path": target,
+            "checksum": info.checksum,
+            "size": info.size,
+        });
+        self.out.emit(&result, |_| {
+            println!("{} -> {} ({} bytes)", path, target.display(), info.size)
+        })
+    }
+
+    async fn share(&self, command: ShareCommand) -> Result<(), ClientError> {
+        match command {
+            ShareCommand::Create(options) => {
+                let password = match options.password {
+                    true => Some(rpassword::prompt_password("Link password: ")?),
+                    false => None,
+                };
+                let mut link = self
+                    .api()
+                    .create_share(&CreateShareRequest {
+                        path: normalize(&options.path),
+                        expires_at: options.expires.as_deref().map(parse_expiry).transpose()?,
+                        password,
+                        max_downloads: options.max_downloads,
+                    })
+                    .await?;
+                link.url = self.api().url(&link.url);
+                self.out.emit(&link, print_link)
+            }
+            ShareCommand::List => {
+                let mut links = self.api().shares().await?;
+                for link in &mut links {
+                    link.url = self.api().url(&link.url);
+                }
+                self.out
+                    .emit(&links, |links| links.iter().for_each(print_link))
+            }
+            ShareCommand::Revoke { id } => {
+                self.api().revoke_share(id).await?;
+                self.out.emit(&serde_json::json!({ "revoked": id }), |_| {
+                    println!("revoked {}", id)
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::errors::ApiError;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_json_flag_is_global() {
+        let cli = Cli::try_parse_from(["rouillecloud", "ls", "docs", "--json"]).unwrap();
+        assert!(cli.json);
+        let cli = Cli::try_parse_from(["rouillecloud", "--json", "ls"]).unwrap();
+        assert!(cli.json);
+        let cli = Cli::try_parse_from(["rouillecloud", "ls"]).unwrap();
+        assert!(!cli.json);
+    }
+
+    #[test]
+    fn test_json_output_replaces_the_text() {
+        let value = serde_json::json!({ "path": "docs", "fetched": 2 });
+        let mut printed = Vec::new();
+        let mut text_called = false;
+        Output { json: true }
+            .emit_to(&mut printed, &value, |_| text_called = true)
+            .unwrap();
+        assert!(!text_called);
+        let printed = String::from_utf8(printed).unwrap();
+        assert!(printed.ends_with('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&printed).unwrap(),
+            value
+        );
+
+        let mut printed = Vec::new();
+        Output { json: false }
+            .emit_to(&mut printed, &value, |_| text_called = true)
+            .unwrap();
+        assert!(text_called);
+        assert!(printed.is_empty());
+    }
+
+    #[test]
+    fn test_errors_as_json() {
+        let error = ClientError::Api {
+            status: StatusCode::NOT_FOUND,
+            error: Some(ApiError::FileNotFound),
+            message: "File not found".to_string(),
+        };
+        let value = error_json(&error);
+        assert_eq!(
+            value["error"],
+            serde_json::to_value(ApiError::FileNotFound).unwrap()
+        );
+        assert_eq!(value["status"], 404);
+        assert_eq!(value["message"], error.to_string());
+        assert_eq!(value["exit_code"], 66);
+
+        let value = error_json(&ClientError::Usage("no path".to_string()));
+        assert_eq!(value["error"], serde_json::Value::Null);
+        assert_eq!(value["status"], serde_json::Value::Null);
+        assert_eq!(value["message"], "no path");
+        assert_eq!(value["exit_code"], 64);
+    }
+}
@@ -0,0 +1,211 @@
+//! Client configuration
+//!
+//! Read from `client.toml` in the user's configuration directory, then from
+//! `ROUILLECLOUD_*` environment variables (`ROUILLECLOUD_SERVER_URL`, ...).
+//!
+//! Transfers are set in a `[transfers]` table: how many run at once, upload
+//! and download limits in bytes per second, and `[[transfers.schedule]]`
+//! rules replacing those limits at some times of day:
+//!
+//! ```toml
+//! [transfers]
+//! max_concurrent_transfers = 4
+//! upload_limit = 1048576
+//!
+//! [[transfers.schedule]]
+//! days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+//! start = "09:00"
+//! end = "18:00"
+//! upload_limit = 262144
+//! download_limit = 1048576
+//! ```
+//!
+//! Virtual files are set in a `[virtual_files]` table: where `mount` mounts
+//! them by default, and how many bytes of content to keep cached.
+
+use crate::error::ClientError;
+use protocol::sync::{SyncDirection, SyncFilters, SyncFolder};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use sync_core::SchedulerOptions;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientConfig {
+    /// Base URL of the server, e.g. `https://cloud.example.org`.
+    pub server_url: String,
+    /// Credentials used once, to register the device.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Account token to register the device with, instead of logging in.
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default = "default_device_name")]
+    pub device_name: String,
+    /// Where the local database and partial downloads live.
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// Quiet time after the last file event before a sync starts.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Interval between syncs run to pick up remote changes.
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    #[serde(default)]
+    pub folders: Vec<FolderConfig>,
+    #[serde(default)]
+    pub transfers: SchedulerOptions,
+    #[serde(default)]
+    pub virtual_files: VirtualFilesConfig,
+}
+
+/// Virtual files; see [`crate::vfs`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualFilesConfig {
+    /// Where `mount` mounts the remote files when given no directory.
+    #[serde(default)]
+    pub mount_point: Option<PathBuf>,
+    /// Most bytes of file content kept cached, pinned files aside.
+    #[serde(default = "default_cache_size")]
+    pub cache_size: u64,
+}
+
+impl Default for VirtualFilesConfig {
+    fn default() -> Self {
+        Self {
+            mount_point: None,
+            cache_size: default_cache_size(),
+        }
+    }
+}
+
+/// A configured sync folder; see [`SyncFolder`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderConfig {
+    pub local_path: String,
+    pub remote_path: String,
+    #[serde(default = "default_direction")]
+    pub direction: SyncDirection,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub filters: SyncFilters,
+}
+
+impl FolderConfig {
+    pub fn to_sync_folder(&self) -> SyncFolder {
+        SyncFolder {
+            local_path: self.local_path.clone(),
+            remote_path: self.remote_path.clone(),
+            sync_direction: self.direction,
+            is_active: self.enabled,
+            filters: self.filters.clone(),
+        }
+    }
+}
+
+fn default_device_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "desktop".to_string())
+}
+
+fn default_state_dir() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rouillecloud")
+}
+
+fn default_debounce_ms() -> u64 {
+    2000
+}
+
+fn default_poll_interval_secs() -> u64 {
+    60
+}
+
+fn default_cache_size() -> u64 {
+    1 << 30
+}
+
+fn default_direction() -> SyncDirection {
+    SyncDirection::Bidirectional
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl ClientConfig {
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("rouillecloud")
+            .join("client.toml")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ClientError> {
+        Self::load_with_server(path, None)
+    }
+
+    /// Like [`load`](Self::load), with the server URL given on the command
+    /// line taking precedence.
+    pub fn load_with_server(path: &Path, server_url: Option<&str>) -> Result<Self, ClientError> {
+        let config: ClientConfig = config::Config::builder()
+            .add_source(config::File::from(path).required(false))
+            .add_source(config::Environment::with_prefix("ROUILLECLOUD"))
+            .set_override_option("server_url", server_url)?
+            .build()?
+            .try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ClientError> {
+        if !self.server_url.starts_with("http://") && !self.server_url.starts_with("https://") {
+            return Err(ClientError::Config(format!(
+                "server_url must be an http(s) URL, got {}",
+                self.server_url
+            )));
+        }
+        self.transfers
+            .validate()
+            .map_err(|e| ClientError::Config(format!("transfers: {}", e)))?;
+        for folder in &self.folders {
+            if !Path::new(&folder.local_path).is_absolute() {
+                return Err(ClientError::Config(format!(
+                    "local_path {} must be absolute",
+                    folder.local_path
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.state_dir.join("client.db")
+    }
+
+    /// Directory holding downloads in progress.
+    pub fn partial_dir(&self) -> PathBuf {
+        self.state_dir.join("partial")
+    }
+
+    /// Contents of virtual files.
+    pub fn cache_dir(&self) -> PathBuf {
+        self.state_dir.join("cache")
+    }
+
+    /// Virtual files being written.
+    pub fn staging_dir(&self) -> PathBuf {
+        self.state_dir.join("staging")
+    }
+
+    pub fn sync_folders(&self) -> Vec<SyncFolder> {
+        self.folders
+            .iter()
+            .map(FolderConfig::to_sync_folder)
+            .collect()
+    }
+}
@@ -0,0 +1,561 @@
+//! The sync daemon
+//!
+//! On first start the daemon registers the machine as a device, with the
+//! configured credentials or account token, and keeps the device token in
+//! its database. It then syncs every active folder once, and again after
+//! file events settle for the debounce delay or when the poll interval
+//! elapses.
+//!
+//! A sync cycle pulls the changes other devices made into the remote
+//! mirror, then for each folder scans the local files, plans against the
+//! folder's base state and executes the plan. Before pulling, the daemon
+//! gives the server its folders' current filters, ignore files included, so
+//! excluded paths are left out of the changes it receives; when they
+//! changed, the mirror is rebuilt from a full sync. A revoked device stops; a
+//! device asked to wipe deletes its folders' contents, confirms and stops.
+//!
+//! The device's public key is given to the server at registration, or on
+//! the first start of a device registered before encrypted folders. Each
+//! cycle loads the keys of the account's encrypted folders, giving them to
+//! the account's devices that lack them, and plans those folders in the
+//! clear (see [`crate::e2ee`]).
+//!
+//! A folder's uploads and downloads go through one [`TransferScheduler`],
+//! shared by all folders so its bandwidth limits hold across them, which
+//! logs the progress of each sync.
+
+use crate::api::ApiClient;
+use crate::config::ClientConfig;
+use crate::db::{
+    LocalDb, SETTING_DEVICE_ID, SETTING_DEVICE_TOKEN, SETTING_REPORTED_FOLDERS, SETTING_SYNC_TOKEN,
+    SETTING_USER_ID, SETTING_WIPED_AT,
+};
+use crate::e2ee::{self, EncryptedFolders};
+use crate::error::ClientError;
+use crate::scanner::{folder_filters, Scanner};
+use crate::transfer::FolderExecutor;
+use crate::vfs::ContentCache;
+use crate::watcher::FolderWatcher;
+use crypto::DeviceKeyPair;
+use protocol::errors::ApiError;
+use protocol::sync::{
+    DeviceType, RegisterDeviceRequest, SyncFolder, SyncProgress, SyncRequest, UpdateDeviceRequest,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use sync_core::{BaseStateStore, PathFilter, Reconciler, Snapshot, TransferScheduler};
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Longest a sync waits for file events to settle.
+const MAX_DEBOUNCE_FACTOR: u32 = 10;
+
+pub struct Daemon {
+    config: ClientConfig,
+    db: Arc<LocalDb>,
+    api: ApiClient,
+    device_id: Uuid,
+    user_id: Uuid,
+    keys: DeviceKeyPair,
+    scheduler: Arc<TransferScheduler>,
+}
+
+/// Remote root of a folder, as the server's journal writes paths.
+fn remote_prefix(folder: &SyncFolder) -> String {
+    folder.remote_path.trim_matches('/').to_string()
+}
+
+impl Daemon {
+    /// Open the local state and make sure the device is registered.
+    pub async fn start(config: ClientConfig) -> Result<Self, ClientError> {
+        let db = Arc::new(LocalDb::open(&config.database_path())?);
+        if let Some(wiped_at) = db.setting(SETTING_WIPED_AT)? {
+            return Err(ClientError::Config(format!(
+                "this device was wiped at {}; delete {} to set it up again",
+                wiped_at,
+                config.database_path().display()
+            )));
+        }
+        let stored = (
+            db.setting(SETTING_DEVICE_ID)?
+                .and_then(|id| Uuid::parse_str(&id).ok()),
+            db.setting(SETTING_DEVICE_TOKEN)?,
+        );
+        let (device_id, token) = match stored {
+            (Some(id), Some(token)) => (id, token),
+            _ => Self::register(&config, &db).await?,
+        };
+        let api = ApiClient::new(&config.server_url)?.with_token(token);
+        let keys = e2ee::device_keys(&db)?;
+        let user_id = match db
+            .setting(SETTING_USER_ID)?
+            .and_then(|id| Uuid::parse_str(&id).ok())
+        {
+            Some(user_id) => user_id,
+            None => Self::publish_key(&api, &db, device_id, &keys).await?,
+        };
+        let scheduler = TransferScheduler::new(config.transfers.clone());
+        scheduler.add_observer(|progress: &SyncProgress| {
+            tracing::debug!(
+                "{}/{} files, {}/{} bytes at {} B/s{}",
+                progress.completed_files,
+                progress.total_files,
+                progress.transferred_bytes,
+                progress.total_bytes,
+                progress.speed_bps,
+                progress
+                    .eta_seconds
+                    .map_or(String::new(), |eta| format!(", {}s left", eta))
+            );
+        });
+        Ok(Self {
+            config,
+            db,
+            api,
+            device_id,
+            user_id,
+            keys,
+            scheduler: Arc::new(scheduler),
+        })
+    }
+
+    /// Give the server the public key of a device registered without one.
+    /// Returns the device's account.
+    async fn publish_key(
+        api: &ApiClient,
+        db: &LocalDb,
+        device_id: Uuid,
+        keys: &DeviceKeyPair,
+    ) -> Result<Uuid, ClientError> {
+        let public_key = crypto::to_base64(&keys.public_key());
+        let device = api
+            .update_device(
+                device_id,
+                &UpdateDeviceRequest {
+                    name: None,
+                    version: None,
+                    sync_folders: None,
+                    public_key: Some(public_key.clone()),
+                },
+            )
+            .await?;
+        if device.public_key.as_deref() != Some(public_key.as_str()) {
+            tracing::warn!("the server has another public key for this device");
+        }
+        db.set_setting(SETTING_USER_ID, &device.user_id.to_string())?;
+        Ok(device.user_id)
+    }
+
+    /// Register this machine as a device with the configured token or
+    /// credentials and store the device token.
+    pub async fn register(
+        config: &ClientConfig,
+        db: &LocalDb,
+    ) -> Result<(Uuid, String), ClientError> {
+        let api = ApiClient::new(&config.server_url)?;
+        let keys = e2ee::device_keys(db)?;
+        let account_token =
+            match (&config.token, &config.username, &config.password) {
+                (Some(token), _, _) => token.clone(),
+                (None, Some(username), Some(password)) => api.login(username, password).await?,
+                _ => return Err(ClientError::Config(
+                    "not logged in: run `login`, or configure a token or a username and password"
+                        .to_string(),
+                )),
+            };
+        let response = api
+            .with_token(account_token)
+            .register_device(&RegisterDeviceRequest {
+                name: config.device_name.clone(),
+                device_type: DeviceType::Desktop,
+                platform: std::env::consts::OS.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                sync_folders: config.sync_folders(),
+                public_key: Some(crypto::to_base64(&keys.public_key())),
+            })
+            .await?;
+        db.set_setting(SETTING_DEVICE_ID, &response.device.id.to_string())?;
+        db.set_setting(SETTING_DEVICE_TOKEN, &response.token)?;
+        db.set_setting(SETTING_USER_ID, &response.device.user_id.to_string())?;
+        tracing::info!("registered as device {}", response.device.id);
+        Ok((response.device.id, response.token))
+    }
+
+    pub fn device_id(&self) -> Uuid {
+        self.device_id
+    }
+
+    pub fn user_id(&self) -> Uuid {
+        self.user_id
+    }
+
+    pub fn device_keys(&self) -> &DeviceKeyPair {
+        &self.keys
+    }
+
+    /// The account's encrypted folders, with the keys this device holds.
+    pub async fn encrypted_folders(&self) -> Result<EncryptedFolders, ClientError> {
+        EncryptedFolders::load(&self.api, &self.keys, self.user_id).await
+    }
+
+    pub fn db(&self) -> &Arc<LocalDb> {
+        &self.db
+    }
+
+    /// Client authenticated with the device token.
+    pub fn api(&self) -> &ApiClient {
+        &self.api
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// The cache of virtual file contents.
+    pub fn content_cache(&self) -> ContentCache {
+        ContentCache::new(
+            self.config.cache_dir(),
+            self.config.virtual_files.cache_size,
+            self.db.clone(),
+        )
+    }
+
+    /// Active folders with the filters they are synced with.
+    fn filtered_folders(&self) -> Result<Vec<SyncFolder>, ClientError> {
+        self.config
+            .sync_folders()
+            .into_iter()
+            .map(|mut folder| {
+                if folder.is_active {
+                    folder.filters = folder_filters(&folder)?;
+                }
+                Ok(folder)
+            })
+            .collect()
+    }
+
+    /// Give the server the folders' filters if they changed since last
+    /// time, and start over with a full sync, as files excluded until now
+    /// were never received.
+    async fn report_folders(&self, folders: &[SyncFolder]) -> Result<(), ClientError> {
+        let reported = serde_json::to_string(folders)?;
+        if self.db.setting(SETTING_REPORTED_FOLDERS)?.as_deref() == Some(reported.as_str()) {
+            return Ok(());
+        }
+        self.api
+            .update_device(
+                self.device_id,
+                &UpdateDeviceRequest {
+                    name: None,
+                    version: None,
+                    sync_folders: Some(folders.to_vec()),
+                    public_key: None,
+                },
+            )
+            .await?;
+        self.db.reset_remote()?;
+        self.db.set_setting(SETTING_REPORTED_FOLDERS, &reported)?;
+        tracing::info!("folder filters changed: fetching the file list again");
+        Ok(())
+    }
+
+    /// Fetch what other devices changed into the remote mirror.
+    pub async fn pull(&self) -> Result<(), ClientError> {
+        let response = self
+            .api
+            .sync(&SyncRequest {
+                device_id: self.device_id,
+                last_sync_token: self.db.setting(SETTING_SYNC_TOKEN)?,
+                changes: Vec::new(),
+            })
+            .await?;
+        self.db.apply_sync_response(&response)
+    }
+
+    /// Plan and execute one folder on a blocking thread. Returns the number
+    /// of actions run.
+    async fn sync_folder(
+        &self,
+        folder: SyncFolder,
+        encryption: Arc<EncryptedFolders>,
+    ) -> Result<usize, ClientError> {
+        let db = self.db.clone();
+        let api = self.api.clone();
+        let device_id = self.device_id;
+        let state_dir = self.config.state_dir.clone();
+        let partial_dir = self.config.partial_dir();
+        let scheduler = self.scheduler.clone();
+        let runtime = Handle::current();
+        let task = tokio::task::spawn_blocking(move || {
+            let root = PathBuf::from(&folder.local_path);
+            std::fs::create_dir_all(&root)?;
+            let filter = PathFilter::new(&folder.filters);
+            let local = Scanner::new(&db, &filter, vec![state_dir]).scan(&root)?;
+            let prefix = remote_prefix(&folder);
+            let remote: Snapshot = e2ee::remote_files(&runtime, &api, &db, &encryption, &prefix)?
+                .into_iter()
+                .filter(|state| filter.accepts_file(&state.path, state.size))
+                .map(|state| (state.path.clone(), state))
+                .collect();
+
+            let mut base = db.load(&folder)?;
+            let plan = Reconciler::for_folder(&folder).plan(&base, &local, &remote);
+            if plan.is_empty() {
+                return Ok(0);
+            }
+            tracing::info!("{}: {} actions", folder.local_path, plan.len());
+            let executor = FolderExecutor::new(
+                runtime,
+                api,
+                db.clone(),
+                device_id,
+                root,
+                prefix,
+                filter,
+                partial_dir,
+                local,
+                encryption,
+            );
+            let result = sync_core::execute_scheduled(
+                &plan, &folder, &mut base, &*db, &executor, &scheduler,
+            );
+            match executor.take_terminal() {
+                Some(terminal) => Err(terminal),
+                None => Ok(result?),
+            }
+        });
+        task.await
+            .map_err(|e| ClientError::Io(std::io::Error::other(e.to_string())))?
+    }
+
+    /// One full sync of every active folder. A folder that fails does not
+    /// stop the others; it is retried on the next cycle.
+    pub async fn sync_once(&self) -> Result<usize, ClientError> {
+        let folders = self.filtered_folders()?;
+        self.report_folders(&folders).await?;
+        self.pull().await?;
+        let encryption = Arc::new(self.encrypted_folders().await?);
+        let mut done = 0;
+        for folder in folders {
+            if !folder.is_active {
+                continue;
+            }
+            let local_path = folder.local_path.clone();
+            match self.sync_folder(folder, encryption.clone()).await {
+                Ok(count) => done += count,
+                Err(e) if e.is_device_terminal() => return Err(e),
+                Err(e) => tracing::warn!("{}: sync failed: {}", local_path, e),
+            }
+        }
+        Ok(done)
+    }
+
+    /// Delete everything in the sync folders, confirm to the server and
+    /// forget the local state.
+    async fn wipe(&self) -> Result<(), ClientError> {
+        tracing::warn!("remote wipe requested: deleting synced folders");
+        for folder in self.config.sync_folders() {
+            clear_dir(Path::new(&folder.local_path))?;
+        }
+        let _ = std::fs::remove_dir_all(self.config.partial_dir());
+        self.api.confirm_wipe(self.device_id).await?;
+        self.db.clear()?;
+        self.db
+            .set_setting(SETTING_WIPED_AT, &chrono::Utc::now().to_rfc3339())?;
+        Ok(())
+    }
+
+    /// Sync, handling a revoked or wiped device. `Err` means the daemon
+    /// must stop.
+    async fn cycle(&self) -> Result<(), ClientError> {
+        match self.sync_once().await {
+            Ok(0) => Ok(()),
+            Ok(count) => {
+                tracing::info!("sync complete: {} actions", count);
+                Ok(())
+            }
+            Err(e) => match e.api_error() {
+                Some(ApiError::WipeRequested) => {
+                    self.wipe().await?;
+                    Err(e)
+                }
+                Some(ApiError::DeviceRevoked) => Err(e),
+                _ => {
+                    tracing::warn!("sync failed, retrying later: {}", e);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    /// Sync until interrupted with Ctrl-C or stopped by the server.
+    pub async fn run(&self) -> Result<(), ClientError> {
+        let roots: Vec<PathBuf> = self
+            .config
+            .sync_folders()
+            .iter()
+            .filter(|folder| folder.is_active)
+            .map(|folder| PathBuf::from(&folder.local_path))
+            .collect();
+        let mut watcher = FolderWatcher::new(&roots, self.config.state_dir.clone())?;
+        let debounce = Duration::from_millis(self.config.debounce_ms);
+        let mut poll =
+            tokio::time::interval(Duration::from_secs(self.config.poll_interval_secs.max(1)));
+        // When the first unsynced event arrived, and when the last one did.
+        let mut pending: Option<(Instant, Instant)> = None;
+
+        loop {
+            let deadline = pending
+                .map(|(first, last)| (last + debounce).min(first + debounce * MAX_DEBOUNCE_FACTOR));
+            tokio::select! {
+                changed = watcher.next() => {
+                    let Some(path) = changed else { break };
+                    tracing::trace!("changed: {}", path.display());
+                    let now = Instant::now();
+                    pending = Some((pending.map_or(now, |(first, _)| first), now));
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    pending = None;
+                    self.cycle().await?;
+                }
+                _ = poll.tick() => {
+                    pending = None;
+                    self.cycle().await?;
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("stopping");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remove the contents of `dir`, keeping the directory itself.
+fn clear_dir(dir: &Path) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SETTING_SYNC_TOKEN;
+    use crate::testing::{Reply, TestServer};
+    use chrono::Utc;
+    use protocol::sync::{Device, FileState, SyncDirection, SyncFilters, SyncStatus};
+
+    fn daemon(server: &TestServer) -> Daemon {
+        let config: ClientConfig = serde_json::from_value(serde_json::json!({
+            "server_url": server.url,
+            "state_dir": std::env::temp_dir(),
+        }))
+        .unwrap();
+        Daemon {
+            api: ApiClient::new(&config.server_url).unwrap(),
+            scheduler: Arc::new(TransferScheduler::new(config.transfers.clone())),
+            config,
+            db: Arc::new(LocalDb::open_in_memory().unwrap()),
+            device_id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            keys: DeviceKeyPair::generate(),
+        }
+    }
+
+    fn folder(exclude: &[&str]) -> SyncFolder {
+        SyncFolder {
+            local_path: "/home/docs".to_string(),
+            remote_path: "/docs".to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active: true,
+            filters: SyncFilters {
+                exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+                ..SyncFilters::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changed_filters_reset_the_remote_mirror() {
+        let server = TestServer::start(|request| {
+            let update: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            Reply::json(&Device {
+                id: Uuid::new_v4(),
+                user_id: Uuid::new_v4(),
+                name: "laptop".to_string(),
+                device_type: DeviceType::Desktop,
+                platform: "linux".to_string(),
+                version: "1".to_string(),
+                last_seen: Utc::now(),
+                is_active: true,
+                sync_folders: serde_json::from_value(update["sync_folders"].clone()).unwrap(),
+                wipe_requested: false,
+                public_key: None,
+            })
+        })
+        .await;
+        let daemon = daemon(&server);
+        let fill_mirror = || {
+            daemon
+                .db
+                .set_remote(&FileState {
+                    path: "docs/a.txt".to_string(),
+                    checksum: "a".to_string(),
+                    size: 1,
+                    modified_at: Utc::now(),
+                    version: 1,
+                    sync_status: SyncStatus::Synced,
+                })
+                .unwrap();
+            daemon.db.set_setting(SETTING_SYNC_TOKEN, "token").unwrap();
+        };
+
+        fill_mirror();
+        daemon.report_folders(&[folder(&["*.tmp"])]).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "PATCH");
+        assert_eq!(
+            requests[0].path(),
+            format!("/api/v1/devices/{}", daemon.device_id)
+        );
+        assert!(daemon.db.remote_files("").unwrap().is_empty());
+        assert_eq!(daemon.db.setting(SETTING_SYNC_TOKEN).unwrap(), None);
+
+        // Unchanged folders are neither reported nor fetched again.
+        fill_mirror();
+        daemon.report_folders(&[folder(&["*.tmp"])]).await.unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(daemon.db.remote_files("").unwrap().len(), 1);
+
+        daemon
+            .report_folders(&[folder(&["*.tmp", "build/"])])
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let update: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(
+            update["sync_folders"][0]["filters"]["exclude_patterns"],
+            serde_json::json!(["*.tmp", "build/"])
+        );
+        assert!(daemon.db.remote_files("").unwrap().is_empty());
+        assert_eq!(daemon.db.setting(SETTING_SYNC_TOKEN).unwrap(), None);
+    }
+}
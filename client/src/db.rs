@@ -0,0 +1,840 @@
+//! Local state database
+//!
+//! A SQLite file in the state directory keeps what the client must not
+//! forget across restarts: the device identity and sync token, a mirror of
+//! the server's files built from sync responses, each folder's base state,
+//! a cache of local file hashes, downloads in progress, unresolved
+//! conflicts, the contents cached for virtual files with the paths pinned
+//! for offline use, and what the files of encrypted folders decrypt to.
+
+use crate::error::ClientError;
+use chrono::{DateTime, Utc};
+use protocol::sync::{ChangeType, FileState, SyncFolder, SyncResponse};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use sync_core::{folder_key, BaseState, BaseStateStore, SyncError, SyncState};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS remote_files (
+    path TEXT PRIMARY KEY,
+    checksum TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    modified_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS base_states (
+    folder_key TEXT PRIMARY KEY,
+    state TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS local_hashes (
+    path TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    mtime_ns INTEGER NOT NULL,
+    checksum TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS transfers (
+    path TEXT PRIMARY KEY,
+    checksum TEXT NOT NULL,
+    partial_path TEXT NOT NULL,
+    started_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS conflicts (
+    path TEXT PRIMARY KEY,
+    conflict_type TEXT NOT NULL,
+    detected_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS cached_contents (
+    checksum TEXT PRIMARY KEY,
+    size INTEGER NOT NULL,
+    used_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pinned_paths (
+    path TEXT PRIMARY KEY,
+    pinned_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS encrypted_contents (
+    checksum TEXT PRIMARY KEY,
+    plain_checksum TEXT NOT NULL,
+    plain_size INTEGER NOT NULL
+);
+";
+
+pub const SETTING_DEVICE_ID: &str = "device_id";
+pub const SETTING_DEVICE_TOKEN: &str = "device_token";
+pub const SETTING_SYNC_TOKEN: &str = "sync_token";
+/// Set after a remote wipe, so the device does not silently sync again.
+pub const SETTING_WIPED_AT: &str = "wiped_at";
+/// The folders and filters last sent to the server, as JSON.
+pub const SETTING_REPORTED_FOLDERS: &str = "reported_folders";
+/// Secret half of the device's key pair for encrypted folders, base64.
+pub const SETTING_DEVICE_KEY: &str = "device_key";
+/// The account of the device, known once its public key was given to the
+/// server.
+pub const SETTING_USER_ID: &str = "user_id";
+/// Public half of the recovery key created on this device, base64; folder
+/// keys are sealed for it without asking while it is the account's.
+pub const SETTING_RECOVERY_KEY: &str = "recovery_key";
+
+/// A download in progress, resumed from its partial file.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub path: String,
+    pub checksum: String,
+    pub partial_path: PathBuf,
+}
+
+/// A conflict waiting for the user.
+#[derive(Debug, Clone)]
+pub struct ConflictRecord {
+    pub path: String,
+    pub conflict_type: String,
+    pub detected_at: DateTime<Utc>,
+}
+
+pub struct LocalDb {
+    conn: Mutex<Connection>,
+}
+
+fn timestamp(value: String) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&value)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+fn remote_state(path: String, checksum: String, size: i64, modified_at: String) -> SyncState {
+    SyncState {
+        path,
+        hash: checksum,
+        modified: timestamp(modified_at),
+        size: size.max(0) as u64,
+    }
+}
+
+fn upsert_remote(
+    conn: &Connection,
+    path: &str,
+    checksum: &str,
+    size: u64,
+    modified_at: DateTime<Utc>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO remote_files (path, checksum, size, modified_at)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (path) DO UPDATE SET checksum = excluded.checksum,
+             size = excluded.size, modified_at = excluded.modified_at",
+        params![
+            path,
+            checksum,
+            i64::try_from(size).unwrap_or(i64::MAX),
+            modified_at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+impl LocalDb {
+    pub fn open(path: &Path) -> Result<Self, ClientError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::with_connection(conn)
+    }
+
+    /// A database that only lives in memory.
+    #[cfg(test)]
+    pub(crate) fn open_in_memory() -> Result<Self, ClientError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, ClientError> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn setting(&self, key: &str) -> Result<Option<String>, ClientError> {
+        Ok(self
+            .conn()
+            .query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Forget everything, as after a remote wipe.
+    pub fn clear(&self) -> Result<(), ClientError> {
+        self.conn().execute_batch(
+            "DELETE FROM settings; DELETE FROM remote_files; DELETE FROM base_states;
+             DELETE FROM local_hashes; DELETE FROM transfers; DELETE FROM conflicts;
+             DELETE FROM cached_contents; DELETE FROM pinned_paths;
+             DELETE FROM encrypted_contents;",
+        )?;
+        Ok(())
+    }
+
+    /// Drop the remote mirror and the sync token, so the next sync fetches
+    /// every file again, as after the server's filters changed.
+    pub fn reset_remote(&self) -> Result<(), ClientError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM remote_files", [])?;
+        tx.execute("DELETE FROM settings WHERE key = ?1", [SETTING_SYNC_TOKEN])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Fold the changes of a sync response into the remote mirror and keep
+    /// its token, in one transaction.
+    pub fn apply_sync_response(&self, response: &SyncResponse) -> Result<(), ClientError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for path in &response.deleted_files {
+            tx.execute("DELETE FROM remote_files WHERE path = ?1", [path])?;
+        }
+        for change in &response.changes {
+            match &change.change_type {
+                ChangeType::Deleted => {
+                    tx.execute("DELETE FROM remote_files WHERE path = ?1", [&change.path])?;
+                    continue;
+                }
+                ChangeType::Moved { old_path } => {
+                    tx.execute("DELETE FROM remote_files WHERE path = ?1", [old_path])?;
+                }
+                ChangeType::Created | ChangeType::Modified | ChangeType::Copied { .. } => {}
+            }
+            let metadata = change.metadata.as_ref();
+            let Some(checksum) = change
+                .checksum
+                .as_deref()
+                .or(metadata.map(|m| m.checksum.as_str()))
+            else {
+                continue;
+            };
+            let size = metadata.map_or(0, |m| m.size);
+            let modified_at = metadata.map_or_else(Utc::now, |m| m.modified_at);
+            upsert_remote(&tx, &change.path, checksum, size, modified_at)?;
+        }
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![SETTING_SYNC_TOKEN, response.sync_token],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record the server's state of a file this device just wrote.
+    pub fn set_remote(&self, state: &FileState) -> Result<(), ClientError> {
+        upsert_remote(
+            &self.conn(),
+            &state.path,
+            &state.checksum,
+            state.size,
+            state.modified_at,
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_remote(&self, path: &str) -> Result<(), ClientError> {
+        self.conn()
+            .execute("DELETE FROM remote_files WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    pub fn remote(&self, path: &str) -> Result<Option<SyncState>, ClientError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT path, checksum, size, modified_at FROM remote_files WHERE path = ?1",
+                [path],
+                |row| {
+                    Ok(remote_state(
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                    ))
+                },
+            )
+            .optional()?)
+    }
+
+    /// Remote files under `prefix` (every file when empty), with paths
+    /// relative to it.
+    pub fn remote_files(&self, prefix: &str) -> Result<Vec<SyncState>, ClientError> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT path, checksum, size, modified_at FROM remote_files")?;
+        let rows = statement.query_map([], |row| {
+            Ok(remote_state(
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+            ))
+        })?;
+        let mut files = Vec::new();
+        for row in rows {
+            let mut state = row?;
+            let relative = if prefix.is_empty() {
+                Some(state.path.as_str())
+            } else {
+                state
+                    .path
+                    .strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_prefix('/'))
+            };
+            if let Some(relative) = relative {
+                state.path = relative.to_string();
+                files.push(state);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Hash of a local file, if it was computed for the same size and
+    /// modification time.
+    pub fn cached_hash(
+        &self,
+        path: &Path,
+        size: u64,
+        mtime_ns: i64,
+    ) -> Result<Option<String>, ClientError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT checksum FROM local_hashes
+                 WHERE path = ?1 AND size = ?2 AND mtime_ns = ?3",
+                params![
+                    path.to_string_lossy(),
+                    i64::try_from(size).unwrap_or(i64::MAX),
+                    mtime_ns
+                ],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn store_hash(
+        &self,
+        path: &Path,
+        size: u64,
+        mtime_ns: i64,
+        checksum: &str,
+    ) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO local_hashes (path, size, mtime_ns, checksum) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (path) DO UPDATE SET size = excluded.size,
+                 mtime_ns = excluded.mtime_ns, checksum = excluded.checksum",
+            params![
+                path.to_string_lossy(),
+                i64::try_from(size).unwrap_or(i64::MAX),
+                mtime_ns,
+                checksum
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn transfer(&self, path: &str) -> Result<Option<Transfer>, ClientError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT path, checksum, partial_path FROM transfers WHERE path = ?1",
+                [path],
+                |row| {
+                    Ok(Transfer {
+                        path: row.get(0)?,
+                        checksum: row.get(1)?,
+                        partial_path: PathBuf::from(row.get::<_, String>(2)?),
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn start_transfer(&self, transfer: &Transfer) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO transfers (path, checksum, partial_path, started_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (path) DO UPDATE SET checksum = excluded.checksum,
+                 partial_path = excluded.partial_path, started_at = excluded.started_at",
+            params![
+                transfer.path,
+                transfer.checksum,
+                transfer.partial_path.to_string_lossy(),
+                Utc::now().to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn finish_transfer(&self, path: &str) -> Result<(), ClientError> {
+        self.conn()
+            .execute("DELETE FROM transfers WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    pub fn record_conflict(&self, path: &str, conflict_type: &str) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO conflicts (path, conflict_type, detected_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (path) DO UPDATE SET conflict_type = excluded.conflict_type",
+            params![path, conflict_type, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn clear_conflict(&self, path: &str) -> Result<(), ClientError> {
+        self.conn()
+            .execute("DELETE FROM conflicts WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    pub fn conflicts(&self) -> Result<Vec<ConflictRecord>, ClientError> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT path, conflict_type, detected_at FROM conflicts ORDER BY path")?;
+        let rows = statement.query_map([], |row| {
+            Ok(ConflictRecord {
+                path: row.get(0)?,
+                conflict_type: row.get(1)?,
+                detected_at: timestamp(row.get(2)?),
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Record content stored in the virtual file cache, or that it was just
+    /// used.
+    pub fn touch_cached(&self, checksum: &str, size: u64) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO cached_contents (checksum, size, used_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (checksum) DO UPDATE SET used_at = excluded.used_at",
+            params![
+                checksum,
+                i64::try_from(size).unwrap_or(i64::MAX),
+                Utc::now().timestamp_micros()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_cached(&self, checksum: &str) -> Result<(), ClientError> {
+        self.conn().execute(
+            "DELETE FROM cached_contents WHERE checksum = ?1",
+            [checksum],
+        )?;
+        Ok(())
+    }
+
+    /// Total size of the cached contents.
+    pub fn cached_size(&self) -> Result<u64, ClientError> {
+        let size: i64 = self.conn().query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM cached_contents",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(size.max(0) as u64)
+    }
+
+    /// Cached contents no pinned file has, least recently used first.
+    pub fn evictable(&self) -> Result<Vec<(String, u64)>, ClientError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT checksum, size FROM cached_contents c
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM remote_files r JOIN pinned_paths p
+                     ON r.path = p.path OR substr(r.path, 1, length(p.path) + 1) = p.path || '/'
+                 WHERE r.checksum = c.checksum)
+             ORDER BY used_at",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Keep the files at or under `path` cached.
+    pub fn pin(&self, path: &str) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO pinned_paths (path, pinned_at) VALUES (?1, ?2)
+             ON CONFLICT (path) DO NOTHING",
+            params![path, Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns whether `path` was pinned.
+    pub fn unpin(&self, path: &str) -> Result<bool, ClientError> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM pinned_paths WHERE path = ?1", [path])?;
+        Ok(removed > 0)
+    }
+
+    pub fn pinned(&self) -> Result<Vec<String>, ClientError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare("SELECT path FROM pinned_paths ORDER BY path")?;
+        let rows = statement.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Checksum and size of the plaintext of encrypted content, if known.
+    pub fn encrypted_content(&self, checksum: &str) -> Result<Option<(String, u64)>, ClientError> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT plain_checksum, plain_size FROM encrypted_contents WHERE checksum = ?1",
+                [checksum],
+                |row| Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64)),
+            )
+            .optional()?)
+    }
+
+    pub fn set_encrypted_content(
+        &self,
+        checksum: &str,
+        plain_checksum: &str,
+        plain_size: u64,
+    ) -> Result<(), ClientError> {
+        self.conn().execute(
+            "INSERT INTO encrypted_contents (checksum, plain_checksum, plain_size)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT (checksum) DO NOTHING",
+            params![
+                checksum,
+                plain_checksum,
+                i64::try_from(plain_size).unwrap_or(i64::MAX)
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+fn sync_error(e: rusqlite::Error) -> SyncError {
+    SyncError::Engine(format!("state database: {}", e))
+}
+
+impl BaseStateStore for LocalDb {
+    fn load(&self, folder: &SyncFolder) -> Result<BaseState, SyncError> {
+        let state: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT state FROM base_states WHERE folder_key = ?1",
+                [folder_key(folder)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sync_error)?;
+        match state {
+            Some(state) => Ok(serde_json::from_str(&state)?),
+            None => Ok(BaseState::default()),
+        }
+    }
+
+    fn save(&self, folder: &SyncFolder, state: &BaseState) -> Result<(), SyncError> {
+        let json = serde_json::to_string(state)?;
+        self.conn()
+            .execute(
+                "INSERT INTO base_states (folder_key, state) VALUES (?1, ?2)
+                 ON CONFLICT (folder_key) DO UPDATE SET state = excluded.state",
+                params![folder_key(folder), json],
+            )
+            .map_err(sync_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::sync::{FileChange, SyncDirection, SyncFilters, SyncStatus};
+
+    fn change(path: &str, change_type: ChangeType, checksum: Option<&str>) -> FileChange {
+        FileChange {
+            path: path.to_string(),
+            change_type,
+            metadata: None,
+            checksum: checksum.map(str::to_string),
+            delta: None,
+        }
+    }
+
+    fn response(token: &str, changes: Vec<FileChange>) -> SyncResponse {
+        SyncResponse {
+            sync_token: token.to_string(),
+            changes,
+            conflicts: Vec::new(),
+            deleted_files: Vec::new(),
+        }
+    }
+
+    fn file_state(path: &str, checksum: &str, size: u64) -> FileState {
+        FileState {
+            path: path.to_string(),
+            checksum: checksum.to_string(),
+            size,
+            modified_at: Utc::now(),
+            version: 1,
+            sync_status: SyncStatus::Synced,
+        }
+    }
+
+    fn remote_paths(db: &LocalDb) -> Vec<(String, String)> {
+        let mut files: Vec<_> = db
+            .remote_files("")
+            .unwrap()
+            .into_iter()
+            .map(|state| (state.path, state.hash))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_settings_are_replaced() {
+        let db = LocalDb::open_in_memory().unwrap();
+        assert_eq!(db.setting(SETTING_DEVICE_ID).unwrap(), None);
+        db.set_setting(SETTING_DEVICE_ID, "one").unwrap();
+        db.set_setting(SETTING_DEVICE_ID, "two").unwrap();
+        assert_eq!(
+            db.setting(SETTING_DEVICE_ID).unwrap().as_deref(),
+            Some("two")
+        );
+    }
+
+    #[test]
+    fn test_sync_responses_update_the_remote_mirror() {
+        let db = LocalDb::open_in_memory().unwrap();
+        db.apply_sync_response(&response(
+            "t1",
+            vec![
+                change("docs/a.txt", ChangeType::Created, Some("a1")),
+                change("docs/b.txt", ChangeType::Created, Some("b1")),
+                change("docs/c.txt", ChangeType::Created, Some("c1")),
+                // Nothing to record without a checksum.
+                change("docs/dir", ChangeType::Created, None),
+            ],
+        ))
+        .unwrap();
+        let mut second = response(
+            "t2",
+            vec![
+                change("docs/a.txt", ChangeType::Modified, Some("a2")),
+                change(
+                    "docs/moved.txt",
+                    ChangeType::Moved {
+                        old_path: "docs/b.txt".to_string(),
+                    },
+                    Some("b1"),
+                ),
+                change("docs/c.txt", ChangeType::Deleted, None),
+            ],
+        );
+        second.deleted_files.push("docs/gone.txt".to_string());
+        db.apply_sync_response(&second).unwrap();
+
+        assert_eq!(
+            remote_paths(&db),
+            vec![
+                ("docs/a.txt".to_string(), "a2".to_string()),
+                ("docs/moved.txt".to_string(), "b1".to_string()),
+            ]
+        );
+        assert_eq!(
+            db.setting(SETTING_SYNC_TOKEN).unwrap().as_deref(),
+            Some("t2")
+        );
+    }
+
+    #[test]
+    fn test_reset_remote_forgets_the_mirror_and_token() {
+        let db = LocalDb::open_in_memory().unwrap();
+        db.set_setting(SETTING_DEVICE_ID, "device").unwrap();
+        db.apply_sync_response(&response(
+            "t1",
+            vec![change("a.txt", ChangeType::Created, Some("a"))],
+        ))
+        .unwrap();
+        db.reset_remote().unwrap();
+        assert!(remote_paths(&db).is_empty());
+        assert_eq!(db.setting(SETTING_SYNC_TOKEN).unwrap(), None);
+        assert_eq!(
+            db.setting(SETTING_DEVICE_ID).unwrap().as_deref(),
+            Some("device")
+        );
+    }
+
+    #[test]
+    fn test_remote_files_are_relative_to_the_prefix() {
+        let db = LocalDb::open_in_memory().unwrap();
+        for path in ["docs/a.txt", "docs/sub/b.txt", "docs2/c.txt", "other.txt"] {
+            db.set_remote(&file_state(path, "x", 1)).unwrap();
+        }
+        let mut paths: Vec<String> = db
+            .remote_files("docs")
+            .unwrap()
+            .into_iter()
+            .map(|state| state.path)
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["a.txt", "sub/b.txt"]);
+        assert_eq!(db.remote_files("").unwrap().len(), 4);
+        db.remove_remote("other.txt").unwrap();
+        assert!(db.remote("other.txt").unwrap().is_none());
+        assert_eq!(db.remote("docs/a.txt").unwrap().unwrap().size, 1);
+    }
+
+    #[test]
+    fn test_cached_hashes_need_the_same_size_and_mtime() {
+        let db = LocalDb::open_in_memory().unwrap();
+        let path = Path::new("/sync/a.txt");
+        db.store_hash(path, 10, 1000, "hash").unwrap();
+        assert_eq!(
+            db.cached_hash(path, 10, 1000).unwrap().as_deref(),
+            Some("hash")
+        );
+        assert_eq!(db.cached_hash(path, 11, 1000).unwrap(), None);
+        assert_eq!(db.cached_hash(path, 10, 1001).unwrap(), None);
+        db.store_hash(path, 11, 1001, "new").unwrap();
+        assert_eq!(
+            db.cached_hash(path, 11, 1001).unwrap().as_deref(),
+            Some("new")
+        );
+    }
+
+    #[test]
+    fn test_transfers_are_kept_until_finished() {
+        let db = LocalDb::open_in_memory().unwrap();
+        let transfer = Transfer {
+            path: "a.bin".to_string(),
+            checksum: "v1".to_string(),
+            partial_path: PathBuf::from("/state/partial/1.part"),
+        };
+        db.start_transfer(&transfer).unwrap();
+        let resumed = db.transfer("a.bin").unwrap().unwrap();
+        assert_eq!(resumed.checksum, "v1");
+        assert_eq!(resumed.partial_path, transfer.partial_path);
+
+        // A newer version replaces the record.
+        db.start_transfer(&Transfer {
+            checksum: "v2".to_string(),
+            ..transfer
+        })
+        .unwrap();
+        assert_eq!(db.transfer("a.bin").unwrap().unwrap().checksum, "v2");
+        db.finish_transfer("a.bin").unwrap();
+        assert!(db.transfer("a.bin").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_conflicts_are_listed_by_path() {
+        let db = LocalDb::open_in_memory().unwrap();
+        db.record_conflict("b.txt", "BothModified").unwrap();
+        db.record_conflict("a.txt", "BothModified").unwrap();
+        db.record_conflict("a.txt", "ModifiedDeleted").unwrap();
+        let conflicts = db.conflicts().unwrap();
+        let listed: Vec<(&str, &str)> = conflicts
+            .iter()
+            .map(|c| (c.path.as_str(), c.conflict_type.as_str()))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![("a.txt", "ModifiedDeleted"), ("b.txt", "BothModified")]
+        );
+        db.clear_conflict("a.txt").unwrap();
+        assert_eq!(db.conflicts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_evictable_contents_skip_pinned_files() {
+        let db = LocalDb::open_in_memory().unwrap();
+        db.set_remote(&file_state("docs/a.txt", "a", 1)).unwrap();
+        db.set_remote(&file_state("docs2/b.txt", "b", 2)).unwrap();
+        db.set_remote(&file_state("c.txt", "c", 3)).unwrap();
+        for checksum in ["c", "a", "b"] {
+            db.touch_cached(checksum, 1).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        db.touch_cached("c", 1).unwrap();
+        let order = |db: &LocalDb| -> Vec<String> {
+            db.evictable()
+                .unwrap()
+                .into_iter()
+                .map(|(checksum, _)| checksum)
+                .collect()
+        };
+        assert_eq!(order(&db), vec!["a", "b", "c"]);
+
+        // Pinning a directory keeps what is under it, but not its siblings.
+        db.pin("docs").unwrap();
+        assert_eq!(order(&db), vec!["b", "c"]);
+        assert!(db.unpin("docs").unwrap());
+        assert!(!db.unpin("docs").unwrap());
+        assert_eq!(order(&db), vec!["a", "b", "c"]);
+        db.remove_cached("a").unwrap();
+        assert_eq!(db.cached_size().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_base_states_are_kept_per_folder() {
+        let db = LocalDb::open_in_memory().unwrap();
+        let folder = |local_path: &str| SyncFolder {
+            local_path: local_path.to_string(),
+            remote_path: "/docs".to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active: true,
+            filters: SyncFilters::default(),
+        };
+        let mut state = BaseState::default();
+        state.entries.insert(
+            "a.txt".to_string(),
+            remote_state(
+                "a.txt".to_string(),
+                "a".to_string(),
+                3,
+                Utc::now().to_rfc3339(),
+            ),
+        );
+        db.save(&folder("/home/docs"), &state).unwrap();
+        assert_eq!(
+            db.load(&folder("/home/docs")).unwrap().entries,
+            state.entries
+        );
+        assert!(db.load(&folder("/home/other")).unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_clear_forgets_everything() {
+        let db = LocalDb::open_in_memory().unwrap();
+        db.set_setting(SETTING_DEVICE_ID, "device").unwrap();
+        db.set_remote(&file_state("a.txt", "a", 1)).unwrap();
+        db.pin("a.txt").unwrap();
+        db.touch_cached("a", 1).unwrap();
+        db.clear().unwrap();
+        assert_eq!(db.setting(SETTING_DEVICE_ID).unwrap(), None);
+        assert!(remote_paths(&db).is_empty());
+        assert!(db.pinned().unwrap().is_empty());
+        assert_eq!(db.cached_size().unwrap(), 0);
+    }
+}
@@ -0,0 +1,283 @@
+//! End-to-end encrypted folders
+//!
+//! The device's X25519 key pair is created on first start and kept in the
+//! local database; its public half is given to the server, which hands
+//! out folder keys sealed for it. Below an encrypted folder every path
+//! component is encrypted with the folder's [`NameCipher`] and every file
+//! is uploaded as content from [`crypto::encrypt_content`], so the server
+//! sees neither names nor contents.
+//!
+//! The sync engine plans in the clear: [`remote_files`] turns the remote
+//! mirror's encrypted paths back into names, and the checksums of encrypted
+//! contents into those of their plaintext, which it reads from the
+//! content's header the first time and remembers in the database.
+//!
+//! A folder key is only sealed for another device once the user approved
+//! it, having compared its [`crypto::key_fingerprint`] with the one that
+//! device shows: the server lists the devices waiting for a key, but
+//! whoever controls it could list a key of their own.
+
+use crate::api::ApiClient;
+use crate::db::{LocalDb, SETTING_DEVICE_KEY};
+use crate::error::ClientError;
+use crate::transfer::remote_path;
+use crypto::{DeviceKeyPair, EncryptionKey, NameCipher, PasswordKeyParams};
+use protocol::sync::{DeviceKey, EncryptedFolder, SealedFolderKey};
+use sync_core::{SyncError, SyncState};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+const RECOVERY_KEY_CONTEXT: &str = "rouillecloud 2024-06-01 e2ee recovery key";
+
+/// The device's key pair, created and stored on first use.
+pub fn device_keys(db: &LocalDb) -> Result<DeviceKeyPair, ClientError> {
+    if let Some(secret) = db.setting(SETTING_DEVICE_KEY)? {
+        let secret: [u8; 32] = crypto::from_base64(&secret)?
+            .try_into()
+            .map_err(|_| ClientError::Config("invalid device key in the database".to_string()))?;
+        return Ok(DeviceKeyPair::from_secret(secret));
+    }
+    let keys = DeviceKeyPair::generate();
+    db.set_setting(
+        SETTING_DEVICE_KEY,
+        &crypto::to_base64(&*keys.secret_bytes()),
+    )?;
+    Ok(keys)
+}
+
+/// Key pair derived from a recovery passphrase with `params`.
+pub fn recovery_keys(
+    passphrase: &str,
+    params: &PasswordKeyParams,
+) -> Result<DeviceKeyPair, ClientError> {
+    let key = params.derive(passphrase, RECOVERY_KEY_CONTEXT)?;
+    Ok(DeviceKeyPair::from_secret(*key.as_bytes()))
+}
+
+fn public_key(device: &DeviceKey) -> Result<[u8; 32], ClientError> {
+    Ok(crypto::from_base64(&device.public_key)?
+        .try_into()
+        .map_err(|_| crypto::CryptoError::InvalidPublicKey)?)
+}
+
+/// The fingerprint of a device's public key, for the user to compare with
+/// the one the device shows.
+pub fn fingerprint(device: &DeviceKey) -> Result<String, ClientError> {
+    Ok(crypto::key_fingerprint(&public_key(device)?))
+}
+
+/// A folder key sealed for each of `devices`, which the user approved.
+pub fn seal_for(
+    key: &EncryptionKey,
+    devices: &[DeviceKey],
+) -> Result<Vec<SealedFolderKey>, ClientError> {
+    devices
+        .iter()
+        .map(|device| {
+            Ok(SealedFolderKey {
+                device_id: device.device_id,
+                sealed_key: crypto::to_base64(&crypto::seal_key(&public_key(device)?, key)?),
+            })
+        })
+        .collect()
+}
+
+fn under<'a>(root: &str, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(root) {
+        Some("") => Some(""),
+        Some(rest) => rest.strip_prefix('/'),
+        None => None,
+    }
+}
+
+/// An encrypted folder this device holds the key of.
+pub struct FolderKey {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    /// Remote path of the folder, in the owner's files; the folder's own
+    /// name is not encrypted.
+    pub path: String,
+    /// Devices of the account waiting for the key, to be approved.
+    pub pending_devices: Vec<DeviceKey>,
+    key: EncryptionKey,
+    names: NameCipher,
+}
+
+impl FolderKey {
+    fn new(folder: &EncryptedFolder, key: EncryptionKey) -> Self {
+        Self {
+            id: folder.id,
+            owner_id: folder.owner_id,
+            path: folder.path.clone(),
+            pending_devices: folder.pending_devices.clone(),
+            names: NameCipher::new(&key),
+            key,
+        }
+    }
+
+    pub fn key(&self) -> &EncryptionKey {
+        &self.key
+    }
+
+    /// Whether `path` is the folder or below it.
+    pub fn contains(&self, path: &str) -> bool {
+        under(&self.path, path).is_some()
+    }
+
+    /// Map the components of `path` below the folder with `map`.
+    fn map_path(
+        &self,
+        path: &str,
+        map: impl Fn(&str) -> Result<String, crypto::CryptoError>,
+    ) -> Result<String, ClientError> {
+        let relative = under(&self.path, path).ok_or_else(|| {
+            ClientError::Usage(format!(
+                "{} is not in the encrypted folder {}",
+                path, self.path
+            ))
+        })?;
+        let mut mapped = self.path.clone();
+        for component in relative.split('/').filter(|c| !c.is_empty()) {
+            mapped.push('/');
+            mapped.push_str(&map(component)?);
+        }
+        Ok(mapped)
+    }
+
+    /// The remote path of the file at `path` in the clear.
+    pub fn encrypt_path(&self, path: &str) -> Result<String, ClientError> {
+        self.map_path(path, |name| self.names.encrypt(name))
+    }
+
+    pub fn decrypt_path(&self, path: &str) -> Result<String, ClientError> {
+        self.map_path(path, |name| self.names.decrypt(name))
+    }
+}
+
+/// The encrypted folders the account owns or was given.
+pub struct EncryptedFolders {
+    user_id: Uuid,
+    folders: Vec<FolderKey>,
+    /// The account's own folders this device has no key to yet.
+    locked: Vec<String>,
+}
+
+impl EncryptedFolders {
+    /// No encrypted folders, everything in the clear.
+    pub fn none(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            folders: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+
+    /// Fetch the folders and open their keys. Devices waiting for a key are
+    /// left for the user to approve.
+    pub async fn load(
+        api: &ApiClient,
+        keys: &DeviceKeyPair,
+        user_id: Uuid,
+    ) -> Result<Self, ClientError> {
+        let mut loaded = Self::none(user_id);
+        for folder in api.encrypted_folders().await? {
+            let Some(sealed) = &folder.sealed_key else {
+                if folder.owner_id == user_id {
+                    loaded.locked.push(folder.path.clone());
+                }
+                continue;
+            };
+            let key = keys.open_key(&crypto::from_base64(sealed)?)?;
+            loaded.folders.push(FolderKey::new(&folder, key));
+        }
+        Ok(loaded)
+    }
+
+    /// Folders this device can decrypt.
+    pub fn folders(&self) -> &[FolderKey] {
+        &self.folders
+    }
+
+    /// The folder of the account's own files that `path` is in, if this
+    /// device has its key.
+    pub fn folder_of(&self, path: &str) -> Option<&FolderKey> {
+        self.folders
+            .iter()
+            .find(|folder| folder.owner_id == self.user_id && folder.contains(path))
+    }
+
+    /// Whether `path` is in one of the account's folders this device cannot
+    /// decrypt yet.
+    pub fn is_locked(&self, path: &str) -> bool {
+        self.locked.iter().any(|root| under(root, path).is_some())
+    }
+
+    /// The remote path of the account's file at `path` in the clear.
+    pub fn encrypt_path(&self, path: &str) -> Result<String, ClientError> {
+        if self.is_locked(path) {
+            return Err(ClientError::Sync(SyncError::Engine(format!(
+                "{} is in an encrypted folder this device has no key to yet",
+                path
+            ))));
+        }
+        match self.folder_of(path) {
+            Some(folder) => folder.encrypt_path(path),
+            None => Ok(path.to_string()),
+        }
+    }
+
+    /// Whether `from` and `to` are in the same encrypted folder, or both
+    /// outside any, so the server can move one to the other.
+    pub fn same_folder(&self, from: &str, to: &str) -> bool {
+        let id = |path| self.folder_of(path).map(|folder| folder.id);
+        id(from) == id(to)
+    }
+}
+
+/// Remote files under `prefix` (every file when empty), with paths relative
+/// to it, as the sync engine plans with them: in the clear, encrypted or
+/// not. Files in folders this device has no key to are left out.
+pub fn remote_files(
+    runtime: &Handle,
+    api: &ApiClient,
+    db: &LocalDb,
+    folders: &EncryptedFolders,
+    prefix: &str,
+) -> Result<Vec<SyncState>, ClientError> {
+    let stored_prefix = folders.encrypt_path(prefix)?;
+    let mut files = Vec::new();
+    for mut state in db.remote_files(&stored_prefix)? {
+        let stored = remote_path(&stored_prefix, &state.path);
+        let Some(folder) = folders.folder_of(&stored) else {
+            if !folders.is_locked(&stored) {
+                files.push(state);
+            }
+            continue;
+        };
+        let path = folder.decrypt_path(&stored)?;
+        state.path = match prefix {
+            "" => path.clone(),
+            prefix => under(prefix, &path).unwrap_or(&path).to_string(),
+        };
+        let (checksum, size) = match db.encrypted_content(&state.hash)? {
+            Some(known) => known,
+            None => {
+                let (header, checksum) =
+                    runtime.block_on(api.read_prefix(&stored, crypto::CONTENT_HEADER_LEN))?;
+                if checksum.as_deref() != Some(state.hash.as_str()) {
+                    return Err(ClientError::Sync(SyncError::Conflict(format!(
+                        "{} changed on the server",
+                        path
+                    ))));
+                }
+                let info = crypto::content_info(folder.key(), &header)?;
+                db.set_encrypted_content(&state.hash, &info.checksum, info.size)?;
+                (info.checksum, info.size)
+            }
+        };
+        state.hash = checksum;
+        state.size = size;
+        files.push(state);
+    }
+    Ok(files)
+}
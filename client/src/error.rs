@@ -0,0 +1,317 @@
+//! Client error type
+//!
+//! Command-line exit codes follow the BSD `sysexits.h` conventions, so
+//! scripts can tell a missing file from a rejected login or a network
+//! failure.
+
+use protocol::errors::ApiError;
+use reqwest::StatusCode;
+use sync_core::SyncError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Server returned {status}: {message}")]
+    Api {
+        status: StatusCode,
+        /// Structured error, when the server sent one.
+        error: Option<ApiError>,
+        message: String,
+    },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("Sync error: {0}")]
+    Sync(#[from] SyncError),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("Encryption error: {0}")]
+    Crypto(#[from] crypto::CryptoError),
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// A command was given arguments it cannot act on.
+    #[error("{0}")]
+    Usage(String),
+}
+
+impl ClientError {
+    /// The server's structured error, if any.
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            ClientError::Api { error, .. } => error.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The device was revoked or told to wipe: syncing cannot go on.
+    pub fn is_device_terminal(&self) -> bool {
+        matches!(
+            self.api_error(),
+            Some(ApiError::DeviceRevoked | ApiError::WipeRequested)
+        )
+    }
+}
+
+/// Exit codes of `sysexits.h`.
+pub mod exit {
+    pub const FAILURE: u8 = 1;
+    pub const USAGE: u8 = 64;
+    pub const DATAERR: u8 = 65;
+    pub const NOINPUT: u8 = 66;
+    pub const UNAVAILABLE: u8 = 69;
+    pub const SOFTWARE: u8 = 70;
+    pub const IOERR: u8 = 74;
+    pub const TEMPFAIL: u8 = 75;
+    pub const NOPERM: u8 = 77;
+    pub const CONFIG: u8 = 78;
+}
+
+fn api_exit_code(error: &ApiError) -> u8 {
+    match error {
+        ApiError::Unauthorized
+        | ApiError::Forbidden
+        | ApiError::InvalidCredentials
+        | ApiError::TokenExpired
+        | ApiError::MfaRequired
+        | ApiError::AccessDenied
+        | ApiError::DeviceNotFound
+        | ApiError::DeviceRevoked
+        | ApiError::WipeRequested => exit::NOPERM,
+        ApiError::FileNotFound | ApiError::CalendarNotFound | ApiError::EventNotFound => {
+            exit::NOINPUT
+        }
+        ApiError::SyncConflict
+        | ApiError::FileAlreadyExists
+        | ApiError::InvalidSyncToken
+        | ApiError::WebDavPreconditionFailed
+        | ApiError::WebDavLocked
+        | ApiError::WebDavConflict
+        | ApiError::CalendarConflict
+        | ApiError::RateLimitExceeded => exit::TEMPFAIL,
+        ApiError::InvalidFileName
+        | ApiError::FileTooLarge
+        | ApiError::InvalidCalendarData
+        | ApiError::WebDavMethodNotAllowed
+        | ApiError::ValidationError { .. }
+        | ApiError::InvalidRequest { .. } => exit::DATAERR,
+        ApiError::ServiceUnavailable | ApiError::NetworkError => exit::UNAVAILABLE,
+        ApiError::InsufficientStorage | ApiError::StorageError => exit::IOERR,
+        ApiError::InternalError | ApiError::DatabaseError => exit::SOFTWARE,
+        ApiError::Custom { .. } => exit::FAILURE,
+    }
+}
+
+fn status_exit_code(status: StatusCode) -> u8 {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => exit::NOPERM,
+        StatusCode::NOT_FOUND | StatusCode::GONE => exit::NOINPUT,
+        StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED | StatusCode::TOO_MANY_REQUESTS => {
+            exit::TEMPFAIL
+        }
+        StatusCode::BAD_REQUEST
+        | StatusCode::PAYLOAD_TOO_LARGE
+        | StatusCode::RANGE_NOT_SATISFIABLE
+        | StatusCode::UNPROCESSABLE_ENTITY => exit::DATAERR,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            exit::UNAVAILABLE
+        }
+        status if status.is_server_error() => exit::SOFTWARE,
+        _ => exit::FAILURE,
+    }
+}
+
+impl ClientError {
+    /// Process exit code for this error, see [`exit`].
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ClientError::Api {
+                error: Some(error), ..
+            } => api_exit_code(error),
+            ClientError::Api { status, .. } => status_exit_code(*status),
+            ClientError::Http(e) if e.is_connect() || e.is_timeout() => exit::UNAVAILABLE,
+            ClientError::Http(_) => exit::FAILURE,
+            ClientError::Io(_) | ClientError::Sync(SyncError::Io(_)) => exit::IOERR,
+            ClientError::Database(_) => exit::SOFTWARE,
+            ClientError::Sync(SyncError::Network(_)) => exit::UNAVAILABLE,
+            ClientError::Sync(SyncError::Conflict(_)) => exit::TEMPFAIL,
+            ClientError::Sync(SyncError::ChecksumMismatch) => exit::DATAERR,
+            ClientError::Sync(_) => exit::FAILURE,
+            ClientError::Serialization(_) | ClientError::Crypto(_) => exit::DATAERR,
+            ClientError::Config(_) => exit::CONFIG,
+            ClientError::NotFound(_) => exit::NOINPUT,
+            ClientError::Usage(_) => exit::USAGE,
+        }
+    }
+}
+
+impl From<config::ConfigError> for ClientError {
+    fn from(e: config::ConfigError) -> Self {
+        ClientError::Config(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api(status: StatusCode, error: Option<ApiError>) -> ClientError {
+        ClientError::Api {
+            status,
+            error,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_api_error_exit_codes() {
+        let cases = [
+            (ApiError::Unauthorized, exit::NOPERM),
+            (ApiError::Forbidden, exit::NOPERM),
+            (ApiError::InvalidCredentials, exit::NOPERM),
+            (ApiError::TokenExpired, exit::NOPERM),
+            (ApiError::MfaRequired, exit::NOPERM),
+            (ApiError::AccessDenied, exit::NOPERM),
+            (ApiError::DeviceNotFound, exit::NOPERM),
+            (ApiError::DeviceRevoked, exit::NOPERM),
+            (ApiError::WipeRequested, exit::NOPERM),
+            (ApiError::FileNotFound, exit::NOINPUT),
+            (ApiError::CalendarNotFound, exit::NOINPUT),
+            (ApiError::EventNotFound, exit::NOINPUT),
+            (ApiError::SyncConflict, exit::TEMPFAIL),
+            (ApiError::FileAlreadyExists, exit::TEMPFAIL),
+            (ApiError::InvalidSyncToken, exit::TEMPFAIL),
+            (ApiError::WebDavPreconditionFailed, exit::TEMPFAIL),
+            (ApiError::WebDavLocked, exit::TEMPFAIL),
+            (ApiError::WebDavConflict, exit::TEMPFAIL),
+            (ApiError::CalendarConflict, exit::TEMPFAIL),
+            (ApiError::RateLimitExceeded, exit::TEMPFAIL),
+            (ApiError::InvalidFileName, exit::DATAERR),
+            (ApiError::FileTooLarge, exit::DATAERR),
+            (ApiError::InvalidCalendarData, exit::DATAERR),
+            (ApiError::WebDavMethodNotAllowed, exit::DATAERR),
+            (
+                ApiError::ValidationError {
+                    field: "name".to_string(),
+                    message: "empty".to_string(),
+                },
+                exit::DATAERR,
+            ),
+            (
+                ApiError::InvalidRequest {
+                    message: "bad".to_string(),
+                },
+                exit::DATAERR,
+            ),
+            (ApiError::ServiceUnavailable, exit::UNAVAILABLE),
+            (ApiError::NetworkError, exit::UNAVAILABLE),
+            (ApiError::InsufficientStorage, exit::IOERR),
+            (ApiError::StorageError, exit::IOERR),
+            (ApiError::InternalError, exit::SOFTWARE),
+            (ApiError::DatabaseError, exit::SOFTWARE),
+            (
+                ApiError::Custom {
+                    code: "X".to_string(),
+                    message: "x".to_string(),
+                },
+                exit::FAILURE,
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(api_exit_code(&error), code, "{:?}", error);
+            // The structured error wins over the status.
+            assert_eq!(
+                api(StatusCode::IM_A_TEAPOT, Some(error.clone())).exit_code(),
+                code,
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_status_exit_codes() {
+        let cases = [
+            (StatusCode::UNAUTHORIZED, exit::NOPERM),
+            (StatusCode::FORBIDDEN, exit::NOPERM),
+            (StatusCode::NOT_FOUND, exit::NOINPUT),
+            (StatusCode::GONE, exit::NOINPUT),
+            (StatusCode::CONFLICT, exit::TEMPFAIL),
+            (StatusCode::PRECONDITION_FAILED, exit::TEMPFAIL),
+            (StatusCode::TOO_MANY_REQUESTS, exit::TEMPFAIL),
+            (StatusCode::BAD_REQUEST, exit::DATAERR),
+            (StatusCode::PAYLOAD_TOO_LARGE, exit::DATAERR),
+            (StatusCode::RANGE_NOT_SATISFIABLE, exit::DATAERR),
+            (StatusCode::UNPROCESSABLE_ENTITY, exit::DATAERR),
+            (StatusCode::BAD_GATEWAY, exit::UNAVAILABLE),
+            (StatusCode::SERVICE_UNAVAILABLE, exit::UNAVAILABLE),
+            (StatusCode::GATEWAY_TIMEOUT, exit::UNAVAILABLE),
+            (StatusCode::INTERNAL_SERVER_ERROR, exit::SOFTWARE),
+            (StatusCode::NOT_IMPLEMENTED, exit::SOFTWARE),
+            (StatusCode::IM_A_TEAPOT, exit::FAILURE),
+            (StatusCode::METHOD_NOT_ALLOWED, exit::FAILURE),
+        ];
+        for (status, code) in cases {
+            assert_eq!(status_exit_code(status), code, "{}", status);
+            assert_eq!(api(status, None).exit_code(), code, "{}", status);
+        }
+    }
+
+    #[test]
+    fn test_client_error_exit_codes() {
+        let io = || std::io::Error::other("disk");
+        let cases = [
+            (ClientError::Io(io()), exit::IOERR),
+            (ClientError::Sync(SyncError::Io(io())), exit::IOERR),
+            (
+                ClientError::Database(rusqlite::Error::InvalidQuery),
+                exit::SOFTWARE,
+            ),
+            (
+                ClientError::Sync(SyncError::Network("reset".to_string())),
+                exit::UNAVAILABLE,
+            ),
+            (
+                ClientError::Sync(SyncError::Conflict("a.txt".to_string())),
+                exit::TEMPFAIL,
+            ),
+            (
+                ClientError::Sync(SyncError::ChecksumMismatch),
+                exit::DATAERR,
+            ),
+            (
+                ClientError::Sync(SyncError::Engine("plan".to_string())),
+                exit::FAILURE,
+            ),
+            (
+                ClientError::Serialization(serde_json::from_str::<u8>("x").unwrap_err()),
+                exit::DATAERR,
+            ),
+            (ClientError::Config("no server".to_string()), exit::CONFIG),
+            (ClientError::NotFound("a.txt".to_string()), exit::NOINPUT),
+            (ClientError::Usage("no path".to_string()), exit::USAGE),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_revoked_and_wiped_devices_are_terminal() {
+        let terminal = |error| api(StatusCode::FORBIDDEN, Some(error)).is_device_terminal();
+        assert!(terminal(ApiError::DeviceRevoked));
+        assert!(terminal(ApiError::WipeRequested));
+        assert!(!terminal(ApiError::Forbidden));
+        assert!(!api(StatusCode::FORBIDDEN, None).is_device_terminal());
+    }
+}
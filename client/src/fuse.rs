@@ -0,0 +1,879 @@
+//! Mounting virtual files with FUSE
+//!
+//! [`Mount`] serves a [`VirtualFs`] to the kernel by speaking the FUSE
+//! protocol on `/dev/fuse` itself: a few worker threads each read a
+//! request, call the file system and write the reply, so a file being
+//! fetched does not hold up the rest of the mount. Run as root, it mounts with
+//! `mount(2)`; otherwise `fusermount3` (or `fusermount`) mounts it and
+//! hands the device back over a socket, as libfuse does.
+//!
+//! Permissions are checked by the kernel (`default_permissions`): files
+//! and directories belong to the user who mounted them. Changing modes,
+//! owners or times is accepted and ignored, as the server keeps none.
+//!
+//! The protocol is spoken here rather than through libfuse or the `fuser`
+//! crate. The few opcodes a file system without modes, owners or links
+//! needs fit in this module, building needs no C library or headers, and
+//! several requests are served at once, where `fuser` runs its session
+//! from a single thread. The price is getting the kernel's structure
+//! layouts right, so the parsing of requests and the replies written are
+//! checked against them in the tests below.
+
+use crate::error::ClientError;
+use crate::vfs::{Attributes, Kind, VfsError, VirtualFs};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+/// Largest write the kernel sends in one request.
+const MAX_WRITE: u32 = 128 * 1024;
+/// Room for a write request and its headers.
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+/// Threads answering requests; as many may wait on the server at once.
+const WORKERS: usize = 8;
+/// Size of `fuse_init_out` at the minor version we speak.
+const INIT_OUT_LEN: usize = 64;
+/// How long the kernel may trust names and attributes before asking again.
+const TTL: Duration = Duration::from_secs(1);
+const FS_NAME: &str = "rouillecloud";
+
+const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+const FATTR_SIZE: u32 = 1 << 3;
+const RENAME_NOREPLACE: u32 = 1;
+
+mod opcode {
+    pub const LOOKUP: u32 = 1;
+    pub const FORGET: u32 = 2;
+    pub const GETATTR: u32 = 3;
+    pub const SETATTR: u32 = 4;
+    pub const MKDIR: u32 = 9;
+    pub const UNLINK: u32 = 10;
+    pub const RMDIR: u32 = 11;
+    pub const RENAME: u32 = 12;
+    pub const OPEN: u32 = 14;
+    pub const READ: u32 = 15;
+    pub const WRITE: u32 = 16;
+    pub const STATFS: u32 = 17;
+    pub const RELEASE: u32 = 18;
+    pub const FSYNC: u32 = 20;
+    pub const FLUSH: u32 = 25;
+    pub const INIT: u32 = 26;
+    pub const OPENDIR: u32 = 27;
+    pub const READDIR: u32 = 28;
+    pub const RELEASEDIR: u32 = 29;
+    pub const FSYNCDIR: u32 = 30;
+    pub const ACCESS: u32 = 34;
+    pub const CREATE: u32 = 35;
+    pub const INTERRUPT: u32 = 36;
+    pub const DESTROY: u32 = 38;
+    pub const BATCH_FORGET: u32 = 42;
+    pub const RENAME2: u32 = 45;
+}
+
+fn errno(error: &VfsError) -> i32 {
+    match error {
+        VfsError::NotFound => libc::ENOENT,
+        VfsError::Exists => libc::EEXIST,
+        VfsError::NotEmpty => libc::ENOTEMPTY,
+        VfsError::IsDir => libc::EISDIR,
+        VfsError::NotDir => libc::ENOTDIR,
+        VfsError::InvalidName => libc::EINVAL,
+        VfsError::Client(ClientError::Io(e)) => e.raw_os_error().unwrap_or(libc::EIO),
+        VfsError::Client(e) if e.status().is_some_and(|s| s.as_u16() == 403) => libc::EACCES,
+        VfsError::Client(e) if e.status().is_some_and(|s| s.as_u16() == 409) => libc::EBUSY,
+        VfsError::Client(_) => libc::EIO,
+    }
+}
+
+/// Fields of a request body, in order.
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < n {
+            return Err(libc::EINVAL);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A NUL-terminated file name.
+    fn name(&mut self) -> Result<&'a str, i32> {
+        let end = self.0.iter().position(|&b| b == 0).ok_or(libc::EINVAL)?;
+        let name = std::str::from_utf8(&self.0[..end]).map_err(|_| libc::EINVAL)?;
+        self.0 = &self.0[end + 1..];
+        Ok(name)
+    }
+}
+
+/// `fuse_in_header`, without the caller's credentials.
+#[derive(Debug, PartialEq, Eq)]
+struct InHeader {
+    opcode: u32,
+    unique: u64,
+    node: u64,
+}
+
+impl InHeader {
+    fn parse(body: &mut Body) -> Result<Self, i32> {
+        let _len = body.u32()?;
+        let opcode = body.u32()?;
+        let unique = body.u64()?;
+        let node = body.u64()?;
+        // uid, gid, pid and padding
+        body.bytes(16)?;
+        Ok(Self {
+            opcode,
+            unique,
+            node,
+        })
+    }
+}
+
+/// `fuse_write_in` and the data following it.
+#[derive(Debug, PartialEq, Eq)]
+struct WriteIn<'a> {
+    fh: u64,
+    offset: u64,
+    data: &'a [u8],
+}
+
+impl<'a> WriteIn<'a> {
+    fn parse(body: &mut Body<'a>) -> Result<Self, i32> {
+        let fh = body.u64()?;
+        let offset = body.u64()?;
+        let size = body.u32()?;
+        // write_flags, lock_owner, flags and padding
+        body.bytes(20)?;
+        let data = body.bytes(size as usize)?;
+        Ok(Self { fh, offset, data })
+    }
+}
+
+/// The parts of `fuse_setattr_in` that are acted on.
+#[derive(Debug, PartialEq, Eq)]
+struct SetattrIn {
+    valid: u32,
+    size: u64,
+}
+
+impl SetattrIn {
+    fn parse(body: &mut Body) -> Result<Self, i32> {
+        let valid = body.u32()?;
+        // padding and fh
+        body.bytes(12)?;
+        let size = body.u64()?;
+        Ok(Self { valid, size })
+    }
+}
+
+/// The `fuse_init_out` answering a `fuse_init_in`.
+fn init(body: &mut Body) -> Result<Reply, i32> {
+    let major = body.u32()?;
+    let _minor = body.u32()?;
+    let max_readahead = body.u32()?;
+    let flags = body.u32()?;
+    if major < KERNEL_VERSION {
+        return Err(libc::EPROTO);
+    }
+    let mut reply = Reply::default();
+    reply
+        .u32(KERNEL_VERSION)
+        .u32(KERNEL_MINOR_VERSION)
+        .u32(max_readahead)
+        .u32(flags & (FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES))
+        // max_background, congestion_threshold
+        .u16(16)
+        .u16(12)
+        .u32(MAX_WRITE)
+        // time_gran
+        .u32(1)
+        // max_pages, map_alignment
+        .u16(0)
+        .u16(0)
+        .u32(0);
+    reply.0.resize(INIT_OUT_LEN, 0);
+    Ok(reply)
+}
+
+/// A reply body, in the kernel's layout.
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+}
+
+/// Who mounted the file system, owner of everything in it.
+#[derive(Clone, Copy)]
+struct Owner {
+    uid: u32,
+    gid: u32,
+}
+
+impl Owner {
+    fn attr(&self, reply: &mut Reply, attributes: &Attributes) {
+        let (mode, nlink) = match attributes.kind {
+            Kind::Directory => (libc::S_IFDIR | 0o755, 2),
+            Kind::File => (libc::S_IFREG | 0o644, 1),
+        };
+        let seconds = attributes.modified.timestamp().max(0) as u64;
+        let nanos = attributes.modified.timestamp_subsec_nanos();
+        reply
+            .u64(attributes.ino)
+            .u64(attributes.size)
+            .u64(attributes.size.div_ceil(512))
+            .u64(seconds)
+            .u64(seconds)
+            .u64(seconds)
+            .u32(nanos)
+            .u32(nanos)
+            .u32(nanos)
+            .u32(mode)
+            .u32(nlink)
+            .u32(self.uid)
+            .u32(self.gid)
+            .u32(0)
+            .u32(4096)
+            .u32(0);
+    }
+
+    fn entry(&self, reply: &mut Reply, attributes: &Attributes) {
+        reply
+            .u64(attributes.ino)
+            .u64(0)
+            .u64(TTL.as_secs())
+            .u64(TTL.as_secs())
+            .u32(0)
+            .u32(0);
+        self.attr(reply, attributes);
+    }
+
+    fn attr_out(&self, reply: &mut Reply, attributes: &Attributes) {
+        reply.u64(TTL.as_secs()).u32(0).u32(0);
+        self.attr(reply, attributes);
+    }
+}
+
+fn open_out(reply: &mut Reply, fh: u64) {
+    reply.u64(fh).u32(0).u32(0);
+}
+
+/// A directory entry; false when it does not fit in `size`.
+fn dirent(reply: &mut Reply, size: usize, ino: u64, offset: u64, name: &str, kind: Kind) -> bool {
+    let padded = (24 + name.len()).div_ceil(8) * 8;
+    if reply.0.len() + padded > size {
+        return false;
+    }
+    let kind = match kind {
+        Kind::Directory => libc::DT_DIR,
+        Kind::File => libc::DT_REG,
+    };
+    reply
+        .u64(ino)
+        .u64(offset)
+        .u32(name.len() as u32)
+        .u32(kind as u32);
+    reply.0.extend_from_slice(name.as_bytes());
+    reply.0.resize(reply.0.len() + padded - 24 - name.len(), 0);
+    true
+}
+
+fn last_error() -> std::io::Error {
+    std::io::Error::last_os_error()
+}
+
+/// A mounted FUSE file system.
+pub struct Mount {
+    device: File,
+    mount_point: PathBuf,
+    /// Mounted with `mount(2)` rather than `fusermount`.
+    privileged: bool,
+    owner: Owner,
+}
+
+impl Mount {
+    /// Mount on `mount_point`, which must be an existing directory.
+    pub fn new(mount_point: &Path) -> Result<Self, ClientError> {
+        let mount_point = mount_point.canonicalize()?;
+        if !mount_point.is_dir() {
+            return Err(ClientError::Usage(format!(
+                "{} is not a directory",
+                mount_point.display()
+            )));
+        }
+        // SAFETY: getuid and getgid cannot fail.
+        let owner = unsafe {
+            Owner {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+            }
+        };
+        let (device, privileged) = match Self::mount_privileged(&mount_point, owner) {
+            Ok(device) => (device, true),
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                (Self::mount_unprivileged(&mount_point)?, false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            device,
+            mount_point,
+            privileged,
+            owner,
+        })
+    }
+
+    fn mount_privileged(mount_point: &Path, owner: Owner) -> std::io::Result<File> {
+        let device = File::options().read(true).write(true).open("/dev/fuse")?;
+        let options = format!(
+            "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+            device.as_raw_fd(),
+            owner.uid,
+            owner.gid
+        );
+        let target = CString::new(mount_point.as_os_str().as_bytes())?;
+        let source = CString::new(FS_NAME)?;
+        let fs_type = CString::new(format!("fuse.{}", FS_NAME))?;
+        let options = CString::new(options)?;
+        // SAFETY: every pointer is a NUL-terminated string living through
+        // the call.
+        let result = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                fs_type.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr().cast(),
+            )
+        };
+        if result != 0 {
+            return Err(last_error());
+        }
+        Ok(device)
+    }
+
+    /// Have `fusermount3` mount, and receive the device it opened.
+    fn mount_unprivileged(mount_point: &Path) -> Result<File, ClientError> {
+        let (ours, theirs) = UnixStream::pair()?;
+        // The helper inherits its end of the socket.
+        // SAFETY: clearing the flags of a descriptor we own.
+        if unsafe { libc::fcntl(theirs.as_raw_fd(), libc::F_SETFD, 0) } != 0 {
+            return Err(last_error().into());
+        }
+        let options = format!("fsname={0},subtype={0},default_permissions", FS_NAME);
+        let mut status = None;
+        for helper in ["fusermount3", "fusermount"] {
+            match Command::new(helper)
+                .args(["-o", &options, "--"])
+                .arg(mount_point)
+                .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+                .status()
+            {
+                Ok(result) => {
+                    status = Some(result);
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        drop(theirs);
+        match status {
+            Some(status) if status.success() => {}
+            Some(status) => {
+                return Err(ClientError::Io(std::io::Error::other(format!(
+                    "fusermount failed: {}",
+                    status
+                ))))
+            }
+            None => {
+                return Err(ClientError::Config(
+                    "mounting needs root or fusermount3, from the fuse3 package".to_string(),
+                ))
+            }
+        }
+        Ok(receive_fd(&ours)?)
+    }
+
+    /// Detach the file system; [`serve`](Self::serve) then returns.
+    pub fn unmount(&self) -> Result<(), ClientError> {
+        if self.privileged {
+            let target = CString::new(self.mount_point.as_os_str().as_bytes())
+                .map_err(std::io::Error::from)?;
+            // SAFETY: `target` is a NUL-terminated string living through
+            // the call.
+            if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+                return Err(last_error().into());
+            }
+            return Ok(());
+        }
+        for helper in ["fusermount3", "fusermount"] {
+            match Command::new(helper)
+                .args(["-u", "-z", "--"])
+                .arg(&self.mount_point)
+                .status()
+            {
+                Ok(status) if status.success() => return Ok(()),
+                Ok(status) => {
+                    return Err(ClientError::Io(std::io::Error::other(format!(
+                        "fusermount -u failed: {}",
+                        status
+                    ))))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(ClientError::Config("fusermount3 not found".to_string()))
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Answer requests until the file system is unmounted.
+    pub fn serve(&self, fs: &VirtualFs) -> Result<(), ClientError> {
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..WORKERS)
+                .map(|_| scope.spawn(|| self.serve_requests(fs)))
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        Err(std::io::Error::other("FUSE worker panicked").into())
+                    })
+                })
+                .collect::<Result<Vec<()>, ClientError>>()
+        })?;
+        Ok(())
+    }
+
+    /// One worker's share of the requests. The kernel hands each request to
+    /// a single reader; every worker stops once the device is gone.
+    fn serve_requests(&self, fs: &VirtualFs) -> Result<(), ClientError> {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        loop {
+            let read = match (&self.device).read(&mut buffer) {
+                Ok(read) => read,
+                Err(e) => match e.raw_os_error() {
+                    // The request was interrupted before it was read.
+                    Some(libc::ENOENT | libc::EINTR | libc::EAGAIN) => continue,
+                    Some(libc::ENODEV) => return Ok(()),
+                    _ => return Err(e.into()),
+                },
+            };
+            let mut request = Body(&buffer[..read]);
+            let Ok(InHeader {
+                opcode,
+                unique,
+                node,
+            }) = InHeader::parse(&mut request)
+            else {
+                continue;
+            };
+            match opcode {
+                opcode::FORGET | opcode::BATCH_FORGET | opcode::INTERRUPT => continue,
+                opcode::DESTROY => {
+                    self.reply(unique, Ok(Reply::default()));
+                    return Ok(());
+                }
+                _ => {}
+            }
+            let reply = self.dispatch(fs, opcode, node, request);
+            self.reply(unique, reply);
+        }
+    }
+
+    fn reply(&self, unique: u64, reply: Result<Reply, i32>) {
+        let (error, body) = match reply {
+            Ok(reply) => (0, reply.0),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut message = Vec::with_capacity(16 + body.len());
+        message.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&error.to_ne_bytes());
+        message.extend_from_slice(&unique.to_ne_bytes());
+        message.extend_from_slice(&body);
+        // A reply to an interrupted request is refused with ENOENT.
+        if let Err(e) = (&self.device).write(&message) {
+            if e.raw_os_error() != Some(libc::ENOENT) {
+                tracing::warn!("FUSE reply failed: {}", e);
+            }
+        }
+    }
+
+    fn dispatch(
+        &self,
+        fs: &VirtualFs,
+        opcode: u32,
+        node: u64,
+        mut body: Body,
+    ) -> Result<Reply, i32> {
+        let owner = self.owner;
+        let failed = |e: VfsError| {
+            if let VfsError::Client(e) = &e {
+                tracing::warn!("{}", e);
+            }
+            errno(&e)
+        };
+        let mut reply = Reply::default();
+        match opcode {
+            opcode::INIT => reply = init(&mut body)?,
+            opcode::LOOKUP => {
+                let attributes = fs.lookup(node, body.name()?).map_err(failed)?;
+                owner.entry(&mut reply, &attributes);
+            }
+            opcode::GETATTR => {
+                let attributes = fs.attributes(node).map_err(failed)?;
+                owner.attr_out(&mut reply, &attributes);
+            }
+            opcode::SETATTR => {
+                let SetattrIn { valid, size } = SetattrIn::parse(&mut body)?;
+                let attributes = if valid & FATTR_SIZE != 0 {
+                    fs.truncate(node, size)
+                } else {
+                    fs.attributes(node)
+                }
+                .map_err(failed)?;
+                owner.attr_out(&mut reply, &attributes);
+            }
+            opcode::OPEN => {
+                let flags = body.u32()? as i32;
+                let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+                let truncate = flags & libc::O_TRUNC != 0;
+                let fh = fs.open(node, write, truncate).map_err(failed)?;
+                open_out(&mut reply, fh);
+            }
+            opcode::CREATE => {
+                body.bytes(16)?;
+                let (attributes, fh) = fs.create(node, body.name()?).map_err(failed)?;
+                owner.entry(&mut reply, &attributes);
+                open_out(&mut reply, fh);
+            }
+            opcode::READ => {
+                let fh = body.u64()?;
+                let offset = body.u64()?;
+                let size = body.u32()?;
+                reply.0 = fs.read(fh, offset, size as usize).map_err(failed)?;
+            }
+            opcode::WRITE => {
+                let WriteIn { fh, offset, data } = WriteIn::parse(&mut body)?;
+                let written = fs.write(fh, offset, data).map_err(failed)?;
+                reply.u32(written as u32).u32(0);
+            }
+            opcode::FLUSH | opcode::FSYNC => {
+                fs.flush(body.u64()?).map_err(failed)?;
+            }
+            opcode::RELEASE => {
+                fs.release(body.u64()?).map_err(failed)?;
+            }
+            opcode::MKDIR => {
+                body.bytes(8)?;
+                let attributes = fs.mkdir(node, body.name()?).map_err(failed)?;
+                owner.entry(&mut reply, &attributes);
+            }
+            opcode::UNLINK => fs.unlink(node, body.name()?).map_err(failed)?,
+            opcode::RMDIR => fs.rmdir(node, body.name()?).map_err(failed)?,
+            opcode::RENAME | opcode::RENAME2 => {
+                let new_parent = body.u64()?;
+                let flags = match opcode {
+                    opcode::RENAME2 => {
+                        let flags = body.u32()?;
+                        body.u32()?;
+                        flags
+                    }
+                    _ => 0,
+                };
+                if flags & !RENAME_NOREPLACE != 0 {
+                    return Err(libc::EINVAL);
+                }
+                let name = body.name()?;
+                let new_name = body.name()?;
+                fs.rename(node, name, new_parent, new_name, flags != 0)
+                    .map_err(failed)?;
+            }
+            opcode::OPENDIR => {
+                fs.read_dir(node).map_err(failed)?;
+                open_out(&mut reply, 0);
+            }
+            opcode::READDIR => {
+                body.u64()?;
+                let offset = body.u64()?;
+                let size = body.u32()? as usize;
+                let mut entries = vec![
+                    (".".to_string(), node, Kind::Directory),
+                    (
+                        "..".to_string(),
+                        fs.parent(node).map_err(failed)?,
+                        Kind::Directory,
+                    ),
+                ];
+                entries.extend(
+                    fs.read_dir(node)
+                        .map_err(failed)?
+                        .into_iter()
+                        .map(|(name, attributes)| (name, attributes.ino, attributes.kind)),
+                );
+                for (index, (name, ino, kind)) in entries.iter().enumerate().skip(offset as usize) {
+                    if !dirent(&mut reply, size, *ino, index as u64 + 1, name, *kind) {
+                        break;
+                    }
+                }
+            }
+            opcode::RELEASEDIR | opcode::FSYNCDIR | opcode::ACCESS => {}
+            opcode::STATFS => {
+                // The server's quota is not known here: report plenty of
+                // room, so programs do not refuse to write.
+                let blocks = 1u64 << 40;
+                reply
+                    .u64(blocks)
+                    .u64(blocks)
+                    .u64(blocks)
+                    .u64(0)
+                    .u64(0)
+                    .u32(4096)
+                    .u32(255)
+                    .u32(4096)
+                    .u32(0);
+                reply.0.resize(80, 0);
+            }
+            _ => return Err(libc::ENOSYS),
+        }
+        Ok(reply)
+    }
+}
+
+/// The descriptor sent with `SCM_RIGHTS` over `socket`.
+fn receive_fd(socket: &UnixStream) -> std::io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // Aligned for the control message header.
+    let mut control = [0u64; 8];
+    // SAFETY: an all-zero msghdr is valid; its pointers are set to buffers
+    // living through the call, with their lengths.
+    let received = unsafe {
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = std::mem::size_of_val(&control) as _;
+        if libc::recvmsg(socket.as_raw_fd(), &mut message, 0) < 0 {
+            return Err(last_error());
+        }
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null()
+            || (*header).cmsg_level != libc::SOL_SOCKET
+            || (*header).cmsg_type != libc::SCM_RIGHTS
+        {
+            None
+        } else {
+            Some(std::ptr::read_unaligned(
+                libc::CMSG_DATA(header) as *const libc::c_int
+            ))
+        }
+    };
+    match received {
+        // SAFETY: the descriptor was just received and belongs to nobody
+        // else.
+        Some(fd) => Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) })),
+        None => Err(std::io::Error::other("fusermount sent no descriptor")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Native-endian bytes of the fields, as the kernel lays them out.
+    fn bytes(fields: &[&[u8]]) -> Vec<u8> {
+        fields.concat()
+    }
+
+    #[test]
+    fn test_body_reads_fields_in_order() {
+        let data = bytes(&[
+            &7u32.to_ne_bytes(),
+            &u64::MAX.to_ne_bytes(),
+            b"name\0",
+            b"other\0",
+        ]);
+        let mut body = Body(&data);
+        assert_eq!(body.u32(), Ok(7));
+        assert_eq!(body.u64(), Ok(u64::MAX));
+        assert_eq!(body.name(), Ok("name"));
+        assert_eq!(body.name(), Ok("other"));
+        assert!(body.0.is_empty());
+    }
+
+    #[test]
+    fn test_body_rejects_short_and_bad_input() {
+        assert_eq!(Body(&[0, 0, 0]).u32(), Err(libc::EINVAL));
+        assert_eq!(Body(&[0; 7]).u64(), Err(libc::EINVAL));
+        assert_eq!(Body(b"unterminated").name(), Err(libc::EINVAL));
+        assert_eq!(Body(b"\xff\xfe\0").name(), Err(libc::EINVAL));
+        let mut body = Body(&[1, 2]);
+        assert_eq!(body.bytes(3), Err(libc::EINVAL));
+        // A failed read consumes nothing.
+        assert_eq!(body.bytes(2), Ok(&[1u8, 2][..]));
+    }
+
+    #[test]
+    fn test_in_header_layout() {
+        let data = bytes(&[
+            &60u32.to_ne_bytes(),
+            &opcode::LOOKUP.to_ne_bytes(),
+            &42u64.to_ne_bytes(),
+            &crate::vfs::ROOT.to_ne_bytes(),
+            &1000u32.to_ne_bytes(),
+            &1000u32.to_ne_bytes(),
+            &1234u32.to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+            b"file.txt\0",
+        ]);
+        // fuse_in_header is 40 bytes; the request body follows it.
+        let mut body = Body(&data);
+        let header = InHeader::parse(&mut body).unwrap();
+        assert_eq!(
+            header,
+            InHeader {
+                opcode: opcode::LOOKUP,
+                unique: 42,
+                node: crate::vfs::ROOT,
+            }
+        );
+        assert_eq!(data.len() - body.0.len(), 40);
+        assert_eq!(body.name(), Ok("file.txt"));
+        assert!(InHeader::parse(&mut Body(&data[..39])).is_err());
+    }
+
+    #[test]
+    fn test_init_reply_layout() {
+        let flags = FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES | (1 << 0) | (1 << 20);
+        let data = bytes(&[
+            &7u32.to_ne_bytes(),
+            &38u32.to_ne_bytes(),
+            &(64u32 * 1024).to_ne_bytes(),
+            &flags.to_ne_bytes(),
+        ]);
+        let reply = init(&mut Body(&data)).unwrap().0;
+        assert_eq!(reply.len(), INIT_OUT_LEN);
+        let u32_at = |at: usize| u32::from_ne_bytes(reply[at..at + 4].try_into().unwrap());
+        let u16_at = |at: usize| u16::from_ne_bytes(reply[at..at + 2].try_into().unwrap());
+        assert_eq!(u32_at(0), KERNEL_VERSION);
+        assert_eq!(u32_at(4), KERNEL_MINOR_VERSION);
+        assert_eq!(u32_at(8), 64 * 1024);
+        // Only the flags we support are acknowledged.
+        assert_eq!(u32_at(12), FUSE_ATOMIC_O_TRUNC | FUSE_BIG_WRITES);
+        assert_eq!(u16_at(16), 16);
+        assert_eq!(u16_at(18), 12);
+        assert_eq!(u32_at(20), MAX_WRITE);
+        assert_eq!(u32_at(24), 1);
+        assert!(reply[28..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_init_rejects_old_kernels() {
+        let data = bytes(&[&6u32.to_ne_bytes(), &0u32.to_ne_bytes(), &[0; 8]]);
+        assert_eq!(init(&mut Body(&data)).err(), Some(libc::EPROTO));
+        assert_eq!(init(&mut Body(&data[..12])).err(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_write_in_layout() {
+        let data = bytes(&[
+            &5u64.to_ne_bytes(),
+            &4096u64.to_ne_bytes(),
+            &3u32.to_ne_bytes(),
+            // write_flags, lock_owner, flags, padding
+            &1u32.to_ne_bytes(),
+            &99u64.to_ne_bytes(),
+            &libc::O_WRONLY.to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+            b"abcdef",
+        ]);
+        let mut body = Body(&data);
+        assert_eq!(
+            WriteIn::parse(&mut body),
+            Ok(WriteIn {
+                fh: 5,
+                offset: 4096,
+                data: b"abc",
+            })
+        );
+        assert_eq!(body.0, b"def");
+        // The data may not run past the request.
+        assert_eq!(WriteIn::parse(&mut Body(&data[..42])), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_setattr_in_layout() {
+        let data = bytes(&[
+            &(FATTR_SIZE | 1).to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+            &7u64.to_ne_bytes(),
+            &12345u64.to_ne_bytes(),
+            // lock_owner, atime, mtime, ctime, ...
+            &[0xaa; 56],
+        ]);
+        assert_eq!(
+            SetattrIn::parse(&mut Body(&data)),
+            Ok(SetattrIn {
+                valid: FATTR_SIZE | 1,
+                size: 12345,
+            })
+        );
+        assert_eq!(SetattrIn::parse(&mut Body(&data[..23])), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_dirent_padding_and_limit() {
+        let mut reply = Reply::default();
+        assert!(dirent(&mut reply, 4096, 2, 1, "a", Kind::File));
+        assert_eq!(reply.0.len(), 32);
+        assert!(dirent(&mut reply, 4096, 3, 2, "exactly8", Kind::Directory));
+        assert_eq!(reply.0.len(), 64);
+        assert_eq!(u32::from_ne_bytes(reply.0[48..52].try_into().unwrap()), 8);
+        assert_eq!(
+            u32::from_ne_bytes(reply.0[52..56].try_into().unwrap()),
+            libc::DT_DIR as u32
+        );
+        assert!(!dirent(&mut reply, 80, 4, 3, "full", Kind::File));
+        assert_eq!(reply.0.len(), 64);
+    }
+}
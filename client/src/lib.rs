@@ -0,0 +1,20 @@
+//! rouillecloud desktop sync client
+
+pub mod api;
+pub mod config;
+pub mod daemon;
+pub mod db;
+pub mod e2ee;
+pub mod error;
+#[cfg(target_os = "linux")]
+pub mod fuse;
+pub mod scanner;
+#[cfg(test)]
+mod testing;
+pub mod transfer;
+pub mod vfs;
+pub mod watcher;
+
+pub use config::ClientConfig;
+pub use daemon::Daemon;
+pub use error::ClientError;
@@ -1,3 +1,31 @@
-fn main() {
-    println!("FileshareCloud Client - TODO: Implement");
+mod cli;
+
+use clap::Parser;
+use cli::{Cli, Command, SyncCommand};
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    // The daemon and mounts log their progress; other commands only
+    // warnings, so their output stays readable.
+    let level = match cli.command {
+        Command::Sync(SyncCommand::Watch | SyncCommand::Once) => tracing::Level::INFO,
+        #[cfg(target_os = "linux")]
+        Command::Mount { .. } => tracing::Level::INFO,
+        _ => tracing::Level::WARN,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let json = cli.json;
+    match cli::run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            cli::report(&e, json);
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
@@ -0,0 +1,222 @@
+//! Local snapshots of sync folders
+//!
+//! Hashing every file on every sync would be slow, so hashes are cached in
+//! the local database by path, size and modification time and only
+//! recomputed for files that changed.
+//!
+//! Folders are scanned through their [`PathFilter`]: excluded directories
+//! are not entered.
+
+use crate::db::LocalDb;
+use crate::error::ClientError;
+use crypto::FileHasher;
+use protocol::sync::{SyncFilters, SyncFolder};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use sync_core::{ignore_file_patterns, PathFilter, Snapshot, SyncOptions, SyncState, IGNORE_FILE};
+
+/// Modification time in nanoseconds since the epoch, as cached.
+pub fn mtime_ns(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| i64::try_from(d.as_nanos()).unwrap_or(i64::MAX))
+}
+
+/// Path of `path` relative to `root`, with `/` separators.
+pub fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect();
+    let joined = parts?.join("/");
+    (!joined.is_empty()).then_some(joined)
+}
+
+/// Filters a folder is synced with: the default exclusions, then the
+/// configured filters, then the patterns of the folder's ignore file. The
+/// server is given the same filters, so it filters the changes it sends.
+pub fn folder_filters(folder: &SyncFolder) -> Result<SyncFilters, ClientError> {
+    let mut filters = folder.filters.clone();
+    let mut exclude = SyncOptions::default().exclude_patterns;
+    exclude.append(&mut filters.exclude_patterns);
+    match fs::read_to_string(Path::new(&folder.local_path).join(IGNORE_FILE)) {
+        Ok(text) => exclude.extend(ignore_file_patterns(&text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    filters.exclude_patterns = exclude;
+    Ok(filters)
+}
+
+pub struct Scanner<'a> {
+    db: &'a LocalDb,
+    filter: &'a PathFilter,
+    /// Directories never scanned, such as the client's state directory.
+    skip: Vec<PathBuf>,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(db: &'a LocalDb, filter: &'a PathFilter, skip: Vec<PathBuf>) -> Self {
+        Self { db, filter, skip }
+    }
+
+    /// State of the file at `path`, hashing it unless the cache is current.
+    pub fn state(&self, root: &Path, path: &Path) -> Result<Option<SyncState>, ClientError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let Some(relative) = relative_path(root, path) else {
+            return Ok(None);
+        };
+        let size = metadata.len();
+        let mtime = mtime_ns(&metadata);
+        let hash = match self.db.cached_hash(path, size, mtime)? {
+            Some(hash) => hash,
+            None => {
+                let hash = FileHasher::hash_file(path)?;
+                self.db.store_hash(path, size, mtime, &hash)?;
+                hash
+            }
+        };
+        Ok(Some(SyncState {
+            path: relative,
+            hash,
+            modified: metadata.modified().map(Into::into).unwrap_or_default(),
+            size,
+        }))
+    }
+
+    fn included(&self, root: &Path, path: &Path, metadata: &fs::Metadata) -> bool {
+        if self.skip.iter().any(|skip| path.starts_with(skip)) {
+            return false;
+        }
+        let Some(relative) = relative_path(root, path) else {
+            return false;
+        };
+        if metadata.is_dir() {
+            self.filter.accepts_dir(&relative)
+        } else {
+            self.filter.accepts_file(&relative, metadata.len())
+        }
+    }
+
+    /// Every included regular file under `root`. Symbolic links are not
+    /// followed.
+    pub fn scan(&self, root: &Path) -> Result<Snapshot, ClientError> {
+        let mut snapshot = Snapshot::new();
+        let mut pending = vec![root.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if dir != root && e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let path = entry.path();
+                let metadata = match fs::symlink_metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if !self.included(root, &path, &metadata) {
+                    continue;
+                }
+                if metadata.is_dir() {
+                    pending.push(path);
+                } else if let Some(state) = self.state(root, &path)? {
+                    snapshot.insert(state.path.clone(), state);
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::sync::SyncDirection;
+    use tempfile::TempDir;
+
+    fn folder(root: &Path, exclude: &[&str]) -> SyncFolder {
+        SyncFolder {
+            local_path: root.to_string_lossy().into_owned(),
+            remote_path: "/docs".to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active: true,
+            filters: SyncFilters {
+                exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+                ..SyncFilters::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_relative_paths_use_slashes() {
+        let root = Path::new("/sync");
+        assert_eq!(
+            relative_path(root, &root.join("a").join("b.txt")).as_deref(),
+            Some("a/b.txt")
+        );
+        assert_eq!(relative_path(root, root), None);
+        assert_eq!(relative_path(root, Path::new("/other/b.txt")), None);
+    }
+
+    #[test]
+    fn test_folder_filters_add_the_ignore_file() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "# comment\n*.log\n").unwrap();
+        let filters = folder_filters(&folder(dir.path(), &["build/"])).unwrap();
+        let defaults = SyncOptions::default().exclude_patterns;
+        assert_eq!(filters.exclude_patterns[..defaults.len()], defaults[..]);
+        assert_eq!(
+            filters.exclude_patterns[defaults.len()..],
+            ["build/".to_string(), "*.log".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_scan_skips_excluded_paths_and_caches_hashes() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("state")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        fs::write(root.join("build/out.bin"), "built").unwrap();
+        fs::write(root.join("state/local.db"), "state").unwrap();
+
+        let db = LocalDb::open_in_memory().unwrap();
+        let filters = folder_filters(&folder(&root, &["build/"])).unwrap();
+        let filter = PathFilter::new(&filters);
+        let scanner = Scanner::new(&db, &filter, vec![root.join("state")]);
+        let snapshot = scanner.scan(&root).unwrap();
+
+        let mut paths: Vec<&str> = snapshot.keys().map(String::as_str).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["notes.txt", "src/main.rs"]);
+        let notes = &snapshot["notes.txt"];
+        assert_eq!(notes.hash, FileHasher::hash_bytes(b"notes"));
+        assert_eq!(notes.size, 5);
+
+        // The hash comes from the cache while size and mtime are unchanged.
+        let metadata = fs::metadata(root.join("notes.txt")).unwrap();
+        db.store_hash(&root.join("notes.txt"), 5, mtime_ns(&metadata), "cached")
+            .unwrap();
+        let state = scanner.state(&root, &root.join("notes.txt")).unwrap();
+        assert_eq!(state.unwrap().hash, "cached");
+        assert!(scanner
+            .state(&root, &root.join("missing.txt"))
+            .unwrap()
+            .is_none());
+    }
+}
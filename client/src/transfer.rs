@@ -0,0 +1,866 @@
+//! Carrying out sync plans
+//!
+//! [`FolderExecutor`] performs the actions the sync engine plans for one
+//! folder. It runs on a blocking thread and drives HTTP requests through the
+//! runtime handle.
+//!
+//! Modified files are uploaded as binary deltas against the server's latest
+//! version when that is smaller than the file. Downloads go to a partial file
+//! in the state directory, recorded in the local database, and resume from
+//! its length with a `Range` request after an interruption or a restart;
+//! the file only replaces the local copy once its checksum is verified.
+//! Network failures and server errors are retried a few times.
+//!
+//! In encrypted folders, files are encrypted before they are uploaded,
+//! whole, and decrypted once downloaded and verified; the plan's paths and
+//! checksums are those of the plaintext, mapped to the server's through
+//! [`EncryptedFolders`]. A move into or out of an encrypted folder is
+//! carried out as an upload and a deletion.
+//!
+//! Transfers run several at a time under the daemon's [`TransferScheduler`];
+//! upload bodies are sent in chunks and downloads read chunk by chunk, each
+//! waiting as long as the bandwidth limit asks.
+//!
+//! [`TransferScheduler`]: sync_core::TransferScheduler
+
+use crate::api::ApiClient;
+use crate::db::{LocalDb, Transfer, SETTING_SYNC_TOKEN};
+use crate::e2ee::{EncryptedFolders, FolderKey};
+use crate::error::ClientError;
+use crate::scanner::Scanner;
+use crypto::{ContentInfo, FileHasher};
+use protocol::sync::{ChangeType, FileChange, FileState, SyncRequest, SyncResponse};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sync_core::{
+    DeltaGenerator, PathFilter, Snapshot, SyncAction, SyncError, SyncOptions, SyncState,
+    TransferExecutor, TransferHandle,
+};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+/// Attempts for a transfer before the action fails until the next sync.
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Size of the pieces a limited upload is sent in.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+impl From<ClientError> for SyncError {
+    fn from(e: ClientError) -> Self {
+        match e {
+            ClientError::Sync(e) => e,
+            ClientError::Io(e) => SyncError::Io(e),
+            ClientError::Http(e) => SyncError::Network(e.to_string()),
+            ClientError::Api {
+                status, message, ..
+            } if status.as_u16() == 409 => SyncError::Conflict(message),
+            e => SyncError::Engine(e.to_string()),
+        }
+    }
+}
+
+/// Whether a failed request is worth repeating.
+fn transient(e: &ClientError) -> bool {
+    match e {
+        ClientError::Http(_) => true,
+        ClientError::Api { status, .. } => status.is_server_error() || status.as_u16() == 429,
+        _ => false,
+    }
+}
+
+/// Path of the remote file `relative` to the folder's remote root.
+pub fn remote_path(prefix: &str, relative: &str) -> String {
+    if prefix.is_empty() {
+        relative.to_string()
+    } else {
+        format!("{}/{}", prefix, relative)
+    }
+}
+
+fn changed(path: &str) -> ClientError {
+    ClientError::Sync(SyncError::Conflict(format!(
+        "{} changed since it was scanned",
+        path
+    )))
+}
+
+/// Record the server's answer to changes pushed by this device, failing if
+/// it reports a conflict.
+pub fn apply_pushed(db: &LocalDb, response: &SyncResponse) -> Result<(), ClientError> {
+    db.apply_sync_response(response)?;
+    match response.conflicts.first() {
+        Some(conflict) => Err(ClientError::Sync(SyncError::Conflict(format!(
+            "{:?} on {}",
+            conflict.conflict_type, conflict.path
+        )))),
+        None => Ok(()),
+    }
+}
+
+/// Request body sending `content` at the pace `transfer` allows, or at once
+/// without one.
+fn paced(content: Vec<u8>, transfer: Option<&TransferHandle>) -> reqwest::Body {
+    let Some(transfer) = transfer.cloned() else {
+        return content.into();
+    };
+    let chunks = futures::stream::unfold((content, 0), move |(content, sent)| {
+        let transfer = transfer.clone();
+        async move {
+            let end = content.len().min(sent + UPLOAD_CHUNK);
+            if end == sent {
+                return None;
+            }
+            tokio::time::sleep(transfer.reserve((end - sent) as u64)).await;
+            let chunk = content[sent..end].to_vec();
+            Some((Ok::<_, std::io::Error>(chunk), (content, end)))
+        }
+    });
+    reqwest::Body::wrap_stream(chunks)
+}
+
+/// Upload the file at `path` to `remote`, as a delta against the server's
+/// latest version when that is smaller than the file. `exists` says whether
+/// the server may have a version to diff against; the upload fails if the
+/// file no longer has the checksum `hash`. With a `transfer`, the upload
+/// keeps to its bandwidth limit.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_file(
+    api: &ApiClient,
+    device_id: Uuid,
+    path: &Path,
+    remote: &str,
+    hash: &str,
+    size: u64,
+    exists: bool,
+    transfer: Option<&TransferHandle>,
+) -> Result<FileState, ClientError> {
+    let base = match exists {
+        true => api.signature(remote).await?,
+        false => None,
+    };
+    let Some(base) = base else {
+        let content = fs::read(path)?;
+        if FileHasher::hash_bytes(&content) != hash {
+            return Err(changed(remote));
+        }
+        return api
+            .upload(device_id, remote, None, paced(content, transfer))
+            .await;
+    };
+
+    let mut generator = DeltaGenerator::new(SyncOptions::default());
+    let delta = generator.generate_from_signature(File::open(path)?, &base.signature)?;
+    if delta.target_checksum != hash {
+        return Err(changed(remote));
+    }
+    let encoded = generator.encode_delta(&delta)?;
+    if (encoded.len() as u64) < size {
+        tracing::debug!(
+            "uploading {} as a {} byte delta against version {}",
+            remote,
+            encoded.len(),
+            base.version
+        );
+        api.upload_delta(device_id, remote, base.version, paced(encoded, transfer))
+            .await
+    } else {
+        let content = fs::read(path)?;
+        if FileHasher::hash_bytes(&content) != hash {
+            return Err(changed(remote));
+        }
+        api.upload(
+            device_id,
+            remote,
+            Some(base.version),
+            paced(content, transfer),
+        )
+        .await
+    }
+}
+
+/// Decrypt the downloaded `encrypted` content into `decrypted`, which must
+/// have the checksum `hash`.
+fn decrypt_file(
+    folder: &FolderKey,
+    encrypted: &Path,
+    decrypted: &Path,
+    hash: &str,
+) -> Result<(), ClientError> {
+    let mut output = File::create(decrypted)?;
+    let info = crypto::decrypt_content(folder.key(), &mut File::open(encrypted)?, &mut output)?;
+    output.sync_all()?;
+    if info.checksum != hash {
+        return Err(ClientError::Sync(SyncError::ChecksumMismatch));
+    }
+    Ok(())
+}
+
+/// Remove the empty directories between `path` and `root`.
+fn prune_empty_parents(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || !current.starts_with(root) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Move `from` over `to`, copying when they are on different file systems.
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let tmp = to.with_extension("rouille-tmp");
+    fs::copy(from, &tmp)?;
+    fs::rename(&tmp, to)?;
+    fs::remove_file(from)
+}
+
+pub struct FolderExecutor {
+    runtime: Handle,
+    api: ApiClient,
+    db: Arc<LocalDb>,
+    device_id: Uuid,
+    root: PathBuf,
+    /// Remote root of the folder, without surrounding slashes.
+    prefix: String,
+    filter: PathFilter,
+    partial_dir: PathBuf,
+    /// Local files as scanned when the plan was made.
+    local: Snapshot,
+    encryption: Arc<EncryptedFolders>,
+    /// A device error that must stop the daemon, kept since the engine
+    /// only reports errors as text.
+    terminal: Mutex<Option<ClientError>>,
+}
+
+impl FolderExecutor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        runtime: Handle,
+        api: ApiClient,
+        db: Arc<LocalDb>,
+        device_id: Uuid,
+        root: PathBuf,
+        prefix: String,
+        filter: PathFilter,
+        partial_dir: PathBuf,
+        local: Snapshot,
+        encryption: Arc<EncryptedFolders>,
+    ) -> Self {
+        Self {
+            runtime,
+            api,
+            db,
+            device_id,
+            root,
+            prefix,
+            filter,
+            partial_dir,
+            local,
+            encryption,
+            terminal: Mutex::new(None),
+        }
+    }
+
+    /// The device error that stopped the run, if any.
+    pub fn take_terminal(&self) -> Option<ClientError> {
+        self.terminal
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take()
+    }
+
+    fn local_path(&self, relative: &str) -> PathBuf {
+        relative
+            .split('/')
+            .fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    /// Path of a file on the server, encrypted in encrypted folders.
+    fn stored_path(&self, relative: &str) -> Result<String, ClientError> {
+        self.encryption
+            .encrypt_path(&remote_path(&self.prefix, relative))
+    }
+
+    fn current(&self, relative: &str) -> Result<Option<SyncState>, ClientError> {
+        Scanner::new(&self.db, &self.filter, Vec::new())
+            .state(&self.root, &self.local_path(relative))
+    }
+
+    /// Fail unless the local file still is as scanned, so nothing written
+    /// meanwhile is overwritten.
+    fn check_unchanged(&self, relative: &str) -> Result<(), ClientError> {
+        let current = self.current(relative)?.map(|state| state.hash);
+        let scanned = self.local.get(relative).map(|state| state.hash.clone());
+        if current != scanned {
+            return Err(changed(relative));
+        }
+        Ok(())
+    }
+
+    /// Run `request` on the runtime, retrying transient failures.
+    fn retry<T, F, Fut>(&self, mut request: F) -> Result<T, ClientError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.runtime.block_on(request()) {
+                Err(e) if attempt < MAX_ATTEMPTS && transient(&e) => {
+                    tracing::warn!("attempt {} failed, retrying: {}", attempt, e);
+                    std::thread::sleep(RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Push changes made on this device, failing if the server reports a
+    /// conflict.
+    fn push(&self, change: FileChange) -> Result<(), ClientError> {
+        let request = SyncRequest {
+            device_id: self.device_id,
+            last_sync_token: self.db.setting(SETTING_SYNC_TOKEN)?,
+            changes: vec![change],
+        };
+        let response = self.retry(|| self.api.sync(&request))?;
+        apply_pushed(&self.db, &response)
+    }
+
+    fn upload(
+        &self,
+        relative: &str,
+        local: &SyncState,
+        transfer: &TransferHandle,
+    ) -> Result<(), ClientError> {
+        let path = self.local_path(relative);
+        let remote = self.stored_path(relative)?;
+        let exists = self.db.remote(&remote)?.is_some();
+        if let Some(folder) = self
+            .encryption
+            .folder_of(&remote_path(&self.prefix, relative))
+        {
+            return self.upload_encrypted(folder, &path, &remote, local, exists, transfer);
+        }
+        let state = self.retry(|| {
+            upload_file(
+                &self.api,
+                self.device_id,
+                &path,
+                &remote,
+                &local.hash,
+                local.size,
+                exists,
+                Some(transfer),
+            )
+        })?;
+        self.db.set_remote(&state)?;
+        Ok(())
+    }
+
+    /// Upload the encrypted content of `path`, whole, as deltas between
+    /// encrypted versions save nothing.
+    fn upload_encrypted(
+        &self,
+        folder: &FolderKey,
+        path: &Path,
+        remote: &str,
+        local: &SyncState,
+        exists: bool,
+        transfer: &TransferHandle,
+    ) -> Result<(), ClientError> {
+        let expected = ContentInfo {
+            checksum: local.hash.clone(),
+            size: local.size,
+        };
+        let mut content = Vec::new();
+        let read = crypto::encrypt_content(
+            folder.key(),
+            &expected,
+            &mut File::open(path)?,
+            &mut content,
+        )?;
+        if read != expected {
+            return Err(changed(remote));
+        }
+        let base_version = match exists {
+            true => self
+                .retry(|| self.api.versions(remote))?
+                .first()
+                .map(|version| version.version),
+            false => None,
+        };
+        let state = self.retry(|| {
+            self.api.upload(
+                self.device_id,
+                remote,
+                base_version,
+                paced(content.clone(), Some(transfer)),
+            )
+        })?;
+        self.db
+            .set_encrypted_content(&state.checksum, &local.hash, local.size)?;
+        self.db.set_remote(&state)?;
+        Ok(())
+    }
+
+    fn download(
+        &self,
+        relative: &str,
+        remote: &SyncState,
+        handle: &TransferHandle,
+    ) -> Result<(), ClientError> {
+        let path = self.local_path(relative);
+        if self
+            .current(relative)?
+            .is_some_and(|state| state.hash == remote.hash)
+        {
+            return Ok(());
+        }
+        self.check_unchanged(relative)?;
+
+        let folder = self
+            .encryption
+            .folder_of(&remote_path(&self.prefix, relative));
+        let remote_path = self.stored_path(relative)?;
+        // What is downloaded is encrypted in encrypted folders, and only
+        // the mirror knows its checksum.
+        let checksum = match folder {
+            Some(_) => match self.db.remote(&remote_path)? {
+                Some(stored) => stored.hash,
+                None => return Err(changed(relative)),
+            },
+            None => remote.hash.clone(),
+        };
+        let transfer = match self.db.transfer(&remote_path)? {
+            Some(transfer) if transfer.checksum == checksum => transfer,
+            previous => {
+                if let Some(previous) = previous {
+                    let _ = fs::remove_file(&previous.partial_path);
+                }
+                let transfer = Transfer {
+                    path: remote_path.clone(),
+                    checksum: checksum.clone(),
+                    partial_path: self.partial_dir.join(format!(
+                        "{}.part",
+                        FileHasher::hash_bytes(remote_path.as_bytes())
+                    )),
+                };
+                self.db.start_transfer(&transfer)?;
+                transfer
+            }
+        };
+        fs::create_dir_all(&self.partial_dir)?;
+
+        self.retry(|| async {
+            let offset = fs::metadata(&transfer.partial_path).map_or(0, |m| m.len());
+            let mut download = self.api.download(&remote_path, None, offset).await?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&transfer.partial_path)?;
+            // Drop whatever the server did not resume from.
+            file.set_len(download.offset.min(offset))?;
+            while let Some(chunk) = download.response.chunk().await? {
+                file.write_all(&chunk)?;
+                tokio::time::sleep(handle.reserve(chunk.len() as u64)).await;
+            }
+            file.sync_all()?;
+            Ok(())
+        })?;
+
+        if FileHasher::hash_file(&transfer.partial_path)? != checksum {
+            let _ = fs::remove_file(&transfer.partial_path);
+            self.db.finish_transfer(&remote_path)?;
+            return Err(ClientError::Sync(SyncError::ChecksumMismatch));
+        }
+        let Some(folder) = folder else {
+            self.check_unchanged(relative)?;
+            replace_file(&transfer.partial_path, &path)?;
+            self.db.finish_transfer(&remote_path)?;
+            return Ok(());
+        };
+
+        let decrypted = transfer.partial_path.with_extension("plain");
+        let result = decrypt_file(folder, &transfer.partial_path, &decrypted, &remote.hash)
+            .and_then(|()| self.check_unchanged(relative))
+            .and_then(|()| Ok(replace_file(&decrypted, &path)?));
+        let _ = fs::remove_file(&transfer.partial_path);
+        let _ = fs::remove_file(&decrypted);
+        self.db.finish_transfer(&remote_path)?;
+        result
+    }
+
+    fn delete_local(&self, relative: &str, hash: &str) -> Result<(), ClientError> {
+        let Some(current) = self.current(relative)? else {
+            return Ok(());
+        };
+        if current.hash != hash {
+            return Err(changed(relative));
+        }
+        let path = self.local_path(relative);
+        fs::remove_file(&path)?;
+        prune_empty_parents(&self.root, &path);
+        Ok(())
+    }
+
+    fn delete_remote(&self, relative: &str, hash: &str) -> Result<(), ClientError> {
+        let remote = self.stored_path(relative)?;
+        let checksum = match self
+            .encryption
+            .folder_of(&remote_path(&self.prefix, relative))
+        {
+            Some(_) => self.db.remote(&remote)?.map(|stored| stored.hash),
+            None => Some(hash.to_string()),
+        };
+        self.push(FileChange {
+            path: remote.clone(),
+            change_type: ChangeType::Deleted,
+            metadata: None,
+            checksum,
+            delta: None,
+        })?;
+        self.db.remove_remote(&remote)?;
+        Ok(())
+    }
+
+    fn move_local(&self, from: &str, to: &str, state: &SyncState) -> Result<(), ClientError> {
+        let target = self.current(to)?;
+        if target.as_ref().is_some_and(|t| t.hash == state.hash) && self.current(from)?.is_none() {
+            return Ok(());
+        }
+        if target.is_some() {
+            return Err(changed(to));
+        }
+        if self.current(from)?.map(|s| s.hash).as_deref() != Some(state.hash.as_str()) {
+            return Err(changed(from));
+        }
+        let source = self.local_path(from);
+        let destination = self.local_path(to);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&source, &destination)?;
+        prune_empty_parents(&self.root, &source);
+        Ok(())
+    }
+
+    fn move_remote(
+        &self,
+        from: &str,
+        to: &str,
+        state: &SyncState,
+        transfer: &TransferHandle,
+    ) -> Result<(), ClientError> {
+        if !self.encryption.same_folder(
+            &remote_path(&self.prefix, from),
+            &remote_path(&self.prefix, to),
+        ) {
+            // Names and contents are encrypted on one side only.
+            self.upload(to, state, transfer)?;
+            return self.delete_remote(from, &state.hash);
+        }
+        let old_path = self.stored_path(from)?;
+        let path = self.stored_path(to)?;
+        let moved = self.db.remote(&old_path)?;
+        // Without a checksum the server keeps the content and size it has.
+        self.push(FileChange {
+            path: path.clone(),
+            change_type: ChangeType::Moved {
+                old_path: old_path.clone(),
+            },
+            metadata: None,
+            checksum: None,
+            delta: None,
+        })?;
+        self.db.remove_remote(&old_path)?;
+        if let Some(moved) = moved {
+            self.db.set_remote(&FileState {
+                path,
+                checksum: moved.hash,
+                size: moved.size,
+                modified_at: moved.modified,
+                version: 0,
+                sync_status: protocol::sync::SyncStatus::Synced,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn run(&self, action: &SyncAction, transfer: &TransferHandle) -> Result<(), ClientError> {
+        match action {
+            SyncAction::Upload { path, local } => self.upload(path, local, transfer)?,
+            SyncAction::Download { path, remote } => self.download(path, remote, transfer)?,
+            SyncAction::DeleteLocal { path, hash } => self.delete_local(path, hash)?,
+            SyncAction::DeleteRemote { path, hash } => self.delete_remote(path, hash)?,
+            SyncAction::MoveLocal { from, to, state } => self.move_local(from, to, state)?,
+            SyncAction::MoveRemote { from, to, state } => {
+                self.move_remote(from, to, state, transfer)?
+            }
+            SyncAction::Conflict {
+                path,
+                conflict_type,
+                ..
+            } => {
+                tracing::warn!("conflict on {}: {:?}", path, conflict_type);
+                self.db.record_conflict(
+                    &remote_path(&self.prefix, path),
+                    &format!("{:?}", conflict_type),
+                )?;
+                return Ok(());
+            }
+            SyncAction::Record { .. } => {}
+        }
+        self.db
+            .clear_conflict(&remote_path(&self.prefix, action.path()))?;
+        Ok(())
+    }
+}
+
+impl TransferExecutor for FolderExecutor {
+    fn execute(&self, action: &SyncAction, transfer: &TransferHandle) -> Result<(), SyncError> {
+        self.run(action, transfer).map_err(|e| {
+            if e.is_device_terminal() {
+                let message = e.to_string();
+                self.terminal
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .get_or_insert(e);
+                SyncError::Engine(message)
+            } else {
+                e.into()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, Request, TestServer};
+    use chrono::Utc;
+    use protocol::sync::{SyncFilters, SyncStatus};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use sync_core::Direction;
+    use tempfile::TempDir;
+
+    const CONTENT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    struct Fixture {
+        dir: TempDir,
+        db: Arc<LocalDb>,
+        executor: Arc<FolderExecutor>,
+    }
+
+    impl Fixture {
+        fn new(server: &TestServer) -> Self {
+            let dir = TempDir::new().unwrap();
+            let db = Arc::new(LocalDb::open_in_memory().unwrap());
+            let executor = FolderExecutor::new(
+                Handle::current(),
+                ApiClient::new(&server.url).unwrap(),
+                db.clone(),
+                Uuid::new_v4(),
+                dir.path().join("root"),
+                "docs".to_string(),
+                PathFilter::new(&SyncFilters::default()),
+                dir.path().join("partial"),
+                Snapshot::new(),
+                Arc::new(EncryptedFolders::none(Uuid::new_v4())),
+            );
+            Self {
+                dir,
+                db,
+                executor: Arc::new(executor),
+            }
+        }
+
+        fn partial_path(&self) -> PathBuf {
+            self.dir
+                .path()
+                .join("partial")
+                .join(format!("{}.part", FileHasher::hash_bytes(b"docs/a.txt")))
+        }
+
+        async fn run(&self, action: SyncAction, direction: Direction) -> Result<(), ClientError> {
+            let executor = self.executor.clone();
+            tokio::task::spawn_blocking(move || {
+                executor.run(&action, &TransferHandle::unlimited(direction))
+            })
+            .await
+            .unwrap()
+        }
+    }
+
+    fn state(path: &str, content: &[u8]) -> SyncState {
+        SyncState {
+            path: path.to_string(),
+            hash: FileHasher::hash_bytes(content),
+            modified: Utc::now(),
+            size: content.len() as u64,
+        }
+    }
+
+    fn download_action() -> SyncAction {
+        SyncAction::Download {
+            path: "a.txt".to_string(),
+            remote: state("a.txt", CONTENT),
+        }
+    }
+
+    /// Offset asked for by a download request, 0 without a range.
+    fn range_start(request: &Request) -> usize {
+        request
+            .header("range")
+            .and_then(|range| range.strip_prefix("bytes="))
+            .and_then(|range| range.trim_end_matches('-').parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Serve `CONTENT` from the requested offset, with a 206 for ranges.
+    fn serve_content(request: &Request) -> Reply {
+        let start = range_start(request);
+        if start == 0 {
+            return Reply::new(200, CONTENT);
+        }
+        Reply::new(206, &CONTENT[start..]).header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, CONTENT.len() - 1, CONTENT.len()),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_resumes_from_a_recorded_partial_file() {
+        let server = TestServer::start(serve_content).await;
+        let fixture = Fixture::new(&server);
+        fs::create_dir_all(fixture.partial_path().parent().unwrap()).unwrap();
+        fs::write(fixture.partial_path(), &CONTENT[..10]).unwrap();
+        fixture
+            .db
+            .start_transfer(&Transfer {
+                path: "docs/a.txt".to_string(),
+                checksum: FileHasher::hash_bytes(CONTENT),
+                partial_path: fixture.partial_path(),
+            })
+            .unwrap();
+
+        fixture
+            .run(download_action(), Direction::Download)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("range"), Some("bytes=10-"));
+        assert_eq!(
+            fs::read(fixture.dir.path().join("root/a.txt")).unwrap(),
+            CONTENT
+        );
+        assert!(fixture.db.transfer("docs/a.txt").unwrap().is_none());
+        assert!(!fixture.partial_path().exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_interrupted_download_is_resumed() {
+        let server = TestServer::start(|request| match range_start(request) {
+            0 => Reply::new(200, &CONTENT[..16]).cut_at(CONTENT.len()),
+            _ => serve_content(request),
+        })
+        .await;
+        let fixture = Fixture::new(&server);
+
+        fixture
+            .run(download_action(), Direction::Download)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("range"), None);
+        assert_eq!(requests[1].header("range"), Some("bytes=16-"));
+        assert_eq!(
+            fs::read(fixture.dir.path().join("root/a.txt")).unwrap(),
+            CONTENT
+        );
+        assert!(fixture.db.transfer("docs/a.txt").unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_corrupt_partial_file_is_discarded() {
+        let server = TestServer::start(serve_content).await;
+        let fixture = Fixture::new(&server);
+        fs::create_dir_all(fixture.partial_path().parent().unwrap()).unwrap();
+        fs::write(fixture.partial_path(), b"not the start").unwrap();
+        fixture
+            .db
+            .start_transfer(&Transfer {
+                path: "docs/a.txt".to_string(),
+                checksum: FileHasher::hash_bytes(CONTENT),
+                partial_path: fixture.partial_path(),
+            })
+            .unwrap();
+
+        let result = fixture.run(download_action(), Direction::Download).await;
+
+        assert!(matches!(
+            result,
+            Err(ClientError::Sync(SyncError::ChecksumMismatch))
+        ));
+        assert!(!fixture.partial_path().exists());
+        assert!(fixture.db.transfer("docs/a.txt").unwrap().is_none());
+        assert!(!fixture.dir.path().join("root/a.txt").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_upload_is_retried() {
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |request| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Reply::new(503, "");
+            }
+            Reply::json(&FileState {
+                path: "docs/a.txt".to_string(),
+                checksum: FileHasher::hash_bytes(&request.body),
+                size: request.body.len() as u64,
+                modified_at: Utc::now(),
+                version: 1,
+                sync_status: SyncStatus::Synced,
+            })
+        })
+        .await;
+        let fixture = Fixture::new(&server);
+        fs::create_dir_all(fixture.dir.path().join("root")).unwrap();
+        fs::write(fixture.dir.path().join("root/a.txt"), CONTENT).unwrap();
+
+        fixture
+            .run(
+                SyncAction::Upload {
+                    path: "a.txt".to_string(),
+                    local: state("a.txt", CONTENT),
+                },
+                Direction::Upload,
+            )
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(request.method, "PUT");
+            assert_eq!(request.body, CONTENT);
+        }
+        let remote = fixture.db.remote("docs/a.txt").unwrap().unwrap();
+        assert_eq!(remote.hash, FileHasher::hash_bytes(CONTENT));
+    }
+}
@@ -0,0 +1,1164 @@
+//! Virtual files
+//!
+//! [`VirtualFs`] shows the user's remote files as a tree of directories and
+//! files built from the remote mirror in the local database, without
+//! downloading anything. A file's content is fetched when it is first
+//! opened and kept in a [`ContentCache`] in the state directory, named by
+//! checksum and trimmed to its size limit least recently used first. Files
+//! at or under a pinned path are fetched ahead of time and never trimmed,
+//! so they stay readable offline.
+//!
+//! A file opened for writing is copied to a staging file, which is uploaded
+//! when it is flushed, as the sync daemon uploads: as a delta against the
+//! server's version when that is smaller. Deletes, moves and new
+//! directories are pushed to the server right away. Directories are
+//! implicit, as for the command-line client: an empty one holds a hidden
+//! placeholder file.
+//!
+//! A file system binding such as [`crate::fuse`] calls it from a blocking
+//! thread; it drives HTTP requests through the runtime handle.
+
+use crate::api::ApiClient;
+use crate::db::{LocalDb, SETTING_SYNC_TOKEN};
+use crate::error::ClientError;
+use crate::transfer::{apply_pushed, upload_file};
+use chrono::{DateTime, Utc};
+use crypto::FileHasher;
+use protocol::sync::{ChangeType, FileChange, SyncRequest};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use sync_core::{SyncError, SyncState};
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+/// Name of the hidden file holding an empty directory in place.
+pub const DIRECTORY_PLACEHOLDER: &str = ".rouillekeep";
+/// Inode of the root directory.
+pub const ROOT: u64 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum VfsError {
+    #[error("no such file or directory")]
+    NotFound,
+    #[error("file exists")]
+    Exists,
+    #[error("directory not empty")]
+    NotEmpty,
+    #[error("is a directory")]
+    IsDir,
+    #[error("not a directory")]
+    NotDir,
+    #[error("invalid file name")]
+    InvalidName,
+    #[error(transparent)]
+    Client(#[from] ClientError),
+}
+
+impl From<std::io::Error> for VfsError {
+    fn from(e: std::io::Error) -> Self {
+        VfsError::Client(ClientError::Io(e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Directory,
+    File,
+}
+
+#[derive(Debug, Clone)]
+pub struct Attributes {
+    pub ino: u64,
+    pub kind: Kind,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+fn join(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+fn check_name(name: &str) -> Result<(), VfsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(VfsError::InvalidName);
+    }
+    if name == DIRECTORY_PLACEHOLDER {
+        return Err(VfsError::InvalidName);
+    }
+    Ok(())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Contents of remote files, stored by checksum.
+pub struct ContentCache {
+    dir: PathBuf,
+    /// Most bytes kept, pinned files aside.
+    limit: u64,
+    db: Arc<LocalDb>,
+}
+
+impl ContentCache {
+    pub fn new(dir: PathBuf, limit: u64, db: Arc<LocalDb>) -> Self {
+        Self { dir, limit, db }
+    }
+
+    fn path(&self, checksum: &str) -> Result<PathBuf, ClientError> {
+        // Checksums come from the server; never let one name another file.
+        if checksum.is_empty() || !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ClientError::Sync(SyncError::Engine(format!(
+                "invalid checksum {}",
+                checksum
+            ))));
+        }
+        Ok(self.dir.join(checksum))
+    }
+
+    /// The cached content with `checksum`, marked as just used.
+    pub fn get(&self, checksum: &str) -> Result<Option<PathBuf>, ClientError> {
+        let path = self.path(checksum)?;
+        match fs::metadata(&path) {
+            Ok(metadata) => {
+                self.db.touch_cached(checksum, metadata.len())?;
+                Ok(Some(path))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.db.remove_cached(checksum)?;
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Copy `file`, whose content has `checksum`, into the cache.
+    pub fn insert(&self, checksum: &str, file: &Path) -> Result<PathBuf, ClientError> {
+        let path = self.path(checksum)?;
+        fs::create_dir_all(&self.dir)?;
+        let partial = self.dir.join(format!("{}.part", Uuid::new_v4().simple()));
+        fs::copy(file, &partial)?;
+        fs::rename(&partial, &path)?;
+        self.db.touch_cached(checksum, fs::metadata(&path)?.len())?;
+        self.evict(Some(checksum))?;
+        Ok(path)
+    }
+
+    /// The content of the remote file `remote`, fetched unless cached.
+    pub async fn fetch(
+        &self,
+        api: &ApiClient,
+        remote: &str,
+        checksum: &str,
+    ) -> Result<PathBuf, ClientError> {
+        if let Some(path) = self.get(checksum)? {
+            return Ok(path);
+        }
+        let path = self.path(checksum)?;
+        fs::create_dir_all(&self.dir)?;
+        let partial = self.dir.join(format!("{}.part", Uuid::new_v4().simple()));
+        let fetched: Result<(), ClientError> = async {
+            let mut download = api.download(remote, None, 0).await?;
+            let mut file = File::create(&partial)?;
+            let mut hasher = FileHasher::new();
+            while let Some(chunk) = download.response.chunk().await? {
+                hasher.update(&chunk);
+                file.write_all(&chunk)?;
+            }
+            file.sync_all()?;
+            // The file changed on the server since the mirror was updated.
+            if hasher.finalize() != checksum {
+                return Err(ClientError::Sync(SyncError::ChecksumMismatch));
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = fetched {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, &path)?;
+        self.db.touch_cached(checksum, fs::metadata(&path)?.len())?;
+        self.evict(Some(checksum))?;
+        Ok(path)
+    }
+
+    /// Fetch the pinned files that are not cached. Returns how many were.
+    pub async fn fetch_pinned(&self, api: &ApiClient) -> Result<usize, ClientError> {
+        let pinned = self.db.pinned()?;
+        let mut fetched = 0;
+        for file in self.db.remote_files("")? {
+            let is_pinned = pinned
+                .iter()
+                .any(|pin| file.path == *pin || file.path.starts_with(&format!("{}/", pin)));
+            if is_pinned && self.get(&file.hash)?.is_none() {
+                self.fetch(api, &file.path, &file.hash).await?;
+                fetched += 1;
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Remove the least recently used contents until the cache fits its
+    /// limit. Open files stay readable, as their handles keep them alive.
+    pub fn trim(&self) -> Result<(), ClientError> {
+        self.evict(None)
+    }
+
+    /// Trim the cache, sparing `keep` so content just stored can be opened
+    /// even when it alone is over the limit.
+    fn evict(&self, keep: Option<&str>) -> Result<(), ClientError> {
+        let mut size = self.db.cached_size()?;
+        if size <= self.limit {
+            return Ok(());
+        }
+        for (checksum, entry_size) in self.db.evictable()? {
+            if size <= self.limit {
+                break;
+            }
+            if keep == Some(checksum.as_str()) {
+                continue;
+            }
+            match fs::remove_file(self.path(&checksum)?) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.db.remove_cached(&checksum)?;
+            size = size.saturating_sub(entry_size);
+        }
+        Ok(())
+    }
+}
+
+enum Entry {
+    Directory {
+        children: BTreeMap<String, u64>,
+    },
+    /// `checksum` is that of the server's version, if there is one.
+    File {
+        checksum: Option<String>,
+        size: u64,
+    },
+}
+
+struct Node {
+    path: String,
+    parent: u64,
+    modified: DateTime<Utc>,
+    entry: Entry,
+}
+
+/// Directories and files by inode. Paths keep their inode across rebuilds,
+/// so the kernel's view stays valid while the mirror changes.
+struct Tree {
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<String, u64>,
+    next: u64,
+}
+
+impl Tree {
+    fn new() -> Self {
+        let mut tree = Self {
+            nodes: HashMap::new(),
+            inodes: HashMap::from([(String::new(), ROOT)]),
+            next: ROOT + 1,
+        };
+        tree.clear();
+        tree
+    }
+
+    fn clear(&mut self) {
+        self.nodes.clear();
+        self.nodes.insert(
+            ROOT,
+            Node {
+                path: String::new(),
+                parent: ROOT,
+                modified: Utc::now(),
+                entry: Entry::Directory {
+                    children: BTreeMap::new(),
+                },
+            },
+        );
+    }
+
+    fn inode(&mut self, path: &str) -> u64 {
+        if let Some(&ino) = self.inodes.get(path) {
+            return ino;
+        }
+        let ino = self.next;
+        self.next += 1;
+        self.inodes.insert(path.to_string(), ino);
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Result<&Node, VfsError> {
+        self.nodes.get(&ino).ok_or(VfsError::NotFound)
+    }
+
+    fn children(&self, ino: u64) -> Result<&BTreeMap<String, u64>, VfsError> {
+        match &self.node(ino)?.entry {
+            Entry::Directory { children } => Ok(children),
+            Entry::File { .. } => Err(VfsError::NotDir),
+        }
+    }
+
+    fn child(&self, parent: u64, name: &str) -> Result<Option<u64>, VfsError> {
+        Ok(self.children(parent)?.get(name).copied())
+    }
+
+    /// Add `node` under the directory at its path's parent, creating the
+    /// missing directories. Gives up when a file is in the way.
+    fn insert(&mut self, path: &str, modified: DateTime<Utc>, entry: Entry) -> Option<u64> {
+        let (parent_path, name) = split(path);
+        let parent = match parent_path {
+            "" => ROOT,
+            parent_path => self.insert_directory(parent_path, modified)?,
+        };
+        let ino = self.inode(path);
+        match &mut self.nodes.get_mut(&parent)?.entry {
+            Entry::Directory { children } => {
+                children.insert(name.to_string(), ino);
+            }
+            Entry::File { .. } => return None,
+        }
+        let node = Node {
+            path: path.to_string(),
+            parent,
+            modified,
+            entry,
+        };
+        match self.nodes.get(&ino) {
+            Some(existing) if matches!(existing.entry, Entry::Directory { .. }) => {
+                // Directories take precedence over a file of the same path.
+                if matches!(node.entry, Entry::Directory { .. }) {
+                    return Some(ino);
+                }
+                return None;
+            }
+            _ => {}
+        }
+        self.nodes.insert(ino, node);
+        Some(ino)
+    }
+
+    fn insert_directory(&mut self, path: &str, modified: DateTime<Utc>) -> Option<u64> {
+        if let Some(&ino) = self.inodes.get(path) {
+            if let Some(node) = self.nodes.get(&ino) {
+                return match node.entry {
+                    Entry::Directory { .. } => Some(ino),
+                    Entry::File { .. } => None,
+                };
+            }
+        }
+        self.insert(
+            path,
+            modified,
+            Entry::Directory {
+                children: BTreeMap::new(),
+            },
+        )
+    }
+
+    /// Rebuild from the remote mirror, with the files written here and not
+    /// uploaded yet.
+    fn rebuild(&mut self, files: Vec<SyncState>, staged: &HashMap<String, Staged>) {
+        self.clear();
+        for file in files {
+            let (parent, name) = split(&file.path);
+            let inserted = if name == DIRECTORY_PLACEHOLDER {
+                parent.is_empty() || self.insert_directory(parent, file.modified).is_some()
+            } else {
+                let entry = Entry::File {
+                    checksum: Some(file.hash),
+                    size: file.size,
+                };
+                self.insert(&file.path, file.modified, entry).is_some()
+            };
+            if !inserted {
+                tracing::warn!("{} is both a file and a directory", file.path);
+            }
+        }
+        for (path, staged) in staged {
+            if !self
+                .inodes
+                .get(path)
+                .is_some_and(|ino| self.nodes.contains_key(ino))
+            {
+                let entry = Entry::File {
+                    checksum: None,
+                    size: 0,
+                };
+                self.insert(path, staged.created, entry);
+            }
+        }
+    }
+
+    fn attributes(
+        &self,
+        ino: u64,
+        staged: &HashMap<String, Staged>,
+    ) -> Result<Attributes, VfsError> {
+        let node = self.node(ino)?;
+        let (kind, mut size, mut modified) = match &node.entry {
+            Entry::Directory { .. } => (Kind::Directory, 0, node.modified),
+            Entry::File { size, .. } => (Kind::File, *size, node.modified),
+        };
+        if let Some(staged) = staged.get(&node.path) {
+            let metadata = fs::metadata(&staged.file)?;
+            size = metadata.len();
+            modified = metadata.modified().map_or(modified, DateTime::from);
+        }
+        Ok(Attributes {
+            ino,
+            kind,
+            size,
+            modified,
+        })
+    }
+}
+
+/// A file written here, in its staging file until uploaded.
+struct Staged {
+    file: PathBuf,
+    created: DateTime<Utc>,
+    /// Written since last uploaded.
+    dirty: bool,
+    handles: usize,
+}
+
+struct OpenFile {
+    path: String,
+    file: File,
+    /// Open on the staging file.
+    staged: bool,
+}
+
+struct State {
+    tree: Tree,
+    staged: HashMap<String, Staged>,
+    handles: HashMap<u64, OpenFile>,
+    next_handle: u64,
+}
+
+pub struct VirtualFs {
+    runtime: Handle,
+    api: ApiClient,
+    db: Arc<LocalDb>,
+    device_id: Uuid,
+    cache: Arc<ContentCache>,
+    staging_dir: PathBuf,
+    state: Mutex<State>,
+}
+
+impl VirtualFs {
+    /// Files left in `staging_dir` by an earlier run are discarded, as what
+    /// they were for is no longer known.
+    pub fn new(
+        runtime: Handle,
+        api: ApiClient,
+        db: Arc<LocalDb>,
+        device_id: Uuid,
+        cache: Arc<ContentCache>,
+        staging_dir: PathBuf,
+    ) -> Result<Self, ClientError> {
+        if staging_dir.exists() {
+            if fs::read_dir(&staging_dir)?.next().is_some() {
+                tracing::warn!("discarding unsaved writes in {}", staging_dir.display());
+            }
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        fs::create_dir_all(&staging_dir)?;
+        let vfs = Self {
+            runtime,
+            api,
+            db,
+            device_id,
+            cache,
+            staging_dir,
+            state: Mutex::new(State {
+                tree: Tree::new(),
+                staged: HashMap::new(),
+                handles: HashMap::new(),
+                next_handle: 1,
+            }),
+        };
+        vfs.refresh()?;
+        Ok(vfs)
+    }
+
+    pub fn cache(&self) -> &Arc<ContentCache> {
+        &self.cache
+    }
+
+    /// Rebuild the tree from the remote mirror, after it was updated.
+    pub fn refresh(&self) -> Result<(), ClientError> {
+        let files = self.db.remote_files("")?;
+        let state = &mut *lock(&self.state);
+        state.tree.rebuild(files, &state.staged);
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    pub fn attributes(&self, ino: u64) -> Result<Attributes, VfsError> {
+        let state = self.state();
+        state.tree.attributes(ino, &state.staged)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &str) -> Result<Attributes, VfsError> {
+        let state = self.state();
+        let ino = match name {
+            DIRECTORY_PLACEHOLDER => None,
+            name => state.tree.child(parent, name)?,
+        };
+        state
+            .tree
+            .attributes(ino.ok_or(VfsError::NotFound)?, &state.staged)
+    }
+
+    /// The entries of a directory, placeholder aside, in name order.
+    pub fn read_dir(&self, ino: u64) -> Result<Vec<(String, Attributes)>, VfsError> {
+        let state = self.state();
+        state
+            .tree
+            .children(ino)?
+            .iter()
+            .filter(|(name, _)| *name != DIRECTORY_PLACEHOLDER)
+            .map(|(name, &child)| Ok((name.clone(), state.tree.attributes(child, &state.staged)?)))
+            .collect()
+    }
+
+    /// Parent inode of a directory.
+    pub fn parent(&self, ino: u64) -> Result<u64, VfsError> {
+        Ok(self.state().tree.node(ino)?.parent)
+    }
+
+    /// Path and server checksum of a file.
+    fn file(&self, ino: u64) -> Result<(String, Option<String>), VfsError> {
+        let state = self.state();
+        let node = state.tree.node(ino)?;
+        match &node.entry {
+            Entry::File { checksum, .. } => Ok((node.path.clone(), checksum.clone())),
+            Entry::Directory { .. } => Err(VfsError::IsDir),
+        }
+    }
+
+    /// Local copy of a file's server version, fetched when not cached.
+    fn content(&self, path: &str, checksum: &str) -> Result<PathBuf, ClientError> {
+        self.runtime
+            .block_on(self.cache.fetch(&self.api, path, checksum))
+    }
+
+    /// Put `path` in a staging file, with its current content unless
+    /// `truncate`.
+    fn stage(&self, path: &str, checksum: Option<&str>, truncate: bool) -> Result<(), VfsError> {
+        if self.state().staged.contains_key(path) {
+            if truncate {
+                let staged = self.state().staged[path].file.clone();
+                File::create(staged)?;
+            }
+            return Ok(());
+        }
+        let file = self.staging_dir.join(Uuid::new_v4().simple().to_string());
+        match checksum {
+            Some(checksum) if !truncate => {
+                fs::copy(self.content(path, checksum)?, &file)?;
+            }
+            _ => {
+                File::create(&file)?;
+            }
+        }
+        self.state().staged.insert(
+            path.to_string(),
+            Staged {
+                file,
+                created: Utc::now(),
+                dirty: truncate || checksum.is_none(),
+                handles: 0,
+            },
+        );
+        Ok(())
+    }
+
+    fn add_handle(&self, path: &str, staged: bool, file: File) -> u64 {
+        let mut state = self.state();
+        let fh = state.next_handle;
+        state.next_handle += 1;
+        if staged {
+            if let Some(staged) = state.staged.get_mut(path) {
+                staged.handles += 1;
+            }
+        }
+        state.handles.insert(
+            fh,
+            OpenFile {
+                path: path.to_string(),
+                file,
+                staged,
+            },
+        );
+        fh
+    }
+
+    /// Open a file, fetching its content first unless it is only written.
+    pub fn open(&self, ino: u64, write: bool, truncate: bool) -> Result<u64, VfsError> {
+        let (path, checksum) = self.file(ino)?;
+        let staged = self.state().staged.get(&path).map(|s| s.file.clone());
+        if write {
+            self.stage(&path, checksum.as_deref(), truncate)?;
+        }
+        let file = match (write, staged, checksum) {
+            (true, _, _) => {
+                let staged = self.state().staged[&path].file.clone();
+                OpenOptions::new().read(true).write(true).open(staged)?
+            }
+            (false, Some(staged), _) => File::open(staged)?,
+            (false, None, Some(checksum)) => File::open(self.content(&path, &checksum)?)?,
+            (false, None, None) => return Err(VfsError::NotFound),
+        };
+        let is_staged = write || self.state().staged.contains_key(&path);
+        Ok(self.add_handle(&path, is_staged, file))
+    }
+
+    /// Create an empty file and open it.
+    pub fn create(&self, parent: u64, name: &str) -> Result<(Attributes, u64), VfsError> {
+        check_name(name)?;
+        let path = {
+            let state = self.state();
+            if state.tree.child(parent, name)?.is_some() {
+                return Err(VfsError::Exists);
+            }
+            join(&state.tree.node(parent)?.path, name)
+        };
+        self.stage(&path, None, true)?;
+        let ino = {
+            let state = &mut *self.state();
+            let entry = Entry::File {
+                checksum: None,
+                size: 0,
+            };
+            state
+                .tree
+                .insert(&path, Utc::now(), entry)
+                .ok_or(VfsError::NotDir)?
+        };
+        let staged = self.state().staged[&path].file.clone();
+        let file = OpenOptions::new().read(true).write(true).open(staged)?;
+        let fh = self.add_handle(&path, true, file);
+        Ok((self.attributes(ino)?, fh))
+    }
+
+    pub fn read(&self, fh: u64, offset: u64, size: usize) -> Result<Vec<u8>, VfsError> {
+        let state = self.state();
+        let mut file = &state.handles.get(&fh).ok_or(VfsError::NotFound)?.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buffer = Vec::with_capacity(size);
+        file.take(size as u64).read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let state = &mut *self.state();
+        let handle = state.handles.get(&fh).ok_or(VfsError::NotFound)?;
+        if !handle.staged {
+            return Err(VfsError::Client(ClientError::Io(std::io::Error::from(
+                std::io::ErrorKind::PermissionDenied,
+            ))));
+        }
+        let mut file = &handle.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        if let Some(staged) = state.staged.get_mut(&handle.path) {
+            staged.dirty = true;
+        }
+        Ok(data.len())
+    }
+
+    /// Change a file's size.
+    pub fn truncate(&self, ino: u64, size: u64) -> Result<Attributes, VfsError> {
+        let (path, checksum) = self.file(ino)?;
+        self.stage(&path, checksum.as_deref(), size == 0)?;
+        {
+            let state = &mut *self.state();
+            let staged = state.staged.get_mut(&path).ok_or(VfsError::NotFound)?;
+            OpenOptions::new()
+                .write(true)
+                .open(&staged.file)?
+                .set_len(size)?;
+            staged.dirty = true;
+        }
+        // With no handle open, no release will upload it.
+        if self.state().staged[&path].handles == 0 {
+            self.upload(&path)?;
+            if let Some(staged) = self.state().staged.remove(&path) {
+                let _ = fs::remove_file(staged.file);
+            }
+        }
+        self.attributes(ino)
+    }
+
+    /// Upload `path`'s staging file if it was written since last uploaded.
+    fn upload(&self, path: &str) -> Result<(), ClientError> {
+        let file = match self.state().staged.get(path) {
+            Some(staged) if staged.dirty => staged.file.clone(),
+            _ => return Ok(()),
+        };
+        let hash = FileHasher::hash_file(&file)?;
+        let size = fs::metadata(&file)?.len();
+        let exists = self.db.remote(path)?.is_some();
+        let uploaded = self.runtime.block_on(upload_file(
+            &self.api,
+            self.device_id,
+            &file,
+            path,
+            &hash,
+            size,
+            exists,
+            None,
+        ))?;
+        self.db.set_remote(&uploaded)?;
+        if let Err(e) = self.cache.insert(&hash, &file) {
+            tracing::warn!("could not cache {}: {}", path, e);
+        }
+        let state = &mut *self.state();
+        if let Some(staged) = state.staged.get_mut(path) {
+            staged.dirty = false;
+        }
+        if let Some(&ino) = state.tree.inodes.get(path) {
+            if let Some(node) = state.tree.nodes.get_mut(&ino) {
+                node.modified = uploaded.modified_at;
+                node.entry = Entry::File {
+                    checksum: Some(uploaded.checksum),
+                    size: uploaded.size,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload what was written through `fh`.
+    pub fn flush(&self, fh: u64) -> Result<(), VfsError> {
+        let path = match self.state().handles.get(&fh) {
+            Some(handle) if handle.staged => handle.path.clone(),
+            Some(_) => return Ok(()),
+            None => return Err(VfsError::NotFound),
+        };
+        Ok(self.upload(&path)?)
+    }
+
+    /// Close `fh`; the staging file goes once its last handle is closed
+    /// and its content uploaded.
+    pub fn release(&self, fh: u64) -> Result<(), VfsError> {
+        let Some(handle) = self.state().handles.remove(&fh) else {
+            return Ok(());
+        };
+        if !handle.staged {
+            return Ok(());
+        }
+        let last = {
+            let mut state = self.state();
+            let Some(staged) = state.staged.get_mut(&handle.path) else {
+                return Ok(());
+            };
+            staged.handles = staged.handles.saturating_sub(1);
+            staged.handles == 0
+        };
+        if !last {
+            return Ok(());
+        }
+        if let Err(e) = self.upload(&handle.path) {
+            // Kept staged, and tried again when next flushed.
+            tracing::warn!("could not upload {}: {}", handle.path, e);
+            return Err(e.into());
+        }
+        if let Some(staged) = self.state().staged.remove(&handle.path) {
+            let _ = fs::remove_file(staged.file);
+        }
+        Ok(())
+    }
+
+    /// Push changes made here to the server.
+    fn push(&self, changes: Vec<FileChange>) -> Result<(), ClientError> {
+        let request = SyncRequest {
+            device_id: self.device_id,
+            last_sync_token: self.db.setting(SETTING_SYNC_TOKEN)?,
+            changes,
+        };
+        let response = self.runtime.block_on(self.api.sync(&request))?;
+        apply_pushed(&self.db, &response)
+    }
+
+    fn upload_placeholder(&self, directory: &str) -> Result<(), ClientError> {
+        let state = self.runtime.block_on(self.api.upload(
+            self.device_id,
+            &join(directory, DIRECTORY_PLACEHOLDER),
+            None,
+            Vec::new(),
+        ))?;
+        self.db.set_remote(&state)
+    }
+
+    pub fn mkdir(&self, parent: u64, name: &str) -> Result<Attributes, VfsError> {
+        check_name(name)?;
+        let path = {
+            let state = self.state();
+            if state.tree.child(parent, name)?.is_some() {
+                return Err(VfsError::Exists);
+            }
+            join(&state.tree.node(parent)?.path, name)
+        };
+        self.upload_placeholder(&path)?;
+        let ino = self
+            .state()
+            .tree
+            .insert_directory(&path, Utc::now())
+            .ok_or(VfsError::NotDir)?;
+        self.attributes(ino)
+    }
+
+    /// Path of `name` in `parent`, which must exist.
+    fn existing(&self, parent: u64, name: &str) -> Result<(u64, String), VfsError> {
+        let state = self.state();
+        let ino = state.tree.child(parent, name)?.ok_or(VfsError::NotFound)?;
+        Ok((ino, state.tree.node(ino)?.path.clone()))
+    }
+
+    /// Keep `directory` once its last entry is gone.
+    fn keep_directory(&self, directory: &str) -> Result<(), ClientError> {
+        if !directory.is_empty() && self.db.remote_files(directory)?.is_empty() {
+            self.upload_placeholder(directory)?;
+        }
+        Ok(())
+    }
+
+    pub fn unlink(&self, parent: u64, name: &str) -> Result<(), VfsError> {
+        let (ino, path) = self.existing(parent, name)?;
+        let (_, checksum) = self.file(ino)?;
+        if let Some(checksum) = checksum {
+            self.push(vec![FileChange {
+                path: path.clone(),
+                change_type: ChangeType::Deleted,
+                metadata: None,
+                checksum: Some(checksum),
+                delta: None,
+            }])?;
+            self.db.remove_remote(&path)?;
+        }
+        if let Some(staged) = self.state().staged.remove(&path) {
+            let _ = fs::remove_file(staged.file);
+        }
+        self.keep_directory(split(&path).0)?;
+        self.refresh()?;
+        Ok(())
+    }
+
+    pub fn rmdir(&self, parent: u64, name: &str) -> Result<(), VfsError> {
+        let (ino, path) = self.existing(parent, name)?;
+        let placeholder = {
+            let state = self.state();
+            let children = state.tree.children(ino)?;
+            if children.keys().any(|name| name != DIRECTORY_PLACEHOLDER) {
+                return Err(VfsError::NotEmpty);
+            }
+            join(&path, DIRECTORY_PLACEHOLDER)
+        };
+        if let Some(remote) = self.db.remote(&placeholder)? {
+            self.push(vec![FileChange {
+                path: placeholder.clone(),
+                change_type: ChangeType::Deleted,
+                metadata: None,
+                checksum: Some(remote.hash),
+                delta: None,
+            }])?;
+            self.db.remove_remote(&placeholder)?;
+        }
+        self.keep_directory(split(&path).0)?;
+        self.refresh()?;
+        Ok(())
+    }
+
+    /// Move a file or directory, replacing a file in the way unless
+    /// `no_replace`.
+    pub fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+        no_replace: bool,
+    ) -> Result<(), VfsError> {
+        check_name(new_name)?;
+        let (ino, from) = self.existing(parent, name)?;
+        let (to, target) = {
+            let state = self.state();
+            let to = join(&state.tree.node(new_parent)?.path, new_name);
+            (to, state.tree.child(new_parent, new_name)?)
+        };
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&format!("{}/", from)) {
+            return Err(VfsError::InvalidName);
+        }
+        let mut changes = Vec::new();
+        if let Some(target) = target {
+            if no_replace {
+                return Err(VfsError::Exists);
+            }
+            let state = self.state();
+            match &state.tree.node(target)?.entry {
+                Entry::Directory { children } => {
+                    if children.keys().any(|name| name != DIRECTORY_PLACEHOLDER) {
+                        return Err(VfsError::NotEmpty);
+                    }
+                    if matches!(state.tree.node(ino)?.entry, Entry::File { .. }) {
+                        return Err(VfsError::IsDir);
+                    }
+                }
+                Entry::File { checksum, .. } => {
+                    if matches!(state.tree.node(ino)?.entry, Entry::Directory { .. }) {
+                        return Err(VfsError::NotDir);
+                    }
+                    if let Some(checksum) = checksum {
+                        changes.push(FileChange {
+                            path: to.clone(),
+                            change_type: ChangeType::Deleted,
+                            metadata: None,
+                            checksum: Some(checksum.clone()),
+                            delta: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Every file at or under `from`; without a checksum the server keeps
+        // the content it has.
+        let prefix = format!("{}/", from);
+        let moved: Vec<SyncState> = self
+            .db
+            .remote_files("")?
+            .into_iter()
+            .filter(|file| file.path == from || file.path.starts_with(&prefix))
+            .collect();
+        let target_of = |path: &str| format!("{}{}", to, &path[from.len()..]);
+        changes.extend(moved.iter().map(|file| FileChange {
+            path: target_of(&file.path),
+            change_type: ChangeType::Moved {
+                old_path: file.path.clone(),
+            },
+            metadata: None,
+            checksum: None,
+            delta: None,
+        }));
+        if !changes.is_empty() {
+            self.push(changes)?;
+        }
+        for file in &moved {
+            self.db.remove_remote(&file.path)?;
+            self.db.set_remote(&protocol::sync::FileState {
+                path: target_of(&file.path),
+                checksum: file.hash.clone(),
+                size: file.size,
+                modified_at: file.modified,
+                version: 0,
+                sync_status: protocol::sync::SyncStatus::Synced,
+            })?;
+        }
+        {
+            let state = &mut *self.state();
+            if let Some(replaced) = state.staged.remove(&to) {
+                let _ = fs::remove_file(replaced.file);
+            }
+            let staged: Vec<String> = state
+                .staged
+                .keys()
+                .filter(|path| **path == from || path.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for path in staged {
+                if let Some(staged) = state.staged.remove(&path) {
+                    state.staged.insert(target_of(&path), staged);
+                }
+            }
+            for handle in state.handles.values_mut() {
+                if handle.path == from || handle.path.starts_with(&prefix) {
+                    handle.path = target_of(&handle.path);
+                }
+            }
+        }
+        self.keep_directory(split(&from).0)?;
+        self.refresh()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Reply, Request, TestServer};
+    use protocol::sync::{FileState, SyncStatus};
+    use tempfile::TempDir;
+
+    fn file_state(path: &str, content: &[u8]) -> FileState {
+        FileState {
+            path: path.to_string(),
+            checksum: FileHasher::hash_bytes(content),
+            size: content.len() as u64,
+            modified_at: Utc::now(),
+            version: 1,
+            sync_status: SyncStatus::Synced,
+        }
+    }
+
+    /// Put `content` in the cache, a little after what was put before, so
+    /// the order of use is unambiguous.
+    fn cache(cache: &ContentCache, dir: &Path, content: &[u8]) -> String {
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let checksum = FileHasher::hash_bytes(content);
+        let source = dir.join("source");
+        fs::write(&source, content).unwrap();
+        cache.insert(&checksum, &source).unwrap();
+        checksum
+    }
+
+    fn cached(cache: &ContentCache, checksum: &str) -> bool {
+        cache.dir.join(checksum).exists()
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used_first() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(LocalDb::open_in_memory().unwrap());
+        let contents = ContentCache::new(dir.path().join("cache"), 8, db.clone());
+        let a = cache(&contents, dir.path(), b"aaaa");
+        let b = cache(&contents, dir.path(), b"bbbb");
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(contents.get(&a).unwrap().is_some());
+
+        // b was used last longest ago.
+        let c = cache(&contents, dir.path(), b"cccc");
+        assert!(cached(&contents, &a));
+        assert!(!cached(&contents, &b));
+        assert!(cached(&contents, &c));
+        assert_eq!(db.cached_size().unwrap(), 8);
+        assert!(contents.get(&b).unwrap().is_none());
+
+        let d = cache(&contents, dir.path(), b"dddd");
+        assert!(!cached(&contents, &a));
+        assert!(cached(&contents, &c));
+        assert!(cached(&contents, &d));
+
+        // Content over the limit on its own is kept until trimmed.
+        let big = cache(&contents, dir.path(), b"0123456789");
+        assert!(cached(&contents, &big));
+        assert!(!cached(&contents, &c));
+        assert!(!cached(&contents, &d));
+    }
+
+    #[test]
+    fn test_cache_never_evicts_pinned_files() {
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(LocalDb::open_in_memory().unwrap());
+        let contents = ContentCache::new(dir.path().join("cache"), 4, db.clone());
+        db.set_remote(&file_state("docs/pinned.txt", b"pinned"))
+            .unwrap();
+        db.pin("docs").unwrap();
+        let pinned = cache(&contents, dir.path(), b"pinned");
+        let a = cache(&contents, dir.path(), b"aaaa");
+        let b = cache(&contents, dir.path(), b"bbbb");
+
+        assert!(cached(&contents, &pinned));
+        assert!(!cached(&contents, &a));
+        assert!(cached(&contents, &b));
+        contents.trim().unwrap();
+        assert!(cached(&contents, &pinned));
+        assert!(!cached(&contents, &b));
+
+        // Once unpinned, it goes like any other content.
+        db.unpin("docs").unwrap();
+        contents.trim().unwrap();
+        assert!(!cached(&contents, &pinned));
+        assert_eq!(db.cached_size().unwrap(), 0);
+    }
+
+    fn uploads(server: &TestServer) -> Vec<Request> {
+        server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "PUT")
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_write_back_uploads_dirty_files() {
+        let server = TestServer::start(|request| {
+            // No version to diff against.
+            if request.method == "GET" {
+                return Reply::new(404, "");
+            }
+            let path = request
+                .target
+                .split("path=")
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            Reply::json(&file_state(&path, &request.body))
+        })
+        .await;
+        let dir = TempDir::new().unwrap();
+        let db = Arc::new(LocalDb::open_in_memory().unwrap());
+        let contents = Arc::new(ContentCache::new(
+            dir.path().join("cache"),
+            1 << 20,
+            db.clone(),
+        ));
+        let api = ApiClient::new(&server.url).unwrap();
+        let staging = dir.path().join("staging");
+        let runtime = Handle::current();
+        let vfs_db = db.clone();
+        let (vfs, ino) = tokio::task::spawn_blocking(move || {
+            let vfs =
+                VirtualFs::new(runtime, api, vfs_db, Uuid::new_v4(), contents, staging).unwrap();
+            let (attributes, fh) = vfs.create(ROOT, "notes.txt").unwrap();
+            vfs.write(fh, 0, b"first").unwrap();
+            vfs.flush(fh).unwrap();
+            // Nothing was written since: nothing to upload.
+            vfs.flush(fh).unwrap();
+            vfs.write(fh, 5, b" second").unwrap();
+            vfs.release(fh).unwrap();
+            (vfs, attributes.ino)
+        })
+        .await
+        .unwrap();
+
+        let uploads = uploads(&server);
+        assert_eq!(uploads.len(), 2);
+        assert_eq!(uploads[0].body, b"first");
+        assert_eq!(uploads[1].body, b"first second");
+        let remote = db.remote("notes.txt").unwrap().unwrap();
+        assert_eq!(remote.hash, FileHasher::hash_bytes(b"first second"));
+        assert_eq!(vfs.attributes(ino).unwrap().size, 12);
+        // The staging file went with the last handle, and what was uploaded
+        // is readable from the cache.
+        assert_eq!(fs::read_dir(dir.path().join("staging")).unwrap().count(), 0);
+        assert!(vfs.cache().get(&remote.hash).unwrap().is_some());
+    }
+}
@@ -0,0 +1,59 @@
+//! File system events of sync folders
+
+use crate::error::ClientError;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+
+/// Watches sync folders recursively and reports the paths that changed.
+pub struct FolderWatcher {
+    // Dropping the watcher stops the events.
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<PathBuf>,
+}
+
+impl FolderWatcher {
+    /// Watch `roots`, ignoring events under `ignored` (the client's own
+    /// state directory).
+    pub fn new(roots: &[PathBuf], ignored: PathBuf) -> Result<Self, ClientError> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+                let event = match result {
+                    Ok(event) => event,
+                    Err(e) => {
+                        tracing::warn!("file watcher error: {}", e);
+                        return;
+                    }
+                };
+                if matches!(event.kind, EventKind::Access(_)) {
+                    return;
+                }
+                for path in event.paths {
+                    if !path.starts_with(&ignored) {
+                        let _ = sender.send(path);
+                    }
+                }
+            })
+            .map_err(|e| ClientError::Config(format!("cannot watch files: {}", e)))?;
+        for root in roots {
+            watch(&mut watcher, root)?;
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// The next changed path; `None` once the watcher stopped.
+    pub async fn next(&mut self) -> Option<PathBuf> {
+        self.events.recv().await
+    }
+}
+
+fn watch(watcher: &mut RecommendedWatcher, root: &Path) -> Result<(), ClientError> {
+    std::fs::create_dir_all(root)?;
+    watcher
+        .watch(root, RecursiveMode::Recursive)
+        .map_err(|e| ClientError::Config(format!("cannot watch {}: {}", root.display(), e)))
+}
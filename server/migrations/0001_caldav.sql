@@ -0,0 +1,39 @@
+-- CalDAV calendars and calendar object resources
+
+CREATE TABLE IF NOT EXISTS calendars (
+    id UUID PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    description TEXT,
+    color TEXT,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    is_shared BOOLEAN NOT NULL DEFAULT FALSE,
+    permissions JSONB NOT NULL DEFAULT
+        '{"read_users": [], "write_users": [], "admin_users": [], "public_read": false}',
+    sync_token BIGINT NOT NULL DEFAULT 0,
+    UNIQUE (owner_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS calendar_objects (
+    id UUID PRIMARY KEY,
+    calendar_id UUID NOT NULL REFERENCES calendars (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    uid TEXT NOT NULL,
+    etag TEXT NOT NULL,
+    data TEXT NOT NULL,
+    component_type TEXT NOT NULL,
+    -- Span of all instances, used to pre-filter time-range queries.
+    -- NULL means unbounded (e.g. an RRULE without COUNT or UNTIL).
+    first_start TIMESTAMPTZ,
+    last_end TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (calendar_id, name),
+    UNIQUE (calendar_id, uid)
+);
+
+CREATE INDEX IF NOT EXISTS calendar_objects_span
+    ON calendar_objects (calendar_id, first_start, last_end);
@@ -0,0 +1,25 @@
+-- CalDAV scheduling (RFC 6638): calendar user addresses and schedule inboxes
+
+-- Addresses (without "mailto:", lower-cased) that identify local users as
+-- organizers and attendees.
+CREATE TABLE IF NOT EXISTS calendar_user_addresses (
+    address TEXT PRIMARY KEY,
+    user_id UUID NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS calendar_user_addresses_user
+    ON calendar_user_addresses (user_id);
+
+-- iTIP messages delivered to a user's schedule inbox.
+CREATE TABLE IF NOT EXISTS schedule_messages (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    uid TEXT NOT NULL,
+    method TEXT NOT NULL,
+    originator TEXT NOT NULL,
+    etag TEXT NOT NULL,
+    data TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, name)
+);
@@ -0,0 +1,30 @@
+-- Calendar sharing invitations and calendar proxy delegation
+
+-- Invitations to share a calendar. Access is granted in the calendar's
+-- permissions once the sharee accepts.
+CREATE TABLE IF NOT EXISTS calendar_invites (
+    id UUID PRIMARY KEY,
+    calendar_id UUID NOT NULL REFERENCES calendars (id) ON DELETE CASCADE,
+    sharee_id UUID NOT NULL,
+    access TEXT NOT NULL,                    -- 'read' or 'write'
+    status TEXT NOT NULL DEFAULT 'pending',  -- 'pending', 'accepted' or 'declined'
+    comment TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (calendar_id, sharee_id)
+);
+
+CREATE INDEX IF NOT EXISTS calendar_invites_sharee
+    ON calendar_invites (sharee_id);
+
+-- Calendar proxies: delegates with read or read-write access to all of an
+-- owner's calendars and scheduling mailboxes.
+CREATE TABLE IF NOT EXISTS calendar_proxies (
+    owner_id UUID NOT NULL,
+    delegate_id UUID NOT NULL,
+    can_write BOOLEAN NOT NULL,
+    PRIMARY KEY (owner_id, delegate_id)
+);
+
+CREATE INDEX IF NOT EXISTS calendar_proxies_delegate
+    ON calendar_proxies (delegate_id);
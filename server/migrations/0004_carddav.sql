@@ -0,0 +1,45 @@
+-- CardDAV address books and address object resources
+
+CREATE TABLE IF NOT EXISTS address_books (
+    id UUID PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    is_shared BOOLEAN NOT NULL DEFAULT FALSE,
+    permissions JSONB NOT NULL DEFAULT
+        '{"read_users": [], "write_users": [], "admin_users": [], "public_read": false}',
+    -- Bumped by every change; the revision behind the sync token.
+    sync_token BIGINT NOT NULL DEFAULT 0,
+    UNIQUE (owner_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS address_book_objects (
+    id UUID PRIMARY KEY,
+    address_book_id UUID NOT NULL REFERENCES address_books (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    uid TEXT NOT NULL,
+    etag TEXT NOT NULL,
+    data TEXT NOT NULL,
+    formatted_name TEXT NOT NULL,
+    -- Address book revision of the last write.
+    revision BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (address_book_id, name),
+    UNIQUE (address_book_id, uid)
+);
+
+CREATE INDEX IF NOT EXISTS address_book_objects_revision
+    ON address_book_objects (address_book_id, revision);
+
+-- Deleted objects, reported by sync-collection to clients holding an older
+-- token. Recreating an object removes its tombstone.
+CREATE TABLE IF NOT EXISTS address_book_tombstones (
+    address_book_id UUID NOT NULL REFERENCES address_books (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    revision BIGINT NOT NULL,
+    PRIMARY KEY (address_book_id, name)
+);
@@ -0,0 +1,40 @@
+-- File metadata journal behind the sync protocol
+
+CREATE TABLE IF NOT EXISTS sync_journals (
+    user_id UUID PRIMARY KEY,
+    -- Bumped by every change; the sequence number behind sync tokens.
+    sequence BIGINT NOT NULL DEFAULT 0
+);
+
+-- Latest metadata of every path a user ever synced. Deleted paths stay as
+-- tombstones so devices holding an older token learn about the deletion.
+CREATE TABLE IF NOT EXISTS sync_entries (
+    user_id UUID NOT NULL,
+    path TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    modified_at TIMESTAMPTZ NOT NULL,
+    -- Per-path version, incremented by every change to the path.
+    version BIGINT NOT NULL,
+    deleted BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Destination of a tombstone left by a move.
+    moved_to TEXT,
+    metadata JSONB,
+    -- The protocol ChangeType of the last change.
+    change JSONB NOT NULL,
+    -- Journal sequence number and device of the last change.
+    sequence BIGINT NOT NULL,
+    device_id UUID NOT NULL,
+    PRIMARY KEY (user_id, path)
+);
+
+CREATE INDEX IF NOT EXISTS sync_entries_sequence ON sync_entries (user_id, sequence);
+
+-- What each device last synchronized, as a protocol SyncState.
+CREATE TABLE IF NOT EXISTS sync_device_states (
+    device_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    last_sync TIMESTAMPTZ NOT NULL,
+    sync_token TEXT NOT NULL,
+    file_states JSONB NOT NULL DEFAULT '{}'
+);
@@ -0,0 +1,21 @@
+-- Registered sync devices
+
+CREATE TABLE IF NOT EXISTS devices (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    device_type JSONB NOT NULL,
+    platform TEXT NOT NULL,
+    version TEXT NOT NULL,
+    sync_folders JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Revoked devices keep their row so they can be told why they are refused.
+    revoked_at TIMESTAMPTZ,
+    -- Set by a remote wipe until the device confirms it deleted its sync
+    -- folders, which also revokes it.
+    wipe_requested_at TIMESTAMPTZ,
+    wiped_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS devices_user ON devices (user_id);
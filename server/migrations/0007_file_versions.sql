@@ -0,0 +1,16 @@
+-- Stored contents of synced files
+
+-- One row per version whose content was uploaded, the content itself being
+-- kept on disk under its checksum.
+CREATE TABLE IF NOT EXISTS sync_file_versions (
+    user_id UUID NOT NULL,
+    path TEXT NOT NULL,
+    -- The sync_entries version the content belongs to.
+    version BIGINT NOT NULL,
+    -- BLAKE3 hex digest of the content, naming its blob.
+    checksum TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    device_id UUID NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, path, version)
+);
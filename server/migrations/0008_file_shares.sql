@@ -0,0 +1,20 @@
+-- Public links to synced files
+
+-- A link serves the current content of a path to anyone holding its token,
+-- until it expires, runs out of downloads or is revoked.
+CREATE TABLE IF NOT EXISTS sync_file_shares (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    path TEXT NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    -- Argon2 hash of the link's password, if it has one.
+    password_hash TEXT,
+    expires_at TIMESTAMPTZ,
+    max_downloads BIGINT,
+    download_count BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS sync_file_shares_user
+    ON sync_file_shares (user_id);
@@ -0,0 +1,30 @@
+-- End-to-end encrypted sync folders
+
+-- X25519 public key of a device, base64, that folder keys are sealed for.
+ALTER TABLE devices ADD COLUMN IF NOT EXISTS public_key TEXT;
+
+-- Folders whose names and contents only the devices can decrypt. Encrypted
+-- folders neither nest nor contain one another.
+CREATE TABLE IF NOT EXISTS encrypted_folders (
+    id UUID PRIMARY KEY,
+    owner_id UUID NOT NULL,
+    path TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (owner_id, path)
+);
+
+-- The folder key sealed for each device that may read the folder. Sealing
+-- it for another user's device shares the folder with that user.
+CREATE TABLE IF NOT EXISTS encrypted_folder_keys (
+    folder_id UUID NOT NULL REFERENCES encrypted_folders (id) ON DELETE CASCADE,
+    device_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    sealed_key TEXT NOT NULL,
+    -- Device that sealed the key.
+    sealed_by UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (folder_id, device_id)
+);
+
+CREATE INDEX IF NOT EXISTS encrypted_folder_keys_user
+    ON encrypted_folder_keys (user_id);
@@ -0,0 +1,27 @@
+-- Key management
+
+-- Data-encryption keys, each version wrapped by a master key that never
+-- leaves its source. Rotating a key adds a version; rotating the master key
+-- re-wraps every version.
+CREATE TABLE IF NOT EXISTS data_keys (
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    wrapped_key BYTEA NOT NULL,
+    -- Id of the master key that wrapped the key.
+    master_key_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- Set once a newer version replaced this one, which is then only used
+    -- to decrypt.
+    retired_at TIMESTAMPTZ,
+    PRIMARY KEY (name, version)
+);
+
+-- A user's recovery key: the public half of an X25519 key pair kept
+-- offline. Folder keys are sealed for it as for a device, so they can be
+-- recovered after losing every device.
+CREATE TABLE IF NOT EXISTS recovery_keys (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL UNIQUE,
+    public_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,9 @@
+-- Storage re-wraps
+
+-- Master keys that wrap the file key of every stored object, recorded once
+-- the re-wrap job went through the whole storage, so it is not scanned
+-- again on every start.
+CREATE TABLE IF NOT EXISTS storage_rewraps (
+    key_id BIGINT PRIMARY KEY,
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+-- Passphrase recovery keys
+
+-- Argon2id parameters a recovery key pair is derived from its passphrase
+-- with. Keys registered before are raw recovery codes and have none.
+ALTER TABLE recovery_keys ADD COLUMN IF NOT EXISTS kdf_params TEXT;
@@ -0,0 +1,109 @@
+//! Address book API endpoints
+
+use crate::auth::AuthenticatedUser;
+use crate::carddav::collection::address_book_href;
+use crate::carddav::{self, store::AddressBookStore};
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use protocol::caldav::{CalendarAccess, CalendarPermissions};
+use protocol::carddav::CreateAddressBookRequest;
+use uuid::Uuid;
+
+fn internal_error(context: &str, e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"error": format!("{}: {}", context, e)}))
+}
+
+/// Address books the caller owns or that are shared with them, with the
+/// CardDAV URL to configure on their devices.
+pub async fn list_address_books(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = AddressBookStore::new(&data.db_pool);
+    let address_books = match store.list_address_books(user.user_id).await {
+        Ok(address_books) => address_books,
+        Err(e) => return Ok(internal_error("Failed to load address books", e)),
+    };
+    let listed: Vec<_> = address_books
+        .into_iter()
+        .map(|address_book| {
+            serde_json::json!({
+                "url": address_book_href(address_book.id),
+                "address_book": address_book,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+pub async fn create_address_book(
+    user: AuthenticatedUser,
+    req: web::Json<CreateAddressBookRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let req = req.into_inner();
+    if req.name.trim().is_empty() {
+        return Ok(
+            HttpResponse::BadRequest().json(serde_json::json!({"error": "Name is required"}))
+        );
+    }
+    let store = AddressBookStore::new(&data.db_pool);
+    let display_name = req.display_name.as_deref().unwrap_or(&req.name);
+    match store
+        .create_address_book(
+            user.user_id,
+            &req.name,
+            display_name,
+            req.description.as_deref(),
+        )
+        .await
+    {
+        Ok(address_book) => Ok(HttpResponse::Created().json(serde_json::json!({
+            "url": address_book_href(address_book.id),
+            "address_book": address_book,
+        }))),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(HttpResponse::Conflict()
+            .json(serde_json::json!({"error": "An address book with this name already exists"}))),
+        Err(e) => Ok(internal_error("Failed to create address book", e)),
+    }
+}
+
+/// Replace an address book's permissions, e.g. to share it with a team.
+/// Requires admin access.
+pub async fn update_permissions(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    req: web::Json<CalendarPermissions>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let address_book_id = path.into_inner();
+    let store = AddressBookStore::new(&data.db_pool);
+    if let Err(response) = carddav::authorize(
+        &store,
+        address_book_id,
+        Some(user.user_id),
+        CalendarAccess::Admin,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let permissions = req.into_inner();
+    if let Err(e) = store
+        .update_permissions(address_book_id, &permissions)
+        .await
+    {
+        return Ok(internal_error("Failed to update permissions", e));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "permissions": permissions })))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/addressbooks", web::get().to(list_address_books))
+        .route("/addressbooks", web::post().to(create_address_book))
+        .route(
+            "/addressbooks/{address_book_id}/permissions",
+            web::put().to(update_permissions),
+        );
+}
@@ -0,0 +1,369 @@
+//! Calendar API endpoints
+
+use crate::auth::AuthenticatedUser;
+use crate::caldav::store::CalendarStore;
+use crate::caldav::{access, freebusy, public, sharing, transfer};
+use crate::AppState;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse, Result};
+use protocol::caldav::{
+    CalendarAccess, CalendarPermissions, CalendarTask, FreeBusyRequest, FreeBusyResponse,
+};
+use protocol::ical::ICalendar;
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// Free/busy time of each attendee across all of their calendars, for the
+/// scheduling view.
+pub async fn free_busy(
+    _user: AuthenticatedUser,
+    req: web::Json<FreeBusyRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(message) = freebusy::check_range(req.start, req.end) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": message})));
+    }
+    let store = CalendarStore::new(&data.db_pool);
+
+    // Attendees are given by user id or calendar user address.
+    let mut responses = Vec::with_capacity(req.attendees.len());
+    for attendee in &req.attendees {
+        let user_id =
+            match Uuid::parse_str(attendee) {
+                Ok(user_id) => Some(user_id),
+                Err(_) => match store.user_for_address(attendee).await {
+                    Ok(user_id) => user_id,
+                    Err(e) => return Ok(HttpResponse::InternalServerError().json(
+                        serde_json::json!({"error": format!("Failed to resolve attendee: {}", e)}),
+                    )),
+                },
+            };
+        let Some(user_id) = user_id else {
+            return Ok(HttpResponse::BadRequest()
+                .json(serde_json::json!({"error": format!("Unknown attendee: {}", attendee)})));
+        };
+        let calendar_ids: Vec<Uuid> = match store.list_calendars(user_id).await {
+            Ok(calendars) => calendars.iter().map(|c| c.id).collect(),
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError().json(
+                    serde_json::json!({"error": format!("Failed to load calendars: {}", e)}),
+                ))
+            }
+        };
+        match freebusy::busy_periods(&store, &calendar_ids, req.start, req.end).await {
+            Ok(busy_periods) => responses.push(FreeBusyResponse {
+                organizer: req.organizer.clone(),
+                attendee: attendee.clone(),
+                start: req.start,
+                end: req.end,
+                busy_periods,
+            }),
+            Err(e) => {
+                return Ok(HttpResponse::InternalServerError()
+                    .json(serde_json::json!({"error": format!("Failed to load events: {}", e)})))
+            }
+        }
+    }
+    Ok(HttpResponse::Ok().json(responses))
+}
+
+fn internal_error(context: &str, e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"error": format!("{}: {}", context, e)}))
+}
+
+/// Share invitations addressed to the caller.
+pub async fn list_invites(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = CalendarStore::new(&data.db_pool);
+    let invites = match store.invites_for_user(user.user_id).await {
+        Ok(invites) => invites,
+        Err(e) => return Ok(internal_error("Failed to load invitations", e)),
+    };
+    let mut listed = Vec::with_capacity(invites.len());
+    for invite in invites {
+        let calendar = match store.get_calendar(invite.calendar_id).await {
+            Ok(Some(calendar)) => calendar,
+            Ok(None) => continue,
+            Err(e) => return Ok(internal_error("Failed to load calendar", e)),
+        };
+        listed.push(serde_json::json!({
+            "id": invite.id,
+            "calendar_id": invite.calendar_id,
+            "calendar_name": calendar.display_name,
+            "owner_id": calendar.owner_id,
+            "access": invite.access,
+            "status": invite.status,
+            "comment": invite.comment,
+            "created_at": invite.created_at,
+        }));
+    }
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+async fn answer_invite(
+    invite_id: Uuid,
+    user: AuthenticatedUser,
+    data: &AppState,
+    accept: bool,
+) -> HttpResponse {
+    let store = CalendarStore::new(&data.db_pool);
+    let invite = match store.get_invite(invite_id).await {
+        Ok(Some(invite)) if invite.sharee_id == user.user_id => invite,
+        Ok(_) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"error": "Invitation not found"}))
+        }
+        Err(e) => return internal_error("Failed to load invitation", e),
+    };
+    match sharing::answer_invite(&store, &invite, accept).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "success": true,
+            "status": if accept { sharing::ACCEPTED } else { sharing::DECLINED },
+        })),
+        Err(e) => internal_error("Failed to answer invitation", e),
+    }
+}
+
+pub async fn accept_invite(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    Ok(answer_invite(path.into_inner(), user, &data, true).await)
+}
+
+pub async fn decline_invite(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    Ok(answer_invite(path.into_inner(), user, &data, false).await)
+}
+
+/// Replace a calendar's permissions directly, e.g. to publish it with
+/// `public_read`. Requires admin access.
+pub async fn update_permissions(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    req: web::Json<CalendarPermissions>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize(
+        &store,
+        calendar_id,
+        Some(user.user_id),
+        CalendarAccess::Admin,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let permissions = req.into_inner();
+    if let Err(e) = store.update_permissions(calendar_id, &permissions).await {
+        return Ok(internal_error("Failed to update permissions", e));
+    }
+    let public_url = permissions
+        .public_read
+        .then(|| public::public_href(calendar_id));
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "permissions": permissions,
+        "public_url": public_url,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    /// Name of the calendar to create; defaults to the file's
+    /// `X-WR-CALNAME`.
+    pub name: Option<String>,
+}
+
+fn invalid_calendar(e: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .json(serde_json::json!({"error": format!("Invalid calendar data: {}", e)}))
+}
+
+/// Create a calendar from an `.ics` file, e.g. one exported from another
+/// server.
+pub async fn import_new(
+    user: AuthenticatedUser,
+    query: web::Query<ImportQuery>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let ics = match transfer::parse_upload(&body) {
+        Ok(ics) => ics,
+        Err(e) => return Ok(invalid_calendar(e)),
+    };
+    let calendar_property = |name: &str| ics.root.property(name).map(|p| p.text_value());
+    let name = query
+        .into_inner()
+        .name
+        .or_else(|| calendar_property("X-WR-CALNAME"))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Imported".to_string());
+    let timezone = calendar_property("X-WR-TIMEZONE").unwrap_or_else(|| "UTC".to_string());
+
+    let store = CalendarStore::new(&data.db_pool);
+    let calendar = match store
+        .create_calendar(user.user_id, &name, &name, &timezone)
+        .await
+    {
+        Ok(calendar) => calendar,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Ok(HttpResponse::Conflict()
+                .json(serde_json::json!({"error": "A calendar with this name already exists"})))
+        }
+        Err(e) => return Ok(internal_error("Failed to create calendar", e)),
+    };
+    match transfer::import(&store, calendar.id, &ics).await {
+        Ok(result) => Ok(HttpResponse::Created().json(serde_json::json!({
+            "calendar": calendar,
+            "result": result,
+        }))),
+        Err(e) => Ok(internal_error("Failed to import calendar", e)),
+    }
+}
+
+/// Import an `.ics` file into an existing calendar. Requires write access.
+pub async fn import_into(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize(
+        &store,
+        calendar_id,
+        Some(user.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let ics = match transfer::parse_upload(&body) {
+        Ok(ics) => ics,
+        Err(e) => return Ok(invalid_calendar(e)),
+    };
+    match transfer::import(&store, calendar_id, &ics).await {
+        Ok(result) => Ok(HttpResponse::Ok().json(result)),
+        Err(e) => Ok(internal_error("Failed to import calendar", e)),
+    }
+}
+
+/// The whole calendar as a single `.ics` file.
+pub async fn export(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    let (calendar, _) = match access::authorize(
+        &store,
+        calendar_id,
+        Some(user.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        Ok(authorized) => authorized,
+        Err(response) => return Ok(response),
+    };
+    let objects = match store.list_objects(calendar_id).await {
+        Ok(objects) => objects,
+        Err(e) => return Ok(internal_error("Failed to load objects", e)),
+    };
+    let filename: String = calendar
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.ics\"", filename),
+        ))
+        .body(transfer::export(&calendar, &objects).to_ics()))
+}
+
+/// Tasks (VTODOs) of a calendar.
+pub async fn list_tasks(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize(
+        &store,
+        calendar_id,
+        Some(user.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let objects = match store.list_objects(calendar_id).await {
+        Ok(objects) => objects,
+        Err(e) => return Ok(internal_error("Failed to load objects", e)),
+    };
+    let mut tasks: Vec<CalendarTask> = Vec::new();
+    for object in objects.iter().filter(|o| o.component_type == "VTODO") {
+        match ICalendar::parse(&object.data).and_then(|ics| ics.tasks(calendar_id)) {
+            Ok(parsed) => tasks.extend(parsed.into_iter().map(|mut task| {
+                task.id = object.id;
+                task.etag = object.etag.clone();
+                task
+            })),
+            Err(e) => tracing::warn!("Skipping unparsable task {}: {}", object.name, e),
+        }
+    }
+    Ok(HttpResponse::Ok().json(tasks))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/calendars/freebusy", web::post().to(free_busy))
+        .route("/calendars/invites", web::get().to(list_invites))
+        .route(
+            "/calendars/invites/{invite_id}/accept",
+            web::post().to(accept_invite),
+        )
+        .route(
+            "/calendars/invites/{invite_id}/decline",
+            web::post().to(decline_invite),
+        )
+        .route(
+            "/calendars/{calendar_id}/permissions",
+            web::put().to(update_permissions),
+        )
+        .service(
+            web::resource("/calendars/import")
+                .app_data(web::PayloadConfig::new(transfer::MAX_IMPORT_SIZE))
+                .route(web::post().to(import_new)),
+        )
+        .service(
+            web::resource("/calendars/{calendar_id}/import")
+                .app_data(web::PayloadConfig::new(transfer::MAX_IMPORT_SIZE))
+                .route(web::post().to(import_into)),
+        )
+        .route("/calendars/{calendar_id}/export", web::get().to(export))
+        .route("/calendars/{calendar_id}/tasks", web::get().to(list_tasks));
+}
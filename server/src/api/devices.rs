@@ -0,0 +1,209 @@
+//! Device registration and management API endpoints
+
+use crate::auth::{issue_device_token, AuthenticatedUser};
+use crate::sync::devices::{self, DeviceStore};
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use protocol::sync::{RegisterDeviceRequest, RegisterDeviceResponse, UpdateDeviceRequest};
+use uuid::Uuid;
+
+const MAX_NAME_LENGTH: usize = 255;
+
+fn internal_error(context: &str, e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"error": format!("{}: {}", context, e)}))
+}
+
+fn invalid_name(name: &str) -> Option<HttpResponse> {
+    let name = name.trim();
+    (name.is_empty() || name.len() > MAX_NAME_LENGTH).then(|| {
+        HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid device name"}))
+    })
+}
+
+fn invalid_public_key(public_key: &str) -> Option<HttpResponse> {
+    let valid = crypto::from_base64(public_key).is_ok_and(|key| key.len() == 32);
+    (!valid).then(|| {
+        HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid public key"}))
+    })
+}
+
+/// Register a device and issue a token scoped to it.
+pub async fn register_device(
+    user: AuthenticatedUser,
+    req: web::Json<RegisterDeviceRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    if let Some(response) = invalid_name(&req.name) {
+        return Ok(response);
+    }
+    if let Some(response) = req.public_key.as_deref().and_then(invalid_public_key) {
+        return Ok(response);
+    }
+    let device = match store.register(user.user_id, &req).await {
+        Ok(device) => device,
+        Err(e) => return Ok(internal_error("Failed to register device", e)),
+    };
+    match issue_device_token(user.user_id, device.id, &data.config.auth) {
+        Ok(token) => Ok(HttpResponse::Created().json(RegisterDeviceResponse { device, token })),
+        Err(e) => Ok(HttpResponse::InternalServerError()
+            .json(serde_json::json!({"error": format!("Failed to issue token: {}", e)}))),
+    }
+}
+
+/// The caller's devices, revoked ones included, most recently seen first.
+pub async fn list_devices(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    match store.list(user.user_id).await {
+        Ok(devices) => Ok(HttpResponse::Ok().json(devices)),
+        Err(e) => Ok(internal_error("Failed to load devices", e)),
+    }
+}
+
+pub async fn get_device(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    match devices::owned_device(&store, &user, path.into_inner()).await {
+        Ok(device) => Ok(HttpResponse::Ok().json(device)),
+        Err(response) => Ok(response),
+    }
+}
+
+/// Rename a device, update its version and sync folders, or give it the
+/// public key it was registered without.
+pub async fn update_device(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    req: web::Json<UpdateDeviceRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    if let Err(response) = devices::owned_device(&store, &user, device_id).await {
+        return Ok(response);
+    }
+    if let Some(response) = req.name.as_deref().and_then(invalid_name) {
+        return Ok(response);
+    }
+    if let Some(response) = req.public_key.as_deref().and_then(invalid_public_key) {
+        return Ok(response);
+    }
+    match store
+        .update(
+            device_id,
+            req.name.as_deref().map(str::trim),
+            req.version.as_deref(),
+            req.sync_folders.as_deref(),
+            req.public_key.as_deref(),
+        )
+        .await
+    {
+        Ok(Some(device)) => Ok(HttpResponse::Ok().json(device)),
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => Ok(internal_error("Failed to update device", e)),
+    }
+}
+
+/// Revoke a device: its token and its sync requests are refused from now
+/// on.
+pub async fn revoke_device(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    if let Err(response) = devices::owned_device(&store, &user, device_id).await {
+        return Ok(response);
+    }
+    match store.revoke(device_id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(internal_error("Failed to revoke device", e)),
+    }
+}
+
+/// Ask a device to delete its sync folders. The device learns about it on
+/// its next sync, which is refused until it confirms the wipe.
+pub async fn wipe_device(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    match devices::owned_device(&store, &user, device_id).await {
+        Ok(device) if !device.is_active => {
+            return Ok(HttpResponse::Conflict()
+                .json(serde_json::json!({"error": "Device has been revoked"})))
+        }
+        Ok(_) => {}
+        Err(response) => return Ok(response),
+    }
+    match store.request_wipe(device_id).await {
+        Ok(()) => Ok(HttpResponse::Accepted().finish()),
+        Err(e) => Ok(internal_error("Failed to request wipe", e)),
+    }
+}
+
+/// Called by a device once it deleted its sync folders; revokes it.
+pub async fn confirm_wipe(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    match devices::owned_device(&store, &user, device_id).await {
+        Ok(device) if !device.wipe_requested => {
+            return Ok(HttpResponse::Conflict()
+                .json(serde_json::json!({"error": "No wipe was requested"})))
+        }
+        Ok(_) => {}
+        Err(response) => return Ok(response),
+    }
+    match store.confirm_wipe(device_id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(internal_error("Failed to confirm wipe", e)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/devices", web::get().to(list_devices))
+        .route("/devices", web::post().to(register_device))
+        .route("/devices/{device_id}", web::get().to(get_device))
+        .route("/devices/{device_id}", web::patch().to(update_device))
+        .route("/devices/{device_id}", web::delete().to(revoke_device))
+        .route("/devices/{device_id}/wipe", web::post().to(wipe_device))
+        .route(
+            "/devices/{device_id}/wipe/confirm",
+            web::post().to(confirm_wipe),
+        );
+}
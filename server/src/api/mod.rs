@@ -2,7 +2,10 @@
 
 pub mod files;
 pub mod auth;
+pub mod calendars;
+pub mod addressbooks;
 pub mod websocket;
+pub mod devices;
 
 use actix_web::web;
 
@@ -11,6 +14,10 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
         web::scope("/api/v1")
             .configure(files::configure)
             .configure(auth::configure)
+            .configure(calendars::configure)
+            .configure(addressbooks::configure)
             .configure(websocket::configure)
+            .configure(devices::configure)
+            .configure(crate::sync::configure)
     );
 }
@@ -1,2 +1,102 @@
-// Authentication module
-// This module will contain authentication-related functionality
+//! Request authentication
+//!
+//! Clients authenticate with a JWT bearer token whose subject is the user id.
+//! Tokens issued to a registered device also name the device, so revoking
+//! the device disables them.
+
+use crate::config::auth::AuthConfig;
+use crate::AppState;
+use actix_web::dev::Payload;
+use actix_web::{error, http::header, web, FromRequest, HttpRequest};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    pub exp: u64,
+    /// The device a device-scoped token was issued to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<Uuid>,
+}
+
+pub fn issue_token(user_id: Uuid, config: &AuthConfig) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: user_id,
+        exp: chrono::Utc::now().timestamp() as u64 + config.jwt_expiration,
+        device: None,
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )
+}
+
+/// Issue a long-lived token for a registered device.
+pub fn issue_device_token(
+    user_id: Uuid,
+    device_id: Uuid,
+    config: &AuthConfig,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: user_id,
+        exp: chrono::Utc::now().timestamp() as u64 + config.device_token_expiration,
+        device: Some(device_id),
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+    )
+}
+
+pub fn verify_token(token: &str, config: &AuthConfig) -> Result<Claims, jsonwebtoken::errors::Error> {
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+/// The authenticated caller. Use `Option<AuthenticatedUser>` for endpoints
+/// that also serve anonymous requests.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    /// Set for device-scoped tokens. Endpoints serving devices check it
+    /// against the device registry, as a signature alone cannot tell a
+    /// revoked device.
+    pub device_id: Option<Uuid>,
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthenticatedUser, error::Error> {
+    let data = req
+        .app_data::<web::Data<AppState>>()
+        .ok_or_else(|| error::ErrorInternalServerError("Application state missing"))?;
+    // TODO: Accept HTTP Basic credentials for DAV clients once users are stored
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| error::ErrorUnauthorized("Authentication required"))?;
+    let claims = verify_token(token.trim(), &data.config.auth)
+        .map_err(|_| error::ErrorUnauthorized("Invalid or expired token"))?;
+    Ok(AuthenticatedUser {
+        user_id: claims.sub,
+        device_id: claims.device,
+    })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = error::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
@@ -0,0 +1,208 @@
+//! Access control for calendars and scheduling mailboxes (RFC 3744)
+//!
+//! Access comes from ownership, the calendar's permissions (set directly or
+//! by accepting a share invitation) and calendar proxies: read and
+//! read-write delegates act for the owner on all of their calendars and on
+//! their schedule inbox and outbox.
+
+use super::collection::calendar_href;
+use super::store::CalendarStore;
+use crate::webdav::internal_error;
+use crate::webdav::xml::{self, PropName, DAV};
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+use protocol::caldav::{Calendar, CalendarAccess};
+use quick_xml::escape::escape;
+use uuid::Uuid;
+
+pub fn principal_href(user_id: Uuid) -> String {
+    format!("/principals/users/{}/", user_id)
+}
+
+/// Name of the read-only or read-write proxy group of a principal.
+pub fn proxy_group(can_write: bool) -> &'static str {
+    if can_write {
+        "calendar-proxy-write"
+    } else {
+        "calendar-proxy-read"
+    }
+}
+
+pub fn proxy_group_href(user_id: Uuid, can_write: bool) -> String {
+    format!("{}{}/", principal_href(user_id), proxy_group(can_write))
+}
+
+/// Parse a principal href back into its user id.
+pub fn principal_id(href: &str) -> Option<Uuid> {
+    let rest = href.trim_end_matches('/');
+    let id = rest.strip_prefix("/principals/users/")?;
+    Uuid::parse_str(id).ok()
+}
+
+/// Access `user` has to everything owned by `owner_id` as the owner or one
+/// of their proxies.
+pub async fn delegated_access(
+    store: &CalendarStore,
+    owner_id: Uuid,
+    user: Option<Uuid>,
+) -> Result<Option<CalendarAccess>, sqlx::Error> {
+    let Some(user) = user else {
+        return Ok(None);
+    };
+    if user == owner_id {
+        return Ok(Some(CalendarAccess::Admin));
+    }
+    Ok(store.get_proxy(owner_id, user).await?.map(|proxy| {
+        if proxy.can_write {
+            CalendarAccess::Write
+        } else {
+            CalendarAccess::Read
+        }
+    }))
+}
+
+pub async fn calendar_access(
+    store: &CalendarStore,
+    calendar: &Calendar,
+    user: Option<Uuid>,
+) -> Result<Option<CalendarAccess>, sqlx::Error> {
+    let delegated = delegated_access(store, calendar.owner_id, user).await?;
+    Ok(calendar.access(user).max(delegated))
+}
+
+fn privilege_name(access: CalendarAccess) -> &'static str {
+    match access {
+        CalendarAccess::Read => "read",
+        CalendarAccess::Write => "write",
+        CalendarAccess::Admin => "all",
+    }
+}
+
+/// 401 for anonymous callers, otherwise 403 with `DAV:need-privileges`.
+pub fn denied(href: &str, user: Option<Uuid>, required: CalendarAccess) -> HttpResponse {
+    if user.is_none() {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer realm=\"rouillecloud\""))
+            .json(serde_json::json!({"error": "Authentication required"}));
+    }
+    let body = format!(
+        "<d:need-privileges><d:resource><d:href>{}</d:href>\
+         <d:privilege><d:{}/></d:privilege></d:resource></d:need-privileges>",
+        escape(href),
+        privilege_name(required)
+    );
+    xml::document_response(StatusCode::FORBIDDEN, PropName::new(DAV, "error"), &body)
+}
+
+/// Load a calendar the caller has at least `required` access to; the error
+/// is the response to return.
+pub async fn authorize(
+    store: &CalendarStore,
+    calendar_id: Uuid,
+    user: Option<Uuid>,
+    required: CalendarAccess,
+) -> Result<(Calendar, CalendarAccess), HttpResponse> {
+    let calendar = match store.get_calendar(calendar_id).await {
+        Ok(Some(calendar)) => calendar,
+        Ok(None) => {
+            return Err(
+                HttpResponse::NotFound().json(serde_json::json!({"error": "Calendar not found"}))
+            )
+        }
+        Err(e) => return Err(internal_error("Failed to load calendar", e)),
+    };
+    match calendar_access(store, &calendar, user).await {
+        Ok(Some(access)) if access >= required => Ok((calendar, access)),
+        Ok(_) => Err(denied(&calendar_href(calendar_id), user, required)),
+        Err(e) => Err(internal_error("Failed to check access", e)),
+    }
+}
+
+/// Check the caller's access to `href`, a resource of `owner_id` other than
+/// a calendar: their schedule inbox and outbox or their proxy groups.
+pub async fn authorize_user(
+    store: &CalendarStore,
+    owner_id: Uuid,
+    href: &str,
+    user: Option<Uuid>,
+    required: CalendarAccess,
+) -> Result<(), HttpResponse> {
+    match delegated_access(store, owner_id, user).await {
+        Ok(Some(access)) if access >= required => Ok(()),
+        Ok(_) => Err(denied(href, user, required)),
+        Err(e) => Err(internal_error("Failed to check access", e)),
+    }
+}
+
+/// `DAV:current-user-privilege-set` contents for `access`, without the
+/// CalDAV-specific `read-free-busy`.
+pub fn privilege_set(access: CalendarAccess) -> String {
+    let mut privileges = vec!["<d:read/>", "<d:read-current-user-privilege-set/>"];
+    if access >= CalendarAccess::Write {
+        privileges.extend([
+            "<d:write/>",
+            "<d:write-properties/>",
+            "<d:write-content/>",
+            "<d:bind/>",
+            "<d:unbind/>",
+        ]);
+    }
+    if access >= CalendarAccess::Admin {
+        privileges.extend(["<d:read-acl/>", "<d:write-acl/>", "<d:share/>", "<d:all/>"]);
+    }
+    privileges
+        .iter()
+        .map(|p| format!("<d:privilege>{}</d:privilege>", p))
+        .collect()
+}
+
+fn ace(principal: &str, access: CalendarAccess, protected: bool) -> String {
+    let mut privileges = String::from("<d:privilege><d:read/></d:privilege>");
+    if access >= CalendarAccess::Write {
+        privileges.push_str("<d:privilege><d:write/></d:privilege>");
+    }
+    if access >= CalendarAccess::Admin {
+        privileges = "<d:privilege><d:all/></d:privilege>".to_string();
+    }
+    format!(
+        "<d:ace><d:principal>{}</d:principal><d:grant>{}</d:grant>{}</d:ace>",
+        principal,
+        privileges,
+        if protected { "<d:protected/>" } else { "" }
+    )
+}
+
+/// `DAV:acl` contents describing who has access to `calendar`. Entries for
+/// the owner and their proxy groups are protected; the others follow the
+/// calendar's permissions.
+pub fn acl(calendar: &Calendar) -> String {
+    let href = |user: Uuid| xml::hrefs([principal_href(user)]);
+    let owner = calendar.owner_id;
+    let mut out = ace(&href(owner), CalendarAccess::Admin, true);
+    for can_write in [true, false] {
+        let access = if can_write {
+            CalendarAccess::Write
+        } else {
+            CalendarAccess::Read
+        };
+        out.push_str(&ace(
+            &xml::hrefs([proxy_group_href(owner, can_write)]),
+            access,
+            true,
+        ));
+    }
+    let permissions = &calendar.permissions;
+    for (users, access) in [
+        (&permissions.admin_users, CalendarAccess::Admin),
+        (&permissions.write_users, CalendarAccess::Write),
+        (&permissions.read_users, CalendarAccess::Read),
+    ] {
+        for user in users {
+            out.push_str(&ace(&href(*user), access, false));
+        }
+    }
+    if permissions.public_read {
+        out.push_str(&ace("<d:all/>", CalendarAccess::Read, false));
+    }
+    out
+}
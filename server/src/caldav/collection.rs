@@ -0,0 +1,158 @@
+//! PROPFIND on calendar collections (RFC 4791 §5.2, RFC 3744 §5)
+
+use super::access;
+use super::report::object_href;
+use super::sharing;
+use super::store::CalendarStore;
+use crate::auth::AuthenticatedUser;
+use crate::webdav::internal_error;
+use crate::webdav::xml::{self, MultiStatus, PropName, PropValue, Response, CALDAV, DAV};
+use crate::AppState;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use protocol::caldav::{Calendar, CalendarAccess};
+use uuid::Uuid;
+
+/// Apple's calendar extensions (`calendar-color`).
+const APPLE_ICAL: &str = "http://apple.com/ns/ical/";
+
+pub fn calendar_href(calendar_id: Uuid) -> String {
+    format!("/caldav/calendars/{}/", calendar_id)
+}
+
+const SUPPORTED_COMPONENTS: &str = "<c:comp name=\"VEVENT\"/><c:comp name=\"VTODO\"/>\
+     <c:comp name=\"VJOURNAL\"/><c:comp name=\"VFREEBUSY\"/>";
+
+const SUPPORTED_REPORTS: &str = "<d:supported-report><d:report><c:calendar-query/></d:report>\
+     </d:supported-report><d:supported-report><d:report><c:calendar-multiget/></d:report>\
+     </d:supported-report><d:supported-report><d:report><c:free-busy-query/></d:report>\
+     </d:supported-report>";
+
+/// `DAV:share-access` of the caller: the owner sees whether the calendar is
+/// shared, sharees see their own access.
+fn share_access(calendar: &Calendar, user: Option<Uuid>, access: CalendarAccess) -> &'static str {
+    if user == Some(calendar.owner_id) {
+        if calendar.is_shared {
+            "<d:shared-owner/>"
+        } else {
+            "<d:not-shared/>"
+        }
+    } else {
+        sharing::share_access_xml(access)
+    }
+}
+
+pub async fn propfind(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let requested = match xml::requested_props(&body) {
+        Ok(requested) => requested,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+    let props = requested.unwrap_or_else(|| {
+        vec![
+            PropName::new(DAV, "resourcetype"),
+            PropName::new(DAV, "displayname"),
+            PropName::new(DAV, "owner"),
+            PropName::new(DAV, "getetag"),
+            PropName::new(DAV, "getcontenttype"),
+        ]
+    });
+
+    let store = CalendarStore::new(&data.db_pool);
+    let user = user.map(|u| u.user_id);
+    let (calendar, access) =
+        match access::authorize(&store, calendar_id, user, CalendarAccess::Read).await {
+            Ok(authorized) => authorized,
+            Err(response) => return Ok(response),
+        };
+    let is_admin = access >= CalendarAccess::Admin;
+
+    let mut multistatus = MultiStatus::new();
+    let mut collection = Response::new(calendar_href(calendar_id));
+    for prop in &props {
+        let value = if prop.is(DAV, "resourcetype") {
+            Some(PropValue::Xml("<d:collection/><c:calendar/>".to_string()))
+        } else if prop.is(DAV, "displayname") {
+            Some(PropValue::Text(calendar.display_name.clone()))
+        } else if prop.is(CALDAV, "calendar-description") {
+            calendar.description.clone().map(PropValue::Text)
+        } else if prop.is(APPLE_ICAL, "calendar-color") {
+            calendar.color.clone().map(PropValue::Text)
+        } else if prop.is(CALDAV, "supported-calendar-component-set") {
+            Some(PropValue::Xml(SUPPORTED_COMPONENTS.to_string()))
+        } else if prop.is(DAV, "supported-report-set") {
+            Some(PropValue::Xml(SUPPORTED_REPORTS.to_string()))
+        } else if prop.is(DAV, "owner") {
+            Some(PropValue::Xml(xml::hrefs([access::principal_href(
+                calendar.owner_id,
+            )])))
+        } else if prop.is(DAV, "current-user-principal") {
+            Some(PropValue::Xml(match user {
+                Some(user) => xml::hrefs([access::principal_href(user)]),
+                None => "<d:unauthenticated/>".to_string(),
+            }))
+        } else if prop.is(DAV, "current-user-privilege-set") {
+            Some(PropValue::Xml(format!(
+                "{}<d:privilege><c:read-free-busy/></d:privilege>",
+                access::privilege_set(access)
+            )))
+        } else if prop.is(DAV, "acl") && is_admin {
+            Some(PropValue::Xml(access::acl(&calendar)))
+        } else if prop.is(DAV, "share-access") {
+            Some(PropValue::Xml(
+                share_access(&calendar, user, access).to_string(),
+            ))
+        } else if prop.is(DAV, "invite") && is_admin {
+            match store.invites_for_calendar(calendar_id).await {
+                Ok(invites) => Some(PropValue::Xml(sharing::invite_xml(&invites))),
+                Err(e) => return Ok(internal_error("Failed to load invitations", e)),
+            }
+        } else {
+            None
+        };
+        match value {
+            Some(value) => collection.prop(prop.clone(), value),
+            None => collection.not_found(prop.clone()),
+        }
+    }
+    multistatus.push(collection);
+
+    if !xml::depth_zero(&req) {
+        let objects = match store.list_objects(calendar_id).await {
+            Ok(objects) => objects,
+            Err(e) => return Ok(internal_error("Failed to load objects", e)),
+        };
+        for object in objects {
+            let mut response = Response::new(object_href(calendar_id, &object.name));
+            for prop in &props {
+                if prop.is(DAV, "resourcetype") {
+                    response.prop(prop.clone(), PropValue::Empty);
+                } else if prop.is(DAV, "getetag") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text(format!("\"{}\"", object.etag)),
+                    );
+                } else if prop.is(DAV, "getcontenttype") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text(format!(
+                            "text/calendar; charset=utf-8; component={}",
+                            object.component_type.to_lowercase()
+                        )),
+                    );
+                } else {
+                    response.not_found(prop.clone());
+                }
+            }
+            multistatus.push(response);
+        }
+    }
+    Ok(multistatus.into_response())
+}
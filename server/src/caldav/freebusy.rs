@@ -0,0 +1,84 @@
+//! Free/busy aggregation over stored calendars (RFC 4791 §7.10)
+
+use super::store::CalendarStore;
+use crate::AppState;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use protocol::caldav::{BusyPeriod, FreeBusyResponse, TimeRange};
+use protocol::freebusy;
+use protocol::ical::ICalendar;
+use uuid::Uuid;
+
+/// Longest range a free/busy query may cover, to bound recurrence expansion.
+pub const MAX_RANGE_DAYS: i64 = 366;
+
+/// Check a requested range; returns a message suitable for a 400 response.
+pub fn check_range(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<(), String> {
+    if end <= start {
+        return Err("end must be after start".to_string());
+    }
+    if end - start > Duration::days(MAX_RANGE_DAYS) {
+        return Err(format!(
+            "free/busy range may not exceed {} days",
+            MAX_RANGE_DAYS
+        ));
+    }
+    Ok(())
+}
+
+/// Coalesced busy periods of `calendar_ids` within `[start, end)`.
+pub async fn busy_periods(
+    store: &CalendarStore,
+    calendar_ids: &[Uuid],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<BusyPeriod>, sqlx::Error> {
+    let mut periods = Vec::new();
+    for &calendar_id in calendar_ids {
+        for object in store.objects_in_range(calendar_id, start, end).await? {
+            if object.component_type != "VEVENT" && object.component_type != "VFREEBUSY" {
+                continue;
+            }
+            let found = ICalendar::parse(&object.data).and_then(|calendar| {
+                freebusy::busy_periods(&calendar.root, &calendar.timezones(), start, end)
+            });
+            match found {
+                Ok(found) => periods.extend(found),
+                Err(e) => tracing::warn!(
+                    "Skipping calendar object {} in free/busy: {}",
+                    object.name,
+                    e
+                ),
+            }
+        }
+    }
+    Ok(freebusy::coalesce(periods))
+}
+
+/// The `free-busy-query` REPORT: a `VFREEBUSY` for one calendar collection.
+pub async fn report(
+    calendar_id: Uuid,
+    range: TimeRange,
+    data: &web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(message) = check_range(range.start, range.end) {
+        return HttpResponse::BadRequest().json(serde_json::json!({"error": message}));
+    }
+    let store = CalendarStore::new(&data.db_pool);
+    match busy_periods(&store, &[calendar_id], range.start, range.end).await {
+        Ok(busy_periods) => {
+            let response = FreeBusyResponse {
+                organizer: String::new(),
+                attendee: String::new(),
+                start: range.start,
+                end: range.end,
+                busy_periods,
+            };
+            HttpResponse::Ok()
+                .content_type("text/calendar; charset=utf-8")
+                .body(freebusy::to_vfreebusy(&response, Utc::now()).to_ics())
+        }
+        Err(e) => HttpResponse::InternalServerError()
+            .json(serde_json::json!({"error": format!("Failed to load objects: {}", e)})),
+    }
+}
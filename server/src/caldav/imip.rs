@@ -0,0 +1,105 @@
+//! iMIP: iTIP messages sent by email to attendees outside this server
+//! (RFC 6047)
+
+use crate::AppState;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, MultiPart, SinglePart};
+use lettre::Message;
+use protocol::ical::ICalendar;
+use protocol::itip::Method;
+
+fn summary(message: &ICalendar) -> String {
+    message
+        .root
+        .components
+        .iter()
+        .find_map(|c| c.property("SUMMARY"))
+        .map(|p| p.text_value())
+        .unwrap_or_else(|| "(no title)".to_string())
+}
+
+fn mailbox(address: &str) -> Result<Mailbox, String> {
+    address
+        .parse::<Mailbox>()
+        .map_err(|e| format!("invalid address '{}': {}", address, e))
+}
+
+/// Build the email carrying `message` from `originator` to `recipient`.
+///
+/// Mail is sent from the configured `sender` address, with the originator as
+/// `Reply-To`, so that it passes the sender domain's checks.
+pub fn compose(
+    sender: &str,
+    originator: &str,
+    recipient: &str,
+    message: &ICalendar,
+    method: Method,
+) -> Result<Message, String> {
+    let summary = summary(message);
+    let (subject, text) = match method {
+        Method::Request => (
+            format!("Invitation: {}", summary),
+            format!("{} has invited you to \"{}\".", originator, summary),
+        ),
+        Method::Reply => (
+            format!("Reply: {}", summary),
+            format!("{} has replied to \"{}\".", originator, summary),
+        ),
+        Method::Cancel => (
+            format!("Cancelled: {}", summary),
+            format!("{} has cancelled \"{}\".", originator, summary),
+        ),
+    };
+    let calendar_type = ContentType::parse(&format!(
+        "text/calendar; charset=utf-8; method={}",
+        method.as_str()
+    ))
+    .map_err(|e| e.to_string())?;
+
+    Message::builder()
+        .from(mailbox(sender)?)
+        .reply_to(mailbox(originator)?)
+        .to(mailbox(recipient)?)
+        .subject(subject)
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::TEXT_PLAIN)
+                        .body(text),
+                )
+                .singlepart(
+                    SinglePart::builder()
+                        .header(calendar_type)
+                        .body(message.to_ics()),
+                ),
+        )
+        .map_err(|e| e.to_string())
+}
+
+/// Send `message` through the configured mail transport.
+pub async fn send(
+    data: &AppState,
+    originator: &str,
+    recipient: &str,
+    message: &ICalendar,
+    method: Method,
+) -> Result<(), String> {
+    let config = &data.config.mail;
+    if !config.enabled {
+        return Err("mail is disabled".to_string());
+    }
+    let plugin = data
+        .plugin_manager
+        .mail_plugin(config.transport.plugin_name())
+        .ok_or_else(|| {
+            format!(
+                "no mail plugin registered for {}",
+                config.transport.plugin_name()
+            )
+        })?;
+    let email = compose(&config.from, originator, recipient, message, method)?;
+    plugin
+        .send(&config.from, &[recipient.to_string()], &email.formatted())
+        .await
+}
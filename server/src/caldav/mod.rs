@@ -1,7 +1,232 @@
-//! CalDAV route stubs
+//! CalDAV endpoints (RFC 4791)
 
-use actix_web::web;
+pub mod access;
+pub mod collection;
+pub mod freebusy;
+pub mod imip;
+pub mod principal;
+pub mod public;
+pub mod query;
+pub mod report;
+pub mod schedule;
+pub mod sharing;
+pub mod store;
+pub mod transfer;
 
-pub fn configure(_cfg: &mut web::ServiceConfig) {
-    // TODO: Implement CalDAV routes
+use crate::auth::AuthenticatedUser;
+use crate::webdav::xml::{self, PropName, CALDAV};
+use crate::webdav::{etag_header, internal_error, preconditions_hold, valid_object_name};
+use crate::AppState;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use protocol::caldav::CalendarAccess;
+use protocol::ical::ICalendar;
+use store::{CalendarStore, ObjectIndex};
+use uuid::Uuid;
+
+pub async fn get_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (calendar_id, name) = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+
+    if let Err(response) = access::authorize(
+        &store,
+        calendar_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    match store.get_object(calendar_id, &name).await {
+        Ok(Some(object)) => Ok(HttpResponse::Ok()
+            .content_type("text/calendar; charset=utf-8")
+            .insert_header(etag_header(&object.etag))
+            .body(object.data)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"}))),
+        Err(e) => Ok(internal_error("Failed to load object", e)),
+    }
+}
+
+pub async fn put_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (calendar_id, name) = path.into_inner();
+    if !valid_object_name(&name) {
+        return Ok(
+            HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid object name"}))
+        );
+    }
+    let store = CalendarStore::new(&data.db_pool);
+
+    let owner = match access::authorize(
+        &store,
+        calendar_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        Ok((owner, _)) => owner,
+        Err(response) => return Ok(response),
+    };
+
+    let calendar = match std::str::from_utf8(&body)
+        .ok()
+        .and_then(|text| ICalendar::parse(text).ok())
+    {
+        Some(calendar) => calendar,
+        None => {
+            return Ok(xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(CALDAV, "valid-calendar-data"),
+            ))
+        }
+    };
+    let index = match ObjectIndex::from_calendar(&calendar) {
+        Ok(index) => index,
+        Err(_) => {
+            return Ok(xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(CALDAV, "valid-calendar-object-resource"),
+            ))
+        }
+    };
+
+    let existing = match store.get_object(calendar_id, &name).await {
+        Ok(existing) => existing,
+        Err(e) => return Ok(internal_error("Failed to load object", e)),
+    };
+    if !preconditions_hold(&req, existing.as_ref().map(|o| o.etag.as_str())) {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    match store.find_by_uid(calendar_id, &index.uid).await {
+        Ok(Some(other)) if other.name != name => {
+            return Ok(xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(CALDAV, "no-uid-conflict"),
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => return Ok(internal_error("Failed to check UID", e)),
+    }
+
+    match store
+        .put_object(calendar_id, &name, &calendar.to_ics(), &index)
+        .await
+    {
+        Ok(object) => {
+            let old = existing
+                .as_ref()
+                .and_then(|existing| ICalendar::parse(&existing.data).ok());
+            schedule::object_stored(&data, &owner, old.as_ref(), &calendar).await;
+
+            let mut response = if existing.is_some() {
+                HttpResponse::NoContent()
+            } else {
+                HttpResponse::Created()
+            };
+            Ok(response.insert_header(etag_header(&object.etag)).finish())
+        }
+        Err(e) => Ok(internal_error("Failed to store object", e)),
+    }
+}
+
+pub async fn delete_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (calendar_id, name) = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+
+    let owner = match access::authorize(
+        &store,
+        calendar_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        Ok((owner, _)) => owner,
+        Err(response) => return Ok(response),
+    };
+    let existing = match store.get_object(calendar_id, &name).await {
+        Ok(Some(existing)) => existing,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"})))
+        }
+        Err(e) => return Ok(internal_error("Failed to load object", e)),
+    };
+    if !preconditions_hold(&req, Some(&existing.etag)) {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    match store.delete_object(calendar_id, &name).await {
+        Ok(_) => {
+            if let Ok(old) = ICalendar::parse(&existing.data) {
+                schedule::object_deleted(&data, &owner, &old).await;
+            }
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Err(e) => Ok(internal_error("Failed to delete object", e)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let report_method = Method::from_bytes(b"REPORT").expect("valid method");
+    let propfind_method = Method::from_bytes(b"PROPFIND").expect("valid method");
+    let proppatch_method = Method::from_bytes(b"PROPPATCH").expect("valid method");
+    cfg.service(
+        web::scope("/caldav/calendars")
+            .route(
+                "/{calendar_id}",
+                web::method(propfind_method.clone()).to(collection::propfind),
+            )
+            .route(
+                "/{calendar_id}",
+                web::method(report_method).to(report::report),
+            )
+            .route("/{calendar_id}", web::post().to(sharing::post_calendar))
+            .route("/{calendar_id}/{object}", web::get().to(get_object))
+            .route("/{calendar_id}/{object}", web::put().to(put_object))
+            .route("/{calendar_id}/{object}", web::delete().to(delete_object)),
+    );
+    cfg.service(
+        web::scope("/caldav/scheduling/{user_id}")
+            .route(
+                "/inbox",
+                web::method(propfind_method.clone()).to(schedule::propfind_inbox),
+            )
+            .route("/inbox/{message}", web::get().to(schedule::get_message))
+            .route(
+                "/inbox/{message}",
+                web::delete().to(schedule::delete_message),
+            )
+            .route("/outbox", web::post().to(schedule::post_outbox)),
+    );
+    cfg.route("/caldav/public/{file}", web::get().to(public::get_feed));
+    cfg.service(
+        web::scope("/principals/users/{user_id}")
+            .route(
+                "",
+                web::method(propfind_method.clone()).to(principal::propfind_principal),
+            )
+            .route(
+                "/{group}",
+                web::method(propfind_method).to(principal::propfind_proxy_group),
+            )
+            .route(
+                "/{group}",
+                web::method(proppatch_method).to(principal::proppatch_proxy_group),
+            ),
+    );
 }
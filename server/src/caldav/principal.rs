@@ -0,0 +1,296 @@
+//! Principals and calendar proxy groups (RFC 3744, RFC 6638 §2.4)
+//!
+//! Every user has a principal under `/principals/users/{id}/` with two
+//! proxy groups, `calendar-proxy-read/` and `calendar-proxy-write/`, whose
+//! members are the user's delegates (caldav-cu-proxy). The owner sets the
+//! members with PROPPATCH on `DAV:group-member-set`.
+
+use super::access::{self, principal_href, proxy_group, proxy_group_href};
+use super::schedule::{inbox_href, outbox_href};
+use super::store::CalendarStore;
+use crate::auth::AuthenticatedUser;
+use crate::carddav::collection::home_href;
+use crate::webdav::internal_error;
+use crate::webdav::xml::{
+    self, MultiStatus, PropName, PropValue, Response, XmlError, CALDAV, CALENDARSERVER, CARDDAV,
+    DAV,
+};
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Result};
+use protocol::caldav::CalendarAccess;
+use uuid::Uuid;
+
+fn requested_or(body: &[u8], defaults: &[(&str, &str)]) -> Result<Vec<PropName>, XmlError> {
+    Ok(xml::requested_props(body)?.unwrap_or_else(|| {
+        defaults
+            .iter()
+            .map(|(namespace, name)| PropName::new(namespace, name))
+            .collect()
+    }))
+}
+
+/// Which proxy group a path segment names.
+fn parse_group(group: &str) -> Option<bool> {
+    match group {
+        "calendar-proxy-read" => Some(false),
+        "calendar-proxy-write" => Some(true),
+        _ => None,
+    }
+}
+
+/// PROPFIND on a user principal.
+pub async fn propfind_principal(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let props = match requested_or(
+        &body,
+        &[
+            (DAV, "resourcetype"),
+            (DAV, "displayname"),
+            (DAV, "principal-URL"),
+            (CALDAV, "calendar-user-address-set"),
+        ],
+    ) {
+        Ok(props) => props,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+
+    let store = CalendarStore::new(&data.db_pool);
+    let addresses = match store.addresses_of(user_id).await {
+        Ok(addresses) => addresses,
+        Err(e) => return Ok(internal_error("Failed to load addresses", e)),
+    };
+    let delegations = match store.delegations(user_id).await {
+        Ok(delegations) => delegations,
+        Err(e) => return Ok(internal_error("Failed to load delegations", e)),
+    };
+    let mailto: Vec<String> = addresses
+        .iter()
+        .map(|address| format!("mailto:{}", address))
+        .collect();
+    let proxy_for = |can_write: bool| {
+        xml::hrefs(
+            delegations
+                .iter()
+                .filter(|d| d.can_write == can_write)
+                .map(|d| principal_href(d.owner_id)),
+        )
+    };
+
+    let mut response = Response::new(principal_href(user_id));
+    for prop in &props {
+        let value = if prop.is(DAV, "resourcetype") {
+            Some(PropValue::Xml("<d:principal/>".to_string()))
+        } else if prop.is(DAV, "displayname") {
+            Some(PropValue::Text(
+                addresses
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| user_id.to_string()),
+            ))
+        } else if prop.is(DAV, "principal-URL") {
+            Some(PropValue::Xml(xml::hrefs([principal_href(user_id)])))
+        } else if prop.is(DAV, "current-user-principal") {
+            Some(PropValue::Xml(xml::hrefs([principal_href(user.user_id)])))
+        } else if prop.is(DAV, "alternate-URI-set") || prop.is(CALDAV, "calendar-user-address-set")
+        {
+            Some(PropValue::Xml(xml::hrefs(&mailto)))
+        } else if prop.is(CALDAV, "schedule-inbox-URL") {
+            Some(PropValue::Xml(xml::hrefs([inbox_href(user_id)])))
+        } else if prop.is(CALDAV, "schedule-outbox-URL") {
+            Some(PropValue::Xml(xml::hrefs([outbox_href(user_id)])))
+        } else if prop.is(CARDDAV, "addressbook-home-set") {
+            Some(PropValue::Xml(xml::hrefs([home_href(user_id)])))
+        } else if prop.is(DAV, "group-membership") {
+            Some(PropValue::Xml(xml::hrefs(
+                delegations
+                    .iter()
+                    .map(|d| proxy_group_href(d.owner_id, d.can_write)),
+            )))
+        } else if prop.is(CALENDARSERVER, "calendar-proxy-read-for") {
+            Some(PropValue::Xml(proxy_for(false)))
+        } else if prop.is(CALENDARSERVER, "calendar-proxy-write-for") {
+            Some(PropValue::Xml(proxy_for(true)))
+        } else {
+            None
+        };
+        match value {
+            Some(value) => response.prop(prop.clone(), value),
+            None => response.not_found(prop.clone()),
+        }
+    }
+    let mut multistatus = MultiStatus::new();
+    multistatus.push(response);
+    Ok(multistatus.into_response())
+}
+
+/// PROPFIND on a proxy group of a principal.
+pub async fn propfind_proxy_group(
+    path: web::Path<(Uuid, String)>,
+    user: AuthenticatedUser,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (user_id, group) = path.into_inner();
+    let Some(can_write) = parse_group(&group) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"})));
+    };
+    let props = match requested_or(
+        &body,
+        &[
+            (DAV, "resourcetype"),
+            (DAV, "displayname"),
+            (DAV, "group-member-set"),
+        ],
+    ) {
+        Ok(props) => props,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+    let proxies = match CalendarStore::new(&data.db_pool).proxies_of(user_id).await {
+        Ok(proxies) => proxies,
+        Err(e) => return Ok(internal_error("Failed to load proxies", e)),
+    };
+
+    let href = proxy_group_href(user_id, can_write);
+    let mut response = Response::new(href.clone());
+    for prop in &props {
+        let value = if prop.is(DAV, "resourcetype") {
+            Some(PropValue::Xml(format!(
+                "<d:principal/><cs:{}/>",
+                proxy_group(can_write)
+            )))
+        } else if prop.is(DAV, "displayname") {
+            Some(PropValue::Text(proxy_group(can_write).to_string()))
+        } else if prop.is(DAV, "principal-URL") {
+            Some(PropValue::Xml(xml::hrefs([href.as_str()])))
+        } else if prop.is(DAV, "current-user-principal") {
+            Some(PropValue::Xml(xml::hrefs([principal_href(user.user_id)])))
+        } else if prop.is(DAV, "group-member-set") {
+            Some(PropValue::Xml(xml::hrefs(
+                proxies
+                    .iter()
+                    .filter(|p| p.can_write == can_write)
+                    .map(|p| principal_href(p.delegate_id)),
+            )))
+        } else if prop.is(DAV, "group-membership") {
+            Some(PropValue::Empty)
+        } else {
+            None
+        };
+        match value {
+            Some(value) => response.prop(prop.clone(), value),
+            None => response.not_found(prop.clone()),
+        }
+    }
+    let mut multistatus = MultiStatus::new();
+    multistatus.push(response);
+    Ok(multistatus.into_response())
+}
+
+/// PROPPATCH on a proxy group, replacing its `DAV:group-member-set`. Only
+/// the principal itself may change its delegates.
+pub async fn proppatch_proxy_group(
+    path: web::Path<(Uuid, String)>,
+    user: AuthenticatedUser,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (user_id, group) = path.into_inner();
+    let Some(can_write) = parse_group(&group) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"})));
+    };
+    let href = proxy_group_href(user_id, can_write);
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize_user(
+        &store,
+        user_id,
+        &href,
+        Some(user.user_id),
+        CalendarAccess::Admin,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let root = match xml::parse(&body) {
+        Ok(root) if root.is(DAV, "propertyupdate") => root,
+        Ok(_) => {
+            return Ok(HttpResponse::BadRequest()
+                .json(serde_json::json!({"error": "Expected a DAV:propertyupdate body"})))
+        }
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+    // The last `set` or `remove` of the member set wins, as instructions
+    // are applied in document order.
+    let mut members: Option<Vec<String>> = None;
+    for instruction in &root.children {
+        let remove = if instruction.is(DAV, "set") {
+            false
+        } else if instruction.is(DAV, "remove") {
+            true
+        } else {
+            continue;
+        };
+        for prop in instruction
+            .children_named(DAV, "prop")
+            .flat_map(|prop| &prop.children)
+        {
+            if !prop.is(DAV, "group-member-set") {
+                return Ok(xml::error_response(
+                    StatusCode::FORBIDDEN,
+                    PropName::new(DAV, "cannot-modify-protected-property"),
+                ));
+            }
+            members = Some(if remove {
+                Vec::new()
+            } else {
+                prop.children_named(DAV, "href")
+                    .map(|h| h.text.trim().to_string())
+                    .collect()
+            });
+        }
+    }
+    let Some(members) = members else {
+        return Ok(HttpResponse::BadRequest()
+            .json(serde_json::json!({"error": "No DAV:group-member-set to update"})));
+    };
+
+    let mut delegates = Vec::with_capacity(members.len());
+    for member in &members {
+        let delegate = match access::principal_id(member) {
+            Some(delegate) => Some(delegate),
+            None => match store.user_for_address(member).await {
+                Ok(delegate) => delegate,
+                Err(e) => return Ok(internal_error("Failed to resolve member", e)),
+            },
+        };
+        match delegate {
+            Some(delegate) => delegates.push(delegate),
+            None => {
+                return Ok(HttpResponse::BadRequest()
+                    .json(serde_json::json!({"error": format!("Unknown principal: {}", member)})))
+            }
+        }
+    }
+    if let Err(e) = store.set_proxies(user_id, can_write, &delegates).await {
+        return Ok(internal_error("Failed to update proxies", e));
+    }
+
+    let mut response = Response::new(href);
+    response.prop(PropName::new(DAV, "group-member-set"), PropValue::Empty);
+    let mut multistatus = MultiStatus::new();
+    multistatus.push(response);
+    Ok(multistatus.into_response())
+}
@@ -0,0 +1,49 @@
+//! Public read-only subscription feeds of calendars with `public_read` set
+//!
+//! `GET /caldav/public/{calendar_id}.ics` returns every object of the
+//! calendar merged into a single VCALENDAR, for clients that subscribe to
+//! a URL rather than speak CalDAV.
+
+use super::store::CalendarStore;
+use super::transfer;
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use uuid::Uuid;
+
+pub fn public_href(calendar_id: Uuid) -> String {
+    format!("/caldav/public/{}.ics", calendar_id)
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({"error": "Calendar not found"}))
+}
+
+pub async fn get_feed(path: web::Path<String>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    let Some(calendar_id) = path
+        .strip_suffix(".ics")
+        .and_then(|id| Uuid::parse_str(id).ok())
+    else {
+        return Ok(not_found());
+    };
+    let store = CalendarStore::new(&data.db_pool);
+    let calendar = match store.get_calendar(calendar_id).await {
+        Ok(Some(calendar)) if calendar.permissions.public_read => calendar,
+        Ok(_) => return Ok(not_found()),
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(serde_json::json!({"error": format!("Failed to load calendar: {}", e)})))
+        }
+    };
+    let objects = match store.list_objects(calendar_id).await {
+        Ok(objects) => objects,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(serde_json::json!({"error": format!("Failed to load objects: {}", e)})))
+        }
+    };
+
+    let feed = transfer::export(&calendar, &objects);
+    Ok(HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .body(feed.to_ics()))
+}
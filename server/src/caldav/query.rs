@@ -0,0 +1,143 @@
+//! calendar-query filter evaluation (RFC 4791 §9.7)
+
+use protocol::caldav::{
+    CalendarFilter, CalendarQuery, MatchType, ParameterFilter, PropertyFilter, TextMatch, TimeRange,
+};
+use protocol::ical::{Component, ICalError, ICalendar, Property, TimeZones};
+use protocol::recurrence;
+
+/// Components that carry a recurrence set and can be time-range filtered.
+const DATED_COMPONENTS: &[&str] = &["VEVENT", "VTODO", "VJOURNAL"];
+
+pub fn is_supported_collation(collation: &str) -> bool {
+    ["i;ascii-casemap", "i;unicode-casemap", "i;octet"]
+        .iter()
+        .any(|c| collation.eq_ignore_ascii_case(c))
+}
+
+/// Whether a calendar object resource matches every filter of `query`.
+pub fn matches(query: &CalendarQuery, calendar: &ICalendar) -> Result<bool, ICalError> {
+    let zones = calendar.timezones();
+    let root = &calendar.root;
+
+    if let Some(range) = &query.time_range {
+        let mut any = false;
+        for name in DATED_COMPONENTS {
+            if recurrence::matches_time_range(root, name, &zones, range.start, range.end)? {
+                any = true;
+                break;
+            }
+        }
+        if !any {
+            return Ok(false);
+        }
+    }
+
+    for filter in &query.filters {
+        let matched = match filter {
+            CalendarFilter::ComponentFilter {
+                name,
+                time_range,
+                property_filters,
+            } => component_matches(root, name, time_range.as_ref(), property_filters, &zones)?,
+            CalendarFilter::PropertyFilter(filter) => property_filter_matches(root, filter, &zones),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn component_matches(
+    root: &Component,
+    name: &str,
+    time_range: Option<&TimeRange>,
+    property_filters: &[PropertyFilter],
+    zones: &TimeZones,
+) -> Result<bool, ICalError> {
+    if name.eq_ignore_ascii_case("VCALENDAR") {
+        return Ok(property_filters
+            .iter()
+            .all(|f| property_filter_matches(root, f, zones)));
+    }
+    let any = root.components_named(name).any(|component| {
+        property_filters
+            .iter()
+            .all(|f| property_filter_matches(component, f, zones))
+    });
+    if !any {
+        return Ok(false);
+    }
+    match time_range {
+        Some(range) => recurrence::matches_time_range(root, name, zones, range.start, range.end),
+        None => Ok(true),
+    }
+}
+
+fn property_filter_matches(
+    component: &Component,
+    filter: &PropertyFilter,
+    zones: &TimeZones,
+) -> bool {
+    let mut properties = component.properties_named(&filter.name).peekable();
+    if filter.is_not_defined == Some(true) {
+        return properties.peek().is_none();
+    }
+    properties.any(|property| property_matches(property, filter, zones))
+}
+
+fn property_matches(property: &Property, filter: &PropertyFilter, zones: &TimeZones) -> bool {
+    if let Some(range) = &filter.time_range {
+        match zones.resolve(property) {
+            Ok(time) if time >= range.start && time < range.end => {}
+            _ => return false,
+        }
+    }
+    if let Some(text_match) = &filter.text_match {
+        if !text_matches(text_match, &property.text_value()) {
+            return false;
+        }
+    }
+    filter
+        .parameter_filters
+        .iter()
+        .all(|f| parameter_matches(property, f))
+}
+
+pub fn parameter_matches(property: &Property, filter: &ParameterFilter) -> bool {
+    let values: Vec<&String> = property
+        .params
+        .iter()
+        .filter(|p| p.name.eq_ignore_ascii_case(&filter.name))
+        .flat_map(|p| &p.values)
+        .collect();
+    if filter.is_not_defined == Some(true) {
+        return values.is_empty();
+    }
+    match &filter.text_match {
+        Some(text_match) => values.iter().any(|v| text_matches(text_match, v)),
+        None => !values.is_empty(),
+    }
+}
+
+pub fn text_matches(text_match: &TextMatch, value: &str) -> bool {
+    let collation = text_match.collation.as_deref().unwrap_or("i;ascii-casemap");
+    let (value, needle) = if collation.eq_ignore_ascii_case("i;octet") {
+        (value.to_string(), text_match.value.clone())
+    } else if collation.eq_ignore_ascii_case("i;unicode-casemap") {
+        (value.to_lowercase(), text_match.value.to_lowercase())
+    } else {
+        (
+            value.to_ascii_lowercase(),
+            text_match.value.to_ascii_lowercase(),
+        )
+    };
+    let found = match text_match.match_type {
+        MatchType::Equals => value == needle,
+        MatchType::Contains => value.contains(&needle),
+        MatchType::StartsWith => value.starts_with(&needle),
+        MatchType::EndsWith => value.ends_with(&needle),
+    };
+    found != text_match.negate_condition
+}
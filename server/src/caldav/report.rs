@@ -0,0 +1,385 @@
+//! CalDAV REPORTs: calendar-query, calendar-multiget and free-busy-query
+//! (RFC 4791 §7.8 - §7.10)
+
+use super::access;
+use super::freebusy;
+use super::query;
+use super::store::{CalendarObject, CalendarStore};
+use crate::auth::AuthenticatedUser;
+use crate::webdav::report::{bad_request, parse_text_match, ReportError};
+use crate::webdav::xml::{self, Element, MultiStatus, PropName, PropValue, Response, CALDAV, DAV};
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use protocol::caldav::{
+    CalendarAccess, CalendarFilter, CalendarQuery, ParameterFilter, PropertyFilter, TimeRange,
+};
+use protocol::ical::{DateTimeValue, ICalError, ICalendar};
+use protocol::recurrence;
+use uuid::Uuid;
+
+/// How calendar data is returned (RFC 4791 §9.6).
+#[derive(Debug, Default)]
+pub struct CalendarData {
+    pub expand: Option<TimeRange>,
+    pub limit_recurrence_set: Option<TimeRange>,
+}
+
+#[derive(Debug)]
+pub enum Report {
+    Query {
+        query: CalendarQuery,
+        props: Vec<PropName>,
+        data: CalendarData,
+    },
+    Multiget {
+        hrefs: Vec<String>,
+        props: Vec<PropName>,
+        data: CalendarData,
+    },
+    FreeBusy(TimeRange),
+}
+
+/// Bounds used for open-ended time ranges.
+fn range_bound(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(year, month, day)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .map(|t| t.and_utc())
+        .unwrap_or_default()
+}
+
+fn parse_utc(value: &str) -> Result<DateTime<Utc>, ReportError> {
+    match DateTimeValue::parse_value(value, None) {
+        Some(DateTimeValue::Utc(time)) => Ok(time),
+        _ => Err(bad_request("time-range values must be UTC date-times")),
+    }
+}
+
+fn parse_time_range(element: &Element, require_both: bool) -> Result<TimeRange, ReportError> {
+    let (start, end) = (element.attr("start"), element.attr("end"));
+    if start.is_none() && end.is_none() || require_both && (start.is_none() || end.is_none()) {
+        return Err(bad_request("time-range requires start or end"));
+    }
+    let start = start.map(parse_utc).transpose()?;
+    let end = end.map(parse_utc).transpose()?;
+    let range = TimeRange {
+        start: start.unwrap_or_else(|| range_bound(1, 1, 1)),
+        end: end.unwrap_or_else(|| range_bound(9999, 12, 31)),
+    };
+    if range.end <= range.start {
+        return Err(bad_request("time-range end must be after start"));
+    }
+    Ok(range)
+}
+
+fn parse_prop_filter(element: &Element) -> Result<PropertyFilter, ReportError> {
+    let name = element
+        .attr("name")
+        .ok_or_else(|| bad_request("prop-filter requires a name"))?;
+    let mut filter = PropertyFilter {
+        name: name.to_ascii_uppercase(),
+        is_not_defined: None,
+        time_range: None,
+        text_match: None,
+        parameter_filters: Vec::new(),
+    };
+    for child in &element.children {
+        if child.is(CALDAV, "is-not-defined") {
+            filter.is_not_defined = Some(true);
+        } else if child.is(CALDAV, "time-range") {
+            filter.time_range = Some(parse_time_range(child, false)?);
+        } else if child.is(CALDAV, "text-match") {
+            filter.text_match = Some(parse_text_match(child, CALDAV, None)?);
+        } else if child.is(CALDAV, "param-filter") {
+            let name = child
+                .attr("name")
+                .ok_or_else(|| bad_request("param-filter requires a name"))?;
+            filter.parameter_filters.push(ParameterFilter {
+                name: name.to_ascii_uppercase(),
+                is_not_defined: child.child(CALDAV, "is-not-defined").map(|_| true),
+                text_match: child
+                    .child(CALDAV, "text-match")
+                    .map(|text_match| parse_text_match(text_match, CALDAV, None))
+                    .transpose()?,
+            });
+        }
+    }
+    Ok(filter)
+}
+
+/// Flatten `VCALENDAR > component` filters into [`CalendarFilter`]s. Deeper
+/// nesting (e.g. `VALARM`) and negated components are not supported.
+fn parse_filter(element: &Element) -> Result<Vec<CalendarFilter>, ReportError> {
+    let mut roots = element.children_named(CALDAV, "comp-filter");
+    let root = roots
+        .next()
+        .filter(|r| r.attr("name") == Some("VCALENDAR"))
+        .ok_or_else(|| bad_request("filter must contain a VCALENDAR comp-filter"))?;
+    if roots.next().is_some() {
+        return Err(bad_request("filter must contain a single comp-filter"));
+    }
+    if root.child(CALDAV, "is-not-defined").is_some() || root.child(CALDAV, "time-range").is_some()
+    {
+        return Err(ReportError::precondition(CALDAV, "supported-filter"));
+    }
+
+    let mut filters = Vec::new();
+    for prop_filter in root.children_named(CALDAV, "prop-filter") {
+        filters.push(CalendarFilter::PropertyFilter(parse_prop_filter(
+            prop_filter,
+        )?));
+    }
+    for comp_filter in root.children_named(CALDAV, "comp-filter") {
+        let name = comp_filter
+            .attr("name")
+            .ok_or_else(|| bad_request("comp-filter requires a name"))?;
+        if comp_filter.child(CALDAV, "is-not-defined").is_some()
+            || comp_filter.child(CALDAV, "comp-filter").is_some()
+        {
+            return Err(ReportError::precondition(CALDAV, "supported-filter"));
+        }
+        filters.push(CalendarFilter::ComponentFilter {
+            name: name.to_ascii_uppercase(),
+            time_range: comp_filter
+                .child(CALDAV, "time-range")
+                .map(|t| parse_time_range(t, false))
+                .transpose()?,
+            property_filters: comp_filter
+                .children_named(CALDAV, "prop-filter")
+                .map(parse_prop_filter)
+                .collect::<Result<_, _>>()?,
+        });
+    }
+    Ok(filters)
+}
+
+/// Requested properties and the calendar-data options among them.
+fn parse_props(root: &Element) -> Result<(Vec<PropName>, CalendarData), ReportError> {
+    let mut data = CalendarData::default();
+    let Some(prop) = root.child(DAV, "prop") else {
+        // allprop (or no prop at all) returns the ETag and the data.
+        return Ok((
+            vec![
+                PropName::new(DAV, "getetag"),
+                PropName::new(CALDAV, "calendar-data"),
+            ],
+            data,
+        ));
+    };
+    for child in &prop.children {
+        if child.is(CALDAV, "calendar-data") {
+            if let Some(expand) = child.child(CALDAV, "expand") {
+                data.expand = Some(parse_time_range(expand, true)?);
+            }
+            if let Some(limit) = child.child(CALDAV, "limit-recurrence-set") {
+                data.limit_recurrence_set = Some(parse_time_range(limit, true)?);
+            }
+        }
+    }
+    Ok((prop.children.iter().map(PropName::of).collect(), data))
+}
+
+impl Report {
+    pub fn parse(calendar_id: Uuid, body: &[u8]) -> Result<Self, ReportError> {
+        let root = xml::parse(body)?;
+        if root.is(CALDAV, "free-busy-query") {
+            let range = root
+                .child(CALDAV, "time-range")
+                .ok_or_else(|| bad_request("free-busy-query requires a time-range"))?;
+            return Ok(Report::FreeBusy(parse_time_range(range, false)?));
+        }
+        let (props, data) = parse_props(&root)?;
+        if root.is(CALDAV, "calendar-query") {
+            let filter = root
+                .child(CALDAV, "filter")
+                .ok_or_else(|| bad_request("calendar-query requires a filter"))?;
+            let query = CalendarQuery {
+                calendar_id,
+                time_range: None,
+                filters: parse_filter(filter)?,
+                properties: props.iter().map(|p| p.name.clone()).collect(),
+            };
+            Ok(Report::Query { query, props, data })
+        } else if root.is(CALDAV, "calendar-multiget") {
+            let hrefs = root
+                .children_named(DAV, "href")
+                .map(|h| h.text.trim().to_string())
+                .collect();
+            Ok(Report::Multiget { hrefs, props, data })
+        } else {
+            Err(ReportError::UnsupportedReport)
+        }
+    }
+}
+
+pub fn object_href(calendar_id: Uuid, name: &str) -> String {
+    format!("/caldav/calendars/{}/{}", calendar_id, name)
+}
+
+fn calendar_data(
+    object: &CalendarObject,
+    calendar: &ICalendar,
+    data: &CalendarData,
+) -> Result<String, ICalError> {
+    let zones = calendar.timezones();
+    if let Some(range) = &data.expand {
+        return Ok(recurrence::expand(&calendar.root, &zones, range.start, range.end)?.to_ics());
+    }
+    if let Some(range) = &data.limit_recurrence_set {
+        return Ok(recurrence::limit_recurrence_set(
+            &calendar.root,
+            &zones,
+            range.start,
+            range.end,
+        )?
+        .to_ics());
+    }
+    Ok(object.data.clone())
+}
+
+fn object_response(
+    object: &CalendarObject,
+    calendar: &ICalendar,
+    props: &[PropName],
+    data: &CalendarData,
+) -> Result<Response, ICalError> {
+    let mut response = Response::new(object_href(object.calendar_id, &object.name));
+    for prop in props {
+        if prop.is(DAV, "getetag") {
+            response.prop(
+                prop.clone(),
+                PropValue::Text(format!("\"{}\"", object.etag)),
+            );
+        } else if prop.is(DAV, "getcontenttype") {
+            response.prop(
+                prop.clone(),
+                PropValue::Text(format!(
+                    "text/calendar; charset=utf-8; component={}",
+                    object.component_type.to_ascii_lowercase()
+                )),
+            );
+        } else if prop.is(CALDAV, "calendar-data") {
+            response.prop(
+                prop.clone(),
+                PropValue::Text(calendar_data(object, calendar, data)?),
+            );
+        } else {
+            response.not_found(prop.clone());
+        }
+    }
+    Ok(response)
+}
+
+/// Pre-filter range: the time-range of the first component filter.
+fn query_range(query: &CalendarQuery) -> Option<&TimeRange> {
+    query.time_range.as_ref().or_else(|| {
+        query.filters.iter().find_map(|f| match f {
+            CalendarFilter::ComponentFilter {
+                time_range: Some(range),
+                ..
+            } => Some(range),
+            _ => None,
+        })
+    })
+}
+
+pub async fn report(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+
+    if let Err(response) = access::authorize(
+        &store,
+        calendar_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let report = match Report::parse(calendar_id, &body) {
+        Ok(report) => report,
+        Err(e) => return Ok(e.into_response()),
+    };
+
+    let mut multistatus = MultiStatus::new();
+    let (objects, props, calendar_data, query) = match report {
+        Report::Query { query, props, data } => {
+            let objects = match query_range(&query) {
+                Some(range) => {
+                    store
+                        .objects_in_range(calendar_id, range.start, range.end)
+                        .await
+                }
+                None => store.list_objects(calendar_id).await,
+            };
+            (objects, props, data, Some(query))
+        }
+        Report::Multiget { hrefs, props, data } => {
+            let prefix = object_href(calendar_id, "");
+            let mut names = Vec::new();
+            for href in hrefs {
+                match href
+                    .strip_prefix(&prefix)
+                    .filter(|n| !n.is_empty() && !n.contains('/'))
+                {
+                    Some(name) => names.push(name.to_string()),
+                    None => multistatus.push(Response::status(href, StatusCode::NOT_FOUND)),
+                }
+            }
+            let objects = store.get_objects(calendar_id, &names).await;
+            if let Ok(found) = &objects {
+                for name in names
+                    .iter()
+                    .filter(|n| !found.iter().any(|o| &o.name == *n))
+                {
+                    multistatus.push(Response::status(
+                        object_href(calendar_id, name),
+                        StatusCode::NOT_FOUND,
+                    ));
+                }
+            }
+            (objects, props, data, None)
+        }
+        Report::FreeBusy(range) => return Ok(freebusy::report(calendar_id, range, &data).await),
+    };
+    let objects = match objects {
+        Ok(objects) => objects,
+        Err(e) => {
+            return Ok(HttpResponse::InternalServerError()
+                .json(serde_json::json!({"error": format!("Failed to load objects: {}", e)})))
+        }
+    };
+
+    for object in &objects {
+        let calendar = match ICalendar::parse(&object.data) {
+            Ok(calendar) => calendar,
+            Err(e) => {
+                tracing::warn!("Skipping unparsable calendar object {}: {}", object.name, e);
+                continue;
+            }
+        };
+        let matched = match &query {
+            Some(query) => query::matches(query, &calendar),
+            None => Ok(true),
+        };
+        let response = matched.and_then(|matched| {
+            matched
+                .then(|| object_response(object, &calendar, &props, &calendar_data))
+                .transpose()
+        });
+        match response {
+            Ok(Some(response)) => multistatus.push(response),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to evaluate calendar object {}: {}", object.name, e),
+        }
+    }
+    Ok(multistatus.into_response())
+}
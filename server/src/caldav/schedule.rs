@@ -0,0 +1,471 @@
+//! CalDAV scheduling (RFC 6638)
+//!
+//! Scheduling is implicit: storing or deleting an object in a calendar whose
+//! owner is the organizer sends `REQUEST`s and `CANCEL`s to the attendees,
+//! and a change of the owner's own participation status as an attendee sends
+//! a `REPLY` to the organizer. Local recipients get the message in their
+//! schedule inbox, replies are applied to the organizer's copy, and anyone
+//! else is sent an iMIP email.
+
+use super::access;
+use super::freebusy;
+use super::imip;
+use super::store::{CalendarStore, ObjectIndex};
+use crate::auth::AuthenticatedUser;
+use crate::webdav::internal_error;
+use crate::webdav::xml::{self, MultiStatus, PropName, PropValue, Response, CALDAV, DAV};
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use protocol::caldav::{AttendeeStatus, Calendar, CalendarAccess, FreeBusyResponse};
+use protocol::freebusy::to_vfreebusy;
+use protocol::ical::{DateTimeValue, ICalendar, Property};
+use protocol::itip::{self, Method};
+use quick_xml::escape::escape;
+use uuid::Uuid;
+
+fn message_uid(message: &ICalendar) -> String {
+    message
+        .root
+        .components
+        .iter()
+        .find_map(|c| c.property("UID"))
+        .map(|p| p.value.clone())
+        .unwrap_or_default()
+}
+
+/// Apply a `REPLY` to the organizer's copy of the object it answers.
+async fn apply_reply(
+    store: &CalendarStore,
+    organizer_id: Uuid,
+    reply: &ICalendar,
+) -> Result<(), String> {
+    let uid = message_uid(reply);
+    let Some(object) = store
+        .find_owned_by_uid(organizer_id, &uid)
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(());
+    };
+    let mut calendar = ICalendar::parse(&object.data).map_err(|e| e.to_string())?;
+    if !itip::apply_reply(&mut calendar, reply).map_err(|e| e.to_string())? {
+        return Ok(());
+    }
+    let index = ObjectIndex::from_calendar(&calendar).map_err(|e| e.to_string())?;
+    store
+        .put_object(object.calendar_id, &object.name, &calendar.to_ics(), &index)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Deliver an iTIP message from `originator` to `recipient`.
+async fn deliver(
+    data: &AppState,
+    store: &CalendarStore,
+    originator: &str,
+    recipient: &str,
+    message: ICalendar,
+) {
+    let Some(method) = itip::method(&message) else {
+        return;
+    };
+    let result = match store.user_for_address(recipient).await {
+        Ok(Some(user_id)) => {
+            let applied = match method {
+                Method::Reply => apply_reply(store, user_id, &message).await,
+                _ => Ok(()),
+            };
+            let delivered = store
+                .deliver_message(
+                    user_id,
+                    &message_uid(&message),
+                    method.as_str(),
+                    originator,
+                    &message.to_ics(),
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string());
+            applied.and(delivered)
+        }
+        Ok(None) => imip::send(data, originator, recipient, &message, method).await,
+        Err(e) => Err(e.to_string()),
+    };
+    match result {
+        Ok(()) => tracing::debug!("Delivered iTIP {} to {}", method.as_str(), recipient),
+        Err(e) => tracing::warn!(
+            "Failed to deliver iTIP {} to {}: {}",
+            method.as_str(),
+            recipient,
+            e
+        ),
+    }
+}
+
+/// Implicit scheduling after an object is created or replaced in `calendar`.
+pub async fn object_stored(
+    data: &AppState,
+    calendar: &Calendar,
+    old: Option<&ICalendar>,
+    new: &ICalendar,
+) {
+    let store = CalendarStore::new(&data.db_pool);
+    let addresses = match store.addresses_of(calendar.owner_id).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            tracing::warn!("Failed to load calendar user addresses: {}", e);
+            return;
+        }
+    };
+    let Some(organizer) = itip::organizer(new) else {
+        return;
+    };
+    let is_owner = |address: &str| addresses.iter().any(|a| itip::same_address(a, address));
+    let now = Utc::now();
+
+    if is_owner(&organizer) {
+        let old_attendees = old.map(itip::attendees).unwrap_or_default();
+        let new_attendees = itip::attendees(new);
+        let changed = old.is_none_or(|old| itip::significant_change(old, new));
+        for attendee in new_attendees.iter().filter(|a| !is_owner(a)) {
+            let invited = !old_attendees
+                .iter()
+                .any(|a| itip::same_address(a, attendee));
+            if changed || invited {
+                if let Some(request) = itip::request(new, attendee, now) {
+                    deliver(data, &store, &organizer, attendee, request).await;
+                }
+            }
+        }
+        if let Some(old) = old {
+            for attendee in old_attendees
+                .iter()
+                .filter(|a| !is_owner(a) && !new_attendees.iter().any(|n| itip::same_address(n, a)))
+            {
+                if let Some(cancel) = itip::cancel(old, attendee, now) {
+                    deliver(data, &store, &organizer, attendee, cancel).await;
+                }
+            }
+        }
+        return;
+    }
+
+    if !itip::organizer_scheduled(new) {
+        return;
+    }
+    for address in &addresses {
+        let after = itip::participation(new, address);
+        if after.is_empty() {
+            continue;
+        }
+        let before = old.map(|old| itip::participation(old, address));
+        let unanswered = after
+            .iter()
+            .all(|(_, status)| *status == AttendeeStatus::NeedsAction);
+        // Filing an invitation that has not been answered yet is not a reply.
+        if before.as_ref() == Some(&after) || before.is_none() && unanswered {
+            continue;
+        }
+        if let Some(reply) = itip::reply(new, address, None, now) {
+            deliver(data, &store, address, &organizer, reply).await;
+        }
+    }
+}
+
+/// Implicit scheduling after an object is deleted from `calendar`: the
+/// organizer cancels the event, an attendee declines it.
+pub async fn object_deleted(data: &AppState, calendar: &Calendar, old: &ICalendar) {
+    let store = CalendarStore::new(&data.db_pool);
+    let addresses = match store.addresses_of(calendar.owner_id).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            tracing::warn!("Failed to load calendar user addresses: {}", e);
+            return;
+        }
+    };
+    let Some(organizer) = itip::organizer(old) else {
+        return;
+    };
+    let is_owner = |address: &str| addresses.iter().any(|a| itip::same_address(a, address));
+    let now = Utc::now();
+
+    if is_owner(&organizer) {
+        for attendee in itip::attendees(old).iter().filter(|a| !is_owner(a)) {
+            if let Some(cancel) = itip::cancel(old, attendee, now) {
+                deliver(data, &store, &organizer, attendee, cancel).await;
+            }
+        }
+    } else if itip::organizer_scheduled(old) {
+        for address in &addresses {
+            if let Some(reply) = itip::reply(old, address, Some(&AttendeeStatus::Declined), now) {
+                deliver(data, &store, address, &organizer, reply).await;
+            }
+        }
+    }
+}
+
+pub fn inbox_href(user_id: Uuid) -> String {
+    format!("/caldav/scheduling/{}/inbox/", user_id)
+}
+
+pub fn outbox_href(user_id: Uuid) -> String {
+    format!("/caldav/scheduling/{}/outbox/", user_id)
+}
+
+/// PROPFIND on the schedule inbox, listing the delivered messages.
+pub async fn propfind_inbox(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let requested = match xml::requested_props(&body) {
+        Ok(requested) => requested,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+    let props = requested.unwrap_or_else(|| {
+        vec![
+            PropName::new(DAV, "resourcetype"),
+            PropName::new(DAV, "getetag"),
+            PropName::new(DAV, "getcontenttype"),
+        ]
+    });
+
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize_user(
+        &store,
+        user_id,
+        &inbox_href(user_id),
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let mut multistatus = MultiStatus::new();
+    let mut collection = Response::new(inbox_href(user_id));
+    for prop in &props {
+        if prop.is(DAV, "resourcetype") {
+            collection.prop(
+                prop.clone(),
+                PropValue::Xml("<d:collection/><c:schedule-inbox/>".to_string()),
+            );
+        } else {
+            collection.not_found(prop.clone());
+        }
+    }
+    multistatus.push(collection);
+
+    if !xml::depth_zero(&req) {
+        let messages = match store.list_messages(user_id).await {
+            Ok(messages) => messages,
+            Err(e) => return Ok(internal_error("Failed to load inbox", e)),
+        };
+        for message in messages {
+            let mut response = Response::new(format!("{}{}", inbox_href(user_id), message.name));
+            for prop in &props {
+                if prop.is(DAV, "resourcetype") {
+                    response.prop(prop.clone(), PropValue::Empty);
+                } else if prop.is(DAV, "getetag") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text(format!("\"{}\"", message.etag)),
+                    );
+                } else if prop.is(DAV, "getcontenttype") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text("text/calendar; charset=utf-8".to_string()),
+                    );
+                } else if prop.is(CALDAV, "calendar-data") {
+                    response.prop(prop.clone(), PropValue::Text(message.data.clone()));
+                } else {
+                    response.not_found(prop.clone());
+                }
+            }
+            multistatus.push(response);
+        }
+    }
+    Ok(multistatus.into_response())
+}
+
+pub async fn get_message(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (user_id, name) = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize_user(
+        &store,
+        user_id,
+        &inbox_href(user_id),
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    match store.get_message(user_id, &name).await {
+        Ok(Some(message)) => Ok(HttpResponse::Ok()
+            .content_type("text/calendar; charset=utf-8")
+            .insert_header(("ETag", format!("\"{}\"", message.etag)))
+            .body(message.data)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"}))),
+        Err(e) => Ok(internal_error("Failed to load message", e)),
+    }
+}
+
+pub async fn delete_message(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (user_id, name) = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize_user(
+        &store,
+        user_id,
+        &inbox_href(user_id),
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    match store.delete_message(user_id, &name).await {
+        Ok(true) => Ok(HttpResponse::NoContent().finish()),
+        Ok(false) => Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"}))),
+        Err(e) => Ok(internal_error("Failed to delete message", e)),
+    }
+}
+
+fn scheduling_error(condition: &str) -> HttpResponse {
+    xml::error_response(StatusCode::FORBIDDEN, PropName::new(CALDAV, condition))
+}
+
+fn utc_property(component: &protocol::ical::Component, name: &str) -> Option<DateTime<Utc>> {
+    match DateTimeValue::parse(component.property(name)?) {
+        Ok(DateTimeValue::Utc(time)) => Some(time),
+        _ => None,
+    }
+}
+
+/// POST to the schedule outbox. Only free/busy requests are sent this way
+/// (RFC 6638 §5); everything else is scheduled implicitly.
+pub async fn post_outbox(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let store = CalendarStore::new(&data.db_pool);
+    if let Err(response) = access::authorize_user(
+        &store,
+        user_id,
+        &outbox_href(user_id),
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let Some(request) = std::str::from_utf8(&body)
+        .ok()
+        .and_then(|text| ICalendar::parse(text).ok())
+    else {
+        return Ok(scheduling_error("valid-calendar-data"));
+    };
+    let Some(freebusy_request) = request
+        .root
+        .components_named("VFREEBUSY")
+        .next()
+        .filter(|_| itip::method(&request) == Some(Method::Request))
+    else {
+        return Ok(scheduling_error("valid-scheduling-message"));
+    };
+    let (Some(start), Some(end)) = (
+        utc_property(freebusy_request, "DTSTART"),
+        utc_property(freebusy_request, "DTEND"),
+    ) else {
+        return Ok(scheduling_error("valid-scheduling-message"));
+    };
+    if let Err(message) = freebusy::check_range(start, end) {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": message})));
+    }
+
+    let addresses = match store.addresses_of(user_id).await {
+        Ok(addresses) => addresses,
+        Err(e) => return Ok(internal_error("Failed to load addresses", e)),
+    };
+    let organizer = freebusy_request
+        .property("ORGANIZER")
+        .map(|p| p.value.clone())
+        .unwrap_or_default();
+    if !addresses.iter().any(|a| itip::same_address(a, &organizer)) {
+        return Ok(scheduling_error("organizer-allowed"));
+    }
+
+    let mut body = String::new();
+    for attendee in freebusy_request.properties_named("ATTENDEE") {
+        body.push_str("<c:response><c:recipient><d:href>");
+        body.push_str(&escape(&attendee.value));
+        body.push_str("</d:href></c:recipient>");
+
+        let user = match store.user_for_address(&attendee.value).await {
+            Ok(user) => user,
+            Err(e) => return Ok(internal_error("Failed to resolve attendee", e)),
+        };
+        let Some(attendee_id) = user else {
+            body.push_str("<c:request-status>3.7;Invalid calendar user</c:request-status>");
+            body.push_str("</c:response>");
+            continue;
+        };
+        let calendar_ids: Vec<Uuid> = match store.list_calendars(attendee_id).await {
+            Ok(calendars) => calendars.iter().map(|c| c.id).collect(),
+            Err(e) => return Ok(internal_error("Failed to load calendars", e)),
+        };
+        let busy_periods = match freebusy::busy_periods(&store, &calendar_ids, start, end).await {
+            Ok(busy_periods) => busy_periods,
+            Err(e) => return Ok(internal_error("Failed to load events", e)),
+        };
+        let mut reply = to_vfreebusy(
+            &FreeBusyResponse {
+                organizer: organizer.clone(),
+                attendee: attendee.value.clone(),
+                start,
+                end,
+                busy_periods,
+            },
+            Utc::now(),
+        );
+        reply
+            .root
+            .push_property(Property::new("METHOD", Method::Reply.as_str()));
+        if let (Some(uid), Some(component)) = (
+            freebusy_request.property("UID"),
+            reply.root.components.first_mut(),
+        ) {
+            component.push_property(uid.clone());
+        }
+        body.push_str("<c:request-status>2.0;Success</c:request-status><c:calendar-data>");
+        body.push_str(&escape(reply.to_ics()));
+        body.push_str("</c:calendar-data></c:response>");
+    }
+    Ok(xml::document_response(
+        StatusCode::OK,
+        PropName::new(CALDAV, "schedule-response"),
+        &body,
+    ))
+}
@@ -0,0 +1,247 @@
+//! Calendar sharing (draft-pot-webdav-resource-sharing)
+//!
+//! Admins of a calendar invite sharees with a `DAV:share-resource` POST.
+//! Access is granted in the calendar's permissions once the sharee accepts,
+//! either with a `DAV:invite-reply` POST to the calendar or through the JSON
+//! API; removing a sharee revokes it.
+
+use super::access;
+use super::store::{CalendarInvite, CalendarStore};
+use crate::auth::AuthenticatedUser;
+use crate::webdav::internal_error;
+use crate::webdav::xml::{self, Element, DAV};
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use protocol::caldav::CalendarAccess;
+use uuid::Uuid;
+
+pub const ACCEPTED: &str = "accepted";
+pub const DECLINED: &str = "declined";
+
+pub fn access_name(access: CalendarAccess) -> &'static str {
+    match access {
+        CalendarAccess::Read => "read",
+        CalendarAccess::Write => "write",
+        CalendarAccess::Admin => "admin",
+    }
+}
+
+pub fn parse_access(name: &str) -> Option<CalendarAccess> {
+    match name {
+        "read" => Some(CalendarAccess::Read),
+        "write" => Some(CalendarAccess::Write),
+        "admin" => Some(CalendarAccess::Admin),
+        _ => None,
+    }
+}
+
+/// `DAV:share-access` contents for a sharee's access.
+pub fn share_access_xml(access: CalendarAccess) -> &'static str {
+    match access {
+        CalendarAccess::Read => "<d:read/>",
+        CalendarAccess::Write | CalendarAccess::Admin => "<d:read-write/>",
+    }
+}
+
+/// `DAV:invite` contents listing the sharees of a calendar.
+pub fn invite_xml(invites: &[CalendarInvite]) -> String {
+    invites
+        .iter()
+        .map(|invite| {
+            let status = match invite.status.as_str() {
+                ACCEPTED => "<d:invite-accepted/>",
+                DECLINED => "<d:invite-declined/>",
+                _ => "<d:invite-noresponse/>",
+            };
+            let access = parse_access(&invite.access).unwrap_or(CalendarAccess::Read);
+            format!(
+                "<d:sharee>{}{}<d:share-access>{}</d:share-access></d:sharee>",
+                xml::hrefs([access::principal_href(invite.sharee_id)]),
+                status,
+                share_access_xml(access)
+            )
+        })
+        .collect()
+}
+
+/// Record the sharee's answer to an invitation, granting or revoking their
+/// access to the calendar.
+pub async fn answer_invite(
+    store: &CalendarStore,
+    invite: &CalendarInvite,
+    accept: bool,
+) -> Result<(), sqlx::Error> {
+    if let Some(calendar) = store.get_calendar(invite.calendar_id).await? {
+        let mut permissions = calendar.permissions;
+        match parse_access(&invite.access) {
+            Some(access) if accept => permissions.grant(invite.sharee_id, access),
+            _ => permissions.revoke(invite.sharee_id),
+        }
+        store
+            .update_permissions(invite.calendar_id, &permissions)
+            .await?;
+    }
+    store
+        .set_invite_status(invite.id, if accept { ACCEPTED } else { DECLINED })
+        .await
+}
+
+/// A principal URL or a calendar user address of a local user.
+async fn resolve_sharee(store: &CalendarStore, href: &str) -> Result<Option<Uuid>, sqlx::Error> {
+    match access::principal_id(href) {
+        Some(user_id) => Ok(Some(user_id)),
+        None => store.user_for_address(href).await,
+    }
+}
+
+struct ShareRequest {
+    href: String,
+    /// `None` for `DAV:no-access`, which removes the sharee.
+    access: Option<CalendarAccess>,
+    comment: Option<String>,
+}
+
+fn parse_sharee(sharee: &Element) -> Option<ShareRequest> {
+    let href = sharee.child(DAV, "href")?.text.trim().to_string();
+    let access = match sharee.child(DAV, "share-access")?.children.first()? {
+        e if e.is(DAV, "read") => Some(CalendarAccess::Read),
+        e if e.is(DAV, "read-write") => Some(CalendarAccess::Write),
+        e if e.is(DAV, "no-access") => None,
+        _ => return None,
+    };
+    Some(ShareRequest {
+        href,
+        access,
+        comment: sharee.child(DAV, "comment").map(|c| c.text.clone()),
+    })
+}
+
+async fn share(
+    store: &CalendarStore,
+    calendar_id: Uuid,
+    user: Option<Uuid>,
+    root: &Element,
+) -> HttpResponse {
+    let (calendar, _) =
+        match access::authorize(store, calendar_id, user, CalendarAccess::Admin).await {
+            Ok(authorized) => authorized,
+            Err(response) => return response,
+        };
+    let Some(requests) = root
+        .children_named(DAV, "sharee")
+        .map(parse_sharee)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return HttpResponse::BadRequest()
+            .json(serde_json::json!({"error": "Invalid DAV:sharee element"}));
+    };
+
+    // Resolve every sharee before changing anything.
+    let mut sharees = Vec::with_capacity(requests.len());
+    for request in requests {
+        match resolve_sharee(store, &request.href).await {
+            Ok(Some(sharee_id)) => sharees.push((sharee_id, request)),
+            Ok(None) => {
+                return HttpResponse::BadRequest().json(
+                    serde_json::json!({"error": format!("Unknown sharee: {}", request.href)}),
+                )
+            }
+            Err(e) => return internal_error("Failed to resolve sharee", e),
+        }
+    }
+
+    // Sharees find pending invitations through the JSON API and the
+    // DAV:invite property.
+    let mut permissions = calendar.permissions;
+    for (sharee_id, request) in sharees {
+        if sharee_id == calendar.owner_id {
+            continue;
+        }
+        let result = match request.access {
+            Some(access) => store
+                .upsert_invite(
+                    calendar_id,
+                    sharee_id,
+                    access_name(access),
+                    request.comment.as_deref(),
+                )
+                .await
+                .map(|invite| {
+                    if invite.status == ACCEPTED {
+                        permissions.grant(sharee_id, access);
+                    }
+                }),
+            None => store
+                .remove_invite(calendar_id, sharee_id)
+                .await
+                .map(|_| permissions.revoke(sharee_id)),
+        };
+        if let Err(e) = result {
+            return internal_error("Failed to update invitation", e);
+        }
+    }
+    match store.update_permissions(calendar_id, &permissions).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => internal_error("Failed to update permissions", e),
+    }
+}
+
+async fn reply(
+    store: &CalendarStore,
+    calendar_id: Uuid,
+    user: Option<Uuid>,
+    root: &Element,
+) -> HttpResponse {
+    let Some(user_id) = user else {
+        return HttpResponse::Unauthorized()
+            .json(serde_json::json!({"error": "Authentication required"}));
+    };
+    let accept = if root.child(DAV, "invite-accepted").is_some() {
+        true
+    } else if root.child(DAV, "invite-declined").is_some() {
+        false
+    } else {
+        return HttpResponse::BadRequest().json(
+            serde_json::json!({"error": "Expected DAV:invite-accepted or DAV:invite-declined"}),
+        );
+    };
+    let invite = match store.find_invite(calendar_id, user_id).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({"error": "Invitation not found"}))
+        }
+        Err(e) => return internal_error("Failed to load invitation", e),
+    };
+    match answer_invite(store, &invite, accept).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => internal_error("Failed to answer invitation", e),
+    }
+}
+
+/// POST to a calendar collection: `DAV:share-resource` or `DAV:invite-reply`.
+pub async fn post_calendar(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let calendar_id = path.into_inner();
+    let root = match xml::parse(&body) {
+        Ok(root) => root,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+    let store = CalendarStore::new(&data.db_pool);
+    let user = user.map(|u| u.user_id);
+
+    if root.is(DAV, "share-resource") {
+        Ok(share(&store, calendar_id, user, &root).await)
+    } else if root.is(DAV, "invite-reply") {
+        Ok(reply(&store, calendar_id, user, &root).await)
+    } else {
+        Ok(HttpResponse::BadRequest()
+            .json(serde_json::json!({"error": "Unsupported POST to a calendar"})))
+    }
+}
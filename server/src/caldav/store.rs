@@ -0,0 +1,655 @@
+//! Calendar and calendar object persistence
+
+use crate::config::database::DatabasePool;
+use crate::webdav::etag;
+use chrono::{DateTime, Utc};
+use protocol::caldav::{Calendar, CalendarPermissions};
+use protocol::ical::{ICalError, ICalendar};
+use protocol::itip;
+use protocol::recurrence::RecurrenceSet;
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// A stored calendar object resource: one `.ics` holding a single component
+/// type and UID (RFC 4791 §4.1).
+#[derive(Debug, Clone, FromRow)]
+pub struct CalendarObject {
+    pub id: Uuid,
+    pub calendar_id: Uuid,
+    pub name: String,
+    pub uid: String,
+    pub etag: String,
+    pub data: String,
+    pub component_type: String,
+    /// Earliest start of any instance; `None` when unknown.
+    pub first_start: Option<DateTime<Utc>>,
+    /// Latest end of any instance; `None` when unbounded or unknown.
+    pub last_end: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An iTIP message in a user's schedule inbox (RFC 6638 §2.2).
+#[derive(Debug, Clone, FromRow)]
+pub struct ScheduleMessage {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub uid: String,
+    pub method: String,
+    pub originator: String,
+    pub etag: String,
+    pub data: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An invitation to share a calendar (draft-pot-webdav-resource-sharing).
+#[derive(Debug, Clone, FromRow)]
+pub struct CalendarInvite {
+    pub id: Uuid,
+    pub calendar_id: Uuid,
+    pub sharee_id: Uuid,
+    /// `read` or `write`.
+    pub access: String,
+    /// `pending`, `accepted` or `declined`.
+    pub status: String,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A delegate acting for a calendar owner.
+#[derive(Debug, Clone, Copy, FromRow)]
+pub struct CalendarProxy {
+    pub owner_id: Uuid,
+    pub delegate_id: Uuid,
+    pub can_write: bool,
+}
+
+#[derive(FromRow)]
+struct CalendarRow {
+    id: Uuid,
+    owner_id: Uuid,
+    name: String,
+    display_name: String,
+    description: Option<String>,
+    color: Option<String>,
+    timezone: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    is_shared: bool,
+    permissions: Json<CalendarPermissions>,
+}
+
+impl From<CalendarRow> for Calendar {
+    fn from(row: CalendarRow) -> Self {
+        Calendar {
+            id: row.id,
+            owner_id: row.owner_id,
+            name: row.name,
+            display_name: row.display_name,
+            description: row.description,
+            color: row.color,
+            timezone: row.timezone,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            is_shared: row.is_shared,
+            permissions: row.permissions.0,
+        }
+    }
+}
+
+/// Columns indexed for a calendar object resource.
+#[derive(Debug, Clone)]
+pub struct ObjectIndex {
+    pub uid: String,
+    pub component_type: String,
+    pub first_start: Option<DateTime<Utc>>,
+    pub last_end: Option<DateTime<Utc>>,
+}
+
+impl ObjectIndex {
+    /// Check that `calendar` is a valid calendar object resource and compute
+    /// the time span used to pre-filter time-range queries.
+    pub fn from_calendar(calendar: &ICalendar) -> Result<Self, ICalError> {
+        let zones = calendar.timezones();
+        let components: Vec<_> = calendar
+            .root
+            .components
+            .iter()
+            .filter(|c| c.name != "VTIMEZONE")
+            .collect();
+        let first = components.first().ok_or_else(|| ICalError::Structure {
+            line: 0,
+            message: "calendar object contains no component".to_string(),
+        })?;
+        let uid = first
+            .property("UID")
+            .map(|p| p.value.clone())
+            .ok_or_else(|| ICalError::MissingProperty("UID".to_string()))?;
+        if components
+            .iter()
+            .any(|c| c.name != first.name || c.property("UID").map(|p| &p.value) != Some(&uid))
+        {
+            return Err(ICalError::Structure {
+                line: 0,
+                message: "calendar object mixes component types or UIDs".to_string(),
+            });
+        }
+
+        let mut first_start: Option<DateTime<Utc>> = None;
+        let mut last_end: Option<DateTime<Utc>> = None;
+        let mut bounded = true;
+        for component in &components {
+            match RecurrenceSet::from_component(component, &zones)? {
+                Some(set) => {
+                    let start = zones.to_utc(&set.start);
+                    first_start = Some(first_start.map_or(start, |s| s.min(start)));
+                    match set.last_end(&zones) {
+                        Some(end) => last_end = Some(last_end.map_or(end, |e| e.max(end))),
+                        None => bounded = false,
+                    }
+                }
+                // Undated components (e.g. a VTODO with only DUE) are always
+                // candidates for time-range queries.
+                None => return Ok(Self::unbounded(uid, first.name.clone())),
+            }
+        }
+        Ok(Self {
+            uid,
+            component_type: first.name.clone(),
+            first_start,
+            last_end: if bounded { last_end } else { None },
+        })
+    }
+
+    fn unbounded(uid: String, component_type: String) -> Self {
+        Self {
+            uid,
+            component_type,
+            first_start: None,
+            last_end: None,
+        }
+    }
+}
+
+pub struct CalendarStore {
+    pool: PgPool,
+}
+
+impl CalendarStore {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    pub async fn get_calendar(&self, id: Uuid) -> Result<Option<Calendar>, sqlx::Error> {
+        let row = sqlx::query_as::<_, CalendarRow>(
+            "SELECT id, owner_id, name, display_name, description, color, timezone,
+                    created_at, updated_at, is_shared, permissions
+             FROM calendars WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Calendar::from))
+    }
+
+    pub async fn list_calendars(&self, owner_id: Uuid) -> Result<Vec<Calendar>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, CalendarRow>(
+            "SELECT id, owner_id, name, display_name, description, color, timezone,
+                    created_at, updated_at, is_shared, permissions
+             FROM calendars WHERE owner_id = $1 ORDER BY name",
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Calendar::from).collect())
+    }
+
+    pub async fn create_calendar(
+        &self,
+        owner_id: Uuid,
+        name: &str,
+        display_name: &str,
+        timezone: &str,
+    ) -> Result<Calendar, sqlx::Error> {
+        let row = sqlx::query_as::<_, CalendarRow>(
+            "INSERT INTO calendars (id, owner_id, name, display_name, timezone)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING id, owner_id, name, display_name, description, color, timezone,
+                       created_at, updated_at, is_shared, permissions",
+        )
+        .bind(Uuid::new_v4())
+        .bind(owner_id)
+        .bind(name)
+        .bind(display_name)
+        .bind(timezone)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.into())
+    }
+
+    /// Replace a calendar's permissions, updating `is_shared` to match.
+    pub async fn update_permissions(
+        &self,
+        calendar_id: Uuid,
+        permissions: &CalendarPermissions,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE calendars SET permissions = $2, is_shared = $3, updated_at = now()
+             WHERE id = $1",
+        )
+        .bind(calendar_id)
+        .bind(Json(permissions))
+        .bind(permissions.is_shared())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_objects(
+        &self,
+        calendar_id: Uuid,
+    ) -> Result<Vec<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT * FROM calendar_objects WHERE calendar_id = $1 ORDER BY name",
+        )
+        .bind(calendar_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Objects that may have an instance in `[start, end)`. This is only a
+    /// pre-filter on the indexed span; callers still evaluate the query.
+    pub async fn objects_in_range(
+        &self,
+        calendar_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT * FROM calendar_objects
+             WHERE calendar_id = $1
+               AND (first_start IS NULL OR first_start < $3)
+               AND (last_end IS NULL OR last_end >= $2)
+             ORDER BY name",
+        )
+        .bind(calendar_id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_objects(
+        &self,
+        calendar_id: Uuid,
+        names: &[String],
+    ) -> Result<Vec<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT * FROM calendar_objects WHERE calendar_id = $1 AND name = ANY($2)",
+        )
+        .bind(calendar_id)
+        .bind(names)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_object(
+        &self,
+        calendar_id: Uuid,
+        name: &str,
+    ) -> Result<Option<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT * FROM calendar_objects WHERE calendar_id = $1 AND name = $2",
+        )
+        .bind(calendar_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn find_by_uid(
+        &self,
+        calendar_id: Uuid,
+        uid: &str,
+    ) -> Result<Option<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT * FROM calendar_objects WHERE calendar_id = $1 AND uid = $2",
+        )
+        .bind(calendar_id)
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Create or replace an object and bump the calendar's sync token.
+    pub async fn put_object(
+        &self,
+        calendar_id: Uuid,
+        name: &str,
+        data: &str,
+        index: &ObjectIndex,
+    ) -> Result<CalendarObject, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let object = sqlx::query_as::<_, CalendarObject>(
+            "INSERT INTO calendar_objects
+                 (id, calendar_id, name, uid, etag, data, component_type, first_start, last_end)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+             ON CONFLICT (calendar_id, name) DO UPDATE SET
+                 uid = EXCLUDED.uid,
+                 etag = EXCLUDED.etag,
+                 data = EXCLUDED.data,
+                 component_type = EXCLUDED.component_type,
+                 first_start = EXCLUDED.first_start,
+                 last_end = EXCLUDED.last_end,
+                 updated_at = now()
+             RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(calendar_id)
+        .bind(name)
+        .bind(&index.uid)
+        .bind(etag(data))
+        .bind(data)
+        .bind(&index.component_type)
+        .bind(index.first_start)
+        .bind(index.last_end)
+        .fetch_one(&mut *tx)
+        .await?;
+        Self::touch(&mut tx, calendar_id).await?;
+        tx.commit().await?;
+        Ok(object)
+    }
+
+    /// Delete an object; returns whether it existed.
+    pub async fn delete_object(&self, calendar_id: Uuid, name: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let deleted =
+            sqlx::query("DELETE FROM calendar_objects WHERE calendar_id = $1 AND name = $2")
+                .bind(calendar_id)
+                .bind(name)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+        if deleted {
+            Self::touch(&mut tx, calendar_id).await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// The local user a calendar user address belongs to.
+    pub async fn user_for_address(&self, address: &str) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar("SELECT user_id FROM calendar_user_addresses WHERE address = $1")
+            .bind(itip::normalize_address(address))
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn addresses_of(&self, user_id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT address FROM calendar_user_addresses WHERE user_id = $1 ORDER BY address",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// The object with `uid` in any calendar owned by `owner_id`.
+    pub async fn find_owned_by_uid(
+        &self,
+        owner_id: Uuid,
+        uid: &str,
+    ) -> Result<Option<CalendarObject>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarObject>(
+            "SELECT o.* FROM calendar_objects o
+             JOIN calendars c ON c.id = o.calendar_id
+             WHERE c.owner_id = $1 AND o.uid = $2
+             ORDER BY o.created_at
+             LIMIT 1",
+        )
+        .bind(owner_id)
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn deliver_message(
+        &self,
+        user_id: Uuid,
+        uid: &str,
+        method: &str,
+        originator: &str,
+        data: &str,
+    ) -> Result<ScheduleMessage, sqlx::Error> {
+        let id = Uuid::new_v4();
+        sqlx::query_as::<_, ScheduleMessage>(
+            "INSERT INTO schedule_messages (id, user_id, name, uid, method, originator, etag, data)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             RETURNING *",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(format!("{}.ics", id))
+        .bind(uid)
+        .bind(method)
+        .bind(originator)
+        .bind(etag(data))
+        .bind(data)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn list_messages(&self, user_id: Uuid) -> Result<Vec<ScheduleMessage>, sqlx::Error> {
+        sqlx::query_as::<_, ScheduleMessage>(
+            "SELECT * FROM schedule_messages WHERE user_id = $1 ORDER BY created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_message(
+        &self,
+        user_id: Uuid,
+        name: &str,
+    ) -> Result<Option<ScheduleMessage>, sqlx::Error> {
+        sqlx::query_as::<_, ScheduleMessage>(
+            "SELECT * FROM schedule_messages WHERE user_id = $1 AND name = $2",
+        )
+        .bind(user_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn delete_message(&self, user_id: Uuid, name: &str) -> Result<bool, sqlx::Error> {
+        Ok(
+            sqlx::query("DELETE FROM schedule_messages WHERE user_id = $1 AND name = $2")
+                .bind(user_id)
+                .bind(name)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+
+    /// Create or update the invitation of `sharee_id` to a calendar. An
+    /// existing answer is kept, so re-sharing only changes the access level.
+    pub async fn upsert_invite(
+        &self,
+        calendar_id: Uuid,
+        sharee_id: Uuid,
+        access: &str,
+        comment: Option<&str>,
+    ) -> Result<CalendarInvite, sqlx::Error> {
+        sqlx::query_as::<_, CalendarInvite>(
+            "INSERT INTO calendar_invites (id, calendar_id, sharee_id, access, comment)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (calendar_id, sharee_id) DO UPDATE SET
+                 access = EXCLUDED.access,
+                 comment = EXCLUDED.comment,
+                 updated_at = now()
+             RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(calendar_id)
+        .bind(sharee_id)
+        .bind(access)
+        .bind(comment)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    pub async fn remove_invite(
+        &self,
+        calendar_id: Uuid,
+        sharee_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(
+            sqlx::query("DELETE FROM calendar_invites WHERE calendar_id = $1 AND sharee_id = $2")
+                .bind(calendar_id)
+                .bind(sharee_id)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+
+    pub async fn get_invite(&self, id: Uuid) -> Result<Option<CalendarInvite>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarInvite>("SELECT * FROM calendar_invites WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn find_invite(
+        &self,
+        calendar_id: Uuid,
+        sharee_id: Uuid,
+    ) -> Result<Option<CalendarInvite>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarInvite>(
+            "SELECT * FROM calendar_invites WHERE calendar_id = $1 AND sharee_id = $2",
+        )
+        .bind(calendar_id)
+        .bind(sharee_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn invites_for_calendar(
+        &self,
+        calendar_id: Uuid,
+    ) -> Result<Vec<CalendarInvite>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarInvite>(
+            "SELECT * FROM calendar_invites WHERE calendar_id = $1 ORDER BY created_at",
+        )
+        .bind(calendar_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn invites_for_user(
+        &self,
+        sharee_id: Uuid,
+    ) -> Result<Vec<CalendarInvite>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarInvite>(
+            "SELECT * FROM calendar_invites WHERE sharee_id = $1 ORDER BY created_at",
+        )
+        .bind(sharee_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn set_invite_status(&self, id: Uuid, status: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE calendar_invites SET status = $2, updated_at = now() WHERE id = $1")
+            .bind(id)
+            .bind(status)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Delegates of `owner_id`.
+    pub async fn proxies_of(&self, owner_id: Uuid) -> Result<Vec<CalendarProxy>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarProxy>(
+            "SELECT * FROM calendar_proxies WHERE owner_id = $1 ORDER BY delegate_id",
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// Owners `delegate_id` acts for.
+    pub async fn delegations(&self, delegate_id: Uuid) -> Result<Vec<CalendarProxy>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarProxy>(
+            "SELECT * FROM calendar_proxies WHERE delegate_id = $1 ORDER BY owner_id",
+        )
+        .bind(delegate_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_proxy(
+        &self,
+        owner_id: Uuid,
+        delegate_id: Uuid,
+    ) -> Result<Option<CalendarProxy>, sqlx::Error> {
+        sqlx::query_as::<_, CalendarProxy>(
+            "SELECT * FROM calendar_proxies WHERE owner_id = $1 AND delegate_id = $2",
+        )
+        .bind(owner_id)
+        .bind(delegate_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Replace the read-only (`can_write` false) or read-write delegates of
+    /// `owner_id`. A delegate moved between the groups keeps one entry.
+    pub async fn set_proxies(
+        &self,
+        owner_id: Uuid,
+        can_write: bool,
+        delegates: &[Uuid],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM calendar_proxies WHERE owner_id = $1 AND can_write = $2")
+            .bind(owner_id)
+            .bind(can_write)
+            .execute(&mut *tx)
+            .await?;
+        for delegate in delegates.iter().filter(|d| **d != owner_id) {
+            sqlx::query(
+                "INSERT INTO calendar_proxies (owner_id, delegate_id, can_write)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT (owner_id, delegate_id) DO UPDATE SET can_write = EXCLUDED.can_write",
+            )
+            .bind(owner_id)
+            .bind(delegate)
+            .bind(can_write)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn touch(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        calendar_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE calendars SET sync_token = sync_token + 1, updated_at = now() WHERE id = $1",
+        )
+        .bind(calendar_id)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! Import and export of whole calendars as `.ics` files
+//!
+//! Imports split the file into one calendar object resource per UID and
+//! match them to existing objects by UID, so importing the same export twice
+//! changes nothing and importing a newer one updates objects in place with a
+//! higher `SEQUENCE`. Imported objects are stored as is: no scheduling
+//! messages are sent for them.
+
+use super::store::{CalendarObject, CalendarStore, ObjectIndex};
+use protocol::caldav::{Calendar, CalendarImportResult, ImportFailure};
+use protocol::ical::{parse_components, Component, ICalError, ICalendar, Property};
+use uuid::Uuid;
+
+/// Largest `.ics` file accepted for import.
+pub const MAX_IMPORT_SIZE: usize = 32 * 1024 * 1024;
+
+/// Properties rewritten by every export, ignored when deciding whether an
+/// imported object differs from the stored one.
+const VOLATILE_PROPERTIES: &[&str] = &["DTSTAMP", "LAST-MODIFIED", "SEQUENCE"];
+
+fn significant(calendar: &ICalendar) -> Vec<Component> {
+    calendar
+        .root
+        .components
+        .iter()
+        .filter(|c| c.name != "VTIMEZONE")
+        .map(|c| {
+            let mut c = c.clone();
+            for name in VOLATILE_PROPERTIES {
+                c.remove_properties(name);
+            }
+            c
+        })
+        .collect()
+}
+
+/// Name for a new object: the UID when it is usable in an href, a random one
+/// otherwise.
+fn object_name(uid: &str) -> String {
+    let name = format!("{}.ics", uid);
+    if super::valid_object_name(&name) {
+        name
+    } else {
+        format!("{}.ics", Uuid::new_v4())
+    }
+}
+
+/// Parse an uploaded `.ics` file. Some tools concatenate several VCALENDARs
+/// into one file; they are merged.
+pub fn parse_upload(body: &[u8]) -> Result<ICalendar, ICalError> {
+    let text = std::str::from_utf8(body).map_err(|_| ICalError::Structure {
+        line: 0,
+        message: "calendar data is not UTF-8".to_string(),
+    })?;
+    let roots = parse_components(text)?;
+    if roots.is_empty() {
+        return Err(ICalError::Structure {
+            line: 1,
+            message: "no VCALENDAR object".to_string(),
+        });
+    }
+    let calendars = roots
+        .into_iter()
+        .map(|root| {
+            if root.name.eq_ignore_ascii_case("VCALENDAR") {
+                Ok(ICalendar { root })
+            } else {
+                Err(ICalError::Structure {
+                    line: 1,
+                    message: format!("expected VCALENDAR, found {}", root.name),
+                })
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut merged = ICalendar::merge(&calendars);
+    // Keep the calendar properties of the first, such as X-WR-CALNAME.
+    merged.root.properties = calendars[0].root.properties.clone();
+    Ok(merged)
+}
+
+/// Import every object of `ics` into a calendar.
+pub async fn import(
+    store: &CalendarStore,
+    calendar_id: Uuid,
+    ics: &ICalendar,
+) -> Result<CalendarImportResult, sqlx::Error> {
+    let mut result = CalendarImportResult {
+        calendar_id,
+        ..Default::default()
+    };
+    for mut object in ics.split_objects() {
+        let index = match ObjectIndex::from_calendar(&object) {
+            Ok(index) => index,
+            Err(e) => {
+                let uid = object
+                    .root
+                    .components
+                    .iter()
+                    .find_map(|c| c.property("UID"))
+                    .map(|p| p.value.clone());
+                result.failed.push(ImportFailure {
+                    uid,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        match store.find_by_uid(calendar_id, &index.uid).await? {
+            Some(existing) => {
+                if let Ok(current) = ICalendar::parse(&existing.data) {
+                    if significant(&current) == significant(&object) {
+                        result.unchanged += 1;
+                        continue;
+                    }
+                    object.raise_sequence(current.sequence() + 1);
+                }
+                store
+                    .put_object(calendar_id, &existing.name, &object.to_ics(), &index)
+                    .await?;
+                result.updated += 1;
+            }
+            None => {
+                let mut name = object_name(&index.uid);
+                if store.get_object(calendar_id, &name).await?.is_some() {
+                    name = format!("{}.ics", Uuid::new_v4());
+                }
+                store
+                    .put_object(calendar_id, &name, &object.to_ics(), &index)
+                    .await?;
+                result.created += 1;
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Every object of a calendar merged into a single VCALENDAR. Objects that
+/// fail to parse are skipped.
+pub fn export(calendar: &Calendar, objects: &[CalendarObject]) -> ICalendar {
+    let calendars: Vec<ICalendar> = objects
+        .iter()
+        .filter_map(|object| match ICalendar::parse(&object.data) {
+            Ok(ics) => Some(ics),
+            Err(_) => {
+                tracing::warn!(object = %object.name, "Skipping unparsable calendar object");
+                None
+            }
+        })
+        .collect();
+    let mut merged = ICalendar::merge(&calendars);
+    merged
+        .root
+        .push_property(Property::text("X-WR-CALNAME", &calendar.display_name));
+    merged
+        .root
+        .push_property(Property::new("X-WR-TIMEZONE", calendar.timezone.clone()));
+    merged
+}
@@ -0,0 +1,224 @@
+//! PROPFIND on address book collections and address book homes (RFC 6352
+//! §6, §7)
+
+use super::report::object_href;
+use super::store::AddressBookStore;
+use crate::auth::AuthenticatedUser;
+use crate::caldav::access::{self, principal_href};
+use crate::webdav::internal_error;
+use crate::webdav::sync::format_token;
+use crate::webdav::xml::{
+    self, MultiStatus, PropName, PropValue, Response, CALENDARSERVER, CARDDAV, DAV,
+};
+use crate::AppState;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use protocol::caldav::CalendarAccess;
+use protocol::carddav::AddressBook;
+use uuid::Uuid;
+
+pub fn address_book_href(address_book_id: Uuid) -> String {
+    format!("/carddav/addressbooks/{}/", address_book_id)
+}
+
+pub fn home_href(user_id: Uuid) -> String {
+    format!("/carddav/home/{}/", user_id)
+}
+
+const SUPPORTED_ADDRESS_DATA: &str = "<card:address-data-type content-type=\"text/vcard\" \
+     version=\"3.0\"/><card:address-data-type content-type=\"text/vcard\" version=\"4.0\"/>";
+
+const SUPPORTED_REPORTS: &str = "<d:supported-report><d:report><card:addressbook-query/>\
+     </d:report></d:supported-report><d:supported-report><d:report>\
+     <card:addressbook-multiget/></d:report></d:supported-report><d:supported-report>\
+     <d:report><d:sync-collection/></d:report></d:supported-report>";
+
+fn default_props() -> Vec<PropName> {
+    vec![
+        PropName::new(DAV, "resourcetype"),
+        PropName::new(DAV, "displayname"),
+        PropName::new(DAV, "owner"),
+        PropName::new(DAV, "getetag"),
+        PropName::new(DAV, "getcontenttype"),
+    ]
+}
+
+/// Properties of an address book collection as seen by `user`.
+fn address_book_response(
+    address_book: &AddressBook,
+    sync_token: i64,
+    user: Option<Uuid>,
+    access: CalendarAccess,
+    props: &[PropName],
+) -> Response {
+    let mut response = Response::new(address_book_href(address_book.id));
+    for prop in props {
+        let value = if prop.is(DAV, "resourcetype") {
+            Some(PropValue::Xml(
+                "<d:collection/><card:addressbook/>".to_string(),
+            ))
+        } else if prop.is(DAV, "displayname") {
+            Some(PropValue::Text(address_book.display_name.clone()))
+        } else if prop.is(CARDDAV, "addressbook-description") {
+            address_book.description.clone().map(PropValue::Text)
+        } else if prop.is(CARDDAV, "supported-address-data") {
+            Some(PropValue::Xml(SUPPORTED_ADDRESS_DATA.to_string()))
+        } else if prop.is(DAV, "supported-report-set") {
+            Some(PropValue::Xml(SUPPORTED_REPORTS.to_string()))
+        } else if prop.is(DAV, "sync-token") {
+            Some(PropValue::Text(format_token(sync_token)))
+        } else if prop.is(CALENDARSERVER, "getctag") {
+            Some(PropValue::Text(sync_token.to_string()))
+        } else if prop.is(DAV, "owner") {
+            Some(PropValue::Xml(xml::hrefs([principal_href(
+                address_book.owner_id,
+            )])))
+        } else if prop.is(DAV, "current-user-principal") {
+            Some(PropValue::Xml(match user {
+                Some(user) => xml::hrefs([principal_href(user)]),
+                None => "<d:unauthenticated/>".to_string(),
+            }))
+        } else if prop.is(DAV, "current-user-privilege-set") {
+            Some(PropValue::Xml(access::privilege_set(access)))
+        } else {
+            None
+        };
+        match value {
+            Some(value) => response.prop(prop.clone(), value),
+            None => response.not_found(prop.clone()),
+        }
+    }
+    response
+}
+
+pub async fn propfind(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let address_book_id = path.into_inner();
+    let props = match xml::requested_props(&body) {
+        Ok(requested) => requested.unwrap_or_else(default_props),
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+
+    let store = AddressBookStore::new(&data.db_pool);
+    let user = user.map(|u| u.user_id);
+    let (address_book, access) =
+        match super::authorize(&store, address_book_id, user, CalendarAccess::Read).await {
+            Ok(authorized) => authorized,
+            Err(response) => return Ok(response),
+        };
+    let sync_token = match store.sync_token(address_book_id).await {
+        Ok(sync_token) => sync_token,
+        Err(e) => return Ok(internal_error("Failed to load sync token", e)),
+    };
+
+    let mut multistatus = MultiStatus::new();
+    multistatus.push(address_book_response(
+        &address_book,
+        sync_token,
+        user,
+        access,
+        &props,
+    ));
+
+    if !xml::depth_zero(&req) {
+        let objects = match store.list_objects(address_book_id).await {
+            Ok(objects) => objects,
+            Err(e) => return Ok(internal_error("Failed to load objects", e)),
+        };
+        for object in objects {
+            let mut response = Response::new(object_href(address_book_id, &object.name));
+            for prop in &props {
+                if prop.is(DAV, "resourcetype") {
+                    response.prop(prop.clone(), PropValue::Empty);
+                } else if prop.is(DAV, "getetag") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text(format!("\"{}\"", object.etag)),
+                    );
+                } else if prop.is(DAV, "getcontenttype") {
+                    response.prop(
+                        prop.clone(),
+                        PropValue::Text("text/vcard; charset=utf-8".to_string()),
+                    );
+                } else {
+                    response.not_found(prop.clone());
+                }
+            }
+            multistatus.push(response);
+        }
+    }
+    Ok(multistatus.into_response())
+}
+
+/// PROPFIND on a user's address book home, listing the address books they
+/// own or that are shared with them.
+pub async fn propfind_home(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let user_id = path.into_inner();
+    let href = home_href(user_id);
+    if user.user_id != user_id {
+        return Ok(access::denied(
+            &href,
+            Some(user.user_id),
+            CalendarAccess::Read,
+        ));
+    }
+    let props = match xml::requested_props(&body) {
+        Ok(requested) => requested.unwrap_or_else(default_props),
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})))
+        }
+    };
+
+    let mut home = Response::new(href);
+    for prop in &props {
+        if prop.is(DAV, "resourcetype") {
+            home.prop(prop.clone(), PropValue::Xml("<d:collection/>".to_string()));
+        } else if prop.is(DAV, "owner") || prop.is(DAV, "current-user-principal") {
+            home.prop(
+                prop.clone(),
+                PropValue::Xml(xml::hrefs([principal_href(user_id)])),
+            );
+        } else {
+            home.not_found(prop.clone());
+        }
+    }
+    let mut multistatus = MultiStatus::new();
+    multistatus.push(home);
+
+    if !xml::depth_zero(&req) {
+        let store = AddressBookStore::new(&data.db_pool);
+        let address_books = match store.list_address_books(user_id).await {
+            Ok(address_books) => address_books,
+            Err(e) => return Ok(internal_error("Failed to load address books", e)),
+        };
+        for address_book in &address_books {
+            let Some(access) = address_book.access(Some(user_id)) else {
+                continue;
+            };
+            let sync_token = match store.sync_token(address_book.id).await {
+                Ok(sync_token) => sync_token,
+                Err(e) => return Ok(internal_error("Failed to load sync token", e)),
+            };
+            multistatus.push(address_book_response(
+                address_book,
+                sync_token,
+                Some(user_id),
+                access,
+                &props,
+            ));
+        }
+    }
+    Ok(multistatus.into_response())
+}
@@ -0,0 +1,219 @@
+//! CardDAV endpoints (RFC 6352)
+
+pub mod collection;
+pub mod query;
+pub mod report;
+pub mod store;
+
+use crate::auth::AuthenticatedUser;
+use crate::caldav::access;
+use crate::webdav::xml::{self, PropName, CARDDAV};
+use crate::webdav::{etag_header, internal_error, preconditions_hold, valid_object_name};
+use crate::AppState;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use protocol::caldav::CalendarAccess;
+use protocol::carddav::AddressBook;
+use protocol::vcard::VCard;
+use store::AddressBookStore;
+use uuid::Uuid;
+
+/// Load an address book the caller has at least `required` access to; the
+/// error is the response to return.
+pub async fn authorize(
+    store: &AddressBookStore,
+    address_book_id: Uuid,
+    user: Option<Uuid>,
+    required: CalendarAccess,
+) -> Result<(AddressBook, CalendarAccess), HttpResponse> {
+    let address_book = match store.get_address_book(address_book_id).await {
+        Ok(Some(address_book)) => address_book,
+        Ok(None) => {
+            return Err(HttpResponse::NotFound()
+                .json(serde_json::json!({"error": "Address book not found"})))
+        }
+        Err(e) => return Err(internal_error("Failed to load address book", e)),
+    };
+    match address_book.access(user) {
+        Some(access) if access >= required => Ok((address_book, access)),
+        _ => Err(access::denied(
+            &collection::address_book_href(address_book_id),
+            user,
+            required,
+        )),
+    }
+}
+
+pub async fn get_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (address_book_id, name) = path.into_inner();
+    let store = AddressBookStore::new(&data.db_pool);
+
+    if let Err(response) = authorize(
+        &store,
+        address_book_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    match store.get_object(address_book_id, &name).await {
+        Ok(Some(object)) => Ok(HttpResponse::Ok()
+            .content_type("text/vcard; charset=utf-8")
+            .insert_header(etag_header(&object.etag))
+            .body(object.data)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"}))),
+        Err(e) => Ok(internal_error("Failed to load object", e)),
+    }
+}
+
+pub async fn put_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (address_book_id, name) = path.into_inner();
+    if !valid_object_name(&name) {
+        return Ok(
+            HttpResponse::BadRequest().json(serde_json::json!({"error": "Invalid object name"}))
+        );
+    }
+    let store = AddressBookStore::new(&data.db_pool);
+
+    if let Err(response) = authorize(
+        &store,
+        address_book_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let card = match std::str::from_utf8(&body)
+        .ok()
+        .and_then(|text| VCard::parse(text).ok())
+    {
+        Some(card) => card,
+        None => {
+            return Ok(xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(CARDDAV, "valid-address-data"),
+            ))
+        }
+    };
+    // Validation guarantees both are present.
+    let uid = card.uid().unwrap_or_default();
+    let formatted_name = card
+        .property("FN")
+        .map(|p| p.text_value())
+        .unwrap_or_default();
+
+    let existing = match store.get_object(address_book_id, &name).await {
+        Ok(existing) => existing,
+        Err(e) => return Ok(internal_error("Failed to load object", e)),
+    };
+    if !preconditions_hold(&req, existing.as_ref().map(|o| o.etag.as_str())) {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    match store.find_by_uid(address_book_id, &uid).await {
+        Ok(Some(other)) if other.name != name => {
+            return Ok(xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(CARDDAV, "no-uid-conflict"),
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => return Ok(internal_error("Failed to check UID", e)),
+    }
+
+    match store
+        .put_object(
+            address_book_id,
+            &name,
+            &uid,
+            &formatted_name,
+            &card.to_vcf(),
+        )
+        .await
+    {
+        Ok(object) => {
+            let mut response = if existing.is_some() {
+                HttpResponse::NoContent()
+            } else {
+                HttpResponse::Created()
+            };
+            Ok(response.insert_header(etag_header(&object.etag)).finish())
+        }
+        Err(e) => Ok(internal_error("Failed to store object", e)),
+    }
+}
+
+pub async fn delete_object(
+    path: web::Path<(Uuid, String)>,
+    user: Option<AuthenticatedUser>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (address_book_id, name) = path.into_inner();
+    let store = AddressBookStore::new(&data.db_pool);
+
+    if let Err(response) = authorize(
+        &store,
+        address_book_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Write,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+    let existing = match store.get_object(address_book_id, &name).await {
+        Ok(Some(existing)) => existing,
+        Ok(None) => {
+            return Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Not found"})))
+        }
+        Err(e) => return Ok(internal_error("Failed to load object", e)),
+    };
+    if !preconditions_hold(&req, Some(&existing.etag)) {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    match store.delete_object(address_book_id, &name).await {
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(internal_error("Failed to delete object", e)),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let report_method = Method::from_bytes(b"REPORT").expect("valid method");
+    let propfind_method = Method::from_bytes(b"PROPFIND").expect("valid method");
+    cfg.service(
+        web::scope("/carddav/addressbooks")
+            .route(
+                "/{address_book_id}",
+                web::method(propfind_method.clone()).to(collection::propfind),
+            )
+            .route(
+                "/{address_book_id}",
+                web::method(report_method).to(report::report),
+            )
+            .route("/{address_book_id}/{object}", web::get().to(get_object))
+            .route("/{address_book_id}/{object}", web::put().to(put_object))
+            .route(
+                "/{address_book_id}/{object}",
+                web::delete().to(delete_object),
+            ),
+    );
+    cfg.route(
+        "/carddav/home/{user_id}",
+        web::method(propfind_method).to(collection::propfind_home),
+    );
+}
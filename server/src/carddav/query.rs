@@ -0,0 +1,52 @@
+//! addressbook-query filter evaluation (RFC 6352 §10.5)
+
+use crate::caldav::query::{parameter_matches, text_matches};
+use protocol::carddav::{AddressFilter, AddressPropertyFilter, FilterTest};
+use protocol::ical::Property;
+use protocol::vcard::VCard;
+
+fn combine(test: FilterTest, mut results: impl Iterator<Item = bool>) -> bool {
+    match test {
+        FilterTest::AnyOf => results.any(|matched| matched),
+        FilterTest::AllOf => results.all(|matched| matched),
+    }
+}
+
+/// Whether a vCard matches `filter`. A filter without property filters
+/// matches every card.
+pub fn matches(filter: &AddressFilter, card: &VCard) -> bool {
+    filter.property_filters.is_empty()
+        || combine(
+            filter.test,
+            filter
+                .property_filters
+                .iter()
+                .map(|f| property_filter_matches(card, f)),
+        )
+}
+
+fn property_filter_matches(card: &VCard, filter: &AddressPropertyFilter) -> bool {
+    let mut properties = card.properties_named(&filter.name).peekable();
+    if filter.is_not_defined {
+        return properties.peek().is_none();
+    }
+    properties.any(|property| property_matches(property, filter))
+}
+
+/// A property matches when its text and parameter tests pass, combined with
+/// the filter's `test`; a bare filter only checks the property exists.
+fn property_matches(property: &Property, filter: &AddressPropertyFilter) -> bool {
+    if filter.text_matches.is_empty() && filter.parameter_filters.is_empty() {
+        return true;
+    }
+    let value = property.text_value();
+    let texts = filter
+        .text_matches
+        .iter()
+        .map(|text_match| text_matches(text_match, &value));
+    let parameters = filter
+        .parameter_filters
+        .iter()
+        .map(|parameter_filter| parameter_matches(property, parameter_filter));
+    combine(filter.test, texts.chain(parameters))
+}
@@ -0,0 +1,397 @@
+//! CardDAV REPORTs: addressbook-query and addressbook-multiget (RFC 6352
+//! §8.6, §8.7) and sync-collection (RFC 6578)
+
+use super::collection::address_book_href;
+use super::query;
+use super::store::{AddressBookStore, AddressObject};
+use crate::auth::AuthenticatedUser;
+use crate::webdav::internal_error;
+use crate::webdav::report::{bad_request, parse_text_match, ReportError};
+use crate::webdav::sync::{self, Change, SyncCollection};
+use crate::webdav::xml::{self, Element, MultiStatus, PropName, PropValue, Response, CARDDAV, DAV};
+use crate::AppState;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, Result};
+use protocol::caldav::{CalendarAccess, ParameterFilter};
+use protocol::carddav::{AddressBookQuery, AddressFilter, AddressPropertyFilter, FilterTest};
+use protocol::vcard::{VCard, VCardVersion};
+use uuid::Uuid;
+
+/// Collation used when a text-match names none (RFC 6352 §8.3).
+const DEFAULT_COLLATION: &str = "i;unicode-casemap";
+
+/// How address data is returned (RFC 6352 §10.4).
+#[derive(Debug, Default)]
+pub struct AddressData {
+    /// Version to convert cards to; `None` returns them as stored.
+    pub version: Option<VCardVersion>,
+    /// Properties to return; `None` returns all of them.
+    pub properties: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum Report {
+    Query {
+        query: AddressBookQuery,
+        props: Vec<PropName>,
+        data: AddressData,
+    },
+    Multiget {
+        hrefs: Vec<String>,
+        props: Vec<PropName>,
+        data: AddressData,
+    },
+    Sync {
+        sync: SyncCollection,
+        data: AddressData,
+    },
+}
+
+fn parse_test(element: &Element) -> Result<FilterTest, ReportError> {
+    match element.attr("test").unwrap_or("anyof") {
+        "anyof" => Ok(FilterTest::AnyOf),
+        "allof" => Ok(FilterTest::AllOf),
+        _ => Err(bad_request("test must be anyof or allof")),
+    }
+}
+
+fn parse_prop_filter(element: &Element) -> Result<AddressPropertyFilter, ReportError> {
+    let name = element
+        .attr("name")
+        .ok_or_else(|| bad_request("prop-filter requires a name"))?;
+    let mut filter = AddressPropertyFilter {
+        name: name.to_ascii_uppercase(),
+        test: parse_test(element)?,
+        is_not_defined: false,
+        text_matches: Vec::new(),
+        parameter_filters: Vec::new(),
+    };
+    for child in &element.children {
+        if child.is(CARDDAV, "is-not-defined") {
+            filter.is_not_defined = true;
+        } else if child.is(CARDDAV, "text-match") {
+            filter
+                .text_matches
+                .push(parse_text_match(child, CARDDAV, Some(DEFAULT_COLLATION))?);
+        } else if child.is(CARDDAV, "param-filter") {
+            let name = child
+                .attr("name")
+                .ok_or_else(|| bad_request("param-filter requires a name"))?;
+            filter.parameter_filters.push(ParameterFilter {
+                name: name.to_ascii_uppercase(),
+                is_not_defined: child.child(CARDDAV, "is-not-defined").map(|_| true),
+                text_match: child
+                    .child(CARDDAV, "text-match")
+                    .map(|text_match| {
+                        parse_text_match(text_match, CARDDAV, Some(DEFAULT_COLLATION))
+                    })
+                    .transpose()?,
+            });
+        }
+    }
+    Ok(filter)
+}
+
+fn parse_filter(element: &Element) -> Result<AddressFilter, ReportError> {
+    Ok(AddressFilter {
+        test: parse_test(element)?,
+        property_filters: element
+            .children_named(CARDDAV, "prop-filter")
+            .map(parse_prop_filter)
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn parse_address_data(element: &Element) -> Result<AddressData, ReportError> {
+    let content_type = element.attr("content-type").unwrap_or("text/vcard");
+    if !content_type.eq_ignore_ascii_case("text/vcard") {
+        return Err(ReportError::precondition(CARDDAV, "supported-address-data"));
+    }
+    let version = match element.attr("version") {
+        Some(version) => Some(
+            VCardVersion::parse(version)
+                .ok_or(ReportError::precondition(CARDDAV, "supported-address-data"))?,
+        ),
+        None => None,
+    };
+    let names: Vec<String> = element
+        .children_named(CARDDAV, "prop")
+        .filter_map(|p| p.attr("name"))
+        .map(str::to_ascii_uppercase)
+        .collect();
+    let all = element.child(CARDDAV, "allprop").is_some() || names.is_empty();
+    Ok(AddressData {
+        version,
+        properties: (!all).then_some(names),
+    })
+}
+
+/// Requested properties and the address-data options among them.
+fn parse_props(root: &Element) -> Result<(Vec<PropName>, AddressData), ReportError> {
+    let Some(prop) = root.child(DAV, "prop") else {
+        // allprop (or no prop at all) returns the ETag and the data.
+        return Ok((
+            vec![
+                PropName::new(DAV, "getetag"),
+                PropName::new(CARDDAV, "address-data"),
+            ],
+            AddressData::default(),
+        ));
+    };
+    let data = match prop.child(CARDDAV, "address-data") {
+        Some(element) => parse_address_data(element)?,
+        None => AddressData::default(),
+    };
+    Ok((prop.children.iter().map(PropName::of).collect(), data))
+}
+
+fn parse_limit(root: &Element) -> Result<Option<usize>, ReportError> {
+    root.child(CARDDAV, "limit")
+        .and_then(|l| l.child(CARDDAV, "nresults"))
+        .map(|n| {
+            n.text
+                .trim()
+                .parse()
+                .map_err(|_| bad_request("invalid nresults"))
+        })
+        .transpose()
+}
+
+impl Report {
+    pub fn parse(address_book_id: Uuid, body: &[u8]) -> Result<Self, ReportError> {
+        let root = xml::parse(body)?;
+        let (props, data) = parse_props(&root)?;
+        if root.is(CARDDAV, "addressbook-query") {
+            let filter = match root.child(CARDDAV, "filter") {
+                Some(filter) => parse_filter(filter)?,
+                None => AddressFilter::default(),
+            };
+            let query = AddressBookQuery {
+                address_book_id,
+                filter,
+                limit: parse_limit(&root)?,
+                properties: props.iter().map(|p| p.name.clone()).collect(),
+            };
+            Ok(Report::Query { query, props, data })
+        } else if root.is(CARDDAV, "addressbook-multiget") {
+            let hrefs = root
+                .children_named(DAV, "href")
+                .map(|h| h.text.trim().to_string())
+                .collect();
+            Ok(Report::Multiget { hrefs, props, data })
+        } else if root.is(DAV, "sync-collection") {
+            let sync = SyncCollection::parse(&root).map_err(ReportError::Sync)?;
+            Ok(Report::Sync { sync, data })
+        } else {
+            Err(ReportError::UnsupportedReport)
+        }
+    }
+}
+
+pub fn object_href(address_book_id: Uuid, name: &str) -> String {
+    format!("/carddav/addressbooks/{}/{}", address_book_id, name)
+}
+
+fn address_data(object: &AddressObject, data: &AddressData) -> String {
+    if data.version.is_none() && data.properties.is_none() {
+        return object.data.clone();
+    }
+    let card = match VCard::parse(&object.data) {
+        Ok(card) => card,
+        Err(e) => {
+            tracing::warn!(
+                "Returning unparsable vCard {} as stored: {}",
+                object.name,
+                e
+            );
+            return object.data.clone();
+        }
+    };
+    let card = match data.version {
+        Some(version) => card.convert(version),
+        None => card,
+    };
+    match &data.properties {
+        Some(names) => card.select(names).to_vcf(),
+        None => card.to_vcf(),
+    }
+}
+
+pub fn object_response(object: &AddressObject, props: &[PropName], data: &AddressData) -> Response {
+    let mut response = Response::new(object_href(object.address_book_id, &object.name));
+    for prop in props {
+        if prop.is(DAV, "getetag") {
+            response.prop(
+                prop.clone(),
+                PropValue::Text(format!("\"{}\"", object.etag)),
+            );
+        } else if prop.is(DAV, "getcontenttype") {
+            response.prop(
+                prop.clone(),
+                PropValue::Text("text/vcard; charset=utf-8".to_string()),
+            );
+        } else if prop.is(DAV, "displayname") {
+            response.prop(prop.clone(), PropValue::Text(object.formatted_name.clone()));
+        } else if prop.is(CARDDAV, "address-data") {
+            response.prop(prop.clone(), PropValue::Text(address_data(object, data)));
+        } else {
+            response.not_found(prop.clone());
+        }
+    }
+    response
+}
+
+async fn address_book_query(
+    store: &AddressBookStore,
+    query: AddressBookQuery,
+    props: &[PropName],
+    data: &AddressData,
+) -> HttpResponse {
+    let objects = match store.list_objects(query.address_book_id).await {
+        Ok(objects) => objects,
+        Err(e) => return internal_error("Failed to load objects", e),
+    };
+    let mut multistatus = MultiStatus::new();
+    let mut matched = 0;
+    for object in &objects {
+        let card = match VCard::parse(&object.data) {
+            Ok(card) => card,
+            Err(e) => {
+                tracing::warn!("Skipping unparsable vCard {}: {}", object.name, e);
+                continue;
+            }
+        };
+        if !query::matches(&query.filter, &card) {
+            continue;
+        }
+        if query.limit.is_some_and(|limit| matched >= limit) {
+            // More results than the client asked for (RFC 6352 §8.6.1).
+            multistatus.push(Response::status(
+                address_book_href(query.address_book_id),
+                StatusCode::INSUFFICIENT_STORAGE,
+            ));
+            break;
+        }
+        matched += 1;
+        multistatus.push(object_response(object, props, data));
+    }
+    multistatus.into_response()
+}
+
+async fn multiget(
+    store: &AddressBookStore,
+    address_book_id: Uuid,
+    hrefs: Vec<String>,
+    props: &[PropName],
+    data: &AddressData,
+) -> HttpResponse {
+    let mut multistatus = MultiStatus::new();
+    let prefix = object_href(address_book_id, "");
+    let mut names = Vec::new();
+    for href in hrefs {
+        match href
+            .strip_prefix(&prefix)
+            .filter(|n| !n.is_empty() && !n.contains('/'))
+        {
+            Some(name) => names.push(name.to_string()),
+            None => multistatus.push(Response::status(href, StatusCode::NOT_FOUND)),
+        }
+    }
+    let objects = match store.get_objects(address_book_id, &names).await {
+        Ok(objects) => objects,
+        Err(e) => return internal_error("Failed to load objects", e),
+    };
+    for name in &names {
+        match objects.iter().find(|o| &o.name == name) {
+            Some(object) => multistatus.push(object_response(object, props, data)),
+            None => multistatus.push(Response::status(
+                object_href(address_book_id, name),
+                StatusCode::NOT_FOUND,
+            )),
+        }
+    }
+    multistatus.into_response()
+}
+
+async fn sync_collection(
+    store: &AddressBookStore,
+    address_book_id: Uuid,
+    sync: SyncCollection,
+    data: &AddressData,
+) -> HttpResponse {
+    let current = match store.sync_token(address_book_id).await {
+        Ok(current) => current,
+        Err(e) => return internal_error("Failed to load sync token", e),
+    };
+    if let Err(e) = sync.check(current) {
+        return e.into_response();
+    }
+    let (objects, tombstones) = match store
+        .changes_since(address_book_id, sync.revision.unwrap_or(0))
+        .await
+    {
+        Ok(changes) => changes,
+        Err(e) => return internal_error("Failed to load changes", e),
+    };
+
+    let mut changes: Vec<(i64, Change)> = objects
+        .iter()
+        .map(|object| {
+            (
+                object.revision,
+                Change::Updated(object_response(object, &sync.props, data)),
+            )
+        })
+        .collect();
+    // An initial sync only lists the current members.
+    if sync.revision.is_some() {
+        changes.extend(tombstones.into_iter().map(|tombstone| {
+            (
+                tombstone.revision,
+                Change::Deleted(object_href(address_book_id, &tombstone.name)),
+            )
+        }));
+    }
+    sync::multistatus(
+        &address_book_href(address_book_id),
+        changes,
+        sync.limit,
+        current,
+    )
+    .into_response()
+}
+
+pub async fn report(
+    path: web::Path<Uuid>,
+    user: Option<AuthenticatedUser>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let address_book_id = path.into_inner();
+    let store = AddressBookStore::new(&data.db_pool);
+
+    if let Err(response) = super::authorize(
+        &store,
+        address_book_id,
+        user.map(|u| u.user_id),
+        CalendarAccess::Read,
+    )
+    .await
+    {
+        return Ok(response);
+    }
+
+    let report = match Report::parse(address_book_id, &body) {
+        Ok(report) => report,
+        Err(e) => return Ok(e.into_response()),
+    };
+    Ok(match report {
+        Report::Query { query, props, data } => {
+            address_book_query(&store, query, &props, &data).await
+        }
+        Report::Multiget { hrefs, props, data } => {
+            multiget(&store, address_book_id, hrefs, &props, &data).await
+        }
+        Report::Sync { sync, data } => sync_collection(&store, address_book_id, sync, &data).await,
+    })
+}
@@ -0,0 +1,329 @@
+//! Address book and address object persistence
+
+use crate::config::database::DatabasePool;
+use crate::webdav::etag;
+use chrono::{DateTime, Utc};
+use protocol::caldav::CalendarPermissions;
+use protocol::carddav::AddressBook;
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// A stored address object resource: one vCard (RFC 6352 §5.1).
+#[derive(Debug, Clone, FromRow)]
+pub struct AddressObject {
+    pub id: Uuid,
+    pub address_book_id: Uuid,
+    pub name: String,
+    pub uid: String,
+    pub etag: String,
+    pub data: String,
+    pub formatted_name: String,
+    /// Address book revision of the last write.
+    pub revision: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An object deleted at `revision`.
+#[derive(Debug, Clone, FromRow)]
+pub struct Tombstone {
+    pub name: String,
+    pub revision: i64,
+}
+
+#[derive(FromRow)]
+struct AddressBookRow {
+    id: Uuid,
+    owner_id: Uuid,
+    name: String,
+    display_name: String,
+    description: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    is_shared: bool,
+    permissions: Json<CalendarPermissions>,
+}
+
+impl From<AddressBookRow> for AddressBook {
+    fn from(row: AddressBookRow) -> Self {
+        AddressBook {
+            id: row.id,
+            owner_id: row.owner_id,
+            name: row.name,
+            display_name: row.display_name,
+            description: row.description,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            is_shared: row.is_shared,
+            permissions: row.permissions.0,
+        }
+    }
+}
+
+const ADDRESS_BOOK_COLUMNS: &str = "id, owner_id, name, display_name, description,
+     created_at, updated_at, is_shared, permissions";
+
+pub struct AddressBookStore {
+    pool: PgPool,
+}
+
+impl AddressBookStore {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    pub async fn get_address_book(&self, id: Uuid) -> Result<Option<AddressBook>, sqlx::Error> {
+        let row = sqlx::query_as::<_, AddressBookRow>(&format!(
+            "SELECT {} FROM address_books WHERE id = $1",
+            ADDRESS_BOOK_COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(AddressBook::from))
+    }
+
+    /// Address books owned by `user_id` or shared with them.
+    pub async fn list_address_books(&self, user_id: Uuid) -> Result<Vec<AddressBook>, sqlx::Error> {
+        let user = serde_json::Value::String(user_id.to_string());
+        let rows = sqlx::query_as::<_, AddressBookRow>(&format!(
+            "SELECT {} FROM address_books
+             WHERE owner_id = $1
+                OR permissions -> 'read_users' @> $2
+                OR permissions -> 'write_users' @> $2
+                OR permissions -> 'admin_users' @> $2
+             ORDER BY owner_id = $1 DESC, name",
+            ADDRESS_BOOK_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(Json(serde_json::Value::Array(vec![user])))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(AddressBook::from).collect())
+    }
+
+    pub async fn create_address_book(
+        &self,
+        owner_id: Uuid,
+        name: &str,
+        display_name: &str,
+        description: Option<&str>,
+    ) -> Result<AddressBook, sqlx::Error> {
+        let row = sqlx::query_as::<_, AddressBookRow>(&format!(
+            "INSERT INTO address_books (id, owner_id, name, display_name, description)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING {}",
+            ADDRESS_BOOK_COLUMNS
+        ))
+        .bind(Uuid::new_v4())
+        .bind(owner_id)
+        .bind(name)
+        .bind(display_name)
+        .bind(description)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.into())
+    }
+
+    /// Replace an address book's permissions, updating `is_shared` to match.
+    pub async fn update_permissions(
+        &self,
+        address_book_id: Uuid,
+        permissions: &CalendarPermissions,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE address_books SET permissions = $2, is_shared = $3, updated_at = now()
+             WHERE id = $1",
+        )
+        .bind(address_book_id)
+        .bind(Json(permissions))
+        .bind(permissions.is_shared())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Current revision of an address book, behind its sync token.
+    pub async fn sync_token(&self, address_book_id: Uuid) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT sync_token FROM address_books WHERE id = $1")
+            .bind(address_book_id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn list_objects(
+        &self,
+        address_book_id: Uuid,
+    ) -> Result<Vec<AddressObject>, sqlx::Error> {
+        sqlx::query_as::<_, AddressObject>(
+            "SELECT * FROM address_book_objects WHERE address_book_id = $1 ORDER BY name",
+        )
+        .bind(address_book_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_objects(
+        &self,
+        address_book_id: Uuid,
+        names: &[String],
+    ) -> Result<Vec<AddressObject>, sqlx::Error> {
+        sqlx::query_as::<_, AddressObject>(
+            "SELECT * FROM address_book_objects WHERE address_book_id = $1 AND name = ANY($2)",
+        )
+        .bind(address_book_id)
+        .bind(names)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn get_object(
+        &self,
+        address_book_id: Uuid,
+        name: &str,
+    ) -> Result<Option<AddressObject>, sqlx::Error> {
+        sqlx::query_as::<_, AddressObject>(
+            "SELECT * FROM address_book_objects WHERE address_book_id = $1 AND name = $2",
+        )
+        .bind(address_book_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    pub async fn find_by_uid(
+        &self,
+        address_book_id: Uuid,
+        uid: &str,
+    ) -> Result<Option<AddressObject>, sqlx::Error> {
+        sqlx::query_as::<_, AddressObject>(
+            "SELECT * FROM address_book_objects WHERE address_book_id = $1 AND uid = $2",
+        )
+        .bind(address_book_id)
+        .bind(uid)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Objects and tombstones changed after `revision`.
+    pub async fn changes_since(
+        &self,
+        address_book_id: Uuid,
+        revision: i64,
+    ) -> Result<(Vec<AddressObject>, Vec<Tombstone>), sqlx::Error> {
+        let objects = sqlx::query_as::<_, AddressObject>(
+            "SELECT * FROM address_book_objects
+             WHERE address_book_id = $1 AND revision > $2
+             ORDER BY revision",
+        )
+        .bind(address_book_id)
+        .bind(revision)
+        .fetch_all(&self.pool)
+        .await?;
+        let tombstones = sqlx::query_as::<_, Tombstone>(
+            "SELECT name, revision FROM address_book_tombstones
+             WHERE address_book_id = $1 AND revision > $2
+             ORDER BY revision",
+        )
+        .bind(address_book_id)
+        .bind(revision)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok((objects, tombstones))
+    }
+
+    /// Create or replace an object at the address book's next revision.
+    pub async fn put_object(
+        &self,
+        address_book_id: Uuid,
+        name: &str,
+        uid: &str,
+        formatted_name: &str,
+        data: &str,
+    ) -> Result<AddressObject, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let revision = Self::bump(&mut tx, address_book_id).await?;
+        let object = sqlx::query_as::<_, AddressObject>(
+            "INSERT INTO address_book_objects
+                 (id, address_book_id, name, uid, etag, data, formatted_name, revision)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (address_book_id, name) DO UPDATE SET
+                 uid = EXCLUDED.uid,
+                 etag = EXCLUDED.etag,
+                 data = EXCLUDED.data,
+                 formatted_name = EXCLUDED.formatted_name,
+                 revision = EXCLUDED.revision,
+                 updated_at = now()
+             RETURNING *",
+        )
+        .bind(Uuid::new_v4())
+        .bind(address_book_id)
+        .bind(name)
+        .bind(uid)
+        .bind(etag(data))
+        .bind(data)
+        .bind(formatted_name)
+        .bind(revision)
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM address_book_tombstones WHERE address_book_id = $1 AND name = $2")
+            .bind(address_book_id)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(object)
+    }
+
+    /// Delete an object, leaving a tombstone; returns whether it existed.
+    pub async fn delete_object(
+        &self,
+        address_book_id: Uuid,
+        name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            "DELETE FROM address_book_objects WHERE address_book_id = $1 AND name = $2",
+        )
+        .bind(address_book_id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if deleted {
+            let revision = Self::bump(&mut tx, address_book_id).await?;
+            sqlx::query(
+                "INSERT INTO address_book_tombstones (address_book_id, name, revision)
+                 VALUES ($1, $2, $3)
+                 ON CONFLICT (address_book_id, name) DO UPDATE SET revision = EXCLUDED.revision",
+            )
+            .bind(address_book_id)
+            .bind(name)
+            .bind(revision)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Advance the address book's revision, returning the new one. The row
+    /// lock serializes concurrent writers so revisions stay ordered.
+    async fn bump(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        address_book_id: Uuid,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "UPDATE address_books SET sync_token = sync_token + 1, updated_at = now()
+             WHERE id = $1
+             RETURNING sync_token",
+        )
+        .bind(address_book_id)
+        .fetch_one(&mut **tx)
+        .await
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use crate::config::ConfigError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    /// Lifetime of issued tokens, in seconds.
+    pub jwt_expiration: u64,
+    /// Lifetime of tokens issued to registered devices, in seconds.
+    #[serde(default = "default_device_token_expiration")]
+    pub device_token_expiration: u64,
+    pub session_timeout: u64,
+    pub enable_mfa: bool,
+    #[serde(default)]
+    pub password_policy: PasswordPolicy,
+}
+
+fn default_device_token_expiration() -> u64 {
+    365 * 86400
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_uppercase: bool,
+    pub require_lowercase: bool,
+    pub require_numbers: bool,
+    pub require_special: bool,
+}
+
+impl AuthConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.jwt_secret.len() < 32 {
+            return Err(ConfigError::InvalidValue("jwt_secret must be at least 32 characters".to_string()));
+        }
+        if self.jwt_expiration == 0 {
+            return Err(ConfigError::InvalidValue("jwt_expiration must be greater than 0".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: "change-this-development-secret-before-deploying".to_string(),
+            jwt_expiration: 86400, // 24 hours
+            device_token_expiration: default_device_token_expiration(),
+            session_timeout: 3600, // 1 hour
+            enable_mfa: false,
+            password_policy: PasswordPolicy::default(),
+        }
+    }
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            require_uppercase: true,
+            require_lowercase: true,
+            require_numbers: true,
+            require_special: false,
+        }
+    }
+}
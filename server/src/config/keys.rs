@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+/// Name of the built-in key management plugin running `keys.command`.
+pub const COMMAND_PLUGIN: &str = "command";
+
+/// Where a master key comes from. Master keys only wrap the data keys kept
+/// in the database; the id of the one that wrapped each data key is
+/// recorded with it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub enum MasterKeySource {
+    /// 32 bytes, raw or hex encoded, read from a file.
+    File { id: String, path: String },
+    /// 32 hex encoded bytes in an environment variable.
+    Env { id: String, var: String },
+    /// A key held by a key management plugin, which wraps and unwraps data
+    /// keys without handing it out. The built-in plugin `command` runs the
+    /// program set up in `keys.command`.
+    Plugin {
+        id: String,
+        plugin: String,
+        key_id: String,
+    },
+}
+
+impl MasterKeySource {
+    pub fn id(&self) -> &str {
+        match self {
+            MasterKeySource::File { id, .. }
+            | MasterKeySource::Env { id, .. }
+            | MasterKeySource::Plugin { id, .. } => id,
+        }
+    }
+}
+
+/// External program the `command` key management plugin runs to wrap and
+/// unwrap data keys, see [`crate::plugins::kms_command`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyCommandConfig {
+    pub program: String,
+    /// Passed before the operation and the key id.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeysConfig {
+    /// Without a master key there is no key store.
+    pub master_key: Option<MasterKeySource>,
+    /// Earlier master keys. Data keys wrapped with them are re-wrapped with
+    /// the current one when the server starts.
+    #[serde(default)]
+    pub retired_master_keys: Vec<MasterKeySource>,
+    #[serde(default)]
+    pub command: Option<KeyCommandConfig>,
+}
+
+impl KeysConfig {
+    pub fn validate(&self) -> Result<(), super::ConfigError> {
+        if self.master_key.is_none() && !self.retired_master_keys.is_empty() {
+            return Err(super::ConfigError::MissingRequired(
+                "keys.master_key".to_string(),
+            ));
+        }
+        let mut ids = Vec::new();
+        for source in self.master_key.iter().chain(&self.retired_master_keys) {
+            if source.id().is_empty() {
+                return Err(super::ConfigError::InvalidValue(
+                    "master key ids must not be empty".to_string(),
+                ));
+            }
+            if ids.contains(&source.id()) {
+                return Err(super::ConfigError::InvalidValue(format!(
+                    "master key id {} is used twice",
+                    source.id()
+                )));
+            }
+            ids.push(source.id());
+            let needs_command = matches!(
+                source,
+                MasterKeySource::Plugin { plugin, .. } if plugin == COMMAND_PLUGIN
+            );
+            if needs_command && self.command.is_none() {
+                return Err(super::ConfigError::MissingRequired(
+                    "keys.command".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
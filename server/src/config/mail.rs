@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+/// How outgoing mail (iMIP invitations) is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    /// Write messages to a local maildir, for testing.
+    Maildir,
+}
+
+impl MailTransport {
+    /// Name of the mail plugin implementing this transport.
+    pub fn plugin_name(&self) -> &'static str {
+        match self {
+            MailTransport::Smtp => "smtp",
+            MailTransport::Maildir => "maildir",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub starttls: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailConfig {
+    pub enabled: bool,
+    pub transport: MailTransport,
+    /// Sender address of outgoing mail.
+    pub from: String,
+    pub smtp: Option<SmtpConfig>,
+    pub maildir_path: Option<String>,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            transport: MailTransport::Maildir,
+            from: "calendar@localhost".to_string(),
+            smtp: None,
+            maildir_path: Some("./data/maildir".to_string()),
+        }
+    }
+}
+
+impl MailConfig {
+    pub fn validate(&self) -> Result<(), super::ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if !self.from.contains('@') {
+            return Err(super::ConfigError::InvalidValue(
+                "mail.from must be an email address".to_string(),
+            ));
+        }
+        match self.transport {
+            MailTransport::Smtp if self.smtp.is_none() => {
+                Err(super::ConfigError::MissingRequired("mail.smtp".to_string()))
+            }
+            MailTransport::Maildir if self.maildir_path.is_none() => Err(
+                super::ConfigError::MissingRequired("mail.maildir_path".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
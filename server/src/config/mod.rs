@@ -3,6 +3,8 @@ pub mod database;
 pub mod storage;
 pub mod auth;
 pub mod monitoring;
+pub mod mail;
+pub mod keys;
 
 use serde::{Deserialize, Serialize};
 use std::path::Path;
@@ -14,6 +16,10 @@ pub struct AppConfig {
     pub storage: storage::StorageConfig,
     pub auth: auth::AuthConfig,
     pub monitoring: monitoring::MonitoringConfig,
+    #[serde(default)]
+    pub mail: mail::MailConfig,
+    #[serde(default)]
+    pub keys: keys::KeysConfig,
 }
 
 impl AppConfig {
@@ -34,6 +40,14 @@ impl AppConfig {
         self.storage.validate()?;
         self.auth.validate()?;
         self.monitoring.validate()?;
+        self.mail.validate()?;
+        self.keys.validate()?;
+        let encryption = &self.storage.encryption;
+        if encryption.enabled && encryption.master_key.is_none() && self.keys.master_key.is_none() {
+            return Err(ConfigError::MissingRequired(
+                "storage.encryption.master_key or keys.master_key".to_string()
+            ));
+        }
         Ok(())
     }
     
@@ -74,6 +88,8 @@ impl Default for AppConfig {
             storage: storage::StorageConfig::default(),
             auth: auth::AuthConfig::default(),
             monitoring: monitoring::MonitoringConfig::default(),
+            mail: mail::MailConfig::default(),
+            keys: keys::KeysConfig::default(),
         }
     }
 }
@@ -1,10 +1,45 @@
 use serde::{Deserialize, Serialize};
 
+/// A master key that no longer encrypts new contents but still opens the
+/// file keys wrapped with it, until they are re-wrapped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetiredKey {
+    pub id: u32,
+    /// 32 bytes, hex encoded.
+    pub key: String,
+}
+
+/// Encryption of stored contents at rest, with per-file keys derived from
+/// a master key.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StorageEncryptionConfig {
+    pub enabled: bool,
+    /// 32 bytes, hex encoded. When absent, the `storage` data key of the
+    /// key store is used instead, and rotating it re-wraps the file keys.
+    pub master_key: Option<String>,
+    /// Recorded with every file key the master key wraps; a new master key
+    /// needs a new id.
+    #[serde(default)]
+    pub key_id: u32,
+    /// Earlier master keys. File keys wrapped with them are re-wrapped with
+    /// the current one when the server starts.
+    #[serde(default)]
+    pub retired_keys: Vec<RetiredKey>,
+    /// Read stored objects that are not encrypted as they are. Only meant
+    /// for migrating contents stored before encryption was enabled: anyone
+    /// able to write to the storage could otherwise swap in contents of
+    /// their own.
+    #[serde(default)]
+    pub allow_plaintext: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub local_path: Option<String>,
     pub max_file_size: u64,
     pub allowed_extensions: Vec<String>,
+    #[serde(default)]
+    pub encryption: StorageEncryptionConfig,
 }
 
 impl Default for StorageConfig {
@@ -18,6 +53,7 @@ impl Default for StorageConfig {
                 "jpg".to_string(),
                 "png".to_string(),
             ],
+            encryption: StorageEncryptionConfig::default(),
         }
     }
 }
@@ -29,7 +65,7 @@ impl StorageConfig {
                 "max_file_size must be greater than 0".to_string()
             ));
         }
-        Ok(())
+        self.encryption.validate()
     }
 
     pub fn from_env() -> Result<Self, super::ConfigError> {
@@ -45,6 +81,40 @@ impl StorageConfig {
             })?;
         }
 
+        if let Ok(key) = std::env::var("STORAGE_ENCRYPTION_KEY") {
+            config.encryption.enabled = true;
+            config.encryption.master_key = Some(key);
+        }
+
+        if let Ok(id) = std::env::var("STORAGE_ENCRYPTION_KEY_ID") {
+            config.encryption.key_id = id.parse().map_err(|_| {
+                super::ConfigError::InvalidValue("STORAGE_ENCRYPTION_KEY_ID must be a valid number".to_string())
+            })?;
+        }
+
+        if let Ok(allow) = std::env::var("STORAGE_ENCRYPTION_ALLOW_PLAINTEXT") {
+            config.encryption.allow_plaintext = allow.parse().map_err(|_| {
+                super::ConfigError::InvalidValue("STORAGE_ENCRYPTION_ALLOW_PLAINTEXT must be true or false".to_string())
+            })?;
+        }
+
+        // Comma separated `id:key` pairs
+        if let Ok(keys) = std::env::var("STORAGE_ENCRYPTION_RETIRED_KEYS") {
+            config.encryption.retired_keys = keys
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| {
+                    let (id, key) = entry.trim().split_once(':').ok_or_else(|| {
+                        super::ConfigError::InvalidValue("STORAGE_ENCRYPTION_RETIRED_KEYS entries must be id:key".to_string())
+                    })?;
+                    let id = id.parse().map_err(|_| {
+                        super::ConfigError::InvalidValue("STORAGE_ENCRYPTION_RETIRED_KEYS ids must be valid numbers".to_string())
+                    })?;
+                    Ok(RetiredKey { id, key: key.to_string() })
+                })
+                .collect::<Result<_, super::ConfigError>>()?;
+        }
+
         if let Ok(extensions) = std::env::var("STORAGE_ALLOWED_EXTENSIONS") {
             config.allowed_extensions = extensions.split(',')
                 .map(|s| s.trim().to_string())
@@ -55,3 +125,71 @@ impl StorageConfig {
             Ok(config)
         }
     }
+
+impl StorageEncryptionConfig {
+    pub fn validate(&self) -> Result<(), super::ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let Some(master_key) = self.master_key.as_deref() else {
+            return Ok(());
+        };
+        if parse_key(master_key).is_none() {
+            return Err(super::ConfigError::InvalidValue(
+                "storage.encryption.master_key must be 32 hex encoded bytes".to_string(),
+            ));
+        }
+        for retired in &self.retired_keys {
+            if retired.id == self.key_id {
+                return Err(super::ConfigError::InvalidValue(format!(
+                    "storage.encryption key id {} is both current and retired",
+                    retired.id
+                )));
+            }
+            if parse_key(&retired.key).is_none() {
+                return Err(super::ConfigError::InvalidValue(format!(
+                    "retired storage key {} must be 32 hex encoded bytes",
+                    retired.id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A 32-byte key from its hex encoding.
+pub fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    // from_str_radix would take a sign as well
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_takes_64_hex_digits() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F";
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+        assert_eq!(parse_key(hex), Some(key));
+        assert_eq!(parse_key(&format!(" {}\n", hex)), Some(key));
+    }
+
+    #[test]
+    fn test_parse_key_rejects_other_input() {
+        assert_eq!(parse_key(""), None);
+        assert_eq!(parse_key(&"0".repeat(62)), None);
+        assert_eq!(parse_key(&"0".repeat(66)), None);
+        assert_eq!(parse_key(&format!("{}zz", "0".repeat(62))), None);
+        assert_eq!(parse_key(&format!("+f{}", "0".repeat(62))), None);
+        assert_eq!(parse_key(&format!("é{}", "0".repeat(62))), None);
+    }
+}
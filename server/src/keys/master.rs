@@ -0,0 +1,250 @@
+//! Master keys
+//!
+//! A master key only wraps and unwraps data keys. Keys read from a file or
+//! an environment variable are held in memory; a key management plugin
+//! keeps its keys to itself and is asked to do the wrapping.
+
+use super::KeyError;
+use crate::config::keys::MasterKeySource;
+use crate::config::storage::parse_key;
+use crate::plugins::manager::PluginManager;
+use crate::plugins::KeyManagementPlugin;
+use async_trait::async_trait;
+use crypto::EncryptionKey;
+use std::sync::Arc;
+
+#[async_trait]
+pub trait MasterKey: Send + Sync {
+    /// Recorded with every data key this wraps.
+    fn id(&self) -> &str;
+
+    async fn wrap(&self, key: &EncryptionKey) -> Result<Vec<u8>, KeyError>;
+
+    async fn unwrap(&self, wrapped: &[u8]) -> Result<EncryptionKey, KeyError>;
+}
+
+pub struct LocalMasterKey {
+    id: String,
+    key: EncryptionKey,
+}
+
+impl LocalMasterKey {
+    pub fn new(id: &str, key: [u8; 32]) -> Self {
+        Self {
+            id: id.to_string(),
+            key: EncryptionKey::new(key),
+        }
+    }
+}
+
+#[async_trait]
+impl MasterKey for LocalMasterKey {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn wrap(&self, key: &EncryptionKey) -> Result<Vec<u8>, KeyError> {
+        Ok(crypto::wrap_key(&self.key, key)?)
+    }
+
+    async fn unwrap(&self, wrapped: &[u8]) -> Result<EncryptionKey, KeyError> {
+        Ok(crypto::unwrap_key(&self.key, wrapped)?)
+    }
+}
+
+pub struct PluginMasterKey {
+    id: String,
+    plugin: Arc<dyn KeyManagementPlugin>,
+    key_id: String,
+}
+
+impl PluginMasterKey {
+    pub fn new(id: &str, plugin: Arc<dyn KeyManagementPlugin>, key_id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            plugin,
+            key_id: key_id.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl MasterKey for PluginMasterKey {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn wrap(&self, key: &EncryptionKey) -> Result<Vec<u8>, KeyError> {
+        self.plugin
+            .wrap_key(&self.key_id, key.as_bytes())
+            .await
+            .map_err(KeyError::Plugin)
+    }
+
+    async fn unwrap(&self, wrapped: &[u8]) -> Result<EncryptionKey, KeyError> {
+        let key = zeroize::Zeroizing::new(
+            self.plugin
+                .unwrap_key(&self.key_id, wrapped)
+                .await
+                .map_err(KeyError::Plugin)?,
+        );
+        Ok(EncryptionKey::from_slice(&key)?)
+    }
+}
+
+/// A key file holds the 32 bytes themselves or their hex encoding.
+fn key_from_file(contents: &[u8]) -> Option<[u8; 32]> {
+    if let Ok(key) = contents.try_into() {
+        return Some(key);
+    }
+    parse_key(std::str::from_utf8(contents).ok()?)
+}
+
+/// The master key `source` describes.
+pub async fn load(
+    source: &MasterKeySource,
+    plugins: &PluginManager,
+) -> Result<Arc<dyn MasterKey>, KeyError> {
+    let invalid = |reason: String| KeyError::InvalidMasterKey(source.id().to_string(), reason);
+    match source {
+        MasterKeySource::File { id, path } => {
+            let contents = zeroize::Zeroizing::new(
+                tokio::fs::read(path)
+                    .await
+                    .map_err(|e| invalid(format!("{}: {}", path, e)))?,
+            );
+            let key = key_from_file(&contents)
+                .ok_or_else(|| invalid(format!("{} does not hold a 32-byte key", path)))?;
+            Ok(Arc::new(LocalMasterKey::new(id, key)))
+        }
+        MasterKeySource::Env { id, var } => {
+            let value = zeroize::Zeroizing::new(
+                std::env::var(var).map_err(|_| invalid(format!("{} is not set", var)))?,
+            );
+            let key = parse_key(&value)
+                .ok_or_else(|| invalid(format!("{} does not hold a 32-byte hex key", var)))?;
+            Ok(Arc::new(LocalMasterKey::new(id, key)))
+        }
+        MasterKeySource::Plugin { id, plugin, key_id } => {
+            let plugin = plugins
+                .key_management_plugin(plugin)
+                .ok_or_else(|| invalid(format!("no key management plugin {}", plugin)))?;
+            Ok(Arc::new(PluginMasterKey::new(id, plugin, key_id)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::keys::KeyCommandConfig;
+    use crate::plugins::kms_command::CommandKeyManagementPlugin;
+    use std::io::Write;
+
+    const HEX_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key() -> [u8; 32] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    async fn round_trip(master: &dyn MasterKey) {
+        let data_key = EncryptionKey::generate();
+        let wrapped = master.wrap(&data_key).await.unwrap();
+        let unwrapped = master.unwrap(&wrapped).await.unwrap();
+        assert_eq!(unwrapped.as_bytes(), data_key.as_bytes());
+    }
+
+    #[test]
+    fn test_key_file_holds_raw_or_hex_bytes() {
+        assert_eq!(key_from_file(&key()), Some(key()));
+        assert_eq!(key_from_file(HEX_KEY.as_bytes()), Some(key()));
+        assert_eq!(
+            key_from_file(format!("{}\n", HEX_KEY).as_bytes()),
+            Some(key())
+        );
+        assert_eq!(key_from_file(&[0; 31]), None);
+        assert_eq!(key_from_file(&HEX_KEY.as_bytes()[..62]), None);
+        assert_eq!(key_from_file(&[0xff; 64]), None);
+    }
+
+    #[tokio::test]
+    async fn test_load_reads_a_key_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{}", HEX_KEY).unwrap();
+        let source = MasterKeySource::File {
+            id: "file".to_string(),
+            path: file.path().to_string_lossy().into_owned(),
+        };
+        let master = load(&source, &PluginManager::new()).await.unwrap();
+        assert_eq!(master.id(), "file");
+        let wrapped = master.wrap(&EncryptionKey::new(key())).await.unwrap();
+        let local = LocalMasterKey::new("local", key());
+        assert_eq!(local.unwrap(&wrapped).await.unwrap().as_bytes(), &key());
+    }
+
+    #[tokio::test]
+    async fn test_load_rejects_a_missing_or_short_key_file() {
+        let short = tempfile::NamedTempFile::new().unwrap();
+        for path in [
+            short.path().to_string_lossy().into_owned(),
+            "/nonexistent/master.key".to_string(),
+        ] {
+            let source = MasterKeySource::File {
+                id: "file".to_string(),
+                path,
+            };
+            let error = load(&source, &PluginManager::new()).await.err().unwrap();
+            assert!(matches!(error, KeyError::InvalidMasterKey(id, _) if id == "file"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_reads_an_environment_variable() {
+        let var = "ROUILLECLOUD_TEST_MASTER_KEY";
+        std::env::set_var(var, HEX_KEY);
+        let source = MasterKeySource::Env {
+            id: "env".to_string(),
+            var: var.to_string(),
+        };
+        let master = load(&source, &PluginManager::new()).await.unwrap();
+        assert_eq!(master.id(), "env");
+        round_trip(master.as_ref()).await;
+
+        let unset = MasterKeySource::Env {
+            id: "env".to_string(),
+            var: "ROUILLECLOUD_TEST_UNSET_MASTER_KEY".to_string(),
+        };
+        assert!(load(&unset, &PluginManager::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_asks_a_registered_plugin() {
+        let source = MasterKeySource::Plugin {
+            id: "kms".to_string(),
+            plugin: "command".to_string(),
+            key_id: "main".to_string(),
+        };
+        let plugins = PluginManager::new();
+        let error = load(&source, &plugins).await.err().unwrap();
+        assert!(matches!(error, KeyError::InvalidMasterKey(id, _) if id == "kms"));
+
+        // Wraps by prefixing the key id, and unwraps by dropping it.
+        plugins.register_key_management_plugin(Arc::new(CommandKeyManagementPlugin::new(
+            &KeyCommandConfig {
+                program: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    r#"case "$1" in
+                         wrap) printf '%s:' "$2"; cat ;;
+                         unwrap) dd bs=1 skip=$((${#2} + 1)) 2>/dev/null ;;
+                       esac"#
+                        .to_string(),
+                    "kms".to_string(),
+                ],
+            },
+        )));
+        let master = load(&source, &plugins).await.unwrap();
+        assert_eq!(master.id(), "kms");
+        round_trip(master.as_ref()).await;
+    }
+}
@@ -0,0 +1,335 @@
+//! Key management
+//!
+//! Data-encryption keys are random keys stored in the database, wrapped by
+//! the master key (see [`master`]), under a name and a version. Rotating a
+//! data key adds a version: the newest one encrypts, the older ones are
+//! kept to decrypt what they encrypted. Rotating the master key only
+//! re-wraps the stored data keys, which the server does on start for those
+//! a retired master key wrapped.
+
+pub mod master;
+
+pub use master::{LocalMasterKey, MasterKey, PluginMasterKey};
+
+use crate::config::database::DatabasePool;
+use crate::config::keys::KeysConfig;
+use crate::plugins::kms_command::CommandKeyManagementPlugin;
+use crate::plugins::manager::PluginManager;
+use chrono::{DateTime, Utc};
+use crypto::EncryptionKey;
+use sqlx::{FromRow, PgConnection, PgPool};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Data key of the storage encryption (see [`crate::storage::encrypted`]).
+pub const STORAGE_DATA_KEY: &str = "storage";
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Crypto error: {0}")]
+    Crypto(#[from] crypto::CryptoError),
+    #[error("Key management plugin error: {0}")]
+    Plugin(String),
+    #[error("Invalid master key {0}: {1}")]
+    InvalidMasterKey(String, String),
+    #[error("Data key {0} was wrapped by unknown master key {1}")]
+    UnknownMasterKey(String, String),
+    #[error("No version {1} of data key {0}")]
+    NotFound(String, i32),
+}
+
+/// A version of a data key.
+pub struct DataKey {
+    pub name: String,
+    pub version: i32,
+    pub key: EncryptionKey,
+}
+
+/// What is known about a data key version without unwrapping it.
+#[derive(Debug, Clone, FromRow)]
+pub struct DataKeyInfo {
+    pub name: String,
+    pub version: i32,
+    pub master_key_id: String,
+    pub created_at: DateTime<Utc>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromRow)]
+struct DataKeyRow {
+    name: String,
+    version: i32,
+    wrapped_key: Vec<u8>,
+    master_key_id: String,
+}
+
+/// Register the key management plugins `config` sets up.
+pub fn register_plugins(plugins: &PluginManager, config: &KeysConfig) {
+    if let Some(command) = &config.command {
+        plugins.register_key_management_plugin(Arc::new(CommandKeyManagementPlugin::new(command)));
+    }
+}
+
+pub struct KeyStore {
+    pool: PgPool,
+    master: Arc<dyn MasterKey>,
+    /// Retired master keys, by id.
+    retired: HashMap<String, Arc<dyn MasterKey>>,
+}
+
+impl KeyStore {
+    pub fn new(
+        db: &DatabasePool,
+        master: Arc<dyn MasterKey>,
+        retired: Vec<Arc<dyn MasterKey>>,
+    ) -> Self {
+        Self {
+            pool: db.pool().clone(),
+            master,
+            retired: retired
+                .into_iter()
+                .map(|key| (key.id().to_string(), key))
+                .collect(),
+        }
+    }
+
+    /// The key store with the configured master keys, if one is configured.
+    pub async fn open(
+        db: &DatabasePool,
+        config: &KeysConfig,
+        plugins: &PluginManager,
+    ) -> Result<Option<Self>, KeyError> {
+        let Some(source) = &config.master_key else {
+            return Ok(None);
+        };
+        let master = master::load(source, plugins).await?;
+        let mut retired = Vec::with_capacity(config.retired_master_keys.len());
+        for source in &config.retired_master_keys {
+            retired.push(master::load(source, plugins).await?);
+        }
+        Ok(Some(Self::new(db, master, retired)))
+    }
+
+    async fn unwrap(&self, row: DataKeyRow) -> Result<DataKey, KeyError> {
+        let master = if row.master_key_id == self.master.id() {
+            &self.master
+        } else {
+            self.retired.get(&row.master_key_id).ok_or_else(|| {
+                KeyError::UnknownMasterKey(row.name.clone(), row.master_key_id.clone())
+            })?
+        };
+        Ok(DataKey {
+            key: master.unwrap(&row.wrapped_key).await?,
+            name: row.name,
+            version: row.version,
+        })
+    }
+
+    /// Hold off other changes to the versions of `name` until the
+    /// transaction ends; row locks cannot, as there may be no row yet.
+    async fn lock(conn: &mut PgConnection, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('data_keys'), hashtext($1))")
+            .bind(name)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Create the first version of `name` unless there is one.
+    async fn create(&self, name: &str) -> Result<(), KeyError> {
+        let wrapped = self.master.wrap(&EncryptionKey::generate()).await?;
+        let mut tx = self.pool.begin().await?;
+        Self::lock(&mut tx, name).await?;
+        sqlx::query(
+            "INSERT INTO data_keys (name, version, wrapped_key, master_key_id)
+             VALUES ($1, 1, $2, $3)
+             ON CONFLICT (name, version) DO NOTHING",
+        )
+        .bind(name)
+        .bind(wrapped)
+        .bind(self.master.id())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Every version of `name`, newest first. The first version is created
+    /// when there is none yet.
+    pub async fn versions(&self, name: &str) -> Result<Vec<DataKey>, KeyError> {
+        let select = || {
+            sqlx::query_as::<_, DataKeyRow>(
+                "SELECT name, version, wrapped_key, master_key_id FROM data_keys
+                 WHERE name = $1
+                 ORDER BY version DESC",
+            )
+            .bind(name)
+            .fetch_all(&self.pool)
+        };
+        let mut rows = select().await?;
+        if rows.is_empty() {
+            self.create(name).await?;
+            rows = select().await?;
+        }
+        let mut keys = Vec::with_capacity(rows.len());
+        for row in rows {
+            keys.push(self.unwrap(row).await?);
+        }
+        Ok(keys)
+    }
+
+    /// The version of `name` to encrypt with.
+    pub async fn current(&self, name: &str) -> Result<DataKey, KeyError> {
+        self.versions(name)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| KeyError::NotFound(name.to_string(), 1))
+    }
+
+    pub async fn get(&self, name: &str, version: i32) -> Result<DataKey, KeyError> {
+        let row = sqlx::query_as::<_, DataKeyRow>(
+            "SELECT name, version, wrapped_key, master_key_id FROM data_keys
+             WHERE name = $1 AND version = $2",
+        )
+        .bind(name)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| KeyError::NotFound(name.to_string(), version))?;
+        self.unwrap(row).await
+    }
+
+    /// Add a version of `name` and retire the current one, which is only
+    /// used to decrypt from then on. Returns the new version.
+    pub async fn rotate(&self, name: &str) -> Result<i32, KeyError> {
+        let wrapped = self.master.wrap(&EncryptionKey::generate()).await?;
+        let mut tx = self.pool.begin().await?;
+        // Without the lock, concurrent rotations could each leave their
+        // version current, or compute the same one.
+        Self::lock(&mut tx, name).await?;
+        sqlx::query(
+            "UPDATE data_keys SET retired_at = now() WHERE name = $1 AND retired_at IS NULL",
+        )
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+        let version: i32 = sqlx::query_scalar(
+            "INSERT INTO data_keys (name, version, wrapped_key, master_key_id)
+             SELECT $1, COALESCE(MAX(version), 0) + 1, $2, $3 FROM data_keys WHERE name = $1
+             RETURNING version",
+        )
+        .bind(name)
+        .bind(wrapped)
+        .bind(self.master.id())
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(version)
+    }
+
+    /// Re-wrap with the current master key every data key a retired one
+    /// wrapped. Returns how many were.
+    pub async fn rewrap(&self) -> Result<usize, KeyError> {
+        let rows = sqlx::query_as::<_, DataKeyRow>(
+            "SELECT name, version, wrapped_key, master_key_id FROM data_keys
+             WHERE master_key_id <> $1",
+        )
+        .bind(self.master.id())
+        .fetch_all(&self.pool)
+        .await?;
+        let mut count = 0;
+        for row in rows {
+            let old_master = row.master_key_id.clone();
+            let key = self.unwrap(row).await?;
+            let wrapped = self.master.wrap(&key.key).await?;
+            let updated = sqlx::query(
+                "UPDATE data_keys SET wrapped_key = $3, master_key_id = $4
+                 WHERE name = $1 AND version = $2 AND master_key_id = $5",
+            )
+            .bind(&key.name)
+            .bind(key.version)
+            .bind(wrapped)
+            .bind(self.master.id())
+            .bind(old_master)
+            .execute(&self.pool)
+            .await?;
+            count += updated.rows_affected() as usize;
+        }
+        Ok(count)
+    }
+
+    /// Every version of every data key, by name and newest first.
+    pub async fn list(&self) -> Result<Vec<DataKeyInfo>, KeyError> {
+        Ok(sqlx::query_as::<_, DataKeyInfo>(
+            "SELECT name, version, master_key_id, created_at, retired_at FROM data_keys
+             ORDER BY name, version DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::database::DatabaseConfig;
+    use uuid::Uuid;
+
+    /// A key store on the database in `DATABASE_URL`, and a data key name
+    /// no other test uses.
+    async fn key_store() -> (KeyStore, String) {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+        let db = DatabasePool::new(&DatabaseConfig {
+            url,
+            ..DatabaseConfig::default()
+        })
+        .await
+        .unwrap();
+        db.migrate().await.unwrap();
+        let master = Arc::new(LocalMasterKey::new("test", [7; 32]));
+        (
+            KeyStore::new(&db, master, Vec::new()),
+            Uuid::new_v4().to_string(),
+        )
+    }
+
+    async fn current_versions(keys: &KeyStore, name: &str) -> Vec<i32> {
+        keys.list()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|key| key.name == name && key.retired_at.is_none())
+            .map(|key| key.version)
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL in DATABASE_URL"]
+    async fn test_rotated_version_comes_first() {
+        let (keys, name) = key_store().await;
+        let first = keys.current(&name).await.unwrap();
+        assert_eq!(first.version, 1);
+        assert_eq!(keys.rotate(&name).await.unwrap(), 2);
+        assert_eq!(keys.rotate(&name).await.unwrap(), 3);
+
+        let versions = keys.versions(&name).await.unwrap();
+        let numbers: Vec<i32> = versions.iter().map(|key| key.version).collect();
+        assert_eq!(numbers, vec![3, 2, 1]);
+        assert_eq!(versions[2].key.as_bytes(), first.key.as_bytes());
+        assert_eq!(current_versions(&keys, &name).await, vec![3]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PostgreSQL in DATABASE_URL"]
+    async fn test_concurrent_rotations_leave_one_current_version() {
+        let (keys, name) = key_store().await;
+        let rotations = (0..8).map(|_| keys.rotate(&name));
+        let mut rotated: Vec<i32> = futures::future::try_join_all(rotations).await.unwrap();
+        rotated.sort();
+        assert_eq!(rotated, (1..=8).collect::<Vec<_>>());
+        assert_eq!(current_versions(&keys, &name).await, vec![8]);
+    }
+}
@@ -4,9 +4,11 @@ pub mod storage;
 pub mod sync;
 pub mod webdav;
 pub mod caldav;
+pub mod carddav;
 pub mod monitoring;
 pub mod plugins;
 pub mod config;
+pub mod keys;
 
 use actix_web::{web, App, HttpServer, middleware};
 use tracing_actix_web::TracingLogger;
@@ -16,6 +18,9 @@ use crate::plugins::manager::PluginManager;
 use crate::plugins::auth_local::LocalAuthPlugin;
 use crate::plugins::monitoring_prometheus::PrometheusMonitoringPlugin;
 use crate::plugins::storage_local::LocalStoragePlugin;
+use crate::plugins::mail_maildir::MaildirMailPlugin;
+use crate::plugins::mail_smtp::SmtpMailPlugin;
+use crate::config::mail::MailTransport;
 use std::sync::Arc;
 
 pub async fn run_server(config: AppConfig) -> std::io::Result<()> {
@@ -27,22 +32,56 @@ pub async fn run_server(config: AppConfig) -> std::io::Result<()> {
     db_pool.migrate().await
         .expect("Failed to run database migrations");
     
-    // Initialize storage
-    let storage = storage::create_storage_backend(&config.storage).await
-        .expect("Failed to initialize storage backend");
-    
     // Initialize plugin manager
     let plugin_manager = Arc::new(PluginManager::new());
     // Register built-in plugins
     plugin_manager.register_auth_plugin(Arc::new(LocalAuthPlugin));
     plugin_manager.register_monitoring_plugin(Arc::new(PrometheusMonitoringPlugin));
     plugin_manager.register_storage_plugin(Arc::new(LocalStoragePlugin));
+    if config.mail.enabled {
+        match config.mail.transport {
+            MailTransport::Smtp => {
+                if let Some(smtp) = &config.mail.smtp {
+                    let plugin = SmtpMailPlugin::new(smtp)
+                        .expect("Failed to initialize SMTP transport");
+                    plugin_manager.register_mail_plugin(Arc::new(plugin));
+                }
+            }
+            MailTransport::Maildir => {
+                if let Some(path) = &config.mail.maildir_path {
+                    plugin_manager.register_mail_plugin(Arc::new(MaildirMailPlugin::new(path)));
+                }
+            }
+        }
+    }
+    
+    keys::register_plugins(&plugin_manager, &config.keys);
+    
+    // Initialize the key store
+    let key_store = keys::KeyStore::open(&db_pool, &config.keys, &plugin_manager).await
+        .expect("Failed to initialize key store")
+        .map(Arc::new);
+    if let Some(key_store) = &key_store {
+        if !config.keys.retired_master_keys.is_empty() {
+            let rewrapping = key_store.clone();
+            tokio::spawn(async move {
+                match rewrapping.rewrap().await {
+                    Ok(count) => tracing::info!("Re-wrapped {} data keys", count),
+                    Err(e) => tracing::error!("Failed to re-wrap data keys: {}", e),
+                }
+            });
+        }
+    }
+    
+    // Initialize storage
+    let storage = storage::create_storage_backend(&config.storage, &db_pool, key_store.as_deref()).await
+        .expect("Failed to initialize storage backend");
     
     // Create application state
     let app_state = web::Data::new(AppState {
         config: config.clone(),
         db_pool,
-        storage: Arc::from(storage),
+        storage,
         plugin_manager,
     });
     
@@ -60,13 +99,14 @@ pub async fn run_server(config: AppConfig) -> std::io::Result<()> {
                         origin.as_bytes().starts_with(b"http://localhost") ||
                         origin.as_bytes().starts_with(b"https://localhost")
                     })
-                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS", "PROPFIND", "PROPPATCH"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS", "PROPFIND", "PROPPATCH", "REPORT"])
                     .allowed_headers(vec!["authorization", "accept", "content-type", "x-requested-with"])
                     .supports_credentials()
             )
             .configure(api::configure_routes)
             .configure(webdav::configure)
             .configure(caldav::configure)
+            .configure(carddav::configure)
             .service(actix_files::Files::new("/static", "./static").index_file("index.html"))
     })
     .bind(format!("{}:{}", config.server.host, config.server.port))?
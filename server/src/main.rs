@@ -1,5 +1,8 @@
 use fileshare_server::{config::AppConfig, run_server};
-use clap::Parser;
+use fileshare_server::config::database::DatabasePool;
+use fileshare_server::keys::{self, KeyStore};
+use fileshare_server::plugins::manager::PluginManager;
+use clap::{Parser, Subcommand};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Parser)]
@@ -17,6 +20,56 @@ struct Cli {
     /// Enable development mode
     #[arg(long)]
     dev: bool,
+    
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the data-encryption keys of the key store
+    #[command(subcommand)]
+    Keys(KeysCommand),
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// List the data keys and their versions
+    List,
+    /// Add a version of a data key, used once the server restarts; older
+    /// versions only decrypt from then on
+    Rotate { name: String },
+    /// Re-wrap the data keys a retired master key wrapped with the current one
+    Rewrap,
+}
+
+async fn run_keys_command(config: &AppConfig, command: KeysCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let db_pool = DatabasePool::new(&config.database).await?;
+    db_pool.migrate().await?;
+    let plugins = PluginManager::new();
+    keys::register_plugins(&plugins, &config.keys);
+    let keys = KeyStore::open(&db_pool, &config.keys, &plugins).await?
+        .ok_or("no master key is configured in [keys]")?;
+    match command {
+        KeysCommand::List => {
+            for key in keys.list().await? {
+                let state = match key.retired_at {
+                    Some(at) => format!("retired {}", at.to_rfc3339()),
+                    None => "current".to_string(),
+                };
+                println!("{}\tv{}\t{}\t{}", key.name, key.version, key.master_key_id, state);
+            }
+        }
+        KeysCommand::Rotate { name } => {
+            let version = keys.rotate(&name).await?;
+            println!("{} is now at version {}", name, version);
+        }
+        KeysCommand::Rewrap => {
+            let count = keys.rewrap().await?;
+            println!("Re-wrapped {} data keys", count);
+        }
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -37,6 +90,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Load configuration
     let config = AppConfig::from_file(&cli.config).await?;
     
+    if let Some(Command::Keys(command)) = cli.command {
+        return run_keys_command(&config, command).await;
+    }
+    
     if cli.dev {
         tracing::warn!("Running in development mode - this should not be used in production!");
     }
@@ -0,0 +1,126 @@
+//! Key management plugin running an external command
+//!
+//! Each operation runs `<program> <args>... wrap <key id>` or `... unwrap
+//! <key id>` with the key on stdin, and takes the wrapped or unwrapped key
+//! from its stdout. Master keys can so stay in a KMS or an HSM, behind the
+//! client tool it comes with. A command exiting with a failure fails the
+//! operation with what it wrote on stderr.
+
+use super::traits::{KeyManagementPlugin, Plugin};
+use crate::config::keys::{KeyCommandConfig, COMMAND_PLUGIN};
+use async_trait::async_trait;
+use std::any::Any;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Debug)]
+pub struct CommandKeyManagementPlugin {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandKeyManagementPlugin {
+    pub fn new(config: &KeyCommandConfig) -> Self {
+        Self {
+            program: config.program.clone(),
+            args: config.args.clone(),
+        }
+    }
+
+    async fn run(&self, operation: &str, key_id: &str, input: &[u8]) -> Result<Vec<u8>, String> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(operation)
+            .arg(key_id)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("{}: {}", self.program, e))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // Written while the output is read, so neither pipe fills up.
+        let write = async move {
+            stdin.write_all(input).await?;
+            stdin.shutdown().await
+        };
+        let (written, output) = tokio::join!(write, child.wait_with_output());
+        let output = output.map_err(|e| format!("{}: {}", self.program, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} {} {} failed ({}): {}",
+                self.program,
+                operation,
+                key_id,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        written.map_err(|e| format!("{}: {}", self.program, e))?;
+        Ok(output.stdout)
+    }
+}
+
+impl Plugin for CommandKeyManagementPlugin {
+    fn name(&self) -> &str {
+        COMMAND_PLUGIN
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[async_trait]
+impl KeyManagementPlugin for CommandKeyManagementPlugin {
+    async fn wrap_key(&self, key_id: &str, key: &[u8]) -> Result<Vec<u8>, String> {
+        self.run("wrap", key_id, key).await
+    }
+
+    async fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        self.run("unwrap", key_id, wrapped).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A plugin running `script` with `sh`, which sees the operation as $1
+    /// and the key id as $2.
+    fn plugin(script: &str) -> CommandKeyManagementPlugin {
+        CommandKeyManagementPlugin::new(&KeyCommandConfig {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string(), "kms".to_string()],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_wraps_and_unwraps_through_the_command() {
+        let kms = plugin(
+            r#"case "$1" in
+                 wrap) printf '%s:' "$2"; cat ;;
+                 unwrap) IFS= read -r wrapped; printf '%s' "${wrapped#*:}" ;;
+               esac"#,
+        );
+        let wrapped = kms.wrap_key("main", b"secret").await.unwrap();
+        assert_eq!(wrapped, b"main:secret");
+        assert_eq!(kms.unwrap_key("main", &wrapped).await.unwrap(), b"secret");
+    }
+
+    #[tokio::test]
+    async fn test_failing_command_reports_stderr() {
+        let kms = plugin("cat > /dev/null; echo 'access denied' >&2; exit 3");
+        let error = kms.wrap_key("main", b"secret").await.unwrap_err();
+        assert!(error.contains("access denied"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_missing_program_fails() {
+        let kms = CommandKeyManagementPlugin::new(&KeyCommandConfig {
+            program: "/nonexistent/kms".to_string(),
+            args: Vec::new(),
+        });
+        assert!(kms.unwrap_key("main", b"wrapped").await.is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Maildir mail transport plugin, for testing outgoing mail locally
+
+use super::traits::{MailPlugin, Plugin};
+use async_trait::async_trait;
+use std::any::Any;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct MaildirMailPlugin {
+    path: PathBuf,
+}
+
+impl MaildirMailPlugin {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Plugin for MaildirMailPlugin {
+    fn name(&self) -> &str {
+        "maildir"
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[async_trait]
+impl MailPlugin for MaildirMailPlugin {
+    async fn send(&self, from: &str, to: &[String], message: &[u8]) -> Result<(), String> {
+        for dir in ["tmp", "new", "cur"] {
+            tokio::fs::create_dir_all(self.path.join(dir))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        // Messages are written to tmp/ and then moved into new/, so readers
+        // never see a partial file.
+        let name = format!(
+            "{}.{}.rouillecloud",
+            chrono::Utc::now().timestamp(),
+            Uuid::new_v4().simple()
+        );
+        let mut data = format!(
+            "Return-Path: <{}>\r\nDelivered-To: {}\r\n",
+            from,
+            to.join(", ")
+        )
+        .into_bytes();
+        data.extend_from_slice(message);
+
+        let tmp = self.path.join("tmp").join(&name);
+        tokio::fs::write(&tmp, &data)
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::fs::rename(&tmp, self.path.join("new").join(&name))
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
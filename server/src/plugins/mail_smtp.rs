@@ -0,0 +1,69 @@
+//! SMTP mail transport plugin
+
+use super::traits::{MailPlugin, Plugin};
+use crate::config::mail::SmtpConfig;
+use async_trait::async_trait;
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::any::Any;
+use std::fmt;
+
+pub struct SmtpMailPlugin {
+    host: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailPlugin {
+    pub fn new(config: &SmtpConfig) -> Result<Self, String> {
+        let builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+                .map_err(|e| e.to_string())?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+        };
+        let mut builder = builder.port(config.port);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            host: config.host.clone(),
+            transport: builder.build(),
+        })
+    }
+}
+
+impl fmt::Debug for SmtpMailPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmtpMailPlugin")
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+impl Plugin for SmtpMailPlugin {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[async_trait]
+impl MailPlugin for SmtpMailPlugin {
+    async fn send(&self, from: &str, to: &[String], message: &[u8]) -> Result<(), String> {
+        let from = from.parse::<Address>().map_err(|e| e.to_string())?;
+        let to = to
+            .iter()
+            .map(|address| address.parse::<Address>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let envelope = Envelope::new(Some(from), to).map_err(|e| e.to_string())?;
+        self.transport
+            .send_raw(&envelope, message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
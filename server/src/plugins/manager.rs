@@ -9,6 +9,8 @@ pub struct PluginManager {
     auth_plugins: RwLock<HashMap<String, Arc<dyn AuthPlugin>>>,
     monitoring_plugins: RwLock<HashMap<String, Arc<dyn MonitoringPlugin>>>,
     storage_plugins: RwLock<HashMap<String, Arc<dyn StoragePlugin>>>,
+    mail_plugins: RwLock<HashMap<String, Arc<dyn MailPlugin>>>,
+    key_management_plugins: RwLock<HashMap<String, Arc<dyn KeyManagementPlugin>>>,
 }
 
 impl PluginManager {
@@ -25,5 +27,19 @@ impl PluginManager {
     pub fn register_storage_plugin(&self, plugin: Arc<dyn StoragePlugin>) {
         self.storage_plugins.write().unwrap().insert(plugin.name().to_string(), plugin);
     }
+    pub fn register_mail_plugin(&self, plugin: Arc<dyn MailPlugin>) {
+        self.mail_plugins.write().unwrap().insert(plugin.name().to_string(), plugin);
+    }
+    pub fn register_key_management_plugin(&self, plugin: Arc<dyn KeyManagementPlugin>) {
+        self.key_management_plugins.write().unwrap().insert(plugin.name().to_string(), plugin);
+    }
+
+    pub fn mail_plugin(&self, name: &str) -> Option<Arc<dyn MailPlugin>> {
+        self.mail_plugins.read().unwrap().get(name).cloned()
+    }
+
+    pub fn key_management_plugin(&self, name: &str) -> Option<Arc<dyn KeyManagementPlugin>> {
+        self.key_management_plugins.read().unwrap().get(name).cloned()
+    }
     // TODO: Add dynamic loading/hot-reloading logic
 }
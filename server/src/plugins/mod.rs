@@ -5,6 +5,9 @@ pub mod traits;
 pub mod auth_local;
 pub mod monitoring_prometheus;
 pub mod storage_local;
+pub mod mail_smtp;
+pub mod mail_maildir;
+pub mod kms_command;
 pub mod dynamic_loader;
 
 // Re-export traits for convenience
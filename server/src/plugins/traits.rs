@@ -28,3 +28,19 @@ pub trait StoragePlugin: Plugin {
     async fn store_file(&self, path: &str, data: &[u8]) -> Result<(), String>;
     async fn retrieve_file(&self, path: &str) -> Result<Vec<u8>, String>;
 }
+
+/// Outgoing mail transport plugin trait
+#[async_trait]
+pub trait MailPlugin: Plugin {
+    /// Deliver a formatted RFC 5322 message to `to`, with `from` as the
+    /// envelope sender.
+    async fn send(&self, from: &str, to: &[String], message: &[u8]) -> Result<(), String>;
+}
+
+/// Key management plugin trait, for master keys kept outside the server
+#[async_trait]
+pub trait KeyManagementPlugin: Plugin {
+    /// Encrypt `key` with the master key `key_id`.
+    async fn wrap_key(&self, key_id: &str, key: &[u8]) -> Result<Vec<u8>, String>;
+    async fn unwrap_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, String>;
+}
@@ -0,0 +1,561 @@
+//! Encryption of stored objects at rest
+//!
+//! Every object gets a key of its own, derived from the master key for a
+//! random file id with [`KeyPurpose::FileEncryption`]. The key is wrapped
+//! with a key-encryption key derived from the master key too, and kept in
+//! the object's header together with the id of the master key and the size
+//! of the plaintext. The body is the plaintext encrypted by
+//! [`FileEncryption::encrypt_stream`], in chunks of [`CHUNK_SIZE`] bytes
+//! which can be decrypted on their own, so a range of the plaintext is read
+//! by decrypting only the chunks it covers.
+//!
+//! Rotating the master key only re-wraps the file keys in the headers: the
+//! retired key unwraps them, the current one wraps them again, and only the
+//! headers are written back. An object without a header is refused, as
+//! anyone able to write to the backend could have put it there; while
+//! contents stored before encryption was enabled remain,
+//! [`EncryptedStorage::allow_plaintext`] has them read as they are.
+
+use super::StorageBackend;
+use async_trait::async_trait;
+use crypto::{
+    EncryptionKey, FileEncryption, KeyDerivationFactory, KeyPurpose, SecureKey, StreamHeader,
+    STREAM_HEADER_LEN,
+};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Starts encrypted objects; the last byte is the format version.
+const MAGIC: &[u8; 4] = b"RCS\x01";
+/// Magic, master key id, file id, wrapped file key and plaintext size.
+const HEADER_LEN: usize = 4 + 4 + 16 + crypto::WRAPPED_KEY_LEN + 8;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+struct Header {
+    key_id: u32,
+    file_id: Uuid,
+    wrapped_key: Vec<u8>,
+    size: u64,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || !data.starts_with(MAGIC) {
+            return None;
+        }
+        let (key_id, rest) = data[4..HEADER_LEN].split_at(4);
+        let (file_id, rest) = rest.split_at(16);
+        let (wrapped_key, rest) = rest.split_at(crypto::WRAPPED_KEY_LEN);
+        Some(Self {
+            key_id: u32::from_le_bytes(key_id.try_into().ok()?),
+            file_id: Uuid::from_slice(file_id).ok()?,
+            wrapped_key: wrapped_key.to_vec(),
+            size: u64::from_le_bytes(rest.try_into().ok()?),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.key_id.to_le_bytes());
+        header.extend_from_slice(self.file_id.as_bytes());
+        header.extend_from_slice(&self.wrapped_key);
+        header.extend_from_slice(&self.size.to_le_bytes());
+        header
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Key wrapping the file keys of `factory`'s master key.
+fn wrapping_key(factory: &KeyDerivationFactory) -> EncryptionKey {
+    let key = factory.derive_key(KeyPurpose::FileEncryption, Some("key-wrapping"));
+    EncryptionKey::new(*key.as_bytes())
+}
+
+fn key_factory(master_key: [u8; 32]) -> KeyDerivationFactory {
+    KeyDerivationFactory::new(SecureKey::new(
+        master_key,
+        "rouillecloud.storage".to_string(),
+    ))
+}
+
+struct MasterKeys {
+    key_id: u32,
+    factory: KeyDerivationFactory,
+    wrapping_key: EncryptionKey,
+    /// Wrapping keys of retired master keys, by id.
+    retired: HashMap<u32, EncryptionKey>,
+}
+
+impl MasterKeys {
+    fn wrapping_key(&self, key_id: u32) -> io::Result<&EncryptionKey> {
+        if key_id == self.key_id {
+            return Ok(&self.wrapping_key);
+        }
+        self.retired
+            .get(&key_id)
+            .ok_or_else(|| invalid(format!("unknown storage master key {}", key_id)))
+    }
+
+    fn file_key(&self, header: &Header) -> io::Result<EncryptionKey> {
+        crypto::unwrap_key(self.wrapping_key(header.key_id)?, &header.wrapped_key).map_err(invalid)
+    }
+
+    /// The file key of `header` wrapped with the current master key.
+    fn rewrap(&self, header: &Header) -> io::Result<Vec<u8>> {
+        crypto::wrap_key(&self.wrapping_key, &self.file_key(header)?).map_err(invalid)
+    }
+
+    fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let file_id = Uuid::new_v4();
+        let file_key = self.factory.derive_file_key(&file_id.to_string());
+        let file_key = EncryptionKey::new(*file_key.as_bytes());
+        let header = Header {
+            key_id: self.key_id,
+            file_id,
+            wrapped_key: crypto::wrap_key(&self.wrapping_key, &file_key).map_err(invalid)?,
+            size: data.len() as u64,
+        };
+        let mut stored = header.to_bytes();
+        FileEncryption::new(&file_key)
+            .encrypt_stream(&mut &data[..], &mut stored, CHUNK_SIZE)
+            .map_err(invalid)?;
+        Ok(stored)
+    }
+
+    /// Decrypt the body of the object with `header`.
+    fn decrypt(&self, header: &Header, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        FileEncryption::new(&self.file_key(header)?)
+            .decrypt_stream(&mut &body[..], &mut data)
+            .map_err(invalid)?;
+        Ok(data)
+    }
+
+    /// Decrypt the chunks from `first` read from the body with `stream`,
+    /// which ends with chunk `last`.
+    fn decrypt_chunks(
+        &self,
+        header: &Header,
+        stream: &StreamHeader,
+        first: u64,
+        last: u64,
+        records: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let encryption = FileEncryption::new(&self.file_key(header)?);
+        let mut data = Vec::new();
+        for (index, record) in (first..).zip(records.chunks(stream.record_len() as usize)) {
+            let chunk = encryption
+                .decrypt_chunk(stream, index, record, index == last)
+                .map_err(invalid)?;
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
+
+/// What a re-wrap went through.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Rewrapped {
+    /// Objects whose file key was re-wrapped.
+    pub rewrapped: usize,
+    /// Objects whose file key could not be unwrapped, and were left alone.
+    pub failed: usize,
+}
+
+/// Encrypts the objects of another backend.
+pub struct EncryptedStorage {
+    inner: Arc<dyn StorageBackend>,
+    keys: Arc<MasterKeys>,
+    allow_plaintext: bool,
+}
+
+impl EncryptedStorage {
+    /// Encrypt with the master key `master_key`, known as `key_id`, and
+    /// still open the file keys wrapped with the `retired` master keys.
+    pub fn new(
+        inner: Arc<dyn StorageBackend>,
+        key_id: u32,
+        master_key: [u8; 32],
+        retired: impl IntoIterator<Item = (u32, [u8; 32])>,
+    ) -> Self {
+        let factory = key_factory(master_key);
+        let retired = retired
+            .into_iter()
+            .map(|(id, key)| (id, wrapping_key(&key_factory(key))))
+            .collect();
+        Self {
+            inner,
+            keys: Arc::new(MasterKeys {
+                key_id,
+                wrapping_key: wrapping_key(&factory),
+                factory,
+                retired,
+            }),
+            allow_plaintext: false,
+        }
+    }
+
+    /// Read objects without a header as they are, while contents stored
+    /// before encryption was enabled remain. They are encrypted when next
+    /// written.
+    pub fn allow_plaintext(mut self, allow: bool) -> Self {
+        self.allow_plaintext = allow;
+        self
+    }
+
+    fn plaintext(&self, key: &str) -> io::Result<()> {
+        if self.allow_plaintext {
+            Ok(())
+        } else {
+            Err(invalid(format!("{} is not encrypted", key)))
+        }
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&MasterKeys) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let keys = self.keys.clone();
+        tokio::task::spawn_blocking(move || work(&keys))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Re-wrap the file keys of the objects under `prefix` that a retired
+    /// master key wrapped, with the current one. Only the headers are
+    /// rewritten, and only while unchanged: objects replaced or deleted in
+    /// the meantime are left as they are.
+    pub async fn rewrap(&self, prefix: &str) -> io::Result<Rewrapped> {
+        let mut done = Rewrapped::default();
+        for key in self.inner.list(prefix).await? {
+            let start = match self.inner.read_range(&key, 0, HEADER_LEN as u64).await {
+                Ok(start) => start,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut header = match Header::parse(&start) {
+                Some(header) if header.key_id != self.keys.key_id => header,
+                _ => continue,
+            };
+            let old = header.clone();
+            let wrapped = match self.blocking(move |keys| keys.rewrap(&old)).await {
+                Ok(wrapped) => wrapped,
+                Err(e) => {
+                    tracing::warn!("Cannot re-wrap the key of {}: {}", key, e);
+                    done.failed += 1;
+                    continue;
+                }
+            };
+            header.key_id = self.keys.key_id;
+            header.wrapped_key = wrapped;
+            if self
+                .inner
+                .replace_start(&key, &start, &header.to_bytes())
+                .await?
+            {
+                done.rewrapped += 1;
+            }
+        }
+        Ok(done)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for EncryptedStorage {
+    async fn write(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let data = data.to_vec();
+        let stored = self.blocking(move |keys| keys.encrypt(&data)).await?;
+        self.inner.write(key, &stored).await
+    }
+
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        let stored = self.inner.read(key).await?;
+        let Some(header) = Header::parse(&stored) else {
+            self.plaintext(key)?;
+            return Ok(stored);
+        };
+        let size = header.size;
+        let data = self
+            .blocking(move |keys| keys.decrypt(&header, &stored[HEADER_LEN..]))
+            .await?;
+        if data.len() as u64 != size {
+            return Err(invalid(format!("{} is truncated", key)));
+        }
+        Ok(data)
+    }
+
+    async fn read_range(&self, key: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let start = self
+            .inner
+            .read_range(key, 0, (HEADER_LEN + STREAM_HEADER_LEN) as u64)
+            .await?;
+        let Some(header) = Header::parse(&start) else {
+            self.plaintext(key)?;
+            return self.inner.read_range(key, offset, len).await;
+        };
+        let stream = StreamHeader::parse(&start[HEADER_LEN..]).map_err(invalid)?;
+        let end = offset.saturating_add(len).min(header.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let chunk_size = stream.chunk_size as u64;
+        let (first, last) = (offset / chunk_size, (end - 1) / chunk_size);
+        let from = stream.chunk_offset(first);
+        let records = self
+            .inner
+            .read_range(
+                key,
+                HEADER_LEN as u64 + from,
+                stream.chunk_offset(last + 1) - from,
+            )
+            .await?;
+        let final_chunk = stream.chunk_count(header.size) - 1;
+        let skip = (offset - first * chunk_size) as usize;
+        let take = (end - offset) as usize;
+        let data = self
+            .blocking(move |keys| {
+                keys.decrypt_chunks(&header, &stream, first, final_chunk, &records)
+            })
+            .await?;
+        data.get(skip..skip + take)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| invalid(format!("{} is truncated", key)))
+    }
+
+    async fn replace_start(&self, key: &str, expected: &[u8], data: &[u8]) -> io::Result<bool> {
+        self.inner.replace_start(key, expected, data).await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.inner.list(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use crypto::STREAM_TAG_LEN;
+
+    const OLD_KEY: [u8; 32] = [1; 32];
+    const NEW_KEY: [u8; 32] = [2; 32];
+
+    fn local(dir: &tempfile::TempDir) -> Arc<dyn StorageBackend> {
+        Arc::new(LocalStorage::new(dir.path()))
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header {
+            key_id: 7,
+            file_id: Uuid::new_v4(),
+            wrapped_key: vec![9; crypto::WRAPPED_KEY_LEN],
+            size: 1 << 40,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN);
+        let parsed = Header::parse(&bytes).unwrap();
+        assert_eq!(parsed.key_id, 7);
+        assert_eq!(parsed.file_id, header.file_id);
+        assert_eq!(parsed.wrapped_key, header.wrapped_key);
+        assert_eq!(parsed.size, 1 << 40);
+
+        assert!(Header::parse(&bytes[..HEADER_LEN - 1]).is_none());
+        let mut other = bytes.clone();
+        other[3] = 2;
+        assert!(Header::parse(&other).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        let storage = EncryptedStorage::new(inner.clone(), 1, NEW_KEY, []);
+        for len in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 17] {
+            let data = sample(len);
+            storage.write("a/object", &data).await.unwrap();
+            assert_eq!(storage.read("a/object").await.unwrap(), data);
+            let stored = inner.read("a/object").await.unwrap();
+            assert!(stored.starts_with(MAGIC));
+            assert!(stored.len() > HEADER_LEN + STREAM_HEADER_LEN + len);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_range_across_chunks_and_past_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = EncryptedStorage::new(local(&dir), 1, NEW_KEY, []);
+        let data = sample(3 * CHUNK_SIZE + 100);
+        let len = data.len() as u64;
+        storage.write("object", &data).await.unwrap();
+
+        let chunk = CHUNK_SIZE as u64;
+        for (offset, count) in [
+            (0, 10),
+            (chunk - 5, 10),
+            (chunk, chunk),
+            (2 * chunk - 1, chunk + 2),
+            (0, len),
+            (len - 3, 10),
+        ] {
+            let end = (offset + count).min(len) as usize;
+            assert_eq!(
+                storage.read_range("object", offset, count).await.unwrap(),
+                &data[offset as usize..end],
+                "range {}+{}",
+                offset,
+                count
+            );
+        }
+        assert!(storage
+            .read_range("object", len, 5)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .read_range("object", len + 10, 5)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rewrap_under_a_retired_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        let data = sample(CHUNK_SIZE + 5);
+        let old = EncryptedStorage::new(inner.clone(), 1, OLD_KEY, []);
+        old.write("object", &data).await.unwrap();
+        let before = inner.read("object").await.unwrap();
+
+        let storage = EncryptedStorage::new(inner.clone(), 2, NEW_KEY, [(1, OLD_KEY)]);
+        assert_eq!(storage.read("object").await.unwrap(), data);
+        let done = storage.rewrap("").await.unwrap();
+        assert_eq!(
+            done,
+            Rewrapped {
+                rewrapped: 1,
+                failed: 0
+            }
+        );
+
+        // Only the header changed.
+        let after = inner.read("object").await.unwrap();
+        assert_eq!(after[HEADER_LEN..], before[HEADER_LEN..]);
+        assert_eq!(Header::parse(&after).unwrap().key_id, 2);
+
+        let current = EncryptedStorage::new(inner.clone(), 2, NEW_KEY, []);
+        assert_eq!(current.read("object").await.unwrap(), data);
+        assert_eq!(storage.rewrap("").await.unwrap(), Rewrapped::default());
+        assert!(old.read("object").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rewrap_counts_unknown_keys_and_skips_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        EncryptedStorage::new(inner.clone(), 1, OLD_KEY, [])
+            .write("old", b"old")
+            .await
+            .unwrap();
+        inner.write("plain", b"not encrypted").await.unwrap();
+        let storage = EncryptedStorage::new(inner.clone(), 2, NEW_KEY, []);
+        storage.write("new", b"new").await.unwrap();
+        assert_eq!(
+            storage.rewrap("").await.unwrap(),
+            Rewrapped {
+                rewrapped: 0,
+                failed: 1
+            }
+        );
+        assert_eq!(inner.read("plain").await.unwrap(), b"not encrypted");
+    }
+
+    #[tokio::test]
+    async fn test_header_is_only_replaced_while_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        inner.write("object", b"abcdef").await.unwrap();
+        assert!(!inner.replace_start("object", b"xyz", b"ABC").await.unwrap());
+        assert!(inner.replace_start("object", b"abc", b"ABC").await.unwrap());
+        assert_eq!(inner.read("object").await.unwrap(), b"ABCdef");
+        // A deleted object is not brought back.
+        inner.delete("object").await.unwrap();
+        assert!(!inner.replace_start("object", b"ABC", b"abc").await.unwrap());
+        assert!(!inner.exists("object").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tampering_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        let storage = EncryptedStorage::new(inner.clone(), 1, NEW_KEY, []);
+        let data = sample(2 * CHUNK_SIZE + 3);
+        storage.write("object", &data).await.unwrap();
+        let stored = inner.read("object").await.unwrap();
+
+        for at in [
+            // wrapped file key
+            30,
+            // stream header
+            HEADER_LEN + 2,
+            // first and last chunks
+            HEADER_LEN + STREAM_HEADER_LEN + 5,
+            stored.len() - 1,
+        ] {
+            let mut tampered = stored.clone();
+            tampered[at] ^= 1;
+            inner.write("object", &tampered).await.unwrap();
+            let error = storage.read("object").await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "byte {}", at);
+        }
+
+        // Dropping the last chunk is noticed, by whole and ranged reads.
+        let record = STREAM_TAG_LEN + CHUNK_SIZE;
+        let truncated = &stored[..HEADER_LEN + STREAM_HEADER_LEN + 2 * record];
+        inner.write("object", truncated).await.unwrap();
+        assert!(storage.read("object").await.is_err());
+        assert!(storage
+            .read_range("object", 2 * CHUNK_SIZE as u64 - 1, 4)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_is_refused_unless_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = local(&dir);
+        inner.write("object", b"stored in the clear").await.unwrap();
+
+        let storage = EncryptedStorage::new(inner.clone(), 1, NEW_KEY, []);
+        let error = storage.read("object").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(storage.read_range("object", 0, 5).await.is_err());
+
+        let migrating = EncryptedStorage::new(inner, 1, NEW_KEY, []).allow_plaintext(true);
+        assert_eq!(
+            migrating.read("object").await.unwrap(),
+            b"stored in the clear"
+        );
+        assert_eq!(migrating.read_range("object", 7, 2).await.unwrap(), b"in");
+    }
+}
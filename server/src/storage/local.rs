@@ -0,0 +1,144 @@
+//! Objects as files below a directory
+
+use super::StorageBackend;
+use async_trait::async_trait;
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+const TMP_MARKER: &str = ".tmp-";
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        let plain = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if key.is_empty() || !plain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage key {}", key),
+            ));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    /// Written to a temporary file first, so readers never see half an
+    /// object.
+    async fn write(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!("{}{}", TMP_MARKER, Uuid::new_v4()));
+        tokio::fs::write(&tmp, data).await?;
+        if let Err(e) = tokio::fs::rename(&tmp, &path).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        tokio::fs::read(self.path(key)?).await
+    }
+
+    async fn read_range(&self, key: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = tokio::fs::File::open(self.path(key)?).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut data = Vec::new();
+        file.take(len).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Written in place: objects are otherwise only ever replaced whole, and
+    /// a write this small is not seen half done by readers.
+    async fn replace_start(&self, key: &str, expected: &[u8], data: &[u8]) -> io::Result<bool> {
+        if expected.len() != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the replacement must be as long as what it replaces",
+            ));
+        }
+        let mut file = match tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.path(key)?)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let mut start = Vec::with_capacity(expected.len());
+        (&mut file)
+            .take(expected.len() as u64)
+            .read_to_end(&mut start)
+            .await?;
+        if start != expected {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(0)).await?;
+        file.write_all(data).await?;
+        file.sync_data().await?;
+        Ok(true)
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        tokio::fs::try_exists(self.path(key)?).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let dir = match prefix.trim_end_matches('/') {
+            "" => self.root.clone(),
+            prefix => self.path(prefix)?,
+        };
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            if !dir.exists() {
+                return Ok(keys);
+            }
+            for entry in walkdir::WalkDir::new(&dir) {
+                let entry = entry.map_err(io::Error::other)?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                let Ok(relative) = entry.path().strip_prefix(&root) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if !key.contains(TMP_MARKER) {
+                    keys.push(key);
+                }
+            }
+            Ok(keys)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
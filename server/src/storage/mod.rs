@@ -1,16 +1,166 @@
-// Storage module
-// This module provides storage backend functionality
+//! Storage backends for file contents
+//!
+//! Contents are stored as objects under `/`-separated keys. [`LocalStorage`]
+//! keeps them as files below a directory; [`EncryptedStorage`] wraps any
+//! backend to encrypt them at rest.
 
+pub mod encrypted;
+pub mod local;
+
+pub use encrypted::{EncryptedStorage, Rewrapped};
+pub use local::LocalStorage;
+
+use crate::config::database::DatabasePool;
+use crate::config::storage::{parse_key, StorageConfig};
+use crate::keys::{DataKey, KeyStore, STORAGE_DATA_KEY};
+use async_trait::async_trait;
+use std::io;
+use std::sync::Arc;
+
+#[async_trait]
 pub trait StorageBackend: Send + Sync {
-    // TODO: Define storage interface
+    /// Store `data` under `key`, replacing any object already there.
+    async fn write(&self, key: &str, data: &[u8]) -> io::Result<()>;
+
+    async fn read(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    /// At most `len` bytes of the object from `offset`; fewer past its end.
+    async fn read_range(&self, key: &str, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+
+    /// Overwrite the start of an object with `data` if it still starts
+    /// with `expected`, of the same length. False when the object is gone
+    /// or starts otherwise.
+    async fn replace_start(&self, key: &str, expected: &[u8], data: &[u8]) -> io::Result<bool>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Keys of the objects under `prefix`.
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
+
+/// Whether every stored file key was already re-wrapped with `key_id`.
+async fn rewrapped(db: &DatabasePool, key_id: u32) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM storage_rewraps WHERE key_id = $1)")
+        .bind(i64::from(key_id))
+        .fetch_one(db.pool())
+        .await
 }
 
-// Temporary implementation for compilation
-pub struct DummyStorageBackend;
+async fn record_rewrap(db: &DatabasePool, key_id: u32) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO storage_rewraps (key_id) VALUES ($1) ON CONFLICT (key_id) DO NOTHING")
+        .bind(i64::from(key_id))
+        .execute(db.pool())
+        .await?;
+    Ok(())
+}
+
+/// Re-wrap the stored file keys with `storage`'s current master key, and
+/// remember once none is left wrapped with a retired one.
+async fn rewrap(storage: &EncryptedStorage, db: &DatabasePool, key_id: u32) {
+    match storage.rewrap("").await {
+        Ok(done) => {
+            tracing::info!("Re-wrapped {} stored file keys", done.rewrapped);
+            if done.failed > 0 {
+                tracing::warn!(
+                    "{} stored file keys could not be re-wrapped; retrying on the next start",
+                    done.failed
+                );
+            } else if let Err(e) = record_rewrap(db, key_id).await {
+                tracing::error!("Failed to record the re-wrap of stored file keys: {}", e);
+            }
+        }
+        Err(e) => tracing::error!("Failed to re-wrap stored file keys: {}", e),
+    }
+}
+
+/// A storage key and its id.
+type StorageKey = (u32, [u8; 32]);
+
+/// The newest version of the storage data key, to encrypt with, and the
+/// others.
+fn storage_keys(
+    versions: Vec<DataKey>,
+) -> Result<(StorageKey, Vec<StorageKey>), Box<dyn std::error::Error>> {
+    let mut keys = Vec::with_capacity(versions.len());
+    for key in versions {
+        let bytes: [u8; 32] = key.key.as_bytes().try_into()?;
+        keys.push((u32::try_from(key.version)?, bytes));
+    }
+    keys.sort_by_key(|&(version, _)| std::cmp::Reverse(version));
+    let (current, retired) = keys.split_first().ok_or("no storage data key")?;
+    Ok((*current, retired.to_vec()))
+}
+
+/// The configured backend, encrypting with the configured master key or
+/// else with the versions of the `storage` data key of `keys`. While
+/// retired master keys are known and the current one was not yet through
+/// the whole storage, file keys are re-wrapped in the background.
+pub async fn create_storage_backend(
+    config: &StorageConfig,
+    db: &DatabasePool,
+    keys: Option<&KeyStore>,
+) -> Result<Arc<dyn StorageBackend>, Box<dyn std::error::Error>> {
+    let local: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(
+        config.local_path.as_deref().unwrap_or("./storage"),
+    ));
+    let encryption = &config.encryption;
+    if !encryption.enabled {
+        return Ok(local);
+    }
+    let (key_id, master_key, retired) = match (encryption.master_key.as_deref(), keys) {
+        (Some(master_key), _) => {
+            let master_key = parse_key(master_key).ok_or("invalid storage encryption key")?;
+            let retired = encryption
+                .retired_keys
+                .iter()
+                .map(|retired| parse_key(&retired.key).map(|key| (retired.id, key)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("invalid retired storage encryption key")?;
+            (encryption.key_id, master_key, retired)
+        }
+        (None, Some(keys)) => {
+            let (current, retired) = storage_keys(keys.versions(STORAGE_DATA_KEY).await?)?;
+            (current.0, current.1, retired)
+        }
+        (None, None) => return Err("storage encryption needs a master key or a key store".into()),
+    };
+    let needs_rewrap = !retired.is_empty() && !rewrapped(db, key_id).await?;
+    let storage = Arc::new(
+        EncryptedStorage::new(local, key_id, master_key, retired)
+            .allow_plaintext(encryption.allow_plaintext),
+    );
+    if needs_rewrap {
+        let (storage, db) = (storage.clone(), db.clone());
+        tokio::spawn(async move { rewrap(&storage, &db, key_id).await });
+    }
+    Ok(storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::EncryptionKey;
+
+    fn version(version: i32) -> DataKey {
+        DataKey {
+            name: STORAGE_DATA_KEY.to_string(),
+            version,
+            key: EncryptionKey::new([version as u8; 32]),
+        }
+    }
 
-impl StorageBackend for DummyStorageBackend {}
+    #[test]
+    fn test_storage_keys_encrypt_with_the_newest_version() {
+        let (current, retired) = storage_keys(vec![version(2), version(3), version(1)]).unwrap();
+        assert_eq!(current, (3, [3; 32]));
+        assert_eq!(retired, vec![(2, [2; 32]), (1, [1; 32])]);
+    }
 
-pub async fn create_storage_backend(_config: &crate::config::storage::StorageConfig) -> Result<Box<dyn StorageBackend>, Box<dyn std::error::Error>> {
-    // TODO: Implement proper storage backend creation
-    Ok(Box::new(DummyStorageBackend))
+    #[test]
+    fn test_storage_keys_need_a_version() {
+        assert!(storage_keys(Vec::new()).is_err());
+        assert!(storage_keys(vec![version(-1)]).is_err());
+    }
 }
@@ -0,0 +1,188 @@
+//! Browsing synced files: listings, stored versions and the trash
+//!
+//! Deleted files stay in the journal as tombstones and their contents stay
+//! in the content store, which is what the trash lists; restoring a file
+//! records it again with the content of its last stored version.
+
+use super::content::{ContentStore, FileVersion};
+use super::devices::{self, DeviceStore};
+use super::journal::{EntryUpdate, SyncJournal};
+use super::{normalize_path, SyncFailure};
+use crate::auth::AuthenticatedUser;
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use chrono::Utc;
+use protocol::sync::{ChangeType, FileState, RestoreFileRequest, SyncStatus};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// Directory or file to list; everything when absent.
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PathQuery {
+    path: String,
+}
+
+fn version_state(version: FileVersion) -> FileState {
+    FileState {
+        path: version.path,
+        checksum: version.checksum,
+        size: version.size.max(0) as u64,
+        modified_at: version.created_at,
+        version: version.version.max(0) as u64,
+        sync_status: SyncStatus::Synced,
+    }
+}
+
+async fn list_entries(
+    user: &AuthenticatedUser,
+    data: &AppState,
+    path: Option<&str>,
+    deleted: bool,
+) -> Result<Vec<FileState>, SyncFailure> {
+    let prefix = match path.map(|p| p.trim_matches('/')) {
+        None | Some("") => None,
+        Some(path) => Some(
+            normalize_path(path).ok_or_else(|| SyncFailure::BadRequest("Invalid path".into()))?,
+        ),
+    };
+    let entries = SyncJournal::new(&data.db_pool)
+        .list(user.user_id, prefix.as_deref(), deleted)
+        .await?;
+    Ok(entries.iter().map(|entry| entry.file_state()).collect())
+}
+
+/// Live files under a path, recursively, in path order.
+pub async fn list_files(
+    user: AuthenticatedUser,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match list_entries(&user, &data, query.path.as_deref(), false).await {
+        Ok(files) => Ok(HttpResponse::Ok().json(files)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// Stored versions of a file, latest first.
+pub async fn list_versions(
+    user: AuthenticatedUser,
+    query: web::Query<PathQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let Some(path) = normalize_path(&query.path) else {
+        return Ok(SyncFailure::BadRequest("Invalid path".into()).into_response());
+    };
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    match store.versions(user.user_id, &path).await {
+        Ok(versions) => Ok(
+            HttpResponse::Ok().json(versions.into_iter().map(version_state).collect::<Vec<_>>())
+        ),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+/// Deleted files, in path order.
+pub async fn list_trash(
+    user: AuthenticatedUser,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match list_entries(&user, &data, query.path.as_deref(), true).await {
+        Ok(files) => Ok(HttpResponse::Ok().json(files)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+async fn restore(
+    journal: &SyncJournal,
+    store: &ContentStore,
+    user_id: Uuid,
+    device_id: Uuid,
+    path: &str,
+) -> Result<FileState, SyncFailure> {
+    let (mut tx, _) = journal.begin(user_id).await?;
+    match SyncJournal::entry(&mut tx, user_id, path).await? {
+        Some(entry) if entry.deleted => {}
+        Some(_) => return Err(SyncFailure::Conflict(format!("{} is not deleted", path))),
+        None => {
+            return Err(SyncFailure::NotFound(format!(
+                "{} is not in the trash",
+                path
+            )))
+        }
+    }
+    let version = store
+        .version(user_id, path, None)
+        .await?
+        .ok_or_else(|| SyncFailure::NotFound(format!("No stored version of {}", path)))?;
+    let entry = SyncJournal::record(
+        &mut tx,
+        user_id,
+        device_id,
+        &EntryUpdate {
+            path,
+            checksum: &version.checksum,
+            size: version.size,
+            modified_at: Utc::now(),
+            deleted: false,
+            moved_to: None,
+            metadata: None,
+            change: &ChangeType::Created,
+        },
+    )
+    .await?;
+    ContentStore::link_version(
+        &mut tx,
+        user_id,
+        device_id,
+        path,
+        entry.version,
+        &version.checksum,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(entry.file_state())
+}
+
+/// Restore a deleted file; other devices get it on their next sync.
+pub async fn restore_file(
+    user: AuthenticatedUser,
+    req: web::Json<RestoreFileRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) =
+        devices::authorize_sync(&DeviceStore::new(&data.db_pool), &user, req.device_id).await
+    {
+        return Ok(response);
+    }
+    let Some(path) = normalize_path(&req.path) else {
+        return Ok(SyncFailure::BadRequest("Invalid path".into()).into_response());
+    };
+    let journal = SyncJournal::new(&data.db_pool);
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    match restore(&journal, &store, user.user_id, req.device_id, &path).await {
+        Ok(state) => Ok(HttpResponse::Ok().json(state)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/files", web::get().to(list_files))
+        .route("/sync/files/versions", web::get().to(list_versions))
+        .route("/sync/trash", web::get().to(list_trash))
+        .route("/sync/trash/restore", web::post().to(restore_file));
+}
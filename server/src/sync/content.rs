@@ -0,0 +1,164 @@
+//! Stored contents of synced file versions
+//!
+//! Contents are stored by the storage backend under
+//! `sync/<user>/<xx>/<checksum>`, so versions with the same content share
+//! one blob; the database maps each
+//! version of a path to its checksum, whether it was uploaded or came from a
+//! move or a copy.
+
+use crate::config::database::DatabasePool;
+use crate::storage::StorageBackend;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgPool, Postgres, Transaction};
+use std::io;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// One stored version of a path.
+#[derive(Debug, Clone, FromRow)]
+pub struct FileVersion {
+    pub path: String,
+    pub version: i64,
+    pub checksum: String,
+    pub size: i64,
+    pub device_id: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+pub struct ContentStore {
+    pool: PgPool,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl ContentStore {
+    pub fn new(db: &DatabasePool, storage: &Arc<dyn StorageBackend>) -> Self {
+        Self {
+            pool: db.pool().clone(),
+            storage: storage.clone(),
+        }
+    }
+
+    fn blob_key(user_id: Uuid, checksum: &str) -> String {
+        let prefix = checksum.get(..2).unwrap_or("__");
+        format!("sync/{}/{}/{}", user_id, prefix, checksum)
+    }
+
+    pub async fn read(&self, user_id: Uuid, checksum: &str) -> io::Result<Vec<u8>> {
+        self.storage.read(&Self::blob_key(user_id, checksum)).await
+    }
+
+    /// At most `len` bytes of the content from `offset`.
+    pub async fn read_range(
+        &self,
+        user_id: Uuid,
+        checksum: &str,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Vec<u8>> {
+        self.storage
+            .read_range(&Self::blob_key(user_id, checksum), offset, len)
+            .await
+    }
+
+    /// Store `data` under `checksum`, which the caller computed from it.
+    /// Existing blobs are left alone.
+    pub async fn write(&self, user_id: Uuid, checksum: &str, data: &[u8]) -> io::Result<()> {
+        let key = Self::blob_key(user_id, checksum);
+        if self.storage.exists(&key).await? {
+            return Ok(());
+        }
+        self.storage.write(&key, data).await
+    }
+
+    /// The given version of a path, or its latest uploaded version.
+    pub async fn version(
+        &self,
+        user_id: Uuid,
+        path: &str,
+        version: Option<i64>,
+    ) -> Result<Option<FileVersion>, sqlx::Error> {
+        sqlx::query_as::<_, FileVersion>(
+            "SELECT path, version, checksum, size, device_id, created_at
+             FROM sync_file_versions
+             WHERE user_id = $1 AND path = $2 AND ($3::BIGINT IS NULL OR version = $3)
+             ORDER BY version DESC
+             LIMIT 1",
+        )
+        .bind(user_id)
+        .bind(path)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Every stored version of a path, latest first.
+    pub async fn versions(
+        &self,
+        user_id: Uuid,
+        path: &str,
+    ) -> Result<Vec<FileVersion>, sqlx::Error> {
+        sqlx::query_as::<_, FileVersion>(
+            "SELECT path, version, checksum, size, device_id, created_at
+             FROM sync_file_versions
+             WHERE user_id = $1 AND path = $2
+             ORDER BY version DESC",
+        )
+        .bind(user_id)
+        .bind(path)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn add_version(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        version: &FileVersion,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO sync_file_versions
+                 (user_id, path, version, checksum, size, device_id, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (user_id, path, version) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(&version.path)
+        .bind(version.version)
+        .bind(&version.checksum)
+        .bind(version.size)
+        .bind(version.device_id)
+        .bind(version.created_at)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Give `version` of `path` the content already stored under
+    /// `checksum` for another path, as after a move or a copy. Does nothing
+    /// when no version has that content.
+    pub async fn link_version(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        device_id: Uuid,
+        path: &str,
+        version: i64,
+        checksum: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO sync_file_versions
+                 (user_id, path, version, checksum, size, device_id, created_at)
+             SELECT user_id, $2, $3, checksum, size, $4, NOW()
+             FROM sync_file_versions
+             WHERE user_id = $1 AND checksum = $5
+             LIMIT 1
+             ON CONFLICT (user_id, path, version) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(path)
+        .bind(version)
+        .bind(device_id)
+        .bind(checksum)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
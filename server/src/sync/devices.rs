@@ -0,0 +1,246 @@
+//! Device registry persistence and device checks for sync endpoints
+
+use crate::auth::AuthenticatedUser;
+use crate::config::database::DatabasePool;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
+use protocol::errors::{ApiError, ErrorResponse};
+use protocol::sync::{Device, DeviceType, RegisterDeviceRequest, SyncFolder};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(FromRow)]
+struct DeviceRow {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    device_type: Json<DeviceType>,
+    platform: String,
+    version: String,
+    sync_folders: Json<Vec<SyncFolder>>,
+    last_seen: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+    wipe_requested_at: Option<DateTime<Utc>>,
+    public_key: Option<String>,
+}
+
+impl From<DeviceRow> for Device {
+    fn from(row: DeviceRow) -> Self {
+        Device {
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            device_type: row.device_type.0,
+            platform: row.platform,
+            version: row.version,
+            last_seen: row.last_seen,
+            is_active: row.revoked_at.is_none(),
+            sync_folders: row.sync_folders.0,
+            wipe_requested: row.wipe_requested_at.is_some(),
+            public_key: row.public_key,
+        }
+    }
+}
+
+const DEVICE_COLUMNS: &str = "id, user_id, name, device_type, platform, version, sync_folders,
+     last_seen, revoked_at, wipe_requested_at, public_key";
+
+pub struct DeviceStore {
+    pool: PgPool,
+}
+
+impl DeviceStore {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    pub async fn register(
+        &self,
+        user_id: Uuid,
+        req: &RegisterDeviceRequest,
+    ) -> Result<Device, sqlx::Error> {
+        let row = sqlx::query_as::<_, DeviceRow>(&format!(
+            "INSERT INTO devices
+                 (id, user_id, name, device_type, platform, version, sync_folders, public_key)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             RETURNING {}",
+            DEVICE_COLUMNS
+        ))
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(req.name.trim())
+        .bind(Json(req.device_type))
+        .bind(&req.platform)
+        .bind(&req.version)
+        .bind(Json(&req.sync_folders))
+        .bind(&req.public_key)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.into())
+    }
+
+    pub async fn get(&self, device_id: Uuid) -> Result<Option<Device>, sqlx::Error> {
+        let row = sqlx::query_as::<_, DeviceRow>(&format!(
+            "SELECT {} FROM devices WHERE id = $1",
+            DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Device::from))
+    }
+
+    /// A user's devices, most recently seen first.
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<Device>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, DeviceRow>(&format!(
+            "SELECT {} FROM devices WHERE user_id = $1 ORDER BY last_seen DESC",
+            DEVICE_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Device::from).collect())
+    }
+
+    /// Change the given fields, leaving the others as they are. A public key
+    /// is only ever set once.
+    pub async fn update(
+        &self,
+        device_id: Uuid,
+        name: Option<&str>,
+        version: Option<&str>,
+        sync_folders: Option<&[SyncFolder]>,
+        public_key: Option<&str>,
+    ) -> Result<Option<Device>, sqlx::Error> {
+        let row = sqlx::query_as::<_, DeviceRow>(&format!(
+            "UPDATE devices SET
+                 name = COALESCE($2, name),
+                 version = COALESCE($3, version),
+                 sync_folders = COALESCE($4, sync_folders),
+                 public_key = COALESCE(public_key, $5)
+             WHERE id = $1
+             RETURNING {}",
+            DEVICE_COLUMNS
+        ))
+        .bind(device_id)
+        .bind(name)
+        .bind(version)
+        .bind(sync_folders.map(Json))
+        .bind(public_key)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Device::from))
+    }
+
+    pub async fn touch(&self, device_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE devices SET last_seen = now() WHERE id = $1")
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn revoke(&self, device_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE devices SET revoked_at = COALESCE(revoked_at, now()) WHERE id = $1")
+            .bind(device_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn request_wipe(&self, device_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE devices SET wipe_requested_at = COALESCE(wipe_requested_at, now())
+             WHERE id = $1",
+        )
+        .bind(device_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Record that the device deleted its sync folders, revoking it.
+    pub async fn confirm_wipe(&self, device_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE devices SET wiped_at = now(), revoked_at = COALESCE(revoked_at, now())
+             WHERE id = $1 AND wipe_requested_at IS NOT NULL",
+        )
+        .bind(device_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+pub fn error_response(status: StatusCode, error: ApiError) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse::new(error))
+}
+
+fn internal_error(context: &str, e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"error": format!("{}: {}", context, e)}))
+}
+
+/// Load one of the caller's devices; the error is the response to return.
+pub async fn owned_device(
+    store: &DeviceStore,
+    user: &AuthenticatedUser,
+    device_id: Uuid,
+) -> Result<Device, HttpResponse> {
+    match store.get(device_id).await {
+        Ok(Some(device)) if device.user_id == user.user_id => Ok(device),
+        Ok(_) => Err(error_response(
+            StatusCode::NOT_FOUND,
+            ApiError::DeviceNotFound,
+        )),
+        Err(e) => Err(internal_error("Failed to load device", e)),
+    }
+}
+
+/// Admit `device_id` to a sync endpoint: it must be one of the caller's
+/// registered devices, not revoked and without a pending wipe, and a
+/// device-scoped token must belong to it. Marks the device as seen.
+pub async fn authorize_sync(
+    store: &DeviceStore,
+    user: &AuthenticatedUser,
+    device_id: Uuid,
+) -> Result<Device, HttpResponse> {
+    if user.device_id.is_some_and(|id| id != device_id) {
+        return Err(error_response(StatusCode::FORBIDDEN, ApiError::Forbidden));
+    }
+    let device = owned_device(store, user, device_id).await?;
+    if !device.is_active {
+        return Err(error_response(
+            StatusCode::FORBIDDEN,
+            ApiError::DeviceRevoked,
+        ));
+    }
+    if device.wipe_requested {
+        return Err(error_response(StatusCode::GONE, ApiError::WipeRequested));
+    }
+    if let Err(e) = store.touch(device_id).await {
+        return Err(internal_error("Failed to update device", e));
+    }
+    Ok(device)
+}
+
+/// Refuse device-scoped tokens whose device has been revoked.
+pub async fn check_token(
+    store: &DeviceStore,
+    user: &AuthenticatedUser,
+) -> Result<(), HttpResponse> {
+    let Some(device_id) = user.device_id else {
+        return Ok(());
+    };
+    match owned_device(store, user, device_id).await {
+        Ok(device) if device.is_active => Ok(()),
+        Ok(_) | Err(_) => Err(error_response(
+            StatusCode::FORBIDDEN,
+            ApiError::DeviceRevoked,
+        )),
+    }
+}
@@ -0,0 +1,602 @@
+//! End-to-end encrypted folders
+//!
+//! A user turns an empty folder into an encrypted one by giving the server
+//! a new folder key sealed for their device (see [`crypto::e2ee`]). The
+//! devices then encrypt the names and contents below it, and the server
+//! only ever holds ciphertext: uploads there that are not encrypted content
+//! are refused, as are moves and copies across the folder's boundary.
+//!
+//! Each device is handed the key sealed for it. A device holding the key
+//! seals it for the account's devices that lack it once the user approved
+//! them, as the public keys listed here are only as trustworthy as the
+//! server. The owner shares the folder by sealing it for another user's
+//! devices, after which that user can list and download the folder's
+//! files, still encrypted. The account's recovery key, if it has one, is
+//! handed folder keys like a device (see [`super::recovery`]).
+
+use super::content::ContentStore;
+use super::devices::{self, DeviceStore};
+use super::files::find_version;
+use super::journal::SyncJournal;
+use super::recovery::RecoveryKeyStore;
+use super::{normalize_path, SyncFailure};
+use crate::auth::AuthenticatedUser;
+use crate::config::database::DatabasePool;
+use crate::AppState;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use protocol::sync::{
+    AddFolderKeysRequest, CreateEncryptedFolderRequest, DeviceKey, EncryptedFolder, SealedFolderKey,
+};
+use serde::Deserialize;
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+#[derive(Debug, FromRow)]
+struct FolderRow {
+    id: Uuid,
+    owner_id: Uuid,
+    path: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct ContentQuery {
+    path: String,
+}
+
+fn contains(root: &str, path: &str) -> bool {
+    path == root
+        || path
+            .strip_prefix(root)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The encrypted folder of `roots` that `path` is in, if any.
+pub(super) fn covering<'a>(roots: &'a [String], path: &str) -> Option<&'a str> {
+    roots
+        .iter()
+        .map(String::as_str)
+        .find(|root| contains(root, path))
+}
+
+/// Refuse a move or copy from `from` to `to` that crosses the boundary of
+/// an encrypted folder: names would end up in the clear or unreadable.
+pub(super) fn check_boundary(roots: &[String], from: &str, to: &str) -> Result<(), SyncFailure> {
+    if covering(roots, from) != covering(roots, to) {
+        return Err(SyncFailure::BadRequest(format!(
+            "{} cannot be moved or copied to {} across an encrypted folder",
+            from, to
+        )));
+    }
+    Ok(())
+}
+
+/// Refuse content for `path` that is not encrypted when the path is in an
+/// encrypted folder.
+pub(super) async fn check_content<'e>(
+    executor: impl PgExecutor<'e>,
+    user_id: Uuid,
+    path: &str,
+    data: &[u8],
+) -> Result<(), SyncFailure> {
+    let roots = EncryptedFolderStore::roots(executor, user_id).await?;
+    match covering(&roots, path) {
+        Some(root) if !crypto::is_encrypted_content(data) => Err(SyncFailure::Invalid(format!(
+            "Files in the encrypted folder {} must be uploaded encrypted",
+            root
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub struct EncryptedFolderStore {
+    pool: PgPool,
+}
+
+impl EncryptedFolderStore {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    /// Paths of the folders a user encrypted.
+    pub async fn roots<'e>(
+        executor: impl PgExecutor<'e>,
+        owner_id: Uuid,
+    ) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT path FROM encrypted_folders WHERE owner_id = $1")
+            .bind(owner_id)
+            .fetch_all(executor)
+            .await
+    }
+
+    async fn get(&self, folder_id: Uuid) -> Result<Option<FolderRow>, sqlx::Error> {
+        sqlx::query_as::<_, FolderRow>(
+            "SELECT id, owner_id, path, created_at FROM encrypted_folders WHERE id = $1",
+        )
+        .bind(folder_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Folders the user owns or holds a key to, in path order.
+    async fn visible(&self, user_id: Uuid) -> Result<Vec<FolderRow>, sqlx::Error> {
+        sqlx::query_as::<_, FolderRow>(
+            "SELECT id, owner_id, path, created_at FROM encrypted_folders f
+             WHERE owner_id = $1
+                OR EXISTS (SELECT 1 FROM encrypted_folder_keys k
+                           WHERE k.folder_id = f.id AND k.user_id = $1)
+             ORDER BY path, id",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn is_member(&self, folder: &FolderRow, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        if folder.owner_id == user_id {
+            return Ok(true);
+        }
+        sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM encrypted_folder_keys
+                            WHERE folder_id = $1 AND user_id = $2)",
+        )
+        .bind(folder.id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    async fn sealed_key(
+        &self,
+        folder_id: Uuid,
+        device_id: Uuid,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT sealed_key FROM encrypted_folder_keys WHERE folder_id = $1 AND device_id = $2",
+        )
+        .bind(folder_id)
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn keyed_devices(&self, folder_id: Uuid) -> Result<HashSet<Uuid>, sqlx::Error> {
+        let devices: Vec<Uuid> =
+            sqlx::query_scalar("SELECT device_id FROM encrypted_folder_keys WHERE folder_id = $1")
+                .bind(folder_id)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(devices.into_iter().collect())
+    }
+
+    async fn create(
+        &self,
+        owner_id: Uuid,
+        path: &str,
+        keys: &[(SealedFolderKey, Uuid)],
+        sealed_by: Option<Uuid>,
+    ) -> Result<FolderRow, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let folder = sqlx::query_as::<_, FolderRow>(
+            "INSERT INTO encrypted_folders (id, owner_id, path) VALUES ($1, $2, $3)
+             RETURNING id, owner_id, path, created_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(owner_id)
+        .bind(path)
+        .fetch_one(&mut *tx)
+        .await?;
+        Self::insert_keys(&mut tx, folder.id, keys, sealed_by).await?;
+        tx.commit().await?;
+        Ok(folder)
+    }
+
+    /// Store sealed keys, keeping those devices already have.
+    async fn insert_keys(
+        conn: &mut PgConnection,
+        folder_id: Uuid,
+        keys: &[(SealedFolderKey, Uuid)],
+        sealed_by: Option<Uuid>,
+    ) -> Result<(), sqlx::Error> {
+        for (key, user_id) in keys {
+            sqlx::query(
+                "INSERT INTO encrypted_folder_keys
+                     (folder_id, device_id, user_id, sealed_key, sealed_by)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (folder_id, device_id) DO NOTHING",
+            )
+            .bind(folder_id)
+            .bind(key.device_id)
+            .bind(user_id)
+            .bind(&key.sealed_key)
+            .bind(sealed_by)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn add_keys(
+        &self,
+        folder_id: Uuid,
+        keys: &[(SealedFolderKey, Uuid)],
+        sealed_by: Option<Uuid>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        Self::insert_keys(&mut tx, folder_id, keys, sealed_by).await?;
+        tx.commit().await
+    }
+
+    /// Forget the keys of a user's devices. Returns whether there were any.
+    async fn remove_member(&self, folder_id: Uuid, user_id: Uuid) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("DELETE FROM encrypted_folder_keys WHERE folder_id = $1 AND user_id = $2")
+                .bind(folder_id)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Active devices of a user that have a public key.
+async fn device_keys(db: &DatabasePool, user_id: Uuid) -> Result<Vec<DeviceKey>, sqlx::Error> {
+    let mut keys: Vec<DeviceKey> = DeviceStore::new(db)
+        .list(user_id)
+        .await?
+        .into_iter()
+        .filter(|device| device.is_active)
+        .filter_map(|device| {
+            Some(DeviceKey {
+                device_id: device.id,
+                user_id: device.user_id,
+                public_key: device.public_key?,
+            })
+        })
+        .collect();
+    if let Some(recovery) = RecoveryKeyStore::new(db).get(user_id).await? {
+        keys.push(DeviceKey {
+            device_id: recovery.id,
+            user_id,
+            public_key: recovery.public_key,
+        });
+    }
+    Ok(keys)
+}
+
+/// Check that each key is for an active device with a public key or for a
+/// recovery key, and pair it with its user.
+async fn key_owners(
+    db: &DatabasePool,
+    keys: &[SealedFolderKey],
+) -> Result<Vec<(SealedFolderKey, Uuid)>, SyncFailure> {
+    let store = DeviceStore::new(db);
+    let recovery = RecoveryKeyStore::new(db);
+    let mut owned = Vec::with_capacity(keys.len());
+    for key in keys {
+        let sealed = crypto::from_base64(&key.sealed_key)
+            .map_err(|_| SyncFailure::BadRequest("Invalid sealed key".into()))?;
+        if sealed.len() != crypto::SEALED_KEY_LEN {
+            return Err(SyncFailure::BadRequest("Invalid sealed key".into()));
+        }
+        let owner = match store.get(key.device_id).await? {
+            Some(device) if device.is_active && device.public_key.is_some() => Some(device.user_id),
+            Some(_) => None,
+            None => recovery.owner(key.device_id).await?,
+        };
+        match owner {
+            Some(user_id) => owned.push((key.clone(), user_id)),
+            None => {
+                return Err(SyncFailure::Invalid(format!(
+                    "Device {} has no public key",
+                    key.device_id
+                )))
+            }
+        }
+    }
+    Ok(owned)
+}
+
+async fn folder_view(
+    folders: &EncryptedFolderStore,
+    db: &DatabasePool,
+    user: &AuthenticatedUser,
+    folder: FolderRow,
+) -> Result<EncryptedFolder, SyncFailure> {
+    let sealed_key = match user.device_id {
+        Some(device_id) => folders.sealed_key(folder.id, device_id).await?,
+        None => None,
+    };
+    let keyed = folders.keyed_devices(folder.id).await?;
+    let pending_devices = device_keys(db, user.user_id)
+        .await?
+        .into_iter()
+        .filter(|device| !keyed.contains(&device.device_id))
+        .collect();
+    Ok(EncryptedFolder {
+        id: folder.id,
+        owner_id: folder.owner_id,
+        path: folder.path,
+        created_at: folder.created_at,
+        sealed_key,
+        pending_devices,
+    })
+}
+
+/// A folder the caller owns or was given the key to.
+async fn member_folder(
+    folders: &EncryptedFolderStore,
+    user: &AuthenticatedUser,
+    folder_id: Uuid,
+) -> Result<FolderRow, SyncFailure> {
+    match folders.get(folder_id).await? {
+        Some(folder) if folders.is_member(&folder, user.user_id).await? => Ok(folder),
+        _ => Err(SyncFailure::NotFound("Encrypted folder not found".into())),
+    }
+}
+
+/// Public keys of a user's active devices, to seal folder keys for.
+pub async fn list_device_keys(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::check_token(&store, &user).await {
+        return Ok(response);
+    }
+    match device_keys(&data.db_pool, path.into_inner()).await {
+        Ok(keys) => Ok(HttpResponse::Ok().json(keys)),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+async fn list(
+    data: &AppState,
+    user: &AuthenticatedUser,
+) -> Result<Vec<EncryptedFolder>, SyncFailure> {
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let mut views = Vec::new();
+    for folder in folders.visible(user.user_id).await? {
+        views.push(folder_view(&folders, &data.db_pool, user, folder).await?);
+    }
+    Ok(views)
+}
+
+/// Encrypted folders the caller owns or was given, with the key sealed
+/// for the calling device.
+pub async fn list_folders(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match list(&data, &user).await {
+        Ok(folders) => Ok(HttpResponse::Ok().json(folders)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+async fn create(
+    data: &AppState,
+    user: &AuthenticatedUser,
+    request: &CreateEncryptedFolderRequest,
+) -> Result<EncryptedFolder, SyncFailure> {
+    let path = normalize_path(&request.path)
+        .ok_or_else(|| SyncFailure::BadRequest("Invalid path".into()))?;
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let roots = EncryptedFolderStore::roots(&folders.pool, user.user_id).await?;
+    if let Some(root) = roots
+        .iter()
+        .find(|root| contains(root, &path) || contains(&path, root))
+    {
+        return Err(SyncFailure::Conflict(format!(
+            "{} overlaps the encrypted folder {}",
+            path, root
+        )));
+    }
+    let existing = SyncJournal::new(&data.db_pool)
+        .list(user.user_id, Some(&path), false)
+        .await?;
+    if !existing.is_empty() {
+        return Err(SyncFailure::Conflict(format!(
+            "{} already has unencrypted files",
+            path
+        )));
+    }
+    let keys = key_owners(&data.db_pool, &request.keys).await?;
+    if keys.is_empty() || keys.iter().any(|(_, owner)| *owner != user.user_id) {
+        return Err(SyncFailure::BadRequest(
+            "The folder key must be sealed for the owner's devices only".into(),
+        ));
+    }
+    let folder = folders
+        .create(user.user_id, &path, &keys, user.device_id)
+        .await?;
+    folder_view(&folders, &data.db_pool, user, folder).await
+}
+
+/// Make an empty folder an encrypted one.
+pub async fn create_folder(
+    user: AuthenticatedUser,
+    req: web::Json<CreateEncryptedFolderRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match create(&data, &user, &req).await {
+        Ok(folder) => Ok(HttpResponse::Created().json(folder)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+async fn add_keys(
+    data: &AppState,
+    user: &AuthenticatedUser,
+    folder_id: Uuid,
+    request: &AddFolderKeysRequest,
+) -> Result<(), SyncFailure> {
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let folder = member_folder(&folders, user, folder_id).await?;
+    let keys = key_owners(&data.db_pool, &request.keys).await?;
+    // Members add their own devices; only the owner shares the folder.
+    if folder.owner_id != user.user_id && keys.iter().any(|(_, owner)| *owner != user.user_id) {
+        return Err(SyncFailure::Forbidden(
+            "Only the owner can share an encrypted folder".into(),
+        ));
+    }
+    folders.add_keys(folder.id, &keys, user.device_id).await?;
+    Ok(())
+}
+
+/// Give devices the folder key, sharing the folder with their users.
+pub async fn add_folder_keys(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    req: web::Json<AddFolderKeysRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match add_keys(&data, &user, path.into_inner(), &req).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+async fn remove_member(
+    data: &AppState,
+    user: &AuthenticatedUser,
+    folder_id: Uuid,
+    member_id: Uuid,
+) -> Result<(), SyncFailure> {
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let folder = member_folder(&folders, user, folder_id).await?;
+    if folder.owner_id != user.user_id {
+        return Err(SyncFailure::Forbidden(
+            "Only the owner can stop sharing an encrypted folder".into(),
+        ));
+    }
+    if member_id == folder.owner_id {
+        return Err(SyncFailure::BadRequest(
+            "The owner cannot be removed from their folder".into(),
+        ));
+    }
+    if !folders.remove_member(folder.id, member_id).await? {
+        return Err(SyncFailure::NotFound(format!(
+            "The folder is not shared with {}",
+            member_id
+        )));
+    }
+    Ok(())
+}
+
+/// Stop sharing a folder with a user. What they already decrypted stays
+/// readable to them; files written from now on are too, until the folder is
+/// moved to a new key, as they keep the old one.
+pub async fn remove_folder_member(
+    path: web::Path<(Uuid, Uuid)>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let (folder_id, member_id) = path.into_inner();
+    match remove_member(&data, &user, folder_id, member_id).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// Files of an encrypted folder, for its owner and the users it is shared
+/// with, with their paths in the owner's files.
+pub async fn list_folder_files(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let folder = match member_folder(&folders, &user, path.into_inner()).await {
+        Ok(folder) => folder,
+        Err(failure) => return Ok(failure.into_response()),
+    };
+    match SyncJournal::new(&data.db_pool)
+        .list(folder.owner_id, Some(&folder.path), false)
+        .await
+    {
+        Ok(entries) => Ok(HttpResponse::Ok().json(
+            entries
+                .iter()
+                .map(|entry| entry.file_state())
+                .collect::<Vec<_>>(),
+        )),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+/// Encrypted content of a file in an encrypted folder.
+pub async fn download_folder_file(
+    path: web::Path<Uuid>,
+    query: web::Query<ContentQuery>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let folders = EncryptedFolderStore::new(&data.db_pool);
+    let folder = match member_folder(&folders, &user, path.into_inner()).await {
+        Ok(folder) => folder,
+        Err(failure) => return Ok(failure.into_response()),
+    };
+    let in_folder = normalize_path(&query.path).is_some_and(|path| contains(&folder.path, &path));
+    if !in_folder {
+        return Ok(
+            SyncFailure::NotFound(format!("{} is not in the encrypted folder", query.path))
+                .into_response(),
+        );
+    }
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    match find_version(&store, folder.owner_id, &query.path, None).await {
+        Ok((version, content)) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((header::ETAG, format!("\"{}\"", version.checksum)))
+            .insert_header(("X-File-Version", version.version.to_string()))
+            .body(content)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/keys/{user_id}", web::get().to(list_device_keys))
+        .route("/sync/encrypted", web::get().to(list_folders))
+        .route("/sync/encrypted", web::post().to(create_folder))
+        .route(
+            "/sync/encrypted/{folder_id}/keys",
+            web::post().to(add_folder_keys),
+        )
+        .route(
+            "/sync/encrypted/{folder_id}/members/{user_id}",
+            web::delete().to(remove_folder_member),
+        )
+        .route(
+            "/sync/encrypted/{folder_id}/files",
+            web::get().to(list_folder_files),
+        )
+        .route(
+            "/sync/encrypted/{folder_id}/content",
+            web::get().to(download_folder_file),
+        );
+}
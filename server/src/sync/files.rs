@@ -0,0 +1,513 @@
+//! File content transfer for sync
+//!
+//! Besides whole uploads and downloads, a modified file can be sent as a
+//! delta: the device fetches the block signature of the version it has,
+//! diffs its copy against it and uploads only the blocks the server lacks,
+//! either as protocol JSON or in the compact binary encoding. Signatures use
+//! the sync engine's default chunk size.
+
+use super::content::{ContentStore, FileVersion};
+use super::devices::{self, DeviceStore};
+use super::encrypted;
+use super::journal::{EntryUpdate, JournalEntry, SyncJournal};
+use super::{normalize_path, SyncFailure};
+use crate::auth::AuthenticatedUser;
+use crate::AppState;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use crypto::FileHasher;
+use futures::StreamExt;
+use protocol::sync::{ChangeType, DeltaUploadRequest};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use sync_core::{DeltaApplier, FileDelta, FileSignature, SyncError, SyncOptions};
+use uuid::Uuid;
+
+/// Largest accepted delta request body, JSON or binary.
+const MAX_DELTA_BODY: usize = 256 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    path: String,
+    /// Latest uploaded version when absent.
+    version: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    device_id: Uuid,
+    path: String,
+    /// Version being replaced; absent when creating the file.
+    base_version: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SignatureResponse {
+    path: String,
+    version: u64,
+    signature: FileSignature,
+}
+
+fn internal_error(e: impl std::fmt::Display) -> HttpResponse {
+    HttpResponse::InternalServerError().json(serde_json::json!({"error": e.to_string()}))
+}
+
+fn version_number(version: u64) -> i64 {
+    i64::try_from(version).unwrap_or(i64::MAX)
+}
+
+async fn lookup_version(
+    store: &ContentStore,
+    user_id: Uuid,
+    path: &str,
+    version: Option<u64>,
+) -> Result<FileVersion, SyncFailure> {
+    let path =
+        normalize_path(path).ok_or_else(|| SyncFailure::BadRequest("Invalid path".into()))?;
+    store
+        .version(user_id, &path, version.map(version_number))
+        .await?
+        .ok_or_else(|| SyncFailure::NotFound(format!("No stored version of {}", path)))
+}
+
+pub(super) async fn find_version(
+    store: &ContentStore,
+    user_id: Uuid,
+    path: &str,
+    version: Option<u64>,
+) -> Result<(FileVersion, Vec<u8>), SyncFailure> {
+    let found = lookup_version(store, user_id, path, version).await?;
+    let data = store.read(user_id, &found.checksum).await?;
+    Ok((found, data))
+}
+
+/// Record `data` as the new content of `path`, provided the file is still
+/// at `base_version` (or does not exist when creating it). Content for an
+/// encrypted folder has to be encrypted.
+#[allow(clippy::too_many_arguments)]
+async fn store_version(
+    journal: &SyncJournal,
+    store: &ContentStore,
+    user_id: Uuid,
+    device_id: Uuid,
+    path: &str,
+    base_version: Option<u64>,
+    data: &[u8],
+    modified_at: DateTime<Utc>,
+) -> Result<JournalEntry, SyncFailure> {
+    let checksum = FileHasher::hash_bytes(data);
+    let (mut tx, _) = journal.begin(user_id).await?;
+    encrypted::check_content(&mut *tx, user_id, path, data).await?;
+    let current = SyncJournal::entry(&mut tx, user_id, path)
+        .await?
+        .filter(|entry| !entry.deleted);
+    let change = match (&current, base_version) {
+        (None, None) => ChangeType::Created,
+        (Some(entry), Some(base)) if entry.version == version_number(base) => ChangeType::Modified,
+        (Some(entry), _) => {
+            return Err(SyncFailure::Conflict(format!(
+                "{} is at version {}",
+                path, entry.version
+            )))
+        }
+        (None, Some(_)) => return Err(SyncFailure::Conflict(format!("{} was deleted", path))),
+    };
+
+    store.write(user_id, &checksum, data).await?;
+    let size = i64::try_from(data.len()).unwrap_or(i64::MAX);
+    let entry = SyncJournal::record(
+        &mut tx,
+        user_id,
+        device_id,
+        &EntryUpdate {
+            path,
+            checksum: &checksum,
+            size,
+            modified_at,
+            deleted: false,
+            moved_to: None,
+            metadata: None,
+            change: &change,
+        },
+    )
+    .await?;
+    ContentStore::add_version(
+        &mut tx,
+        user_id,
+        &FileVersion {
+            path: path.to_string(),
+            version: entry.version,
+            checksum,
+            size,
+            device_id,
+            created_at: Utc::now(),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(entry)
+}
+
+/// Rebuild the uploaded file from its base content, checking the result
+/// against the checksum the device announced.
+fn apply_delta(
+    base: &[u8],
+    request: &DeltaUploadRequest,
+    max_size: u64,
+) -> Result<Vec<u8>, SyncFailure> {
+    let options = SyncOptions::default();
+    if request.delta.total_size > max_size {
+        return Err(SyncFailure::Invalid(format!(
+            "File exceeds the {} byte limit",
+            max_size
+        )));
+    }
+    let signature = FileSignature::compute(&mut Cursor::new(base), options.chunk_size)
+        .map_err(|e| SyncFailure::Storage(std::io::Error::other(e.to_string())))?;
+    let delta = FileDelta::from_wire(&request.delta, &signature, &request.target_checksum)
+        .map_err(|e| SyncFailure::BadRequest(e.to_string()))?;
+
+    let applier = DeltaApplier::new(options);
+    let mut result = Vec::with_capacity(request.delta.total_size as usize);
+    applier
+        .apply_delta(Cursor::new(base), &mut result, &delta)
+        .map_err(|e| match e {
+            SyncError::Io(e) => SyncFailure::BadRequest(format!("Delta does not apply: {}", e)),
+            e => SyncFailure::BadRequest(e.to_string()),
+        })?;
+    verify(&applier, result, &delta.target_checksum)
+}
+
+/// Rebuild a file uploaded as a binary encoded delta, which names the
+/// checksum of its base.
+fn apply_encoded_delta(
+    base: &FileVersion,
+    content: &[u8],
+    encoded: &[u8],
+    max_size: u64,
+) -> Result<Vec<u8>, SyncFailure> {
+    let delta = FileDelta::decode(encoded, max_size).map_err(|e| match e {
+        SyncError::FileTooLarge { .. } => {
+            SyncFailure::Invalid(format!("Delta exceeds the {} byte limit", max_size))
+        }
+        e => SyncFailure::BadRequest(e.to_string()),
+    })?;
+    if delta.source_checksum != base.checksum {
+        return Err(SyncFailure::Conflict(format!(
+            "Delta was not computed against version {}",
+            base.version
+        )));
+    }
+
+    let applier = DeltaApplier::new(SyncOptions::default());
+    let mut result = Vec::new();
+    applier
+        .apply_delta(Cursor::new(content), &mut result, &delta)
+        .map_err(|e| SyncFailure::BadRequest(format!("Delta does not apply: {}", e)))?;
+    if result.len() as u64 > max_size {
+        return Err(SyncFailure::Invalid(format!(
+            "File exceeds the {} byte limit",
+            max_size
+        )));
+    }
+    verify(&applier, result, &delta.target_checksum)
+}
+
+fn verify(
+    applier: &DeltaApplier,
+    result: Vec<u8>,
+    target_checksum: &str,
+) -> Result<Vec<u8>, SyncFailure> {
+    let verified = applier
+        .verify_delta_application(Cursor::new(&result), target_checksum)
+        .map_err(|e| SyncFailure::Invalid(e.to_string()))?;
+    if !verified {
+        return Err(SyncFailure::Invalid(
+            "Checksum mismatch after applying the delta".into(),
+        ));
+    }
+    Ok(result)
+}
+
+/// Block signature of a stored version, to compute a delta against.
+pub async fn get_signature(
+    user: AuthenticatedUser,
+    query: web::Query<VersionQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    let (version, content) =
+        match find_version(&store, user.user_id, &query.path, query.version).await {
+            Ok(found) => found,
+            Err(failure) => return Ok(failure.into_response()),
+        };
+    let block_size = SyncOptions::default().chunk_size;
+    let signature =
+        match web::block(move || FileSignature::compute(&mut Cursor::new(content), block_size))
+            .await
+        {
+            Ok(Ok(signature)) => signature,
+            Ok(Err(e)) => return Ok(internal_error(e)),
+            Err(e) => return Ok(internal_error(e)),
+        };
+    Ok(HttpResponse::Ok().json(SignatureResponse {
+        path: version.path,
+        version: version.version.max(0) as u64,
+        signature,
+    }))
+}
+
+/// Byte range asked for by a `Range: bytes=start-[end]` header, clamped to
+/// `len`. `Err` when the range cannot be satisfied; other range forms are
+/// ignored and the whole file is sent.
+fn requested_range(req: &HttpRequest, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let Ok(start) = start.trim().parse::<u64>() else {
+        return Ok(None);
+    };
+    let end = match end.trim() {
+        "" => len.saturating_sub(1),
+        end => match end.parse::<u64>() {
+            Ok(end) => end.min(len.saturating_sub(1)),
+            Err(_) => return Ok(None),
+        },
+    };
+    if start >= len || end < start {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Content of a stored version. A `Range: bytes=N-` request, used to
+/// resume an interrupted download, gets the rest of the file with 206.
+pub async fn download(
+    user: AuthenticatedUser,
+    req: HttpRequest,
+    query: web::Query<VersionQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    let version = match lookup_version(&store, user.user_id, &query.path, query.version).await {
+        Ok(version) => version,
+        Err(failure) => return Ok(failure.into_response()),
+    };
+    let len = u64::try_from(version.size).unwrap_or(0);
+    let Ok(range) = requested_range(&req, len) else {
+        return Ok(HttpResponse::RangeNotSatisfiable()
+            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+            .finish());
+    };
+    // Only the requested range is read, and decrypted when stored
+    // encrypted.
+    let content = match range {
+        Some((start, end)) => {
+            store
+                .read_range(user.user_id, &version.checksum, start, end - start + 1)
+                .await
+        }
+        None => store.read(user.user_id, &version.checksum).await,
+    };
+    let content = match content {
+        Ok(content) => content,
+        Err(e) => return Ok(SyncFailure::from(e).into_response()),
+    };
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    response
+        .content_type("application/octet-stream")
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(("ETag", format!("\"{}\"", version.checksum)))
+        .insert_header(("X-File-Version", version.version.to_string()));
+    match range {
+        Some((start, end)) => Ok(response
+            .insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            ))
+            .body(content)),
+        None => Ok(response.body(content)),
+    }
+}
+
+/// Upload a whole file as a new version.
+pub async fn upload(
+    user: AuthenticatedUser,
+    query: web::Query<UploadQuery>,
+    mut payload: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) =
+        devices::authorize_sync(&DeviceStore::new(&data.db_pool), &user, query.device_id).await
+    {
+        return Ok(response);
+    }
+    let Some(path) = normalize_path(&query.path) else {
+        return Ok(SyncFailure::BadRequest("Invalid path".into()).into_response());
+    };
+
+    let max_size = data.config.storage.max_file_size;
+    let mut content = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        content.extend_from_slice(&chunk?);
+        if content.len() as u64 > max_size {
+            return Ok(HttpResponse::PayloadTooLarge().json(
+                serde_json::json!({"error": format!("File exceeds the {} byte limit", max_size)}),
+            ));
+        }
+    }
+
+    let journal = SyncJournal::new(&data.db_pool);
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    match store_version(
+        &journal,
+        &store,
+        user.user_id,
+        query.device_id,
+        &path,
+        query.base_version,
+        &content,
+        Utc::now(),
+    )
+    .await
+    {
+        Ok(entry) => Ok(HttpResponse::Ok().json(entry.file_state())),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// Upload a modified file as a delta against one of its stored versions.
+pub async fn upload_delta(
+    user: AuthenticatedUser,
+    req: web::Json<DeltaUploadRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) =
+        devices::authorize_sync(&DeviceStore::new(&data.db_pool), &user, req.device_id).await
+    {
+        return Ok(response);
+    }
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    let (base, content) =
+        match find_version(&store, user.user_id, &req.path, Some(req.base_version)).await {
+            Ok(found) => found,
+            Err(failure) => return Ok(failure.into_response()),
+        };
+
+    let request = req.into_inner();
+    let max_size = data.config.storage.max_file_size;
+    let (request, result) = match web::block(move || {
+        let result = apply_delta(&content, &request, max_size);
+        (request, result)
+    })
+    .await
+    {
+        Ok((request, Ok(result))) => (request, result),
+        Ok((_, Err(failure))) => return Ok(failure.into_response()),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let journal = SyncJournal::new(&data.db_pool);
+    match store_version(
+        &journal,
+        &store,
+        user.user_id,
+        request.device_id,
+        &base.path,
+        Some(request.base_version),
+        &result,
+        request.modified_at.unwrap_or_else(Utc::now),
+    )
+    .await
+    {
+        Ok(entry) => Ok(HttpResponse::Ok().json(entry.file_state())),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// Upload a modified file as a delta in its binary wire form (see
+/// [`sync_core::encoding`]), against the version named by `base_version`.
+pub async fn upload_encoded_delta(
+    user: AuthenticatedUser,
+    query: web::Query<UploadQuery>,
+    body: web::Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) =
+        devices::authorize_sync(&DeviceStore::new(&data.db_pool), &user, query.device_id).await
+    {
+        return Ok(response);
+    }
+    let Some(base_version) = query.base_version else {
+        return Ok(SyncFailure::BadRequest("base_version is required".into()).into_response());
+    };
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    let (base, content) =
+        match find_version(&store, user.user_id, &query.path, Some(base_version)).await {
+            Ok(found) => found,
+            Err(failure) => return Ok(failure.into_response()),
+        };
+
+    let max_size = data.config.storage.max_file_size;
+    let (base, result) = match web::block(move || {
+        let result = apply_encoded_delta(&base, &content, &body, max_size);
+        (base, result)
+    })
+    .await
+    {
+        Ok((base, Ok(result))) => (base, result),
+        Ok((_, Err(failure))) => return Ok(failure.into_response()),
+        Err(e) => return Ok(internal_error(e)),
+    };
+
+    let journal = SyncJournal::new(&data.db_pool);
+    match store_version(
+        &journal,
+        &store,
+        user.user_id,
+        query.device_id,
+        &base.path,
+        Some(base_version),
+        &result,
+        Utc::now(),
+    )
+    .await
+    {
+        Ok(entry) => Ok(HttpResponse::Ok().json(entry.file_state())),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/files/signature", web::get().to(get_signature))
+        .route("/sync/files/content", web::get().to(download))
+        .route("/sync/files/content", web::put().to(upload))
+        .service(
+            web::resource("/sync/files/delta")
+                .app_data(web::JsonConfig::default().limit(MAX_DELTA_BODY))
+                .route(web::post().to(upload_delta)),
+        )
+        .service(
+            web::resource("/sync/files/delta/binary")
+                .app_data(web::PayloadConfig::new(MAX_DELTA_BODY))
+                .route(web::post().to(upload_encoded_delta)),
+        );
+}
@@ -0,0 +1,278 @@
+//! Sync journal and per-device sync state persistence
+
+use crate::config::database::DatabasePool;
+use chrono::{DateTime, Utc};
+use protocol::file::FileMetadata;
+use protocol::sync::{ChangeType, FileState, SyncState, SyncStatus};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgExecutor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Latest state of one path in a user's journal.
+#[derive(Debug, Clone, FromRow)]
+pub struct JournalEntry {
+    pub path: String,
+    pub checksum: String,
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+    /// Incremented by every change to the path.
+    pub version: i64,
+    pub deleted: bool,
+    /// Where the file went, for tombstones left by a move.
+    pub moved_to: Option<String>,
+    pub metadata: Option<Json<FileMetadata>>,
+    pub change: Json<ChangeType>,
+    /// Journal sequence number of the last change.
+    pub sequence: i64,
+    /// Device that made the last change.
+    pub device_id: Uuid,
+}
+
+impl JournalEntry {
+    pub fn file_state(&self) -> FileState {
+        FileState {
+            path: self.path.clone(),
+            checksum: self.checksum.clone(),
+            size: self.size.max(0) as u64,
+            modified_at: self.modified_at,
+            version: self.version.max(0) as u64,
+            sync_status: if self.deleted {
+                SyncStatus::Deleted
+            } else {
+                SyncStatus::Synced
+            },
+        }
+    }
+}
+
+/// New state of a path, written by [`SyncJournal::record`].
+pub struct EntryUpdate<'a> {
+    pub path: &'a str,
+    pub checksum: &'a str,
+    pub size: i64,
+    pub modified_at: DateTime<Utc>,
+    pub deleted: bool,
+    pub moved_to: Option<&'a str>,
+    pub metadata: Option<&'a FileMetadata>,
+    pub change: &'a ChangeType,
+}
+
+#[derive(FromRow)]
+struct DeviceStateRow {
+    device_id: Uuid,
+    user_id: Uuid,
+    last_sync: DateTime<Utc>,
+    sync_token: String,
+    file_states: Json<HashMap<String, FileState>>,
+}
+
+impl From<DeviceStateRow> for SyncState {
+    fn from(row: DeviceStateRow) -> Self {
+        SyncState {
+            user_id: row.user_id,
+            device_id: row.device_id,
+            last_sync: row.last_sync,
+            sync_token: row.sync_token,
+            file_states: row.file_states.0,
+        }
+    }
+}
+
+const ENTRY_COLUMNS: &str = "path, checksum, size, modified_at, version, deleted, moved_to,
+     metadata, change, sequence, device_id";
+
+pub struct SyncJournal {
+    pool: PgPool,
+}
+
+impl SyncJournal {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Start a transaction holding the lock on the user's journal, so
+    /// batches from several devices apply one after the other. Returns the
+    /// journal's current sequence number.
+    pub async fn begin(
+        &self,
+        user_id: Uuid,
+    ) -> Result<(Transaction<'static, Postgres>, i64), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO sync_journals (user_id) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let sequence =
+            sqlx::query_scalar("SELECT sequence FROM sync_journals WHERE user_id = $1 FOR UPDATE")
+                .bind(user_id)
+                .fetch_one(&mut *tx)
+                .await?;
+        Ok((tx, sequence))
+    }
+
+    pub async fn entry(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        path: &str,
+    ) -> Result<Option<JournalEntry>, sqlx::Error> {
+        sqlx::query_as::<_, JournalEntry>(&format!(
+            "SELECT {} FROM sync_entries WHERE user_id = $1 AND path = $2",
+            ENTRY_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(path)
+        .fetch_optional(&mut **tx)
+        .await
+    }
+
+    /// The entry of a path as last committed, for reads outside a batch.
+    pub async fn current(
+        &self,
+        user_id: Uuid,
+        path: &str,
+    ) -> Result<Option<JournalEntry>, sqlx::Error> {
+        sqlx::query_as::<_, JournalEntry>(&format!(
+            "SELECT {} FROM sync_entries WHERE user_id = $1 AND path = $2",
+            ENTRY_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(path)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Write the new state of a path at the journal's next sequence number.
+    pub async fn record(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        device_id: Uuid,
+        update: &EntryUpdate<'_>,
+    ) -> Result<JournalEntry, sqlx::Error> {
+        let sequence: i64 = sqlx::query_scalar(
+            "UPDATE sync_journals SET sequence = sequence + 1 WHERE user_id = $1
+             RETURNING sequence",
+        )
+        .bind(user_id)
+        .fetch_one(&mut **tx)
+        .await?;
+        sqlx::query_as::<_, JournalEntry>(&format!(
+            "INSERT INTO sync_entries
+                 (user_id, path, checksum, size, modified_at, version, deleted, moved_to,
+                  metadata, change, sequence, device_id)
+             VALUES ($1, $2, $3, $4, $5, 1, $6, $7, $8, $9, $10, $11)
+             ON CONFLICT (user_id, path) DO UPDATE SET
+                 checksum = EXCLUDED.checksum,
+                 size = EXCLUDED.size,
+                 modified_at = EXCLUDED.modified_at,
+                 version = sync_entries.version + 1,
+                 deleted = EXCLUDED.deleted,
+                 moved_to = EXCLUDED.moved_to,
+                 metadata = EXCLUDED.metadata,
+                 change = EXCLUDED.change,
+                 sequence = EXCLUDED.sequence,
+                 device_id = EXCLUDED.device_id
+             RETURNING {}",
+            ENTRY_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(update.path)
+        .bind(update.checksum)
+        .bind(update.size)
+        .bind(update.modified_at)
+        .bind(update.deleted)
+        .bind(update.moved_to)
+        .bind(update.metadata.map(Json))
+        .bind(Json(update.change))
+        .bind(sequence)
+        .bind(device_id)
+        .fetch_one(&mut **tx)
+        .await
+    }
+
+    /// Entries changed after `since` by devices other than `device_id`, in
+    /// journal order.
+    pub async fn changes_since(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        since: i64,
+        device_id: Uuid,
+    ) -> Result<Vec<JournalEntry>, sqlx::Error> {
+        sqlx::query_as::<_, JournalEntry>(&format!(
+            "SELECT {} FROM sync_entries
+             WHERE user_id = $1 AND sequence > $2 AND device_id <> $3
+             ORDER BY sequence",
+            ENTRY_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(since)
+        .bind(device_id)
+        .fetch_all(&mut **tx)
+        .await
+    }
+
+    /// Live entries under `prefix` (every entry when `None`) in path
+    /// order; with `deleted`, the tombstones of deleted rather than moved
+    /// files instead.
+    pub async fn list(
+        &self,
+        user_id: Uuid,
+        prefix: Option<&str>,
+        deleted: bool,
+    ) -> Result<Vec<JournalEntry>, sqlx::Error> {
+        sqlx::query_as::<_, JournalEntry>(&format!(
+            "SELECT {} FROM sync_entries
+             WHERE user_id = $1 AND deleted = $2 AND moved_to IS NULL
+               AND ($3::TEXT IS NULL OR path = $3 OR starts_with(path, $3 || '/'))
+             ORDER BY path",
+            ENTRY_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(deleted)
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn device_state<'e>(
+        executor: impl PgExecutor<'e>,
+        device_id: Uuid,
+    ) -> Result<Option<SyncState>, sqlx::Error> {
+        let row = sqlx::query_as::<_, DeviceStateRow>(
+            "SELECT * FROM sync_device_states WHERE device_id = $1",
+        )
+        .bind(device_id)
+        .fetch_optional(executor)
+        .await?;
+        Ok(row.map(SyncState::from))
+    }
+
+    pub async fn save_device_state(
+        tx: &mut Transaction<'_, Postgres>,
+        state: &SyncState,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO sync_device_states
+                 (device_id, user_id, last_sync, sync_token, file_states)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (device_id) DO UPDATE SET
+                 last_sync = EXCLUDED.last_sync,
+                 sync_token = EXCLUDED.sync_token,
+                 file_states = EXCLUDED.file_states",
+        )
+        .bind(state.device_id)
+        .bind(state.user_id)
+        .bind(state.last_sync)
+        .bind(&state.sync_token)
+        .bind(Json(&state.file_states))
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
@@ -1,2 +1,801 @@
-// Synchronization module
-// This module handles file synchronization functionality
+//! File synchronization protocol endpoints
+//!
+//! Every change to a user's files is recorded in a journal under an
+//! increasing sequence number, which is what sync tokens carry. A device
+//! pushes the changes it made since its last sync together with that
+//! token; a change to a path another device changed after the token is
+//! reported as a conflict instead of being applied. The response carries
+//! everything other devices changed since the token and a new token.
+//!
+//! File contents travel separately, whole or as deltas against a stored
+//! version (see [`files`]). Synced files can be listed, restored from the
+//! trash with their stored versions (see [`browse`]) and published through
+//! public links (see [`shares`]). Encrypted folders hold only content the
+//! devices encrypted, and can be shared with other users (see
+//! [`encrypted`]); a user's recovery key lets a new device get their keys
+//! back (see [`recovery`]).
+//!
+//! Only registered devices may sync; revoked devices and devices with a
+//! pending remote wipe are turned away. Changes to paths a device's folder
+//! filters exclude are left out of what it receives.
+
+pub mod browse;
+pub mod content;
+pub mod devices;
+pub mod encrypted;
+pub mod files;
+pub mod journal;
+pub mod recovery;
+pub mod shares;
+
+use crate::auth::AuthenticatedUser;
+use crate::webdav::sync::{format_token, parse_token};
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use content::ContentStore;
+use devices::DeviceStore;
+use encrypted::EncryptedFolderStore;
+use journal::{EntryUpdate, JournalEntry, SyncJournal};
+use protocol::file::FileMetadata;
+use protocol::sync::{
+    ChangeType, ConflictType, FileChange, FileState, SyncConflict, SyncRequest, SyncResponse,
+    SyncState, SyncStatus,
+};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+use sync_core::FolderScope;
+use uuid::Uuid;
+
+/// Changes accepted in one request; larger batches have to be split.
+const MAX_CHANGES: usize = 10_000;
+
+#[derive(Debug)]
+enum SyncFailure {
+    BadRequest(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// The request is well-formed but its content is wrong.
+    Invalid(String),
+    Database(sqlx::Error),
+    Storage(std::io::Error),
+}
+
+impl From<sqlx::Error> for SyncFailure {
+    fn from(e: sqlx::Error) -> Self {
+        SyncFailure::Database(e)
+    }
+}
+
+impl From<std::io::Error> for SyncFailure {
+    fn from(e: std::io::Error) -> Self {
+        SyncFailure::Storage(e)
+    }
+}
+
+impl SyncFailure {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            SyncFailure::BadRequest(message) => {
+                HttpResponse::BadRequest().json(serde_json::json!({"error": message}))
+            }
+            SyncFailure::Forbidden(message) => {
+                HttpResponse::Forbidden().json(serde_json::json!({"error": message}))
+            }
+            SyncFailure::NotFound(message) => {
+                HttpResponse::NotFound().json(serde_json::json!({"error": message}))
+            }
+            SyncFailure::Conflict(message) => {
+                HttpResponse::Conflict().json(serde_json::json!({"error": message}))
+            }
+            SyncFailure::Invalid(message) => {
+                HttpResponse::UnprocessableEntity().json(serde_json::json!({"error": message}))
+            }
+            SyncFailure::Database(e) => HttpResponse::InternalServerError()
+                .json(serde_json::json!({"error": format!("Sync failed: {}", e)})),
+            SyncFailure::Storage(e) => HttpResponse::InternalServerError()
+                .json(serde_json::json!({"error": format!("Storage failed: {}", e)})),
+        }
+    }
+}
+
+/// Journal paths are relative to the user's root, without empty, `.` or
+/// `..` segments.
+fn normalize_path(path: &str) -> Option<String> {
+    let path = path.trim_matches('/');
+    let valid = !path.is_empty()
+        && !path.contains('\0')
+        && path
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    valid.then(|| path.to_string())
+}
+
+/// Outcome of one pushed change.
+enum Applied {
+    /// Entries written for the change; a move writes two.
+    Recorded(Vec<JournalEntry>),
+    /// The journal already matched the change.
+    Unchanged(Option<JournalEntry>),
+    Conflict(SyncConflict),
+}
+
+/// One request's batch of changes from a device.
+struct Batch<'a> {
+    user_id: Uuid,
+    device_id: Uuid,
+    /// Sequence number of the device's token; 0 for an initial sync.
+    since: i64,
+    /// What the device had after its last sync.
+    base: &'a HashMap<String, FileState>,
+    /// The user's encrypted folders, which nothing is moved or copied into
+    /// or out of.
+    encrypted: &'a [String],
+    now: DateTime<Utc>,
+}
+
+/// Content of a pushed change, from its checksum and metadata.
+struct Content<'a> {
+    checksum: Option<&'a str>,
+    size: i64,
+    modified_at: DateTime<Utc>,
+    metadata: Option<&'a FileMetadata>,
+}
+
+impl<'a> Content<'a> {
+    fn of(change: &'a FileChange, now: DateTime<Utc>) -> Self {
+        let metadata = change.metadata.as_ref();
+        let size = metadata
+            .map(|m| m.size)
+            .or(change.delta.as_ref().map(|d| d.total_size))
+            .unwrap_or(0);
+        Content {
+            checksum: change
+                .checksum
+                .as_deref()
+                .or(metadata.map(|m| m.checksum.as_str())),
+            size: i64::try_from(size).unwrap_or(i64::MAX),
+            modified_at: metadata.map_or(now, |m| m.modified_at),
+            metadata,
+        }
+    }
+}
+
+impl Batch<'_> {
+    /// Whether another device changed the entry after the device's token.
+    fn unseen(&self, entry: &JournalEntry) -> bool {
+        entry.sequence > self.since && entry.device_id != self.device_id
+    }
+
+    fn conflict(
+        &self,
+        path: &str,
+        conflict_type: ConflictType,
+        content: &Content,
+        remote: &JournalEntry,
+    ) -> Applied {
+        let deleted = conflict_type == ConflictType::DeleteModify;
+        Applied::Conflict(SyncConflict {
+            path: path.to_string(),
+            conflict_type,
+            local_version: FileState {
+                path: path.to_string(),
+                checksum: content.checksum.unwrap_or_default().to_string(),
+                size: if deleted {
+                    0
+                } else {
+                    content.size.max(0) as u64
+                },
+                modified_at: content.modified_at,
+                version: self.base.get(path).map_or(0, |s| s.version),
+                sync_status: if deleted {
+                    SyncStatus::Deleted
+                } else {
+                    SyncStatus::Modified
+                },
+            },
+            remote_version: remote.file_state(),
+            base_version: self.base.get(path).cloned(),
+        })
+    }
+
+    /// Create or replace the file at `path` with `checksum`. `modifies`
+    /// tells whether the device expected the file to exist.
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        path: &str,
+        checksum: &str,
+        content: &Content<'_>,
+        change: &ChangeType,
+        modifies: bool,
+    ) -> Result<Applied, SyncFailure> {
+        let existing = SyncJournal::entry(tx, self.user_id, path).await?;
+        if let Some(existing) = &existing {
+            if self.unseen(existing) {
+                if !existing.deleted && existing.checksum != checksum {
+                    return Ok(self.conflict(path, ConflictType::ModifyModify, content, existing));
+                }
+                if existing.deleted && modifies {
+                    return Ok(self.conflict(path, ConflictType::ModifyDelete, content, existing));
+                }
+            }
+            if !existing.deleted && existing.checksum == checksum {
+                return Ok(Applied::Unchanged(Some(existing.clone())));
+            }
+        }
+        let entry = SyncJournal::record(
+            tx,
+            self.user_id,
+            self.device_id,
+            &EntryUpdate {
+                path,
+                checksum,
+                size: content.size,
+                modified_at: content.modified_at,
+                deleted: false,
+                moved_to: None,
+                metadata: content.metadata,
+                change,
+            },
+        )
+        .await?;
+        self.link_content(tx, &entry).await?;
+        Ok(Applied::Recorded(vec![entry]))
+    }
+
+    /// Carry stored content over to an entry written without an upload,
+    /// such as the target of a move or a copy.
+    async fn link_content(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        entry: &JournalEntry,
+    ) -> Result<(), SyncFailure> {
+        ContentStore::link_version(
+            tx,
+            self.user_id,
+            self.device_id,
+            &entry.path,
+            entry.version,
+            &entry.checksum,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        path: &str,
+        content: &Content<'_>,
+    ) -> Result<Applied, SyncFailure> {
+        let existing = match SyncJournal::entry(tx, self.user_id, path).await? {
+            Some(existing) if !existing.deleted => existing,
+            existing => return Ok(Applied::Unchanged(existing)),
+        };
+        if self.unseen(&existing) {
+            return Ok(self.conflict(path, ConflictType::DeleteModify, content, &existing));
+        }
+        let entry = SyncJournal::record(
+            tx,
+            self.user_id,
+            self.device_id,
+            &EntryUpdate {
+                path,
+                checksum: &existing.checksum,
+                size: 0,
+                modified_at: self.now,
+                deleted: true,
+                moved_to: None,
+                metadata: None,
+                change: &ChangeType::Deleted,
+            },
+        )
+        .await?;
+        Ok(Applied::Recorded(vec![entry]))
+    }
+
+    /// Move `from` to `path`. A move of a file the journal does not have,
+    /// or that the device already saw deleted, is taken as a creation.
+    async fn move_file(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        from: &str,
+        path: &str,
+        content: &Content<'_>,
+        change: &ChangeType,
+    ) -> Result<Applied, SyncFailure> {
+        let source = SyncJournal::entry(tx, self.user_id, from).await?;
+        let source = match source {
+            Some(source) if !source.deleted => source,
+            Some(source) if self.unseen(&source) && source.moved_to.as_deref() != Some(path) => {
+                let (conflict_type, remote) = match &source.moved_to {
+                    Some(moved_to) => (
+                        ConflictType::MoveMove,
+                        SyncJournal::entry(tx, self.user_id, moved_to)
+                            .await?
+                            .unwrap_or(source),
+                    ),
+                    None => (ConflictType::ModifyDelete, source),
+                };
+                return Ok(self.conflict(from, conflict_type, content, &remote));
+            }
+            _ => {
+                let Some(checksum) = content.checksum else {
+                    return Err(SyncFailure::BadRequest(format!(
+                        "Move to {} has no checksum",
+                        path
+                    )));
+                };
+                return self
+                    .write(tx, path, checksum, content, &ChangeType::Created, false)
+                    .await;
+            }
+        };
+
+        let checksum = content.checksum.unwrap_or(&source.checksum);
+        if self.unseen(&source) && checksum != source.checksum {
+            return Ok(self.conflict(from, ConflictType::ModifyModify, content, &source));
+        }
+        if let Some(target) = SyncJournal::entry(tx, self.user_id, path).await? {
+            if !target.deleted && self.unseen(&target) && target.checksum != checksum {
+                return Ok(self.conflict(path, ConflictType::ModifyModify, content, &target));
+            }
+        }
+        let tombstone = SyncJournal::record(
+            tx,
+            self.user_id,
+            self.device_id,
+            &EntryUpdate {
+                path: from,
+                checksum: &source.checksum,
+                size: 0,
+                modified_at: self.now,
+                deleted: true,
+                moved_to: Some(path),
+                metadata: None,
+                change: &ChangeType::Deleted,
+            },
+        )
+        .await?;
+        let metadata = content.metadata.or(source.metadata.as_ref().map(|m| &m.0));
+        let entry = SyncJournal::record(
+            tx,
+            self.user_id,
+            self.device_id,
+            &EntryUpdate {
+                path,
+                checksum,
+                size: if content.checksum.is_some() {
+                    content.size
+                } else {
+                    source.size
+                },
+                modified_at: content.modified_at,
+                deleted: false,
+                moved_to: None,
+                metadata,
+                change,
+            },
+        )
+        .await?;
+        self.link_content(tx, &entry).await?;
+        Ok(Applied::Recorded(vec![tombstone, entry]))
+    }
+
+    async fn apply(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        change: &FileChange,
+    ) -> Result<Applied, SyncFailure> {
+        let path = normalize_path(&change.path)
+            .ok_or_else(|| SyncFailure::BadRequest(format!("Invalid path: {}", change.path)))?;
+        let content = Content::of(change, self.now);
+        match &change.change_type {
+            ChangeType::Deleted => self.delete(tx, &path, &content).await,
+            ChangeType::Moved { old_path } => {
+                let from = normalize_path(old_path)
+                    .filter(|from| *from != path)
+                    .ok_or_else(|| {
+                        SyncFailure::BadRequest(format!("Invalid move source: {}", old_path))
+                    })?;
+                encrypted::check_boundary(self.encrypted, &from, &path)?;
+                let change = ChangeType::Moved {
+                    old_path: from.clone(),
+                };
+                self.move_file(tx, &from, &path, &content, &change).await
+            }
+            ChangeType::Copied { source_path } => {
+                let source = normalize_path(source_path).ok_or_else(|| {
+                    SyncFailure::BadRequest(format!("Invalid copy source: {}", source_path))
+                })?;
+                encrypted::check_boundary(self.encrypted, &source, &path)?;
+                let change = ChangeType::Copied {
+                    source_path: source.clone(),
+                };
+                match content.checksum {
+                    Some(checksum) => {
+                        self.write(tx, &path, checksum, &content, &change, false)
+                            .await
+                    }
+                    None => {
+                        // A plain copy has the content of its source.
+                        let source = match SyncJournal::entry(tx, self.user_id, &source).await? {
+                            Some(source) if !source.deleted => source,
+                            _ => {
+                                return Err(SyncFailure::BadRequest(format!(
+                                    "Copy source {} does not exist",
+                                    source
+                                )))
+                            }
+                        };
+                        let content = Content {
+                            size: source.size,
+                            ..content
+                        };
+                        self.write(tx, &path, &source.checksum, &content, &change, false)
+                            .await
+                    }
+                }
+            }
+            ChangeType::Created | ChangeType::Modified => {
+                let checksum = content.checksum.ok_or_else(|| {
+                    SyncFailure::BadRequest(format!("Change to {} has no checksum", path))
+                })?;
+                let modifies = matches!(change.change_type, ChangeType::Modified);
+                self.write(tx, &path, checksum, &content, &change.change_type, modifies)
+                    .await
+            }
+        }
+    }
+}
+
+/// What a device is told of the journal entries its folders accept that
+/// changed since `since`, 0 for an initial sync: the changes, and the paths
+/// deleted.
+fn report_changes(
+    remote: &[JournalEntry],
+    since: i64,
+    scope: &FolderScope,
+) -> (Vec<FileChange>, Vec<String>) {
+    let live: HashSet<&str> = remote
+        .iter()
+        .filter(|entry| !entry.deleted)
+        .map(|entry| entry.path.as_str())
+        .collect();
+    let mut changes = Vec::new();
+    let mut deleted_files = Vec::new();
+    for entry in remote {
+        if entry.deleted {
+            // A move is reported once, as the change at its destination,
+            // unless the device does not get the destination.
+            let moved = entry
+                .moved_to
+                .as_deref()
+                .is_some_and(|to| live.contains(to));
+            if since > 0 && !moved {
+                deleted_files.push(entry.path.clone());
+            }
+            continue;
+        }
+        let change_type = match entry.change.0.clone() {
+            // An initial sync knows nothing to move or modify.
+            _ if since == 0 => ChangeType::Created,
+            // Nor does the device know files its folders do not accept.
+            ChangeType::Moved { old_path: source }
+            | ChangeType::Copied {
+                source_path: source,
+            } if !scope.accepts(&source, entry.size.max(0) as u64) => ChangeType::Created,
+            change => change,
+        };
+        changes.push(FileChange {
+            path: entry.path.clone(),
+            change_type,
+            metadata: entry.metadata.as_ref().map(|m| m.0.clone()),
+            checksum: Some(entry.checksum.clone()),
+            delta: None,
+        });
+    }
+    (changes, deleted_files)
+}
+
+async fn run_sync(
+    journal: &SyncJournal,
+    user_id: Uuid,
+    request: &SyncRequest,
+    scope: &FolderScope,
+) -> Result<SyncResponse, SyncFailure> {
+    if request.changes.len() > MAX_CHANGES {
+        return Err(SyncFailure::BadRequest(format!(
+            "At most {} changes can be pushed at once",
+            MAX_CHANGES
+        )));
+    }
+    let (mut tx, current) = journal.begin(user_id).await?;
+    let since = match &request.last_sync_token {
+        None => 0,
+        Some(token) => match parse_token(token) {
+            Some(since) if since <= current => since,
+            _ => return Err(SyncFailure::BadRequest("Invalid sync token".to_string())),
+        },
+    };
+    let mut state = match SyncJournal::device_state(&mut *tx, request.device_id).await? {
+        Some(state) if state.user_id != user_id => {
+            return Err(SyncFailure::Forbidden(
+                "Device belongs to another user".to_string(),
+            ))
+        }
+        Some(state) => state,
+        None => SyncState {
+            user_id,
+            device_id: request.device_id,
+            last_sync: Utc::now(),
+            sync_token: String::new(),
+            file_states: HashMap::new(),
+        },
+    };
+
+    let base = state.file_states.clone();
+    let encrypted = EncryptedFolderStore::roots(&mut *tx, user_id).await?;
+    let batch = Batch {
+        user_id,
+        device_id: request.device_id,
+        since,
+        base: &base,
+        encrypted: &encrypted,
+        now: Utc::now(),
+    };
+    let mut settled: Vec<JournalEntry> = Vec::new();
+    let mut conflicts = Vec::new();
+    for change in &request.changes {
+        match batch.apply(&mut tx, change).await? {
+            Applied::Recorded(entries) => settled.extend(entries),
+            Applied::Unchanged(entry) => settled.extend(entry),
+            Applied::Conflict(conflict) => conflicts.push(conflict),
+        }
+    }
+
+    // The device keeps its own version of conflicting paths until the
+    // conflict is resolved, and never hears of paths its folders exclude.
+    let conflicted: HashSet<&str> = conflicts.iter().map(|c| c.path.as_str()).collect();
+    let remote: Vec<JournalEntry> =
+        SyncJournal::changes_since(&mut tx, user_id, since, request.device_id)
+            .await?
+            .into_iter()
+            .filter(|entry| !conflicted.contains(entry.path.as_str()))
+            .filter(|entry| scope.accepts(&entry.path, entry.size.max(0) as u64))
+            .collect();
+    let (changes, deleted_files) = report_changes(&remote, since, scope);
+
+    settled.extend(remote);
+    settled.sort_by_key(|entry| entry.sequence);
+    for entry in &settled {
+        if entry.deleted {
+            state.file_states.remove(&entry.path);
+        } else {
+            state
+                .file_states
+                .insert(entry.path.clone(), entry.file_state());
+        }
+    }
+    for conflict in &conflicts {
+        if let Some(file_state) = state.file_states.get_mut(&conflict.path) {
+            file_state.sync_status = SyncStatus::Conflicted;
+        }
+    }
+
+    let sequence: i64 = sqlx::query_scalar("SELECT sequence FROM sync_journals WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    state.last_sync = Utc::now();
+    state.sync_token = format_token(sequence);
+    SyncJournal::save_device_state(&mut tx, &state).await?;
+    tx.commit().await?;
+
+    Ok(SyncResponse {
+        sync_token: state.sync_token,
+        changes,
+        conflicts,
+        deleted_files,
+    })
+}
+
+/// Push a device's changes and pull everything other devices changed since
+/// its last sync.
+pub async fn sync(
+    user: AuthenticatedUser,
+    req: web::Json<SyncRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let store = DeviceStore::new(&data.db_pool);
+    let device = match devices::authorize_sync(&store, &user, req.device_id).await {
+        Ok(device) => device,
+        Err(response) => return Ok(response),
+    };
+    let journal = SyncJournal::new(&data.db_pool);
+    let scope = FolderScope::new(&device.sync_folders);
+    match run_sync(&journal, user.user_id, &req, &scope).await {
+        Ok(response) => Ok(HttpResponse::Ok().json(response)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// What the server last synchronized with a device.
+pub async fn get_device_state(
+    path: web::Path<Uuid>,
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let device_id = path.into_inner();
+    let store = DeviceStore::new(&data.db_pool);
+    if let Err(response) = devices::authorize_sync(&store, &user, device_id).await {
+        return Ok(response);
+    }
+    let journal = SyncJournal::new(&data.db_pool);
+    match SyncJournal::device_state(journal.pool(), device_id).await {
+        Ok(Some(state)) if state.user_id == user.user_id => Ok(HttpResponse::Ok().json(state)),
+        Ok(_) => {
+            Ok(HttpResponse::NotFound().json(serde_json::json!({"error": "Device not found"})))
+        }
+        Err(e) => Ok(SyncFailure::Database(e).into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync", web::post().to(sync))
+        .route("/sync/devices/{device_id}", web::get().to(get_device_state))
+        .configure(files::configure)
+        .configure(browse::configure)
+        .configure(shares::configure)
+        .configure(encrypted::configure)
+        .configure(recovery::configure);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::sync::{SyncDirection, SyncFilters, SyncFolder};
+    use sqlx::types::Json;
+
+    /// A device syncing `docs`, without its `drafts` directory.
+    fn scope() -> FolderScope {
+        FolderScope::new(&[SyncFolder {
+            local_path: "/home/docs".to_string(),
+            remote_path: "/docs".to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active: true,
+            filters: SyncFilters {
+                exclude_patterns: vec!["drafts/".to_string()],
+                ..SyncFilters::default()
+            },
+        }])
+    }
+
+    fn entry(path: &str, change: ChangeType, sequence: i64) -> JournalEntry {
+        JournalEntry {
+            path: path.to_string(),
+            checksum: "abc".to_string(),
+            size: 3,
+            modified_at: Utc::now(),
+            version: 1,
+            deleted: false,
+            moved_to: None,
+            metadata: None,
+            change: Json(change),
+            sequence,
+            device_id: Uuid::new_v4(),
+        }
+    }
+
+    fn tombstone(path: &str, moved_to: &str, sequence: i64) -> JournalEntry {
+        JournalEntry {
+            deleted: true,
+            moved_to: Some(moved_to.to_string()),
+            ..entry(path, ChangeType::Deleted, sequence)
+        }
+    }
+
+    /// The entries the device's scope accepts, as `run_sync` passes them.
+    fn report(entries: Vec<JournalEntry>, since: i64) -> (Vec<FileChange>, Vec<String>) {
+        let scope = scope();
+        let accepted: Vec<JournalEntry> = entries
+            .into_iter()
+            .filter(|entry| scope.accepts(&entry.path, entry.size.max(0) as u64))
+            .collect();
+        report_changes(&accepted, since, &scope)
+    }
+
+    #[test]
+    fn test_moves_within_scope_stay_moves() {
+        let (changes, deleted) = report(
+            vec![
+                tombstone("docs/a.txt", "docs/b.txt", 1),
+                entry(
+                    "docs/b.txt",
+                    ChangeType::Moved {
+                        old_path: "docs/a.txt".to_string(),
+                    },
+                    2,
+                ),
+            ],
+            1,
+        );
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].path, "docs/b.txt");
+        assert!(
+            matches!(&changes[0].change_type, ChangeType::Moved { old_path } if old_path == "docs/a.txt")
+        );
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn test_moves_into_scope_are_creations() {
+        let (changes, deleted) = report(
+            vec![
+                tombstone("docs/drafts/a.txt", "docs/a.txt", 1),
+                entry(
+                    "docs/a.txt",
+                    ChangeType::Moved {
+                        old_path: "docs/drafts/a.txt".to_string(),
+                    },
+                    2,
+                ),
+                entry(
+                    "docs/b.txt",
+                    ChangeType::Copied {
+                        source_path: "docs/drafts/b.txt".to_string(),
+                    },
+                    3,
+                ),
+            ],
+            1,
+        );
+        let paths: Vec<&str> = changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(paths, vec!["docs/a.txt", "docs/b.txt"]);
+        assert!(changes
+            .iter()
+            .all(|change| matches!(change.change_type, ChangeType::Created)));
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn test_moves_out_of_scope_are_deletions() {
+        let (changes, deleted) = report(
+            vec![
+                tombstone("docs/a.txt", "docs/drafts/a.txt", 1),
+                entry(
+                    "docs/drafts/a.txt",
+                    ChangeType::Moved {
+                        old_path: "docs/a.txt".to_string(),
+                    },
+                    2,
+                ),
+            ],
+            1,
+        );
+        assert!(changes.is_empty());
+        assert_eq!(deleted, vec!["docs/a.txt"]);
+    }
+
+    #[test]
+    fn test_initial_sync_only_creates() {
+        let (changes, deleted) = report(
+            vec![
+                tombstone("docs/a.txt", "docs/b.txt", 1),
+                entry(
+                    "docs/b.txt",
+                    ChangeType::Moved {
+                        old_path: "docs/a.txt".to_string(),
+                    },
+                    2,
+                ),
+            ],
+            0,
+        );
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].change_type, ChangeType::Created));
+        assert!(deleted.is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! Recovery keys for encrypted folders
+//!
+//! A user can register the public half of an X25519 key pair derived from
+//! a passphrase with Argon2id, along with the parameters it was derived
+//! with, so only the passphrase needs to be kept offline. Their devices seal
+//! folder keys for it like for any of their devices (it is listed among
+//! them, under its own id, and waits to be approved the same way), so after losing every device a new one can
+//! fetch those sealed keys, open them with the passphrase and seal them
+//! for itself. Replacing or removing the recovery key forgets the folder
+//! keys sealed for the old one.
+
+use super::devices::{self, DeviceStore};
+use super::SyncFailure;
+use crate::auth::AuthenticatedUser;
+use crate::config::database::DatabasePool;
+use crate::AppState;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use crypto::PasswordKeyParams;
+use protocol::sync::{RecoveredFolderKey, RecoveryKey, SetRecoveryKeyRequest};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, FromRow)]
+struct RecoveryKeyRow {
+    id: Uuid,
+    public_key: String,
+    kdf_params: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<RecoveryKeyRow> for RecoveryKey {
+    fn from(row: RecoveryKeyRow) -> Self {
+        RecoveryKey {
+            id: row.id,
+            public_key: row.public_key,
+            kdf_params: row.kdf_params,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct RecoveredKeyRow {
+    folder_id: Uuid,
+    owner_id: Uuid,
+    path: String,
+    sealed_key: String,
+}
+
+pub struct RecoveryKeyStore {
+    pool: PgPool,
+}
+
+impl RecoveryKeyStore {
+    pub fn new(db: &DatabasePool) -> Self {
+        Self {
+            pool: db.pool().clone(),
+        }
+    }
+
+    /// A user's recovery key.
+    pub async fn get(&self, user_id: Uuid) -> Result<Option<RecoveryKey>, sqlx::Error> {
+        Ok(Self::for_user(&self.pool, user_id).await?.map(Into::into))
+    }
+
+    async fn for_user<'e>(
+        executor: impl PgExecutor<'e>,
+        user_id: Uuid,
+    ) -> Result<Option<RecoveryKeyRow>, sqlx::Error> {
+        sqlx::query_as::<_, RecoveryKeyRow>(
+            "SELECT id, public_key, kdf_params, created_at FROM recovery_keys WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// The user a recovery key belongs to.
+    pub async fn owner(&self, id: Uuid) -> Result<Option<Uuid>, sqlx::Error> {
+        sqlx::query_scalar("SELECT user_id FROM recovery_keys WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    async fn forget(conn: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+        if let Some(old) = Self::for_user(&mut *conn, user_id).await? {
+            sqlx::query("DELETE FROM encrypted_folder_keys WHERE device_id = $1")
+                .bind(old.id)
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("DELETE FROM recovery_keys WHERE id = $1")
+                .bind(old.id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Replace a user's recovery key with a new one.
+    pub async fn set(
+        &self,
+        user_id: Uuid,
+        public_key: &str,
+        kdf_params: &str,
+    ) -> Result<RecoveryKey, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        Self::forget(&mut tx, user_id).await?;
+        let row = sqlx::query_as::<_, RecoveryKeyRow>(
+            "INSERT INTO recovery_keys (id, user_id, public_key, kdf_params) VALUES ($1, $2, $3, $4)
+             RETURNING id, public_key, kdf_params, created_at",
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(public_key)
+        .bind(kdf_params)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(row.into())
+    }
+
+    /// Remove a user's recovery key and the folder keys sealed for it.
+    pub async fn delete(&self, user_id: Uuid) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        Self::forget(&mut tx, user_id).await?;
+        tx.commit().await
+    }
+
+    /// Folder keys sealed for a user's recovery key, in path order.
+    pub async fn folder_keys(&self, user_id: Uuid) -> Result<Vec<RecoveredFolderKey>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, RecoveredKeyRow>(
+            "SELECT k.folder_id, f.owner_id, f.path, k.sealed_key
+             FROM encrypted_folder_keys k
+             JOIN encrypted_folders f ON f.id = k.folder_id
+             JOIN recovery_keys r ON r.id = k.device_id
+             WHERE r.user_id = $1
+             ORDER BY f.path, f.id",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| RecoveredFolderKey {
+                folder_id: row.folder_id,
+                owner_id: row.owner_id,
+                path: row.path,
+                sealed_key: row.sealed_key,
+            })
+            .collect())
+    }
+}
+
+/// The caller's recovery key.
+pub async fn get_recovery_key(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match RecoveryKeyStore::new(&data.db_pool).get(user.user_id).await {
+        Ok(Some(key)) => Ok(HttpResponse::Ok().json(key)),
+        Ok(None) => Ok(SyncFailure::NotFound("No recovery key".into()).into_response()),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+async fn set(
+    data: &AppState,
+    user: &AuthenticatedUser,
+    request: &SetRecoveryKeyRequest,
+) -> Result<RecoveryKey, SyncFailure> {
+    let valid = crypto::from_base64(&request.public_key).is_ok_and(|key| key.len() == 32);
+    if !valid {
+        return Err(SyncFailure::BadRequest("Invalid public key".into()));
+    }
+    if request.kdf_params.parse::<PasswordKeyParams>().is_err() {
+        return Err(SyncFailure::BadRequest(
+            "Invalid key derivation parameters".into(),
+        ));
+    }
+    Ok(RecoveryKeyStore::new(&data.db_pool)
+        .set(user.user_id, &request.public_key, &request.kdf_params)
+        .await?)
+}
+
+/// Replace the caller's recovery key.
+pub async fn set_recovery_key(
+    user: AuthenticatedUser,
+    req: web::Json<SetRecoveryKeyRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match set(&data, &user, &req).await {
+        Ok(key) => Ok(HttpResponse::Ok().json(key)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// Remove the caller's recovery key.
+pub async fn delete_recovery_key(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match RecoveryKeyStore::new(&data.db_pool)
+        .delete(user.user_id)
+        .await
+    {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+/// Folder keys sealed for the caller's recovery key.
+pub async fn list_recovery_folder_keys(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match RecoveryKeyStore::new(&data.db_pool)
+        .folder_keys(user.user_id)
+        .await
+    {
+        Ok(keys) => Ok(HttpResponse::Ok().json(keys)),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/recovery-key", web::get().to(get_recovery_key))
+        .route("/sync/recovery-key", web::put().to(set_recovery_key))
+        .route("/sync/recovery-key", web::delete().to(delete_recovery_key))
+        .route(
+            "/sync/recovery-key/folder-keys",
+            web::get().to(list_recovery_folder_keys),
+        );
+}
@@ -0,0 +1,276 @@
+//! Public links to synced files
+//!
+//! A link is identified by a random token and serves the latest content of
+//! its path for as long as the file exists, until it expires, reaches its
+//! download limit or is revoked. A link may ask for a password, sent in the
+//! `X-Share-Password` header.
+
+use super::content::ContentStore;
+use super::devices::{self, DeviceStore};
+use super::journal::SyncJournal;
+use super::{normalize_path, SyncFailure};
+use crate::auth::AuthenticatedUser;
+use crate::AppState;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use crypto::PasswordManager;
+use protocol::file::{CreateShareRequest, ShareLink};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+const PASSWORD_HEADER: &str = "X-Share-Password";
+
+#[derive(Debug, FromRow)]
+struct ShareRow {
+    id: Uuid,
+    user_id: Uuid,
+    path: String,
+    token: String,
+    password_hash: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<i64>,
+    download_count: i64,
+    created_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+const SHARE_COLUMNS: &str = "id, user_id, path, token, password_hash, expires_at, \
+                             max_downloads, download_count, created_at, revoked_at";
+
+impl ShareRow {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires| expires > now)
+            && self
+                .max_downloads
+                .is_none_or(|max| self.download_count < max)
+    }
+
+    fn link(&self) -> ShareLink {
+        ShareLink {
+            id: self.id,
+            path: self.path.clone(),
+            token: self.token.clone(),
+            url: format!("/api/v1/public/shares/{}", self.token),
+            expires_at: self.expires_at,
+            password_protected: self.password_hash.is_some(),
+            download_count: self.download_count.max(0) as u64,
+            max_downloads: self.max_downloads.map(|max| max.max(0) as u64),
+            created_at: self.created_at,
+            is_active: self.is_active(Utc::now()),
+        }
+    }
+}
+
+/// Link tokens carry 244 random bits.
+fn new_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+async fn create(
+    data: &AppState,
+    user_id: Uuid,
+    request: &CreateShareRequest,
+) -> Result<ShareLink, SyncFailure> {
+    let path = normalize_path(&request.path)
+        .ok_or_else(|| SyncFailure::BadRequest("Invalid path".into()))?;
+    if request
+        .expires_at
+        .is_some_and(|expires| expires <= Utc::now())
+    {
+        return Err(SyncFailure::Invalid("Expiry is in the past".into()));
+    }
+    if request.max_downloads == Some(0) {
+        return Err(SyncFailure::Invalid(
+            "max_downloads must be positive".into(),
+        ));
+    }
+    let journal = SyncJournal::new(&data.db_pool);
+    let live = journal
+        .current(user_id, &path)
+        .await?
+        .is_some_and(|entry| !entry.deleted);
+    if !live {
+        return Err(SyncFailure::NotFound(format!("{} does not exist", path)));
+    }
+    let password_hash = match request.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => Some(
+            PasswordManager::new()
+                .hash_password(password)
+                .map_err(|e| SyncFailure::BadRequest(e.to_string()))?,
+        ),
+        None => None,
+    };
+    let row = sqlx::query_as::<_, ShareRow>(&format!(
+        "INSERT INTO sync_file_shares
+             (id, user_id, path, token, password_hash, expires_at, max_downloads)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         RETURNING {}",
+        SHARE_COLUMNS
+    ))
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(&path)
+    .bind(new_token())
+    .bind(password_hash)
+    .bind(request.expires_at)
+    .bind(
+        request
+            .max_downloads
+            .map(|max| i64::try_from(max).unwrap_or(i64::MAX)),
+    )
+    .fetch_one(journal.pool())
+    .await?;
+    Ok(row.link())
+}
+
+/// Publish a file through a new link.
+pub async fn create_share(
+    user: AuthenticatedUser,
+    req: web::Json<CreateShareRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    match create(&data, user.user_id, &req).await {
+        Ok(link) => Ok(HttpResponse::Created().json(link)),
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+/// The user's links that were not revoked, newest first.
+pub async fn list_shares(
+    user: AuthenticatedUser,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let rows = sqlx::query_as::<_, ShareRow>(&format!(
+        "SELECT {} FROM sync_file_shares
+         WHERE user_id = $1 AND revoked_at IS NULL
+         ORDER BY created_at DESC",
+        SHARE_COLUMNS
+    ))
+    .bind(user.user_id)
+    .fetch_all(data.db_pool.pool())
+    .await;
+    match rows {
+        Ok(rows) => {
+            Ok(HttpResponse::Ok().json(rows.iter().map(ShareRow::link).collect::<Vec<_>>()))
+        }
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+pub async fn revoke_share(
+    user: AuthenticatedUser,
+    path: web::Path<Uuid>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if let Err(response) = devices::check_token(&DeviceStore::new(&data.db_pool), &user).await {
+        return Ok(response);
+    }
+    let result = sqlx::query(
+        "UPDATE sync_file_shares SET revoked_at = NOW()
+         WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(path.into_inner())
+    .bind(user.user_id)
+    .execute(data.db_pool.pool())
+    .await;
+    match result {
+        Ok(done) if done.rows_affected() == 0 => {
+            Ok(SyncFailure::NotFound("Share not found".into()).into_response())
+        }
+        Ok(_) => Ok(HttpResponse::NoContent().finish()),
+        Err(e) => Ok(SyncFailure::from(e).into_response()),
+    }
+}
+
+async fn open_share(
+    data: &AppState,
+    token: &str,
+    password: Option<&str>,
+) -> Result<(String, Vec<u8>), SyncFailure> {
+    let pool = data.db_pool.pool();
+    let share = sqlx::query_as::<_, ShareRow>(&format!(
+        "SELECT {} FROM sync_file_shares WHERE token = $1",
+        SHARE_COLUMNS
+    ))
+    .bind(token)
+    .fetch_optional(pool)
+    .await?
+    .filter(|share| share.is_active(Utc::now()))
+    .ok_or_else(|| SyncFailure::NotFound("Share not found".into()))?;
+    if let Some(hash) = &share.password_hash {
+        let valid = password.is_some_and(|password| {
+            PasswordManager::new()
+                .verify_password(password, hash)
+                .unwrap_or(false)
+        });
+        if !valid {
+            return Err(SyncFailure::Forbidden("Wrong share password".into()));
+        }
+    }
+
+    let live = SyncJournal::new(&data.db_pool)
+        .current(share.user_id, &share.path)
+        .await?
+        .filter(|entry| !entry.deleted)
+        .ok_or_else(|| SyncFailure::NotFound("Shared file no longer exists".into()))?;
+    let store = ContentStore::new(&data.db_pool, &data.storage);
+    let version = store
+        .version(share.user_id, &share.path, Some(live.version))
+        .await?
+        .ok_or_else(|| SyncFailure::NotFound("Shared file has no content".into()))?;
+    let content = store.read(share.user_id, &version.checksum).await?;
+
+    // Counted only once the file is known to be there; the condition keeps
+    // concurrent downloads from going over the limit.
+    let counted = sqlx::query(
+        "UPDATE sync_file_shares SET download_count = download_count + 1
+         WHERE id = $1 AND revoked_at IS NULL
+           AND (max_downloads IS NULL OR download_count < max_downloads)",
+    )
+    .bind(share.id)
+    .execute(pool)
+    .await?;
+    if counted.rows_affected() == 0 {
+        return Err(SyncFailure::NotFound("Share not found".into()));
+    }
+    Ok((share.path, content))
+}
+
+/// Download a shared file; needs no account.
+pub async fn download_share(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let password = req
+        .headers()
+        .get(PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match open_share(&data, &path, password).await {
+        Ok((path, content)) => {
+            let name = path.rsplit('/').next().unwrap_or(&path).replace('"', "");
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}\"", name),
+                ))
+                .body(content))
+        }
+        Err(failure) => Ok(failure.into_response()),
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/shares", web::post().to(create_share))
+        .route("/sync/shares", web::get().to(list_shares))
+        .route("/sync/shares/{id}", web::delete().to(revoke_share))
+        .route("/public/shares/{token}", web::get().to(download_share));
+}
@@ -1,6 +1,64 @@
-//! WebDAV route stubs
+//! WebDAV route stubs and helpers shared by the DAV endpoints
 
-use actix_web::web;
+pub mod report;
+pub mod sync;
+pub mod xml;
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// Object names are used verbatim in hrefs, so keep them to safe characters.
+pub fn valid_object_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+/// 500 response for a failed database query.
+pub fn internal_error(context: &str, e: sqlx::Error) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .json(serde_json::json!({"error": format!("{}: {}", context, e)}))
+}
+
+/// Entity tag of a resource's content.
+pub fn etag(data: &str) -> String {
+    blake3::hash(data.as_bytes()).to_hex()[..32].to_string()
+}
+
+fn header_value(req: &HttpRequest, name: header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Evaluate If-Match / If-None-Match against the current ETag, if any.
+pub fn preconditions_hold(req: &HttpRequest, current: Option<&str>) -> bool {
+    let quoted = current.map(|etag| format!("\"{}\"", etag));
+    if let Some(if_match) = header_value(req, header::IF_MATCH) {
+        let ok = match &quoted {
+            Some(etag) => if_match.trim() == "*" || if_match.split(',').any(|t| t.trim() == etag),
+            None => false,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    if let Some(if_none_match) = header_value(req, header::IF_NONE_MATCH) {
+        if let Some(etag) = &quoted {
+            if if_none_match.trim() == "*" || if_none_match.split(',').any(|t| t.trim() == etag) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+pub fn etag_header(etag: &str) -> (header::HeaderName, HeaderValue) {
+    (
+        header::ETAG,
+        HeaderValue::from_str(&format!("\"{}\"", etag)).unwrap_or(HeaderValue::from_static("")),
+    )
+}
 
 pub fn configure(_cfg: &mut web::ServiceConfig) {
     // TODO: Implement WebDAV routes
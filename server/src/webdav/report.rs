@@ -0,0 +1,76 @@
+//! Parsing shared by the CalDAV and CardDAV REPORTs
+
+use super::sync::SyncError;
+use super::xml::{self, Element, PropName, DAV};
+use crate::caldav::query::is_supported_collation;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use protocol::caldav::{MatchType, TextMatch};
+
+#[derive(Debug)]
+pub enum ReportError {
+    BadRequest(String),
+    UnsupportedReport,
+    /// A precondition of the protocol failed, such as CalDAV's
+    /// `supported-filter`.
+    Precondition(PropName),
+    Sync(SyncError),
+}
+
+impl From<xml::XmlError> for ReportError {
+    fn from(e: xml::XmlError) -> Self {
+        ReportError::BadRequest(e.to_string())
+    }
+}
+
+impl ReportError {
+    pub fn precondition(namespace: &str, name: &str) -> Self {
+        ReportError::Precondition(PropName::new(namespace, name))
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            ReportError::BadRequest(message) => {
+                HttpResponse::BadRequest().json(serde_json::json!({"error": message}))
+            }
+            ReportError::UnsupportedReport => xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(DAV, "supported-report"),
+            ),
+            ReportError::Precondition(condition) => {
+                xml::error_response(StatusCode::FORBIDDEN, condition)
+            }
+            ReportError::Sync(e) => e.into_response(),
+        }
+    }
+}
+
+pub fn bad_request(message: &str) -> ReportError {
+    ReportError::BadRequest(message.to_string())
+}
+
+/// Parse a `text-match` element of the protocol in `namespace`, whose
+/// collation is `default_collation` when it names none.
+pub fn parse_text_match(
+    element: &Element,
+    namespace: &str,
+    default_collation: Option<&str>,
+) -> Result<TextMatch, ReportError> {
+    let collation = element.attr("collation").or(default_collation);
+    if collation.is_some_and(|c| !is_supported_collation(c)) {
+        return Err(ReportError::precondition(namespace, "supported-collation"));
+    }
+    let match_type = match element.attr("match-type").unwrap_or("contains") {
+        "equals" => MatchType::Equals,
+        "contains" => MatchType::Contains,
+        "starts-with" => MatchType::StartsWith,
+        "ends-with" => MatchType::EndsWith,
+        _ => return Err(bad_request("unknown match-type")),
+    };
+    Ok(TextMatch {
+        collation: collation.map(str::to_string),
+        negate_condition: element.attr("negate-condition") == Some("yes"),
+        match_type,
+        value: element.text.clone(),
+    })
+}
@@ -0,0 +1,151 @@
+//! Collection synchronization (RFC 6578)
+//!
+//! Collections keep a revision counter bumped by every change. Members record
+//! the revision they were last written at and deleted members leave a
+//! tombstone with the revision of the deletion, so a `sync-collection` REPORT
+//! answers with everything newer than the revision in the client's token.
+
+use super::xml::{self, Element, MultiStatus, PropName, Response, DAV};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+pub const TOKEN_PREFIX: &str = "urn:rouillecloud:sync:";
+
+pub fn format_token(revision: i64) -> String {
+    format!("{}{}", TOKEN_PREFIX, revision)
+}
+
+pub fn parse_token(token: &str) -> Option<i64> {
+    token
+        .trim()
+        .strip_prefix(TOKEN_PREFIX)?
+        .parse()
+        .ok()
+        .filter(|revision| *revision >= 0)
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    BadRequest(String),
+    /// The token is not one of ours or is ahead of the collection.
+    InvalidToken,
+}
+
+impl SyncError {
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            SyncError::BadRequest(message) => {
+                HttpResponse::BadRequest().json(serde_json::json!({"error": message}))
+            }
+            SyncError::InvalidToken => xml::error_response(
+                StatusCode::FORBIDDEN,
+                PropName::new(DAV, "valid-sync-token"),
+            ),
+        }
+    }
+}
+
+/// A parsed `DAV:sync-collection` REPORT.
+#[derive(Debug)]
+pub struct SyncCollection {
+    /// Revision of the client's token; `None` for an initial sync.
+    pub revision: Option<i64>,
+    pub limit: Option<usize>,
+    pub props: Vec<PropName>,
+}
+
+impl SyncCollection {
+    /// Parse the REPORT body. Only `sync-level` 1 is supported, as the
+    /// collections synchronized this way have no child collections.
+    pub fn parse(root: &Element) -> Result<Self, SyncError> {
+        let level = root
+            .child(DAV, "sync-level")
+            .map(|l| l.text.trim())
+            .unwrap_or("1");
+        if level != "1" {
+            return Err(SyncError::BadRequest(
+                "only sync-level 1 is supported".to_string(),
+            ));
+        }
+        let revision = match root.child(DAV, "sync-token").map(|t| t.text.trim()) {
+            None | Some("") => None,
+            Some(token) => Some(parse_token(token).ok_or(SyncError::InvalidToken)?),
+        };
+        let limit = match root
+            .child(DAV, "limit")
+            .and_then(|l| l.child(DAV, "nresults"))
+        {
+            Some(n) => Some(
+                n.text
+                    .trim()
+                    .parse()
+                    .map_err(|_| SyncError::BadRequest("invalid nresults".to_string()))?,
+            ),
+            None => None,
+        };
+        let props = root
+            .child(DAV, "prop")
+            .map(|prop| prop.children.iter().map(PropName::of).collect())
+            .unwrap_or_else(|| vec![PropName::new(DAV, "getetag")]);
+        Ok(Self {
+            revision,
+            limit,
+            props,
+        })
+    }
+
+    /// Fail if the token is ahead of the collection's `current` revision.
+    pub fn check(&self, current: i64) -> Result<(), SyncError> {
+        match self.revision {
+            Some(revision) if revision > current => Err(SyncError::InvalidToken),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A change since the client's token.
+#[derive(Debug)]
+pub enum Change {
+    /// A member created or modified at a revision, with its properties.
+    Updated(Response),
+    /// The href of a member deleted at a revision.
+    Deleted(String),
+}
+
+/// The multistatus for `changes`, each tagged with its revision. When
+/// `limit` truncates them, the oldest changes are returned with a token for
+/// the last of them and a 507 for the collection, so the client continues
+/// from there (RFC 6578 §3.6).
+pub fn multistatus(
+    collection_href: &str,
+    mut changes: Vec<(i64, Change)>,
+    limit: Option<usize>,
+    current: i64,
+) -> MultiStatus {
+    changes.sort_by_key(|(revision, _)| *revision);
+    let mut token = current;
+    let mut truncated = false;
+    if let Some(limit) = limit {
+        if changes.len() > limit {
+            changes.truncate(limit);
+            token = changes.last().map_or(current, |(revision, _)| *revision);
+            truncated = true;
+        }
+    }
+
+    let mut multistatus = MultiStatus::new();
+    for (_, change) in changes {
+        multistatus.push(match change {
+            Change::Updated(response) => response,
+            Change::Deleted(href) => Response::status(href, StatusCode::NOT_FOUND),
+        });
+    }
+    if truncated {
+        multistatus.push(Response::status(
+            collection_href,
+            StatusCode::INSUFFICIENT_STORAGE,
+        ));
+    }
+    multistatus.set_sync_token(format_token(token));
+    multistatus
+}
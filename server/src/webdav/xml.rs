@@ -0,0 +1,400 @@
+//! DAV XML request parsing and multistatus responses (RFC 4918)
+//!
+//! Request bodies are small, so they are parsed into a namespace-resolved
+//! element tree. Responses are written as text with a fixed set of prefixes.
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+use std::fmt;
+
+pub const DAV: &str = "DAV:";
+pub const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+pub const CARDDAV: &str = "urn:ietf:params:xml:ns:carddav";
+/// Apple calendar server extensions (calendar proxies).
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// Prefixes declared on every response.
+const PREFIXES: &[(&str, &str)] = &[
+    ("d", DAV),
+    ("c", CALDAV),
+    ("card", CARDDAV),
+    ("cs", CALENDARSERVER),
+];
+
+/// Largest request body accepted by [`parse`].
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct XmlError(String);
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid XML: {}", self.0)
+    }
+}
+
+impl std::error::Error for XmlError {}
+
+impl From<quick_xml::Error> for XmlError {
+    fn from(e: quick_xml::Error) -> Self {
+        XmlError(e.to_string())
+    }
+}
+
+/// An element with its namespace resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub namespace: String,
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.is(namespace, name))
+    }
+
+    pub fn children_named<'a>(
+        &'a self,
+        namespace: &'a str,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |c| c.is(namespace, name))
+    }
+
+    /// Attribute by local name (DAV attributes are not namespaced).
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn start_element(resolved: ResolveResult, start: &BytesStart) -> Result<Element, XmlError> {
+    let namespace = match resolved {
+        ResolveResult::Bound(ns) => String::from_utf8_lossy(ns.as_ref()).into_owned(),
+        _ => String::new(),
+    };
+    let mut attributes = Vec::new();
+    for attr in start.attributes() {
+        let attr = attr.map_err(|e| XmlError(e.to_string()))?;
+        let name = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+        attributes.push((name, attr.unescape_value()?.into_owned()));
+    }
+    Ok(Element {
+        namespace,
+        name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+        attributes,
+        children: Vec::new(),
+        text: String::new(),
+    })
+}
+
+/// Parse a request body into its root element.
+pub fn parse(body: &[u8]) -> Result<Element, XmlError> {
+    if body.len() > MAX_BODY_SIZE {
+        return Err(XmlError("request body too large".to_string()));
+    }
+    let text = std::str::from_utf8(body).map_err(|e| XmlError(e.to_string()))?;
+    let mut reader = NsReader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut stack: Vec<Element> = Vec::new();
+    loop {
+        let (resolved, event) = reader.read_resolved_event()?;
+        let finished = match event {
+            Event::Start(start) => {
+                stack.push(start_element(resolved, &start)?);
+                None
+            }
+            Event::Empty(start) => Some(start_element(resolved, &start)?),
+            Event::End(_) => Some(
+                stack
+                    .pop()
+                    .ok_or_else(|| XmlError("unbalanced end tag".to_string()))?,
+            ),
+            Event::Text(text) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&text.unescape()?);
+                }
+                None
+            }
+            Event::CData(data) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&String::from_utf8_lossy(&data));
+                }
+                None
+            }
+            Event::Eof => return Err(XmlError("unexpected end of document".to_string())),
+            _ => None,
+        };
+        if let Some(element) = finished {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => return Ok(element),
+            }
+        }
+    }
+}
+
+/// Properties requested by a PROPFIND body; `None` for `allprop` or an
+/// empty body, which callers answer with their default set.
+pub fn requested_props(body: &[u8]) -> Result<Option<Vec<PropName>>, XmlError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let root = parse(body)?;
+    if !root.is(DAV, "propfind") {
+        return Err(XmlError("expected a DAV:propfind body".to_string()));
+    }
+    Ok(root
+        .child(DAV, "prop")
+        .map(|prop| prop.children.iter().map(PropName::of).collect()))
+}
+
+/// Whether a PROPFIND is limited to the resource itself (`Depth: 0`). A
+/// missing header is treated as depth 1.
+pub fn depth_zero(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("Depth")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|depth| depth.trim() == "0")
+}
+
+/// Pre-rendered `DAV:href` elements.
+pub fn hrefs<I, S>(hrefs: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    hrefs
+        .into_iter()
+        .map(|href| format!("<d:href>{}</d:href>", escape(href.as_ref())))
+        .collect()
+}
+
+/// A namespace-qualified property name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PropName {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl PropName {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn of(element: &Element) -> Self {
+        Self::new(&element.namespace, &element.name)
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// Response prefix of the namespace, if it is one of [`PREFIXES`].
+    fn prefix(&self) -> Option<&'static str> {
+        PREFIXES
+            .iter()
+            .find(|(_, ns)| *ns == self.namespace)
+            .map(|(prefix, _)| *prefix)
+    }
+
+    fn open(&self, out: &mut String, empty: bool) {
+        match self.prefix() {
+            Some(prefix) => out.push_str(&format!("<{}:{}", prefix, self.name)),
+            None => out.push_str(&format!(
+                "<x:{} xmlns:x=\"{}\"",
+                self.name,
+                escape(&self.namespace)
+            )),
+        }
+        out.push_str(if empty { "/>" } else { ">" });
+    }
+
+    fn close(&self, out: &mut String) {
+        out.push_str(&format!(
+            "</{}:{}>",
+            self.prefix().unwrap_or("x"),
+            self.name
+        ));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PropValue {
+    Empty,
+    /// Text content, escaped on output.
+    Text(String),
+    /// Pre-rendered XML using the response prefixes (`d:`, `c:`, `card:`,
+    /// `cs:`).
+    Xml(String),
+}
+
+/// One `DAV:response` of a multistatus.
+#[derive(Debug, Clone)]
+pub struct Response {
+    href: String,
+    status: Option<StatusCode>,
+    found: Vec<(PropName, PropValue)>,
+    missing: Vec<PropName>,
+}
+
+impl Response {
+    pub fn new(href: impl Into<String>) -> Self {
+        Self {
+            href: href.into(),
+            status: None,
+            found: Vec::new(),
+            missing: Vec::new(),
+        }
+    }
+
+    /// A response carrying only a status, e.g. 404 for an unknown href.
+    pub fn status(href: impl Into<String>, status: StatusCode) -> Self {
+        Self {
+            status: Some(status),
+            ..Self::new(href)
+        }
+    }
+
+    pub fn prop(&mut self, name: PropName, value: PropValue) {
+        self.found.push((name, value));
+    }
+
+    pub fn not_found(&mut self, name: PropName) {
+        self.missing.push(name);
+    }
+
+    fn write(&self, out: &mut String) {
+        out.push_str("<d:response><d:href>");
+        out.push_str(&escape(&self.href));
+        out.push_str("</d:href>");
+        if let Some(status) = self.status {
+            write_status(out, status);
+        }
+        if !self.found.is_empty() {
+            out.push_str("<d:propstat><d:prop>");
+            for (name, value) in &self.found {
+                match value {
+                    PropValue::Empty => name.open(out, true),
+                    PropValue::Text(text) => {
+                        name.open(out, false);
+                        out.push_str(&escape(text));
+                        name.close(out);
+                    }
+                    PropValue::Xml(xml) => {
+                        name.open(out, false);
+                        out.push_str(xml);
+                        name.close(out);
+                    }
+                }
+            }
+            out.push_str("</d:prop>");
+            write_status(out, StatusCode::OK);
+            out.push_str("</d:propstat>");
+        }
+        if !self.missing.is_empty() {
+            out.push_str("<d:propstat><d:prop>");
+            for name in &self.missing {
+                name.open(out, true);
+            }
+            out.push_str("</d:prop>");
+            write_status(out, StatusCode::NOT_FOUND);
+            out.push_str("</d:propstat>");
+        }
+        out.push_str("</d:response>");
+    }
+}
+
+fn write_status(out: &mut String, status: StatusCode) {
+    out.push_str(&format!(
+        "<d:status>HTTP/1.1 {} {}</d:status>",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("")
+    ));
+}
+
+/// Open the document element, declaring the response prefixes. `root` must
+/// be in one of [`PREFIXES`].
+fn write_root_open(out: &mut String, root: &PropName) {
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>");
+    out.push_str(&format!("<{}:{}", root.prefix().unwrap_or("d"), root.name));
+    for (prefix, namespace) in PREFIXES {
+        out.push_str(&format!(" xmlns:{}=\"{}\"", prefix, namespace));
+    }
+    out.push('>');
+}
+
+/// An XML response whose document element `root` wraps `body`, which is
+/// pre-rendered XML using the response prefixes.
+pub fn document_response(status: StatusCode, root: PropName, body: &str) -> HttpResponse {
+    let mut out = String::new();
+    write_root_open(&mut out, &root);
+    out.push_str(body);
+    root.close(&mut out);
+    HttpResponse::build(status)
+        .content_type("application/xml; charset=utf-8")
+        .body(out)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MultiStatus {
+    responses: Vec<Response>,
+    sync_token: Option<String>,
+}
+
+impl MultiStatus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, response: Response) {
+        self.responses.push(response);
+    }
+
+    pub fn set_sync_token(&mut self, token: impl Into<String>) {
+        self.sync_token = Some(token.into());
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut out = String::new();
+        write_root_open(&mut out, &PropName::new(DAV, "multistatus"));
+        for response in &self.responses {
+            response.write(&mut out);
+        }
+        if let Some(token) = &self.sync_token {
+            out.push_str(&format!("<d:sync-token>{}</d:sync-token>", escape(token)));
+        }
+        out.push_str("</d:multistatus>");
+        out
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        HttpResponse::build(StatusCode::MULTI_STATUS)
+            .content_type("application/xml; charset=utf-8")
+            .body(self.to_xml())
+    }
+}
+
+/// A `DAV:error` body naming the failed precondition (RFC 4918 §16).
+pub fn error_response(status: StatusCode, condition: PropName) -> HttpResponse {
+    let mut body = String::new();
+    condition.open(&mut body, true);
+    document_response(status, PropName::new(DAV, "error"), &body)
+}
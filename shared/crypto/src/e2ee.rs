@@ -0,0 +1,552 @@
+//! End-to-end encryption of synced folders
+//!
+//! Every device holds an X25519 key pair. A folder's key is sealed for each
+//! device allowed to read the folder: it is encrypted with AES-256-GCM under
+//! a key derived from a Diffie-Hellman exchange between a throwaway key pair
+//! and the device's public key, so only that device can open it.
+//!
+//! Each file gets a random key of its own, wrapped by the folder key and
+//! kept in the header of the encrypted content together with the checksum
+//! and size of the plaintext, so a device can tell what a file holds by
+//! reading its first [`CONTENT_HEADER_LEN`] bytes. The body is encrypted in
+//! chunks with [`FileEncryption`]. Names are encrypted deterministically, so
+//! a path always maps to the same remote path.
+
+use crate::encryption::{CryptoError, EncryptionKey, FileEncryption};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::io::{Read, Write};
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Starts encrypted content; the last byte is the format version.
+pub const CONTENT_MAGIC: &[u8; 4] = b"RCE\x01";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// A key wrapped by another: nonce, encrypted key and tag.
+pub const WRAPPED_KEY_LEN: usize = NONCE_LEN + KEY_LEN + TAG_LEN;
+/// A key sealed for a device: the throwaway public key, then the wrapped key.
+pub const SEALED_KEY_LEN: usize = KEY_LEN + WRAPPED_KEY_LEN;
+/// Checksum and size of the plaintext, encrypted with the file key.
+const INFO_LEN: usize = NONCE_LEN + 32 + 8 + TAG_LEN;
+/// Bytes of encrypted content before its body.
+pub const CONTENT_HEADER_LEN: usize = CONTENT_MAGIC.len() + WRAPPED_KEY_LEN + INFO_LEN;
+/// Plaintext bytes per encrypted chunk of a body.
+const CONTENT_CHUNK: usize = 64 * 1024;
+
+const SEAL_CONTEXT: &str = "rouillecloud 2024-06-01 e2ee folder key seal";
+const NAME_KEY_CONTEXT: &str = "rouillecloud 2024-06-01 e2ee name encryption";
+const NAME_NONCE_CONTEXT: &str = "rouillecloud 2024-06-01 e2ee name nonce";
+const FINGERPRINT_CONTEXT: &str = "rouillecloud 2024-06-01 e2ee public key fingerprint";
+
+/// Text form of keys and sealed keys, as exchanged with the server.
+pub fn to_base64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn from_base64(text: &str) -> Result<Vec<u8>, CryptoError> {
+    URL_SAFE_NO_PAD
+        .decode(text)
+        .map_err(|_| CryptoError::InvalidEncoding)
+}
+
+/// Whether `data` looks like content encrypted by [`encrypt_content`].
+pub fn is_encrypted_content(data: &[u8]) -> bool {
+    data.len() >= CONTENT_HEADER_LEN && data.starts_with(CONTENT_MAGIC)
+}
+
+fn cipher(key: &EncryptionKey) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_bytes()))
+}
+
+/// Encrypt `key` under `kek`.
+pub fn wrap_key(kek: &EncryptionKey, key: &EncryptionKey) -> Result<Vec<u8>, CryptoError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher(kek)
+        .encrypt(&nonce, key.as_bytes())
+        .map_err(|_| CryptoError::EncryptionFailed)?;
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend_from_slice(&encrypted);
+    Ok(wrapped)
+}
+
+pub fn unwrap_key(kek: &EncryptionKey, wrapped: &[u8]) -> Result<EncryptionKey, CryptoError> {
+    if wrapped.len() != WRAPPED_KEY_LEN {
+        return Err(CryptoError::InvalidKeySize);
+    }
+    let (nonce, encrypted) = wrapped.split_at(NONCE_LEN);
+    let key = Zeroizing::new(
+        cipher(kek)
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| CryptoError::DecryptionFailed)?,
+    );
+    EncryptionKey::from_slice(&key)
+}
+
+/// Key encrypting a sealed key, from the exchange between the throwaway
+/// and the recipient's key pairs.
+fn seal_kek(
+    shared: SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<EncryptionKey, CryptoError> {
+    // A low order public key would make the secret predictable.
+    if !shared.was_contributory() {
+        return Err(CryptoError::InvalidPublicKey);
+    }
+    let mut material = Zeroizing::new(Vec::with_capacity(3 * KEY_LEN));
+    material.extend_from_slice(shared.as_bytes());
+    material.extend_from_slice(ephemeral.as_bytes());
+    material.extend_from_slice(recipient.as_bytes());
+    Ok(EncryptionKey::new(blake3::derive_key(
+        SEAL_CONTEXT,
+        &material,
+    )))
+}
+
+/// Encrypt `key` so only the holder of the secret matching `recipient`, a
+/// device's public key, can open it.
+pub fn seal_key(recipient: &[u8; 32], key: &EncryptionKey) -> Result<Vec<u8>, CryptoError> {
+    let recipient = PublicKey::from(*recipient);
+    let ephemeral = DeviceKeyPair::generate();
+    let kek = seal_kek(
+        ephemeral.secret.diffie_hellman(&recipient),
+        &ephemeral.public,
+        &recipient,
+    )?;
+    let mut sealed = ephemeral.public.to_bytes().to_vec();
+    sealed.extend_from_slice(&wrap_key(&kek, key)?);
+    Ok(sealed)
+}
+
+/// Short text identifying a device's public key, for a user to compare
+/// between two devices before sealing a folder key for one of them: eight
+/// groups of four hex digits.
+pub fn key_fingerprint(public_key: &[u8; 32]) -> String {
+    let hash = blake3::derive_key(FINGERPRINT_CONTEXT, public_key);
+    hash[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// A device's key pair.
+pub struct DeviceKeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl DeviceKeyPair {
+    pub fn generate() -> Self {
+        let mut secret = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut *secret);
+        Self::from_secret(*secret)
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn secret_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.secret.to_bytes())
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Open a key sealed for this device with [`seal_key`].
+    pub fn open_key(&self, sealed: &[u8]) -> Result<EncryptionKey, CryptoError> {
+        if sealed.len() != SEALED_KEY_LEN {
+            return Err(CryptoError::InvalidKeySize);
+        }
+        let (ephemeral, wrapped) = sealed.split_at(KEY_LEN);
+        let mut bytes = [0u8; KEY_LEN];
+        bytes.copy_from_slice(ephemeral);
+        let ephemeral = PublicKey::from(bytes);
+        let kek = seal_kek(
+            self.secret.diffie_hellman(&ephemeral),
+            &ephemeral,
+            &self.public,
+        )?;
+        unwrap_key(&kek, wrapped)
+    }
+}
+
+/// Deterministic encryption of the names in a folder: the nonce is a keyed
+/// hash of the name, so equal names encrypt alike and nothing else does.
+pub struct NameCipher {
+    cipher: Aes256Gcm,
+    nonce_key: Zeroizing<[u8; 32]>,
+}
+
+impl NameCipher {
+    pub fn new(folder_key: &EncryptionKey) -> Self {
+        let key = EncryptionKey::new(blake3::derive_key(NAME_KEY_CONTEXT, folder_key.as_bytes()));
+        Self {
+            cipher: cipher(&key),
+            nonce_key: Zeroizing::new(blake3::derive_key(
+                NAME_NONCE_CONTEXT,
+                folder_key.as_bytes(),
+            )),
+        }
+    }
+
+    fn nonce(&self, name: &str) -> [u8; NONCE_LEN] {
+        let hash = blake3::keyed_hash(&self.nonce_key, name.as_bytes());
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&hash.as_bytes()[..NONCE_LEN]);
+        nonce
+    }
+
+    /// The encrypted form of `name`, usable as a file name.
+    pub fn encrypt(&self, name: &str) -> Result<String, CryptoError> {
+        let nonce = self.nonce(name);
+        let encrypted = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), name.as_bytes())
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&encrypted);
+        Ok(to_base64(&bytes))
+    }
+
+    pub fn decrypt(&self, encrypted: &str) -> Result<String, CryptoError> {
+        let bytes = from_base64(encrypted)?;
+        if bytes.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::InvalidEncoding);
+        }
+        let (nonce, data) = bytes.split_at(NONCE_LEN);
+        let name = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), data)
+            .map_err(|_| CryptoError::DecryptionFailed)?;
+        let name = String::from_utf8(name).map_err(|_| CryptoError::DecryptionFailed)?;
+        if self.nonce(&name) != nonce {
+            return Err(CryptoError::DecryptionFailed);
+        }
+        Ok(name)
+    }
+}
+
+/// What a file's plaintext is, as recorded in its encrypted header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentInfo {
+    /// BLAKE3 hash, in hex.
+    pub checksum: String,
+    pub size: u64,
+}
+
+struct HashingReader<'a, R> {
+    inner: &'a mut R,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The file key and plaintext description in an encrypted header.
+fn open_header(
+    folder_key: &EncryptionKey,
+    header: &[u8],
+) -> Result<(EncryptionKey, ContentInfo), CryptoError> {
+    if header.len() < CONTENT_HEADER_LEN || !header.starts_with(CONTENT_MAGIC) {
+        return Err(CryptoError::InvalidHeader);
+    }
+    let (clear, info) =
+        header[..CONTENT_HEADER_LEN].split_at(CONTENT_MAGIC.len() + WRAPPED_KEY_LEN);
+    let file_key = unwrap_key(folder_key, &clear[CONTENT_MAGIC.len()..])?;
+    let (nonce, encrypted) = info.split_at(NONCE_LEN);
+    let info = cipher(&file_key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: encrypted,
+                aad: clear,
+            },
+        )
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    let (checksum, size) = info.split_at(32);
+    let mut size_bytes = [0u8; 8];
+    size_bytes.copy_from_slice(size);
+    let mut checksum_bytes = [0u8; 32];
+    checksum_bytes.copy_from_slice(checksum);
+    Ok((
+        file_key,
+        ContentInfo {
+            checksum: blake3::Hash::from_bytes(checksum_bytes)
+                .to_hex()
+                .to_string(),
+            size: u64::from_le_bytes(size_bytes),
+        },
+    ))
+}
+
+/// What the content whose first bytes are `header` decrypts to.
+pub fn content_info(folder_key: &EncryptionKey, header: &[u8]) -> Result<ContentInfo, CryptoError> {
+    open_header(folder_key, header).map(|(_, info)| info)
+}
+
+/// Encrypt `reader`, whose plaintext is expected to be `info`, under a new
+/// file key wrapped by `folder_key`. Returns what was actually read: when
+/// it differs from `info` the output is wrong and must be discarded.
+pub fn encrypt_content<R: Read, W: Write>(
+    folder_key: &EncryptionKey,
+    info: &ContentInfo,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<ContentInfo, CryptoError> {
+    let checksum =
+        blake3::Hash::from_hex(&info.checksum).map_err(|_| CryptoError::InvalidHeader)?;
+    let file_key = EncryptionKey::generate();
+    let mut clear = CONTENT_MAGIC.to_vec();
+    clear.extend_from_slice(&wrap_key(folder_key, &file_key)?);
+    let mut plain_info = Zeroizing::new(checksum.as_bytes().to_vec());
+    plain_info.extend_from_slice(&info.size.to_le_bytes());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted_info = cipher(&file_key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plain_info,
+                aad: &clear,
+            },
+        )
+        .map_err(|_| CryptoError::EncryptionFailed)?;
+    writer
+        .write_all(&clear)
+        .and_then(|_| writer.write_all(&nonce))
+        .and_then(|_| writer.write_all(&encrypted_info))
+        .map_err(|_| CryptoError::IoError)?;
+
+    let mut reader = HashingReader {
+        inner: reader,
+        hasher: blake3::Hasher::new(),
+        size: 0,
+    };
+    FileEncryption::new(&file_key).encrypt_stream(&mut reader, writer, CONTENT_CHUNK)?;
+    Ok(ContentInfo {
+        checksum: reader.hasher.finalize().to_hex().to_string(),
+        size: reader.size,
+    })
+}
+
+/// Decrypt content written by [`encrypt_content`], failing unless the
+/// plaintext matches its header.
+pub fn decrypt_content<R: Read, W: Write>(
+    folder_key: &EncryptionKey,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<ContentInfo, CryptoError> {
+    let mut header = [0u8; CONTENT_HEADER_LEN];
+    reader.read_exact(&mut header).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => CryptoError::InvalidHeader,
+        _ => CryptoError::IoError,
+    })?;
+    let (file_key, info) = open_header(folder_key, &header)?;
+    let mut writer = HashingWriter {
+        inner: writer,
+        hasher: blake3::Hasher::new(),
+        size: 0,
+    };
+    FileEncryption::new(&file_key).decrypt_stream(reader, &mut writer)?;
+    let checksum = writer.hasher.finalize().to_hex().to_string();
+    if checksum != info.checksum || writer.size != info.size {
+        return Err(CryptoError::DecryptionFailed);
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{STREAM_HEADER_LEN, STREAM_TAG_LEN};
+    use std::io::Cursor;
+
+    fn info_of(data: &[u8]) -> ContentInfo {
+        ContentInfo {
+            checksum: blake3::hash(data).to_hex().to_string(),
+            size: data.len() as u64,
+        }
+    }
+
+    #[test]
+    fn test_sealed_key_opens_only_for_recipient() {
+        let device = DeviceKeyPair::generate();
+        let other = DeviceKeyPair::generate();
+        let folder_key = EncryptionKey::generate();
+
+        let sealed = seal_key(&device.public_key(), &folder_key).unwrap();
+        assert_eq!(sealed.len(), SEALED_KEY_LEN);
+        let opened = device.open_key(&sealed).unwrap();
+        assert_eq!(opened.as_bytes(), folder_key.as_bytes());
+        assert!(other.open_key(&sealed).is_err());
+
+        let restored = DeviceKeyPair::from_secret(*device.secret_bytes());
+        assert_eq!(restored.public_key(), device.public_key());
+        assert!(restored.open_key(&sealed).is_ok());
+    }
+
+    #[test]
+    fn test_fingerprints_tell_keys_apart() {
+        let device = DeviceKeyPair::generate();
+        let other = DeviceKeyPair::generate();
+        let fingerprint = key_fingerprint(&device.public_key());
+        assert_eq!(fingerprint.len(), 39);
+        assert_eq!(fingerprint.split('-').count(), 8);
+        assert_eq!(fingerprint, key_fingerprint(&device.public_key()));
+        assert_ne!(fingerprint, key_fingerprint(&other.public_key()));
+    }
+
+    #[test]
+    fn test_low_order_public_key_is_refused() {
+        let key = EncryptionKey::generate();
+        assert!(matches!(
+            seal_key(&[0u8; 32], &key),
+            Err(CryptoError::InvalidPublicKey)
+        ));
+    }
+
+    #[test]
+    fn test_wrapped_key_round_trip() {
+        let kek = EncryptionKey::generate();
+        let key = EncryptionKey::generate();
+        let mut wrapped = wrap_key(&kek, &key).unwrap();
+        assert_eq!(wrapped.len(), WRAPPED_KEY_LEN);
+        assert_eq!(
+            unwrap_key(&kek, &wrapped).unwrap().as_bytes(),
+            key.as_bytes()
+        );
+
+        wrapped[NONCE_LEN] ^= 1;
+        assert!(unwrap_key(&kek, &wrapped).is_err());
+    }
+
+    #[test]
+    fn test_names_encrypt_deterministically() {
+        let key = EncryptionKey::generate();
+        let names = NameCipher::new(&key);
+        let encrypted = names.encrypt("report.pdf").unwrap();
+        assert_eq!(encrypted, names.encrypt("report.pdf").unwrap());
+        assert_ne!(encrypted, names.encrypt("report.txt").unwrap());
+        assert!(!encrypted.contains('/'));
+        assert_eq!(names.decrypt(&encrypted).unwrap(), "report.pdf");
+
+        let other = NameCipher::new(&EncryptionKey::generate());
+        assert_ne!(other.encrypt("report.pdf").unwrap(), encrypted);
+        assert!(other.decrypt(&encrypted).is_err());
+        assert!(names.decrypt("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_content_round_trip() {
+        let folder_key = EncryptionKey::generate();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut encrypted = Vec::new();
+        let read = encrypt_content(
+            &folder_key,
+            &info_of(&data),
+            &mut Cursor::new(&data),
+            &mut encrypted,
+        )
+        .unwrap();
+        assert_eq!(read, info_of(&data));
+        assert!(is_encrypted_content(&encrypted));
+        assert!(!encrypted.windows(64).any(|w| w == &data[1000..1064]));
+
+        assert_eq!(
+            content_info(&folder_key, &encrypted[..CONTENT_HEADER_LEN]).unwrap(),
+            info_of(&data)
+        );
+        let mut decrypted = Vec::new();
+        let info =
+            decrypt_content(&folder_key, &mut Cursor::new(&encrypted), &mut decrypted).unwrap();
+        assert_eq!(info, info_of(&data));
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn test_changed_input_is_reported() {
+        let folder_key = EncryptionKey::generate();
+        let mut encrypted = Vec::new();
+        let read = encrypt_content(
+            &folder_key,
+            &info_of(b"expected"),
+            &mut Cursor::new(b"actual"),
+            &mut encrypted,
+        )
+        .unwrap();
+        assert_eq!(read, info_of(b"actual"));
+        // The header describes what was expected, so the body fails to match.
+        assert!(
+            decrypt_content(&folder_key, &mut Cursor::new(&encrypted), &mut Vec::new()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_tampered_or_truncated_content_fails() {
+        let folder_key = EncryptionKey::generate();
+        let data = vec![7u8; 150_000];
+        let mut encrypted = Vec::new();
+        encrypt_content(
+            &folder_key,
+            &info_of(&data),
+            &mut Cursor::new(&data),
+            &mut encrypted,
+        )
+        .unwrap();
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(
+            decrypt_content(&folder_key, &mut Cursor::new(&tampered), &mut Vec::new()).is_err()
+        );
+
+        // Dropping the last chunk leaves a stream without its last chunk.
+        let first_chunk = CONTENT_HEADER_LEN + STREAM_HEADER_LEN + CONTENT_CHUNK + STREAM_TAG_LEN;
+        let truncated = &encrypted[..first_chunk];
+        assert!(
+            decrypt_content(&folder_key, &mut Cursor::new(truncated), &mut Vec::new()).is_err()
+        );
+
+        let wrong = EncryptionKey::generate();
+        assert!(content_info(&wrong, &encrypted).is_err());
+        assert!(matches!(
+            content_info(&folder_key, &encrypted[..10]),
+            Err(CryptoError::InvalidHeader)
+        ));
+    }
+}
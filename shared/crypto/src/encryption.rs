@@ -1,11 +1,23 @@
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
     Aes256Gcm, Key, Nonce,
 };
 use serde::{Deserialize, Serialize};
 use zeroize::Zeroize;
 use aes_gcm::aead::rand_core::RngCore;
-//use std::io::{Read, Write};
+use std::io::{Read, Write};
+
+/// Starts a stream written by [`FileEncryption::encrypt_stream`].
+pub const STREAM_MAGIC: &[u8; 4] = b"RCST";
+pub const STREAM_VERSION: u8 = 1;
+const NONCE_PREFIX_LEN: usize = 7;
+/// Magic, version, key id, chunk size and nonce prefix.
+pub const STREAM_HEADER_LEN: usize = STREAM_MAGIC.len() + 1 + 4 + 4 + NONCE_PREFIX_LEN;
+/// Added to every chunk once encrypted.
+pub const STREAM_TAG_LEN: usize = 16;
+/// Largest chunk a stream may be written or read with, which bounds what
+/// decrypting an untrusted stream allocates.
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedData {
@@ -57,15 +69,127 @@ impl Zeroize for EncryptionKey {
     }
 }
 
+/// Header of an encrypted stream.
+///
+/// A stream is encrypted STREAM-style: chunk `i` is sealed with the nonce
+/// made of a random prefix, `i` as a big-endian 32-bit counter and a byte
+/// telling whether it is the last chunk, and with the header as associated
+/// data. Reordering, dropping, truncating or extending chunks, or editing
+/// the header, all make decryption fail. Every chunk but the last holds
+/// exactly `chunk_size` bytes, so chunk `i` can be located and decrypted
+/// on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamHeader {
+    /// Identifies the key the stream is encrypted with.
+    pub key_id: u32,
+    pub chunk_size: u32,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+}
+
+impl StreamHeader {
+    fn new(key_id: u32, chunk_size: usize) -> Result<Self, CryptoError> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(CryptoError::InvalidChunk);
+        }
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        Ok(Self {
+            key_id,
+            chunk_size: chunk_size as u32,
+            nonce_prefix,
+        })
+    }
+
+    /// The header at the start of `data`.
+    pub fn parse(data: &[u8]) -> Result<Self, CryptoError> {
+        if data.len() < STREAM_HEADER_LEN
+            || !data.starts_with(STREAM_MAGIC)
+            || data[STREAM_MAGIC.len()] != STREAM_VERSION
+        {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let field = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let key_id = field(5);
+        let chunk_size = field(9);
+        if chunk_size == 0 || chunk_size as usize > MAX_CHUNK_SIZE {
+            return Err(CryptoError::InvalidChunk);
+        }
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        nonce_prefix.copy_from_slice(&data[13..STREAM_HEADER_LEN]);
+        Ok(Self {
+            key_id,
+            chunk_size,
+            nonce_prefix,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; STREAM_HEADER_LEN] {
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        header[..4].copy_from_slice(STREAM_MAGIC);
+        header[4] = STREAM_VERSION;
+        header[5..9].copy_from_slice(&self.key_id.to_le_bytes());
+        header[9..13].copy_from_slice(&self.chunk_size.to_le_bytes());
+        header[13..].copy_from_slice(&self.nonce_prefix);
+        header
+    }
+
+    /// Length of an encrypted chunk; only the last one may be shorter.
+    pub fn record_len(&self) -> u64 {
+        self.chunk_size as u64 + STREAM_TAG_LEN as u64
+    }
+
+    /// Where chunk `index` starts, from the start of the stream.
+    pub fn chunk_offset(&self, index: u64) -> u64 {
+        STREAM_HEADER_LEN as u64 + index * self.record_len()
+    }
+
+    /// How many chunks encrypt `plaintext_len` bytes. Never zero: an empty
+    /// stream still has its last chunk.
+    pub fn chunk_count(&self, plaintext_len: u64) -> u64 {
+        plaintext_len.div_ceil(self.chunk_size as u64).max(1)
+    }
+
+    fn nonce(&self, index: u64, last: bool) -> Result<[u8; 12], CryptoError> {
+        let counter = u32::try_from(index).map_err(|_| CryptoError::InvalidChunk)?;
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&counter.to_be_bytes());
+        nonce[11] = last as u8;
+        Ok(nonce)
+    }
+}
+
+/// Fill `buffer` from `reader`, short only at the end of the input.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, CryptoError> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return Err(CryptoError::IoError),
+        }
+    }
+    Ok(filled)
+}
+
 pub struct FileEncryption {
     cipher: Aes256Gcm,
+    key_id: u32,
 }
 
 impl FileEncryption {
     pub fn new(key: &EncryptionKey) -> Self {
         let aes_key = Key::<Aes256Gcm>::from_slice(key.as_bytes());
         let cipher = Aes256Gcm::new(aes_key);
-        Self { cipher }
+        Self { cipher, key_id: 0 }
+    }
+
+    /// Record `key_id` in the streams this encrypts, and only decrypt
+    /// streams recording it.
+    pub fn with_key_id(mut self, key_id: u32) -> Self {
+        self.key_id = key_id;
+        self
     }
     
     pub fn encrypt(&self, data: &[u8]) -> Result<EncryptedData, CryptoError> {
@@ -101,76 +225,132 @@ impl FileEncryption {
             .map_err(|_| CryptoError::DecryptionFailed)
     }
     
-    pub fn encrypt_stream<R: std::io::Read, W: std::io::Write>(
+    /// Encrypt `reader` into `writer` as a stream of `chunk_size` byte
+    /// chunks, described by [`StreamHeader`].
+    pub fn encrypt_stream<R: Read, W: Write>(
         &self,
         reader: &mut R,
         writer: &mut W,
         chunk_size: usize,
     ) -> Result<(), CryptoError> {
-        let mut buffer = vec![0u8; chunk_size];
-        
+        let header = StreamHeader::new(self.key_id, chunk_size)?;
+        self.write_stream(&header, reader, writer)
+    }
+
+    fn write_stream<R: Read, W: Write>(
+        &self,
+        header: &StreamHeader,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<(), CryptoError> {
+        writer.write_all(&header.to_bytes())
+            .map_err(|_| CryptoError::IoError)?;
+
+        let chunk_size = header.chunk_size as usize;
+        let mut current = zeroize::Zeroizing::new(vec![0u8; chunk_size]);
+        let mut next = zeroize::Zeroizing::new(vec![0u8; chunk_size]);
+        let mut len = read_full(reader, &mut current)?;
+        let mut index = 0;
         loop {
-            let bytes_read = reader.read(&mut buffer)
+            // A full chunk is the last one when nothing follows it.
+            let next_len = match len {
+                len if len < chunk_size => 0,
+                _ => read_full(reader, &mut next)?,
+            };
+            let last = len < chunk_size || next_len == 0;
+            let record = self.encrypt_chunk(header, index, &current[..len], last)?;
+            writer.write_all(&record)
                 .map_err(|_| CryptoError::IoError)?;
-            
-            if bytes_read == 0 {
-                break;
+            if last {
+                return Ok(());
             }
-            
-            let chunk = &buffer[..bytes_read];
-            let encrypted = self.encrypt(chunk)?;
-            
-            // Write encrypted chunk with length prefix
-            let chunk_len = (encrypted.data.len() + encrypted.nonce.len() + encrypted.tag.len()) as u32;
-            writer.write_all(&chunk_len.to_le_bytes())
-                .map_err(|_| CryptoError::IoError)?;
-            writer.write_all(&encrypted.nonce)
-                .map_err(|_| CryptoError::IoError)?;
-            writer.write_all(&encrypted.tag)
-                .map_err(|_| CryptoError::IoError)?;
-            writer.write_all(&encrypted.data)
-                .map_err(|_| CryptoError::IoError)?;
+            std::mem::swap(&mut current, &mut next);
+            len = next_len;
+            index += 1;
         }
-        
-        Ok(())
     }
-    
-    pub fn decrypt_stream<R: std::io::Read, W: std::io::Write>(
+
+    fn encrypt_chunk(
+        &self,
+        header: &StreamHeader,
+        index: u64,
+        chunk: &[u8],
+        last: bool,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let nonce = header.nonce(index, last)?;
+        self.cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: chunk,
+                    aad: &header.to_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::EncryptionFailed)
+    }
+
+    /// Decrypt chunk `index` of the stream with `header`, the `record` found
+    /// at [`StreamHeader::chunk_offset`]; `last` tells whether the stream
+    /// ends with it.
+    pub fn decrypt_chunk(
+        &self,
+        header: &StreamHeader,
+        index: u64,
+        record: &[u8],
+        last: bool,
+    ) -> Result<Vec<u8>, CryptoError> {
+        if header.key_id != self.key_id {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let full = record.len() as u64 == header.record_len();
+        if record.len() < STREAM_TAG_LEN || record.len() as u64 > header.record_len() || (!last && !full) {
+            return Err(CryptoError::InvalidChunk);
+        }
+        let nonce = header.nonce(index, last)?;
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: record,
+                    aad: &header.to_bytes(),
+                },
+            )
+            .map_err(|_| CryptoError::DecryptionFailed)
+    }
+
+    /// Decrypt a stream written by [`FileEncryption::encrypt_stream`],
+    /// failing if it was cut short or altered in any way. Plaintext is
+    /// written out as chunks are authenticated, so on failure what was
+    /// written must be discarded.
+    pub fn decrypt_stream<R: Read, W: Write>(
         &self,
         reader: &mut R,
         writer: &mut W,
     ) -> Result<(), CryptoError> {
-        let mut len_buffer = [0u8; 4];
-        
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => CryptoError::InvalidHeader,
+            _ => CryptoError::IoError,
+        })?;
+        let header = StreamHeader::parse(&header)?;
+        let record_len = header.record_len() as usize;
+
+        // One byte more than a record, to tell whether another one follows.
+        let mut buffer = vec![0u8; record_len + 1];
+        let mut filled = read_full(reader, &mut buffer)?;
+        let mut index = 0;
         loop {
-            match reader.read_exact(&mut len_buffer) {
-                Ok(_) => {},
-                Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(_) => return Err(CryptoError::IoError),
-            }
-            
-            let chunk_len = u32::from_le_bytes(len_buffer) as usize;
-            let mut chunk_buffer = vec![0u8; chunk_len];
-            
-            reader.read_exact(&mut chunk_buffer)
+            let last = filled <= record_len;
+            let chunk = self.decrypt_chunk(&header, index, &buffer[..filled.min(record_len)], last)?;
+            writer.write_all(&chunk)
                 .map_err(|_| CryptoError::IoError)?;
-            
-            if chunk_len < 28 { // 12 (nonce) + 16 (tag) minimum
-                return Err(CryptoError::InvalidChunk);
+            if last {
+                return Ok(());
             }
-            
-            let encrypted = EncryptedData {
-                nonce: chunk_buffer[0..12].to_vec(),
-                tag: chunk_buffer[12..28].to_vec(),
-                data: chunk_buffer[28..].to_vec(),
-            };
-            
-            let decrypted = self.decrypt(&encrypted)?;
-            writer.write_all(&decrypted)
-                .map_err(|_| CryptoError::IoError)?;
+            buffer[0] = buffer[record_len];
+            filled = 1 + read_full(reader, &mut buffer[1..])?;
+            index += 1;
         }
-        
-        Ok(())
     }
 }
 
@@ -188,6 +368,14 @@ pub enum CryptoError {
     IoError,
     #[error("Invalid chunk")]
     InvalidChunk,
+    #[error("Invalid encrypted content header")]
+    InvalidHeader,
+    #[error("Invalid encoding")]
+    InvalidEncoding,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Invalid key derivation parameters")]
+    InvalidKdfParameters,
 }
 
 #[cfg(test)]
@@ -224,4 +412,153 @@ mod tests {
         
         assert_eq!(data, decrypted_buffer.as_slice());
     }
+
+    fn stream_of(data: &[u8], chunk_size: usize) -> (FileEncryption, Vec<u8>) {
+        let encryption = FileEncryption::new(&EncryptionKey::new([0x42; 32]));
+        let mut encrypted = Vec::new();
+        encryption
+            .encrypt_stream(&mut &data[..], &mut encrypted, chunk_size)
+            .unwrap();
+        (encryption, encrypted)
+    }
+
+    fn decrypts(encryption: &FileEncryption, encrypted: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut decrypted = Vec::new();
+        encryption.decrypt_stream(&mut &encrypted[..], &mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn test_stream_known_vector() {
+        let encryption = FileEncryption::new(&EncryptionKey::new([0x42; 32])).with_key_id(7);
+        let header = StreamHeader {
+            key_id: 7,
+            chunk_size: 16,
+            nonce_prefix: [1, 2, 3, 4, 5, 6, 7],
+        };
+        let mut encrypted = Vec::new();
+        encryption
+            .write_stream(&header, &mut &b"rouillecloud stream vector"[..], &mut encrypted)
+            .unwrap();
+        let hex: String = encrypted.iter().map(|b| format!("{:02x}", b)).collect();
+        let expected = concat!(
+            // magic, version, key id, chunk size, nonce prefix
+            "52435354", "01", "07000000", "10000000", "01020304050607",
+            // first chunk and its tag
+            "5c68cac01bef669d759a76cd097433866a74b667cae39d214d1e2bee3169ec78",
+            // last chunk and its tag
+            "be8917dd435a3dc78a757eed143530b2f5d122fbdb0e5ff8f315",
+        );
+        assert_eq!(hex, expected);
+        assert_eq!(decrypts(&encryption, &encrypted).unwrap(), b"rouillecloud stream vector");
+    }
+
+    #[test]
+    fn test_stream_chunk_boundaries() {
+        for len in [0usize, 1, 15, 16, 17, 32, 33] {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let (encryption, encrypted) = stream_of(&data, 16);
+            let header = StreamHeader::parse(&encrypted).unwrap();
+            let count = header.chunk_count(len as u64);
+            let last_len = len as u64 - (count - 1) * 16;
+            assert_eq!(
+                encrypted.len() as u64,
+                header.chunk_offset(count - 1) + last_len + STREAM_TAG_LEN as u64
+            );
+            assert_eq!(decrypts(&encryption, &encrypted).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_stream_tampering_fails() {
+        let data: Vec<u8> = (0..64).collect();
+        let (encryption, encrypted) = stream_of(&data, 16);
+        let header = StreamHeader::parse(&encrypted).unwrap();
+        let chunk = |i: u64| {
+            let start = header.chunk_offset(i) as usize;
+            encrypted[start..start + header.record_len() as usize].to_vec()
+        };
+        let body = |chunks: &[u64]| {
+            let mut stream = header.to_bytes().to_vec();
+            for &i in chunks {
+                stream.extend_from_slice(&chunk(i));
+            }
+            stream
+        };
+        assert_eq!(body(&[0, 1, 2, 3]), encrypted);
+
+        let mut flipped = encrypted.clone();
+        flipped[STREAM_HEADER_LEN + 20] ^= 1;
+        let mut cut = encrypted.clone();
+        cut.truncate(encrypted.len() - 5);
+        let mut extended = encrypted.clone();
+        extended.push(0);
+        let tampered = [
+            ("flipped byte", flipped),
+            ("reordered", body(&[1, 0, 2, 3])),
+            ("dropped chunk", body(&[0, 2, 3])),
+            ("dropped last chunk", body(&[0, 1, 2])),
+            ("repeated last chunk", body(&[0, 1, 2, 3, 3])),
+            ("cut in a chunk", cut),
+            ("extended", extended),
+            ("header only", header.to_bytes().to_vec()),
+        ];
+        for (case, stream) in tampered {
+            assert!(decrypts(&encryption, &stream).is_err(), "{}", case);
+        }
+
+        for at in [4, 5, 9, 13] {
+            let mut edited = encrypted.clone();
+            edited[at] ^= 1;
+            assert!(decrypts(&encryption, &edited).is_err(), "header byte {}", at);
+        }
+        let wrong_key = FileEncryption::new(&EncryptionKey::new([0x43; 32]));
+        assert!(decrypts(&wrong_key, &encrypted).is_err());
+        assert!(matches!(
+            decrypts(&encryption.with_key_id(1), &encrypted),
+            Err(CryptoError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn test_stream_chunk_size_is_bounded() {
+        let encryption = FileEncryption::new(&EncryptionKey::generate());
+        for chunk_size in [0, MAX_CHUNK_SIZE + 1] {
+            assert!(matches!(
+                encryption.encrypt_stream(&mut &b"data"[..], &mut Vec::new(), chunk_size),
+                Err(CryptoError::InvalidChunk)
+            ));
+        }
+        let (_, mut encrypted) = stream_of(b"data", 16);
+        encrypted[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            StreamHeader::parse(&encrypted),
+            Err(CryptoError::InvalidChunk)
+        ));
+    }
+
+    #[test]
+    fn test_stream_random_access() {
+        let data: Vec<u8> = (0..40).collect();
+        let (encryption, encrypted) = stream_of(&data, 16);
+        let header = StreamHeader::parse(&encrypted).unwrap();
+        assert_eq!(header.chunk_count(40), 3);
+        let record = |i: u64| {
+            let start = header.chunk_offset(i) as usize;
+            let end = (start + header.record_len() as usize).min(encrypted.len());
+            &encrypted[start..end]
+        };
+
+        assert_eq!(
+            encryption.decrypt_chunk(&header, 1, record(1), false).unwrap(),
+            data[16..32]
+        );
+        assert_eq!(
+            encryption.decrypt_chunk(&header, 2, record(2), true).unwrap(),
+            data[32..]
+        );
+        assert!(encryption.decrypt_chunk(&header, 0, record(1), false).is_err());
+        assert!(encryption.decrypt_chunk(&header, 1, record(1), true).is_err());
+        assert!(encryption.decrypt_chunk(&header, 2, record(2), false).is_err());
+    }
 }
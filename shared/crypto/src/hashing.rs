@@ -1,4 +1,5 @@
 use blake3::Hasher;
+use serde::{Deserialize, Serialize};
 use std::io::{Read, BufReader};
 use std::fs::File;
 use std::path::Path;
@@ -95,7 +96,7 @@ impl Default for FileHasher {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BlockHash {
     pub offset: usize,
     pub size: usize,
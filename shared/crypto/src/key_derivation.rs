@@ -1,22 +1,153 @@
-use rand::RngCore;
-use rand::rngs::OsRng;
+use crate::encryption::CryptoError;
+use crate::legacy;
+use argon2::{Algorithm, Argon2, Params, Version};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
 use blake3::derive_key;
-use zeroize::Zeroize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Shortest salt a password key is derived with.
+pub const MIN_SALT_LEN: usize = 16;
+const ARGON2ID_PREFIX: &str = "argon2id$v=19$";
+
+/// Cost of deriving a key from a password with Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordKdfCost {
+    /// Memory used, in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordKdfCost {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl PasswordKdfCost {
+    /// Upper bounds on what a stored cost may ask for, so a crafted one
+    /// cannot exhaust the machine deriving it.
+    pub const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+    pub const MAX_ITERATIONS: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 16;
+
+    fn params(&self) -> Result<Params, CryptoError> {
+        if self.memory_kib > Self::MAX_MEMORY_KIB
+            || self.iterations > Self::MAX_ITERATIONS
+            || self.parallelism > Self::MAX_PARALLELISM
+        {
+            return Err(CryptoError::InvalidKdfParameters);
+        }
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|_| CryptoError::InvalidKdfParameters)
+    }
+}
+
+/// What it takes to derive a key from a password again: the Argon2id cost
+/// and the salt. Kept next to whatever the key protects, written as
+/// `argon2id$v=19$m=<KiB>,t=<iterations>,p=<lanes>$<salt>` with the salt
+/// in URL-safe base64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordKeyParams {
+    pub cost: PasswordKdfCost,
+    pub salt: Vec<u8>,
+}
+
+impl PasswordKeyParams {
+    /// Parameters with `cost` and a new random salt.
+    pub fn generate(cost: PasswordKdfCost) -> Self {
+        Self {
+            cost,
+            salt: KeyDerivation::generate_salt(MIN_SALT_LEN),
+        }
+    }
+
+    /// The key for `context` derived from `password`.
+    pub fn derive(&self, password: &str, context: &str) -> Result<SecureKey, CryptoError> {
+        if self.salt.len() < MIN_SALT_LEN {
+            return Err(CryptoError::InvalidKdfParameters);
+        }
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.cost.params()?);
+        let mut output = Zeroizing::new([0u8; 32]);
+        argon2
+            .hash_password_into(password.as_bytes(), &self.salt, &mut *output)
+            .map_err(|_| CryptoError::InvalidKdfParameters)?;
+        Ok(SecureKey::new(derive_key(context, &*output), context.to_string()))
+    }
+}
+
+impl fmt::Display for PasswordKeyParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}m={},t={},p={}${}",
+            ARGON2ID_PREFIX,
+            self.cost.memory_kib,
+            self.cost.iterations,
+            self.cost.parallelism,
+            crate::to_base64(&self.salt)
+        )
+    }
+}
+
+impl FromStr for PasswordKeyParams {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CryptoError::InvalidKdfParameters;
+        let rest = s.strip_prefix(ARGON2ID_PREFIX).ok_or_else(invalid)?;
+        let (cost, salt) = rest.split_once('$').ok_or_else(invalid)?;
+        let mut values = [None; 3];
+        for pair in cost.split(',') {
+            let (name, value) = pair.split_once('=').ok_or_else(invalid)?;
+            let slot = match name {
+                "m" => 0,
+                "t" => 1,
+                "p" => 2,
+                _ => return Err(invalid()),
+            };
+            if values[slot].is_some() {
+                return Err(invalid());
+            }
+            values[slot] = Some(value.parse::<u32>().map_err(|_| invalid())?);
+        }
+        let [Some(memory_kib), Some(iterations), Some(parallelism)] = values else {
+            return Err(invalid());
+        };
+        let params = Self {
+            cost: PasswordKdfCost {
+                memory_kib,
+                iterations,
+                parallelism,
+            },
+            salt: crate::from_base64(salt)?,
+        };
+        params.cost.params()?;
+        if params.salt.len() < MIN_SALT_LEN {
+            return Err(invalid());
+        }
+        Ok(params)
+    }
+}
 
 pub struct KeyDerivation;
 
 impl KeyDerivation {
     /// Derive a key from a password using BLAKE3
+    #[deprecated(note = "not memory hard: use PasswordKeyParams::derive, or legacy::blake3_password_key to open old data")]
     pub fn derive_key_from_password(
         password: &str,
         salt: &[u8],
         context: &str,
     ) -> [u8; 32] {
-        let mut key_material = Vec::with_capacity(password.len() + salt.len());
-        key_material.extend_from_slice(password.as_bytes());
-        key_material.extend_from_slice(salt);
-        
-        derive_key(context, &key_material)
+        legacy::blake3_password_key(password, salt, context)
     }
     
     /// Generate a random salt
@@ -34,16 +165,9 @@ impl KeyDerivation {
     }
     
     /// Key stretching using multiple rounds of BLAKE3
+    #[deprecated(note = "not memory hard: use PasswordKeyParams::derive, or legacy::blake3_stretch_key to open old data")]
     pub fn stretch_key(key: &[u8], rounds: u32) -> [u8; 32] {
-        let mut result = [0u8; 32];
-        result[..key.len().min(32)].copy_from_slice(&key[..key.len().min(32)]);
-        
-        for i in 0..rounds {
-            let context = format!("stretch:{}", i);
-            result = derive_key(&context, &result);
-        }
-        
-        result
+        legacy::blake3_stretch_key(key, rounds)
     }
 }
 
@@ -59,13 +183,23 @@ impl SecureKey {
         Self { key, context }
     }
     
+    #[deprecated(note = "not memory hard: use SecureKey::from_passphrase")]
     pub fn from_password(password: &str, salt: &[u8], context: &str) -> Self {
-        let key = KeyDerivation::derive_key_from_password(password, salt, context);
+        let key = legacy::blake3_password_key(password, salt, context);
         Self {
             key,
             context: context.to_string(),
         }
     }
+
+    /// Key for `context` derived from `password` with Argon2id.
+    pub fn from_passphrase(
+        password: &str,
+        params: &PasswordKeyParams,
+        context: &str,
+    ) -> Result<Self, CryptoError> {
+        params.derive(password, context)
+    }
     
     pub fn generate(context: String) -> Self {
         let mut key = [0u8; 32];
@@ -140,14 +274,21 @@ impl KeyDerivationFactory {
         Self { master_key }
     }
     
+    #[deprecated(note = "not memory hard: use KeyDerivationFactory::from_passphrase")]
     pub fn from_password(password: &str, salt: &[u8]) -> Self {
-        let master_key = SecureKey::from_password(
-            password, 
-            salt, 
-            "fileshare.master"
-        );
+        let key = legacy::blake3_password_key(password, salt, "fileshare.master");
+        let master_key = SecureKey::new(key, "fileshare.master".to_string());
         Self { master_key }
     }
+
+    /// Factory whose master key is derived from `password` with Argon2id.
+    pub fn from_passphrase(
+        password: &str,
+        params: &PasswordKeyParams,
+    ) -> Result<Self, CryptoError> {
+        let master_key = params.derive(password, "fileshare.master")?;
+        Ok(Self { master_key })
+    }
     
     pub fn derive_key(&self, purpose: KeyPurpose, additional_info: Option<&str>) -> SecureKey {
         let info = match additional_info {
@@ -176,6 +317,7 @@ mod tests {
     use super::*;
     
     #[test]
+    #[allow(deprecated)]
     fn test_key_derivation_from_password() {
         let password = "test_password";
         let salt = KeyDerivation::generate_salt(16);
@@ -193,6 +335,7 @@ mod tests {
     }
     
     #[test]
+    #[allow(deprecated)]
     fn test_secure_key() {
         let password = "test_password";
         let salt = KeyDerivation::generate_salt(16);
@@ -226,6 +369,7 @@ mod tests {
     }
     
     #[test]
+    #[allow(deprecated)]
     fn test_key_stretching() {
         let original_key = b"short_key";
         let stretched1 = KeyDerivation::stretch_key(original_key, 1000);
@@ -235,4 +379,97 @@ mod tests {
         assert_eq!(stretched1, stretched2);
         assert_ne!(stretched1, stretched_different);
     }
+
+    fn cheap_params() -> PasswordKeyParams {
+        PasswordKeyParams {
+            cost: PasswordKdfCost {
+                memory_kib: 1024,
+                iterations: 2,
+                parallelism: 1,
+            },
+            salt: (0..16).collect(),
+        }
+    }
+
+    #[test]
+    fn test_passphrase_key_known_vector() {
+        // Argon2id output for these parameters, from an independent
+        // implementation; the key is then bound to its context.
+        let argon2id: [u8; 32] = [
+            0x58, 0x78, 0x2f, 0xc9, 0x6f, 0x06, 0xa6, 0xd7, 0xfc, 0xec, 0x47, 0x28, 0x09, 0x9f,
+            0x6a, 0x78, 0x8e, 0xe9, 0x8a, 0x04, 0xf4, 0xcd, 0x8f, 0xa0, 0xa2, 0x7a, 0xf6, 0xdb,
+            0x8b, 0x7a, 0x46, 0xb8,
+        ];
+        let key = cheap_params()
+            .derive("correct horse battery staple", "test")
+            .unwrap();
+        assert_eq!(key.as_bytes(), &derive_key("test", &argon2id));
+        assert_eq!(key.context(), "test");
+    }
+
+    #[test]
+    fn test_passphrase_key_depends_on_every_input() {
+        let params = cheap_params();
+        let key = params.derive("passphrase", "folder").unwrap();
+        assert_eq!(key.as_bytes(), params.derive("passphrase", "folder").unwrap().as_bytes());
+        assert_ne!(key.as_bytes(), params.derive("passphrasf", "folder").unwrap().as_bytes());
+        assert_ne!(key.as_bytes(), params.derive("passphrase", "other").unwrap().as_bytes());
+
+        let mut salted = params.clone();
+        salted.salt[0] ^= 1;
+        assert_ne!(key.as_bytes(), salted.derive("passphrase", "folder").unwrap().as_bytes());
+        let mut costlier = params.clone();
+        costlier.cost.iterations += 1;
+        assert_ne!(key.as_bytes(), costlier.derive("passphrase", "folder").unwrap().as_bytes());
+
+        let factory = KeyDerivationFactory::from_passphrase("passphrase", &params).unwrap();
+        let again = KeyDerivationFactory::from_passphrase("passphrase", &params).unwrap();
+        assert_eq!(
+            factory.derive_file_key("file").as_bytes(),
+            again.derive_file_key("file").as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_passphrase_params_format() {
+        let params = cheap_params();
+        let written = params.to_string();
+        assert_eq!(written, "argon2id$v=19$m=1024,t=2,p=1$AAECAwQFBgcICQoLDA0ODw");
+        assert_eq!(written.parse::<PasswordKeyParams>().unwrap(), params);
+
+        let generated = PasswordKeyParams::generate(PasswordKdfCost::default());
+        assert_eq!(generated.salt.len(), MIN_SALT_LEN);
+        assert_eq!(generated.to_string().parse::<PasswordKeyParams>().unwrap(), generated);
+
+        for bad in [
+            "argon2i$v=19$m=1024,t=2,p=1$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=16$m=1024,t=2,p=1$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=19$m=1024,t=2$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=19$m=1024,t=2,p=1,p=1$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=19$m=1024,t=0,p=1$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=19$m=4294967295,t=2,p=1$AAECAwQFBgcICQoLDA0ODw",
+            "argon2id$v=19$m=1024,t=2,p=1$AAECAwQFBgc",
+            "argon2id$v=19$m=1024,t=2,p=1$not base64!",
+        ] {
+            assert!(bad.parse::<PasswordKeyParams>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_legacy_helpers_are_unchanged() {
+        let salt = KeyDerivation::generate_salt(16);
+        assert_eq!(
+            KeyDerivation::derive_key_from_password("password", &salt, "context"),
+            legacy::blake3_password_key("password", &salt, "context")
+        );
+        assert_eq!(
+            SecureKey::from_password("password", &salt, "context").as_bytes(),
+            &legacy::blake3_password_key("password", &salt, "context")
+        );
+        assert_eq!(
+            crate::hash_password("password", "salt"),
+            legacy::blake3_password_hash("password", "salt")
+        );
+    }
 }
@@ -0,0 +1,36 @@
+//! Password helpers from before keys were derived with Argon2id
+//!
+//! They run the password through BLAKE3 alone, which is cheap enough to
+//! brute force at scale. They are kept only to open what was derived with
+//! them; derive anything new with [`PasswordKeyParams`](crate::PasswordKeyParams)
+//! and hash account passwords with [`PasswordManager`](crate::PasswordManager).
+
+use blake3::{derive_key, Hasher};
+
+/// Key derived from `password` and `salt` with a single BLAKE3 pass.
+pub fn blake3_password_key(password: &str, salt: &[u8], context: &str) -> [u8; 32] {
+    let mut key_material = zeroize::Zeroizing::new(Vec::with_capacity(password.len() + salt.len()));
+    key_material.extend_from_slice(password.as_bytes());
+    key_material.extend_from_slice(salt);
+    derive_key(context, &key_material)
+}
+
+/// Hex BLAKE3 hash of `password` followed by `salt`.
+pub fn blake3_password_hash(password: &str, salt: &str) -> String {
+    let mut hasher = Hasher::new();
+    hasher.update(password.as_bytes());
+    hasher.update(salt.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// `rounds` chained BLAKE3 key derivations over the first 32 bytes of
+/// `key`.
+pub fn blake3_stretch_key(key: &[u8], rounds: u32) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    result[..key.len().min(32)].copy_from_slice(&key[..key.len().min(32)]);
+    for i in 0..rounds {
+        let context = format!("stretch:{}", i);
+        result = derive_key(&context, &result);
+    }
+    result
+}
@@ -1,8 +1,11 @@
+pub mod e2ee;
 pub mod encryption;
 pub mod hashing;
 pub mod password;
 pub mod key_derivation;
+pub mod legacy;
 
+pub use e2ee::*;
 pub use encryption::*;
 pub use hashing::*;
 pub use password::*;
@@ -17,7 +20,8 @@ use serde::{Deserialize, Serialize};
 pub struct CryptoConfig {
     pub encryption_algorithm: String,
     pub key_size: usize,
-    pub pbkdf2_iterations: u32,
+    /// Cost of deriving keys from passwords with Argon2id
+    pub password_kdf: PasswordKdfCost,
     pub salt_size: usize,
 }
 
@@ -26,8 +30,19 @@ impl Default for CryptoConfig {
         Self {
             encryption_algorithm: "AES-256-GCM".to_string(),
             key_size: 32, // 256 bits
-            pbkdf2_iterations: 100_000,
-            salt_size: 16,
+            password_kdf: PasswordKdfCost::default(),
+            salt_size: MIN_SALT_LEN,
+        }
+    }
+}
+
+impl CryptoConfig {
+    /// Parameters to derive a new password key with: the configured cost
+    /// and a fresh salt.
+    pub fn password_key_params(&self) -> PasswordKeyParams {
+        PasswordKeyParams {
+            cost: self.password_kdf,
+            salt: KeyDerivation::generate_salt(self.salt_size.max(MIN_SALT_LEN)),
         }
     }
 }
@@ -63,9 +78,7 @@ pub fn hash_file(data: &[u8]) -> String {
     hasher.finalize().to_hex().to_string()
 }
 
+#[deprecated(note = "not a password hash: use PasswordManager::hash_password, or legacy::blake3_password_hash to check old hashes")]
 pub fn hash_password(password: &str, salt: &str) -> String {
-    let mut hasher = Hasher::new();
-    hasher.update(password.as_bytes());
-    hasher.update(salt.as_bytes());
-    hasher.finalize().to_hex().to_string()
+    legacy::blake3_password_hash(password, salt)
 }
@@ -2,7 +2,7 @@ use argon2::{
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use rand::rngs::OsRng;
+use aes_gcm::aead::OsRng;
 use zeroize::Zeroize;
 
 pub struct PasswordManager {
@@ -38,11 +38,11 @@ impl PasswordManager {
     pub fn generate_secure_password(length: usize) -> String {
         use rand::Rng;
         const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%^&*";
-        let mut rng = OsRng;
+        let mut rng = rand::rng();
         
         (0..length)
             .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
+                let idx = rng.random_range(0..CHARSET.len());
                 CHARSET[idx] as char
             })
             .collect()
@@ -1,7 +1,6 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
-use std::collections::HashMap;
 
 // CalDAV Protocol Implementation (RFC 4791)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,7 +26,69 @@ pub struct CalendarPermissions {
     pub public_read: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Access a user has to a calendar; each level includes the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum CalendarAccess {
+    Read,
+    Write,
+    /// Manage sharing and permissions.
+    Admin,
+}
+
+impl CalendarPermissions {
+    /// Access granted to `user` (`None` for anonymous requests) by these
+    /// permissions, not counting ownership.
+    pub fn access(&self, user: Option<Uuid>) -> Option<CalendarAccess> {
+        let granted = user.and_then(|user| {
+            if self.admin_users.contains(&user) {
+                Some(CalendarAccess::Admin)
+            } else if self.write_users.contains(&user) {
+                Some(CalendarAccess::Write)
+            } else if self.read_users.contains(&user) {
+                Some(CalendarAccess::Read)
+            } else {
+                None
+            }
+        });
+        granted.or(self.public_read.then_some(CalendarAccess::Read))
+    }
+
+    /// Grant `access` to `user`, replacing any previous grant.
+    pub fn grant(&mut self, user: Uuid, access: CalendarAccess) {
+        self.revoke(user);
+        match access {
+            CalendarAccess::Read => self.read_users.push(user),
+            CalendarAccess::Write => self.write_users.push(user),
+            CalendarAccess::Admin => self.admin_users.push(user),
+        }
+    }
+
+    pub fn revoke(&mut self, user: Uuid) {
+        self.read_users.retain(|u| *u != user);
+        self.write_users.retain(|u| *u != user);
+        self.admin_users.retain(|u| *u != user);
+    }
+
+    /// Whether anyone besides the owner has access.
+    pub fn is_shared(&self) -> bool {
+        self.public_read
+            || !self.read_users.is_empty()
+            || !self.write_users.is_empty()
+            || !self.admin_users.is_empty()
+    }
+}
+
+impl Calendar {
+    /// Access `user` has to this calendar; owners have full access.
+    pub fn access(&self, user: Option<Uuid>) -> Option<CalendarAccess> {
+        if user == Some(self.owner_id) {
+            return Some(CalendarAccess::Admin);
+        }
+        self.permissions.access(user)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CalendarEvent {
     pub id: Uuid,
     pub calendar_id: Uuid,
@@ -51,7 +112,7 @@ pub struct CalendarEvent {
     pub etag: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Attendee {
     pub email: String,
     pub name: Option<String>,
@@ -60,7 +121,7 @@ pub struct Attendee {
     pub rsvp: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AttendeeRole {
     Required,
     Optional,
@@ -68,7 +129,7 @@ pub enum AttendeeRole {
     NonParticipant,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AttendeeStatus {
     NeedsAction,
     Accepted,
@@ -77,25 +138,73 @@ pub enum AttendeeStatus {
     Delegated,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Organizer {
     pub email: String,
     pub name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum EventStatus {
     Tentative,
     Confirmed,
     Cancelled,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Transparency {
     Opaque,
     Transparent,
 }
 
+/// A to-do (`VTODO`), stored in the same calendars as events.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalendarTask {
+    pub id: Uuid,
+    pub calendar_id: Uuid,
+    pub uid: String, // iCalendar UID
+    pub summary: String,
+    pub description: Option<String>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub due: Option<DateTime<Utc>>,
+    /// `DTSTART` and `DUE` are dates rather than date-times.
+    pub all_day: bool,
+    pub completed: Option<DateTime<Utc>>,
+    /// 0 to 100.
+    pub percent_complete: u8,
+    pub status: TaskStatus,
+    pub priority: u8,
+    /// `RELATED-TO`, e.g. the parent of a subtask.
+    pub related_to: Vec<TaskRelation>,
+    pub categories: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub sequence: u32,
+    pub etag: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TaskStatus {
+    NeedsAction,
+    InProcess,
+    Completed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskRelation {
+    pub uid: String,
+    pub relation: RelationType,
+}
+
+/// `RELTYPE` of a `RELATED-TO` property; `Parent` when absent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelationType {
+    Parent,
+    Child,
+    Sibling,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CalendarQuery {
     pub calendar_id: Uuid,
@@ -170,21 +279,76 @@ pub struct FreeBusyRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FreeBusyResponse {
     pub organizer: String,
+    pub attendee: String,
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
     pub busy_periods: Vec<BusyPeriod>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BusyPeriod {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
     pub busy_type: BusyType,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BusyType {
     Busy,
     Tentative,
     Unavailable,
 }
+
+/// Outcome of importing an `.ics` file into a calendar. Objects are matched
+/// to existing ones by UID.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CalendarImportResult {
+    pub calendar_id: Uuid,
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub failed: Vec<ImportFailure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportFailure {
+    pub uid: Option<String>,
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permissions() -> CalendarPermissions {
+        CalendarPermissions {
+            read_users: Vec::new(),
+            write_users: Vec::new(),
+            admin_users: Vec::new(),
+            public_read: false,
+        }
+    }
+
+    #[test]
+    fn test_access_levels() {
+        let (reader, writer, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut permissions = permissions();
+        permissions.grant(reader, CalendarAccess::Read);
+        permissions.grant(writer, CalendarAccess::Read);
+        permissions.grant(writer, CalendarAccess::Write);
+        assert_eq!(permissions.read_users, vec![reader]);
+        assert_eq!(permissions.access(Some(reader)), Some(CalendarAccess::Read));
+        assert_eq!(permissions.access(Some(writer)), Some(CalendarAccess::Write));
+        assert_eq!(permissions.access(Some(stranger)), None);
+        assert_eq!(permissions.access(None), None);
+        assert!(permissions.is_shared());
+
+        permissions.public_read = true;
+        assert_eq!(permissions.access(None), Some(CalendarAccess::Read));
+        assert_eq!(permissions.access(Some(writer)), Some(CalendarAccess::Write));
+
+        permissions.revoke(writer);
+        assert_eq!(permissions.access(Some(writer)), Some(CalendarAccess::Read));
+        assert!(CalendarAccess::Admin > CalendarAccess::Write);
+    }
+}
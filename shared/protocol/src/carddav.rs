@@ -0,0 +1,126 @@
+use crate::caldav::{CalendarAccess, CalendarPermissions, ParameterFilter, TextMatch};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressBook {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub name: String,
+    pub display_name: String,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub is_shared: bool,
+    /// Address books are shared like calendars.
+    pub permissions: CalendarPermissions,
+}
+
+impl AddressBook {
+    /// Access `user` has to this address book; owners have full access.
+    pub fn access(&self, user: Option<Uuid>) -> Option<CalendarAccess> {
+        if user == Some(self.owner_id) {
+            return Some(CalendarAccess::Admin);
+        }
+        self.permissions.access(user)
+    }
+}
+
+/// A projection of a vCard; see [`crate::vcard::VCard::contact`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub uid: String,
+    /// `FN`, the name as displayed.
+    pub formatted_name: String,
+    pub name: Option<StructuredName>,
+    pub nickname: Option<String>,
+    pub emails: Vec<ContactEmail>,
+    pub phones: Vec<ContactPhone>,
+    pub addresses: Vec<PostalAddress>,
+    pub organization: Option<String>,
+    pub title: Option<String>,
+    pub birthday: Option<NaiveDate>,
+    pub note: Option<String>,
+    pub urls: Vec<String>,
+    pub categories: Vec<String>,
+    pub revision: Option<DateTime<Utc>>,
+}
+
+/// `N`: family name, given name, additional names, prefixes and suffixes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StructuredName {
+    pub family: String,
+    pub given: String,
+    pub additional: String,
+    pub prefixes: String,
+    pub suffixes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactEmail {
+    pub address: String,
+    /// Lower-cased `TYPE` values such as `work` or `home`.
+    pub types: Vec<String>,
+    pub preferred: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactPhone {
+    pub number: String,
+    /// Lower-cased `TYPE` values such as `cell`, `work` or `voice`.
+    pub types: Vec<String>,
+    pub preferred: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PostalAddress {
+    pub po_box: String,
+    pub extended: String,
+    pub street: String,
+    pub locality: String,
+    pub region: String,
+    pub postal_code: String,
+    pub country: String,
+    pub types: Vec<String>,
+}
+
+/// An `addressbook-query` REPORT (RFC 6352 §8.6).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddressBookQuery {
+    pub address_book_id: Uuid,
+    pub filter: AddressFilter,
+    pub limit: Option<usize>,
+    pub properties: Vec<String>,
+}
+
+/// Whether any or all of a list of tests must match (`test` attribute).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterTest {
+    #[default]
+    AnyOf,
+    AllOf,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AddressFilter {
+    pub test: FilterTest,
+    pub property_filters: Vec<AddressPropertyFilter>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddressPropertyFilter {
+    pub name: String,
+    pub test: FilterTest,
+    pub is_not_defined: bool,
+    pub text_matches: Vec<TextMatch>,
+    pub parameter_filters: Vec<ParameterFilter>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAddressBookRequest {
+    pub name: String,
+    /// Defaults to `name`.
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+}
@@ -22,6 +22,8 @@ pub enum ApiError {
     SyncConflict,
     InvalidSyncToken,
     DeviceNotFound,
+    DeviceRevoked,
+    WipeRequested,
     
     // WebDAV errors
     WebDavMethodNotAllowed,
@@ -72,6 +74,8 @@ impl fmt::Display for ApiError {
             ApiError::SyncConflict => write!(f, "Synchronization conflict detected"),
             ApiError::InvalidSyncToken => write!(f, "Invalid synchronization token"),
             ApiError::DeviceNotFound => write!(f, "Device not found"),
+            ApiError::DeviceRevoked => write!(f, "Device has been revoked"),
+            ApiError::WipeRequested => write!(f, "Device must wipe its sync folders"),
             
             ApiError::WebDavMethodNotAllowed => write!(f, "WebDAV method not allowed"),
             ApiError::WebDavPreconditionFailed => write!(f, "WebDAV precondition failed"),
@@ -126,3 +126,32 @@ pub struct FileSearchResult {
     pub total_count: u64,
     pub search_time_ms: u64,
 }
+
+/// Request to publish a synced file through a public link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateShareRequest {
+    pub path: String,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Asked for before downloading, when set.
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+}
+
+/// A public link to the current content of a synced file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub path: String,
+    pub token: String,
+    /// Server-relative URL the file is downloaded from.
+    pub url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub password_protected: bool,
+    pub download_count: u64,
+    pub max_downloads: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    pub is_active: bool,
+}
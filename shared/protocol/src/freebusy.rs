@@ -0,0 +1,273 @@
+//! Free/busy time (RFC 5545 §3.6.4, RFC 4791 §7.10).
+//!
+//! Busy time is taken from the `VEVENT` instances of a calendar, skipping
+//! transparent and cancelled events, and from the `FREEBUSY` properties of
+//! any stored `VFREEBUSY` components.
+
+use crate::caldav::{BusyPeriod, BusyType, FreeBusyResponse};
+use crate::ical::{
+    format_utc, invalid, parse_duration, split_unescaped, Component, DateTimeValue, ICalError,
+    ICalendar, Property, TimeZones,
+};
+use crate::recurrence;
+use chrono::{DateTime, Utc};
+
+/// How a `VEVENT` instance counts towards free/busy time, if at all.
+fn event_busy_type(event: &Component) -> Option<BusyType> {
+    let value = |name: &str| {
+        event
+            .property(name)
+            .map(|p| p.value.trim().to_ascii_uppercase())
+    };
+    if value("TRANSP").as_deref() == Some("TRANSPARENT") {
+        return None;
+    }
+    match value("STATUS").as_deref() {
+        Some("CANCELLED") => None,
+        Some("TENTATIVE") => Some(BusyType::Tentative),
+        _ => Some(BusyType::Busy),
+    }
+}
+
+fn fbtype(property: &Property) -> Option<BusyType> {
+    match property
+        .param("FBTYPE")
+        .map(str::to_ascii_uppercase)
+        .as_deref()
+    {
+        Some("FREE") => None,
+        Some("BUSY-TENTATIVE") => Some(BusyType::Tentative),
+        Some("BUSY-UNAVAILABLE") => Some(BusyType::Unavailable),
+        // BUSY, and unknown types which must be treated as BUSY.
+        _ => Some(BusyType::Busy),
+    }
+}
+
+/// Parse one `start/end` or `start/duration` PERIOD value.
+fn parse_period(value: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (start, rest) = value.split_once('/')?;
+    let Some(DateTimeValue::Utc(start)) = DateTimeValue::parse_value(start, None) else {
+        return None;
+    };
+    let end = match DateTimeValue::parse_value(rest, None) {
+        Some(DateTimeValue::Utc(end)) => end,
+        Some(_) => return None,
+        None => start + parse_duration(rest)?,
+    };
+    Some((start, end))
+}
+
+/// Clip `[start, end)` to the requested range; empty periods are dropped.
+fn clipped(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    busy_type: BusyType,
+    range_start: DateTime<Utc>,
+    range_end: DateTime<Utc>,
+) -> Option<BusyPeriod> {
+    let start = start.max(range_start);
+    let end = end.min(range_end);
+    (start < end).then_some(BusyPeriod {
+        start,
+        end,
+        busy_type,
+    })
+}
+
+/// Busy periods of a calendar object resource within `[start, end)`, clipped
+/// to the range. The result is not coalesced; see [`coalesce`].
+pub fn busy_periods(
+    calendar: &Component,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<BusyPeriod>, ICalError> {
+    let mut periods = Vec::new();
+    for instance in recurrence::instances(calendar, "VEVENT", zones, start, end)? {
+        if let Some(busy_type) = event_busy_type(instance.component) {
+            periods.extend(clipped(instance.start, instance.end, busy_type, start, end));
+        }
+    }
+    for freebusy in calendar.components_named("VFREEBUSY") {
+        for property in freebusy.properties_named("FREEBUSY") {
+            let Some(busy_type) = fbtype(property) else {
+                continue;
+            };
+            for value in split_unescaped(&property.value, ',') {
+                let (period_start, period_end) =
+                    parse_period(value.trim()).ok_or_else(|| invalid(property))?;
+                periods.extend(clipped(period_start, period_end, busy_type, start, end));
+            }
+        }
+    }
+    Ok(periods)
+}
+
+/// Sort periods by start and merge overlapping or adjacent periods of the
+/// same type.
+pub fn coalesce(mut periods: Vec<BusyPeriod>) -> Vec<BusyPeriod> {
+    periods.sort_by_key(|p| (p.start, p.end));
+    let mut merged: Vec<BusyPeriod> = Vec::with_capacity(periods.len());
+    for period in periods {
+        let open = merged
+            .iter_mut()
+            .rev()
+            .find(|p| p.busy_type == period.busy_type);
+        match open {
+            Some(last) if period.start <= last.end => last.end = last.end.max(period.end),
+            _ => merged.push(period),
+        }
+    }
+    merged
+}
+
+/// `ORGANIZER` and `ATTENDEE` values are calendar user addresses (URIs).
+fn cal_address(value: &str) -> String {
+    if value.contains(':') {
+        value.to_string()
+    } else {
+        format!("mailto:{}", value)
+    }
+}
+
+/// Render a free/busy result as a `VCALENDAR` holding one `VFREEBUSY`.
+pub fn to_vfreebusy(response: &FreeBusyResponse, stamp: DateTime<Utc>) -> ICalendar {
+    let mut freebusy = Component::new("VFREEBUSY");
+    freebusy.push_property(Property::new("DTSTAMP", format_utc(stamp)));
+    freebusy.push_property(Property::new("DTSTART", format_utc(response.start)));
+    freebusy.push_property(Property::new("DTEND", format_utc(response.end)));
+    if !response.organizer.is_empty() {
+        freebusy.push_property(Property::new("ORGANIZER", cal_address(&response.organizer)));
+    }
+    if !response.attendee.is_empty() {
+        freebusy.push_property(Property::new("ATTENDEE", cal_address(&response.attendee)));
+    }
+    for period in &response.busy_periods {
+        let fbtype = match period.busy_type {
+            BusyType::Busy => "BUSY",
+            BusyType::Tentative => "BUSY-TENTATIVE",
+            BusyType::Unavailable => "BUSY-UNAVAILABLE",
+        };
+        freebusy.push_property(
+            Property::new(
+                "FREEBUSY",
+                format!("{}/{}", format_utc(period.start), format_utc(period.end)),
+            )
+            .with_param("FBTYPE", fbtype),
+        );
+    }
+
+    let mut calendar = ICalendar::new();
+    calendar.root.components.push(freebusy);
+    calendar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, d, h, 0, 0).unwrap()
+    }
+
+    fn period(start: DateTime<Utc>, end: DateTime<Utc>, busy_type: BusyType) -> BusyPeriod {
+        BusyPeriod {
+            start,
+            end,
+            busy_type,
+        }
+    }
+
+    fn periods(ics: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<BusyPeriod> {
+        let calendar = ICalendar::parse(ics).unwrap();
+        let zones = calendar.timezones();
+        coalesce(busy_periods(&calendar.root, &zones, start, end).unwrap())
+    }
+
+    #[test]
+    fn test_transparency_and_status() {
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+BEGIN:VEVENT\r\nUID:busy\r\nDTSTART:20240304T090000Z\r\nDTEND:20240304T100000Z\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:free\r\nDTSTART:20240304T110000Z\r\nDTEND:20240304T120000Z\r\nTRANSP:TRANSPARENT\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:cancelled\r\nDTSTART:20240304T130000Z\r\nDTEND:20240304T140000Z\r\nSTATUS:CANCELLED\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:maybe\r\nDTSTART:20240304T150000Z\r\nDTEND:20240304T160000Z\r\nSTATUS:TENTATIVE\r\nEND:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        assert_eq!(
+            periods(ics, utc(1, 0), utc(8, 0)),
+            vec![
+                period(utc(4, 9), utc(4, 10), BusyType::Busy),
+                period(utc(4, 15), utc(4, 16), BusyType::Tentative),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recurring_events_are_clipped_and_overrides_apply() {
+        // Daily 09:00-11:00; the 5th is cancelled and the 6th moved to 14:00.
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+BEGIN:VEVENT\r\nUID:daily\r\nDTSTART:20240304T090000Z\r\nDTEND:20240304T110000Z\r\nRRULE:FREQ=DAILY\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:daily\r\nRECURRENCE-ID:20240305T090000Z\r\nDTSTART:20240305T090000Z\r\nDTEND:20240305T110000Z\r\nSTATUS:CANCELLED\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:daily\r\nRECURRENCE-ID:20240306T090000Z\r\nDTSTART:20240306T140000Z\r\nDTEND:20240306T150000Z\r\nEND:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        assert_eq!(
+            periods(ics, utc(4, 10), utc(7, 10)),
+            vec![
+                period(utc(4, 10), utc(4, 11), BusyType::Busy),
+                period(utc(6, 14), utc(6, 15), BusyType::Busy),
+                period(utc(7, 9), utc(7, 10), BusyType::Busy),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stored_vfreebusy_periods() {
+        let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VFREEBUSY\r\nUID:fb\r\n\
+FREEBUSY:20240304T090000Z/PT1H,20240304T100000Z/20240304T110000Z\r\n\
+FREEBUSY;FBTYPE=BUSY-UNAVAILABLE:20240305T000000Z/PT8H\r\n\
+FREEBUSY;FBTYPE=FREE:20240306T000000Z/PT8H\r\n\
+END:VFREEBUSY\r\nEND:VCALENDAR\r\n";
+        assert_eq!(
+            periods(ics, utc(1, 0), utc(8, 0)),
+            vec![
+                period(utc(4, 9), utc(4, 11), BusyType::Busy),
+                period(utc(5, 0), utc(5, 8), BusyType::Unavailable),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_keeps_types_apart() {
+        let merged = coalesce(vec![
+            period(utc(4, 12), utc(4, 13), BusyType::Busy),
+            period(utc(4, 9), utc(4, 11), BusyType::Busy),
+            period(utc(4, 10), utc(4, 12), BusyType::Tentative),
+            period(utc(4, 10), utc(4, 12), BusyType::Busy),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                period(utc(4, 9), utc(4, 13), BusyType::Busy),
+                period(utc(4, 10), utc(4, 12), BusyType::Tentative),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vfreebusy_round_trip() {
+        let response = FreeBusyResponse {
+            organizer: "alice@example.com".to_string(),
+            attendee: "mailto:bob@example.com".to_string(),
+            start: utc(1, 0),
+            end: utc(8, 0),
+            busy_periods: vec![period(utc(4, 15), utc(4, 16), BusyType::Tentative)],
+        };
+        let ics = to_vfreebusy(&response, utc(1, 0)).to_ics();
+        assert!(ics.contains("ORGANIZER:mailto:alice@example.com\r\n"));
+        assert!(ics.contains("ATTENDEE:mailto:bob@example.com\r\n"));
+        assert!(
+            ics.contains("FREEBUSY;FBTYPE=BUSY-TENTATIVE:20240304T150000Z/20240304T160000Z\r\n")
+        );
+        assert_eq!(periods(&ics, utc(1, 0), utc(8, 0)), response.busy_periods);
+    }
+}
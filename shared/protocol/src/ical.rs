@@ -0,0 +1,2316 @@
+//! iCalendar (RFC 5545) parsing and serialization.
+//!
+//! Calendar data is parsed into a generic [`Component`] tree which serializes
+//! back losslessly, including properties and components we do not model.
+//! [`CalendarEvent`] is a projection of a `VEVENT`: use [`ICalendar::events`]
+//! to read events and [`update_vevent`] to write an edited event back into its
+//! original component without dropping alarms, time zones or vendor
+//! extensions. [`CalendarTask`] is the matching projection of a `VTODO`.
+
+use crate::caldav::{
+    Attendee, AttendeeRole, AttendeeStatus, CalendarEvent, CalendarTask, EventStatus, Organizer,
+    RelationType, TaskRelation, TaskStatus, Transparency,
+};
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone as _, Utc, Weekday,
+};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+
+/// Maximum length of a folded content line in octets, excluding the CRLF.
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Maximum nesting of BEGIN/END blocks accepted by the parser.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<Parameter>,
+    /// Raw value as it appears on the content line (TEXT values are still escaped).
+    pub value: String,
+}
+
+impl Property {
+    pub fn new(name: &str, value: impl Into<String>) -> Self {
+        Self {
+            name: name.to_ascii_uppercase(),
+            params: Vec::new(),
+            value: value.into(),
+        }
+    }
+
+    /// Create a TEXT property, escaping the value.
+    pub fn text(name: &str, value: &str) -> Self {
+        Self::new(name, escape_text(value))
+    }
+
+    pub fn with_param(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.set_param(name, value);
+        self
+    }
+
+    /// First value of the named parameter.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+            .and_then(|p| p.values.first())
+            .map(String::as_str)
+    }
+
+    pub fn set_param(&mut self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self
+            .params
+            .iter_mut()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+        {
+            Some(param) => param.values = vec![value],
+            None => self.params.push(Parameter {
+                name: name.to_ascii_uppercase(),
+                values: vec![value],
+            }),
+        }
+    }
+
+    pub fn remove_param(&mut self, name: &str) {
+        self.params.retain(|p| !p.name.eq_ignore_ascii_case(name));
+    }
+
+    /// Value unescaped as TEXT.
+    pub fn text_value(&self) -> String {
+        unescape_text(&self.value)
+    }
+
+    fn write_to(&self, out: &mut String) {
+        let mut line = self.name.clone();
+        for param in &self.params {
+            line.push(';');
+            line.push_str(&param.name);
+            line.push('=');
+            for (i, value) in param.values.iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                write_param_value(&mut line, value);
+            }
+        }
+        line.push(':');
+        line.push_str(&self.value);
+        fold_line(&line, out);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Component {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_ascii_uppercase(),
+            properties: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn properties_named<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Property> + 'a {
+        self.properties
+            .iter()
+            .filter(move |p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn components_named<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Component> + 'a {
+        self.components
+            .iter()
+            .filter(move |c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn push_property(&mut self, property: Property) {
+        self.properties.push(property);
+    }
+
+    /// Replace the single property `name`, keeping its position if present.
+    pub fn set_property(&mut self, property: Property) {
+        let name = property.name.clone();
+        self.replace_properties(&name, vec![property]);
+    }
+
+    pub fn remove_properties(&mut self, name: &str) {
+        self.properties
+            .retain(|p| !p.name.eq_ignore_ascii_case(name));
+    }
+
+    /// Replace every property called `name` with `properties`, inserted where
+    /// the first old one was (or appended when there was none).
+    pub fn replace_properties(&mut self, name: &str, properties: Vec<Property>) {
+        let position = self
+            .properties
+            .iter()
+            .position(|p| p.name.eq_ignore_ascii_case(name))
+            .unwrap_or(self.properties.len());
+        self.remove_properties(name);
+        let position = position.min(self.properties.len());
+        self.properties.splice(position..position, properties);
+    }
+
+    pub fn to_ics(&self) -> String {
+        let mut out = String::new();
+        self.write_to(&mut out);
+        out
+    }
+
+    fn write_to(&self, out: &mut String) {
+        fold_line(&format!("BEGIN:{}", self.name), out);
+        for property in &self.properties {
+            property.write_to(out);
+        }
+        for component in &self.components {
+            component.write_to(out);
+        }
+        fold_line(&format!("END:{}", self.name), out);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ICalError {
+    /// A content line could not be parsed.
+    Syntax { line: usize, message: String },
+    /// BEGIN/END blocks are not balanced.
+    Structure { line: usize, message: String },
+    /// A property value has the wrong format.
+    InvalidValue { property: String, value: String },
+    /// A required property is absent.
+    MissingProperty(String),
+}
+
+impl fmt::Display for ICalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ICalError::Syntax { line, message } => {
+                write!(f, "Syntax error on line {}: {}", line, message)
+            }
+            ICalError::Structure { line, message } => {
+                write!(f, "Invalid structure on line {}: {}", line, message)
+            }
+            ICalError::InvalidValue { property, value } => {
+                write!(f, "Invalid value for {}: '{}'", property, value)
+            }
+            ICalError::MissingProperty(name) => write!(f, "Missing required property {}", name),
+        }
+    }
+}
+
+impl std::error::Error for ICalError {}
+
+/// A parsed `VCALENDAR` object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ICalendar {
+    pub root: Component,
+}
+
+impl ICalendar {
+    pub fn new() -> Self {
+        let mut root = Component::new("VCALENDAR");
+        root.push_property(Property::new("VERSION", "2.0"));
+        root.push_property(Property::new(
+            "PRODID",
+            "-//RouilleCloud//CalDAV Server//EN",
+        ));
+        Self { root }
+    }
+
+    /// Parse a stream containing exactly one `VCALENDAR`.
+    pub fn parse(input: &str) -> Result<Self, ICalError> {
+        let mut calendars = parse_components(input)?;
+        match calendars.len() {
+            1 => {
+                let root = calendars.remove(0);
+                if !root.name.eq_ignore_ascii_case("VCALENDAR") {
+                    return Err(ICalError::Structure {
+                        line: 1,
+                        message: format!("expected VCALENDAR, found {}", root.name),
+                    });
+                }
+                Ok(Self { root })
+            }
+            0 => Err(ICalError::Structure {
+                line: 1,
+                message: "no VCALENDAR object".to_string(),
+            }),
+            _ => Err(ICalError::Structure {
+                line: 1,
+                message: "more than one top-level object".to_string(),
+            }),
+        }
+    }
+
+    pub fn to_ics(&self) -> String {
+        self.root.to_ics()
+    }
+
+    pub fn timezones(&self) -> TimeZones {
+        TimeZones::from_calendar(&self.root)
+    }
+
+    /// Project every master `VEVENT` (those without a RECURRENCE-ID) onto a
+    /// [`CalendarEvent`] belonging to `calendar_id`.
+    pub fn events(&self, calendar_id: Uuid) -> Result<Vec<CalendarEvent>, ICalError> {
+        let zones = self.timezones();
+        self.root
+            .components_named("VEVENT")
+            .filter(|c| c.property("RECURRENCE-ID").is_none())
+            .map(|c| vevent_to_event(c, calendar_id, &zones))
+            .collect()
+    }
+
+    /// Build a calendar containing freshly serialized `events`.
+    pub fn from_events(events: &[CalendarEvent]) -> Self {
+        let mut calendar = Self::new();
+        calendar
+            .root
+            .components
+            .extend(events.iter().map(event_to_vevent));
+        calendar
+    }
+
+    /// Project every master `VTODO` onto a [`CalendarTask`] belonging to
+    /// `calendar_id`.
+    pub fn tasks(&self, calendar_id: Uuid) -> Result<Vec<CalendarTask>, ICalError> {
+        let zones = self.timezones();
+        self.root
+            .components_named("VTODO")
+            .filter(|c| c.property("RECURRENCE-ID").is_none())
+            .map(|c| vtodo_to_task(c, calendar_id, &zones))
+            .collect()
+    }
+
+    /// Build a calendar containing freshly serialized `tasks`.
+    pub fn from_tasks(tasks: &[CalendarTask]) -> Self {
+        let mut calendar = Self::new();
+        calendar
+            .root
+            .components
+            .extend(tasks.iter().map(task_to_vtodo));
+        calendar
+    }
+
+    /// Split a calendar holding many objects, such as an export from another
+    /// server, into one calendar per UID, each with the VTIMEZONEs its
+    /// components refer to. A later component with the same UID and
+    /// RECURRENCE-ID replaces an earlier one. Components without a UID each
+    /// get a calendar of their own.
+    pub fn split_objects(&self) -> Vec<ICalendar> {
+        let timezones: HashMap<&str, &Component> = self
+            .root
+            .components_named("VTIMEZONE")
+            .filter_map(|tz| tz.property("TZID").map(|id| (id.value.as_str(), tz)))
+            .collect();
+        let mut objects: Vec<(Option<&str>, Vec<&Component>)> = Vec::new();
+        for component in self
+            .root
+            .components
+            .iter()
+            .filter(|c| c.name != "VTIMEZONE")
+        {
+            let uid = component.property("UID").map(|p| p.value.trim());
+            let instance = |c: &Component| c.property("RECURRENCE-ID").map(|p| p.value.clone());
+            match objects.iter_mut().find(|(u, _)| uid.is_some() && *u == uid) {
+                Some((_, components)) => {
+                    match components.iter().position(|c| {
+                        c.name == component.name && instance(c) == instance(component)
+                    }) {
+                        Some(i) => components[i] = component,
+                        None => components.push(component),
+                    }
+                }
+                None => objects.push((uid, vec![component])),
+            }
+        }
+
+        objects
+            .into_iter()
+            .map(|(_, components)| {
+                let mut tzids = Vec::new();
+                for component in &components {
+                    collect_tzids(component, &mut tzids);
+                }
+                let mut calendar = Self::new();
+                calendar.root.components.extend(
+                    tzids
+                        .iter()
+                        .filter_map(|tzid| timezones.get(tzid.as_str()))
+                        .map(|tz| (*tz).clone()),
+                );
+                calendar
+                    .root
+                    .components
+                    .extend(components.into_iter().cloned());
+                calendar
+            })
+            .collect()
+    }
+
+    /// Merge calendars into one, keeping a single copy of each VTIMEZONE.
+    pub fn merge<'a>(calendars: impl IntoIterator<Item = &'a ICalendar>) -> Self {
+        let mut merged = Self::new();
+        let mut tzids = Vec::new();
+        let mut components = Vec::new();
+        for calendar in calendars {
+            for component in &calendar.root.components {
+                if component.name == "VTIMEZONE" {
+                    let tzid = component.property("TZID").map(|p| p.value.clone());
+                    if !tzids.contains(&tzid) {
+                        tzids.push(tzid);
+                        merged.root.components.push(component.clone());
+                    }
+                } else {
+                    components.push(component.clone());
+                }
+            }
+        }
+        merged.root.components.extend(components);
+        merged
+    }
+
+    /// Highest `SEQUENCE` of the calendar's components.
+    pub fn sequence(&self) -> u32 {
+        self.root
+            .components
+            .iter()
+            .filter_map(|c| c.property("SEQUENCE"))
+            .filter_map(|p| p.value.trim().parse().ok())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Raise the `SEQUENCE` of every component other than VTIMEZONEs to at
+    /// least `sequence`.
+    pub fn raise_sequence(&mut self, sequence: u32) {
+        for component in &mut self.root.components {
+            if component.name == "VTIMEZONE" {
+                continue;
+            }
+            let current: u32 = component
+                .property("SEQUENCE")
+                .and_then(|p| p.value.trim().parse().ok())
+                .unwrap_or(0);
+            if current < sequence {
+                component.set_property(Property::new("SEQUENCE", sequence.to_string()));
+            }
+        }
+    }
+}
+
+/// `TZID` parameters used anywhere in `component`, in order of appearance.
+fn collect_tzids(component: &Component, tzids: &mut Vec<String>) {
+    for property in &component.properties {
+        if let Some(tzid) = property.param("TZID") {
+            if !tzids.iter().any(|t| t == tzid) {
+                tzids.push(tzid.to_string());
+            }
+        }
+    }
+    for child in &component.components {
+        collect_tzids(child, tzids);
+    }
+}
+
+impl Default for ICalendar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse every top-level component in `input`.
+pub fn parse_components(input: &str) -> Result<Vec<Component>, ICalError> {
+    let mut roots = Vec::new();
+    let mut stack: Vec<Component> = Vec::new();
+
+    for (line_no, line) in unfold(input) {
+        let property = parse_content_line(&line, line_no)?;
+
+        if property.name == "BEGIN" {
+            if stack.len() >= MAX_DEPTH {
+                return Err(ICalError::Structure {
+                    line: line_no,
+                    message: "components nested too deeply".to_string(),
+                });
+            }
+            let name = property.value.trim();
+            if name.is_empty() {
+                return Err(ICalError::Syntax {
+                    line: line_no,
+                    message: "BEGIN without a component name".to_string(),
+                });
+            }
+            stack.push(Component::new(name));
+        } else if property.name == "END" {
+            let component = stack.pop().ok_or_else(|| ICalError::Structure {
+                line: line_no,
+                message: format!("END:{} without matching BEGIN", property.value),
+            })?;
+            if !component.name.eq_ignore_ascii_case(property.value.trim()) {
+                return Err(ICalError::Structure {
+                    line: line_no,
+                    message: format!("END:{} closes BEGIN:{}", property.value, component.name),
+                });
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.components.push(component),
+                None => roots.push(component),
+            }
+        } else {
+            match stack.last_mut() {
+                Some(component) => component.properties.push(property),
+                None => {
+                    return Err(ICalError::Structure {
+                        line: line_no,
+                        message: format!("property {} outside of a component", property.name),
+                    })
+                }
+            }
+        }
+    }
+
+    if let Some(open) = stack.last() {
+        return Err(ICalError::Structure {
+            line: 0,
+            message: format!("BEGIN:{} is never closed", open.name),
+        });
+    }
+
+    Ok(roots)
+}
+
+/// Join folded lines, yielding each logical line with its starting line number.
+fn unfold(input: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+
+    for (index, raw) in input.split('\n').enumerate() {
+        let raw = raw.strip_suffix('\r').unwrap_or(raw);
+        if let Some(continuation) = raw.strip_prefix(' ').or_else(|| raw.strip_prefix('\t')) {
+            if let Some((_, last)) = lines.last_mut() {
+                last.push_str(continuation);
+                continue;
+            }
+        }
+        if raw.trim().is_empty() {
+            continue;
+        }
+        lines.push((index + 1, raw.to_string()));
+    }
+
+    lines
+}
+
+fn parse_content_line(line: &str, line_no: usize) -> Result<Property, ICalError> {
+    let syntax = |message: &str| ICalError::Syntax {
+        line: line_no,
+        message: message.to_string(),
+    };
+
+    let bytes = line.as_bytes();
+    let mut pos = 0;
+    // '.' separates a vCard property group ("item1.EMAIL") from the name.
+    while pos < bytes.len()
+        && (bytes[pos].is_ascii_alphanumeric() || matches!(bytes[pos], b'-' | b'.'))
+    {
+        pos += 1;
+    }
+    if pos == 0 {
+        return Err(syntax("missing property name"));
+    }
+    let name = line[..pos].to_ascii_uppercase();
+
+    let mut params = Vec::new();
+    while pos < bytes.len() && bytes[pos] == b';' {
+        pos += 1;
+        let start = pos;
+        while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'-') {
+            pos += 1;
+        }
+        if pos == start || pos >= bytes.len() || bytes[pos] != b'=' {
+            return Err(syntax("malformed parameter"));
+        }
+        let param_name = line[start..pos].to_ascii_uppercase();
+        pos += 1;
+
+        let mut values = Vec::new();
+        loop {
+            if pos < bytes.len() && bytes[pos] == b'"' {
+                let start = pos + 1;
+                let end = line[start..]
+                    .find('"')
+                    .map(|i| start + i)
+                    .ok_or_else(|| syntax("unterminated quoted parameter value"))?;
+                values.push(decode_param_value(&line[start..end]));
+                pos = end + 1;
+            } else {
+                let start = pos;
+                while pos < bytes.len() && !matches!(bytes[pos], b',' | b';' | b':') {
+                    pos += 1;
+                }
+                values.push(decode_param_value(&line[start..pos]));
+            }
+            if pos < bytes.len() && bytes[pos] == b',' {
+                pos += 1;
+            } else {
+                break;
+            }
+        }
+        params.push(Parameter {
+            name: param_name,
+            values,
+        });
+    }
+
+    if pos >= bytes.len() || bytes[pos] != b':' {
+        return Err(syntax("expected ':' after property name"));
+    }
+
+    Ok(Property {
+        name,
+        params,
+        value: line[pos + 1..].to_string(),
+    })
+}
+
+/// Decode RFC 6868 caret escapes in a parameter value.
+fn decode_param_value(value: &str) -> String {
+    if !value.contains('^') {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '^' {
+            match chars.peek() {
+                Some('n') => {
+                    out.push('\n');
+                    chars.next();
+                }
+                Some('\'') => {
+                    out.push('"');
+                    chars.next();
+                }
+                Some('^') => {
+                    out.push('^');
+                    chars.next();
+                }
+                _ => out.push('^'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn write_param_value(out: &mut String, value: &str) {
+    let encoded = value
+        .replace('^', "^^")
+        .replace('\n', "^n")
+        .replace('"', "^'");
+    if encoded.contains([':', ';', ',']) {
+        out.push('"');
+        out.push_str(&encoded);
+        out.push('"');
+    } else {
+        out.push_str(&encoded);
+    }
+}
+
+/// Append `line` folded at 75 octets, never splitting a UTF-8 sequence.
+fn fold_line(line: &str, out: &mut String) {
+    let mut start = 0;
+    let mut limit = MAX_LINE_OCTETS;
+    while line.len() - start > limit {
+        let mut end = start + limit;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.push_str(&line[start..end]);
+        out.push_str("\r\n ");
+        start = end;
+        // The leading space of a continuation line counts towards its length.
+        limit = MAX_LINE_OCTETS - 1;
+    }
+    out.push_str(&line[start..]);
+    out.push_str("\r\n");
+}
+
+/// Escape a TEXT value (RFC 5545 section 3.3.11).
+pub fn escape_text(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn unescape_text(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Split a list value on `separator`, ignoring backslash-escaped separators.
+pub fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == separator {
+            parts.push(&value[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// A DATE or DATE-TIME value before time zone resolution.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DateTimeValue {
+    Date(NaiveDate),
+    Utc(DateTime<Utc>),
+    /// Local time, optionally tied to a TZID; `None` is a floating time.
+    Local {
+        time: NaiveDateTime,
+        tzid: Option<String>,
+    },
+}
+
+impl DateTimeValue {
+    pub fn parse(property: &Property) -> Result<Self, ICalError> {
+        Self::parse_value(&property.value, property.param("TZID")).ok_or_else(|| invalid(property))
+    }
+
+    pub fn parse_value(value: &str, tzid: Option<&str>) -> Option<Self> {
+        let value = value.trim();
+        if value.len() == 8 {
+            return parse_date(value).map(DateTimeValue::Date);
+        }
+        let (value, utc) = match value.strip_suffix('Z').or_else(|| value.strip_suffix('z')) {
+            Some(v) => (v, true),
+            None => (value, false),
+        };
+        let time = parse_date_time(value)?;
+        if utc {
+            Some(DateTimeValue::Utc(Utc.from_utc_datetime(&time)))
+        } else {
+            Some(DateTimeValue::Local {
+                time,
+                tzid: tzid.map(str::to_string),
+            })
+        }
+    }
+
+    pub fn is_date(&self) -> bool {
+        matches!(self, DateTimeValue::Date(_))
+    }
+
+    /// Wall-clock time of the value (midnight for dates).
+    pub fn naive(&self) -> NaiveDateTime {
+        match self {
+            DateTimeValue::Date(date) => date.and_time(NaiveTime::MIN),
+            DateTimeValue::Utc(time) => time.naive_utc(),
+            DateTimeValue::Local { time, .. } => *time,
+        }
+    }
+}
+
+fn parse_digits(value: &str) -> Option<u32> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    if value.len() != 8 || !value.is_ascii() {
+        return None;
+    }
+    let year = parse_digits(&value[0..4])? as i32;
+    let month = parse_digits(&value[4..6])?;
+    let day = parse_digits(&value[6..8])?;
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+fn parse_date_time(value: &str) -> Option<NaiveDateTime> {
+    if value.len() != 15 || !value.is_ascii() || &value[8..9] != "T" {
+        return None;
+    }
+    let date = parse_date(&value[0..8])?;
+    let hour = parse_digits(&value[9..11])?;
+    let minute = parse_digits(&value[11..13])?;
+    // Leap seconds ("60") are clamped rather than rejected.
+    let second = parse_digits(&value[13..15])?.min(59);
+    date.and_hms_opt(hour, minute, second)
+}
+
+pub fn format_date(date: NaiveDate) -> String {
+    date.format("%Y%m%d").to_string()
+}
+
+pub fn format_date_time(time: NaiveDateTime) -> String {
+    time.format("%Y%m%dT%H%M%S").to_string()
+}
+
+pub fn format_utc(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Parse a DURATION value such as `P1DT2H`, `-PT15M` or `P2W`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (negative, rest) = match value.as_bytes().first()? {
+        b'-' => (true, &value[1..]),
+        b'+' => (false, &value[1..]),
+        _ => (false, value),
+    };
+    let rest = rest.strip_prefix('P').or_else(|| rest.strip_prefix('p'))?;
+
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    let mut seen_unit = false;
+    for c in rest.chars() {
+        match c.to_ascii_uppercase() {
+            'T' if !in_time && number.is_empty() => in_time = true,
+            d if d.is_ascii_digit() => number.push(d),
+            unit => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                let part = match (unit, in_time) {
+                    ('W', false) => Duration::try_weeks(n)?,
+                    ('D', false) => Duration::try_days(n)?,
+                    ('H', true) => Duration::try_hours(n)?,
+                    ('M', true) => Duration::try_minutes(n)?,
+                    ('S', true) => Duration::try_seconds(n)?,
+                    _ => return None,
+                };
+                total = total.checked_add(&part)?;
+                seen_unit = true;
+            }
+        }
+    }
+    if !number.is_empty() || !seen_unit {
+        return None;
+    }
+    Some(if negative { -total } else { total })
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let mut out = String::new();
+    let mut seconds = duration.num_seconds();
+    if seconds < 0 {
+        out.push('-');
+        seconds = -seconds;
+    }
+    out.push('P');
+    let days = seconds / 86_400;
+    seconds %= 86_400;
+    if days > 0 {
+        out.push_str(&format!("{}D", days));
+    }
+    if seconds > 0 || days == 0 {
+        out.push('T');
+        let (hours, minutes, secs) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        if hours > 0 {
+            out.push_str(&format!("{}H", hours));
+        }
+        if minutes > 0 {
+            out.push_str(&format!("{}M", minutes));
+        }
+        if secs > 0 || (hours == 0 && minutes == 0) {
+            out.push_str(&format!("{}S", secs));
+        }
+    }
+    out
+}
+
+pub(crate) fn invalid(property: &Property) -> ICalError {
+    ICalError::InvalidValue {
+        property: property.name.clone(),
+        value: property.value.clone(),
+    }
+}
+
+/// Time zones defined by the `VTIMEZONE` components of a calendar.
+#[derive(Debug, Clone, Default)]
+pub struct TimeZones {
+    zones: HashMap<String, VTimeZone>,
+}
+
+impl TimeZones {
+    pub fn from_calendar(calendar: &Component) -> Self {
+        let zones = calendar
+            .components_named("VTIMEZONE")
+            .filter_map(VTimeZone::from_component)
+            .map(|zone| (zone.tzid.clone(), zone))
+            .collect();
+        Self { zones }
+    }
+
+    pub fn get(&self, tzid: &str) -> Option<&VTimeZone> {
+        self.zones.get(tzid)
+    }
+
+    /// Convert a wall-clock time in `tzid` to UTC.
+    ///
+    /// Floating times, and TZIDs without a matching `VTIMEZONE`, are treated
+    /// as UTC.
+    pub fn local_to_utc(&self, time: NaiveDateTime, tzid: Option<&str>) -> DateTime<Utc> {
+        let offset = tzid
+            .and_then(|tzid| self.zones.get(tzid))
+            .map(|zone| zone.offset_at(time))
+            .unwrap_or(0);
+        Utc.from_utc_datetime(&(time - Duration::seconds(offset as i64)))
+    }
+
+    /// Convert a UTC instant to wall-clock time in `tzid`, the inverse of
+    /// [`TimeZones::local_to_utc`].
+    pub fn utc_to_local(&self, time: DateTime<Utc>, tzid: Option<&str>) -> NaiveDateTime {
+        let Some(zone) = tzid.and_then(|tzid| self.zones.get(tzid)) else {
+            return time.naive_utc();
+        };
+        // Offsets are keyed by local time, so guess with the offset at the
+        // UTC wall-clock and refine once; this settles outside transitions.
+        let utc = time.naive_utc();
+        let guess = utc + Duration::seconds(zone.offset_at(utc) as i64);
+        utc + Duration::seconds(zone.offset_at(guess) as i64)
+    }
+
+    /// Resolve a DATE or DATE-TIME value to UTC. Dates resolve to midnight UTC.
+    pub fn to_utc(&self, value: &DateTimeValue) -> DateTime<Utc> {
+        match value {
+            DateTimeValue::Date(date) => Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)),
+            DateTimeValue::Utc(time) => *time,
+            DateTimeValue::Local { time, tzid } => self.local_to_utc(*time, tzid.as_deref()),
+        }
+    }
+
+    pub fn resolve(&self, property: &Property) -> Result<DateTime<Utc>, ICalError> {
+        Ok(self.to_utc(&DateTimeValue::parse(property)?))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VTimeZone {
+    pub tzid: String,
+    observances: Vec<Observance>,
+}
+
+/// A STANDARD or DAYLIGHT sub-component of a `VTIMEZONE`.
+#[derive(Debug, Clone)]
+struct Observance {
+    start: NaiveDateTime,
+    offset_from: i32,
+    offset_to: i32,
+    rule: Option<YearlyRule>,
+    rdates: Vec<NaiveDateTime>,
+}
+
+impl VTimeZone {
+    fn from_component(component: &Component) -> Option<Self> {
+        let tzid = component.property("TZID")?.value.clone();
+        let observances = component
+            .components
+            .iter()
+            .filter(|c| c.name == "STANDARD" || c.name == "DAYLIGHT")
+            .filter_map(Observance::from_component)
+            .collect::<Vec<_>>();
+        if observances.is_empty() {
+            return None;
+        }
+        Some(Self { tzid, observances })
+    }
+
+    /// UTC offset in seconds in effect at the given wall-clock time.
+    pub fn offset_at(&self, local: NaiveDateTime) -> i32 {
+        self.observances
+            .iter()
+            .filter_map(|o| o.last_onset(local).map(|onset| (onset, o.offset_to)))
+            .max_by_key(|(onset, _)| *onset)
+            .map(|(_, offset)| offset)
+            .unwrap_or_else(|| {
+                // Before the first transition, the earliest observance's
+                // "from" offset applies.
+                self.observances
+                    .iter()
+                    .min_by_key(|o| o.start)
+                    .map(|o| o.offset_from)
+                    .unwrap_or(0)
+            })
+    }
+}
+
+impl Observance {
+    fn from_component(component: &Component) -> Option<Self> {
+        let start = DateTimeValue::parse(component.property("DTSTART")?)
+            .ok()?
+            .naive();
+        let offset_from = parse_utc_offset(&component.property("TZOFFSETFROM")?.value)?;
+        let offset_to = parse_utc_offset(&component.property("TZOFFSETTO")?.value)?;
+        let rule = component
+            .property("RRULE")
+            .and_then(|p| YearlyRule::parse(&p.value, start));
+        let rdates = component
+            .properties_named("RDATE")
+            .flat_map(|p| p.value.split(',').map(str::to_string).collect::<Vec<_>>())
+            .filter_map(|v| DateTimeValue::parse_value(&v, None).map(|d| d.naive()))
+            .collect();
+        Some(Self {
+            start,
+            offset_from,
+            offset_to,
+            rule,
+            rdates,
+        })
+    }
+
+    /// Latest onset of this observance at or before `local`.
+    fn last_onset(&self, local: NaiveDateTime) -> Option<NaiveDateTime> {
+        if self.start > local {
+            return None;
+        }
+        let mut best = self.start;
+        for rdate in &self.rdates {
+            if *rdate <= local && *rdate > best {
+                best = *rdate;
+            }
+        }
+        if let Some(rule) = &self.rule {
+            for year in [local.year() - 1, local.year()] {
+                if let Some(onset) = rule.onset(year) {
+                    if onset >= self.start && onset <= local && onset > best {
+                        best = onset;
+                    }
+                }
+            }
+        }
+        Some(best)
+    }
+}
+
+/// The yearly transition rules used by `VTIMEZONE` observances, e.g.
+/// `FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU`.
+#[derive(Debug, Clone)]
+struct YearlyRule {
+    month: u32,
+    weekday: Option<(i32, Weekday)>,
+    month_days: Vec<i32>,
+    time: NaiveTime,
+    until: Option<NaiveDateTime>,
+}
+
+impl YearlyRule {
+    fn parse(rule: &str, start: NaiveDateTime) -> Option<Self> {
+        let mut freq_yearly = false;
+        let mut month = start.month();
+        let mut weekday = None;
+        let mut month_days = Vec::new();
+        let mut until = None;
+        for part in rule.split(';') {
+            let (key, value) = part.split_once('=')?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => freq_yearly = value.eq_ignore_ascii_case("YEARLY"),
+                "BYMONTH" => month = value.parse().ok()?,
+                "BYDAY" => weekday = parse_by_day(value),
+                "BYMONTHDAY" => {
+                    month_days = value.split(',').filter_map(|d| d.parse().ok()).collect()
+                }
+                "UNTIL" => until = DateTimeValue::parse_value(value, None).map(|d| d.naive()),
+                _ => {}
+            }
+        }
+        if !freq_yearly {
+            return None;
+        }
+        if weekday.is_none() && month_days.is_empty() {
+            month_days.push(start.day() as i32);
+        }
+        Some(Self {
+            month,
+            weekday,
+            month_days,
+            time: start.time(),
+            until,
+        })
+    }
+
+    fn onset(&self, year: i32) -> Option<NaiveDateTime> {
+        let first = NaiveDate::from_ymd_opt(year, self.month, 1)?;
+        let days_in_month = days_in_month(year, self.month);
+        let candidates: Vec<NaiveDate> = (1..=days_in_month)
+            .filter_map(|d| first.with_day(d))
+            .filter(|d| {
+                self.month_days.is_empty()
+                    || self.month_days.iter().any(|&md| {
+                        let md = if md < 0 {
+                            days_in_month as i32 + 1 + md
+                        } else {
+                            md
+                        };
+                        md == d.day() as i32
+                    })
+            })
+            .filter(|d| self.weekday.is_none_or(|(_, wd)| d.weekday() == wd))
+            .collect();
+        let date = match self.weekday {
+            Some((n, _)) if n > 0 => candidates.get(n as usize - 1).copied(),
+            Some((n, _)) if n < 0 => candidates
+                .len()
+                .checked_sub(n.unsigned_abs() as usize)
+                .and_then(|i| candidates.get(i).copied()),
+            _ => candidates.first().copied(),
+        }?;
+        let onset = date.and_time(self.time);
+        match self.until {
+            Some(until) if onset > until => None,
+            _ => Some(onset),
+        }
+    }
+}
+
+fn parse_by_day(value: &str) -> Option<(i32, Weekday)> {
+    let value = value.split(',').next()?.trim();
+    if value.len() < 2 || !value.is_ascii() {
+        return None;
+    }
+    let (ordinal, day) = value.split_at(value.len() - 2);
+    let weekday = parse_weekday(day)?;
+    let ordinal = if ordinal.is_empty() {
+        0
+    } else {
+        ordinal.parse().ok()?
+    };
+    Some((ordinal, weekday))
+}
+
+pub(crate) fn parse_weekday(value: &str) -> Option<Weekday> {
+    Some(match value.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+pub(crate) fn days_in_month(year: i32, month: u32) -> u32 {
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    };
+    next.and_then(|d| d.pred_opt())
+        .map(|d| d.day())
+        .unwrap_or(31)
+}
+
+/// Parse a UTC-OFFSET value (`+0200`, `-0530`, `+013045`) into seconds.
+fn parse_utc_offset(value: &str) -> Option<i32> {
+    let value = value.trim();
+    if !value.is_ascii() || (value.len() != 5 && value.len() != 7) {
+        return None;
+    }
+    let sign = match &value[0..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let hours = parse_digits(&value[1..3])? as i32;
+    let minutes = parse_digits(&value[3..5])? as i32;
+    let seconds = if value.len() == 7 {
+        parse_digits(&value[5..7])? as i32
+    } else {
+        0
+    };
+    Some(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+pub(crate) fn calendar_address(value: &str) -> String {
+    let value = value.trim();
+    match value.get(..7) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("mailto:") => value[7..].to_string(),
+        _ => value.to_string(),
+    }
+}
+
+fn parse_role(value: Option<&str>) -> AttendeeRole {
+    match value.map(str::to_ascii_uppercase).as_deref() {
+        Some("CHAIR") => AttendeeRole::Chair,
+        Some("OPT-PARTICIPANT") => AttendeeRole::Optional,
+        Some("NON-PARTICIPANT") => AttendeeRole::NonParticipant,
+        _ => AttendeeRole::Required,
+    }
+}
+
+fn role_value(role: &AttendeeRole) -> &'static str {
+    match role {
+        AttendeeRole::Required => "REQ-PARTICIPANT",
+        AttendeeRole::Optional => "OPT-PARTICIPANT",
+        AttendeeRole::Chair => "CHAIR",
+        AttendeeRole::NonParticipant => "NON-PARTICIPANT",
+    }
+}
+
+pub fn parse_partstat(value: Option<&str>) -> AttendeeStatus {
+    match value.map(str::to_ascii_uppercase).as_deref() {
+        Some("ACCEPTED") => AttendeeStatus::Accepted,
+        Some("DECLINED") => AttendeeStatus::Declined,
+        Some("TENTATIVE") => AttendeeStatus::Tentative,
+        Some("DELEGATED") => AttendeeStatus::Delegated,
+        _ => AttendeeStatus::NeedsAction,
+    }
+}
+
+pub fn partstat_value(status: &AttendeeStatus) -> &'static str {
+    match status {
+        AttendeeStatus::NeedsAction => "NEEDS-ACTION",
+        AttendeeStatus::Accepted => "ACCEPTED",
+        AttendeeStatus::Declined => "DECLINED",
+        AttendeeStatus::Tentative => "TENTATIVE",
+        AttendeeStatus::Delegated => "DELEGATED",
+    }
+}
+
+fn parse_attendee(property: &Property) -> Attendee {
+    Attendee {
+        email: calendar_address(&property.value),
+        name: property.param("CN").map(str::to_string),
+        role: parse_role(property.param("ROLE")),
+        status: parse_partstat(property.param("PARTSTAT")),
+        rsvp: property
+            .param("RSVP")
+            .is_some_and(|v| v.eq_ignore_ascii_case("TRUE")),
+    }
+}
+
+/// Write the modelled parameters of `attendee` onto `property`, keeping any
+/// other parameters (CUTYPE, DELEGATED-TO, X-...) intact.
+fn write_attendee(property: &mut Property, attendee: &Attendee) {
+    property.value = format!("mailto:{}", attendee.email);
+    match &attendee.name {
+        Some(name) => property.set_param("CN", name.clone()),
+        None => property.remove_param("CN"),
+    }
+    property.set_param("ROLE", role_value(&attendee.role));
+    property.set_param("PARTSTAT", partstat_value(&attendee.status));
+    if attendee.rsvp {
+        property.set_param("RSVP", "TRUE");
+    } else {
+        property.remove_param("RSVP");
+    }
+}
+
+fn organizer_property(organizer: &Organizer) -> Property {
+    let mut property = Property::new("ORGANIZER", format!("mailto:{}", organizer.email));
+    if let Some(name) = &organizer.name {
+        property.set_param("CN", name.clone());
+    }
+    property
+}
+
+/// Project a `VEVENT` component onto a [`CalendarEvent`].
+///
+/// A fresh `id` is assigned and `etag` is left empty; both are owned by the
+/// storage layer.
+pub fn vevent_to_event(
+    component: &Component,
+    calendar_id: Uuid,
+    zones: &TimeZones,
+) -> Result<CalendarEvent, ICalError> {
+    let uid = component
+        .property("UID")
+        .map(|p| p.value.trim().to_string())
+        .filter(|uid| !uid.is_empty())
+        .ok_or_else(|| ICalError::MissingProperty("UID".to_string()))?;
+    let dtstart = component
+        .property("DTSTART")
+        .ok_or_else(|| ICalError::MissingProperty("DTSTART".to_string()))?;
+    let start_value = DateTimeValue::parse(dtstart)?;
+    let all_day = start_value.is_date();
+    let start_time = zones.to_utc(&start_value);
+
+    let end_time = if let Some(dtend) = component.property("DTEND") {
+        zones.resolve(dtend)?
+    } else if let Some(duration) = component.property("DURATION") {
+        parse_duration(&duration.value)
+            .and_then(|d| start_time.checked_add_signed(d))
+            .ok_or_else(|| invalid(duration))?
+    } else if all_day {
+        start_time + Duration::days(1)
+    } else {
+        start_time
+    };
+
+    let text = |name: &str| component.property(name).map(Property::text_value);
+    let timestamp = |name: &str| component.property(name).and_then(|p| zones.resolve(p).ok());
+    let dtstamp = timestamp("DTSTAMP");
+
+    let status = match component
+        .property("STATUS")
+        .map(|p| p.value.to_ascii_uppercase())
+        .as_deref()
+    {
+        Some("TENTATIVE") => EventStatus::Tentative,
+        Some("CANCELLED") => EventStatus::Cancelled,
+        _ => EventStatus::Confirmed,
+    };
+    let transparency = match component.property("TRANSP") {
+        Some(p) if p.value.trim().eq_ignore_ascii_case("TRANSPARENT") => Transparency::Transparent,
+        _ => Transparency::Opaque,
+    };
+    let categories = parse_categories(component);
+
+    Ok(CalendarEvent {
+        id: Uuid::new_v4(),
+        calendar_id,
+        uid,
+        summary: text("SUMMARY").unwrap_or_default(),
+        description: text("DESCRIPTION"),
+        location: text("LOCATION"),
+        start_time,
+        end_time,
+        all_day,
+        recurrence_rule: component.property("RRULE").map(|p| p.value.clone()),
+        attendees: component
+            .properties_named("ATTENDEE")
+            .map(parse_attendee)
+            .collect(),
+        organizer: component.property("ORGANIZER").map(|p| Organizer {
+            email: calendar_address(&p.value),
+            name: p.param("CN").map(str::to_string),
+        }),
+        status,
+        priority: component
+            .property("PRIORITY")
+            .and_then(|p| p.value.trim().parse::<u8>().ok())
+            .map(|p| p.min(9))
+            .unwrap_or(0),
+        transparency,
+        categories,
+        created_at: timestamp("CREATED").or(dtstamp).unwrap_or_else(Utc::now),
+        updated_at: timestamp("LAST-MODIFIED")
+            .or(dtstamp)
+            .unwrap_or_else(Utc::now),
+        sequence: component
+            .property("SEQUENCE")
+            .and_then(|p| p.value.trim().parse().ok())
+            .unwrap_or(0),
+        etag: String::new(),
+    })
+}
+
+fn start_property(event: &CalendarEvent) -> Property {
+    if event.all_day {
+        Property::new("DTSTART", format_date(event.start_time.date_naive()))
+            .with_param("VALUE", "DATE")
+    } else {
+        Property::new("DTSTART", format_utc(event.start_time))
+    }
+}
+
+fn end_property(event: &CalendarEvent) -> Property {
+    if event.all_day {
+        Property::new("DTEND", format_date(event.end_time.date_naive())).with_param("VALUE", "DATE")
+    } else {
+        Property::new("DTEND", format_utc(event.end_time))
+    }
+}
+
+fn optional_text(name: &str, value: &Option<String>) -> Vec<Property> {
+    value.iter().map(|v| Property::text(name, v)).collect()
+}
+
+fn status_value(status: &EventStatus) -> &'static str {
+    match status {
+        EventStatus::Tentative => "TENTATIVE",
+        EventStatus::Confirmed => "CONFIRMED",
+        EventStatus::Cancelled => "CANCELLED",
+    }
+}
+
+fn categories_property(categories: &[String]) -> Vec<Property> {
+    if categories.is_empty() {
+        return Vec::new();
+    }
+    let value = categories
+        .iter()
+        .map(|c| escape_text(c))
+        .collect::<Vec<_>>()
+        .join(",");
+    vec![Property::new("CATEGORIES", value)]
+}
+
+/// Serialize an event as a new `VEVENT` component.
+pub fn event_to_vevent(event: &CalendarEvent) -> Component {
+    let mut component = Component::new("VEVENT");
+    component.push_property(Property::new("UID", event.uid.clone()));
+    component.push_property(Property::new("DTSTAMP", format_utc(event.updated_at)));
+    component.push_property(start_property(event));
+    component.push_property(end_property(event));
+    component.push_property(Property::text("SUMMARY", &event.summary));
+    component
+        .properties
+        .extend(optional_text("DESCRIPTION", &event.description));
+    component
+        .properties
+        .extend(optional_text("LOCATION", &event.location));
+    if let Some(rule) = &event.recurrence_rule {
+        component.push_property(Property::new("RRULE", rule.clone()));
+    }
+    if let Some(organizer) = &event.organizer {
+        component.push_property(organizer_property(organizer));
+    }
+    for attendee in &event.attendees {
+        let mut property = Property::new("ATTENDEE", String::new());
+        write_attendee(&mut property, attendee);
+        component.push_property(property);
+    }
+    component.push_property(Property::new("STATUS", status_value(&event.status)));
+    if event.priority > 0 {
+        component.push_property(Property::new("PRIORITY", event.priority.to_string()));
+    }
+    if event.transparency == Transparency::Transparent {
+        component.push_property(Property::new("TRANSP", "TRANSPARENT"));
+    }
+    component
+        .properties
+        .extend(categories_property(&event.categories));
+    component.push_property(Property::new("CREATED", format_utc(event.created_at)));
+    component.push_property(Property::new("LAST-MODIFIED", format_utc(event.updated_at)));
+    component.push_property(Property::new("SEQUENCE", event.sequence.to_string()));
+    component
+}
+
+/// Write `event` back into an existing `VEVENT`, touching only the properties
+/// whose modelled value changed. Unknown properties, parameters and
+/// sub-components (such as `VALARM`) are preserved.
+pub fn update_vevent(component: &mut Component, event: &CalendarEvent, zones: &TimeZones) {
+    let current = match vevent_to_event(component, event.calendar_id, zones) {
+        Ok(current) => current,
+        Err(_) => {
+            let alarms = std::mem::take(&mut component.components);
+            *component = event_to_vevent(event);
+            component.components = alarms;
+            return;
+        }
+    };
+
+    if current.uid != event.uid {
+        component.set_property(Property::new("UID", event.uid.clone()));
+    }
+    if current.summary != event.summary {
+        component.set_property(Property::text("SUMMARY", &event.summary));
+    }
+    if current.description != event.description {
+        component.replace_properties(
+            "DESCRIPTION",
+            optional_text("DESCRIPTION", &event.description),
+        );
+    }
+    if current.location != event.location {
+        component.replace_properties("LOCATION", optional_text("LOCATION", &event.location));
+    }
+    if current.start_time != event.start_time || current.all_day != event.all_day {
+        component.set_property(start_property(event));
+    }
+    if current.end_time != event.end_time || current.all_day != event.all_day {
+        component.remove_properties("DURATION");
+        component.set_property(end_property(event));
+    }
+    if current.recurrence_rule != event.recurrence_rule {
+        let rule = event
+            .recurrence_rule
+            .iter()
+            .map(|r| Property::new("RRULE", r.clone()))
+            .collect();
+        component.replace_properties("RRULE", rule);
+    }
+    if current.organizer != event.organizer {
+        let organizer = event.organizer.iter().map(organizer_property).collect();
+        component.replace_properties("ORGANIZER", organizer);
+    }
+    if current.attendees != event.attendees {
+        let existing: Vec<Property> = component.properties_named("ATTENDEE").cloned().collect();
+        let attendees = event
+            .attendees
+            .iter()
+            .map(|attendee| {
+                let mut property = existing
+                    .iter()
+                    .find(|p| calendar_address(&p.value).eq_ignore_ascii_case(&attendee.email))
+                    .cloned()
+                    .unwrap_or_else(|| Property::new("ATTENDEE", String::new()));
+                write_attendee(&mut property, attendee);
+                property
+            })
+            .collect();
+        component.replace_properties("ATTENDEE", attendees);
+    }
+    if current.status != event.status {
+        component.set_property(Property::new("STATUS", status_value(&event.status)));
+    }
+    if current.priority != event.priority {
+        component.set_property(Property::new("PRIORITY", event.priority.to_string()));
+    }
+    if current.transparency != event.transparency {
+        let value = match event.transparency {
+            Transparency::Opaque => "OPAQUE",
+            Transparency::Transparent => "TRANSPARENT",
+        };
+        component.set_property(Property::new("TRANSP", value));
+    }
+    if current.categories != event.categories {
+        component.replace_properties("CATEGORIES", categories_property(&event.categories));
+    }
+    if current.created_at != event.created_at {
+        component.set_property(Property::new("CREATED", format_utc(event.created_at)));
+    }
+    if current.updated_at != event.updated_at {
+        component.set_property(Property::new("LAST-MODIFIED", format_utc(event.updated_at)));
+        component.set_property(Property::new("DTSTAMP", format_utc(event.updated_at)));
+    }
+    if current.sequence != event.sequence {
+        component.set_property(Property::new("SEQUENCE", event.sequence.to_string()));
+    }
+}
+
+fn parse_categories(component: &Component) -> Vec<String> {
+    component
+        .properties_named("CATEGORIES")
+        .flat_map(|p| {
+            split_unescaped(&p.value, ',')
+                .into_iter()
+                .map(unescape_text)
+                .collect::<Vec<_>>()
+        })
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn task_status_value(status: &TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::NeedsAction => "NEEDS-ACTION",
+        TaskStatus::InProcess => "IN-PROCESS",
+        TaskStatus::Completed => "COMPLETED",
+        TaskStatus::Cancelled => "CANCELLED",
+    }
+}
+
+/// Project a `VTODO` component onto a [`CalendarTask`].
+///
+/// A fresh `id` is assigned and `etag` is left empty; both are owned by the
+/// storage layer. Without a `STATUS`, a task with `COMPLETED` set is
+/// completed.
+pub fn vtodo_to_task(
+    component: &Component,
+    calendar_id: Uuid,
+    zones: &TimeZones,
+) -> Result<CalendarTask, ICalError> {
+    let uid = component
+        .property("UID")
+        .map(|p| p.value.trim().to_string())
+        .filter(|uid| !uid.is_empty())
+        .ok_or_else(|| ICalError::MissingProperty("UID".to_string()))?;
+    let date_time = |name: &str| -> Result<Option<DateTimeValue>, ICalError> {
+        component
+            .property(name)
+            .map(DateTimeValue::parse)
+            .transpose()
+    };
+    let start = date_time("DTSTART")?;
+    let mut due = date_time("DUE")?.map(|due| zones.to_utc(&due));
+    if due.is_none() {
+        if let (Some(start), Some(duration)) = (&start, component.property("DURATION")) {
+            due = Some(
+                parse_duration(&duration.value)
+                    .and_then(|d| zones.to_utc(start).checked_add_signed(d))
+                    .ok_or_else(|| invalid(duration))?,
+            );
+        }
+    }
+    let all_day = start
+        .as_ref()
+        .map(DateTimeValue::is_date)
+        .or_else(|| {
+            component.property("DUE").map(|p| {
+                p.param("VALUE")
+                    .is_some_and(|v| v.eq_ignore_ascii_case("DATE"))
+            })
+        })
+        .unwrap_or(false);
+
+    let text = |name: &str| component.property(name).map(Property::text_value);
+    let timestamp = |name: &str| component.property(name).and_then(|p| zones.resolve(p).ok());
+    let dtstamp = timestamp("DTSTAMP");
+    let completed = timestamp("COMPLETED");
+
+    let status = match component
+        .property("STATUS")
+        .map(|p| p.value.trim().to_ascii_uppercase())
+        .as_deref()
+    {
+        Some("IN-PROCESS") => TaskStatus::InProcess,
+        Some("COMPLETED") => TaskStatus::Completed,
+        Some("CANCELLED") => TaskStatus::Cancelled,
+        Some(_) => TaskStatus::NeedsAction,
+        None if completed.is_some() => TaskStatus::Completed,
+        None => TaskStatus::NeedsAction,
+    };
+    let related_to = component
+        .properties_named("RELATED-TO")
+        .map(|p| TaskRelation {
+            uid: p.value.trim().to_string(),
+            relation: match p.param("RELTYPE").map(str::to_ascii_uppercase).as_deref() {
+                Some("CHILD") => RelationType::Child,
+                Some("SIBLING") => RelationType::Sibling,
+                _ => RelationType::Parent,
+            },
+        })
+        .filter(|r| !r.uid.is_empty())
+        .collect();
+
+    Ok(CalendarTask {
+        id: Uuid::new_v4(),
+        calendar_id,
+        uid,
+        summary: text("SUMMARY").unwrap_or_default(),
+        description: text("DESCRIPTION"),
+        start_time: start.map(|start| zones.to_utc(&start)),
+        due,
+        all_day,
+        completed,
+        percent_complete: component
+            .property("PERCENT-COMPLETE")
+            .and_then(|p| p.value.trim().parse::<u8>().ok())
+            .map(|p| p.min(100))
+            .unwrap_or(0),
+        status,
+        priority: component
+            .property("PRIORITY")
+            .and_then(|p| p.value.trim().parse::<u8>().ok())
+            .map(|p| p.min(9))
+            .unwrap_or(0),
+        related_to,
+        categories: parse_categories(component),
+        created_at: timestamp("CREATED").or(dtstamp).unwrap_or_else(Utc::now),
+        updated_at: timestamp("LAST-MODIFIED")
+            .or(dtstamp)
+            .unwrap_or_else(Utc::now),
+        sequence: component
+            .property("SEQUENCE")
+            .and_then(|p| p.value.trim().parse().ok())
+            .unwrap_or(0),
+        etag: String::new(),
+    })
+}
+
+fn task_date_property(name: &str, time: DateTime<Utc>, all_day: bool) -> Property {
+    if all_day {
+        Property::new(name, format_date(time.date_naive())).with_param("VALUE", "DATE")
+    } else {
+        Property::new(name, format_utc(time))
+    }
+}
+
+/// Serialize a task as a new `VTODO` component.
+pub fn task_to_vtodo(task: &CalendarTask) -> Component {
+    let mut component = Component::new("VTODO");
+    component.push_property(Property::new("UID", task.uid.clone()));
+    component.push_property(Property::new("DTSTAMP", format_utc(task.updated_at)));
+    if let Some(start) = task.start_time {
+        component.push_property(task_date_property("DTSTART", start, task.all_day));
+    }
+    if let Some(due) = task.due {
+        component.push_property(task_date_property("DUE", due, task.all_day));
+    }
+    component.push_property(Property::text("SUMMARY", &task.summary));
+    component
+        .properties
+        .extend(optional_text("DESCRIPTION", &task.description));
+    component.push_property(Property::new("STATUS", task_status_value(&task.status)));
+    if task.percent_complete > 0 {
+        component.push_property(Property::new(
+            "PERCENT-COMPLETE",
+            task.percent_complete.min(100).to_string(),
+        ));
+    }
+    if let Some(completed) = task.completed {
+        component.push_property(Property::new("COMPLETED", format_utc(completed)));
+    }
+    if task.priority > 0 {
+        component.push_property(Property::new("PRIORITY", task.priority.to_string()));
+    }
+    for relation in &task.related_to {
+        let property = Property::new("RELATED-TO", relation.uid.clone());
+        component.push_property(match relation.relation {
+            RelationType::Parent => property,
+            RelationType::Child => property.with_param("RELTYPE", "CHILD"),
+            RelationType::Sibling => property.with_param("RELTYPE", "SIBLING"),
+        });
+    }
+    component
+        .properties
+        .extend(categories_property(&task.categories));
+    component.push_property(Property::new("CREATED", format_utc(task.created_at)));
+    component.push_property(Property::new("LAST-MODIFIED", format_utc(task.updated_at)));
+    component.push_property(Property::new("SEQUENCE", task.sequence.to_string()));
+    component
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOGLE_EXPORT: &str = "BEGIN:VCALENDAR\r\n\
+PRODID:-//Google Inc//Google Calendar 70.9054//EN\r\n\
+VERSION:2.0\r\n\
+CALSCALE:GREGORIAN\r\n\
+METHOD:PUBLISH\r\n\
+X-WR-CALNAME:Team\r\n\
+X-WR-TIMEZONE:Europe/Paris\r\n\
+BEGIN:VTIMEZONE\r\n\
+TZID:Europe/Paris\r\n\
+X-LIC-LOCATION:Europe/Paris\r\n\
+BEGIN:DAYLIGHT\r\n\
+TZOFFSETFROM:+0100\r\n\
+TZOFFSETTO:+0200\r\n\
+TZNAME:CEST\r\n\
+DTSTART:19700329T020000\r\n\
+RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU\r\n\
+END:DAYLIGHT\r\n\
+BEGIN:STANDARD\r\n\
+TZOFFSETFROM:+0200\r\n\
+TZOFFSETTO:+0100\r\n\
+TZNAME:CET\r\n\
+DTSTART:19701025T030000\r\n\
+RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU\r\n\
+END:STANDARD\r\n\
+END:VTIMEZONE\r\n\
+BEGIN:VEVENT\r\n\
+DTSTART;TZID=Europe/Paris:20240614T100000\r\n\
+DTEND;TZID=Europe/Paris:20240614T110000\r\n\
+RRULE:FREQ=WEEKLY;BYDAY=FR\r\n\
+DTSTAMP:20240601T080000Z\r\n\
+ORGANIZER;CN=Alice Martin:mailto:alice@example.com\r\n\
+UID:4m2l8c0d9kq@google.com\r\n\
+ATTENDEE;CUTYPE=INDIVIDUAL;ROLE=REQ-PARTICIPANT;PARTSTAT=ACCEPTED;RSVP=TRUE\r\n \
+;CN=Alice Martin;X-NUM-GUESTS=0:mailto:alice@example.com\r\n\
+ATTENDEE;CUTYPE=INDIVIDUAL;ROLE=OPT-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=\r\n \
+TRUE;CN=bob@example.com;X-NUM-GUESTS=0:mailto:bob@example.com\r\n\
+CREATED:20240601T075500Z\r\n\
+DESCRIPTION:Weekly sync\\, bring notes.\\nAgenda: https://example.com/a;b\r\n\
+LAST-MODIFIED:20240601T080000Z\r\n\
+LOCATION:Room 4\\; second floor\r\n\
+SEQUENCE:2\r\n\
+STATUS:CONFIRMED\r\n\
+SUMMARY:Team sync\r\n\
+TRANSP:OPAQUE\r\n\
+BEGIN:VALARM\r\n\
+ACTION:DISPLAY\r\n\
+DESCRIPTION:This is an event reminder\r\n\
+TRIGGER:-P0DT0H10M0S\r\n\
+END:VALARM\r\n\
+END:VEVENT\r\n\
+BEGIN:VEVENT\r\n\
+DTSTART;TZID=Europe/Paris:20240621T113000\r\n\
+DTEND;TZID=Europe/Paris:20240621T123000\r\n\
+DTSTAMP:20240601T080000Z\r\n\
+UID:4m2l8c0d9kq@google.com\r\n\
+RECURRENCE-ID;TZID=Europe/Paris:20240621T100000\r\n\
+SEQUENCE:3\r\n\
+SUMMARY:Team sync (moved)\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+
+    const OUTLOOK_EXPORT: &str = "BEGIN:VCALENDAR\n\
+PRODID:-//Microsoft Corporation//Outlook 16.0 MIMEDIR//EN\n\
+VERSION:2.0\n\
+METHOD:REQUEST\n\
+X-MS-OLK-FORCEINSPECTOROPEN:TRUE\n\
+BEGIN:VTIMEZONE\n\
+TZID:Eastern Standard Time\n\
+BEGIN:STANDARD\n\
+DTSTART:16011104T020000\n\
+RRULE:FREQ=YEARLY;BYDAY=1SU;BYMONTH=11\n\
+TZOFFSETFROM:-0400\n\
+TZOFFSETTO:-0500\n\
+END:STANDARD\n\
+BEGIN:DAYLIGHT\n\
+DTSTART:16010311T020000\n\
+RRULE:FREQ=YEARLY;BYDAY=2SU;BYMONTH=3\n\
+TZOFFSETFROM:-0500\n\
+TZOFFSETTO:-0400\n\
+END:DAYLIGHT\n\
+END:VTIMEZONE\n\
+BEGIN:VEVENT\n\
+CLASS:PUBLIC\n\
+CREATED:20240110T150000Z\n\
+DESCRIPTION:Quarterly review\\n\\nPlease confirm.\n\
+DTEND;TZID=\"Eastern Standard Time\":20240115T103000\n\
+DTSTAMP:20240110T150000Z\n\
+DTSTART;TZID=\"Eastern Standard Time\":20240115T093000\n\
+LAST-MODIFIED:20240110T150000Z\n\
+LOCATION:Microsoft Teams Meeting\n\
+PRIORITY:5\n\
+SEQUENCE:0\n\
+SUMMARY;LANGUAGE=en-us:Q1 review\n\
+TRANSP:OPAQUE\n\
+UID:040000008200E00074C5B7101A82E00800000000\n\
+X-MICROSOFT-CDO-BUSYSTATUS:BUSY\n\
+X-MICROSOFT-CDO-IMPORTANCE:1\n\
+X-MICROSOFT-DISALLOW-COUNTER:FALSE\n\
+BEGIN:VALARM\n\
+TRIGGER:-PT15M\n\
+ACTION:DISPLAY\n\
+DESCRIPTION:Reminder\n\
+END:VALARM\n\
+END:VEVENT\n\
+END:VCALENDAR\n";
+
+    const APPLE_EXPORT: &str = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:-//Apple Inc.//macOS 14.4//EN\r\n\
+CALSCALE:GREGORIAN\r\n\
+BEGIN:VEVENT\r\n\
+CREATED:20240301T101010Z\r\n\
+UID:8F3A21C4-5B7E-4E8F-9D2A-0C1B2A3D4E5F\r\n\
+DTEND;VALUE=DATE:20240309\r\n\
+TRANSP:TRANSPARENT\r\n\
+X-APPLE-TRAVEL-ADVISORY-BEHAVIOR:AUTOMATIC\r\n\
+SUMMARY:Conférence à Lyon – journée complète 🎉 avec un titre suffisamment long pour être plié\r\n\
+LAST-MODIFIED:20240301T101500Z\r\n\
+DTSTAMP:20240301T101500Z\r\n\
+DTSTART;VALUE=DATE:20240307\r\n\
+CATEGORIES:Travel,Work\\, external\r\n\
+SEQUENCE:1\r\n\
+X-APPLE-STRUCTURED-LOCATION;VALUE=URI;X-APPLE-RADIUS=141.17;X-TITLE=\"Lyon, France\":geo:45.757814,4.832011\r\n\
+BEGIN:VALARM\r\n\
+X-WR-ALARMUID:0D1B2C3A-4E5F-6071-8293-A4B5C6D7E8F9\r\n\
+UID:0D1B2C3A-4E5F-6071-8293-A4B5C6D7E8F9\r\n\
+TRIGGER;VALUE=DATE-TIME:19760401T005545Z\r\n\
+ACTION:NONE\r\n\
+END:VALARM\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+
+    fn parse(input: &str) -> ICalendar {
+        ICalendar::parse(input).expect("calendar should parse")
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_real_world_files() {
+        for input in [GOOGLE_EXPORT, OUTLOOK_EXPORT, APPLE_EXPORT] {
+            let calendar = parse(input);
+            let serialized = calendar.to_ics();
+            assert_eq!(parse(&serialized), calendar);
+            // Serialization is stable once normalized.
+            assert_eq!(parse(&serialized).to_ics(), serialized);
+        }
+    }
+
+    #[test]
+    fn test_serialized_lines_are_folded() {
+        let serialized = parse(APPLE_EXPORT).to_ics();
+        for line in serialized.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_OCTETS, "line too long: {:?}", line);
+        }
+        assert!(serialized.ends_with("END:VCALENDAR\r\n"));
+    }
+
+    #[test]
+    fn test_unknown_properties_and_components_preserved() {
+        let calendar = parse(OUTLOOK_EXPORT);
+        let event = calendar.root.components_named("VEVENT").next().unwrap();
+        assert_eq!(
+            event.property("X-MICROSOFT-CDO-BUSYSTATUS").unwrap().value,
+            "BUSY"
+        );
+        assert_eq!(
+            event.property("SUMMARY").unwrap().param("LANGUAGE"),
+            Some("en-us")
+        );
+        assert_eq!(event.components_named("VALARM").count(), 1);
+        assert_eq!(
+            calendar
+                .root
+                .property("X-MS-OLK-FORCEINSPECTOROPEN")
+                .unwrap()
+                .value,
+            "TRUE"
+        );
+    }
+
+    #[test]
+    fn test_google_event_projection() {
+        let calendar_id = Uuid::new_v4();
+        let events = parse(GOOGLE_EXPORT).events(calendar_id).unwrap();
+        assert_eq!(
+            events.len(),
+            1,
+            "overrides are not projected as separate events"
+        );
+
+        let event = &events[0];
+        assert_eq!(event.calendar_id, calendar_id);
+        assert_eq!(event.uid, "4m2l8c0d9kq@google.com");
+        assert_eq!(event.summary, "Team sync");
+        assert_eq!(
+            event.description.as_deref(),
+            Some("Weekly sync, bring notes.\nAgenda: https://example.com/a;b")
+        );
+        assert_eq!(event.location.as_deref(), Some("Room 4; second floor"));
+        // 10:00 CEST is 08:00 UTC.
+        assert_eq!(event.start_time, utc(2024, 6, 14, 8, 0));
+        assert_eq!(event.end_time, utc(2024, 6, 14, 9, 0));
+        assert!(!event.all_day);
+        assert_eq!(
+            event.recurrence_rule.as_deref(),
+            Some("FREQ=WEEKLY;BYDAY=FR")
+        );
+        assert_eq!(event.sequence, 2);
+        assert_eq!(event.status, EventStatus::Confirmed);
+        assert_eq!(event.transparency, Transparency::Opaque);
+
+        let organizer = event.organizer.as_ref().unwrap();
+        assert_eq!(organizer.email, "alice@example.com");
+        assert_eq!(organizer.name.as_deref(), Some("Alice Martin"));
+
+        assert_eq!(event.attendees.len(), 2);
+        assert_eq!(event.attendees[0].status, AttendeeStatus::Accepted);
+        assert!(event.attendees[0].rsvp);
+        assert_eq!(event.attendees[1].email, "bob@example.com");
+        assert_eq!(event.attendees[1].role, AttendeeRole::Optional);
+        assert_eq!(event.attendees[1].status, AttendeeStatus::NeedsAction);
+    }
+
+    #[test]
+    fn test_vtimezone_resolution_across_dst() {
+        let zones = parse(OUTLOOK_EXPORT).timezones();
+        let zone = zones.get("Eastern Standard Time").unwrap();
+        let local = |m, d, h| {
+            NaiveDate::from_ymd_opt(2024, m, d)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap()
+        };
+
+        assert_eq!(zone.offset_at(local(1, 15, 9)), -5 * 3600);
+        assert_eq!(zone.offset_at(local(3, 10, 1)), -5 * 3600);
+        assert_eq!(zone.offset_at(local(3, 10, 3)), -4 * 3600);
+        assert_eq!(zone.offset_at(local(7, 1, 12)), -4 * 3600);
+        assert_eq!(zone.offset_at(local(11, 3, 3)), -5 * 3600);
+
+        let event = &parse(OUTLOOK_EXPORT).events(Uuid::nil()).unwrap()[0];
+        assert_eq!(event.start_time, utc(2024, 1, 15, 14, 30));
+        assert_eq!(event.priority, 5);
+        assert_eq!(event.summary, "Q1 review");
+    }
+
+    #[test]
+    fn test_all_day_event_and_categories() {
+        let event = &parse(APPLE_EXPORT).events(Uuid::nil()).unwrap()[0];
+        assert!(event.all_day);
+        assert_eq!(event.start_time, utc(2024, 3, 7, 0, 0));
+        assert_eq!(event.end_time, utc(2024, 3, 9, 0, 0));
+        assert_eq!(event.transparency, Transparency::Transparent);
+        assert_eq!(
+            event.categories,
+            vec!["Travel".to_string(), "Work, external".to_string()]
+        );
+        assert!(event.summary.starts_with("Conférence à Lyon"));
+        assert!(event.summary.ends_with("plié"));
+    }
+
+    #[test]
+    fn test_update_vevent_only_touches_changed_properties() {
+        let calendar = parse(GOOGLE_EXPORT);
+        let zones = calendar.timezones();
+        let original = calendar
+            .root
+            .components_named("VEVENT")
+            .next()
+            .unwrap()
+            .clone();
+
+        // An unchanged event leaves the component byte-for-byte identical.
+        let event = vevent_to_event(&original, Uuid::nil(), &zones).unwrap();
+        let mut unchanged = original.clone();
+        update_vevent(&mut unchanged, &event, &zones);
+        assert_eq!(unchanged, original);
+
+        let mut edited = event.clone();
+        edited.summary = "Team sync; new room".to_string();
+        edited.attendees[1].status = AttendeeStatus::Declined;
+        edited.sequence += 1;
+        let mut component = original.clone();
+        update_vevent(&mut component, &edited, &zones);
+
+        assert_eq!(
+            component.property("SUMMARY").unwrap().value,
+            "Team sync\\; new room"
+        );
+        assert_eq!(component.property("SEQUENCE").unwrap().value, "3");
+        let bob = component.properties_named("ATTENDEE").nth(1).unwrap();
+        assert_eq!(bob.param("PARTSTAT"), Some("DECLINED"));
+        assert_eq!(bob.param("X-NUM-GUESTS"), Some("0"));
+        assert_eq!(bob.param("CUTYPE"), Some("INDIVIDUAL"));
+        // Start time is untouched, so the TZID form survives.
+        assert_eq!(
+            component.property("DTSTART").unwrap().param("TZID"),
+            Some("Europe/Paris")
+        );
+        assert_eq!(component.components_named("VALARM").count(), 1);
+
+        let reparsed = vevent_to_event(&component, Uuid::nil(), &zones).unwrap();
+        assert_eq!(reparsed.summary, edited.summary);
+        assert_eq!(reparsed.attendees, edited.attendees);
+    }
+
+    #[test]
+    fn test_event_round_trip_through_fresh_component() {
+        let event = &parse(GOOGLE_EXPORT).events(Uuid::nil()).unwrap()[0];
+        let calendar = ICalendar::from_events(std::slice::from_ref(event));
+        let reparsed = &parse(&calendar.to_ics()).events(Uuid::nil()).unwrap()[0];
+
+        let mut expected = event.clone();
+        expected.id = reparsed.id;
+        assert_eq!(reparsed, &expected);
+    }
+
+    #[test]
+    fn test_parameter_quoting_and_caret_escapes() {
+        let input = "BEGIN:VCALENDAR\r\n\
+BEGIN:VEVENT\r\n\
+UID:1\r\n\
+DTSTART:20240101T000000Z\r\n\
+ATTENDEE;CN=\"Doe, Jane: PhD\";X-NOTE=line^none^'quoted^'^^:mailto:jane@example.com\r\n\
+END:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        let calendar = parse(input);
+        let event = calendar.root.components_named("VEVENT").next().unwrap();
+        let attendee = event.property("ATTENDEE").unwrap();
+        assert_eq!(attendee.param("CN"), Some("Doe, Jane: PhD"));
+        assert_eq!(attendee.param("X-NOTE"), Some("line\none\"quoted\"^"));
+        assert_eq!(parse(&calendar.to_ics()), calendar);
+    }
+
+    #[test]
+    fn test_text_escaping() {
+        let text = "a,b;c\\d\nnext";
+        assert_eq!(escape_text(text), "a\\,b\\;c\\\\d\\nnext");
+        assert_eq!(unescape_text(&escape_text(text)), text);
+        assert_eq!(unescape_text("line\\Nbreak"), "line\nbreak");
+        assert_eq!(split_unescaped("a\\,b,c", ','), vec!["a\\,b", "c"]);
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("PT15M"), Some(Duration::minutes(15)));
+        assert_eq!(parse_duration("-P0DT0H10M0S"), Some(-Duration::minutes(10)));
+        assert_eq!(parse_duration("P1DT2H"), Some(Duration::hours(26)));
+        assert_eq!(parse_duration("P2W"), Some(Duration::weeks(2)));
+        assert_eq!(parse_duration("P"), None);
+        assert_eq!(parse_duration("PT5"), None);
+        assert_eq!(parse_duration("P1H"), None);
+        assert_eq!(format_duration(Duration::hours(26)), "P1DT2H");
+        assert_eq!(format_duration(-Duration::minutes(15)), "-PT15M");
+        assert_eq!(format_duration(Duration::zero()), "PT0S");
+    }
+
+    #[test]
+    fn test_structural_errors() {
+        assert!(matches!(
+            ICalendar::parse("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nEND:VCALENDAR\r\n"),
+            Err(ICalError::Structure { .. })
+        ));
+        assert!(matches!(
+            ICalendar::parse("BEGIN:VCALENDAR\r\n"),
+            Err(ICalError::Structure { .. })
+        ));
+        assert!(matches!(
+            ICalendar::parse("SUMMARY:orphan\r\n"),
+            Err(ICalError::Structure { .. })
+        ));
+        assert!(matches!(
+            ICalendar::parse("BEGIN:VCALENDAR\r\nno colon here\r\nEND:VCALENDAR\r\n"),
+            Err(ICalError::Syntax { line: 2, .. })
+        ));
+        let missing_uid = "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nDTSTART:20240101T000000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+        assert_eq!(
+            parse(missing_uid).events(Uuid::nil()),
+            Err(ICalError::MissingProperty("UID".to_string()))
+        );
+    }
+
+    /// Small deterministic generator so the fuzz test needs no extra crates.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn test_fuzz_mutations_never_panic_and_round_trip() {
+        const NOISE: &[&str] = &[
+            "\r\n",
+            "\r\n ",
+            ":",
+            ";",
+            ",",
+            "\"",
+            "=",
+            "\\",
+            "^",
+            "BEGIN:VEVENT\r\n",
+            "END:VEVENT\r\n",
+            "é",
+            "🎉",
+            "\t",
+            "Z",
+            "T",
+        ];
+        let mut rng = XorShift(0x5eed_1ca1);
+
+        for round in 0..3000 {
+            let base = [GOOGLE_EXPORT, OUTLOOK_EXPORT, APPLE_EXPORT][round % 3];
+            let mut input = base.to_string();
+            for _ in 0..1 + rng.below(4) {
+                if input.is_empty() {
+                    break;
+                }
+                let mut at = rng.below(input.len());
+                while !input.is_char_boundary(at) {
+                    at -= 1;
+                }
+                match rng.below(3) {
+                    0 => input.insert_str(at, NOISE[rng.below(NOISE.len())]),
+                    1 => {
+                        let mut end = (at + 1 + rng.below(8)).min(input.len());
+                        while !input.is_char_boundary(end) {
+                            end += 1;
+                        }
+                        input.replace_range(at..end, "");
+                    }
+                    _ => input.truncate(at),
+                }
+            }
+
+            if let Ok(calendar) = ICalendar::parse(&input) {
+                let serialized = calendar.to_ics();
+                assert_eq!(
+                    ICalendar::parse(&serialized).as_ref(),
+                    Ok(&calendar),
+                    "round trip failed for input {:?}",
+                    input
+                );
+                let zones = calendar.timezones();
+                for component in calendar.root.components_named("VEVENT") {
+                    if let Ok(event) = vevent_to_event(component, Uuid::nil(), &zones) {
+                        let mut copy = component.clone();
+                        update_vevent(&mut copy, &event, &zones);
+                    }
+                }
+            }
+        }
+    }
+
+    const TASKS_ORG: &str = "BEGIN:VCALENDAR\r\n\
+VERSION:2.0\r\n\
+PRODID:+//IDN tasks.org//android-130904//EN\r\n\
+BEGIN:VTODO\r\n\
+DTSTAMP:20240510T081500Z\r\n\
+UID:5318742891234567890\r\n\
+CREATED:20240501T090000Z\r\n\
+LAST-MODIFIED:20240510T081500Z\r\n\
+SUMMARY:Write release notes\r\n\
+PRIORITY:1\r\n\
+STATUS:IN-PROCESS\r\n\
+PERCENT-COMPLETE:40\r\n\
+DUE;VALUE=DATE:20240520\r\n\
+RELATED-TO:4400000000000000001\r\n\
+CATEGORIES:Release\r\n\
+X-APPLE-SORT-ORDER:-123\r\n\
+END:VTODO\r\n\
+END:VCALENDAR\r\n";
+
+    #[test]
+    fn test_vtodo_projection_and_round_trip() {
+        let calendar = parse(TASKS_ORG);
+        let tasks = calendar.tasks(Uuid::nil()).unwrap();
+        assert_eq!(tasks.len(), 1);
+        let task = &tasks[0];
+        assert_eq!(task.summary, "Write release notes");
+        assert_eq!(task.status, TaskStatus::InProcess);
+        assert_eq!(task.percent_complete, 40);
+        assert_eq!(task.priority, 1);
+        assert!(task.all_day);
+        assert_eq!(task.due, Some(utc(2024, 5, 20, 0, 0)));
+        assert_eq!(task.start_time, None);
+        assert_eq!(task.completed, None);
+        assert_eq!(
+            task.related_to,
+            vec![TaskRelation {
+                uid: "4400000000000000001".to_string(),
+                relation: RelationType::Parent,
+            }]
+        );
+
+        let mut done = task.clone();
+        done.status = TaskStatus::Completed;
+        done.percent_complete = 100;
+        done.completed = Some(utc(2024, 5, 19, 17, 30));
+        done.related_to.push(TaskRelation {
+            uid: "child-1".to_string(),
+            relation: RelationType::Child,
+        });
+        let serialized = ICalendar::from_tasks(std::slice::from_ref(&done)).to_ics();
+        assert!(serialized.contains("DUE;VALUE=DATE:20240520\r\n"));
+        assert!(serialized.contains("RELATED-TO;RELTYPE=CHILD:child-1\r\n"));
+        let mut reparsed = parse(&serialized).tasks(Uuid::nil()).unwrap().remove(0);
+        reparsed.id = done.id;
+        assert_eq!(reparsed, done);
+    }
+
+    #[test]
+    fn test_completed_without_status() {
+        let input = "BEGIN:VCALENDAR\r\nBEGIN:VTODO\r\nUID:t\r\n\
+DTSTART:20240101T090000Z\r\nDURATION:PT2H\r\nCOMPLETED:20240101T100000Z\r\n\
+PERCENT-COMPLETE:250\r\nEND:VTODO\r\nEND:VCALENDAR\r\n";
+        let task = parse(input).tasks(Uuid::nil()).unwrap().remove(0);
+        assert_eq!(task.status, TaskStatus::Completed);
+        assert_eq!(task.percent_complete, 100);
+        assert_eq!(task.due, Some(utc(2024, 1, 1, 11, 0)));
+        assert!(!task.all_day);
+    }
+
+    #[test]
+    fn test_split_objects_groups_by_uid() {
+        let input = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n\
+BEGIN:VTIMEZONE\r\nTZID:Europe/Paris\r\nEND:VTIMEZONE\r\n\
+BEGIN:VTIMEZONE\r\nTZID:America/New_York\r\nEND:VTIMEZONE\r\n\
+BEGIN:VEVENT\r\nUID:a\r\nDTSTART;TZID=Europe/Paris:20240101T090000\r\nSUMMARY:old\r\nEND:VEVENT\r\n\
+BEGIN:VTODO\r\nUID:b\r\nSUMMARY:task\r\nEND:VTODO\r\n\
+BEGIN:VEVENT\r\nUID:a\r\nRECURRENCE-ID;TZID=Europe/Paris:20240108T090000\r\n\
+DTSTART;TZID=Europe/Paris:20240108T100000\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:a\r\nDTSTART;TZID=Europe/Paris:20240101T090000\r\nSUMMARY:new\r\nEND:VEVENT\r\n\
+END:VCALENDAR\r\n";
+        let objects = parse(input).split_objects();
+        assert_eq!(objects.len(), 2);
+
+        let names: Vec<&str> = objects[0]
+            .root
+            .components
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(names, ["VTIMEZONE", "VEVENT", "VEVENT"]);
+        assert_eq!(
+            objects[0].root.components[0]
+                .property("TZID")
+                .unwrap()
+                .value,
+            "Europe/Paris"
+        );
+        // The duplicate master replaced the first one in place.
+        assert_eq!(
+            objects[0].root.components[1]
+                .property("SUMMARY")
+                .unwrap()
+                .value,
+            "new"
+        );
+        assert_eq!(objects[1].root.components.len(), 1);
+        assert_eq!(objects[1].root.components[0].name, "VTODO");
+
+        let merged = ICalendar::merge(&objects);
+        assert_eq!(merged.root.components_named("VTIMEZONE").count(), 1);
+        assert_eq!(merged.root.components_named("VEVENT").count(), 2);
+        assert_eq!(merged.root.components_named("VTODO").count(), 1);
+        assert_eq!(merged.root.components[0].name, "VTIMEZONE");
+    }
+
+    #[test]
+    fn test_raise_sequence() {
+        let mut calendar = parse(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:a\r\nSEQUENCE:3\r\nEND:VEVENT\r\n\
+BEGIN:VEVENT\r\nUID:a\r\nRECURRENCE-ID:20240101T000000Z\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        );
+        assert_eq!(calendar.sequence(), 3);
+        calendar.raise_sequence(4);
+        let sequences: Vec<&str> = calendar
+            .root
+            .components
+            .iter()
+            .map(|c| c.property("SEQUENCE").unwrap().value.as_str())
+            .collect();
+        assert_eq!(sequences, ["4", "4"]);
+    }
+}
@@ -0,0 +1,480 @@
+//! iTIP scheduling messages (RFC 5546) for CalDAV implicit scheduling
+//! (RFC 6638).
+//!
+//! The server schedules on behalf of calendar users: when an organizer saves
+//! an event, each attendee receives a `REQUEST` (or a `CANCEL` once removed),
+//! and when an attendee changes their participation status the organizer
+//! receives a `REPLY`, which is applied to the organizer's copy.
+
+use crate::caldav::AttendeeStatus;
+use crate::ical::{
+    calendar_address, format_utc, parse_partstat, partstat_value, Component, DateTimeValue,
+    ICalError, ICalendar, Property, TimeZones,
+};
+use chrono::{DateTime, Utc};
+
+/// Components that carry scheduling information.
+const SCHEDULING_COMPONENTS: &[&str] = &["VEVENT", "VTODO"];
+
+/// Parameters that only make sense on the sender's copy.
+const SCHEDULING_PARAMS: &[&str] = &["SCHEDULE-AGENT", "SCHEDULE-STATUS", "SCHEDULE-FORCE-SEND"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Request,
+    Reply,
+    Cancel,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Request => "REQUEST",
+            Method::Reply => "REPLY",
+            Method::Cancel => "CANCEL",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "REQUEST" => Some(Method::Request),
+            "REPLY" => Some(Method::Reply),
+            "CANCEL" => Some(Method::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// A calendar user address without `mailto:`, lower-cased for lookups.
+pub fn normalize_address(value: &str) -> String {
+    calendar_address(value).to_ascii_lowercase()
+}
+
+/// Whether two calendar user addresses name the same user.
+pub fn same_address(a: &str, b: &str) -> bool {
+    calendar_address(a).eq_ignore_ascii_case(&calendar_address(b))
+}
+
+fn scheduling_components(calendar: &Component) -> impl Iterator<Item = &Component> {
+    calendar
+        .components
+        .iter()
+        .filter(|c| SCHEDULING_COMPONENTS.contains(&c.name.as_str()))
+}
+
+/// The server only schedules for users whose `SCHEDULE-AGENT` is `SERVER`
+/// (the default).
+fn server_scheduled(property: &Property) -> bool {
+    property
+        .param("SCHEDULE-AGENT")
+        .is_none_or(|agent| agent.eq_ignore_ascii_case("SERVER"))
+}
+
+fn find_attendee<'a>(component: &'a Component, address: &str) -> Option<&'a Property> {
+    component
+        .properties_named("ATTENDEE")
+        .find(|p| same_address(&p.value, address))
+}
+
+/// The iTIP method of a scheduling message.
+pub fn method(calendar: &ICalendar) -> Option<Method> {
+    calendar
+        .root
+        .property("METHOD")
+        .and_then(|p| Method::parse(&p.value))
+}
+
+/// Address of the organizer of a calendar object resource, without `mailto:`.
+pub fn organizer(calendar: &ICalendar) -> Option<String> {
+    scheduling_components(&calendar.root)
+        .find_map(|c| c.property("ORGANIZER"))
+        .map(|p| calendar_address(&p.value))
+}
+
+/// Whether the organizer is scheduled by the server.
+pub fn organizer_scheduled(calendar: &ICalendar) -> bool {
+    scheduling_components(&calendar.root)
+        .find_map(|c| c.property("ORGANIZER"))
+        .is_some_and(server_scheduled)
+}
+
+/// Attendees the server schedules for, excluding the organizer, in order of
+/// first appearance.
+pub fn attendees(calendar: &ICalendar) -> Vec<String> {
+    let organizer = organizer(calendar);
+    let mut found: Vec<String> = Vec::new();
+    for component in scheduling_components(&calendar.root) {
+        for property in component.properties_named("ATTENDEE") {
+            let address = calendar_address(&property.value);
+            if !server_scheduled(property)
+                || organizer
+                    .as_deref()
+                    .is_some_and(|o| same_address(o, &address))
+                || found.iter().any(|a| same_address(a, &address))
+            {
+                continue;
+            }
+            found.push(address);
+        }
+    }
+    found
+}
+
+/// `PARTSTAT` of `address` in each component it is invited to, keyed by the
+/// raw `RECURRENCE-ID` (`None` for the master).
+pub fn participation(calendar: &ICalendar, address: &str) -> Vec<(Option<String>, AttendeeStatus)> {
+    scheduling_components(&calendar.root)
+        .filter_map(|component| {
+            let attendee = find_attendee(component, address)?;
+            Some((
+                component.property("RECURRENCE-ID").map(|p| p.value.clone()),
+                parse_partstat(attendee.param("PARTSTAT")),
+            ))
+        })
+        .collect()
+}
+
+/// The parts of an object attendees care about: everything except
+/// participation status, scheduling parameters and timestamps.
+fn scheduling_view(calendar: &ICalendar) -> String {
+    let mut root = calendar.root.clone();
+    for component in root.components.iter_mut() {
+        component.remove_properties("DTSTAMP");
+        component.remove_properties("LAST-MODIFIED");
+        component.components.retain(|c| c.name != "VALARM");
+        for property in component.properties.iter_mut() {
+            if property.name == "ATTENDEE" || property.name == "ORGANIZER" {
+                for param in ["PARTSTAT", "RSVP"].iter().chain(SCHEDULING_PARAMS) {
+                    property.remove_param(param);
+                }
+            }
+        }
+    }
+    root.to_ics()
+}
+
+/// Whether an organizer's change needs to be sent to attendees again.
+pub fn significant_change(old: &ICalendar, new: &ICalendar) -> bool {
+    scheduling_view(old) != scheduling_view(new)
+}
+
+/// Copy of `component` suitable for sending: no alarms and no scheduling
+/// parameters.
+fn outgoing(component: &Component, stamp: DateTime<Utc>) -> Component {
+    let mut component = component.clone();
+    component.components.retain(|c| c.name != "VALARM");
+    for property in component.properties.iter_mut() {
+        if property.name == "ATTENDEE" || property.name == "ORGANIZER" {
+            for param in SCHEDULING_PARAMS {
+                property.remove_param(param);
+            }
+        }
+    }
+    component.set_property(Property::new("DTSTAMP", format_utc(stamp)));
+    component
+}
+
+/// A message with `method` holding the given components and the time zones
+/// of `source`.
+fn message(source: &ICalendar, method: Method, components: Vec<Component>) -> ICalendar {
+    let mut calendar = ICalendar::new();
+    calendar
+        .root
+        .push_property(Property::new("METHOD", method.as_str()));
+    calendar.root.components.extend(
+        source
+            .root
+            .components_named("VTIMEZONE")
+            .cloned()
+            .chain(components),
+    );
+    calendar
+}
+
+/// A `REQUEST` for `attendee`, holding the instances they are invited to.
+/// Returns `None` when the attendee is not invited to any of them.
+pub fn request(calendar: &ICalendar, attendee: &str, stamp: DateTime<Utc>) -> Option<ICalendar> {
+    let components: Vec<Component> = scheduling_components(&calendar.root)
+        .filter(|c| find_attendee(c, attendee).is_some())
+        .map(|c| outgoing(c, stamp))
+        .collect();
+    (!components.is_empty()).then(|| message(calendar, Method::Request, components))
+}
+
+/// A `CANCEL` telling `attendee` they are no longer invited.
+pub fn cancel(calendar: &ICalendar, attendee: &str, stamp: DateTime<Utc>) -> Option<ICalendar> {
+    let components: Vec<Component> = scheduling_components(&calendar.root)
+        .filter(|c| find_attendee(c, attendee).is_some())
+        .map(|c| {
+            let mut component = outgoing(c, stamp);
+            component.set_property(Property::new("STATUS", "CANCELLED"));
+            component
+        })
+        .collect();
+    (!components.is_empty()).then(|| message(calendar, Method::Cancel, components))
+}
+
+/// A `REPLY` from `attendee` with their current participation status, or
+/// with `status` when given (e.g. `Declined` when they delete the event).
+pub fn reply(
+    calendar: &ICalendar,
+    attendee: &str,
+    status: Option<&AttendeeStatus>,
+    stamp: DateTime<Utc>,
+) -> Option<ICalendar> {
+    const KEPT: &[&str] = &["UID", "RECURRENCE-ID", "SEQUENCE", "ORGANIZER", "DTSTART"];
+    let mut components = Vec::new();
+    for component in scheduling_components(&calendar.root) {
+        let Some(property) = find_attendee(component, attendee) else {
+            continue;
+        };
+        let mut reply = Component::new(&component.name);
+        reply.push_property(Property::new("DTSTAMP", format_utc(stamp)));
+        for name in KEPT {
+            if let Some(kept) = component.property(name) {
+                reply.push_property(kept.clone());
+            }
+        }
+        let mut property = property.clone();
+        for param in SCHEDULING_PARAMS.iter().chain(&["RSVP"]) {
+            property.remove_param(param);
+        }
+        if let Some(status) = status {
+            property.set_param("PARTSTAT", partstat_value(status));
+        }
+        reply.push_property(property);
+        components.push(reply);
+    }
+    (!components.is_empty()).then(|| message(calendar, Method::Reply, components))
+}
+
+fn recurrence_id(
+    component: &Component,
+    zones: &TimeZones,
+) -> Result<Option<DateTime<Utc>>, ICalError> {
+    component
+        .property("RECURRENCE-ID")
+        .map(|p| DateTimeValue::parse(p).map(|id| zones.to_utc(&id)))
+        .transpose()
+}
+
+/// Apply a `REPLY` to the organizer's copy, updating the replying attendee's
+/// `PARTSTAT` on each instance it names. Returns whether anything changed.
+///
+/// Replies for instances the organizer has not overridden update the master.
+pub fn apply_reply(organizer_copy: &mut ICalendar, reply: &ICalendar) -> Result<bool, ICalError> {
+    let reply_zones = reply.timezones();
+    let zones = organizer_copy.timezones();
+    let mut changed = false;
+    for component in scheduling_components(&reply.root) {
+        let (Some(uid), Some(attendee)) =
+            (component.property("UID"), component.property("ATTENDEE"))
+        else {
+            continue;
+        };
+        let status = parse_partstat(attendee.param("PARTSTAT"));
+        let instance = recurrence_id(component, &reply_zones)?;
+
+        let mut matching = Vec::new();
+        for (index, target) in organizer_copy.root.components.iter().enumerate() {
+            if SCHEDULING_COMPONENTS.contains(&target.name.as_str())
+                && target.property("UID").map(|p| &p.value) == Some(&uid.value)
+            {
+                matching.push((index, recurrence_id(target, &zones)?));
+            }
+        }
+        let target = matching
+            .iter()
+            .find(|(_, id)| *id == instance)
+            .or_else(|| matching.iter().find(|(_, id)| id.is_none()))
+            .map(|(index, _)| *index);
+        let Some(target) = target else {
+            continue;
+        };
+
+        if let Some(property) = organizer_copy.root.components[target]
+            .properties
+            .iter_mut()
+            .find(|p| p.name == "ATTENDEE" && same_address(&p.value, &attendee.value))
+        {
+            if parse_partstat(property.param("PARTSTAT")) != status
+                || property.param("PARTSTAT").is_none()
+            {
+                property.set_param("PARTSTAT", partstat_value(&status));
+                property.remove_param("RSVP");
+                changed = true;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const MEETING: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+PRODID:-//Test//EN\r
+BEGIN:VEVENT\r
+UID:meeting-1\r
+DTSTAMP:20240301T080000Z\r
+DTSTART:20240304T090000Z\r
+DTEND:20240304T100000Z\r
+RRULE:FREQ=WEEKLY;COUNT=4\r
+SUMMARY:Planning\r
+SEQUENCE:2\r
+ORGANIZER;SCHEDULE-STATUS=1.2:mailto:alice@example.com\r
+ATTENDEE;PARTSTAT=ACCEPTED:mailto:alice@example.com\r
+ATTENDEE;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:mailto:Bob@Example.com\r
+ATTENDEE;SCHEDULE-AGENT=CLIENT:mailto:carol@example.com\r
+BEGIN:VALARM\r
+ACTION:DISPLAY\r
+TRIGGER:-PT10M\r
+END:VALARM\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:meeting-1\r
+RECURRENCE-ID:20240311T090000Z\r
+DTSTAMP:20240301T080000Z\r
+DTSTART:20240311T110000Z\r
+DTEND:20240311T120000Z\r
+SUMMARY:Planning (moved)\r
+SEQUENCE:2\r
+ORGANIZER:mailto:alice@example.com\r
+ATTENDEE;PARTSTAT=ACCEPTED:mailto:alice@example.com\r
+ATTENDEE;PARTSTAT=NEEDS-ACTION:mailto:bob@example.com\r
+ATTENDEE;PARTSTAT=NEEDS-ACTION:mailto:dave@example.com\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    fn stamp() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 2, 12, 0, 0).unwrap()
+    }
+
+    fn meeting() -> ICalendar {
+        ICalendar::parse(MEETING).unwrap()
+    }
+
+    #[test]
+    fn test_organizer_and_attendees() {
+        let calendar = meeting();
+        assert_eq!(organizer(&calendar).as_deref(), Some("alice@example.com"));
+        assert!(organizer_scheduled(&calendar));
+        // The organizer and client-scheduled attendees are left out.
+        assert_eq!(
+            attendees(&calendar),
+            vec![
+                "Bob@Example.com".to_string(),
+                "dave@example.com".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_request_holds_invited_instances_only() {
+        let calendar = meeting();
+        let request = request(&calendar, "mailto:dave@example.com", stamp()).unwrap();
+        assert_eq!(method(&request), Some(Method::Request));
+        let events: Vec<_> = request.root.components_named("VEVENT").collect();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].property("RECURRENCE-ID").is_some());
+
+        let request = super::request(&calendar, "bob@example.com", stamp()).unwrap();
+        let ics = request.to_ics();
+        assert_eq!(request.root.components_named("VEVENT").count(), 2);
+        assert!(!ics.contains("VALARM"));
+        assert!(!ics.contains("SCHEDULE-STATUS"));
+        assert!(ics.contains("DTSTAMP:20240302T120000Z\r\n"));
+        assert!(super::request(&calendar, "eve@example.com", stamp()).is_none());
+    }
+
+    #[test]
+    fn test_cancel_marks_components_cancelled() {
+        let cancel = cancel(&meeting(), "dave@example.com", stamp()).unwrap();
+        assert_eq!(method(&cancel), Some(Method::Cancel));
+        let event = cancel.root.components_named("VEVENT").next().unwrap();
+        assert_eq!(event.property("STATUS").unwrap().value, "CANCELLED");
+    }
+
+    #[test]
+    fn test_reply_round_trip_updates_partstat() {
+        let mut attendee_copy = meeting();
+        for event in attendee_copy.root.components.iter_mut() {
+            for property in event.properties.iter_mut() {
+                if property.name == "ATTENDEE" && same_address(&property.value, "bob@example.com") {
+                    property.set_param("PARTSTAT", "ACCEPTED");
+                }
+            }
+        }
+        let reply = reply(&attendee_copy, "bob@example.com", None, stamp()).unwrap();
+        assert_eq!(method(&reply), Some(Method::Reply));
+        let event = reply.root.components_named("VEVENT").next().unwrap();
+        assert_eq!(event.properties_named("ATTENDEE").count(), 1);
+        assert!(event.property("SUMMARY").is_none());
+
+        let mut organizer_copy = meeting();
+        assert!(apply_reply(&mut organizer_copy, &reply).unwrap());
+        assert_eq!(
+            participation(&organizer_copy, "bob@example.com"),
+            vec![
+                (None, AttendeeStatus::Accepted),
+                (
+                    Some("20240311T090000Z".to_string()),
+                    AttendeeStatus::Accepted
+                ),
+            ]
+        );
+        // Applying the same reply again changes nothing.
+        assert!(!apply_reply(&mut organizer_copy, &reply).unwrap());
+        // Participation changes are not significant for the organizer.
+        assert!(!significant_change(&meeting(), &organizer_copy));
+    }
+
+    #[test]
+    fn test_reply_for_unknown_instance_updates_master() {
+        let reply = ICalendar::parse(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nMETHOD:REPLY\r\nBEGIN:VEVENT\r\nUID:meeting-1\r\n\
+RECURRENCE-ID:20240318T090000Z\r\nDTSTAMP:20240302T120000Z\r\n\
+ATTENDEE;PARTSTAT=DECLINED:mailto:bob@example.com\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+        let mut organizer_copy = meeting();
+        assert!(apply_reply(&mut organizer_copy, &reply).unwrap());
+        assert_eq!(
+            participation(&organizer_copy, "bob@example.com")[0],
+            (None, AttendeeStatus::Declined)
+        );
+    }
+
+    #[test]
+    fn test_declining_by_deletion() {
+        let reply = reply(
+            &meeting(),
+            "bob@example.com",
+            Some(&AttendeeStatus::Declined),
+            stamp(),
+        )
+        .unwrap();
+        assert!(reply
+            .to_ics()
+            .contains("ATTENDEE;PARTSTAT=DECLINED:mailto:Bob@Example.com\r\n"));
+    }
+
+    #[test]
+    fn test_significant_changes() {
+        let old = meeting();
+        let moved = ICalendar::parse(
+            &MEETING.replace("DTSTART:20240304T090000Z", "DTSTART:20240304T093000Z"),
+        )
+        .unwrap();
+        assert!(significant_change(&old, &moved));
+        let restamped = ICalendar::parse(
+            &MEETING.replace("DTSTAMP:20240301T080000Z", "DTSTAMP:20240302T080000Z"),
+        )
+        .unwrap();
+        assert!(!significant_change(&old, &restamped));
+    }
+}
@@ -1,3 +1,14 @@
+pub mod caldav;
+pub mod carddav;
+pub mod errors;
+pub mod file;
+pub mod freebusy;
+pub mod ical;
+pub mod itip;
+pub mod recurrence;
+pub mod sync;
+pub mod vcard;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
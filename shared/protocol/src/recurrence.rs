@@ -0,0 +1,1730 @@
+//! Recurrence expansion for iCalendar components (RFC 5545 §3.3.10, §3.8.5).
+//!
+//! Rules are expanded in the wall-clock time of `DTSTART`, so a weekly 09:00
+//! meeting stays at 09:00 across daylight saving changes, and each instance
+//! is then resolved to UTC through the calendar's `VTIMEZONE`s. On top of the
+//! expansion this module implements the CalDAV (RFC 4791) pieces that depend
+//! on it: time-range matching, and the `expand` and `limit-recurrence-set`
+//! transforms of `calendar-data`.
+
+use crate::ical::{
+    days_in_month, format_date, format_utc, invalid, parse_duration, parse_weekday, Component,
+    DateTimeValue, ICalError, Property, TimeZones,
+};
+use chrono::{
+    DateTime, Datelike, Days, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone as _,
+    Timelike, Utc, Weekday,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::iter::Peekable;
+
+/// Upper bound on instances walked when computing the end of a finite set.
+const MAX_FINITE_INSTANCES: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Frequency {
+    Secondly,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Frequency {
+    fn parse(value: &str) -> Option<Self> {
+        Some(match value.trim().to_ascii_uppercase().as_str() {
+            "SECONDLY" => Frequency::Secondly,
+            "MINUTELY" => Frequency::Minutely,
+            "HOURLY" => Frequency::Hourly,
+            "DAILY" => Frequency::Daily,
+            "WEEKLY" => Frequency::Weekly,
+            "MONTHLY" => Frequency::Monthly,
+            "YEARLY" => Frequency::Yearly,
+            _ => return None,
+        })
+    }
+
+    /// Consecutive periods without an instance after which a rule is treated
+    /// as exhausted, e.g. `FREQ=MONTHLY;BYMONTH=2;BYMONTHDAY=30`. Roughly
+    /// four centuries for every frequency.
+    fn max_empty_periods(self) -> u32 {
+        match self {
+            Frequency::Yearly => 400,
+            Frequency::Monthly => 4_800,
+            Frequency::Weekly => 21_000,
+            _ => 150_000,
+        }
+    }
+
+    /// Length in seconds of one sub-daily period.
+    fn seconds(self) -> i64 {
+        match self {
+            Frequency::Hourly => 3_600,
+            Frequency::Minutely => 60,
+            _ => 1,
+        }
+    }
+}
+
+/// A parsed `RRULE` value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurrenceRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<DateTimeValue>,
+    pub by_second: Vec<u32>,
+    pub by_minute: Vec<u32>,
+    pub by_hour: Vec<u32>,
+    /// `BYDAY` entries as (ordinal, weekday); ordinal 0 means every such day.
+    pub by_day: Vec<(i32, Weekday)>,
+    pub by_month_day: Vec<i32>,
+    pub by_year_day: Vec<i32>,
+    pub by_week_no: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+    pub week_start: Weekday,
+}
+
+impl RecurrenceRule {
+    pub fn parse(value: &str) -> Result<Self, ICalError> {
+        let invalid = || ICalError::InvalidValue {
+            property: "RRULE".to_string(),
+            value: value.to_string(),
+        };
+        let mut frequency = None;
+        let mut rule = Self {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_second: Vec::new(),
+            by_minute: Vec::new(),
+            by_hour: Vec::new(),
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_year_day: Vec::new(),
+            by_week_no: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+        for part in value.trim().split(';').filter(|p| !p.is_empty()) {
+            let (key, v) = part.split_once('=').ok_or_else(invalid)?;
+            match key.trim().to_ascii_uppercase().as_str() {
+                "FREQ" => frequency = Some(Frequency::parse(v).ok_or_else(invalid)?),
+                "INTERVAL" => {
+                    rule.interval = v
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|&i| i > 0)
+                        .ok_or_else(invalid)?
+                }
+                "COUNT" => rule.count = Some(v.trim().parse().map_err(|_| invalid())?),
+                "UNTIL" => {
+                    rule.until = Some(DateTimeValue::parse_value(v, None).ok_or_else(invalid)?)
+                }
+                "BYSECOND" => rule.by_second = parse_list(v, 0, 60).ok_or_else(invalid)?,
+                "BYMINUTE" => rule.by_minute = parse_list(v, 0, 59).ok_or_else(invalid)?,
+                "BYHOUR" => rule.by_hour = parse_list(v, 0, 23).ok_or_else(invalid)?,
+                "BYDAY" => {
+                    rule.by_day = v
+                        .split(',')
+                        .map(parse_by_day)
+                        .collect::<Option<_>>()
+                        .ok_or_else(invalid)?
+                }
+                "BYMONTHDAY" => rule.by_month_day = parse_signed_list(v, 31).ok_or_else(invalid)?,
+                "BYYEARDAY" => rule.by_year_day = parse_signed_list(v, 366).ok_or_else(invalid)?,
+                "BYWEEKNO" => rule.by_week_no = parse_signed_list(v, 53).ok_or_else(invalid)?,
+                "BYMONTH" => rule.by_month = parse_list(v, 1, 12).ok_or_else(invalid)?,
+                "BYSETPOS" => rule.by_set_pos = parse_signed_list(v, 366).ok_or_else(invalid)?,
+                "WKST" => rule.week_start = parse_weekday(v.trim()).ok_or_else(invalid)?,
+                // X- rule parts and RFC 7529 (RSCALE/SKIP) are not supported.
+                _ => {}
+            }
+        }
+        rule.frequency = frequency.ok_or_else(invalid)?;
+        Ok(rule)
+    }
+
+    /// Whether the rule has neither COUNT nor UNTIL.
+    pub fn is_infinite(&self) -> bool {
+        self.count.is_none() && self.until.is_none()
+    }
+
+    /// Iterate the wall-clock start times generated by this rule for a
+    /// component starting at `start`, in ascending order.
+    ///
+    /// Only times produced by the rule itself are returned: `DTSTART` is not
+    /// added when it does not match the rule (see [`RecurrenceSet`]).
+    pub fn iter(&self, start: &DateTimeValue, zones: &TimeZones) -> RuleIter<'_> {
+        RuleIter::new(self, start, zones)
+    }
+
+    /// UNTIL as a wall-clock bound comparable with the expanded times.
+    fn local_until(&self, start: &DateTimeValue, zones: &TimeZones) -> Option<NaiveDateTime> {
+        let until = self.until.as_ref()?;
+        Some(match (until, start) {
+            // A DATE bound includes the whole day.
+            (DateTimeValue::Date(date), _) => date.and_hms_opt(23, 59, 59)?,
+            (DateTimeValue::Utc(time), DateTimeValue::Local { tzid, .. }) => {
+                zones.utc_to_local(*time, tzid.as_deref())
+            }
+            (other, _) => other.naive(),
+        })
+    }
+}
+
+fn parse_list(value: &str, min: u32, max: u32) -> Option<Vec<u32>> {
+    value
+        .split(',')
+        .map(|v| v.trim().parse().ok().filter(|n| (min..=max).contains(n)))
+        .collect()
+}
+
+fn parse_signed_list(value: &str, max: i32) -> Option<Vec<i32>> {
+    value
+        .split(',')
+        .map(|v| {
+            v.trim()
+                .parse::<i32>()
+                .ok()
+                .filter(|n| *n != 0 && n.abs() <= max)
+        })
+        .collect()
+}
+
+fn parse_by_day(value: &str) -> Option<(i32, Weekday)> {
+    let value = value.trim();
+    if value.len() < 2 || !value.is_ascii() {
+        return None;
+    }
+    let (ordinal, day) = value.split_at(value.len() - 2);
+    let weekday = parse_weekday(day)?;
+    let ordinal = match ordinal {
+        "" => 0,
+        n => n.parse::<i32>().ok().filter(|n| *n != 0 && n.abs() <= 53)?,
+    };
+    Some((ordinal, weekday))
+}
+
+fn days_in_year(year: i32) -> u32 {
+    if NaiveDate::from_ymd_opt(year, 2, 29).is_some() {
+        366
+    } else {
+        365
+    }
+}
+
+/// Days from `from` forward to `day` (0..=6).
+fn days_since(day: Weekday, from: Weekday) -> u64 {
+    ((day.num_days_from_monday() + 7 - from.num_days_from_monday()) % 7) as u64
+}
+
+/// First day of week 1 of `year`: the first week, starting on `week_start`,
+/// with at least four days in the year (the one containing January 4th).
+fn week_one_start(year: i32, week_start: Weekday) -> Option<NaiveDate> {
+    let jan4 = NaiveDate::from_ymd_opt(year, 1, 4)?;
+    jan4.checked_sub_days(Days::new(days_since(jan4.weekday(), week_start)))
+}
+
+/// Whether a 1-based `position` among `len` items matches a BYxxx value that
+/// may count from the end.
+fn matches_index(n: i32, position: u32, len: u32) -> bool {
+    if n > 0 {
+        n as u32 == position
+    } else {
+        len as i32 + 1 + n == position as i32
+    }
+}
+
+/// Scope that BYDAY ordinals such as `-1SU` count within.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OrdinalScope {
+    Month,
+    Year,
+    None,
+}
+
+/// Iterator over the wall-clock times produced by a [`RecurrenceRule`].
+pub struct RuleIter<'a> {
+    rule: &'a RecurrenceRule,
+    start: NaiveDateTime,
+    until: Option<NaiveDateTime>,
+    months: Vec<u32>,
+    month_days: Vec<i32>,
+    week_days: Vec<(i32, Weekday)>,
+    scope: OrdinalScope,
+    period: i64,
+    pending: VecDeque<NaiveDateTime>,
+    emitted: u32,
+    empty_periods: u32,
+    done: bool,
+}
+
+impl<'a> RuleIter<'a> {
+    fn new(rule: &'a RecurrenceRule, start: &DateTimeValue, zones: &TimeZones) -> Self {
+        let until = rule.local_until(start, zones);
+        let start = start.naive();
+        let mut months = rule.by_month.clone();
+        let mut month_days = rule.by_month_day.clone();
+        let mut week_days = rule.by_day.clone();
+
+        // Without any day-level part, the day is taken from DTSTART.
+        let no_day_parts = rule.by_week_no.is_empty()
+            && rule.by_year_day.is_empty()
+            && rule.by_month_day.is_empty()
+            && rule.by_day.is_empty();
+        if no_day_parts {
+            match rule.frequency {
+                Frequency::Yearly => {
+                    if months.is_empty() {
+                        months.push(start.month());
+                    }
+                    month_days.push(start.day() as i32);
+                }
+                Frequency::Monthly => month_days.push(start.day() as i32),
+                Frequency::Weekly => week_days.push((0, start.weekday())),
+                _ => {}
+            }
+        }
+
+        let scope = match rule.frequency {
+            Frequency::Monthly => OrdinalScope::Month,
+            Frequency::Yearly if !rule.by_week_no.is_empty() => OrdinalScope::None,
+            Frequency::Yearly if !months.is_empty() => OrdinalScope::Month,
+            Frequency::Yearly => OrdinalScope::Year,
+            _ => OrdinalScope::None,
+        };
+
+        Self {
+            rule,
+            start,
+            until,
+            months,
+            month_days,
+            week_days,
+            scope,
+            period: 0,
+            pending: VecDeque::new(),
+            emitted: 0,
+            empty_periods: 0,
+            done: false,
+        }
+    }
+
+    fn date_matches(&self, date: NaiveDate) -> bool {
+        let rule = self.rule;
+        (self.months.is_empty() || self.months.contains(&date.month()))
+            && (rule.by_year_day.is_empty()
+                || rule
+                    .by_year_day
+                    .iter()
+                    .any(|&n| matches_index(n, date.ordinal(), days_in_year(date.year()))))
+            && (self.month_days.is_empty()
+                || self.month_days.iter().any(|&n| {
+                    matches_index(n, date.day(), days_in_month(date.year(), date.month()))
+                }))
+            && (self.week_days.is_empty()
+                || self.week_days.iter().any(|&(n, weekday)| {
+                    weekday == date.weekday() && (n == 0 || self.ordinal_matches(n, date))
+                }))
+    }
+
+    fn ordinal_matches(&self, n: i32, date: NaiveDate) -> bool {
+        let (position, len) = match self.scope {
+            OrdinalScope::Month => (date.day(), days_in_month(date.year(), date.month())),
+            OrdinalScope::Year => (date.ordinal(), days_in_year(date.year())),
+            OrdinalScope::None => return true,
+        };
+        if n > 0 {
+            (position - 1) / 7 + 1 == n as u32
+        } else {
+            (len - position) / 7 + 1 == n.unsigned_abs()
+        }
+    }
+
+    /// Candidate days of a yearly period.
+    fn year_dates(&self, year: i32) -> Option<Vec<NaiveDate>> {
+        if self.rule.by_week_no.is_empty() {
+            let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+            return Some(
+                first
+                    .iter_days()
+                    .take(days_in_year(year) as usize)
+                    .collect(),
+            );
+        }
+        // Week-numbered days may spill into the neighbouring years.
+        let week_start = self.rule.week_start;
+        let week1 = week_one_start(year, week_start)?;
+        let weeks = (week_one_start(year + 1, week_start)? - week1).num_days() / 7;
+        let mut dates = Vec::new();
+        for &n in &self.rule.by_week_no {
+            let n = if n > 0 {
+                n as i64
+            } else {
+                weeks + 1 + n as i64
+            };
+            if n < 1 || n > weeks {
+                continue;
+            }
+            let first = week1.checked_add_days(Days::new((n as u64 - 1) * 7))?;
+            dates.extend(first.iter_days().take(7));
+        }
+        dates.sort();
+        dates.dedup();
+        Some(dates)
+    }
+
+    /// Times of day for a period; `fixed` components come from the period
+    /// itself for sub-daily frequencies.
+    fn times(&self, fixed: [Option<u32>; 3]) -> Vec<NaiveTime> {
+        let rule = self.rule;
+        let pick = |fixed: Option<u32>, by: &[u32], default: u32| -> Vec<u32> {
+            match fixed {
+                Some(v) if by.is_empty() || by.contains(&v) => vec![v],
+                Some(_) => Vec::new(),
+                None if by.is_empty() => vec![default],
+                None => {
+                    let mut values = by.to_vec();
+                    values.sort_unstable();
+                    values.dedup();
+                    values
+                }
+            }
+        };
+        let hours = pick(fixed[0], &rule.by_hour, self.start.hour());
+        let minutes = pick(fixed[1], &rule.by_minute, self.start.minute());
+        let seconds = pick(fixed[2], &rule.by_second, self.start.second());
+        let mut times = Vec::new();
+        for &h in &hours {
+            for &m in &minutes {
+                for &s in &seconds {
+                    if let Some(time) = NaiveTime::from_hms_opt(h, m, s) {
+                        times.push(time);
+                    }
+                }
+            }
+        }
+        times
+    }
+
+    /// Candidates of the next period, or `None` once no further period can
+    /// produce anything.
+    fn next_period(&mut self) -> Option<Vec<NaiveDateTime>> {
+        let rule = self.rule;
+        let steps = self.period.checked_mul(rule.interval as i64)?;
+        self.period += 1;
+
+        let (dates, fixed) = match rule.frequency {
+            Frequency::Yearly => {
+                let year = i32::try_from(self.start.year() as i64 + steps).ok()?;
+                (self.year_dates(year)?, [None; 3])
+            }
+            Frequency::Monthly => {
+                let index = (self.start.year() as i64 * 12 + self.start.month0() as i64)
+                    .checked_add(steps)?;
+                let year = i32::try_from(index.div_euclid(12)).ok()?;
+                let month = index.rem_euclid(12) as u32 + 1;
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let days = days_in_month(year, month) as usize;
+                (first.iter_days().take(days).collect(), [None; 3])
+            }
+            Frequency::Weekly => {
+                let offset = days_since(self.start.weekday(), rule.week_start);
+                let first = self
+                    .start
+                    .date()
+                    .checked_sub_days(Days::new(offset))?
+                    .checked_add_signed(Duration::try_weeks(steps)?)?;
+                (first.iter_days().take(7).collect(), [None; 3])
+            }
+            Frequency::Daily => {
+                let date = self
+                    .start
+                    .date()
+                    .checked_add_signed(Duration::try_days(steps)?)?;
+                (vec![date], [None; 3])
+            }
+            Frequency::Hourly | Frequency::Minutely | Frequency::Secondly => {
+                let unit = rule.frequency.seconds();
+                let base = match rule.frequency {
+                    Frequency::Hourly => self.start.date().and_hms_opt(self.start.hour(), 0, 0)?,
+                    Frequency::Minutely => {
+                        self.start
+                            .date()
+                            .and_hms_opt(self.start.hour(), self.start.minute(), 0)?
+                    }
+                    _ => self.start,
+                };
+                let time =
+                    base.checked_add_signed(Duration::try_seconds(steps.checked_mul(unit)?)?)?;
+                if self.until.is_some_and(|until| time > until) {
+                    return None;
+                }
+                if !self.date_matches(time.date()) {
+                    // Skip the rest of the day in one step.
+                    let next_day = time.date().succ_opt()?.and_time(NaiveTime::MIN);
+                    let period = unit * rule.interval as i64;
+                    let remaining = (next_day - base).num_seconds();
+                    self.period = self.period.max((remaining + period - 1) / period);
+                    return Some(Vec::new());
+                }
+                let fixed = match rule.frequency {
+                    Frequency::Hourly => [Some(time.hour()), None, None],
+                    Frequency::Minutely => [Some(time.hour()), Some(time.minute()), None],
+                    _ => [Some(time.hour()), Some(time.minute()), Some(time.second())],
+                };
+                (vec![time.date()], fixed)
+            }
+        };
+
+        if let (Some(until), Some(first)) = (self.until, dates.first()) {
+            if *first > until.date() {
+                return None;
+            }
+        }
+
+        let times = self.times(fixed);
+        let mut candidates: Vec<NaiveDateTime> = dates
+            .into_iter()
+            .filter(|date| self.date_matches(*date))
+            .flat_map(|date| times.iter().map(move |time| date.and_time(*time)))
+            .collect();
+
+        if !rule.by_set_pos.is_empty() {
+            let len = candidates.len() as i32;
+            let mut picked: Vec<NaiveDateTime> = rule
+                .by_set_pos
+                .iter()
+                .filter_map(|&pos| {
+                    let index = if pos > 0 { pos - 1 } else { len + pos };
+                    (0..len)
+                        .contains(&index)
+                        .then(|| candidates[index as usize])
+                })
+                .collect();
+            picked.sort();
+            picked.dedup();
+            candidates = picked;
+        }
+        Some(candidates)
+    }
+}
+
+impl Iterator for RuleIter<'_> {
+    type Item = NaiveDateTime;
+
+    fn next(&mut self) -> Option<NaiveDateTime> {
+        loop {
+            if let Some(time) = self.pending.pop_front() {
+                let past_until = self.until.is_some_and(|until| time > until);
+                let past_count = self.rule.count.is_some_and(|count| self.emitted >= count);
+                if past_until || past_count {
+                    self.pending.clear();
+                    self.done = true;
+                    return None;
+                }
+                self.emitted += 1;
+                return Some(time);
+            }
+            if self.done {
+                return None;
+            }
+            match self.next_period() {
+                None => self.done = true,
+                Some(candidates) if candidates.is_empty() => {
+                    self.empty_periods += 1;
+                    if self.empty_periods > self.rule.frequency.max_empty_periods() {
+                        self.done = true;
+                    }
+                }
+                Some(candidates) => {
+                    self.empty_periods = 0;
+                    let start = self.start;
+                    self.pending
+                        .extend(candidates.into_iter().filter(|time| *time >= start));
+                }
+            }
+        }
+    }
+}
+
+/// An `RDATE` value, with its own duration when given as a PERIOD.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurrenceDate {
+    pub value: DateTimeValue,
+    pub duration: Option<Duration>,
+}
+
+/// One occurrence of a recurrence set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Occurrence {
+    /// The instance's original start, in the form of `DTSTART`; this is the
+    /// value a `RECURRENCE-ID` refers to.
+    pub recurrence_id: DateTimeValue,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// The recurrence set of a component: `DTSTART`, plus the `RRULE` and
+/// `RDATE` instances, minus `EXDATE`.
+#[derive(Debug, Clone)]
+pub struct RecurrenceSet {
+    pub start: DateTimeValue,
+    pub duration: Duration,
+    pub rules: Vec<RecurrenceRule>,
+    pub dates: Vec<RecurrenceDate>,
+    pub exceptions: Vec<DateTimeValue>,
+}
+
+impl RecurrenceSet {
+    /// Read the recurrence set of a `VEVENT`, `VTODO` or `VJOURNAL`.
+    ///
+    /// Returns `None` for components without `DTSTART`, which have no
+    /// recurrence set (e.g. a `VTODO` with only `DUE`).
+    pub fn from_component(
+        component: &Component,
+        zones: &TimeZones,
+    ) -> Result<Option<Self>, ICalError> {
+        let Some(dtstart) = component.property("DTSTART") else {
+            return Ok(None);
+        };
+        let start = DateTimeValue::parse(dtstart)?;
+
+        let end = component
+            .property("DTEND")
+            .or_else(|| component.property("DUE"));
+        let duration = if let Some(end) = end {
+            match (&start, DateTimeValue::parse(end)?) {
+                (DateTimeValue::Date(s), DateTimeValue::Date(e)) => e - *s,
+                (_, end) => zones.to_utc(&end) - zones.to_utc(&start),
+            }
+        } else if let Some(duration) = component.property("DURATION") {
+            parse_duration(&duration.value).ok_or_else(|| invalid(duration))?
+        } else if start.is_date() {
+            Duration::days(1)
+        } else {
+            Duration::zero()
+        };
+
+        let rules = component
+            .properties_named("RRULE")
+            .map(|p| RecurrenceRule::parse(&p.value))
+            .collect::<Result<_, _>>()?;
+
+        let mut dates = Vec::new();
+        for property in component.properties_named("RDATE") {
+            let is_period = property
+                .param("VALUE")
+                .is_some_and(|v| v.eq_ignore_ascii_case("PERIOD"));
+            for value in property.value.split(',') {
+                dates.push(if is_period {
+                    parse_period(property, value, zones)?
+                } else {
+                    RecurrenceDate {
+                        value: parse_date_value(property, value)?,
+                        duration: None,
+                    }
+                });
+            }
+        }
+
+        let exceptions = component
+            .properties_named("EXDATE")
+            .flat_map(|p| p.value.split(',').map(move |v| parse_date_value(p, v)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Some(Self {
+            start,
+            duration: duration.max(Duration::zero()),
+            rules,
+            dates,
+            exceptions,
+        }))
+    }
+
+    pub fn is_recurring(&self) -> bool {
+        !self.rules.is_empty() || !self.dates.is_empty()
+    }
+
+    /// Whether the set has an unbounded rule.
+    pub fn is_infinite(&self) -> bool {
+        self.rules.iter().any(RecurrenceRule::is_infinite)
+    }
+
+    /// All occurrences in ascending order of start; infinite for unbounded
+    /// rules.
+    pub fn occurrences<'a>(&'a self, zones: &'a TimeZones) -> Occurrences<'a> {
+        let mut dates: Vec<Occurrence> =
+            std::iter::once(self.occurrence(zones, self.start.clone(), self.duration))
+                .chain(self.dates.iter().map(|rdate| {
+                    self.occurrence(
+                        zones,
+                        rdate.value.clone(),
+                        rdate.duration.unwrap_or(self.duration),
+                    )
+                }))
+                .collect();
+        dates.sort_by_key(|o| o.start);
+
+        let mut excluded = HashSet::new();
+        let mut excluded_days = HashSet::new();
+        for exception in &self.exceptions {
+            match exception {
+                DateTimeValue::Date(date) if !self.start.is_date() => {
+                    excluded_days.insert(*date);
+                }
+                value => {
+                    excluded.insert(zones.to_utc(value));
+                }
+            }
+        }
+
+        Occurrences {
+            set: self,
+            zones,
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| rule.iter(&self.start, zones).peekable())
+                .collect(),
+            dates: dates.into(),
+            excluded,
+            excluded_days,
+            last: None,
+        }
+    }
+
+    /// Occurrences overlapping `[start, end)` per RFC 4791 §9.9, at most
+    /// `limit` of them.
+    pub fn occurrences_between(
+        &self,
+        zones: &TimeZones,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        limit: Option<usize>,
+    ) -> Vec<Occurrence> {
+        let mut found = Vec::new();
+        for occurrence in self.occurrences(zones) {
+            if occurrence.start >= end || limit.is_some_and(|limit| found.len() >= limit) {
+                break;
+            }
+            if overlaps(occurrence.start, occurrence.end, start, end) {
+                found.push(occurrence);
+            }
+        }
+        found
+    }
+
+    /// End of the last occurrence, or `None` when the set is unbounded (or
+    /// too large to walk).
+    pub fn last_end(&self, zones: &TimeZones) -> Option<DateTime<Utc>> {
+        if self.is_infinite() {
+            return None;
+        }
+        let mut last = None;
+        for (i, occurrence) in self.occurrences(zones).enumerate() {
+            if i >= MAX_FINITE_INSTANCES {
+                return None;
+            }
+            last = last.max(Some(occurrence.end));
+        }
+        last
+    }
+
+    fn occurrence(
+        &self,
+        zones: &TimeZones,
+        recurrence_id: DateTimeValue,
+        duration: Duration,
+    ) -> Occurrence {
+        let start = zones.to_utc(&recurrence_id);
+        let end = start.checked_add_signed(duration).unwrap_or(start);
+        Occurrence {
+            recurrence_id,
+            start,
+            end,
+        }
+    }
+
+    /// Wrap a wall-clock time produced by a rule in the form of `DTSTART`.
+    fn rule_value(&self, local: NaiveDateTime) -> DateTimeValue {
+        match &self.start {
+            DateTimeValue::Date(_) => DateTimeValue::Date(local.date()),
+            DateTimeValue::Utc(_) => DateTimeValue::Utc(Utc.from_utc_datetime(&local)),
+            DateTimeValue::Local { tzid, .. } => DateTimeValue::Local {
+                time: local,
+                tzid: tzid.clone(),
+            },
+        }
+    }
+}
+
+fn parse_date_value(property: &Property, value: &str) -> Result<DateTimeValue, ICalError> {
+    DateTimeValue::parse_value(value, property.param("TZID")).ok_or_else(|| invalid(property))
+}
+
+/// Parse a PERIOD (`start/end` or `start/duration`).
+fn parse_period(
+    property: &Property,
+    value: &str,
+    zones: &TimeZones,
+) -> Result<RecurrenceDate, ICalError> {
+    let (start, end) = value.split_once('/').ok_or_else(|| invalid(property))?;
+    let start = parse_date_value(property, start)?;
+    let duration = match parse_duration(end) {
+        Some(duration) => duration,
+        None => zones.to_utc(&parse_date_value(property, end)?) - zones.to_utc(&start),
+    };
+    Ok(RecurrenceDate {
+        value: start,
+        duration: Some(duration.max(Duration::zero())),
+    })
+}
+
+/// Iterator over the occurrences of a [`RecurrenceSet`].
+pub struct Occurrences<'a> {
+    set: &'a RecurrenceSet,
+    zones: &'a TimeZones,
+    rules: Vec<Peekable<RuleIter<'a>>>,
+    dates: VecDeque<Occurrence>,
+    excluded: HashSet<DateTime<Utc>>,
+    excluded_days: HashSet<NaiveDate>,
+    last: Option<DateTime<Utc>>,
+}
+
+impl Occurrences<'_> {
+    fn next_candidate(&mut self) -> Option<Occurrence> {
+        let mut best: Option<(DateTime<Utc>, Option<usize>)> = None;
+        for (i, rule) in self.rules.iter_mut().enumerate() {
+            if let Some(local) = rule.peek() {
+                let start = self.zones.to_utc(&self.set.rule_value(*local));
+                if best.is_none_or(|(b, _)| start < b) {
+                    best = Some((start, Some(i)));
+                }
+            }
+        }
+        if let Some(date) = self.dates.front() {
+            if best.is_none_or(|(b, _)| date.start <= b) {
+                best = Some((date.start, None));
+            }
+        }
+        match best? {
+            (_, Some(i)) => {
+                let local = self.rules[i].next()?;
+                let value = self.set.rule_value(local);
+                Some(self.set.occurrence(self.zones, value, self.set.duration))
+            }
+            (_, None) => self.dates.pop_front(),
+        }
+    }
+}
+
+impl Iterator for Occurrences<'_> {
+    type Item = Occurrence;
+
+    fn next(&mut self) -> Option<Occurrence> {
+        loop {
+            let occurrence = self.next_candidate()?;
+            if self.last == Some(occurrence.start) {
+                continue;
+            }
+            self.last = Some(occurrence.start);
+            let day = occurrence.recurrence_id.naive().date();
+            if self.excluded.contains(&occurrence.start) || self.excluded_days.contains(&day) {
+                continue;
+            }
+            return Some(occurrence);
+        }
+    }
+}
+
+/// RFC 4791 §9.9 overlap test: a zero-length instance matches when it starts
+/// inside the range, otherwise the two intervals must intersect.
+pub fn overlaps(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    range_start: DateTime<Utc>,
+    range_end: DateTime<Utc>,
+) -> bool {
+    if end > start {
+        range_start < end && range_end > start
+    } else {
+        range_start <= start && range_end > start
+    }
+}
+
+/// One instance of a component, after applying overridden instances.
+#[derive(Debug, Clone)]
+pub struct Instance<'a> {
+    /// The master component, or the override that replaces this instance.
+    pub component: &'a Component,
+    /// `None` for components that do not recur.
+    pub recurrence_id: Option<DateTimeValue>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+fn uid(component: &Component) -> &str {
+    component
+        .property("UID")
+        .map(|p| p.value.as_str())
+        .unwrap_or("")
+}
+
+fn this_and_future(recurrence_id: &Property) -> bool {
+    recurrence_id
+        .param("RANGE")
+        .is_some_and(|r| r.eq_ignore_ascii_case("THISANDFUTURE"))
+}
+
+/// Instances of the `name` components (e.g. `VEVENT`) of a calendar object
+/// resource that overlap `[start, end)`, sorted by start.
+///
+/// Components sharing a `UID` form one recurring item: instances listed by a
+/// `RECURRENCE-ID` component replace the master's, wherever they were moved
+/// to. `RANGE=THISANDFUTURE` overrides only replace their own instance.
+pub fn instances<'a>(
+    calendar: &'a Component,
+    name: &str,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<Instance<'a>>, ICalError> {
+    let mut masters = Vec::new();
+    let mut overrides: HashMap<&str, Vec<(&Component, DateTimeValue)>> = HashMap::new();
+    for component in calendar
+        .components
+        .iter()
+        .filter(|c| c.name.eq_ignore_ascii_case(name))
+    {
+        match component.property("RECURRENCE-ID") {
+            Some(id) => overrides
+                .entry(uid(component))
+                .or_default()
+                .push((component, DateTimeValue::parse(id)?)),
+            None => masters.push(component),
+        }
+    }
+
+    let mut found = Vec::new();
+    for master in masters {
+        let Some(set) = RecurrenceSet::from_component(master, zones)? else {
+            continue;
+        };
+        let overridden: HashSet<DateTime<Utc>> = overrides
+            .get(uid(master))
+            .map(|o| o.iter().map(|(_, id)| zones.to_utc(id)).collect())
+            .unwrap_or_default();
+        let recurring = set.is_recurring();
+        for occurrence in set.occurrences_between(zones, start, end, None) {
+            if overridden.contains(&occurrence.start) {
+                continue;
+            }
+            found.push(Instance {
+                component: master,
+                recurrence_id: recurring.then_some(occurrence.recurrence_id),
+                start: occurrence.start,
+                end: occurrence.end,
+            });
+        }
+    }
+    for (component, id) in overrides.into_values().flatten() {
+        let Some(set) = RecurrenceSet::from_component(component, zones)? else {
+            continue;
+        };
+        let occurrence = set.occurrence(zones, set.start.clone(), set.duration);
+        if overlaps(occurrence.start, occurrence.end, start, end) {
+            found.push(Instance {
+                component,
+                recurrence_id: Some(id),
+                start: occurrence.start,
+                end: occurrence.end,
+            });
+        }
+    }
+    found.sort_by_key(|instance| instance.start);
+    Ok(found)
+}
+
+/// RFC 4791 §9.9 time-range test for a component without `DTSTART`.
+fn undated_matches(
+    component: &Component,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<bool, ICalError> {
+    let time = |name: &str| {
+        component
+            .property(name)
+            .map(|p| zones.resolve(p))
+            .transpose()
+    };
+    Ok(match (time("DUE")?, time("COMPLETED")?, time("CREATED")?) {
+        (Some(due), _, _) => start < due && end >= due,
+        (None, Some(completed), Some(created)) => {
+            (start <= created || start <= completed) && (end >= created || end >= completed)
+        }
+        (None, Some(completed), None) => start <= completed && end >= completed,
+        (None, None, Some(created)) => end > created,
+        // A VTODO with no dates at all matches any range.
+        (None, None, None) => component.name == "VTODO",
+    })
+}
+
+/// Whether any `name` component of a calendar object resource overlaps
+/// `[start, end)`, the `CALDAV:time-range` test of a `comp-filter`.
+pub fn matches_time_range(
+    calendar: &Component,
+    name: &str,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<bool, ICalError> {
+    if !instances(calendar, name, zones, start, end)?.is_empty() {
+        return Ok(true);
+    }
+    for component in calendar.components_named(name) {
+        if component.property("DTSTART").is_none() && undated_matches(component, zones, start, end)?
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn time_property(name: &str, time: DateTime<Utc>, all_day: bool) -> Property {
+    if all_day {
+        Property::new(name, format_date(time.date_naive())).with_param("VALUE", "DATE")
+    } else {
+        Property::new(name, format_utc(time))
+    }
+}
+
+fn expanded_instance(instance: &Instance<'_>, zones: &TimeZones) -> Result<Component, ICalError> {
+    let mut component = instance.component.clone();
+    let all_day = match component.property("DTSTART") {
+        Some(dtstart) => DateTimeValue::parse(dtstart)?.is_date(),
+        None => false,
+    };
+    if component.property("RECURRENCE-ID").is_none() {
+        for name in ["RRULE", "RDATE", "EXDATE", "EXRULE"] {
+            component.remove_properties(name);
+        }
+    }
+    component.set_property(time_property("DTSTART", instance.start, all_day));
+    for name in ["DTEND", "DUE"] {
+        if component.property(name).is_some() {
+            component.set_property(time_property(name, instance.end, all_day));
+        }
+    }
+    if let Some(id) = &instance.recurrence_id {
+        component.set_property(time_property(
+            "RECURRENCE-ID",
+            zones.to_utc(id),
+            id.is_date(),
+        ));
+    }
+    Ok(component)
+}
+
+/// The `CALDAV:expand` transform (RFC 4791 §9.6.5): every instance
+/// overlapping `[start, end)` becomes its own component with a
+/// `RECURRENCE-ID`, times are converted to UTC and `VTIMEZONE`s dropped.
+pub fn expand(
+    calendar: &Component,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Component, ICalError> {
+    let mut expanded = Component::new(&calendar.name);
+    expanded.properties = calendar.properties.clone();
+
+    let mut names: Vec<&str> = Vec::new();
+    for component in &calendar.components {
+        if component.name != "VTIMEZONE" && !names.contains(&component.name.as_str()) {
+            names.push(&component.name);
+        }
+    }
+    for name in names {
+        if !matches!(name, "VEVENT" | "VTODO" | "VJOURNAL") {
+            expanded
+                .components
+                .extend(calendar.components_named(name).cloned());
+            continue;
+        }
+        for instance in instances(calendar, name, zones, start, end)? {
+            expanded
+                .components
+                .push(expanded_instance(&instance, zones)?);
+        }
+        for component in calendar.components_named(name) {
+            if component.property("DTSTART").is_none()
+                && undated_matches(component, zones, start, end)?
+            {
+                expanded.components.push(component.clone());
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// The `CALDAV:limit-recurrence-set` transform (RFC 4791 §9.6.6): keep the
+/// master components, but only the overridden instances that overlap
+/// `[start, end)` or whose original instance did.
+pub fn limit_recurrence_set(
+    calendar: &Component,
+    zones: &TimeZones,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Component, ICalError> {
+    let mut limited = Component::new(&calendar.name);
+    limited.properties = calendar.properties.clone();
+    for component in &calendar.components {
+        if let Some(id) = component.property("RECURRENCE-ID") {
+            let original = zones.resolve(id)?;
+            let keep = this_and_future(id)
+                || (original >= start && original < end)
+                || match RecurrenceSet::from_component(component, zones)? {
+                    Some(set) => {
+                        let occurrence = set.occurrence(zones, set.start.clone(), set.duration);
+                        overlaps(occurrence.start, occurrence.end, start, end)
+                    }
+                    None => true,
+                };
+            if !keep {
+                continue;
+            }
+        }
+        limited.components.push(component.clone());
+    }
+    Ok(limited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ical::{format_date_time, ICalendar};
+
+    const NEW_YORK: &str = "BEGIN:VTIMEZONE\r
+TZID:America/New_York\r
+BEGIN:DAYLIGHT\r
+TZOFFSETFROM:-0500\r
+TZOFFSETTO:-0400\r
+DTSTART:20070311T020000\r
+RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=2SU\r
+TZNAME:EDT\r
+END:DAYLIGHT\r
+BEGIN:STANDARD\r
+TZOFFSETFROM:-0400\r
+TZOFFSETTO:-0500\r
+DTSTART:20071104T020000\r
+RRULE:FREQ=YEARLY;BYMONTH=11;BYDAY=1SU\r
+TZNAME:EST\r
+END:STANDARD\r
+END:VTIMEZONE\r
+";
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    /// Expand a rule for a floating DTSTART, as compact local times.
+    fn expand_rule(rule: &str, dtstart: &str, take: usize) -> Vec<String> {
+        let rule = RecurrenceRule::parse(rule).unwrap();
+        let start = DateTimeValue::parse_value(dtstart, None).unwrap();
+        rule.iter(&start, &TimeZones::default())
+            .take(take)
+            .map(format_date_time)
+            .collect()
+    }
+
+    fn days(dates: &[&str], time: &str) -> Vec<String> {
+        dates.iter().map(|d| format!("{}T{}", d, time)).collect()
+    }
+
+    fn calendar(events: &str) -> ICalendar {
+        let ics = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Test//EN\r\n{}{}END:VCALENDAR\r\n",
+            NEW_YORK, events
+        );
+        ICalendar::parse(&ics).unwrap()
+    }
+
+    #[test]
+    fn test_rule_parsing() {
+        let rule = RecurrenceRule::parse(
+            "FREQ=MONTHLY;INTERVAL=2;COUNT=10;BYDAY=1SU,-1SU,TU;BYSETPOS=-1;WKST=SU;X-FOO=1",
+        )
+        .unwrap();
+        assert_eq!(rule.frequency, Frequency::Monthly);
+        assert_eq!(rule.interval, 2);
+        assert_eq!(rule.count, Some(10));
+        assert_eq!(
+            rule.by_day,
+            vec![(1, Weekday::Sun), (-1, Weekday::Sun), (0, Weekday::Tue)]
+        );
+        assert_eq!(rule.by_set_pos, vec![-1]);
+        assert_eq!(rule.week_start, Weekday::Sun);
+        assert!(!rule.is_infinite());
+
+        for bad in [
+            "INTERVAL=2",
+            "FREQ=FORTNIGHTLY",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=DAILY;BYMONTHDAY=0",
+            "FREQ=DAILY;BYMONTH=13",
+            "FREQ=DAILY;BYDAY=XX",
+            "FREQ=DAILY;BYDAY=60MO",
+            "FREQ=DAILY;UNTIL=tomorrow",
+            "FREQ=DAILY;COUNT",
+        ] {
+            assert!(
+                matches!(
+                    RecurrenceRule::parse(bad),
+                    Err(ICalError::InvalidValue { .. })
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_rfc5545_daily_and_weekly_examples() {
+        assert_eq!(
+            expand_rule("FREQ=DAILY;COUNT=10", "19970902T090000", 20),
+            days(
+                &[
+                    "19970902", "19970903", "19970904", "19970905", "19970906", "19970907",
+                    "19970908", "19970909", "19970910", "19970911"
+                ],
+                "090000"
+            )
+        );
+
+        let until = expand_rule("FREQ=DAILY;UNTIL=19971224T000000Z", "19970902T090000", 500);
+        assert_eq!(until.len(), 113);
+        assert_eq!(until.last().unwrap(), "19971223T090000");
+
+        assert_eq!(
+            expand_rule(
+                "FREQ=WEEKLY;UNTIL=19971007T000000Z;WKST=SU;BYDAY=TU,TH",
+                "19970902T090000",
+                20
+            ),
+            days(
+                &[
+                    "19970902", "19970904", "19970909", "19970911", "19970916", "19970918",
+                    "19970923", "19970925", "19970930", "19971002"
+                ],
+                "090000"
+            )
+        );
+
+        // WKST changes which days share a week with an INTERVAL.
+        assert_eq!(
+            expand_rule(
+                "FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=MO",
+                "19970805T090000",
+                10
+            ),
+            days(&["19970805", "19970810", "19970819", "19970824"], "090000")
+        );
+        assert_eq!(
+            expand_rule(
+                "FREQ=WEEKLY;INTERVAL=2;COUNT=4;BYDAY=TU,SU;WKST=SU",
+                "19970805T090000",
+                10
+            ),
+            days(&["19970805", "19970817", "19970819", "19970831"], "090000")
+        );
+    }
+
+    #[test]
+    fn test_rfc5545_monthly_examples() {
+        assert_eq!(
+            expand_rule("FREQ=MONTHLY;COUNT=10;BYDAY=1FR", "19970905T090000", 20),
+            days(
+                &[
+                    "19970905", "19971003", "19971107", "19971205", "19980102", "19980206",
+                    "19980306", "19980403", "19980501", "19980605"
+                ],
+                "090000"
+            )
+        );
+        assert_eq!(
+            expand_rule("FREQ=MONTHLY;COUNT=6;BYDAY=-2MO", "19970922T090000", 20),
+            days(
+                &["19970922", "19971020", "19971117", "19971222", "19980119", "19980216"],
+                "090000"
+            )
+        );
+        // Friday the 13th; DTSTART itself is not an instance of the rule.
+        assert_eq!(
+            expand_rule("FREQ=MONTHLY;BYDAY=FR;BYMONTHDAY=13", "19970902T090000", 5),
+            days(
+                &["19980213", "19980313", "19981113", "19990813", "20001013"],
+                "090000"
+            )
+        );
+        // Second-to-last weekday of the month.
+        assert_eq!(
+            expand_rule(
+                "FREQ=MONTHLY;BYDAY=MO,TU,WE,TH,FR;BYSETPOS=-2",
+                "19970929T090000",
+                7
+            ),
+            days(
+                &[
+                    "19970929", "19971030", "19971127", "19971230", "19980129", "19980226",
+                    "19980330"
+                ],
+                "090000"
+            )
+        );
+        // Invalid dates (February 30th) are skipped, not moved.
+        assert_eq!(
+            expand_rule(
+                "FREQ=MONTHLY;BYMONTHDAY=15,30;COUNT=5",
+                "20070115T090000",
+                10
+            ),
+            days(
+                &["20070115", "20070130", "20070215", "20070315", "20070330"],
+                "090000"
+            )
+        );
+    }
+
+    #[test]
+    fn test_rfc5545_yearly_examples() {
+        assert_eq!(
+            expand_rule("FREQ=YEARLY;COUNT=10;BYMONTH=6,7", "19970610T090000", 20),
+            days(
+                &[
+                    "19970610", "19970710", "19980610", "19980710", "19990610", "19990710",
+                    "20000610", "20000710", "20010610", "20010710"
+                ],
+                "090000"
+            )
+        );
+        assert_eq!(
+            expand_rule(
+                "FREQ=YEARLY;INTERVAL=3;COUNT=10;BYYEARDAY=1,100,200",
+                "19970101T090000",
+                20
+            ),
+            days(
+                &[
+                    "19970101", "19970410", "19970719", "20000101", "20000409", "20000718",
+                    "20030101", "20030410", "20030719", "20060101"
+                ],
+                "090000"
+            )
+        );
+        assert_eq!(
+            expand_rule("FREQ=YEARLY;BYWEEKNO=20;BYDAY=MO", "19970512T090000", 3),
+            days(&["19970512", "19980511", "19990517"], "090000")
+        );
+        assert_eq!(
+            expand_rule("FREQ=YEARLY;BYDAY=20MO", "19970519T090000", 3),
+            days(&["19970519", "19980518", "19990517"], "090000")
+        );
+        assert_eq!(
+            expand_rule("FREQ=YEARLY", "20000229T090000", 3),
+            days(&["20000229", "20040229", "20080229"], "090000")
+        );
+    }
+
+    #[test]
+    fn test_sub_daily_rules() {
+        assert_eq!(
+            expand_rule(
+                "FREQ=HOURLY;INTERVAL=3;UNTIL=19970902T170000",
+                "19970902T090000",
+                10
+            ),
+            vec!["19970902T090000", "19970902T120000", "19970902T150000"]
+        );
+
+        let minutes = expand_rule(
+            "FREQ=MINUTELY;INTERVAL=20;BYHOUR=9,10,11,12,13,14,15,16",
+            "19970902T090000",
+            26,
+        );
+        assert_eq!(minutes[23], "19970902T164000");
+        assert_eq!(minutes[24], "19970903T090000");
+        assert_eq!(minutes[25], "19970903T092000");
+
+        // Whole days failing the date parts are skipped in one step.
+        assert_eq!(
+            expand_rule(
+                "FREQ=HOURLY;BYMONTH=3;BYMONTHDAY=1;BYHOUR=8",
+                "20200101T003000",
+                2
+            ),
+            vec!["20200301T083000", "20210301T083000"]
+        );
+    }
+
+    #[test]
+    fn test_impossible_rules_terminate() {
+        assert!(
+            expand_rule("FREQ=MONTHLY;BYMONTH=2;BYMONTHDAY=30", "20240101T090000", 1).is_empty()
+        );
+        assert!(
+            expand_rule("FREQ=YEARLY;BYYEARDAY=366;BYMONTH=1", "20240101T090000", 1).is_empty()
+        );
+        assert!(
+            expand_rule("FREQ=SECONDLY;INTERVAL=2;BYSECOND=1", "20240101T090000", 1).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_rule_follows_wall_clock_across_dst() {
+        let cal = calendar(
+            "BEGIN:VEVENT\r
+UID:standup\r
+DTSTART;TZID=America/New_York:20240301T090000\r
+DTEND;TZID=America/New_York:20240301T093000\r
+RRULE:FREQ=WEEKLY;COUNT=3\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:daily\r
+DTSTART;TZID=America/New_York:20240309T090000\r
+RRULE:FREQ=DAILY;UNTIL=20240312T130000Z\r
+END:VEVENT\r
+",
+        );
+        let zones = cal.timezones();
+        let sets: Vec<RecurrenceSet> = cal
+            .root
+            .components_named("VEVENT")
+            .map(|c| RecurrenceSet::from_component(c, &zones).unwrap().unwrap())
+            .collect();
+
+        let weekly: Vec<_> = sets[0].occurrences(&zones).collect();
+        let starts: Vec<_> = weekly.iter().map(|o| o.start).collect();
+        assert_eq!(
+            starts,
+            vec![
+                utc(2024, 3, 1, 14, 0),
+                utc(2024, 3, 8, 14, 0),
+                utc(2024, 3, 15, 13, 0)
+            ]
+        );
+        assert_eq!(weekly[2].end, utc(2024, 3, 15, 13, 30));
+        assert_eq!(
+            weekly[2].recurrence_id,
+            DateTimeValue::Local {
+                time: NaiveDate::from_ymd_opt(2024, 3, 15)
+                    .unwrap()
+                    .and_hms_opt(9, 0, 0)
+                    .unwrap(),
+                tzid: Some("America/New_York".to_string()),
+            }
+        );
+
+        // A UTC UNTIL is compared in the event's own time zone.
+        let daily: Vec<_> = sets[1].occurrences(&zones).map(|o| o.start).collect();
+        assert_eq!(
+            daily,
+            vec![
+                utc(2024, 3, 9, 14, 0),
+                utc(2024, 3, 10, 13, 0),
+                utc(2024, 3, 11, 13, 0),
+                utc(2024, 3, 12, 13, 0)
+            ]
+        );
+        assert_eq!(sets[1].last_end(&zones), Some(utc(2024, 3, 12, 13, 0)));
+    }
+
+    #[test]
+    fn test_rdate_exdate_and_periods() {
+        let cal = calendar(
+            "BEGIN:VEVENT\r
+UID:set\r
+DTSTART:20240101T100000Z\r
+DURATION:PT1H\r
+RRULE:FREQ=DAILY;COUNT=5\r
+EXDATE:20240103T100000Z\r
+RDATE;VALUE=PERIOD:20240110T150000Z/PT2H,20240102T100000Z/20240102T103000Z\r
+RDATE:20240108T080000Z\r
+END:VEVENT\r
+",
+        );
+        let zones = cal.timezones();
+        let set = RecurrenceSet::from_component(&cal.root.components[1], &zones)
+            .unwrap()
+            .unwrap();
+        assert!(set.is_recurring());
+        assert!(!set.is_infinite());
+
+        let occurrences: Vec<_> = set.occurrences(&zones).map(|o| (o.start, o.end)).collect();
+        assert_eq!(
+            occurrences,
+            vec![
+                (utc(2024, 1, 1, 10, 0), utc(2024, 1, 1, 11, 0)),
+                // An RDATE period on an existing instance sets its length.
+                (utc(2024, 1, 2, 10, 0), utc(2024, 1, 2, 10, 30)),
+                (utc(2024, 1, 4, 10, 0), utc(2024, 1, 4, 11, 0)),
+                (utc(2024, 1, 5, 10, 0), utc(2024, 1, 5, 11, 0)),
+                (utc(2024, 1, 8, 8, 0), utc(2024, 1, 8, 9, 0)),
+                (utc(2024, 1, 10, 15, 0), utc(2024, 1, 10, 17, 0)),
+            ]
+        );
+        assert_eq!(set.last_end(&zones), Some(utc(2024, 1, 10, 17, 0)));
+
+        let between = set.occurrences_between(
+            &zones,
+            utc(2024, 1, 4, 10, 30),
+            utc(2024, 1, 10, 15, 0),
+            None,
+        );
+        assert_eq!(between.len(), 3);
+        assert_eq!(between[0].start, utc(2024, 1, 4, 10, 0));
+        assert_eq!(
+            set.occurrences_between(
+                &zones,
+                utc(2024, 1, 1, 0, 0),
+                utc(2025, 1, 1, 0, 0),
+                Some(2)
+            )
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_time_range_semantics() {
+        let point = utc(2024, 5, 1, 12, 0);
+        // Zero-length instances match when they start inside the range.
+        assert!(overlaps(point, point, point, utc(2024, 5, 1, 13, 0)));
+        assert!(!overlaps(point, point, utc(2024, 5, 1, 11, 0), point));
+        // Otherwise the end of the range and of the instance are exclusive.
+        let end = utc(2024, 5, 1, 13, 0);
+        assert!(!overlaps(point, end, end, utc(2024, 5, 1, 14, 0)));
+        assert!(!overlaps(point, end, utc(2024, 5, 1, 11, 0), point));
+        assert!(overlaps(
+            point,
+            end,
+            utc(2024, 5, 1, 12, 59),
+            utc(2024, 5, 1, 14, 0)
+        ));
+
+        let cal = calendar(
+            "BEGIN:VEVENT\r
+UID:all-day\r
+DTSTART;VALUE=DATE:20240501\r
+RRULE:FREQ=YEARLY\r
+END:VEVENT\r
+BEGIN:VTODO\r
+UID:todo\r
+DUE:20240601T120000Z\r
+END:VTODO\r
+",
+        );
+        let zones = cal.timezones();
+        let root = &cal.root;
+        // An all-day instance lasts the whole day.
+        assert!(matches_time_range(
+            root,
+            "VEVENT",
+            &zones,
+            utc(2030, 5, 1, 23, 0),
+            utc(2030, 5, 2, 0, 0)
+        )
+        .unwrap());
+        assert!(!matches_time_range(
+            root,
+            "VEVENT",
+            &zones,
+            utc(2030, 5, 2, 0, 0),
+            utc(2030, 5, 3, 0, 0)
+        )
+        .unwrap());
+        // A VTODO with only DUE matches ranges containing it.
+        assert!(matches_time_range(
+            root,
+            "VTODO",
+            &zones,
+            utc(2024, 6, 1, 0, 0),
+            utc(2024, 6, 1, 12, 0)
+        )
+        .unwrap());
+        assert!(!matches_time_range(
+            root,
+            "VTODO",
+            &zones,
+            utc(2024, 6, 1, 12, 0),
+            utc(2024, 6, 2, 0, 0)
+        )
+        .unwrap());
+        assert!(!matches_time_range(
+            root,
+            "VJOURNAL",
+            &zones,
+            utc(2000, 1, 1, 0, 0),
+            utc(2100, 1, 1, 0, 0)
+        )
+        .unwrap());
+    }
+
+    const OVERRIDDEN: &str = "BEGIN:VEVENT\r
+UID:weekly\r
+SUMMARY:Weekly sync\r
+DTSTART;TZID=America/New_York:20240102T100000\r
+DTEND;TZID=America/New_York:20240102T110000\r
+RRULE:FREQ=WEEKLY\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:weekly\r
+SUMMARY:Moved to Friday\r
+RECURRENCE-ID;TZID=America/New_York:20240109T100000\r
+DTSTART;TZID=America/New_York:20240112T140000\r
+DTEND;TZID=America/New_York:20240112T150000\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:weekly\r
+SUMMARY:Moved to March\r
+RECURRENCE-ID;TZID=America/New_York:20240116T100000\r
+DTSTART;TZID=America/New_York:20240301T100000\r
+DTEND;TZID=America/New_York:20240301T110000\r
+END:VEVENT\r
+";
+
+    #[test]
+    fn test_overridden_instances() {
+        let cal = calendar(OVERRIDDEN);
+        let zones = cal.timezones();
+        let january = instances(
+            &cal.root,
+            "VEVENT",
+            &zones,
+            utc(2024, 1, 1, 0, 0),
+            utc(2024, 1, 20, 0, 0),
+        )
+        .unwrap();
+        let summary: Vec<_> = january
+            .iter()
+            .map(|i| {
+                (
+                    i.start,
+                    i.component.property("SUMMARY").unwrap().value.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (utc(2024, 1, 2, 15, 0), "Weekly sync"),
+                (utc(2024, 1, 12, 19, 0), "Moved to Friday"),
+            ]
+        );
+
+        // The override moved into March is found there, next to the master's
+        // own March instances.
+        let march = instances(
+            &cal.root,
+            "VEVENT",
+            &zones,
+            utc(2024, 3, 1, 0, 0),
+            utc(2024, 3, 6, 0, 0),
+        )
+        .unwrap();
+        assert_eq!(march.len(), 2);
+        assert_eq!(
+            march[0].component.property("SUMMARY").unwrap().value,
+            "Moved to March"
+        );
+        assert_eq!(march[1].start, utc(2024, 3, 5, 15, 0));
+    }
+
+    #[test]
+    fn test_expand_and_limit_recurrence_set() {
+        let cal = calendar(OVERRIDDEN);
+        let zones = cal.timezones();
+        let (start, end) = (utc(2024, 1, 1, 0, 0), utc(2024, 1, 14, 0, 0));
+
+        let expanded = expand(&cal.root, &zones, start, end).unwrap();
+        assert_eq!(expanded.components_named("VTIMEZONE").count(), 0);
+        let events: Vec<_> = expanded.components_named("VEVENT").collect();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.property("RRULE").is_none()));
+        assert_eq!(
+            events[0].property("DTSTART").unwrap().value,
+            "20240102T150000Z"
+        );
+        assert_eq!(
+            events[0].property("DTEND").unwrap().value,
+            "20240102T160000Z"
+        );
+        assert_eq!(
+            events[0].property("RECURRENCE-ID").unwrap().value,
+            "20240102T150000Z"
+        );
+        assert_eq!(
+            events[1].property("DTSTART").unwrap().value,
+            "20240112T190000Z"
+        );
+        assert_eq!(
+            events[1].property("RECURRENCE-ID").unwrap().value,
+            "20240109T150000Z"
+        );
+        assert!(events[1]
+            .property("RECURRENCE-ID")
+            .unwrap()
+            .param("TZID")
+            .is_none());
+        // The expanded calendar is still valid iCalendar.
+        ICalendar::parse(&expanded.to_ics()).unwrap();
+
+        let limited = limit_recurrence_set(&cal.root, &zones, start, end).unwrap();
+        assert_eq!(limited.components_named("VTIMEZONE").count(), 1);
+        let summaries: Vec<_> = limited
+            .components_named("VEVENT")
+            .map(|e| e.property("SUMMARY").unwrap().value.as_str())
+            .collect();
+        assert_eq!(summaries, vec!["Weekly sync", "Moved to Friday"]);
+    }
+
+    #[test]
+    fn test_all_day_expansion_keeps_dates() {
+        let cal = calendar(
+            "BEGIN:VEVENT\r
+UID:birthday\r
+DTSTART;VALUE=DATE:20200815\r
+DTEND;VALUE=DATE:20200816\r
+RRULE:FREQ=YEARLY\r
+END:VEVENT\r
+",
+        );
+        let zones = cal.timezones();
+        let set = RecurrenceSet::from_component(&cal.root.components[1], &zones)
+            .unwrap()
+            .unwrap();
+        assert!(set.is_infinite());
+        assert_eq!(set.last_end(&zones), None);
+
+        let expanded = expand(
+            &cal.root,
+            &zones,
+            utc(2024, 1, 1, 0, 0),
+            utc(2025, 1, 1, 0, 0),
+        )
+        .unwrap();
+        let event = &expanded.components[0];
+        let dtstart = event.property("DTSTART").unwrap();
+        assert_eq!(dtstart.value, "20240815");
+        assert_eq!(dtstart.param("VALUE"), Some("DATE"));
+        assert_eq!(event.property("DTEND").unwrap().value, "20240816");
+        assert_eq!(event.property("RECURRENCE-ID").unwrap().value, "20240815");
+    }
+}
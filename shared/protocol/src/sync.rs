@@ -12,7 +12,7 @@ pub struct SyncState {
     pub file_states: HashMap<String, FileState>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileState {
     pub path: String,
     pub checksum: String,
@@ -22,7 +22,7 @@ pub struct FileState {
     pub sync_status: SyncStatus,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SyncStatus {
     Synced,
     Modified,
@@ -52,7 +52,7 @@ pub struct SyncResponse {
 pub struct FileChange {
     pub path: String,
     pub change_type: ChangeType,
-    pub metadata: Option<super::FileMetadata>,
+    pub metadata: Option<crate::file::FileMetadata>,
     pub checksum: Option<String>,
     pub delta: Option<FileDelta>,
 }
@@ -80,7 +80,29 @@ pub struct DeltaBlock {
     pub data: Option<Vec<u8>>, // None for unchanged blocks
 }
 
+/// Upload of a modified file as a delta against a version the server has.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaUploadRequest {
+    pub device_id: Uuid,
+    pub path: String,
+    /// Version the delta was computed against; the upload is refused if the
+    /// file changed since.
+    pub base_version: u64,
+    /// Checksum of the file once the delta is applied.
+    pub target_checksum: String,
+    pub delta: FileDelta,
+    #[serde(default)]
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+/// Bring a deleted file back from the trash with its last stored content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreFileRequest {
+    pub device_id: Uuid,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SyncConflict {
     pub path: String,
     pub conflict_type: ConflictType,
@@ -89,7 +111,7 @@ pub struct SyncConflict {
     pub base_version: Option<FileState>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConflictType {
     ModifyModify,
     ModifyDelete,
@@ -97,14 +119,14 @@ pub enum ConflictType {
     MoveMove,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConflictResolution {
     pub path: String,
     pub resolution: ResolutionStrategy,
-    pub resolved_metadata: Option<super::FileMetadata>,
+    pub resolved_metadata: Option<crate::file::FileMetadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResolutionStrategy {
     UseLocal,
     UseRemote,
@@ -114,7 +136,7 @@ pub enum ResolutionStrategy {
     Custom { content: Vec<u8> },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncProgress {
     pub total_files: u64,
     pub completed_files: u64,
@@ -125,7 +147,7 @@ pub struct SyncProgress {
     pub eta_seconds: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Device {
     pub id: Uuid,
     pub user_id: Uuid,
@@ -134,11 +156,20 @@ pub struct Device {
     pub platform: String,
     pub version: String,
     pub last_seen: DateTime<Utc>,
+    /// False once the device has been revoked.
     pub is_active: bool,
     pub sync_folders: Vec<SyncFolder>,
+    /// A remote wipe is pending: the device is told to delete its sync
+    /// folders the next time it connects.
+    #[serde(default)]
+    pub wipe_requested: bool,
+    /// X25519 public key, base64, that folder keys of encrypted folders are
+    /// sealed for.
+    #[serde(default)]
+    pub public_key: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeviceType {
     Desktop,
     Mobile,
@@ -146,7 +177,118 @@ pub enum DeviceType {
     Cli,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterDeviceRequest {
+    pub name: String,
+    pub device_type: DeviceType,
+    pub platform: String,
+    pub version: String,
+    #[serde(default)]
+    pub sync_folders: Vec<SyncFolder>,
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterDeviceResponse {
+    pub device: Device,
+    /// Bearer token scoped to the device, refused once it is revoked.
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateDeviceRequest {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub sync_folders: Option<Vec<SyncFolder>>,
+    /// Set once, by a device registered before it had a key pair.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+/// A folder whose names and contents are encrypted by the devices, which
+/// the server only stores as ciphertext.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedFolder {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    /// Path of the folder in its owner's files; the names below it are
+    /// encrypted.
+    pub path: String,
+    pub created_at: DateTime<Utc>,
+    /// The folder key sealed for the calling device, if it has been given
+    /// one.
+    pub sealed_key: Option<String>,
+    /// Devices of the caller's account that have no copy of the key yet,
+    /// and its recovery key if that has none either, for a device holding
+    /// the key to approve.
+    #[serde(default)]
+    pub pending_devices: Vec<DeviceKey>,
+}
+
+/// An active device's public key. A recovery key is listed the same way,
+/// under its own id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceKey {
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+    pub public_key: String,
+}
+
+/// A folder key sealed for one device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SealedFolderKey {
+    pub device_id: Uuid,
+    pub sealed_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateEncryptedFolderRequest {
+    pub path: String,
+    /// The new folder key, sealed for at least the creating device.
+    pub keys: Vec<SealedFolderKey>,
+}
+
+/// Give more devices the folder key; sealing it for another user's devices
+/// shares the folder with them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddFolderKeysRequest {
+    pub keys: Vec<SealedFolderKey>,
+}
+
+/// A user's recovery key: an X25519 key pair derived from a passphrase,
+/// whose public half folder keys are sealed for as for a device, so they
+/// can be recovered after losing every device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryKey {
+    pub id: Uuid,
+    pub public_key: String,
+    /// Argon2id parameters the key pair is derived from the passphrase
+    /// with, as written by `crypto::PasswordKeyParams`. None for a key
+    /// given as a raw recovery code.
+    #[serde(default)]
+    pub kdf_params: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Replace the account's recovery key. Folder keys sealed for the previous
+/// one are forgotten.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetRecoveryKeyRequest {
+    pub public_key: String,
+    pub kdf_params: String,
+}
+
+/// A folder key sealed for the account's recovery key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveredFolderKey {
+    pub folder_id: Uuid,
+    pub owner_id: Uuid,
+    pub path: String,
+    pub sealed_key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncFolder {
     pub local_path: String,
     pub remote_path: String,
@@ -155,14 +297,15 @@ pub struct SyncFolder {
     pub filters: SyncFilters,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SyncDirection {
     Bidirectional,
     UploadOnly,
     DownloadOnly,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SyncFilters {
     pub include_patterns: Vec<String>,
     pub exclude_patterns: Vec<String>,
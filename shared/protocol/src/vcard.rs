@@ -0,0 +1,632 @@
+//! vCard 3.0 (RFC 2426) and 4.0 (RFC 6350) parsing and serialization.
+//!
+//! vCards share iCalendar's content-line syntax, so they are parsed into the
+//! same generic [`Component`] tree and serialize back losslessly. [`Contact`]
+//! is a projection of the card; [`VCard::from_contact`] writes one in either
+//! version and [`VCard::convert`] translates the version-specific parts of a
+//! stored card (`PREF`, `tel:` URIs, date formats) for clients that ask for
+//! the other version.
+
+use crate::carddav::{Contact, ContactEmail, ContactPhone, PostalAddress, StructuredName};
+use crate::ical::{
+    escape_text, parse_components, split_unescaped, unescape_text, Component, DateTimeValue,
+    ICalError, Parameter, Property,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VCardVersion {
+    V3,
+    V4,
+}
+
+impl VCardVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VCardVersion::V3 => "3.0",
+            VCardVersion::V4 => "4.0",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "3.0" => Some(VCardVersion::V3),
+            "4.0" => Some(VCardVersion::V4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VCardError {
+    Parse(ICalError),
+    /// The data is not a single `VCARD`.
+    Structure(String),
+    UnsupportedVersion(String),
+    MissingProperty(String),
+}
+
+impl fmt::Display for VCardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VCardError::Parse(e) => write!(f, "{}", e),
+            VCardError::Structure(message) => write!(f, "{}", message),
+            VCardError::UnsupportedVersion(version) => {
+                write!(f, "unsupported vCard version {}", version)
+            }
+            VCardError::MissingProperty(name) => write!(f, "missing required property {}", name),
+        }
+    }
+}
+
+impl std::error::Error for VCardError {}
+
+impl From<ICalError> for VCardError {
+    fn from(e: ICalError) -> Self {
+        VCardError::Parse(e)
+    }
+}
+
+/// Property name without its vCard group (`item1.EMAIL` is `EMAIL`).
+pub fn base_name(property: &Property) -> &str {
+    property
+        .name
+        .rsplit_once('.')
+        .map_or(property.name.as_str(), |(_, name)| name)
+}
+
+/// Lower-cased `TYPE` values of a property, however they are written
+/// (`TYPE=work,voice` or repeated `TYPE` parameters).
+fn types(property: &Property) -> Vec<String> {
+    property
+        .params
+        .iter()
+        .filter(|p| p.name == "TYPE")
+        .flat_map(|p| &p.values)
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// vCard 3 marks preference as `TYPE=pref`, vCard 4 with `PREF=1..100`.
+fn is_preferred(property: &Property) -> bool {
+    property.param("PREF").is_some() || types(property).iter().any(|t| t == "pref")
+}
+
+/// `TYPE` values other than the preference and the vCard 3 `internet`
+/// email type, which carry no meaning.
+fn kind_types(property: &Property) -> Vec<String> {
+    types(property)
+        .into_iter()
+        .filter(|t| t != "pref" && t != "internet")
+        .collect()
+}
+
+fn structured(property: &Property) -> Vec<String> {
+    split_unescaped(&property.value, ';')
+        .into_iter()
+        .map(unescape_text)
+        .collect()
+}
+
+fn parse_birthday(value: &str) -> Option<NaiveDate> {
+    let date = value.split('T').next()?.replace('-', "");
+    NaiveDate::parse_from_str(&date, "%Y%m%d").ok()
+}
+
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let basic: String = value.chars().filter(|c| *c != '-' && *c != ':').collect();
+    match DateTimeValue::parse_value(&basic, None) {
+        Some(DateTimeValue::Utc(time)) => Some(time),
+        _ => None,
+    }
+}
+
+fn typed(
+    mut property: Property,
+    types: &[String],
+    preferred: bool,
+    version: VCardVersion,
+) -> Property {
+    let mut values = types.to_vec();
+    if preferred {
+        match version {
+            VCardVersion::V3 => values.push("pref".to_string()),
+            VCardVersion::V4 => property.set_param("PREF", "1"),
+        }
+    }
+    if !values.is_empty() {
+        property.params.push(Parameter {
+            name: "TYPE".to_string(),
+            values,
+        });
+    }
+    property
+}
+
+fn structured_property(name: &str, parts: &[&str]) -> Property {
+    let value: Vec<String> = parts.iter().map(|part| escape_text(part)).collect();
+    Property::new(name, value.join(";"))
+}
+
+/// A parsed `VCARD`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VCard {
+    pub root: Component,
+}
+
+impl VCard {
+    /// Parse a stream containing exactly one vCard.
+    pub fn parse(input: &str) -> Result<Self, VCardError> {
+        let mut cards = Self::parse_all(input)?;
+        match cards.len() {
+            1 => Ok(cards.remove(0)),
+            0 => Err(VCardError::Structure("no VCARD found".to_string())),
+            n => Err(VCardError::Structure(format!(
+                "expected one VCARD, found {}",
+                n
+            ))),
+        }
+    }
+
+    /// Parse every vCard in a stream, e.g. an address book export.
+    pub fn parse_all(input: &str) -> Result<Vec<Self>, VCardError> {
+        parse_components(input)?
+            .into_iter()
+            .map(|root| {
+                if !root.name.eq_ignore_ascii_case("VCARD") {
+                    return Err(VCardError::Structure(format!(
+                        "expected VCARD, found {}",
+                        root.name
+                    )));
+                }
+                let card = Self { root };
+                card.validate()?;
+                Ok(card)
+            })
+            .collect()
+    }
+
+    /// Check the properties every stored card must have: a supported
+    /// `VERSION`, `FN` and `UID`.
+    fn validate(&self) -> Result<(), VCardError> {
+        let version = self
+            .property("VERSION")
+            .ok_or_else(|| VCardError::MissingProperty("VERSION".to_string()))?;
+        if VCardVersion::parse(&version.value).is_none() {
+            return Err(VCardError::UnsupportedVersion(version.value.clone()));
+        }
+        for name in ["FN", "UID"] {
+            if self.property(name).is_none() {
+                return Err(VCardError::MissingProperty(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn version(&self) -> VCardVersion {
+        self.property("VERSION")
+            .and_then(|p| VCardVersion::parse(&p.value))
+            .unwrap_or(VCardVersion::V3)
+    }
+
+    pub fn uid(&self) -> Option<String> {
+        self.property("UID").map(|p| p.value.clone())
+    }
+
+    /// First property called `name`, ignoring groups.
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.root
+            .properties
+            .iter()
+            .find(|p| base_name(p).eq_ignore_ascii_case(name))
+    }
+
+    /// Properties called `name`, ignoring groups.
+    pub fn properties_named<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Property> + 'a {
+        self.root
+            .properties
+            .iter()
+            .filter(move |p| base_name(p).eq_ignore_ascii_case(name))
+    }
+
+    pub fn to_vcf(&self) -> String {
+        self.root.to_ics()
+    }
+
+    pub fn contact(&self) -> Contact {
+        let text = |name: &str| self.property(name).map(Property::text_value);
+        let name = self.property("N").map(|property| {
+            let mut parts = structured(property).into_iter();
+            let mut next = || parts.next().unwrap_or_default();
+            StructuredName {
+                family: next(),
+                given: next(),
+                additional: next(),
+                prefixes: next(),
+                suffixes: next(),
+            }
+        });
+        let addresses = self
+            .properties_named("ADR")
+            .map(|property| {
+                let mut parts = structured(property).into_iter();
+                let mut next = || parts.next().unwrap_or_default();
+                PostalAddress {
+                    po_box: next(),
+                    extended: next(),
+                    street: next(),
+                    locality: next(),
+                    region: next(),
+                    postal_code: next(),
+                    country: next(),
+                    types: kind_types(property),
+                }
+            })
+            .collect();
+
+        Contact {
+            uid: self.uid().unwrap_or_default(),
+            formatted_name: text("FN").unwrap_or_default(),
+            name,
+            nickname: text("NICKNAME"),
+            emails: self
+                .properties_named("EMAIL")
+                .map(|property| ContactEmail {
+                    address: property.text_value(),
+                    types: kind_types(property),
+                    preferred: is_preferred(property),
+                })
+                .collect(),
+            phones: self
+                .properties_named("TEL")
+                .map(|property| ContactPhone {
+                    number: property
+                        .value
+                        .strip_prefix("tel:")
+                        .unwrap_or(&property.value)
+                        .to_string(),
+                    types: kind_types(property),
+                    preferred: is_preferred(property),
+                })
+                .collect(),
+            addresses,
+            organization: self
+                .property("ORG")
+                .and_then(|property| structured(property).into_iter().next())
+                .filter(|org| !org.is_empty()),
+            title: text("TITLE"),
+            birthday: self.property("BDAY").and_then(|p| parse_birthday(&p.value)),
+            note: text("NOTE"),
+            urls: self
+                .properties_named("URL")
+                .map(|p| p.value.clone())
+                .collect(),
+            categories: self
+                .properties_named("CATEGORIES")
+                .flat_map(|p| {
+                    split_unescaped(&p.value, ',')
+                        .into_iter()
+                        .map(unescape_text)
+                })
+                .collect(),
+            revision: self.property("REV").and_then(|p| parse_timestamp(&p.value)),
+        }
+    }
+
+    /// Write `contact` as a new card in `version`.
+    pub fn from_contact(contact: &Contact, version: VCardVersion) -> Self {
+        let mut root = Component::new("VCARD");
+        root.push_property(Property::new("VERSION", version.as_str()));
+        root.push_property(Property::new("UID", contact.uid.clone()));
+        root.push_property(Property::text("FN", &contact.formatted_name));
+        // N is required in vCard 3.
+        match (&contact.name, version) {
+            (Some(name), _) => root.push_property(structured_property(
+                "N",
+                &[
+                    &name.family,
+                    &name.given,
+                    &name.additional,
+                    &name.prefixes,
+                    &name.suffixes,
+                ],
+            )),
+            (None, VCardVersion::V3) => root.push_property(Property::new("N", ";;;;")),
+            (None, VCardVersion::V4) => {}
+        }
+        if let Some(nickname) = &contact.nickname {
+            root.push_property(Property::text("NICKNAME", nickname));
+        }
+        for email in &contact.emails {
+            let mut types = email.types.clone();
+            if version == VCardVersion::V3 {
+                types.insert(0, "internet".to_string());
+            }
+            root.push_property(typed(
+                Property::text("EMAIL", &email.address),
+                &types,
+                email.preferred,
+                version,
+            ));
+        }
+        for phone in &contact.phones {
+            let property = match version {
+                VCardVersion::V3 => Property::new("TEL", phone.number.clone()),
+                VCardVersion::V4 => {
+                    Property::new("TEL", format!("tel:{}", phone.number)).with_param("VALUE", "uri")
+                }
+            };
+            root.push_property(typed(property, &phone.types, phone.preferred, version));
+        }
+        for address in &contact.addresses {
+            let property = structured_property(
+                "ADR",
+                &[
+                    &address.po_box,
+                    &address.extended,
+                    &address.street,
+                    &address.locality,
+                    &address.region,
+                    &address.postal_code,
+                    &address.country,
+                ],
+            );
+            root.push_property(typed(property, &address.types, false, version));
+        }
+        if let Some(organization) = &contact.organization {
+            root.push_property(Property::text("ORG", organization));
+        }
+        if let Some(title) = &contact.title {
+            root.push_property(Property::text("TITLE", title));
+        }
+        if let Some(birthday) = contact.birthday {
+            let format = match version {
+                VCardVersion::V3 => "%Y-%m-%d",
+                VCardVersion::V4 => "%Y%m%d",
+            };
+            root.push_property(Property::new("BDAY", birthday.format(format).to_string()));
+        }
+        if let Some(note) = &contact.note {
+            root.push_property(Property::text("NOTE", note));
+        }
+        for url in &contact.urls {
+            root.push_property(Property::new("URL", url.clone()));
+        }
+        if !contact.categories.is_empty() {
+            let categories: Vec<String> =
+                contact.categories.iter().map(|c| escape_text(c)).collect();
+            root.push_property(Property::new("CATEGORIES", categories.join(",")));
+        }
+        if let Some(revision) = contact.revision {
+            root.push_property(Property::new(
+                "REV",
+                revision.format("%Y%m%dT%H%M%SZ").to_string(),
+            ));
+        }
+        Self { root }
+    }
+
+    /// This card translated to `version`. Properties that only differ in
+    /// syntax are rewritten; everything else is kept as is.
+    pub fn convert(&self, version: VCardVersion) -> Self {
+        if self.version() == version {
+            return self.clone();
+        }
+        let mut root = self.root.clone();
+        for property in &mut root.properties {
+            let preferred = is_preferred(property);
+            let name = base_name(property).to_ascii_uppercase();
+            if name == "VERSION" {
+                property.value = version.as_str().to_string();
+                continue;
+            }
+            if preferred {
+                property.remove_param("PREF");
+                for param in property.params.iter_mut().filter(|p| p.name == "TYPE") {
+                    param.values = param
+                        .values
+                        .iter()
+                        .flat_map(|v| v.split(','))
+                        .filter(|v| !v.eq_ignore_ascii_case("pref"))
+                        .map(str::to_string)
+                        .collect();
+                }
+                property.params.retain(|p| !p.values.is_empty());
+                let types = types(property);
+                property.params.retain(|p| p.name != "TYPE");
+                *property = typed(property.clone(), &types, true, version);
+            }
+            match (name.as_str(), version) {
+                ("TEL", VCardVersion::V3) => {
+                    if let Some(number) = property.value.strip_prefix("tel:") {
+                        property.value = number.to_string();
+                    }
+                    property.remove_param("VALUE");
+                }
+                ("TEL", VCardVersion::V4) if property.param("VALUE").is_none() => {
+                    property.value = format!("tel:{}", property.value);
+                    property.set_param("VALUE", "uri");
+                }
+                ("BDAY", _) => {
+                    if let Some(date) = parse_birthday(&property.value) {
+                        property.value = match version {
+                            VCardVersion::V3 => date.format("%Y-%m-%d").to_string(),
+                            VCardVersion::V4 => date.format("%Y%m%d").to_string(),
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+        if version == VCardVersion::V3 && !root.properties.iter().any(|p| base_name(p) == "N") {
+            root.push_property(Property::new("N", ";;;;"));
+        }
+        Self { root }
+    }
+
+    /// A copy holding only the named properties, for `address-data`
+    /// requests that list them. `VERSION` and `UID` are always kept.
+    pub fn select(&self, names: &[String]) -> Self {
+        let mut root = Component::new("VCARD");
+        root.properties = self
+            .root
+            .properties
+            .iter()
+            .filter(|p| {
+                let name = base_name(p);
+                name == "VERSION"
+                    || name == "UID"
+                    || names.iter().any(|n| n.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+            .collect();
+        Self { root }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPLE_V3: &str = "BEGIN:VCARD\r\n\
+VERSION:3.0\r\n\
+PRODID:-//Apple Inc.//iPhone OS 17.0//EN\r\n\
+N:Durand;Camille;;Dr.;\r\n\
+FN:Dr. Camille Durand\r\n\
+ORG:Rouille SARL;Engineering;\r\n\
+TITLE:Lead\\, platform\r\n\
+item1.EMAIL;type=INTERNET;type=WORK;type=pref:camille@example.com\r\n\
+item1.X-ABLabel:_$!<Work>!$_\r\n\
+EMAIL;type=INTERNET;type=HOME:camille.d@example.net\r\n\
+TEL;type=CELL;type=VOICE;type=pref:+33 6 12 34 56 78\r\n\
+ADR;type=WORK:;;12 rue de la Paix;Paris;;75002;France\r\n\
+BDAY:1990-04-12\r\n\
+NOTE:Met at RustConf\\nLikes \\;semicolons\r\n\
+CATEGORIES:Team,Friends\r\n\
+REV:2024-01-02T03:04:05Z\r\n\
+UID:9C8B6F2A-1111-2222-3333-444455556666\r\n\
+END:VCARD\r\n";
+
+    #[test]
+    fn test_parses_apple_vcard_3() {
+        let card = VCard::parse(APPLE_V3).unwrap();
+        assert_eq!(card.version(), VCardVersion::V3);
+        let contact = card.contact();
+        assert_eq!(contact.formatted_name, "Dr. Camille Durand");
+        let name = contact.name.as_ref().unwrap();
+        assert_eq!(
+            (name.family.as_str(), name.given.as_str()),
+            ("Durand", "Camille")
+        );
+        assert_eq!(name.prefixes, "Dr.");
+        assert_eq!(contact.organization.as_deref(), Some("Rouille SARL"));
+        assert_eq!(contact.title.as_deref(), Some("Lead, platform"));
+        assert_eq!(contact.emails.len(), 2);
+        assert_eq!(contact.emails[0].address, "camille@example.com");
+        assert_eq!(contact.emails[0].types, vec!["work"]);
+        assert!(contact.emails[0].preferred);
+        assert!(!contact.emails[1].preferred);
+        assert_eq!(contact.phones[0].types, vec!["cell", "voice"]);
+        assert_eq!(contact.addresses[0].street, "12 rue de la Paix");
+        assert_eq!(contact.addresses[0].country, "France");
+        assert_eq!(contact.birthday, NaiveDate::from_ymd_opt(1990, 4, 12));
+        assert_eq!(
+            contact.note.as_deref(),
+            Some("Met at RustConf\nLikes ;semicolons")
+        );
+        assert_eq!(contact.categories, vec!["Team", "Friends"]);
+        assert!(contact.revision.is_some());
+
+        // Grouped properties survive a round trip.
+        let written = card.to_vcf();
+        assert!(written.contains("ITEM1.X-ABLABEL:_$!<Work>!$_\r\n"));
+        assert_eq!(VCard::parse(&written).unwrap().contact(), contact);
+    }
+
+    #[test]
+    fn test_parses_vcard_4() {
+        let card = VCard::parse(
+            "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:urn:uuid:4fbe8971-0bc3-424c-9c26-36c3e1eff6b1\r\n\
+             FN:Ada Lovelace\r\nEMAIL;TYPE=work;PREF=1:ada@example.org\r\n\
+             TEL;VALUE=uri;TYPE=\"voice,home\":tel:+44-20-7946-0000\r\nBDAY:18151210\r\nEND:VCARD\r\n",
+        )
+        .unwrap();
+        assert_eq!(card.version(), VCardVersion::V4);
+        let contact = card.contact();
+        assert_eq!(contact.uid, "urn:uuid:4fbe8971-0bc3-424c-9c26-36c3e1eff6b1");
+        assert!(contact.name.is_none());
+        assert!(contact.emails[0].preferred);
+        assert_eq!(contact.phones[0].number, "+44-20-7946-0000");
+        assert_eq!(contact.phones[0].types, vec!["voice", "home"]);
+        assert_eq!(contact.birthday, NaiveDate::from_ymd_opt(1815, 12, 10));
+    }
+
+    #[test]
+    fn test_from_contact_round_trips_in_both_versions() {
+        let contact = VCard::parse(APPLE_V3).unwrap().contact();
+        for version in [VCardVersion::V3, VCardVersion::V4] {
+            let card = VCard::from_contact(&contact, version);
+            let parsed = VCard::parse(&card.to_vcf()).unwrap();
+            assert_eq!(parsed.version(), version);
+            assert_eq!(parsed.contact(), contact);
+        }
+        let v4 = VCard::from_contact(&contact, VCardVersion::V4).to_vcf();
+        assert!(v4.contains("EMAIL;PREF=1;TYPE=work:camille@example.com\r\n"));
+        assert!(v4.contains("TEL;VALUE=uri;PREF=1;TYPE=cell,voice:tel:+33 6 12 34 56 78\r\n"));
+        let v3 = VCard::from_contact(&contact, VCardVersion::V3).to_vcf();
+        assert!(v3.contains("EMAIL;TYPE=internet,work,pref:camille@example.com\r\n"));
+    }
+
+    #[test]
+    fn test_converts_between_versions() {
+        let card = VCard::parse(APPLE_V3).unwrap();
+        let v4 = card.convert(VCardVersion::V4);
+        assert_eq!(v4.version(), VCardVersion::V4);
+        let tel = v4.property("TEL").unwrap();
+        assert_eq!(tel.value, "tel:+33 6 12 34 56 78");
+        assert_eq!(tel.param("PREF"), Some("1"));
+        assert!(!types(tel).contains(&"pref".to_string()));
+        assert_eq!(v4.property("BDAY").unwrap().value, "19900412");
+        // Unmodelled properties are kept.
+        assert!(v4.property("X-ABLabel").is_some());
+        assert_eq!(v4.contact(), card.contact());
+
+        let back = v4.convert(VCardVersion::V3);
+        assert_eq!(back.property("TEL").unwrap().value, "+33 6 12 34 56 78");
+        assert_eq!(back.contact(), card.contact());
+    }
+
+    #[test]
+    fn test_selects_properties() {
+        let card = VCard::parse(APPLE_V3).unwrap();
+        let selected = card.select(&["FN".to_string(), "EMAIL".to_string()]);
+        let names: Vec<&str> = selected.root.properties.iter().map(base_name).collect();
+        assert_eq!(names, vec!["VERSION", "FN", "EMAIL", "EMAIL", "UID"]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_cards() {
+        assert_eq!(
+            VCard::parse("BEGIN:VCARD\r\nVERSION:4.0\r\nUID:x\r\nEND:VCARD\r\n"),
+            Err(VCardError::MissingProperty("FN".to_string()))
+        );
+        assert_eq!(
+            VCard::parse("BEGIN:VCARD\r\nVERSION:2.1\r\nFN:x\r\nUID:x\r\nEND:VCARD\r\n"),
+            Err(VCardError::UnsupportedVersion("2.1".to_string()))
+        );
+        assert!(matches!(
+            VCard::parse("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"),
+            Err(VCardError::Structure(_))
+        ));
+        let two = "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:a\r\nUID:a\r\nEND:VCARD\r\n\
+                   BEGIN:VCARD\r\nVERSION:4.0\r\nFN:b\r\nUID:b\r\nEND:VCARD\r\n";
+        assert!(matches!(VCard::parse(two), Err(VCardError::Structure(_))));
+        assert_eq!(VCard::parse_all(two).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,118 @@
+//! Applying deltas, streaming and in place, and diffing small files.
+//!
+//! Run with `cargo bench -p sync-core --bench delta_apply`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::io::Cursor;
+use sync_core::{
+    DeltaApplier, DeltaOperation, DiffAlgorithm, FileDelta, SmallFileDelta, SyncOptions,
+};
+
+const FILE_SIZE: usize = 16 << 20;
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+fn content(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64 ^ seed;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"abcdefghijklmnopqrstuvwxyz \n"[(state % 28) as usize]
+        })
+        .collect()
+}
+
+/// A delta that moves every other block to the end of the file and
+/// rewrites the first block, as large copies interleaved with inserts.
+fn shuffle_delta() -> FileDelta {
+    let blocks = FILE_SIZE as u64 / BLOCK_SIZE;
+    let order: Vec<u64> = (0..blocks)
+        .filter(|b| b % 2 == 1)
+        .chain((0..blocks).filter(|b| b % 2 == 0))
+        .collect();
+    let operations = order
+        .iter()
+        .enumerate()
+        .map(|(position, &block)| {
+            let target_offset = position as u64 * BLOCK_SIZE;
+            if position == 0 {
+                DeltaOperation::Insert {
+                    target_offset,
+                    data: content(BLOCK_SIZE as usize, 7),
+                }
+            } else {
+                DeltaOperation::Copy {
+                    source_offset: block * BLOCK_SIZE,
+                    target_offset,
+                    length: BLOCK_SIZE,
+                }
+            }
+        })
+        .collect();
+    FileDelta {
+        file_id: String::new(),
+        source_checksum: String::new(),
+        target_checksum: String::new(),
+        operations,
+        compressed_size: None,
+    }
+}
+
+fn apply(c: &mut Criterion) {
+    let source = content(FILE_SIZE, 0);
+    let delta = shuffle_delta();
+    let applier = DeltaApplier::new(SyncOptions::default());
+
+    let mut group = c.benchmark_group("delta_apply");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(20);
+    group.bench_function("streaming", |b| {
+        let mut target = Vec::with_capacity(FILE_SIZE);
+        b.iter(|| {
+            target.clear();
+            applier
+                .apply_delta(Cursor::new(&source), &mut target, black_box(&delta))
+                .unwrap();
+        })
+    });
+    group.bench_function("in_place", |b| {
+        b.iter_batched(
+            || Cursor::new(source.clone()),
+            |mut file| {
+                applier
+                    .apply_in_place(&mut file, black_box(&delta))
+                    .unwrap()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn small_files(c: &mut Criterion) {
+    let source = content(256 * 1024, 1);
+    let mut target = source.clone();
+    for at in (0..target.len()).step_by(16 * 1024) {
+        target[at..at + 32].copy_from_slice(&[b'#'; 32]);
+    }
+
+    let mut group = c.benchmark_group("small_file_delta");
+    group.throughput(Throughput::Bytes(source.len() as u64));
+    group.sample_size(20);
+    for (name, algorithm) in [
+        ("myers", DiffAlgorithm::Myers),
+        ("patience", DiffAlgorithm::Patience),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                SmallFileDelta::generate_with(Cursor::new(&source), Cursor::new(&target), algorithm)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, apply, small_files);
+criterion_main!(benches);
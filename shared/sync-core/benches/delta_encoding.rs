@@ -0,0 +1,98 @@
+//! Wire size and speed of the binary delta encoding against JSON.
+//!
+//! Run with `cargo bench -p sync-core --bench delta_encoding`; the sizes
+//! are printed before the timings.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::io::Cursor;
+use sync_core::{DeltaGenerator, FileDelta, FileSignature, SyncOptions, DELTA_COMPRESSION_LEVEL};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Text-like content, deterministic so sizes are comparable between runs.
+fn content(len: usize, seed: u64) -> Vec<u8> {
+    let words = [
+        "sync ", "delta ", "block ", "file ", "server ", "device ", "version ", "cloud\n",
+    ];
+    let mut state = 0x2545_f491_4f6c_dd1du64 ^ seed;
+    let mut out = Vec::with_capacity(len + 16);
+    while out.len() < len {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        out.extend_from_slice(words[(state % words.len() as u64) as usize].as_bytes());
+    }
+    out.truncate(len);
+    out
+}
+
+/// A 4 MiB file with a few scattered edits and 300 KB appended, as a delta
+/// against the original.
+fn sample_delta() -> FileDelta {
+    let source = content(4 << 20, 0);
+    let mut target = source.clone();
+    for at in [10_000, 900_000, 2_000_000, 3_500_000] {
+        target[at..at + 64].copy_from_slice(&[b'#'; 64]);
+    }
+    target.extend_from_slice(&content(300_000, 1));
+
+    let signature = FileSignature::compute(&mut Cursor::new(&source), BLOCK_SIZE).unwrap();
+    let options = SyncOptions {
+        chunk_size: BLOCK_SIZE,
+        ..SyncOptions::default()
+    };
+    DeltaGenerator::new(options)
+        .generate_delta(
+            Cursor::new(&source),
+            Cursor::new(&target),
+            &signature.blocks,
+        )
+        .unwrap()
+}
+
+fn encoding(c: &mut Criterion) {
+    let delta = sample_delta();
+    let json = serde_json::to_vec(&delta).unwrap();
+    let binary = delta.encode(None).unwrap();
+    let compressed = delta.encode(Some(DELTA_COMPRESSION_LEVEL)).unwrap();
+    println!(
+        "delta wire size: json {} bytes, binary {} bytes ({:.1}x smaller), \
+         binary+zstd {} bytes ({:.1}x smaller)",
+        json.len(),
+        binary.len(),
+        json.len() as f64 / binary.len() as f64,
+        compressed.len(),
+        json.len() as f64 / compressed.len() as f64,
+    );
+
+    let mut group = c.benchmark_group("delta_encode");
+    group.bench_function("json", |b| {
+        b.iter(|| serde_json::to_vec(black_box(&delta)).unwrap())
+    });
+    group.bench_function("binary", |b| {
+        b.iter(|| black_box(&delta).encode(None).unwrap())
+    });
+    group.bench_function("binary_zstd", |b| {
+        b.iter(|| {
+            black_box(&delta)
+                .encode(Some(DELTA_COMPRESSION_LEVEL))
+                .unwrap()
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("delta_decode");
+    group.bench_function("json", |b| {
+        b.iter(|| serde_json::from_slice::<FileDelta>(black_box(&json)).unwrap())
+    });
+    group.bench_function("binary", |b| {
+        b.iter(|| FileDelta::decode(black_box(&binary), u64::MAX).unwrap())
+    });
+    group.bench_function("binary_zstd", |b| {
+        b.iter(|| FileDelta::decode(black_box(&compressed), u64::MAX).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
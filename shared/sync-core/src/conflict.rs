@@ -0,0 +1,719 @@
+//! Conflict detection and resolution.
+//!
+//! A conflict exists only when both sides changed a file concurrently: the
+//! version vectors tell concurrent edits apart from one side simply being
+//! behind, and the base state tells which kind of edit each side made.
+//! Conflicts are resolved either automatically by the configured
+//! [`ConflictResolutionMode`] or by an explicit [`ConflictResolution`].
+
+use crate::{ConflictResolutionMode, SyncAction, SyncError, SyncState};
+use chrono::{DateTime, Utc};
+use protocol::sync::{
+    ConflictResolution, ConflictType, FileState, ResolutionStrategy, SyncConflict, SyncStatus,
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Per-device edit counters of one file. Each device increments its own
+/// counter when it changes the file and merges the other side's vector when
+/// it receives a version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+/// How two version vectors relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// The other version includes every change of this one.
+    Before,
+    /// This version includes every change of the other one.
+    After,
+    /// Each side has changes the other lacks.
+    Concurrent,
+}
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, device: &str) -> u64 {
+        self.0.get(device).copied().unwrap_or(0)
+    }
+
+    /// Record a change made on `device`.
+    pub fn increment(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_insert(0) += 1;
+    }
+
+    /// Take in every change known to `other`.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, &counter) in &other.0 {
+            let entry = self.0.entry(device.clone()).or_insert(0);
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut behind = false;
+        let mut ahead = false;
+        for device in self.0.keys().chain(other.0.keys()) {
+            match self.get(device).cmp(&other.get(device)) {
+                Ordering::Less => behind = true,
+                Ordering::Greater => ahead = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (behind, ahead) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+/// One side's version of a file. `state.path` is where the file is now on
+/// that side, so a move shows up as a path different from the base's, and a
+/// deletion as [`SyncStatus::Deleted`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub state: FileState,
+    pub vector: VersionVector,
+}
+
+pub fn is_deleted(state: &FileState) -> bool {
+    state.sync_status == SyncStatus::Deleted
+}
+
+/// What one side did to a file since the base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Unchanged,
+    Modified,
+    Moved,
+    Deleted,
+}
+
+impl Edit {
+    fn of(base: Option<&FileState>, state: &FileState) -> Self {
+        match base {
+            _ if is_deleted(state) => Edit::Deleted,
+            None => Edit::Modified,
+            Some(base) if base.path != state.path => Edit::Moved,
+            Some(base) if base.checksum != state.checksum => Edit::Modified,
+            Some(_) => Edit::Unchanged,
+        }
+    }
+}
+
+/// Whether `local` and `remote` conflict, and how.
+///
+/// Versions where one vector descends from the other never conflict; the
+/// newer one simply replaces the older. Without version information (both
+/// vectors empty) the revisions are taken to be concurrent and only the base
+/// decides. Compatible concurrent edits are not conflicts either: identical
+/// results, deletions on both sides, and a pure move on one side combined
+/// with an edit on the other.
+pub fn detect_conflict(
+    base: Option<&FileState>,
+    local: &Revision,
+    remote: &Revision,
+) -> Option<SyncConflict> {
+    let unversioned = local.vector.is_empty() && remote.vector.is_empty();
+    if !unversioned && local.vector.compare(&remote.vector) != Causality::Concurrent {
+        return None;
+    }
+
+    let (l, r) = (&local.state, &remote.state);
+    let content_unchanged = |state: &FileState| base.is_some_and(|b| b.checksum == state.checksum);
+    let conflict_type = match (Edit::of(base, l), Edit::of(base, r)) {
+        (Edit::Unchanged, _) | (_, Edit::Unchanged) | (Edit::Deleted, Edit::Deleted) => {
+            return None
+        }
+        (Edit::Deleted, _) => ConflictType::DeleteModify,
+        (_, Edit::Deleted) => ConflictType::ModifyDelete,
+        _ if l.path == r.path && l.checksum == r.checksum => return None,
+        (Edit::Moved, Edit::Moved) if l.path != r.path => ConflictType::MoveMove,
+        (Edit::Moved, Edit::Modified) if content_unchanged(l) => return None,
+        (Edit::Modified, Edit::Moved) if content_unchanged(r) => return None,
+        _ => ConflictType::ModifyModify,
+    };
+    Some(SyncConflict {
+        path: base.map_or_else(|| l.path.clone(), |b| b.path.clone()),
+        conflict_type,
+        local_version: l.clone(),
+        remote_version: r.clone(),
+        base_version: base.cloned(),
+    })
+}
+
+fn file_state(path: &str, state: Option<&SyncState>, base: Option<&SyncState>) -> FileState {
+    match state {
+        Some(state) => FileState {
+            path: state.path.clone(),
+            checksum: state.hash.clone(),
+            size: state.size,
+            modified_at: state.modified,
+            version: 0,
+            sync_status: SyncStatus::Modified,
+        },
+        // A deletion carries no time of its own; dating it at the base
+        // makes any later edit the newer version.
+        None => FileState {
+            path: path.to_string(),
+            checksum: base.map(|b| b.hash.clone()).unwrap_or_default(),
+            size: 0,
+            modified_at: base.map(|b| b.modified).unwrap_or_default(),
+            version: 0,
+            sync_status: SyncStatus::Deleted,
+        },
+    }
+}
+
+/// The conflict reported by a [`SyncAction::Conflict`] of the engine.
+pub fn conflict_from_action(action: &SyncAction) -> Option<SyncConflict> {
+    let SyncAction::Conflict {
+        path,
+        conflict_type,
+        local,
+        remote,
+        base,
+    } = action
+    else {
+        return None;
+    };
+    Some(SyncConflict {
+        path: path.clone(),
+        conflict_type: *conflict_type,
+        local_version: file_state(path, local.as_ref(), base.as_ref()),
+        remote_version: file_state(path, remote.as_ref(), base.as_ref()),
+        base_version: base.as_ref().map(|b| file_state(path, Some(b), None)),
+    })
+}
+
+/// Name for a copy of `path` saved by `device` at `at`, e.g.
+/// `notes/todo (conflicted copy from laptop 2024-03-01 142501).txt`.
+pub fn conflict_copy_name(path: &str, device: &str, at: DateTime<Utc>) -> String {
+    let (dir, name) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+    let (stem, extension) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let device: String = device
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    let device = if device.is_empty() {
+        "unknown device"
+    } else {
+        &device
+    };
+    format!(
+        "{}{} (conflicted copy from {} {}){}",
+        dir,
+        stem,
+        device,
+        at.format("%Y-%m-%d %H%M%S"),
+        extension
+    )
+}
+
+/// Which version a resolution keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Local,
+    Remote,
+    Base,
+}
+
+/// What has to happen to settle a conflict.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Make both sides hold `state`, or delete the file when `state` is a
+    /// deletion. `state.path` is where the file ends up, which differs from
+    /// `path` when the kept version was moved.
+    Take {
+        path: String,
+        side: Side,
+        state: FileState,
+    },
+    /// Keep the remote version and save the local one as `copy`.
+    KeepBoth {
+        path: String,
+        remote: FileState,
+        local: FileState,
+        copy: String,
+    },
+    /// Merge the contents of both versions against the base.
+    Merge {
+        path: String,
+        base: Option<FileState>,
+        local: FileState,
+        remote: FileState,
+    },
+    /// Replace the file on both sides with `content`.
+    Replace { path: String, content: Vec<u8> },
+}
+
+impl Outcome {
+    pub fn path(&self) -> &str {
+        match self {
+            Outcome::Take { path, .. }
+            | Outcome::KeepBoth { path, .. }
+            | Outcome::Merge { path, .. }
+            | Outcome::Replace { path, .. } => path,
+        }
+    }
+}
+
+/// Resolve `conflict` as `resolution` says.
+///
+/// A `Rename` name without a `/` is taken relative to the conflicting
+/// file's directory. Renaming when the local side deleted the file leaves
+/// nothing to copy, so the remote version is kept.
+pub fn apply_resolution(
+    conflict: &SyncConflict,
+    resolution: &ConflictResolution,
+) -> Result<Outcome, SyncError> {
+    if resolution.path != conflict.path {
+        return Err(SyncError::Conflict(format!(
+            "resolution for {} does not match conflict on {}",
+            resolution.path, conflict.path
+        )));
+    }
+    let path = conflict.path.clone();
+    let local = &conflict.local_version;
+    let remote = &conflict.remote_version;
+    let take = |side: Side, state: &FileState| Outcome::Take {
+        path: path.clone(),
+        side,
+        state: state.clone(),
+    };
+    Ok(match &resolution.resolution {
+        ResolutionStrategy::UseLocal => take(Side::Local, local),
+        ResolutionStrategy::UseRemote => take(Side::Remote, remote),
+        ResolutionStrategy::UseBase => match &conflict.base_version {
+            Some(base) => take(Side::Base, base),
+            None => {
+                return Err(SyncError::Conflict(format!(
+                    "{} has no base version to restore",
+                    path
+                )))
+            }
+        },
+        ResolutionStrategy::Merge => {
+            if is_deleted(local) || is_deleted(remote) {
+                return Err(SyncError::Conflict(format!(
+                    "{} was deleted on one side and cannot be merged",
+                    path
+                )));
+            }
+            Outcome::Merge {
+                path: path.clone(),
+                base: conflict.base_version.clone(),
+                local: local.clone(),
+                remote: remote.clone(),
+            }
+        }
+        ResolutionStrategy::Rename { .. } if is_deleted(local) => take(Side::Remote, remote),
+        ResolutionStrategy::Rename { new_name } => {
+            let copy = sibling(&local.path, new_name)?;
+            if copy == remote.path || copy == local.path {
+                return Err(SyncError::Conflict(format!(
+                    "conflict copy of {} would replace {}",
+                    path, copy
+                )));
+            }
+            Outcome::KeepBoth {
+                path: path.clone(),
+                remote: remote.clone(),
+                local: local.clone(),
+                copy,
+            }
+        }
+        ResolutionStrategy::Custom { content } => Outcome::Replace {
+            path: path.clone(),
+            content: content.clone(),
+        },
+    })
+}
+
+fn sibling(path: &str, name: &str) -> Result<String, SyncError> {
+    let invalid = name.is_empty()
+        || name.starts_with('/')
+        || name
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..");
+    if invalid {
+        return Err(SyncError::Conflict(format!(
+            "invalid conflict copy name: {:?}",
+            name
+        )));
+    }
+    Ok(match path.rfind('/') {
+        Some(i) if !name.contains('/') => format!("{}{}", &path[..=i], name),
+        _ => name.to_string(),
+    })
+}
+
+/// Resolves conflicts automatically according to a
+/// [`ConflictResolutionMode`].
+#[derive(Debug, Clone)]
+pub struct ConflictResolver {
+    mode: ConflictResolutionMode,
+    device_name: String,
+}
+
+impl ConflictResolver {
+    /// `device_name` names the conflict copies this device creates.
+    pub fn new(mode: ConflictResolutionMode, device_name: impl Into<String>) -> Self {
+        Self {
+            mode,
+            device_name: device_name.into(),
+        }
+    }
+
+    /// The strategy the mode picks for `conflict`, or `None` when the user
+    /// has to decide. `Timestamp` keeps the newer and `Size` the larger
+    /// version, counting a deletion as empty; on a tie both versions are
+    /// kept with the local one as a conflict copy.
+    pub fn strategy(
+        &self,
+        conflict: &SyncConflict,
+        now: DateTime<Utc>,
+    ) -> Option<ResolutionStrategy> {
+        let local = &conflict.local_version;
+        let remote = &conflict.remote_version;
+        let size = |state: &FileState| if is_deleted(state) { 0 } else { state.size };
+        let order = match self.mode {
+            ConflictResolutionMode::Manual => return None,
+            ConflictResolutionMode::LocalWins => Ordering::Greater,
+            ConflictResolutionMode::RemoteWins => Ordering::Less,
+            ConflictResolutionMode::Timestamp => local.modified_at.cmp(&remote.modified_at),
+            ConflictResolutionMode::Size => size(local).cmp(&size(remote)),
+        };
+        Some(match order {
+            Ordering::Greater => ResolutionStrategy::UseLocal,
+            Ordering::Less => ResolutionStrategy::UseRemote,
+            Ordering::Equal => ResolutionStrategy::Rename {
+                new_name: conflict_copy_name(&local.path, &self.device_name, now),
+            },
+        })
+    }
+
+    /// Resolve `conflict` automatically; `None` in manual mode.
+    pub fn resolve(
+        &self,
+        conflict: &SyncConflict,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Outcome>, SyncError> {
+        let Some(strategy) = self.strategy(conflict, now) else {
+            return Ok(None);
+        };
+        let resolution = ConflictResolution {
+            path: conflict.path.clone(),
+            resolution: strategy,
+            resolved_metadata: None,
+        };
+        apply_resolution(conflict, &resolution).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 1, hour, 0, 0).unwrap()
+    }
+
+    fn state(path: &str, checksum: &str, hour: u32) -> FileState {
+        FileState {
+            path: path.to_string(),
+            checksum: checksum.to_string(),
+            size: checksum.len() as u64,
+            modified_at: at(hour),
+            version: 1,
+            sync_status: SyncStatus::Synced,
+        }
+    }
+
+    fn deleted(path: &str) -> FileState {
+        FileState {
+            sync_status: SyncStatus::Deleted,
+            ..state(path, "", 0)
+        }
+    }
+
+    fn revision(state: FileState) -> Revision {
+        Revision {
+            state,
+            vector: VersionVector::new(),
+        }
+    }
+
+    fn detect(
+        base: Option<FileState>,
+        local: FileState,
+        remote: FileState,
+    ) -> Option<ConflictType> {
+        detect_conflict(base.as_ref(), &revision(local), &revision(remote)).map(|c| c.conflict_type)
+    }
+
+    #[test]
+    fn test_version_vectors_order_changes() {
+        let mut a = VersionVector::new();
+        a.increment("laptop");
+        let mut b = a.clone();
+        assert_eq!(a.compare(&b), Causality::Equal);
+        b.increment("phone");
+        assert_eq!(a.compare(&b), Causality::Before);
+        assert_eq!(b.compare(&a), Causality::After);
+        a.increment("laptop");
+        assert_eq!(a.compare(&b), Causality::Concurrent);
+        a.merge(&b);
+        assert_eq!((a.get("laptop"), a.get("phone")), (2, 1));
+        assert_eq!(a.compare(&b), Causality::After);
+    }
+
+    #[test]
+    fn test_detects_every_conflict_type() {
+        let base = || Some(state("a.txt", "base", 1));
+        assert_eq!(
+            detect(base(), state("a.txt", "L", 2), state("a.txt", "R", 3)),
+            Some(ConflictType::ModifyModify)
+        );
+        assert_eq!(
+            detect(base(), state("a.txt", "L", 2), deleted("a.txt")),
+            Some(ConflictType::ModifyDelete)
+        );
+        assert_eq!(
+            detect(base(), deleted("a.txt"), state("a.txt", "R", 2)),
+            Some(ConflictType::DeleteModify)
+        );
+        assert_eq!(
+            detect(base(), state("b.txt", "base", 1), state("c.txt", "base", 1)),
+            Some(ConflictType::MoveMove)
+        );
+        // Added on both sides with different content.
+        assert_eq!(
+            detect(None, state("a.txt", "L", 2), state("a.txt", "R", 2)),
+            Some(ConflictType::ModifyModify)
+        );
+    }
+
+    #[test]
+    fn test_compatible_changes_do_not_conflict() {
+        let base = || Some(state("a.txt", "base", 1));
+        // Only one side changed.
+        assert_eq!(
+            detect(base(), state("a.txt", "base", 1), state("a.txt", "R", 2)),
+            None
+        );
+        // Both made the same change.
+        assert_eq!(
+            detect(base(), state("a.txt", "X", 2), state("a.txt", "X", 3)),
+            None
+        );
+        assert_eq!(detect(base(), deleted("a.txt"), deleted("a.txt")), None);
+        // A rename on one side and an edit on the other combine.
+        assert_eq!(
+            detect(base(), state("b.txt", "base", 1), state("a.txt", "R", 2)),
+            None
+        );
+        assert_eq!(
+            detect(base(), state("b.txt", "L", 2), state("a.txt", "R", 2)),
+            Some(ConflictType::ModifyModify)
+        );
+    }
+
+    #[test]
+    fn test_version_vectors_rule_out_fast_forwards() {
+        let base = Some(state("a.txt", "base", 1));
+        let mut local = revision(state("a.txt", "L", 2));
+        local.vector.increment("laptop");
+        let mut remote = revision(state("a.txt", "R", 3));
+        remote.vector = local.vector.clone();
+        remote.vector.increment("phone");
+        // The remote version was made on top of the local one.
+        assert_eq!(detect_conflict(base.as_ref(), &local, &remote), None);
+
+        local.vector.increment("laptop");
+        let conflict = detect_conflict(base.as_ref(), &local, &remote).unwrap();
+        assert_eq!(conflict.conflict_type, ConflictType::ModifyModify);
+        assert_eq!(conflict.base_version, base);
+    }
+
+    #[test]
+    fn test_converts_engine_conflicts() {
+        let sync_state = |hash: &str| SyncState {
+            path: "a.txt".to_string(),
+            hash: hash.to_string(),
+            modified: at(1),
+            size: 4,
+        };
+        let action = SyncAction::Conflict {
+            path: "a.txt".to_string(),
+            conflict_type: ConflictType::ModifyDelete,
+            local: Some(sync_state("L")),
+            remote: None,
+            base: Some(sync_state("B")),
+        };
+        let conflict = conflict_from_action(&action).unwrap();
+        assert_eq!(conflict.local_version.checksum, "L");
+        assert!(is_deleted(&conflict.remote_version));
+        assert_eq!(conflict.remote_version.modified_at, at(1));
+        assert_eq!(conflict.base_version.unwrap().checksum, "B");
+        assert!(conflict_from_action(&SyncAction::Record {
+            path: "a.txt".to_string(),
+            state: None
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn test_copy_names_keep_directory_and_extension() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 14, 25, 1).unwrap();
+        assert_eq!(
+            conflict_copy_name("notes/todo.txt", "laptop", now),
+            "notes/todo (conflicted copy from laptop 2024-03-01 142501).txt"
+        );
+        assert_eq!(
+            conflict_copy_name(".bashrc", "Work: PC/2", now),
+            ".bashrc (conflicted copy from Work_ PC_2 2024-03-01 142501)"
+        );
+        assert_eq!(
+            conflict_copy_name("a.tar.gz", " ", now),
+            "a.tar (conflicted copy from unknown device 2024-03-01 142501).gz"
+        );
+    }
+
+    fn conflict(local: FileState, remote: FileState) -> SyncConflict {
+        SyncConflict {
+            path: "docs/a.txt".to_string(),
+            conflict_type: ConflictType::ModifyModify,
+            local_version: local,
+            remote_version: remote,
+            base_version: None,
+        }
+    }
+
+    #[test]
+    fn test_modes_pick_a_strategy() {
+        let c = conflict(state("docs/a.txt", "long", 5), state("docs/a.txt", "s", 3));
+        let strategy = |mode| ConflictResolver::new(mode, "laptop").strategy(&c, at(9));
+        assert_eq!(strategy(ConflictResolutionMode::Manual), None);
+        assert_eq!(
+            strategy(ConflictResolutionMode::LocalWins),
+            Some(ResolutionStrategy::UseLocal)
+        );
+        assert_eq!(
+            strategy(ConflictResolutionMode::RemoteWins),
+            Some(ResolutionStrategy::UseRemote)
+        );
+        assert_eq!(
+            strategy(ConflictResolutionMode::Timestamp),
+            Some(ResolutionStrategy::UseLocal)
+        );
+        assert_eq!(
+            strategy(ConflictResolutionMode::Size),
+            Some(ResolutionStrategy::UseLocal)
+        );
+
+        // A tie keeps both.
+        let tie = conflict(state("docs/a.txt", "L", 5), state("docs/a.txt", "R", 5));
+        let resolver = ConflictResolver::new(ConflictResolutionMode::Timestamp, "laptop");
+        match resolver.resolve(&tie, at(9)).unwrap() {
+            Some(Outcome::KeepBoth { copy, local, .. }) => {
+                assert_eq!(copy, conflict_copy_name("docs/a.txt", "laptop", at(9)));
+                assert_eq!(local.checksum, "L");
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+
+        // Size counts a deletion as empty, so the edit survives.
+        let edit_vs_delete = conflict(deleted("docs/a.txt"), state("docs/a.txt", "R", 1));
+        let resolver = ConflictResolver::new(ConflictResolutionMode::Size, "laptop");
+        assert!(matches!(
+            resolver.resolve(&edit_vs_delete, at(9)).unwrap(),
+            Some(Outcome::Take {
+                side: Side::Remote,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_applies_explicit_resolutions() {
+        let c = conflict(state("docs/a.txt", "L", 2), state("docs/a.txt", "R", 3));
+        let resolve = |resolution| {
+            apply_resolution(
+                &c,
+                &ConflictResolution {
+                    path: "docs/a.txt".to_string(),
+                    resolution,
+                    resolved_metadata: None,
+                },
+            )
+        };
+        assert!(matches!(
+            resolve(ResolutionStrategy::UseRemote).unwrap(),
+            Outcome::Take { side: Side::Remote, state, .. } if state.checksum == "R"
+        ));
+        assert!(resolve(ResolutionStrategy::UseBase).is_err());
+        assert!(matches!(
+            resolve(ResolutionStrategy::Merge).unwrap(),
+            Outcome::Merge { .. }
+        ));
+        assert!(matches!(
+            resolve(ResolutionStrategy::Rename {
+                new_name: "a (mine).txt".to_string()
+            })
+            .unwrap(),
+            Outcome::KeepBoth { copy, .. } if copy == "docs/a (mine).txt"
+        ));
+        for bad in ["a.txt", "../a.txt", ""] {
+            assert!(resolve(ResolutionStrategy::Rename {
+                new_name: bad.to_string()
+            })
+            .is_err());
+        }
+        assert_eq!(
+            resolve(ResolutionStrategy::Custom {
+                content: b"merged".to_vec()
+            })
+            .unwrap(),
+            Outcome::Replace {
+                path: "docs/a.txt".to_string(),
+                content: b"merged".to_vec()
+            }
+        );
+
+        let wrong_path = apply_resolution(
+            &c,
+            &ConflictResolution {
+                path: "other.txt".to_string(),
+                resolution: ResolutionStrategy::UseLocal,
+                resolved_metadata: None,
+            },
+        );
+        assert!(wrong_path.is_err());
+    }
+}
@@ -1,8 +1,12 @@
+use crate::encoding::DELTA_COMPRESSION_LEVEL;
 use crate::{SyncError, SyncOptions};
-use fileshare_crypto::{BlockHash, FileHasher, RollingHasher};
+use crypto::{BlockHash, FileHasher};
+use protocol::sync::{DeltaBlock, FileDelta as WireDelta};
 use serde::{Deserialize, Serialize};
+use similar::{capture_diff_slices_deadline, Algorithm, DiffTag};
 use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileDelta {
@@ -30,18 +34,189 @@ pub enum DeltaOperation {
     },
 }
 
+/// Block signature of one version of a file: what a delta against that
+/// version is computed from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileSignature {
+    pub checksum: String,
+    pub size: u64,
+    pub block_size: usize,
+    pub blocks: Vec<BlockHash>,
+}
+
+impl FileSignature {
+    /// Hash `reader` in blocks of `block_size` bytes; only the last block
+    /// may be shorter.
+    pub fn compute<R: Read>(reader: &mut R, block_size: usize) -> Result<Self, SyncError> {
+        let block_size = block_size.max(1);
+        let mut hasher = FileHasher::new();
+        let mut blocks = Vec::new();
+        let mut buffer = vec![0u8; block_size];
+        let mut offset = 0;
+        loop {
+            let mut filled = 0;
+            while filled < block_size {
+                match reader.read(&mut buffer[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            let block = &buffer[..filled];
+            hasher.update(block);
+            blocks.push(BlockHash {
+                offset,
+                size: filled,
+                hash: FileHasher::hash_bytes(block),
+            });
+            offset += filled;
+            if filled < block_size {
+                break;
+            }
+        }
+        Ok(Self {
+            checksum: hasher.finalize(),
+            size: offset as u64,
+            block_size,
+            blocks,
+        })
+    }
+}
+
+impl FileDelta {
+    /// Rebuild a delta received as protocol blocks. A block without data
+    /// copies the block of `base` with the same checksum; blocks must cover
+    /// the target contiguously.
+    pub fn from_wire(
+        wire: &WireDelta,
+        base: &FileSignature,
+        target_checksum: &str,
+    ) -> Result<Self, SyncError> {
+        let by_hash: HashMap<&str, &BlockHash> = base
+            .blocks
+            .iter()
+            .map(|block| (block.hash.as_str(), block))
+            .collect();
+        let mut operations = Vec::with_capacity(wire.blocks.len());
+        let mut target_offset = 0u64;
+        for block in &wire.blocks {
+            if block.offset != target_offset {
+                return Err(SyncError::InvalidDelta(format!(
+                    "block at offset {} does not follow offset {}",
+                    block.offset, target_offset
+                )));
+            }
+            match &block.data {
+                Some(data) => {
+                    if data.len() as u64 != block.size
+                        || FileHasher::hash_bytes(data) != block.checksum
+                    {
+                        return Err(SyncError::InvalidDelta(format!(
+                            "data of block at offset {} does not match its checksum",
+                            block.offset
+                        )));
+                    }
+                    operations.push(DeltaOperation::Insert {
+                        target_offset,
+                        data: data.clone(),
+                    });
+                }
+                None => {
+                    let source = by_hash
+                        .get(block.checksum.as_str())
+                        .filter(|source| source.size as u64 == block.size)
+                        .ok_or_else(|| {
+                            SyncError::InvalidDelta(format!(
+                                "block at offset {} is not in the base version",
+                                block.offset
+                            ))
+                        })?;
+                    operations.push(DeltaOperation::Copy {
+                        source_offset: source.offset as u64,
+                        target_offset,
+                        length: block.size,
+                    });
+                }
+            }
+            target_offset += block.size;
+        }
+        if target_offset != wire.total_size {
+            return Err(SyncError::InvalidDelta(format!(
+                "blocks cover {} bytes, expected {}",
+                target_offset, wire.total_size
+            )));
+        }
+        Ok(Self {
+            file_id: String::new(),
+            source_checksum: base.checksum.clone(),
+            target_checksum: target_checksum.to_string(),
+            operations,
+            compressed_size: None,
+        })
+    }
+
+    /// Protocol form of the delta, copies being named by the checksum of
+    /// the `base` block they come from.
+    pub fn to_wire(&self, base: &FileSignature) -> Result<WireDelta, SyncError> {
+        let by_offset: HashMap<u64, &BlockHash> = base
+            .blocks
+            .iter()
+            .map(|block| (block.offset as u64, block))
+            .collect();
+        let mut blocks = Vec::with_capacity(self.operations.len());
+        let mut total_size = 0;
+        for operation in &self.operations {
+            match operation {
+                DeltaOperation::Copy {
+                    source_offset,
+                    target_offset,
+                    length,
+                } => {
+                    let source = by_offset
+                        .get(source_offset)
+                        .filter(|source| source.size as u64 == *length)
+                        .ok_or_else(|| {
+                            SyncError::InvalidDelta(format!(
+                                "copy from offset {} is not a base block",
+                                source_offset
+                            ))
+                        })?;
+                    blocks.push(DeltaBlock {
+                        offset: *target_offset,
+                        size: *length,
+                        checksum: source.hash.clone(),
+                        data: None,
+                    });
+                    total_size = target_offset + length;
+                }
+                DeltaOperation::Insert {
+                    target_offset,
+                    data,
+                } => {
+                    blocks.push(DeltaBlock {
+                        offset: *target_offset,
+                        size: data.len() as u64,
+                        checksum: FileHasher::hash_bytes(data),
+                        data: Some(data.clone()),
+                    });
+                    total_size = target_offset + data.len() as u64;
+                }
+                DeltaOperation::Delete { .. } => {}
+            }
+        }
+        Ok(WireDelta { blocks, total_size })
+    }
+}
+
 pub struct DeltaGenerator {
     options: SyncOptions,
-    rolling_hasher: RollingHasher,
 }
 
 impl DeltaGenerator {
     pub fn new(options: SyncOptions) -> Self {
-        let rolling_hasher = RollingHasher::new(options.chunk_size.min(4096));
-        Self {
-            options,
-            rolling_hasher,
-        }
+        Self { options }
     }
 
     /// Generate delta between two files
@@ -83,20 +258,59 @@ impl DeltaGenerator {
         // Find matching blocks in target
         let operations = self.find_delta_operations(&mut target, &block_map)?;
 
-        let mut delta = FileDelta {
+        Ok(FileDelta {
             file_id: String::new(),
             source_checksum,
             target_checksum,
             operations,
             compressed_size: None,
-        };
+        })
+    }
 
-        // Compress delta if enabled
-        if self.options.compression_enabled {
-            delta = self.compress_delta(delta)?;
+    /// Delta from the version described by `base` to `target`, for a device
+    /// that no longer has the base content itself. Blocks are matched at
+    /// the signature's block size.
+    pub fn generate_from_signature<R: Read + Seek>(
+        &mut self,
+        mut target: R,
+        base: &FileSignature,
+    ) -> Result<FileDelta, SyncError> {
+        target.seek(SeekFrom::Start(0))?;
+        let target_checksum = FileHasher::hash_stream(&mut target)?;
+        if target_checksum == base.checksum {
+            return Ok(FileDelta {
+                file_id: String::new(),
+                source_checksum: base.checksum.clone(),
+                target_checksum,
+                operations: vec![],
+                compressed_size: None,
+            });
         }
 
-        Ok(delta)
+        target.seek(SeekFrom::Start(0))?;
+        let block_map = Self::create_block_map(&base.blocks);
+        let chunk_size = self.options.chunk_size;
+        self.options.chunk_size = base.block_size;
+        let operations = self.find_delta_operations(&mut target, &block_map);
+        self.options.chunk_size = chunk_size;
+
+        Ok(FileDelta {
+            file_id: String::new(),
+            source_checksum: base.checksum.clone(),
+            target_checksum,
+            operations: operations?,
+            compressed_size: None,
+        })
+    }
+
+    /// Wire form of `delta`: the binary encoding, zstd compressed if
+    /// compression is enabled.
+    pub fn encode_delta(&self, delta: &FileDelta) -> Result<Vec<u8>, SyncError> {
+        let level = self
+            .options
+            .compression_enabled
+            .then_some(DELTA_COMPRESSION_LEVEL);
+        delta.encode(level)
     }
 
     fn create_block_map(blocks: &[BlockHash]) -> HashMap<String, Vec<&BlockHash>> {
@@ -164,33 +378,44 @@ impl DeltaGenerator {
 
         Ok(operations)
     }
+}
 
-    fn compress_delta(&self, mut delta: FileDelta) -> Result<FileDelta, SyncError> {
-        let serialized = serde_json::to_vec(&delta.operations).map_err(SyncError::Serialization)?;
-
-        let compressed = zstd::bulk::compress(&serialized, 3)
-            .map_err(|e| SyncError::Compression(e.to_string()))?;
-
-        if compressed.len() < serialized.len() {
-            delta.compressed_size = Some(compressed.len());
-            // In a real implementation, you'd store the compressed data
-            // For now, we just record that compression was beneficial
-        }
-
-        Ok(delta)
+/// Size of the buffer copies go through when applying a delta.
+pub const APPLY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Copy exactly `length` bytes from `source` to `target` through `buffer`.
+pub(crate) fn copy_exact<R, W>(
+    source: &mut R,
+    target: &mut W,
+    mut length: u64,
+    buffer: &mut [u8],
+) -> Result<(), SyncError>
+where
+    R: Read + ?Sized,
+    W: std::io::Write + ?Sized,
+{
+    let capacity = buffer.len() as u64;
+    while length > 0 {
+        let chunk = &mut buffer[..length.min(capacity) as usize];
+        source.read_exact(chunk)?;
+        target.write_all(chunk)?;
+        length -= chunk.len() as u64;
     }
+    Ok(())
 }
 
-pub struct DeltaApplier {
-    options: SyncOptions,
-}
+pub struct DeltaApplier;
 
 impl DeltaApplier {
-    pub fn new(options: SyncOptions) -> Self {
-        Self { options }
+    /// Applying a delta needs none of the options; they are taken like
+    /// [`DeltaGenerator::new`] does.
+    pub fn new(_options: SyncOptions) -> Self {
+        Self
     }
 
-    /// Apply delta to reconstruct target file
+    /// Apply delta to reconstruct target file. Copies stream through a
+    /// fixed-size buffer, and the source is only sought when a copy does
+    /// not start where the previous one ended.
     pub fn apply_delta<R, W>(
         &self,
         mut source: R,
@@ -201,6 +426,8 @@ impl DeltaApplier {
         R: Read + Seek,
         W: std::io::Write,
     {
+        let mut buffer = vec![0u8; APPLY_BUFFER_SIZE];
+        let mut position = None;
         for operation in &delta.operations {
             match operation {
                 DeltaOperation::Copy {
@@ -208,10 +435,11 @@ impl DeltaApplier {
                     length,
                     ..
                 } => {
-                    source.seek(SeekFrom::Start(*source_offset))?;
-                    let mut buffer = vec![0u8; *length as usize];
-                    source.read_exact(&mut buffer)?;
-                    target.write_all(&buffer)?;
+                    if position != Some(*source_offset) {
+                        source.seek(SeekFrom::Start(*source_offset))?;
+                    }
+                    copy_exact(&mut source, &mut target, *length, &mut buffer)?;
+                    position = Some(source_offset + length);
                 }
                 DeltaOperation::Insert { data, .. } => {
                     target.write_all(data)?;
@@ -226,6 +454,25 @@ impl DeltaApplier {
         Ok(())
     }
 
+    /// Decode a delta in its wire form and apply it, returning the decoded
+    /// delta so the result can be checked against its target checksum.
+    /// `max_size` bounds the decoded delta.
+    pub fn apply_encoded<R, W>(
+        &self,
+        source: R,
+        target: W,
+        encoded: &[u8],
+        max_size: u64,
+    ) -> Result<FileDelta, SyncError>
+    where
+        R: Read + Seek,
+        W: std::io::Write,
+    {
+        let delta = FileDelta::decode(encoded, max_size)?;
+        self.apply_delta(source, target, &delta)?;
+        Ok(delta)
+    }
+
     /// Verify delta application by checking checksums
     pub fn verify_delta_application<R>(
         &self,
@@ -240,6 +487,23 @@ impl DeltaApplier {
     }
 }
 
+/// Files larger than this are not diffed byte by byte.
+pub const SMALL_FILE_LIMIT: u64 = 1024 * 1024;
+
+/// How long a small file diff may search for a minimal result before
+/// settling for a coarser one.
+const SMALL_DIFF_DEADLINE: Duration = Duration::from_millis(500);
+
+/// Algorithm behind [`SmallFileDelta`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    /// Anchors on bytes unique to both sides first; better on files with
+    /// moved blocks, falls back to Myers between anchors.
+    Patience,
+}
+
 /// Optimized delta for small changes
 pub struct SmallFileDelta {
     pub changes: Vec<ByteChange>,
@@ -253,63 +517,94 @@ pub struct ByteChange {
 }
 
 impl SmallFileDelta {
-    pub fn generate<R1, R2>(mut source: R1, mut target: R2) -> Result<Self, SyncError>
+    pub fn generate<R1, R2>(source: R1, target: R2) -> Result<Self, SyncError>
     where
         R1: Read,
         R2: Read,
     {
-        let mut source_data = Vec::new();
-        let mut target_data = Vec::new();
-
-        source.read_to_end(&mut source_data)?;
-        target.read_to_end(&mut target_data)?;
-
-        let changes = Self::diff_bytes(&source_data, &target_data);
-        Ok(Self { changes })
+        Self::generate_with(source, target, DiffAlgorithm::Myers)
     }
 
-    fn diff_bytes(source: &[u8], target: &[u8]) -> Vec<ByteChange> {
-        let mut changes = Vec::new();
-        let mut i = 0;
-        let mut j = 0;
+    /// Diff two files of at most [`SMALL_FILE_LIMIT`] bytes each.
+    pub fn generate_with<R1, R2>(
+        source: R1,
+        target: R2,
+        algorithm: DiffAlgorithm,
+    ) -> Result<Self, SyncError>
+    where
+        R1: Read,
+        R2: Read,
+    {
+        let source_data = read_small(source)?;
+        let target_data = read_small(target)?;
+        Ok(Self {
+            changes: Self::diff_bytes(&source_data, &target_data, algorithm),
+        })
+    }
 
-        while i < source.len() || j < target.len() {
-            if i < source.len() && j < target.len() && source[i] == target[j] {
-                // Bytes match, continue
-                i += 1;
-                j += 1;
+    fn diff_bytes(source: &[u8], target: &[u8], algorithm: DiffAlgorithm) -> Vec<ByteChange> {
+        let algorithm = match algorithm {
+            DiffAlgorithm::Myers => Algorithm::Myers,
+            DiffAlgorithm::Patience => Algorithm::Patience,
+        };
+        let deadline = Instant::now() + SMALL_DIFF_DEADLINE;
+        let ops = capture_diff_slices_deadline(algorithm, source, target, Some(deadline));
+
+        // Adjacent deletions and insertions make up one change.
+        let mut changes: Vec<ByteChange> = Vec::new();
+        let mut open = false;
+        for op in ops {
+            let (tag, old, new) = op.as_tag_tuple();
+            if tag == DiffTag::Equal {
+                open = false;
                 continue;
             }
-
-            // Find the extent of the difference
-            let start_i = i;
-            let start_j = j;
-
-            // Simple diff: find next matching position
-            while i < source.len() && j < target.len() {
-                if source[i] == target[j] {
-                    break;
-                }
-                i += 1;
-                j += 1;
+            if !open {
+                changes.push(ByteChange {
+                    offset: old.start as u64,
+                    old_bytes: Vec::new(),
+                    new_bytes: Vec::new(),
+                });
+                open = true;
             }
+            let change = changes.last_mut().expect("a change is open");
+            change.old_bytes.extend_from_slice(&source[old]);
+            change.new_bytes.extend_from_slice(&target[new]);
+        }
+        changes
+    }
 
-            // If we hit the end of one or both, extend to the end
-            if i == source.len() {
-                j = target.len();
-            } else if j == target.len() {
-                i = source.len();
+    /// Rebuild the target from the source the delta was generated from.
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, SyncError> {
+        let mut result = Vec::with_capacity(source.len());
+        let mut position = 0usize;
+        for change in &self.changes {
+            let offset = usize::try_from(change.offset)
+                .ok()
+                .filter(|&offset| offset >= position)
+                .ok_or_else(|| SyncError::InvalidDelta("changes out of order".into()))?;
+            let end = offset + change.old_bytes.len();
+            if source.get(offset..end) != Some(&change.old_bytes[..]) {
+                return Err(SyncError::ChecksumMismatch);
             }
-
-            changes.push(ByteChange {
-                offset: start_i as u64,
-                old_bytes: source[start_i..i].to_vec(),
-                new_bytes: target[start_j..j].to_vec(),
-            });
+            result.extend_from_slice(&source[position..offset]);
+            result.extend_from_slice(&change.new_bytes);
+            position = end;
         }
+        result.extend_from_slice(&source[position..]);
+        Ok(result)
+    }
+}
 
-        changes
+fn read_small<R: Read>(reader: R) -> Result<Vec<u8>, SyncError> {
+    let mut data = Vec::new();
+    reader.take(SMALL_FILE_LIMIT + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > SMALL_FILE_LIMIT {
+        return Err(SyncError::FileTooLarge {
+            size: data.len() as u64,
+        });
     }
+    Ok(data)
 }
 
 #[cfg(test)]
@@ -339,6 +634,122 @@ mod tests {
         assert!(delta.operations.is_empty());
     }
 
+    #[test]
+    fn test_wire_delta_round_trip() {
+        let options = SyncOptions {
+            chunk_size: 4,
+            compression_enabled: false,
+            ..SyncOptions::default()
+        };
+        let source_data = b"aaaabbbbccccdddd";
+        let target_data = b"aaaaXXXXccccdddd";
+        let base = FileSignature::compute(&mut Cursor::new(source_data), 4).unwrap();
+        assert_eq!(base.blocks.len(), 4);
+        assert_eq!(base.checksum, FileHasher::hash_bytes(source_data));
+
+        let delta = DeltaGenerator::new(options.clone())
+            .generate_delta(
+                Cursor::new(source_data),
+                Cursor::new(target_data),
+                &base.blocks,
+            )
+            .unwrap();
+        let wire = delta.to_wire(&base).unwrap();
+        assert_eq!(wire.total_size, target_data.len() as u64);
+        assert_eq!(wire.blocks.iter().filter(|b| b.data.is_some()).count(), 1);
+
+        let received = FileDelta::from_wire(&wire, &base, &delta.target_checksum).unwrap();
+        let applier = DeltaApplier::new(options);
+        let mut result = Vec::new();
+        applier
+            .apply_delta(Cursor::new(source_data), &mut result, &received)
+            .unwrap();
+        assert_eq!(result, target_data);
+        assert!(applier
+            .verify_delta_application(Cursor::new(&result), &received.target_checksum)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_generates_from_a_signature() {
+        let source_data = b"aaaabbbbccccdddd";
+        let target_data = b"aaaabbbbXXXXdddd";
+        let base = FileSignature::compute(&mut Cursor::new(source_data), 4).unwrap();
+
+        // The generator's own chunk size is ignored for the signature's.
+        let options = SyncOptions::default();
+        let delta = DeltaGenerator::new(options.clone())
+            .generate_from_signature(Cursor::new(target_data), &base)
+            .unwrap();
+        assert_eq!(delta.source_checksum, base.checksum);
+        assert_eq!(delta.operations.len(), 4);
+
+        let mut result = Vec::new();
+        DeltaApplier::new(options)
+            .apply_delta(Cursor::new(source_data), &mut result, &delta)
+            .unwrap();
+        assert_eq!(result, target_data);
+    }
+
+    #[test]
+    fn test_applies_encoded_deltas() {
+        let options = SyncOptions {
+            chunk_size: 8,
+            ..SyncOptions::default()
+        };
+        let source_data = b"0123456789abcdef".repeat(4);
+        let mut target_data = source_data.clone();
+        target_data[20..24].copy_from_slice(b"XXXX");
+        let base = FileSignature::compute(&mut Cursor::new(&source_data), 8).unwrap();
+
+        let mut generator = DeltaGenerator::new(options.clone());
+        let delta = generator
+            .generate_delta(
+                Cursor::new(&source_data),
+                Cursor::new(&target_data),
+                &base.blocks,
+            )
+            .unwrap();
+        let encoded = generator.encode_delta(&delta).unwrap();
+
+        let applier = DeltaApplier::new(options);
+        let mut result = Vec::new();
+        let decoded = applier
+            .apply_encoded(Cursor::new(&source_data), &mut result, &encoded, 1 << 20)
+            .unwrap();
+        assert_eq!(result, target_data);
+        assert_eq!(decoded.target_checksum, delta.target_checksum);
+    }
+
+    #[test]
+    fn test_wire_delta_rejects_unknown_blocks() {
+        let base = FileSignature::compute(&mut Cursor::new(b"aaaabbbb"), 4).unwrap();
+        let wire = WireDelta {
+            blocks: vec![DeltaBlock {
+                offset: 0,
+                size: 4,
+                checksum: FileHasher::hash_bytes(b"zzzz"),
+                data: None,
+            }],
+            total_size: 4,
+        };
+        assert!(matches!(
+            FileDelta::from_wire(&wire, &base, "target"),
+            Err(SyncError::InvalidDelta(_))
+        ));
+
+        let gap = WireDelta {
+            blocks: vec![DeltaBlock {
+                offset: 4,
+                size: 4,
+                checksum: base.blocks[0].hash.clone(),
+                data: None,
+            }],
+            total_size: 8,
+        };
+        assert!(FileDelta::from_wire(&gap, &base, "target").is_err());
+    }
+
     #[test]
     fn test_small_file_delta() {
         let source = b"Hello, World!";
@@ -354,6 +765,66 @@ mod tests {
         assert!(change.new_bytes.contains(&b'R'));
     }
 
+    #[test]
+    fn test_small_file_delta_round_trips() {
+        let source = b"line one\nline two\nline three\nline four\n".repeat(20);
+        let mut target = source.clone();
+        target.splice(30..38, b"replaced".iter().copied());
+        target.extend_from_slice(b"appended\n");
+        target.drain(200..260);
+
+        for algorithm in [DiffAlgorithm::Myers, DiffAlgorithm::Patience] {
+            let delta = SmallFileDelta::generate_with(
+                Cursor::new(&source),
+                Cursor::new(&target),
+                algorithm,
+            )
+            .unwrap();
+            assert!(!delta.changes.is_empty());
+            assert_eq!(delta.apply(&source).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_small_file_delta_refuses_large_files() {
+        let large = vec![0u8; SMALL_FILE_LIMIT as usize + 1];
+        assert!(matches!(
+            SmallFileDelta::generate(Cursor::new(&large), Cursor::new(b"")),
+            Err(SyncError::FileTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_streams_copies_larger_than_the_buffer() {
+        let source: Vec<u8> = (0..APPLY_BUFFER_SIZE * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let length = (APPLY_BUFFER_SIZE * 2 + 17) as u64;
+        let delta = FileDelta {
+            file_id: String::new(),
+            source_checksum: String::new(),
+            target_checksum: String::new(),
+            operations: vec![
+                DeltaOperation::Copy {
+                    source_offset: 5,
+                    target_offset: 0,
+                    length,
+                },
+                DeltaOperation::Copy {
+                    source_offset: 5 + length,
+                    target_offset: length,
+                    length: 100,
+                },
+            ],
+            compressed_size: None,
+        };
+        let mut result = Vec::new();
+        DeltaApplier::new(SyncOptions::default())
+            .apply_delta(Cursor::new(&source), &mut result, &delta)
+            .unwrap();
+        assert_eq!(result, &source[5..5 + length as usize + 100]);
+    }
+
     #[test]
     fn test_delta_application() {
         let options = SyncOptions::default();
@@ -0,0 +1,310 @@
+//! Compact binary encoding of deltas
+//!
+//! An encoded delta starts with a five byte header: the `RCD` magic, the
+//! format version and a flags byte telling whether the body is zstd
+//! compressed. The body holds the file id and both checksums as
+//! length-prefixed strings, the operation count, then the operations: a tag
+//! byte followed by LEB128 varints, an insert's bytes following its length
+//! as they are.
+
+use crate::delta::{DeltaOperation, FileDelta};
+use crate::SyncError;
+use std::io::Read;
+
+pub const DELTA_MAGIC: &[u8; 3] = b"RCD";
+pub const DELTA_FORMAT_VERSION: u8 = 1;
+/// zstd level used when compressing delta bodies.
+pub const DELTA_COMPRESSION_LEVEL: i32 = 3;
+
+const HEADER_LEN: usize = 5;
+const FLAG_ZSTD: u8 = 0x01;
+
+const TAG_COPY: u8 = 0;
+const TAG_INSERT: u8 = 1;
+const TAG_DELETE: u8 = 2;
+
+fn invalid(message: impl Into<String>) -> SyncError {
+    SyncError::InvalidDelta(message.into())
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Reads the fields of a decompressed body.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, SyncError> {
+        let (&first, rest) = self
+            .data
+            .split_first()
+            .ok_or_else(|| invalid("truncated delta"))?;
+        self.data = rest;
+        Ok(first)
+    }
+
+    fn varint(&mut self) -> Result<u64, SyncError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = u64::from(byte & 0x7f);
+            if shift == 63 && bits > 1 {
+                return Err(invalid("varint overflows 64 bits"));
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint overflows 64 bits"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SyncError> {
+        let len = self.varint()?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.data.len())
+            .ok_or_else(|| invalid("truncated delta"))?;
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, SyncError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("checksum is not UTF-8"))
+    }
+}
+
+impl FileDelta {
+    /// Binary form of the delta, its body compressed with zstd at `level`
+    /// when that makes it smaller.
+    pub fn encode(&self, level: Option<i32>) -> Result<Vec<u8>, SyncError> {
+        let mut body = Vec::new();
+        put_bytes(&mut body, self.file_id.as_bytes());
+        put_bytes(&mut body, self.source_checksum.as_bytes());
+        put_bytes(&mut body, self.target_checksum.as_bytes());
+        put_varint(&mut body, self.operations.len() as u64);
+        for operation in &self.operations {
+            match operation {
+                DeltaOperation::Copy {
+                    source_offset,
+                    target_offset,
+                    length,
+                } => {
+                    body.push(TAG_COPY);
+                    put_varint(&mut body, *source_offset);
+                    put_varint(&mut body, *target_offset);
+                    put_varint(&mut body, *length);
+                }
+                DeltaOperation::Insert {
+                    target_offset,
+                    data,
+                } => {
+                    body.push(TAG_INSERT);
+                    put_varint(&mut body, *target_offset);
+                    put_bytes(&mut body, data);
+                }
+                DeltaOperation::Delete {
+                    source_offset,
+                    length,
+                } => {
+                    body.push(TAG_DELETE);
+                    put_varint(&mut body, *source_offset);
+                    put_varint(&mut body, *length);
+                }
+            }
+        }
+
+        let mut flags = 0;
+        if let Some(level) = level {
+            let compressed = zstd::bulk::compress(&body, level)
+                .map_err(|e| SyncError::Compression(e.to_string()))?;
+            if compressed.len() < body.len() {
+                body = compressed;
+                flags |= FLAG_ZSTD;
+            }
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+        out.extend_from_slice(DELTA_MAGIC);
+        out.push(DELTA_FORMAT_VERSION);
+        out.push(flags);
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    /// Parse an encoded delta. `max_size` bounds the decompressed body, so
+    /// a small upload cannot expand without limit.
+    pub fn decode(encoded: &[u8], max_size: u64) -> Result<Self, SyncError> {
+        if encoded.len() < HEADER_LEN || &encoded[..3] != DELTA_MAGIC {
+            return Err(invalid("not an encoded delta"));
+        }
+        if encoded[3] != DELTA_FORMAT_VERSION {
+            return Err(invalid(format!(
+                "unsupported delta format version {}",
+                encoded[3]
+            )));
+        }
+        let flags = encoded[4];
+        if flags & !FLAG_ZSTD != 0 {
+            return Err(invalid(format!("unknown delta flags {:#04x}", flags)));
+        }
+
+        let payload = &encoded[HEADER_LEN..];
+        let decompressed;
+        let body = if flags & FLAG_ZSTD != 0 {
+            let mut body = Vec::new();
+            zstd::stream::read::Decoder::new(payload)
+                .map_err(|e| SyncError::Compression(e.to_string()))?
+                .take(max_size.saturating_add(1))
+                .read_to_end(&mut body)
+                .map_err(|e| SyncError::Compression(e.to_string()))?;
+            decompressed = body;
+            &decompressed[..]
+        } else {
+            payload
+        };
+        if body.len() as u64 > max_size {
+            return Err(SyncError::FileTooLarge {
+                size: body.len() as u64,
+            });
+        }
+
+        let mut reader = Reader { data: body };
+        let file_id = reader.string()?;
+        let source_checksum = reader.string()?;
+        let target_checksum = reader.string()?;
+        let count = reader.varint()?;
+        // Every operation takes at least two bytes.
+        let mut operations = Vec::with_capacity(count.min(body.len() as u64 / 2) as usize);
+        for _ in 0..count {
+            let operation = match reader.byte()? {
+                TAG_COPY => DeltaOperation::Copy {
+                    source_offset: reader.varint()?,
+                    target_offset: reader.varint()?,
+                    length: reader.varint()?,
+                },
+                TAG_INSERT => DeltaOperation::Insert {
+                    target_offset: reader.varint()?,
+                    data: reader.bytes()?.to_vec(),
+                },
+                TAG_DELETE => DeltaOperation::Delete {
+                    source_offset: reader.varint()?,
+                    length: reader.varint()?,
+                },
+                tag => return Err(invalid(format!("unknown operation tag {}", tag))),
+            };
+            operations.push(operation);
+        }
+        if !reader.data.is_empty() {
+            return Err(invalid("trailing bytes after the last operation"));
+        }
+
+        Ok(Self {
+            file_id,
+            source_checksum,
+            target_checksum,
+            operations,
+            compressed_size: (flags & FLAG_ZSTD != 0).then_some(encoded.len()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> FileDelta {
+        FileDelta {
+            file_id: "notes.txt".to_string(),
+            source_checksum: "a".repeat(64),
+            target_checksum: "b".repeat(64),
+            operations: vec![
+                DeltaOperation::Copy {
+                    source_offset: 0,
+                    target_offset: 0,
+                    length: 1 << 20,
+                },
+                DeltaOperation::Insert {
+                    target_offset: 1 << 20,
+                    data: b"inserted line\n".repeat(200),
+                },
+                DeltaOperation::Delete {
+                    source_offset: u64::MAX - 1,
+                    length: 300,
+                },
+            ],
+            compressed_size: None,
+        }
+    }
+
+    fn assert_same(a: &FileDelta, b: &FileDelta) {
+        assert_eq!(a.file_id, b.file_id);
+        assert_eq!(a.source_checksum, b.source_checksum);
+        assert_eq!(a.target_checksum, b.target_checksum);
+        assert_eq!(
+            serde_json::to_string(&a.operations).unwrap(),
+            serde_json::to_string(&b.operations).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_round_trips_with_and_without_compression() {
+        let delta = sample();
+        let plain = delta.encode(None).unwrap();
+        assert_eq!(&plain[..3], DELTA_MAGIC);
+        assert_eq!(plain[4], 0);
+        let decoded = FileDelta::decode(&plain, u64::MAX).unwrap();
+        assert_same(&delta, &decoded);
+        assert_eq!(decoded.compressed_size, None);
+
+        let compressed = delta.encode(Some(DELTA_COMPRESSION_LEVEL)).unwrap();
+        assert_eq!(compressed[4], FLAG_ZSTD);
+        assert!(compressed.len() < plain.len());
+        let decoded = FileDelta::decode(&compressed, u64::MAX).unwrap();
+        assert_same(&delta, &decoded);
+        assert_eq!(decoded.compressed_size, Some(compressed.len()));
+    }
+
+    #[test]
+    fn test_is_smaller_than_json() {
+        let delta = sample();
+        let json = serde_json::to_vec(&delta).unwrap();
+        let binary = delta.encode(None).unwrap();
+        assert!(binary.len() * 3 < json.len());
+    }
+
+    #[test]
+    fn test_rejects_malformed_input() {
+        let encoded = sample().encode(None).unwrap();
+        assert!(FileDelta::decode(b"RC", u64::MAX).is_err());
+        let mut version = encoded.clone();
+        version[3] = 9;
+        assert!(FileDelta::decode(&version, u64::MAX).is_err());
+        assert!(FileDelta::decode(&encoded[..encoded.len() - 1], u64::MAX).is_err());
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(FileDelta::decode(&trailing, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_bounds_the_decompressed_size() {
+        let encoded = sample().encode(Some(DELTA_COMPRESSION_LEVEL)).unwrap();
+        assert!(matches!(
+            FileDelta::decode(&encoded, 1024),
+            Err(SyncError::FileTooLarge { .. })
+        ));
+    }
+}
@@ -0,0 +1,888 @@
+//! Three-way reconciliation of a sync folder.
+//!
+//! Local and remote snapshots are compared against the base state recorded
+//! after the last successful sync, which tells a local delete apart from a
+//! remote add. [`Reconciler::plan`] classifies every path into an ordered
+//! [`SyncPlan`]; [`execute`] runs it and records each completed action in the
+//! base state, so an interrupted sync resumes by simply planning again.
+
+use crate::{SyncError, SyncState};
+use protocol::sync::{ConflictType, SyncDirection, SyncFolder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+/// Files of one side of a sync folder, keyed by path relative to its root.
+pub type Snapshot = HashMap<String, SyncState>;
+
+/// One step of a [`SyncPlan`]. Every action names the state it expects, so
+/// executing it again after it already took effect is detectable and
+/// harmless.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum SyncAction {
+    /// Send the local file to the remote.
+    Upload { path: String, local: SyncState },
+    /// Fetch the remote file.
+    Download { path: String, remote: SyncState },
+    /// Delete the local file, unless it no longer has `hash`.
+    DeleteLocal { path: String, hash: String },
+    /// Delete the remote file, unless it no longer has `hash`.
+    DeleteRemote { path: String, hash: String },
+    /// Rename a local file to follow a remote move.
+    MoveLocal {
+        from: String,
+        to: String,
+        state: SyncState,
+    },
+    /// Rename a remote file to follow a local move.
+    MoveRemote {
+        from: String,
+        to: String,
+        state: SyncState,
+    },
+    /// Both sides changed the path incompatibly.
+    Conflict {
+        path: String,
+        conflict_type: ConflictType,
+        local: Option<SyncState>,
+        remote: Option<SyncState>,
+        base: Option<SyncState>,
+    },
+    /// Both sides already agree; only the base state changes.
+    Record {
+        path: String,
+        state: Option<SyncState>,
+    },
+}
+
+impl SyncAction {
+    pub fn path(&self) -> &str {
+        match self {
+            SyncAction::Upload { path, .. }
+            | SyncAction::Download { path, .. }
+            | SyncAction::DeleteLocal { path, .. }
+            | SyncAction::DeleteRemote { path, .. }
+            | SyncAction::Conflict { path, .. }
+            | SyncAction::Record { path, .. } => path,
+            SyncAction::MoveLocal { to, .. } | SyncAction::MoveRemote { to, .. } => to,
+        }
+    }
+
+    /// Execution order: moves first so later steps see the new names, then
+    /// deletes, transfers, base-only updates and finally conflicts.
+    fn phase(&self) -> u8 {
+        match self {
+            SyncAction::MoveLocal { .. } | SyncAction::MoveRemote { .. } => 0,
+            SyncAction::DeleteLocal { .. } | SyncAction::DeleteRemote { .. } => 1,
+            SyncAction::Upload { .. } | SyncAction::Download { .. } => 2,
+            SyncAction::Record { .. } => 3,
+            SyncAction::Conflict { .. } => 4,
+        }
+    }
+}
+
+/// Ordered actions bringing both sides of a folder in sync.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &SyncAction> {
+        self.actions
+            .iter()
+            .filter(|a| matches!(a, SyncAction::Conflict { .. }))
+    }
+
+    /// Sort by phase, then by path. Deletes run deepest path first so a
+    /// directory's children go before it; everything else runs parents
+    /// first.
+    fn sort(&mut self) {
+        self.actions.sort_by(|a, b| {
+            a.phase().cmp(&b.phase()).then_with(|| {
+                if a.phase() == 1 {
+                    b.path().cmp(a.path())
+                } else {
+                    a.path().cmp(b.path())
+                }
+            })
+        });
+    }
+}
+
+/// Files as they were on both sides after the last successful sync.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BaseState {
+    pub entries: BTreeMap<String, SyncState>,
+}
+
+impl BaseState {
+    /// Record a completed action. Conflicts leave the base unchanged until
+    /// they are resolved.
+    pub fn apply(&mut self, action: &SyncAction) {
+        match action {
+            SyncAction::Upload { path, local: state }
+            | SyncAction::Download {
+                path,
+                remote: state,
+            } => {
+                self.entries.insert(path.clone(), state.clone());
+            }
+            SyncAction::DeleteLocal { path, .. } | SyncAction::DeleteRemote { path, .. } => {
+                self.entries.remove(path);
+            }
+            SyncAction::MoveLocal { from, to, state }
+            | SyncAction::MoveRemote { from, to, state } => {
+                self.entries.remove(from);
+                self.entries.insert(to.clone(), state.clone());
+            }
+            SyncAction::Record { path, state } => match state {
+                Some(state) => {
+                    self.entries.insert(path.clone(), state.clone());
+                }
+                None => {
+                    self.entries.remove(path);
+                }
+            },
+            SyncAction::Conflict { .. } => {}
+        }
+    }
+}
+
+/// Persistence of the base state of each sync folder.
+pub trait BaseStateStore {
+    /// The folder's base state; empty when it was never synced.
+    fn load(&self, folder: &SyncFolder) -> Result<BaseState, SyncError>;
+    fn save(&self, folder: &SyncFolder, state: &BaseState) -> Result<(), SyncError>;
+}
+
+/// Stable identifier of a sync folder, derived from both of its roots
+/// (64-bit FNV-1a, so it survives toolchain upgrades).
+pub fn folder_key(folder: &SyncFolder) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = folder
+        .local_path
+        .bytes()
+        .chain(std::iter::once(0))
+        .chain(folder.remote_path.bytes());
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Keeps each folder's base state as a JSON file in a directory.
+pub struct JsonStateStore {
+    dir: PathBuf,
+}
+
+impl JsonStateStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, folder: &SyncFolder) -> PathBuf {
+        self.dir.join(format!("{}.json", folder_key(folder)))
+    }
+}
+
+impl BaseStateStore for JsonStateStore {
+    fn load(&self, folder: &SyncFolder) -> Result<BaseState, SyncError> {
+        match fs::read(self.path(folder)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BaseState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Written to a temporary file and renamed, so a crash never leaves a
+    /// truncated state behind.
+    fn save(&self, folder: &SyncFolder, state: &BaseState) -> Result<(), SyncError> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(folder);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(state)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// How one side changed relative to the base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    /// Neither in the base nor on this side.
+    Absent,
+    Unchanged,
+    Added,
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    fn of(base: Option<&SyncState>, current: Option<&SyncState>) -> Self {
+        match (base, current) {
+            (None, None) => Change::Absent,
+            (None, Some(_)) => Change::Added,
+            (Some(_), None) => Change::Deleted,
+            (Some(base), Some(current)) if base.hash == current.hash => Change::Unchanged,
+            (Some(_), Some(_)) => Change::Modified,
+        }
+    }
+
+    fn is_write(self) -> bool {
+        matches!(self, Change::Added | Change::Modified)
+    }
+
+    fn is_quiet(self) -> bool {
+        matches!(self, Change::Absent | Change::Unchanged)
+    }
+}
+
+/// Plans the synchronization of a folder in one [`SyncDirection`].
+#[derive(Debug, Clone, Copy)]
+pub struct Reconciler {
+    direction: SyncDirection,
+}
+
+impl Reconciler {
+    pub fn new(direction: SyncDirection) -> Self {
+        Self { direction }
+    }
+
+    pub fn for_folder(folder: &SyncFolder) -> Self {
+        Self::new(folder.sync_direction)
+    }
+
+    /// Classify every path of `base`, `local` and `remote`.
+    ///
+    /// In [`SyncDirection::UploadOnly`] remote changes are ignored and the
+    /// local side always wins; [`SyncDirection::DownloadOnly`] is the
+    /// mirror image. Ignored changes stay out of the base, so they are
+    /// reported again on every run rather than silently forgotten.
+    pub fn plan(&self, base: &BaseState, local: &Snapshot, remote: &Snapshot) -> SyncPlan {
+        let paths: BTreeSet<&str> = base
+            .entries
+            .keys()
+            .chain(local.keys())
+            .chain(remote.keys())
+            .map(String::as_str)
+            .collect();
+        let changes: BTreeMap<&str, (Change, Change)> = paths
+            .iter()
+            .map(|path| {
+                let base = base.entries.get(*path);
+                (
+                    *path,
+                    (
+                        Change::of(base, local.get(*path)),
+                        Change::of(base, remote.get(*path)),
+                    ),
+                )
+            })
+            .collect();
+
+        let mut plan = SyncPlan::default();
+        let mut handled: HashSet<&str> = HashSet::new();
+        self.plan_moves(base, local, remote, &changes, &mut plan, &mut handled);
+
+        for (path, (local_change, remote_change)) in &changes {
+            if handled.contains(path) {
+                continue;
+            }
+            let action = match self.direction {
+                SyncDirection::Bidirectional => bidirectional(
+                    path,
+                    *local_change,
+                    *remote_change,
+                    base.entries.get(*path),
+                    local.get(*path),
+                    remote.get(*path),
+                ),
+                SyncDirection::UploadOnly => {
+                    upload_only(path, *local_change, local.get(*path), remote.get(*path))
+                }
+                SyncDirection::DownloadOnly => {
+                    download_only(path, *remote_change, local.get(*path), remote.get(*path))
+                }
+            };
+            plan.actions.extend(action);
+        }
+        plan.sort();
+        plan
+    }
+
+    /// Pair deletes with adds of the same content into moves, so renames
+    /// are not transferred again. Pairs are matched in path order.
+    fn plan_moves<'a>(
+        &self,
+        base: &BaseState,
+        local: &Snapshot,
+        remote: &Snapshot,
+        changes: &BTreeMap<&'a str, (Change, Change)>,
+        plan: &mut SyncPlan,
+        handled: &mut HashSet<&'a str>,
+    ) {
+        use Change::*;
+        let find =
+            |handled: &HashSet<&str>, hash: &str, want: (Change, Change), side: &Snapshot| {
+                changes
+                    .iter()
+                    .find(|(path, change)| {
+                        **change == want
+                            && !handled.contains(**path)
+                            && side.get(**path).is_some_and(|s| s.hash == hash)
+                    })
+                    .map(|(path, _)| *path)
+            };
+
+        for (from, change) in changes {
+            let Some(original) = base.entries.get(*from) else {
+                continue;
+            };
+            let local_to = find(handled, &original.hash, (Added, Absent), local);
+            let remote_to = find(handled, &original.hash, (Absent, Added), remote);
+            let action = match (*change, local_to, remote_to, self.direction) {
+                ((Deleted, Unchanged), Some(to), _, dir) if dir != SyncDirection::DownloadOnly => {
+                    handled.insert(to);
+                    SyncAction::MoveRemote {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        state: local[to].clone(),
+                    }
+                }
+                ((Unchanged, Deleted), _, Some(to), dir) if dir != SyncDirection::UploadOnly => {
+                    handled.insert(to);
+                    SyncAction::MoveLocal {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                        state: remote[to].clone(),
+                    }
+                }
+                (
+                    (Deleted, Deleted),
+                    Some(local_to),
+                    Some(remote_to),
+                    SyncDirection::Bidirectional,
+                ) => {
+                    handled.insert(local_to);
+                    handled.insert(remote_to);
+                    SyncAction::Conflict {
+                        path: from.to_string(),
+                        conflict_type: ConflictType::MoveMove,
+                        local: Some(local[local_to].clone()),
+                        remote: Some(remote[remote_to].clone()),
+                        base: Some(original.clone()),
+                    }
+                }
+                _ => continue,
+            };
+            handled.insert(from);
+            plan.actions.push(action);
+        }
+    }
+}
+
+fn conflict(
+    path: &str,
+    conflict_type: ConflictType,
+    base: Option<&SyncState>,
+    local: Option<&SyncState>,
+    remote: Option<&SyncState>,
+) -> SyncAction {
+    SyncAction::Conflict {
+        path: path.to_string(),
+        conflict_type,
+        local: local.cloned(),
+        remote: remote.cloned(),
+        base: base.cloned(),
+    }
+}
+
+fn bidirectional(
+    path: &str,
+    local_change: Change,
+    remote_change: Change,
+    base: Option<&SyncState>,
+    local: Option<&SyncState>,
+    remote: Option<&SyncState>,
+) -> Option<SyncAction> {
+    use Change::*;
+    let path_string = path.to_string();
+    Some(match (local_change, remote_change, local, remote) {
+        (l, r, Some(local), _) if l.is_write() && r.is_quiet() => SyncAction::Upload {
+            path: path_string,
+            local: local.clone(),
+        },
+        (l, r, _, Some(remote)) if l.is_quiet() && r.is_write() => SyncAction::Download {
+            path: path_string,
+            remote: remote.clone(),
+        },
+        (Deleted, Unchanged, _, Some(remote)) => SyncAction::DeleteRemote {
+            path: path_string,
+            hash: remote.hash.clone(),
+        },
+        (Unchanged, Deleted, Some(local), _) => SyncAction::DeleteLocal {
+            path: path_string,
+            hash: local.hash.clone(),
+        },
+        (Deleted, Deleted, _, _) => SyncAction::Record {
+            path: path_string,
+            state: None,
+        },
+        (l, r, Some(local), Some(remote)) if l.is_write() && r.is_write() => {
+            if local.hash == remote.hash {
+                SyncAction::Record {
+                    path: path_string,
+                    state: Some(local.clone()),
+                }
+            } else {
+                conflict(
+                    path,
+                    ConflictType::ModifyModify,
+                    base,
+                    Some(local),
+                    Some(remote),
+                )
+            }
+        }
+        (l, Deleted, Some(local), _) if l.is_write() => {
+            conflict(path, ConflictType::ModifyDelete, base, Some(local), None)
+        }
+        (Deleted, r, _, Some(remote)) if r.is_write() => {
+            conflict(path, ConflictType::DeleteModify, base, None, Some(remote))
+        }
+        _ => return None,
+    })
+}
+
+/// The local side is authoritative; remote changes are ignored.
+fn upload_only(
+    path: &str,
+    local_change: Change,
+    local: Option<&SyncState>,
+    remote: Option<&SyncState>,
+) -> Option<SyncAction> {
+    let path = path.to_string();
+    match (local, remote) {
+        (Some(local), Some(remote)) if local_change.is_write() && local.hash == remote.hash => {
+            Some(SyncAction::Record {
+                path,
+                state: Some(local.clone()),
+            })
+        }
+        (Some(local), _) if local_change.is_write() => Some(SyncAction::Upload {
+            path,
+            local: local.clone(),
+        }),
+        (None, Some(remote)) if local_change == Change::Deleted => Some(SyncAction::DeleteRemote {
+            path,
+            hash: remote.hash.clone(),
+        }),
+        (None, None) if local_change == Change::Deleted => {
+            Some(SyncAction::Record { path, state: None })
+        }
+        _ => None,
+    }
+}
+
+/// The remote side is authoritative; local changes are ignored.
+fn download_only(
+    path: &str,
+    remote_change: Change,
+    local: Option<&SyncState>,
+    remote: Option<&SyncState>,
+) -> Option<SyncAction> {
+    let path = path.to_string();
+    match (local, remote) {
+        (Some(local), Some(remote)) if remote_change.is_write() && local.hash == remote.hash => {
+            Some(SyncAction::Record {
+                path,
+                state: Some(remote.clone()),
+            })
+        }
+        (_, Some(remote)) if remote_change.is_write() => Some(SyncAction::Download {
+            path,
+            remote: remote.clone(),
+        }),
+        (Some(local), None) if remote_change == Change::Deleted => Some(SyncAction::DeleteLocal {
+            path,
+            hash: local.hash.clone(),
+        }),
+        (None, None) if remote_change == Change::Deleted => {
+            Some(SyncAction::Record { path, state: None })
+        }
+        _ => None,
+    }
+}
+
+/// Carries out the actions of a plan on the file system and the server.
+pub trait ActionExecutor {
+    /// Perform one action. An action whose effect is already in place, e.g.
+    /// a download completed before a crash, must succeed without redoing
+    /// it; a file that no longer has the expected content must be left
+    /// alone and reported as an error.
+    fn execute(&mut self, action: &SyncAction) -> Result<(), SyncError>;
+}
+
+/// Run `plan`, recording each completed action in `base` and saving it
+/// before the next one starts, so an interrupted run loses at most the
+/// action in progress. Conflicts are handed to the executor but leave the
+/// base unchanged. Returns the number of actions completed.
+pub fn execute(
+    plan: &SyncPlan,
+    folder: &SyncFolder,
+    base: &mut BaseState,
+    store: &dyn BaseStateStore,
+    executor: &mut dyn ActionExecutor,
+) -> Result<usize, SyncError> {
+    for (done, action) in plan.actions.iter().enumerate() {
+        if let Err(e) = executor.execute(action) {
+            store.save(folder, base)?;
+            return Err(SyncError::Engine(format!(
+                "{} after {} of {} actions: {}",
+                action.path(),
+                done,
+                plan.len(),
+                e
+            )));
+        }
+        base.apply(action);
+        store.save(folder, base)?;
+    }
+    Ok(plan.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use protocol::sync::SyncFilters;
+    use std::cell::RefCell;
+
+    fn state(path: &str, hash: &str) -> SyncState {
+        SyncState {
+            path: path.to_string(),
+            hash: hash.to_string(),
+            modified: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            size: hash.len() as u64,
+        }
+    }
+
+    fn snapshot(files: &[(&str, &str)]) -> Snapshot {
+        files
+            .iter()
+            .map(|(path, hash)| (path.to_string(), state(path, hash)))
+            .collect()
+    }
+
+    fn base(files: &[(&str, &str)]) -> BaseState {
+        BaseState {
+            entries: snapshot(files).into_iter().collect(),
+        }
+    }
+
+    fn folder(direction: SyncDirection) -> SyncFolder {
+        SyncFolder {
+            local_path: "/home/me/Documents".to_string(),
+            remote_path: "/Documents".to_string(),
+            sync_direction: direction,
+            is_active: true,
+            filters: SyncFilters::default(),
+        }
+    }
+
+    fn plan(
+        direction: SyncDirection,
+        base_files: &[(&str, &str)],
+        local: &[(&str, &str)],
+        remote: &[(&str, &str)],
+    ) -> SyncPlan {
+        Reconciler::new(direction).plan(&base(base_files), &snapshot(local), &snapshot(remote))
+    }
+
+    /// Applies actions to in-memory snapshots, optionally failing at one.
+    struct Memory {
+        local: Snapshot,
+        remote: Snapshot,
+        fail_at: Option<usize>,
+        executed: usize,
+    }
+
+    impl ActionExecutor for Memory {
+        fn execute(&mut self, action: &SyncAction) -> Result<(), SyncError> {
+            if self.fail_at == Some(self.executed) {
+                return Err(SyncError::Network("connection reset".to_string()));
+            }
+            self.executed += 1;
+            match action {
+                SyncAction::Upload { path, local } => {
+                    self.remote.insert(path.clone(), local.clone());
+                }
+                SyncAction::Download { path, remote } => {
+                    self.local.insert(path.clone(), remote.clone());
+                }
+                SyncAction::DeleteLocal { path, .. } => {
+                    self.local.remove(path);
+                }
+                SyncAction::DeleteRemote { path, .. } => {
+                    self.remote.remove(path);
+                }
+                SyncAction::MoveLocal { from, to, .. } => {
+                    let state = self.local.remove(from).unwrap();
+                    self.local.insert(to.clone(), state);
+                }
+                SyncAction::MoveRemote { from, to, .. } => {
+                    let state = self.remote.remove(from).unwrap();
+                    self.remote.insert(to.clone(), state);
+                }
+                SyncAction::Conflict { .. } | SyncAction::Record { .. } => {}
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MemoryStore {
+        saved: RefCell<Option<BaseState>>,
+    }
+
+    impl BaseStateStore for MemoryStore {
+        fn load(&self, _: &SyncFolder) -> Result<BaseState, SyncError> {
+            Ok(self.saved.borrow().clone().unwrap_or_default())
+        }
+
+        fn save(&self, _: &SyncFolder, state: &BaseState) -> Result<(), SyncError> {
+            *self.saved.borrow_mut() = Some(state.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tells_local_delete_from_remote_add() {
+        // Same local and remote maps, different base: a two-way diff
+        // cannot tell these apart.
+        let deleted = plan(
+            SyncDirection::Bidirectional,
+            &[("a.txt", "1")],
+            &[],
+            &[("a.txt", "1")],
+        );
+        assert_eq!(
+            deleted.actions,
+            vec![SyncAction::DeleteRemote {
+                path: "a.txt".to_string(),
+                hash: "1".to_string()
+            }]
+        );
+        let added = plan(SyncDirection::Bidirectional, &[], &[], &[("a.txt", "1")]);
+        assert_eq!(
+            added.actions,
+            vec![SyncAction::Download {
+                path: "a.txt".to_string(),
+                remote: state("a.txt", "1")
+            }]
+        );
+    }
+
+    #[test]
+    fn test_classifies_conflicts() {
+        let p = plan(
+            SyncDirection::Bidirectional,
+            &[
+                ("mm", "0"),
+                ("same", "0"),
+                ("md", "0"),
+                ("dm", "0"),
+                ("dd", "0"),
+            ],
+            &[("mm", "L"), ("same", "X"), ("md", "L"), ("new", "L")],
+            &[("mm", "R"), ("same", "X"), ("dm", "R"), ("new", "R")],
+        );
+        let conflicts: Vec<(&str, ConflictType)> = p
+            .actions
+            .iter()
+            .filter_map(|a| match a {
+                SyncAction::Conflict {
+                    path,
+                    conflict_type,
+                    ..
+                } => Some((path.as_str(), *conflict_type)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                ("dm", ConflictType::DeleteModify),
+                ("md", ConflictType::ModifyDelete),
+                ("mm", ConflictType::ModifyModify),
+                ("new", ConflictType::ModifyModify),
+            ]
+        );
+        assert!(p.actions.contains(&SyncAction::Record {
+            path: "same".to_string(),
+            state: Some(state("same", "X"))
+        }));
+        assert!(p.actions.contains(&SyncAction::Record {
+            path: "dd".to_string(),
+            state: None
+        }));
+        // Conflicts come last.
+        assert!(matches!(
+            p.actions.last(),
+            Some(SyncAction::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn test_detects_moves() {
+        let p = plan(
+            SyncDirection::Bidirectional,
+            &[("a", "1"), ("b", "2"), ("c", "3")],
+            &[("a2", "1"), ("b", "2"), ("c", "3")],
+            &[("a", "1"), ("b2", "2"), ("c", "3")],
+        );
+        assert_eq!(
+            p.actions,
+            vec![
+                SyncAction::MoveRemote {
+                    from: "a".to_string(),
+                    to: "a2".to_string(),
+                    state: state("a2", "1")
+                },
+                SyncAction::MoveLocal {
+                    from: "b".to_string(),
+                    to: "b2".to_string(),
+                    state: state("b2", "2")
+                },
+            ]
+        );
+
+        let both = plan(
+            SyncDirection::Bidirectional,
+            &[("a", "1")],
+            &[("x", "1")],
+            &[("y", "1")],
+        );
+        assert_eq!(both.len(), 1);
+        assert!(matches!(
+            &both.actions[0],
+            SyncAction::Conflict { path, conflict_type: ConflictType::MoveMove, .. } if path == "a"
+        ));
+    }
+
+    #[test]
+    fn test_honors_direction() {
+        let files = (
+            &[("kept", "0"), ("gone", "0"), ("theirs", "0")][..],
+            &[("kept", "L"), ("theirs", "0")][..],
+            &[
+                ("kept", "R"),
+                ("gone", "0"),
+                ("theirs", "R"),
+                ("extra", "R"),
+            ][..],
+        );
+        let up = plan(SyncDirection::UploadOnly, files.0, files.1, files.2);
+        assert_eq!(
+            up.actions,
+            vec![
+                SyncAction::DeleteRemote {
+                    path: "gone".to_string(),
+                    hash: "0".to_string()
+                },
+                SyncAction::Upload {
+                    path: "kept".to_string(),
+                    local: state("kept", "L")
+                },
+            ]
+        );
+        let down = plan(SyncDirection::DownloadOnly, files.0, files.1, files.2);
+        assert_eq!(
+            down.actions,
+            vec![
+                SyncAction::Download {
+                    path: "extra".to_string(),
+                    remote: state("extra", "R")
+                },
+                SyncAction::Download {
+                    path: "kept".to_string(),
+                    remote: state("kept", "R")
+                },
+                SyncAction::Download {
+                    path: "theirs".to_string(),
+                    remote: state("theirs", "R")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deletes_run_children_first() {
+        let p = plan(
+            SyncDirection::Bidirectional,
+            &[("dir", "d"), ("dir/a", "1"), ("dir/b", "2")],
+            &[("dir", "d"), ("dir/a", "1"), ("dir/b", "2")],
+            &[],
+        );
+        let paths: Vec<&str> = p.actions.iter().map(SyncAction::path).collect();
+        assert_eq!(paths, ["dir/b", "dir/a", "dir"]);
+    }
+
+    #[test]
+    fn test_execution_converges_and_resumes() {
+        let folder = folder(SyncDirection::Bidirectional);
+        let store = MemoryStore::default();
+        let reconciler = Reconciler::for_folder(&folder);
+        let mut memory = Memory {
+            local: snapshot(&[("a", "1"), ("b", "L"), ("new-local", "n")]),
+            remote: snapshot(&[("b", "0"), ("c", "3"), ("new-remote", "m")]),
+            fail_at: Some(2),
+            executed: 0,
+        };
+        let mut state = base(&[("a", "1"), ("b", "0"), ("c", "3")]);
+        store.save(&folder, &state).unwrap();
+
+        let first = reconciler.plan(&state, &memory.local, &memory.remote);
+        assert_eq!(first.len(), 5);
+        let err = execute(&first, &folder, &mut state, &store, &mut memory).unwrap_err();
+        assert!(err.to_string().contains("after 2 of 5"));
+        assert_eq!(store.load(&folder).unwrap(), state);
+
+        // Planning again picks up where the failed run stopped.
+        memory.fail_at = None;
+        let resumed = reconciler.plan(&state, &memory.local, &memory.remote);
+        assert_eq!(resumed.actions, first.actions[2..].to_vec());
+        execute(&resumed, &folder, &mut state, &store, &mut memory).unwrap();
+
+        assert_eq!(memory.local, memory.remote);
+        assert!(reconciler
+            .plan(&state, &memory.local, &memory.remote)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_json_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("sync-core-engine-{}", std::process::id()));
+        let store = JsonStateStore::new(&dir);
+        let folder = folder(SyncDirection::Bidirectional);
+        assert_eq!(store.load(&folder).unwrap(), BaseState::default());
+
+        let state = base(&[("a", "1"), ("dir/b", "2")]);
+        store.save(&folder, &state).unwrap();
+        assert_eq!(store.load(&folder).unwrap(), state);
+        assert_eq!(folder_key(&folder), folder_key(&folder.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
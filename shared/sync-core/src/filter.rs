@@ -0,0 +1,482 @@
+//! Which paths of a sync folder are synced
+//!
+//! Exclude patterns follow gitignore: `*`, `?` and `[...]` match within a
+//! path segment and `**` across segments. A pattern without a slash, other
+//! than a trailing one, matches a name at any depth; one with a slash is
+//! anchored at the folder root. A trailing `/` only matches directories and
+//! a leading `!` brings back what an earlier pattern excluded. The last
+//! matching pattern wins, and nothing under an excluded directory comes
+//! back. A folder's [`IGNORE_FILE`] adds patterns after the configured ones.
+//!
+//! Include patterns select what is synced at all: when there are any, only
+//! paths matching one, or under a directory matching one, are synced, and
+//! directories that cannot hold such a path are never entered.
+//!
+//! The server applies the same filters to each device's change stream
+//! through [`FolderScope`], so excluded paths never reach the device.
+
+use protocol::sync::{SyncFilters, SyncFolder};
+
+/// Ignore file read at the root of a sync folder.
+pub const IGNORE_FILE: &str = ".rouilleignore";
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// Glob per path segment; `**` stands for any number of segments.
+    segments: Vec<Vec<char>>,
+    negated: bool,
+    dir_only: bool,
+}
+
+fn is_globstar(segment: &[char]) -> bool {
+    segment == ['*', '*']
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, rest) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        // `\#` and `\!` start patterns with a literal `#` or `!`.
+        let rest = rest
+            .strip_prefix('\\')
+            .filter(|rest| rest.starts_with(['#', '!']))
+            .unwrap_or(rest);
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let anchored = rest.contains('/');
+        let mut segments: Vec<Vec<char>> = rest
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.chars().collect())
+            .collect();
+        if segments.is_empty() {
+            return None;
+        }
+        if !anchored {
+            segments.insert(0, vec!['*', '*']);
+        }
+        Some(Self {
+            segments,
+            negated,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, path: &[&str], is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && match_segments(&self.segments, path)
+    }
+
+    /// Whether the pattern may match something inside directory `dir`.
+    fn may_match_under(&self, dir: &[&str]) -> bool {
+        fn walk(pattern: &[Vec<char>], dir: &[&str]) -> bool {
+            let Some((first, rest)) = pattern.split_first() else {
+                return false;
+            };
+            if is_globstar(first) {
+                return true;
+            }
+            match dir.split_first() {
+                None => true,
+                Some((name, dir)) => glob(first, name) && walk(rest, dir),
+            }
+        }
+        walk(&self.segments, dir)
+    }
+}
+
+fn match_segments(pattern: &[Vec<char>], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        // A trailing `**` matches what is inside, not the directory itself.
+        Some((first, rest)) if is_globstar(first) && rest.is_empty() => !path.is_empty(),
+        Some((first, rest)) if is_globstar(first) => {
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        Some((first, rest)) => path
+            .split_first()
+            .is_some_and(|(name, path)| glob(first, name) && match_segments(rest, path)),
+    }
+}
+
+/// Match one path segment against a glob.
+fn glob(pattern: &[char], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it swallowed.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match class(&pattern[p + 1..], name[n]) {
+                Some((true, len)) => Some(len + 1),
+                Some((false, _)) => None,
+                // An unclosed bracket is a literal.
+                None => (name[n] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == name[n]).then_some(2),
+            Some(&c) => (c == name[n]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(step), _) => {
+                p += step;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the character class following a `[`. Returns whether
+/// it matched and the length of the class up to its closing `]`, or `None`
+/// when the class is not closed.
+fn class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let start = i;
+    let mut matched = false;
+    while i < pattern.len() {
+        let first = pattern[i];
+        // A `]` right after the opening bracket is a literal.
+        if first == ']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= (first..=pattern[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= first == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+/// Gitignore-style patterns, in order.
+#[derive(Debug, Clone, Default)]
+pub struct PatternSet {
+    patterns: Vec<Pattern>,
+}
+
+impl PatternSet {
+    /// Parse one pattern per line, skipping blank lines and `#` comments.
+    pub fn new<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            patterns: lines
+                .into_iter()
+                .filter_map(|line| Pattern::parse(line.as_ref()))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the last pattern matching the path itself is a positive one.
+    fn decides(&self, path: &[&str], is_dir: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    /// Whether the patterns select `path`, itself or through one of its
+    /// parent directories.
+    pub fn matches(&self, path: &str, is_dir: bool) -> bool {
+        self.matches_segments(&segments(path), is_dir)
+    }
+
+    fn matches_segments(&self, path: &[&str], is_dir: bool) -> bool {
+        (1..path.len()).any(|len| self.decides(&path[..len], true)) || self.decides(path, is_dir)
+    }
+
+    /// Whether something inside directory `dir` may be selected.
+    fn may_match_under(&self, dir: &[&str]) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| !pattern.negated && pattern.may_match_under(dir))
+    }
+}
+
+/// Patterns of an ignore file, without blank lines and comments.
+pub fn ignore_file_patterns(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The compiled [`SyncFilters`] of a folder. Paths are relative to the
+/// folder root.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: PatternSet,
+    exclude: PatternSet,
+    max_file_size: Option<u64>,
+    ignore_hidden: bool,
+}
+
+impl PathFilter {
+    pub fn new(filters: &SyncFilters) -> Self {
+        Self {
+            include: PatternSet::new(&filters.include_patterns),
+            exclude: PatternSet::new(&filters.exclude_patterns),
+            max_file_size: filters.max_file_size,
+            ignore_hidden: filters.ignore_hidden,
+        }
+    }
+
+    /// Whether a file of `size` bytes is synced.
+    pub fn accepts_file(&self, path: &str, size: u64) -> bool {
+        self.max_file_size.is_none_or(|max| size <= max) && self.accepts(path, false)
+    }
+
+    /// Whether a directory may hold synced files; others are not scanned or
+    /// downloaded at all.
+    pub fn accepts_dir(&self, path: &str) -> bool {
+        self.accepts(path, true)
+    }
+
+    fn accepts(&self, path: &str, is_dir: bool) -> bool {
+        let path = segments(path);
+        if path.is_empty() {
+            return true;
+        }
+        if self.ignore_hidden && path.iter().any(|segment| segment.starts_with('.')) {
+            return false;
+        }
+        if self.exclude.matches_segments(&path, is_dir) {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.matches_segments(&path, is_dir)
+            || (is_dir && self.include.may_match_under(&path))
+    }
+}
+
+/// The filters of a device's sync folders, by remote root.
+#[derive(Debug, Clone, Default)]
+pub struct FolderScope {
+    folders: Vec<(String, PathFilter)>,
+}
+
+impl FolderScope {
+    /// Scope of the active folders among `folders`.
+    pub fn new(folders: &[SyncFolder]) -> Self {
+        Self {
+            folders: folders
+                .iter()
+                .filter(|folder| folder.is_active)
+                .map(|folder| {
+                    (
+                        folder.remote_path.trim_matches('/').to_string(),
+                        PathFilter::new(&folder.filters),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether a file at `path` from the user's root is synced to the
+    /// device. The innermost folder holding the path decides; paths outside
+    /// every folder are not filtered.
+    pub fn accepts(&self, path: &str, size: u64) -> bool {
+        let path = path.trim_matches('/');
+        self.folders
+            .iter()
+            .filter_map(|(root, filter)| {
+                let relative = match root.as_str() {
+                    "" => Some(path),
+                    root => path.strip_prefix(root)?.strip_prefix('/'),
+                };
+                relative.map(|relative| (root.len(), relative, filter))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .is_none_or(|(_, relative, filter)| filter.accepts_file(relative, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::sync::SyncDirection;
+
+    fn set(patterns: &[&str]) -> PatternSet {
+        PatternSet::new(patterns)
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> PathFilter {
+        PathFilter::new(&SyncFilters {
+            include_patterns: include.iter().map(|p| p.to_string()).collect(),
+            exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+            ..SyncFilters::default()
+        })
+    }
+
+    #[test]
+    fn test_globs_match_within_a_segment() {
+        assert!(glob(&['*', '.', 'l', 'o', 'g'], "build.log"));
+        assert!(!glob(&['*', '.', 'l', 'o', 'g'], "build.logs"));
+        assert!(glob(&['?', 'a', '*'], "data"));
+        assert!(glob(&['[', 'a', '-', 'c', ']', 'x'], "bx"));
+        assert!(!glob(&['[', '!', 'a', '-', 'c', ']', 'x'], "bx"));
+        assert!(glob(&['[', ']', ']'], "]"));
+        assert!(glob(&['\\', '*'], "*"));
+        assert!(!glob(&['\\', '*'], "a"));
+        assert!(glob(&['[', 'a'], "[a"));
+    }
+
+    #[test]
+    fn test_names_match_at_any_depth() {
+        let rules = set(&[".DS_Store", "*.tmp"]);
+        assert!(rules.matches(".DS_Store", false));
+        assert!(rules.matches("photos/2024/.DS_Store", false));
+        assert!(rules.matches("a/b.tmp", false));
+        assert!(!rules.matches("a/b.tmpx", false));
+    }
+
+    #[test]
+    fn test_slashes_anchor_patterns() {
+        let rules = set(&["/build", "docs/*.pdf"]);
+        assert!(rules.matches("build", true));
+        assert!(rules.matches("build/out.o", false));
+        assert!(!rules.matches("src/build", true));
+        assert!(rules.matches("docs/a.pdf", false));
+        assert!(!rules.matches("docs/sub/a.pdf", false));
+        assert!(!rules.matches("other/docs/a.pdf", false));
+    }
+
+    #[test]
+    fn test_trailing_slashes_only_match_directories() {
+        let rules = set(&["cache/"]);
+        assert!(rules.matches("cache", true));
+        assert!(rules.matches("app/cache/data.bin", false));
+        assert!(!rules.matches("cache", false));
+    }
+
+    #[test]
+    fn test_globstars_cross_segments() {
+        let rules = set(&["**/node_modules", "logs/**", "a/**/z"]);
+        assert!(rules.matches("node_modules/x.js", false));
+        assert!(rules.matches("web/node_modules/x.js", false));
+        assert!(rules.matches("logs/today.txt", false));
+        assert!(!rules.matches("logs", false));
+        assert!(rules.matches("a/z", false));
+        assert!(rules.matches("a/b/c/z", false));
+    }
+
+    #[test]
+    fn test_negations_bring_files_back() {
+        let rules = set(&["*.log", "!keep.log"]);
+        assert!(rules.matches("debug.log", false));
+        assert!(!rules.matches("keep.log", false));
+        assert!(!rules.matches("dir/keep.log", false));
+    }
+
+    #[test]
+    fn test_excluded_directories_stay_excluded() {
+        let rules = set(&["tmp/", "!tmp/keep.txt"]);
+        assert!(rules.matches("tmp/keep.txt", false));
+    }
+
+    #[test]
+    fn test_comments_and_escapes() {
+        let rules = set(&["# comment", "", "\\#notes", "\\!bang"]);
+        assert!(rules.matches("#notes", false));
+        assert!(rules.matches("!bang", false));
+        assert!(!rules.matches("comment", false));
+        assert_eq!(
+            ignore_file_patterns("# build output\n\n/target/\n*.swp  \n"),
+            vec!["/target/", "*.swp"]
+        );
+    }
+
+    #[test]
+    fn test_filters_apply_limits_and_hidden_files() {
+        let filter = PathFilter::new(&SyncFilters {
+            max_file_size: Some(10),
+            ignore_hidden: true,
+            ..SyncFilters::default()
+        });
+        assert!(filter.accepts_file("a/b.txt", 10));
+        assert!(!filter.accepts_file("a/b.txt", 11));
+        assert!(!filter.accepts_file(".git/config", 1));
+        assert!(!filter.accepts_dir(".git"));
+    }
+
+    #[test]
+    fn test_includes_select_folders() {
+        let filter = filter(&["/Documents/", "Photos/2024"], &["*.raw"]);
+        assert!(filter.accepts_file("Documents/cv.pdf", 1));
+        assert!(filter.accepts_file("Photos/2024/a.jpg", 1));
+        assert!(!filter.accepts_file("Photos/2024/a.raw", 1));
+        assert!(!filter.accepts_file("Photos/2023/a.jpg", 1));
+        assert!(!filter.accepts_file("notes.txt", 1));
+        // Directories leading to a selected one are entered, others not.
+        assert!(filter.accepts_dir("Photos"));
+        assert!(!filter.accepts_dir("Photos/2023"));
+        assert!(!filter.accepts_dir("Music"));
+    }
+
+    #[test]
+    fn test_unanchored_includes_enter_every_directory() {
+        let filter = filter(&["*.pdf"], &[]);
+        assert!(filter.accepts_dir("any/where"));
+        assert!(filter.accepts_file("any/where/a.pdf", 1));
+        assert!(!filter.accepts_file("any/where/a.txt", 1));
+    }
+
+    #[test]
+    fn test_scopes_use_the_innermost_folder() {
+        let folder = |remote: &str, exclude: &[&str], is_active: bool| SyncFolder {
+            local_path: "/local".to_string(),
+            remote_path: remote.to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active,
+            filters: SyncFilters {
+                exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+                ..SyncFilters::default()
+            },
+        };
+        let scope = FolderScope::new(&[
+            folder("/work/", &["*.o"], true),
+            folder("work/site", &["/cache/"], true),
+            folder("music", &["*"], false),
+        ]);
+        assert!(!scope.accepts("work/main.o", 1));
+        assert!(scope.accepts("work/main.c", 1));
+        assert!(!scope.accepts("work/site/cache/page.html", 1));
+        // The inner folder's filters replace the outer one's.
+        assert!(scope.accepts("work/site/main.o", 1));
+        assert!(scope.accepts("workshop/main.o", 1));
+        assert!(scope.accepts("music/song.mp3", 1));
+    }
+}
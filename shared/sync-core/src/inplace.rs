@@ -0,0 +1,307 @@
+//! Applying a delta over its own source
+//!
+//! When source and target are the same file, a copy may read bytes another
+//! operation already overwrote. Copies are therefore ordered so that every
+//! region is read before it is written: a copy reading the target range of
+//! another one runs first. Copies caught in a cycle are turned into inserts
+//! by reading their bytes up front, smallest first. Inserts read nothing
+//! and run last.
+
+use crate::delta::{DeltaApplier, DeltaOperation, FileDelta, APPLY_BUFFER_SIZE};
+use crate::SyncError;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+struct Copy {
+    source: u64,
+    target: u64,
+    length: u64,
+}
+
+fn overlaps(a_start: u64, a_len: u64, b_start: u64, b_len: u64) -> bool {
+    a_start < b_start + b_len && b_start < a_start + a_len
+}
+
+/// Copy `length` bytes from `source` to `target` within one file, in the
+/// direction that never overwrites bytes still to be read.
+fn move_within<F: Read + Write + Seek>(
+    file: &mut F,
+    copy: &Copy,
+    buffer: &mut [u8],
+) -> Result<(), SyncError> {
+    let chunk_len = buffer.len() as u64;
+    let mut step = |start: u64, len: u64, file: &mut F| -> Result<(), SyncError> {
+        let chunk = &mut buffer[..len as usize];
+        file.seek(SeekFrom::Start(copy.source + start))?;
+        file.read_exact(chunk)?;
+        file.seek(SeekFrom::Start(copy.target + start))?;
+        file.write_all(chunk)?;
+        Ok(())
+    };
+    if copy.target < copy.source {
+        let mut start = 0;
+        while start < copy.length {
+            let len = chunk_len.min(copy.length - start);
+            step(start, len, file)?;
+            start += len;
+        }
+    } else {
+        let mut end = copy.length;
+        while end > 0 {
+            let len = chunk_len.min(end);
+            step(end - len, len, file)?;
+            end -= len;
+        }
+    }
+    Ok(())
+}
+
+impl DeltaApplier {
+    /// Patch `file`, which holds the delta's source, into its target.
+    /// Returns the target length; the caller truncates the file to it when
+    /// the target is shorter.
+    pub fn apply_in_place<F>(&self, file: &mut F, delta: &FileDelta) -> Result<u64, SyncError>
+    where
+        F: Read + Write + Seek,
+    {
+        let source_len = file.seek(SeekFrom::End(0))?;
+        let mut copies = Vec::new();
+        let mut inserts = Vec::new();
+        let mut targets = Vec::new();
+        for operation in &delta.operations {
+            match operation {
+                DeltaOperation::Copy {
+                    source_offset,
+                    target_offset,
+                    length,
+                } => {
+                    if source_offset
+                        .checked_add(*length)
+                        .is_none_or(|end| end > source_len)
+                    {
+                        return Err(SyncError::InvalidDelta(format!(
+                            "copy from offset {} reads past the end of the source",
+                            source_offset
+                        )));
+                    }
+                    targets.push((*target_offset, *length));
+                    if *length > 0 && source_offset != target_offset {
+                        copies.push(Copy {
+                            source: *source_offset,
+                            target: *target_offset,
+                            length: *length,
+                        });
+                    }
+                }
+                DeltaOperation::Insert {
+                    target_offset,
+                    data,
+                } => {
+                    targets.push((*target_offset, data.len() as u64));
+                    inserts.push((*target_offset, data.as_slice()));
+                }
+                DeltaOperation::Delete { .. } => {}
+            }
+        }
+
+        targets.sort_unstable();
+        let mut target_len = 0u64;
+        for &(offset, length) in &targets {
+            if offset < target_len && length > 0 {
+                return Err(SyncError::InvalidDelta(format!(
+                    "operations overlap at target offset {}",
+                    offset
+                )));
+            }
+            target_len = target_len.max(offset.saturating_add(length));
+        }
+
+        // Copy `j` waits for the `waiting[j]` copies reading what it writes;
+        // `unblocks[i]` are the copies whose target copy `i` reads.
+        copies.sort_unstable_by_key(|copy| copy.target);
+        let mut waiting = vec![0usize; copies.len()];
+        let mut unblocks: Vec<Vec<usize>> = vec![Vec::new(); copies.len()];
+        for (i, reader) in copies.iter().enumerate() {
+            let first = copies.partition_point(|c| c.target + c.length <= reader.source);
+            for (j, writer) in copies.iter().enumerate().skip(first) {
+                if writer.target >= reader.source + reader.length {
+                    break;
+                }
+                if i != j && overlaps(reader.source, reader.length, writer.target, writer.length) {
+                    waiting[j] += 1;
+                    unblocks[i].push(j);
+                }
+            }
+        }
+
+        let mut buffer = vec![0u8; APPLY_BUFFER_SIZE];
+        let mut done = vec![false; copies.len()];
+        let mut ready: Vec<usize> = (0..copies.len()).filter(|&j| waiting[j] == 0).collect();
+        let mut deferred = Vec::new();
+        let mut remaining = copies.len();
+        let mut finish = |i: usize, done: &mut Vec<bool>, ready: &mut Vec<usize>| {
+            done[i] = true;
+            for &j in &unblocks[i] {
+                waiting[j] -= 1;
+                if waiting[j] == 0 && !done[j] {
+                    ready.push(j);
+                }
+            }
+        };
+        while remaining > 0 {
+            while let Some(j) = ready.pop() {
+                if done[j] {
+                    continue;
+                }
+                move_within(file, &copies[j], &mut buffer)?;
+                finish(j, &mut done, &mut ready);
+                remaining -= 1;
+            }
+            if remaining == 0 {
+                break;
+            }
+            // Every copy left waits on another: break the cycle by saving
+            // the smallest one's bytes before anything overwrites them.
+            let victim = (0..copies.len())
+                .filter(|&j| !done[j])
+                .min_by_key(|&j| copies[j].length)
+                .expect("copies remain");
+            let copy = &copies[victim];
+            let mut data = vec![0u8; copy.length as usize];
+            file.seek(SeekFrom::Start(copy.source))?;
+            file.read_exact(&mut data)?;
+            deferred.push((copy.target, data));
+            finish(victim, &mut done, &mut ready);
+            remaining -= 1;
+        }
+
+        let deferred = deferred
+            .iter()
+            .map(|(offset, data)| (*offset, data.as_slice()));
+        for (offset, data) in inserts.into_iter().chain(deferred) {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(data)?;
+        }
+        file.flush()?;
+        Ok(target_len)
+    }
+
+    /// Patch the file at `path` in place with a delta against its current
+    /// content.
+    pub fn patch_file(&self, path: &Path, delta: &FileDelta) -> Result<(), SyncError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = self.apply_in_place(&mut file, delta)?;
+        file.set_len(len)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyncOptions;
+    use std::io::Cursor;
+
+    fn delta(operations: Vec<DeltaOperation>) -> FileDelta {
+        FileDelta {
+            file_id: String::new(),
+            source_checksum: String::new(),
+            target_checksum: String::new(),
+            operations,
+            compressed_size: None,
+        }
+    }
+
+    fn copy(source_offset: u64, target_offset: u64, length: u64) -> DeltaOperation {
+        DeltaOperation::Copy {
+            source_offset,
+            target_offset,
+            length,
+        }
+    }
+
+    /// Apply in place and check the result against the streaming path.
+    fn check(source: &[u8], delta: &FileDelta) -> Vec<u8> {
+        let applier = DeltaApplier::new(SyncOptions::default());
+        let mut expected = Vec::new();
+        applier
+            .apply_delta(Cursor::new(source), &mut expected, delta)
+            .unwrap();
+
+        let mut file = Cursor::new(source.to_vec());
+        let len = applier.apply_in_place(&mut file, delta).unwrap();
+        let mut patched = file.into_inner();
+        patched.truncate(len as usize);
+        assert_eq!(patched, expected);
+        patched
+    }
+
+    #[test]
+    fn test_swaps_blocks_through_a_cycle() {
+        let patched = check(
+            b"aaaabbbbcccc",
+            &delta(vec![copy(8, 0, 4), copy(4, 4, 4), copy(0, 8, 4)]),
+        );
+        assert_eq!(patched, b"ccccbbbbaaaa");
+    }
+
+    #[test]
+    fn test_shifts_overlapping_ranges() {
+        let source: Vec<u8> = (0..APPLY_BUFFER_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let len = source.len() as u64;
+        // Right: insert a header, then the whole source.
+        let right = delta(vec![
+            DeltaOperation::Insert {
+                target_offset: 0,
+                data: b"header".to_vec(),
+            },
+            copy(0, 6, len),
+        ]);
+        check(&source, &right);
+        // Left: drop the first 1000 bytes.
+        check(&source, &delta(vec![copy(1000, 0, len - 1000)]));
+    }
+
+    #[test]
+    fn test_orders_chains_of_copies() {
+        let source: Vec<u8> = (0..64u8).collect();
+        // Each block moves to where the next one was, with an insert at
+        // the front, so every copy but the last overwrites a later read.
+        let mut operations = vec![DeltaOperation::Insert {
+            target_offset: 0,
+            data: vec![0xff; 8],
+        }];
+        for block in 0..7u64 {
+            operations.push(copy(block * 8, (block + 1) * 8, 8));
+        }
+        check(&source, &delta(operations));
+    }
+
+    #[test]
+    fn test_rejects_invalid_deltas() {
+        let applier = DeltaApplier::new(SyncOptions::default());
+        let mut file = Cursor::new(b"abcdefgh".to_vec());
+        assert!(applier
+            .apply_in_place(&mut file, &delta(vec![copy(4, 0, 8)]))
+            .is_err());
+        assert!(applier
+            .apply_in_place(&mut file, &delta(vec![copy(0, 0, 4), copy(4, 2, 4)]))
+            .is_err());
+        assert_eq!(file.into_inner(), b"abcdefgh");
+    }
+
+    #[test]
+    fn test_patches_files() {
+        let path = std::env::temp_dir().join(format!("sync-core-inplace-{}", std::process::id()));
+        std::fs::write(&path, b"hello world, hello sync").unwrap();
+        DeltaApplier::new(SyncOptions::default())
+            .patch_file(&path, &delta(vec![copy(13, 0, 10)]))
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello sync");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
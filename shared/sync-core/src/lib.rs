@@ -1,22 +1,28 @@
 pub mod delta;
+pub mod encoding;
+pub mod inplace;
 pub mod conflict;
 pub mod merger;
 pub mod engine;
 pub mod chunker;
+pub mod filter;
+pub mod scheduler;
 
 pub use delta::*;
+pub use encoding::*;
+pub use engine::*;
+pub use conflict::*;
+pub use merger::*;
+pub use filter::*;
+pub use scheduler::*;
 // TODO: Uncomment when these modules are implemented
-// pub use conflict::*;
-// pub use merger::*;
-// pub use engine::*;
 // pub use chunker::*;
 
-use protocol::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SyncState {
     pub path: String,
     pub hash: String,
@@ -35,6 +41,12 @@ pub struct SyncEngine {
     local_state: HashMap<String, SyncState>,
 }
 
+impl Default for SyncEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SyncEngine {
     pub fn new() -> Self {
         Self {
@@ -87,6 +99,7 @@ pub struct SyncOptions {
     pub conflict_resolution: ConflictResolutionMode,
     pub bandwidth_limit: Option<u64>, // bytes per second
     pub max_file_size: Option<u64>,
+    /// Gitignore-style patterns excluded from every folder; see [`filter`].
     pub exclude_patterns: Vec<String>,
 }
 
@@ -101,14 +114,14 @@ impl Default for SyncOptions {
             exclude_patterns: vec![
                 ".DS_Store".to_string(),
                 "Thumbs.db".to_string(),
-                ".tmp".to_string(),
-                ".temp".to_string(),
+                "*.tmp".to_string(),
+                "*.temp".to_string(),
             ],
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConflictResolutionMode {
     Manual,
     LocalWins,
@@ -133,6 +146,10 @@ pub enum SyncError {
     Compression(String),
     #[error("Checksum mismatch")]
     ChecksumMismatch,
+    #[error("Invalid delta: {0}")]
+    InvalidDelta(String),
+    #[error("Cannot merge binary file: {0}")]
+    BinaryFile(String),
     #[error("Sync engine error: {0}")]
     Engine(String),
 }
@@ -0,0 +1,582 @@
+//! Three-way merges of text files for [`ResolutionStrategy::Merge`].
+//!
+//! Every text file gets a line-based diff3 merge: edits to different parts of
+//! the file are combined and overlapping edits are written out between
+//! conflict markers. When that leaves conflicts, or breaks the syntax of a
+//! JSON or TOML file, a structure-aware merge is tried: JSON and TOML merge
+//! key by key, Markdown heading section by heading section. Binary files are
+//! refused.
+//!
+//! [`ResolutionStrategy::Merge`]: protocol::sync::ResolutionStrategy::Merge
+
+use crate::SyncError;
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub const MARKER_LOCAL: &str = "<<<<<<< local";
+pub const MARKER_BASE: &str = "||||||| base";
+pub const MARKER_SEPARATOR: &str = "=======";
+pub const MARKER_REMOTE: &str = ">>>>>>> remote";
+
+/// How much of a file is inspected for NUL bytes, as git does.
+const BINARY_PROBE_SIZE: usize = 8000;
+
+/// Merge strategy, chosen by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeFormat {
+    Text,
+    Json,
+    Toml,
+    Markdown,
+}
+
+impl MergeFormat {
+    pub fn from_path(path: &str) -> Self {
+        let extension = path
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("json") => MergeFormat::Json,
+            Some("toml") => MergeFormat::Toml,
+            Some("md" | "markdown") => MergeFormat::Markdown,
+            _ => MergeFormat::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    pub content: String,
+    /// Number of conflict marker blocks in `content`.
+    pub conflicts: usize,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+/// Whether `data` should be treated as binary: not UTF-8, or a NUL byte
+/// near the start.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(BINARY_PROBE_SIZE)].contains(&0) || std::str::from_utf8(data).is_err()
+}
+
+/// Merge the local and remote versions of the file at `path`. An empty
+/// `base` stands for a file added on both sides.
+pub fn merge_files(
+    path: &str,
+    base: &[u8],
+    local: &[u8],
+    remote: &[u8],
+) -> Result<MergeResult, SyncError> {
+    let text = |data: &[u8]| match std::str::from_utf8(data) {
+        Ok(text) if !is_binary(data) => Ok(text.to_string()),
+        _ => Err(SyncError::BinaryFile(path.to_string())),
+    };
+    let (base, local, remote) = (text(base)?, text(local)?, text(remote)?);
+    let merged = merge_text(&base, &local, &remote);
+
+    let structured = match MergeFormat::from_path(path) {
+        MergeFormat::Text => None,
+        MergeFormat::Json if merged.is_clean() && parse_json(&merged.content).is_some() => None,
+        MergeFormat::Json => merge_json(&base, &local, &remote),
+        MergeFormat::Toml if merged.is_clean() && parse_toml(&merged.content).is_some() => None,
+        MergeFormat::Toml => merge_toml(&base, &local, &remote),
+        MergeFormat::Markdown if merged.is_clean() => None,
+        MergeFormat::Markdown => merge_markdown(&base, &local, &remote)
+            .filter(|sections| sections.conflicts < merged.conflicts),
+    };
+    Ok(structured.unwrap_or(merged))
+}
+
+/// For each line of `base`, the line of `other` it was matched with.
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; base.len()];
+    for op in capture_diff_slices(Algorithm::Myers, base, other) {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for offset in 0..len {
+                matched[old_index + offset] = Some(new_index + offset);
+            }
+        }
+    }
+    matched
+}
+
+fn push_lines(out: &mut String, lines: &[&str]) {
+    for line in lines {
+        out.push_str(line);
+    }
+}
+
+/// Line ending of the first of `texts` that has one, `\n` if none does.
+fn line_ending(texts: &[&str]) -> &'static str {
+    match texts
+        .iter()
+        .find_map(|text| text.find('\n').map(|end| &text[..end]))
+    {
+        Some(line) if line.ends_with('\r') => "\r\n",
+        _ => "\n",
+    }
+}
+
+/// Lines of a conflict block, each ending in a newline so the following
+/// marker starts on a line of its own.
+fn push_block(out: &mut String, marker: &str, lines: &[&str], newline: &str) {
+    out.push_str(marker);
+    out.push_str(newline);
+    push_lines(out, lines);
+    if !out.ends_with('\n') {
+        out.push_str(newline);
+    }
+}
+
+/// Line-based three-way merge (diff3).
+///
+/// Lines of `base` kept by both sides anchor the merge; between anchors,
+/// whichever side changed the lines wins, identical changes are taken once
+/// and differing changes become a conflict block showing all three
+/// versions. Markers end in the line ending the files use.
+pub fn merge_text(base: &str, local: &str, remote: &str) -> MergeResult {
+    let newline = line_ending(&[local, remote, base]);
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let local: Vec<&str> = local.split_inclusive('\n').collect();
+    let remote: Vec<&str> = remote.split_inclusive('\n').collect();
+    let to_local = matches(&base, &local);
+    let to_remote = matches(&base, &remote);
+
+    let mut content = String::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    while i < base.len() || j < local.len() || k < remote.len() {
+        if i < base.len() && to_local[i] == Some(j) && to_remote[i] == Some(k) {
+            content.push_str(base[i]);
+            (i, j, k) = (i + 1, j + 1, k + 1);
+            continue;
+        }
+        // The changed region runs up to the next line both sides kept.
+        let next = (i..base.len()).find_map(|n| match (to_local[n], to_remote[n]) {
+            (Some(l), Some(r)) => Some((n, l, r)),
+            _ => None,
+        });
+        let (ni, nj, nk) = next.unwrap_or((base.len(), local.len(), remote.len()));
+        let (b, l, r) = (&base[i..ni], &local[j..nj], &remote[k..nk]);
+        if l == b || l == r {
+            push_lines(&mut content, r);
+        } else if r == b {
+            push_lines(&mut content, l);
+        } else {
+            push_block(&mut content, MARKER_LOCAL, l, newline);
+            push_block(&mut content, MARKER_BASE, b, newline);
+            push_block(&mut content, MARKER_SEPARATOR, r, newline);
+            content.push_str(MARKER_REMOTE);
+            content.push_str(newline);
+            conflicts += 1;
+        }
+        (i, j, k) = (ni, nj, nk);
+    }
+    MergeResult { content, conflicts }
+}
+
+/// Documents made of nested key-value tables.
+trait Tree: Clone + PartialEq {
+    fn entries(&self) -> Option<BTreeMap<String, Self>>;
+    fn from_entries(entries: BTreeMap<String, Self>) -> Self;
+}
+
+impl Tree for serde_json::Value {
+    fn entries(&self) -> Option<BTreeMap<String, Self>> {
+        self.as_object()
+            .map(|object| object.clone().into_iter().collect())
+    }
+
+    fn from_entries(entries: BTreeMap<String, Self>) -> Self {
+        serde_json::Value::Object(entries.into_iter().collect())
+    }
+}
+
+impl Tree for toml::Value {
+    fn entries(&self) -> Option<BTreeMap<String, Self>> {
+        self.as_table()
+            .map(|table| table.clone().into_iter().collect())
+    }
+
+    fn from_entries(entries: BTreeMap<String, Self>) -> Self {
+        toml::Value::Table(entries.into_iter().collect())
+    }
+}
+
+/// Both sides changed the same value differently.
+struct Unmergeable;
+
+/// Merge one value; `None` is an absent key.
+fn merge_tree<T: Tree>(
+    base: Option<&T>,
+    local: Option<&T>,
+    remote: Option<&T>,
+) -> Result<Option<T>, Unmergeable> {
+    if local == remote || remote == base {
+        return Ok(local.cloned());
+    }
+    if local == base {
+        return Ok(remote.cloned());
+    }
+    let (Some(local), Some(remote)) = (local.and_then(T::entries), remote.and_then(T::entries))
+    else {
+        return Err(Unmergeable);
+    };
+    let base = base.and_then(T::entries).unwrap_or_default();
+    let mut merged = BTreeMap::new();
+    let keys: HashSet<&String> = local.keys().chain(remote.keys()).collect();
+    for key in keys {
+        if let Some(value) = merge_tree(base.get(key), local.get(key), remote.get(key))? {
+            merged.insert(key.clone(), value);
+        }
+    }
+    Ok(Some(T::from_entries(merged)))
+}
+
+fn parse_json(text: &str) -> Option<serde_json::Value> {
+    serde_json::from_str(text).ok()
+}
+
+fn parse_toml(text: &str) -> Option<toml::Value> {
+    text.parse::<toml::Table>().ok().map(toml::Value::Table)
+}
+
+/// Parse all three versions and merge them as trees. `None` when a version
+/// does not parse or both sides changed the same value.
+fn merge_parsed<T: Tree>(
+    base: &str,
+    local: &str,
+    remote: &str,
+    parse: fn(&str) -> Option<T>,
+) -> Option<T> {
+    let base = if base.trim().is_empty() {
+        None
+    } else {
+        Some(parse(base)?)
+    };
+    let (local, remote) = (parse(local)?, parse(remote)?);
+    merge_tree(base.as_ref(), Some(&local), Some(&remote)).ok()?
+}
+
+/// Key-by-key merge of JSON documents. The result is pretty-printed with
+/// keys sorted, so it is only worth using when the line merge fails.
+pub fn merge_json(base: &str, local: &str, remote: &str) -> Option<MergeResult> {
+    let merged = merge_parsed(base, local, remote, parse_json)?;
+    let mut content = serde_json::to_string_pretty(&merged).ok()?;
+    content.push('\n');
+    Some(MergeResult {
+        content,
+        conflicts: 0,
+    })
+}
+
+/// Key-by-key merge of TOML documents. Comments are lost, so it is only
+/// worth using when the line merge fails.
+pub fn merge_toml(base: &str, local: &str, remote: &str) -> Option<MergeResult> {
+    let merged = merge_parsed(base, local, remote, parse_toml)?;
+    Some(MergeResult {
+        content: toml::to_string(&merged).ok()?,
+        conflicts: 0,
+    })
+}
+
+/// Split a Markdown document at its ATX headings, outside code fences. Each
+/// section is keyed by its heading and how often that heading occurred
+/// before; text before the first heading has an empty heading.
+fn sections(doc: &str) -> Vec<((String, usize), String)> {
+    let mut sections: Vec<((String, usize), String)> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut fence: Option<&str> = None;
+    for line in doc.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        if indent < 4 {
+            let marker = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m));
+            match (fence, marker) {
+                (None, Some(marker)) => fence = Some(marker),
+                (Some(open), Some(marker)) if open == marker => fence = None,
+                _ => {}
+            }
+        }
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        let is_heading = fence.is_none()
+            && indent < 4
+            && (1..=6).contains(&level)
+            && trimmed[level..].starts_with([' ', '\t', '\n', '\r']);
+        if is_heading || sections.is_empty() {
+            let heading = if is_heading {
+                trimmed.trim_end().to_string()
+            } else {
+                String::new()
+            };
+            let count = seen.entry(heading.clone()).or_insert(0);
+            sections.push(((heading, *count), String::new()));
+            *count += 1;
+        }
+        if let Some((_, text)) = sections.last_mut() {
+            text.push_str(line);
+        }
+    }
+    sections
+}
+
+/// Section-by-section merge of Markdown documents: sections added on either
+/// side are kept, in local order with remote additions after the section
+/// they follow, and conflicts stay confined to the sections both sides
+/// edited. `None` when one side edited a section the other deleted.
+pub fn merge_markdown(base: &str, local: &str, remote: &str) -> Option<MergeResult> {
+    let base: HashMap<_, _> = sections(base).into_iter().collect();
+    let local = sections(local);
+    let remote = sections(remote);
+    let remote_text: HashMap<_, _> = remote.iter().cloned().collect();
+    let local_keys: HashSet<_> = local.iter().map(|(key, _)| key.clone()).collect();
+
+    let local_added: HashSet<_> = local
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| !base.contains_key(*key) && !remote_text.contains_key(*key))
+        .collect();
+
+    // Sections with whether they were inserted from the remote side.
+    let mut merged: Vec<((String, usize), MergeResult, bool)> = Vec::new();
+    for (key, text) in &local {
+        let section = match (base.get(key), remote_text.get(key)) {
+            (base, Some(remote)) => merge_text(base.map_or("", String::as_str), text, remote),
+            // Deleted remotely: fine unless edited locally.
+            (Some(base), None) if base == text => continue,
+            (Some(_), None) => return None,
+            (None, None) => MergeResult {
+                content: text.clone(),
+                conflicts: 0,
+            },
+        };
+        merged.push((key.clone(), section, false));
+    }
+    let mut previous: Option<&(String, usize)> = None;
+    for (key, text) in &remote {
+        if !local_keys.contains(key) {
+            match base.get(key) {
+                // Deleted locally: fine unless edited remotely.
+                Some(base) if base == text => {}
+                Some(_) => return None,
+                None => {
+                    // After the preceding section and whatever the local
+                    // side added behind it.
+                    let mut at = previous
+                        .and_then(|p| merged.iter().position(|(k, _, _)| k == p))
+                        .map_or(0, |i| i + 1);
+                    while at < merged.len() && local_added.contains(&merged[at].0) {
+                        at += 1;
+                    }
+                    let section = MergeResult {
+                        content: text.clone(),
+                        conflicts: 0,
+                    };
+                    merged.insert(at, (key.clone(), section, true));
+                }
+            }
+        }
+        previous = Some(key);
+    }
+
+    let mut content = String::new();
+    let mut conflicts = 0;
+    for (_, section, inserted) in merged {
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        // Keep inserted headings off the end of the previous paragraph.
+        if inserted && !content.is_empty() && !content.ends_with("\n\n") {
+            content.push('\n');
+        }
+        content.push_str(&section.content);
+        conflicts += section.conflicts;
+    }
+    Some(MergeResult { content, conflicts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combines_separate_edits() {
+        let base = "one\ntwo\nthree\nfour\nfive\n";
+        let local = "ONE\ntwo\nthree\nfour\nfive\n";
+        let remote = "one\ntwo\nthree\nfour\nFIVE\nsix\n";
+        assert_eq!(
+            merge_text(base, local, remote),
+            MergeResult {
+                content: "ONE\ntwo\nthree\nfour\nFIVE\nsix\n".to_string(),
+                conflicts: 0
+            }
+        );
+        // The same change on both sides is taken once.
+        let both = "one\ntwo\nTHREE\nfour\nfive\n";
+        assert_eq!(merge_text(base, both, both).content, both);
+        // One side deleting lines.
+        assert_eq!(
+            merge_text(base, "one\nfour\nfive\n", remote).content,
+            "one\nfour\nFIVE\nsix\n"
+        );
+    }
+
+    #[test]
+    fn test_marks_overlapping_edits() {
+        let merged = merge_text("a\nb\nc\n", "a\nlocal\nc\n", "a\nremote\nc\n");
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.content,
+            "a\n<<<<<<< local\nlocal\n||||||| base\nb\n=======\nremote\n>>>>>>> remote\nc\n"
+        );
+
+        // Different insertions at the same place.
+        let merged = merge_text("a\n", "a\nx\n", "a\ny\n");
+        assert_eq!(merged.conflicts, 1);
+        assert!(merged
+            .content
+            .starts_with("a\n<<<<<<< local\nx\n||||||| base\n=======\ny\n"));
+    }
+
+    #[test]
+    fn test_markers_keep_crlf_line_endings() {
+        let merged = merge_text(
+            "a\r\nb\r\nc\r\n",
+            "a\r\nlocal\r\nc\r\n",
+            "a\r\nremote\r\nc\r\n",
+        );
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.content,
+            "a\r\n<<<<<<< local\r\nlocal\r\n||||||| base\r\nb\r\n=======\r\n\
+             remote\r\n>>>>>>> remote\r\nc\r\n"
+        );
+
+        // A last line without an ending gets the files' own.
+        let merged = merge_text("a\r\nb", "a\r\nx", "a\r\ny");
+        assert_eq!(
+            merged.content,
+            "a\r\n<<<<<<< local\r\nx\r\n||||||| base\r\nb\r\n=======\r\ny\r\n>>>>>>> remote\r\n"
+        );
+    }
+
+    #[test]
+    fn test_refuses_binary_files() {
+        assert!(is_binary(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert!(is_binary(&[0xff, 0xfe, 0x41]));
+        assert!(!is_binary("héllo\n".as_bytes()));
+        assert!(matches!(
+            merge_files("image.png", b"", b"a\0", b"b"),
+            Err(SyncError::BinaryFile(path)) if path == "image.png"
+        ));
+    }
+
+    #[test]
+    fn test_detects_formats() {
+        assert_eq!(MergeFormat::from_path("a/package.json"), MergeFormat::Json);
+        assert_eq!(MergeFormat::from_path("Cargo.TOML"), MergeFormat::Toml);
+        assert_eq!(MergeFormat::from_path("README.md"), MergeFormat::Markdown);
+        assert_eq!(MergeFormat::from_path("v1.json/notes"), MergeFormat::Text);
+    }
+
+    #[test]
+    fn test_merges_json_by_key() {
+        let base = "{\n  \"a\": 1\n}\n";
+        let local = "{\n  \"a\": 1,\n  \"b\": 2\n}\n";
+        let remote = "{\n  \"a\": 1,\n  \"c\": {\"d\": 3}\n}\n";
+        // Both sides appended to the object, which lines cannot combine.
+        assert!(!merge_text(base, local, remote).is_clean());
+        let merged = merge_files(
+            "settings.json",
+            base.as_bytes(),
+            local.as_bytes(),
+            remote.as_bytes(),
+        )
+        .unwrap();
+        assert!(merged.is_clean());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&merged.content).unwrap(),
+            serde_json::json!({"a": 1, "b": 2, "c": {"d": 3}})
+        );
+
+        // Changing the same value differently cannot be merged by key.
+        assert!(merge_json("{\"a\": 1}", "{\"a\": 2}", "{\"a\": 3}").is_none());
+        let merged = merge_files("a.json", b"{\"a\": 1}", b"{\"a\": 2}", b"{\"a\": 3}").unwrap();
+        assert_eq!(merged.conflicts, 1);
+    }
+
+    #[test]
+    fn test_merges_toml_by_key() {
+        let base = "[server]\nport = 80\n";
+        let local = "[server]\nport = 8080\n";
+        let remote = "[server]\nport = 80\n\n[client]\nretries = 3\n";
+        let merged = merge_files(
+            "config.toml",
+            base.as_bytes(),
+            local.as_bytes(),
+            remote.as_bytes(),
+        )
+        .unwrap();
+        assert!(merged.is_clean());
+        let table: toml::Table = merged.content.parse().unwrap();
+        assert_eq!(table["server"]["port"].as_integer(), Some(8080));
+        assert_eq!(table["client"]["retries"].as_integer(), Some(3));
+
+        let local = "[server]\nport = 80\nhost = \"a\"\n";
+        let remote = "[server]\nport = 80\ntimeout = 5\n";
+        let merged = merge_files(
+            "config.toml",
+            base.as_bytes(),
+            local.as_bytes(),
+            remote.as_bytes(),
+        )
+        .unwrap();
+        let table: toml::Table = merged.content.parse().unwrap();
+        assert_eq!(table["server"]["host"].as_str(), Some("a"));
+        assert_eq!(table["server"]["timeout"].as_integer(), Some(5));
+    }
+
+    #[test]
+    fn test_merges_markdown_by_section() {
+        let base = "# Notes\n\nintro\n\n## Todo\n\n- one\n";
+        let local = "# Notes\n\nintro\n\n## Todo\n\n- one\n\n## Local\n\nmine\n";
+        let remote = "# Notes\n\nintro\n\n## Todo\n\n- one\n\n## Remote\n\ntheirs\n";
+        assert!(!merge_text(base, local, remote).is_clean());
+        let merged = merge_files(
+            "notes.md",
+            base.as_bytes(),
+            local.as_bytes(),
+            remote.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            merged.content,
+            "# Notes\n\nintro\n\n## Todo\n\n- one\n\n## Local\n\nmine\n\n## Remote\n\ntheirs\n"
+        );
+        assert!(merged.is_clean());
+
+        // Headings inside code fences do not start sections.
+        let fenced = sections("intro\n```\n# not a heading\n```\n# Real\n");
+        assert_eq!(fenced.len(), 2);
+        assert_eq!(fenced[1].0, ("# Real".to_string(), 0));
+
+        // Editing a section the other side deleted.
+        assert!(merge_markdown(
+            base,
+            "# Notes\n\nintro\n",
+            "# Notes\n\nintro\n\n## Todo\n\n- two\n"
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,879 @@
+//! Scheduling file transfers
+//!
+//! [`execute_scheduled`] runs a plan like [`execute`](crate::execute), but
+//! hands its uploads and downloads to a [`TransferScheduler`], which runs up
+//! to a configured number of them at once, smallest file first, so many
+//! small files are not held up behind a large one.
+//!
+//! Bandwidth is shared through one token bucket per direction. Transfers
+//! report the bytes they send or receive to their [`TransferHandle`], which
+//! tells them how long to wait to stay under the limit. Limits can change
+//! with the time of day through [`ScheduleRule`]s. Progress over the whole
+//! run is sent to [`ProgressObserver`]s as [`SyncProgress`] while transfers
+//! go on.
+
+use crate::engine::{BaseState, BaseStateStore, SyncAction, SyncPlan};
+use crate::{SyncError, SyncOptions};
+use chrono::{Datelike, Duration as DayDuration, Local, NaiveDateTime, NaiveTime, Weekday};
+use protocol::sync::{SyncFolder, SyncProgress};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Default number of transfers running at once.
+pub const DEFAULT_CONCURRENT_TRANSFERS: usize = 4;
+/// Shortest interval between two progress reports of a running transfer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Period the transfer speed is averaged over.
+const SPEED_WINDOW: Duration = Duration::from_secs(5);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Direction and size of a transfer action.
+pub fn transfer_of(action: &SyncAction) -> Option<(Direction, u64)> {
+    match action {
+        SyncAction::Upload { local, .. } => Some((Direction::Upload, local.size)),
+        SyncAction::Download { remote, .. } => Some((Direction::Download, remote.size)),
+        _ => None,
+    }
+}
+
+/// Token bucket holding up to one second of traffic.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second; `None` is unlimited.
+    rate: Option<u64>,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            updated: now,
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        if rate != self.rate {
+            self.tokens = self.tokens.min(rate.unwrap_or(0) as f64);
+            self.rate = rate;
+        }
+    }
+
+    /// Take `bytes`, running into debt if there are not enough tokens, and
+    /// return how long to wait before they go through.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        let Some(rate) = self.rate.map(|rate| rate as f64) else {
+            return Duration::ZERO;
+        };
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Upload and download limits in bytes per second; `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl BandwidthLimits {
+    pub fn get(&self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+/// Limits applying between two times of day. A rule whose end is before
+/// its start runs past midnight; its days are those it starts on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    /// Every day when empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Limits while the rule applies; a missing one is unlimited.
+    #[serde(default)]
+    pub upload_limit: Option<u64>,
+    #[serde(default)]
+    pub download_limit: Option<u64>,
+}
+
+impl ScheduleRule {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn applies(&self, at: NaiveDateTime) -> bool {
+        let (day, time) = (at.weekday(), at.time());
+        if self.start <= self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            (time >= self.start && self.on(day))
+                || (time < self.end && self.on((at - DayDuration::days(1)).weekday()))
+        }
+    }
+
+    fn limits(&self) -> BandwidthLimits {
+        BandwidthLimits {
+            upload: self.upload_limit,
+            download: self.download_limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerOptions {
+    pub max_concurrent_transfers: usize,
+    /// Bytes per second outside the schedule; `None` is unlimited.
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    /// The first rule applying replaces both limits.
+    pub schedule: Vec<ScheduleRule>,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_transfers: DEFAULT_CONCURRENT_TRANSFERS,
+            upload_limit: None,
+            download_limit: None,
+            schedule: Vec::new(),
+        }
+    }
+}
+
+impl SchedulerOptions {
+    /// Options limiting both directions to the engine's bandwidth limit.
+    pub fn from_sync_options(options: &SyncOptions) -> Self {
+        Self {
+            upload_limit: options.bandwidth_limit,
+            download_limit: options.bandwidth_limit,
+            ..Self::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SyncError> {
+        if self.max_concurrent_transfers == 0 {
+            return Err(SyncError::Engine(
+                "at least one transfer must be allowed at a time".to_string(),
+            ));
+        }
+        let limits = self
+            .schedule
+            .iter()
+            .flat_map(|rule| [rule.upload_limit, rule.download_limit])
+            .chain([self.upload_limit, self.download_limit]);
+        if limits.into_iter().any(|limit| limit == Some(0)) {
+            return Err(SyncError::Engine(
+                "bandwidth limits must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn limits_at(&self, at: NaiveDateTime) -> BandwidthLimits {
+        self.schedule.iter().find(|rule| rule.applies(at)).map_or(
+            BandwidthLimits {
+                upload: self.upload_limit,
+                download: self.download_limit,
+            },
+            ScheduleRule::limits,
+        )
+    }
+}
+
+/// Receives the progress of running transfers.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &SyncProgress);
+}
+
+impl<F: Fn(&SyncProgress) + Send + Sync> ProgressObserver for F {
+    fn on_progress(&self, progress: &SyncProgress) {
+        self(progress)
+    }
+}
+
+struct Active {
+    path: String,
+    size: u64,
+    transferred: u64,
+}
+
+/// Progress of the transfers of one run.
+#[derive(Default)]
+struct Tracker {
+    total_files: u64,
+    completed_files: u64,
+    total_bytes: u64,
+    transferred_bytes: u64,
+    active: HashMap<usize, Active>,
+    /// The transfer started last, reported as the current file.
+    current: Option<usize>,
+    samples: VecDeque<(Instant, u64)>,
+    reported: Option<Instant>,
+}
+
+impl Tracker {
+    fn speed(&mut self, now: Instant) -> u64 {
+        self.samples.push_back((now, self.transferred_bytes));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(at, _)| now.duration_since(*at) >= SPEED_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let (start, bytes) = self.samples[0];
+        let elapsed = now.duration_since(start).as_secs_f64();
+        if elapsed > 0.0 {
+            ((self.transferred_bytes - bytes) as f64 / elapsed) as u64
+        } else {
+            0
+        }
+    }
+
+    /// The progress to report, unless one was reported too recently.
+    fn report(&mut self, now: Instant, force: bool) -> Option<SyncProgress> {
+        let speed_bps = self.speed(now);
+        if !force
+            && self
+                .reported
+                .is_some_and(|at| now.duration_since(at) < PROGRESS_INTERVAL)
+        {
+            return None;
+        }
+        self.reported = Some(now);
+        let remaining = self.total_bytes.saturating_sub(self.transferred_bytes);
+        Some(SyncProgress {
+            total_files: self.total_files,
+            completed_files: self.completed_files,
+            total_bytes: self.total_bytes,
+            transferred_bytes: self.transferred_bytes,
+            current_file: self
+                .current
+                .and_then(|id| self.active.get(&id))
+                .map(|active| active.path.clone()),
+            speed_bps,
+            eta_seconds: match (remaining, speed_bps) {
+                (0, _) => Some(0),
+                (_, 0) => None,
+                (remaining, speed) => Some(remaining.div_ceil(speed)),
+            },
+        })
+    }
+}
+
+struct Shared {
+    options: SchedulerOptions,
+    upload: Mutex<TokenBucket>,
+    download: Mutex<TokenBucket>,
+    observers: Mutex<Vec<Arc<dyn ProgressObserver>>>,
+    tracker: Mutex<Tracker>,
+    /// Local time of day, for the schedule.
+    clock: fn() -> NaiveDateTime,
+}
+
+impl Shared {
+    fn notify(&self, progress: Option<SyncProgress>) {
+        let Some(progress) = progress else {
+            return;
+        };
+        let observers = lock(&self.observers).clone();
+        for observer in observers {
+            observer.on_progress(&progress);
+        }
+    }
+}
+
+/// One running transfer's access to the bandwidth limits and progress.
+#[derive(Clone)]
+pub struct TransferHandle {
+    shared: Arc<Shared>,
+    direction: Direction,
+    id: usize,
+}
+
+impl TransferHandle {
+    /// A handle with no limit whose progress goes nowhere, for actions run
+    /// outside a scheduler.
+    pub fn unlimited(direction: Direction) -> Self {
+        TransferScheduler::new(SchedulerOptions::default()).handle(direction, usize::MAX)
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Account for `bytes` about to be sent or just received, returning how
+    /// long to wait to stay under the current limit.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let limit = self
+            .shared
+            .options
+            .limits_at((self.shared.clock)())
+            .get(self.direction);
+        let bucket = match self.direction {
+            Direction::Upload => &self.shared.upload,
+            Direction::Download => &self.shared.download,
+        };
+        let wait = {
+            let mut bucket = lock(bucket);
+            bucket.set_rate(limit);
+            bucket.take(bytes, now)
+        };
+        let progress = {
+            let mut tracker = lock(&self.shared.tracker);
+            match tracker.active.get_mut(&self.id) {
+                Some(active) => {
+                    // Deltas and resumed downloads move fewer bytes than the
+                    // file size; never count more than it.
+                    let counted = bytes.min(active.size.saturating_sub(active.transferred));
+                    active.transferred += counted;
+                    tracker.transferred_bytes += counted;
+                    tracker.report(now, false)
+                }
+                None => None,
+            }
+        };
+        self.shared.notify(progress);
+        wait
+    }
+
+    /// [`reserve`](Self::reserve) and wait, for blocking transfers.
+    pub fn throttle(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Runs transfers concurrently under shared bandwidth limits.
+pub struct TransferScheduler {
+    shared: Arc<Shared>,
+}
+
+impl TransferScheduler {
+    pub fn new(options: SchedulerOptions) -> Self {
+        Self::with_clock(options, || Local::now().naive_local())
+    }
+
+    /// A scheduler reading the time of day from `clock`.
+    pub fn with_clock(options: SchedulerOptions, clock: fn() -> NaiveDateTime) -> Self {
+        let now = Instant::now();
+        Self {
+            shared: Arc::new(Shared {
+                upload: Mutex::new(TokenBucket::new(options.upload_limit, now)),
+                download: Mutex::new(TokenBucket::new(options.download_limit, now)),
+                options,
+                observers: Mutex::new(Vec::new()),
+                tracker: Mutex::new(Tracker::default()),
+                clock,
+            }),
+        }
+    }
+
+    pub fn options(&self) -> &SchedulerOptions {
+        &self.shared.options
+    }
+
+    pub fn add_observer(&self, observer: impl ProgressObserver + 'static) {
+        lock(&self.shared.observers).push(Arc::new(observer));
+    }
+
+    fn handle(&self, direction: Direction, id: usize) -> TransferHandle {
+        TransferHandle {
+            shared: self.shared.clone(),
+            direction,
+            id,
+        }
+    }
+
+    fn started(&self, id: usize, action: &SyncAction, size: u64) {
+        let progress = {
+            let mut tracker = lock(&self.shared.tracker);
+            tracker.active.insert(
+                id,
+                Active {
+                    path: action.path().to_string(),
+                    size,
+                    transferred: 0,
+                },
+            );
+            tracker.current = Some(id);
+            tracker.report(Instant::now(), true)
+        };
+        self.shared.notify(progress);
+    }
+
+    fn finished(&self, id: usize, succeeded: bool) {
+        let progress = {
+            let mut tracker = lock(&self.shared.tracker);
+            if let Some(active) = tracker.active.remove(&id) {
+                if succeeded {
+                    tracker.completed_files += 1;
+                    tracker.transferred_bytes += active.size - active.transferred;
+                } else {
+                    tracker.total_bytes -= active.size - active.transferred;
+                }
+            }
+            if tracker.current == Some(id) {
+                tracker.current = tracker.active.keys().max().copied();
+            }
+            tracker.report(Instant::now(), true)
+        };
+        self.shared.notify(progress);
+    }
+
+    /// Run the transfer actions among `actions` with `work`, smallest file
+    /// first and up to the configured number at once, calling `done` after
+    /// each one that succeeds. Once one fails, no other starts; the first
+    /// failure is returned after the running ones end.
+    pub fn run<'a, W, D>(
+        &self,
+        actions: impl IntoIterator<Item = &'a SyncAction>,
+        work: W,
+        done: D,
+    ) -> Result<(), (&'a SyncAction, SyncError)>
+    where
+        W: Fn(&SyncAction, &TransferHandle) -> Result<(), SyncError> + Sync,
+        D: Fn(&SyncAction) -> Result<(), SyncError> + Sync,
+    {
+        let mut queue: Vec<(&SyncAction, Direction, u64)> = actions
+            .into_iter()
+            .filter_map(|action| transfer_of(action).map(|(dir, size)| (action, dir, size)))
+            .collect();
+        queue.sort_by(|a, b| a.2.cmp(&b.2).then_with(|| a.0.path().cmp(b.0.path())));
+        *lock(&self.shared.tracker) = Tracker {
+            total_files: queue.len() as u64,
+            total_bytes: queue.iter().map(|(_, _, size)| size).sum(),
+            ..Tracker::default()
+        };
+        let workers = self
+            .options()
+            .max_concurrent_transfers
+            .clamp(1, queue.len().max(1));
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let failure = Mutex::new(None);
+
+        std::thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while !stop.load(Ordering::SeqCst) {
+                        let id = next.fetch_add(1, Ordering::SeqCst);
+                        let Some(&(action, direction, size)) = queue.get(id) else {
+                            break;
+                        };
+                        self.started(id, action, size);
+                        let result =
+                            work(action, &self.handle(direction, id)).and_then(|()| done(action));
+                        self.finished(id, result.is_ok());
+                        if let Err(e) = result {
+                            stop.store(true, Ordering::SeqCst);
+                            lock(&failure).get_or_insert((action, e));
+                        }
+                    }
+                });
+            }
+        });
+        match failure.into_inner().unwrap_or_else(|p| p.into_inner()) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Performs sync actions, several transfers possibly at once.
+pub trait TransferExecutor: Sync {
+    /// Perform one action, as [`ActionExecutor::execute`] does. Transfers
+    /// report the bytes they move to `transfer` and wait as it tells them.
+    ///
+    /// [`ActionExecutor::execute`]: crate::ActionExecutor::execute
+    fn execute(&self, action: &SyncAction, transfer: &TransferHandle) -> Result<(), SyncError>;
+}
+
+/// Run `plan` as [`execute`](crate::execute) does, with its transfers
+/// handed to `scheduler`. Each completed action is recorded in `base` and
+/// saved as it completes.
+pub fn execute_scheduled(
+    plan: &SyncPlan,
+    folder: &SyncFolder,
+    base: &mut BaseState,
+    store: &(dyn BaseStateStore + Sync),
+    executor: &dyn TransferExecutor,
+    scheduler: &TransferScheduler,
+) -> Result<usize, SyncError> {
+    let completed = AtomicUsize::new(0);
+    let base = Mutex::new(base);
+    let failed = |action: &SyncAction, e: SyncError| -> SyncError {
+        if let Err(save) = store.save(folder, &lock(&base)) {
+            return save;
+        }
+        SyncError::Engine(format!(
+            "{} after {} of {} actions: {}",
+            action.path(),
+            completed.load(Ordering::SeqCst),
+            plan.len(),
+            e
+        ))
+    };
+    let record = |action: &SyncAction| -> Result<(), SyncError> {
+        let mut base = lock(&base);
+        base.apply(action);
+        store.save(folder, &base)?;
+        completed.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    };
+
+    // Plans are ordered by phase: everything before the transfers, the
+    // transfers, then everything after.
+    let first = plan.actions.iter().position(|a| transfer_of(a).is_some());
+    let end = plan.actions.iter().rposition(|a| transfer_of(a).is_some());
+    let (before, transfers, after) = match (first, end) {
+        (Some(first), Some(end)) => (
+            &plan.actions[..first],
+            &plan.actions[first..=end],
+            &plan.actions[end + 1..],
+        ),
+        _ => (&plan.actions[..], &[][..], &[][..]),
+    };
+    let sequential = |actions: &[SyncAction]| -> Result<(), SyncError> {
+        let handle = TransferHandle::unlimited(Direction::Upload);
+        for action in actions {
+            executor
+                .execute(action, &handle)
+                .map_err(|e| failed(action, e))?;
+            record(action)?;
+        }
+        Ok(())
+    };
+
+    sequential(before)?;
+    scheduler
+        .run(
+            transfers,
+            |action, handle| executor.execute(action, handle),
+            record,
+        )
+        .map_err(|(action, e)| failed(action, e))?;
+    sequential(after)?;
+    Ok(completed.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyncState;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use protocol::sync::{SyncDirection, SyncFilters};
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday.
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn upload(path: &str, size: u64) -> SyncAction {
+        SyncAction::Upload {
+            path: path.to_string(),
+            local: SyncState {
+                path: path.to_string(),
+                hash: format!("hash-{}", path),
+                modified: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+                size,
+            },
+        }
+    }
+
+    #[test]
+    fn test_buckets_pace_traffic() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(Some(1000), start);
+        assert_eq!(bucket.take(600, start), Duration::ZERO);
+        assert_eq!(bucket.take(900, start), Duration::from_millis(500));
+        // Refilled at the rate, and never above one second's worth.
+        assert_eq!(
+            bucket.take(500, start + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+        assert_eq!(
+            bucket.take(1500, start + Duration::from_secs(10)),
+            Duration::from_millis(500)
+        );
+        bucket.set_rate(None);
+        assert_eq!(bucket.take(1 << 30, start), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_schedules_pick_the_first_rule_applying() {
+        let options = SchedulerOptions {
+            upload_limit: Some(100),
+            download_limit: None,
+            schedule: vec![
+                ScheduleRule {
+                    days: vec![Weekday::Sat, Weekday::Sun],
+                    start: time(0, 0),
+                    end: time(23, 59),
+                    upload_limit: None,
+                    download_limit: None,
+                },
+                ScheduleRule {
+                    days: Vec::new(),
+                    start: time(9, 0),
+                    end: time(18, 0),
+                    upload_limit: Some(10),
+                    download_limit: Some(20),
+                },
+            ],
+            ..SchedulerOptions::default()
+        };
+        let limits = options.limits_at(at(1, 12, 0));
+        assert_eq!((limits.upload, limits.download), (Some(10), Some(20)));
+        assert_eq!(options.limits_at(at(1, 18, 0)).upload, Some(100));
+        assert_eq!(options.limits_at(at(6, 12, 0)).upload, None);
+    }
+
+    #[test]
+    fn test_rules_run_past_midnight_from_their_days() {
+        let rule = ScheduleRule {
+            days: vec![Weekday::Fri],
+            start: time(22, 0),
+            end: time(6, 0),
+            upload_limit: Some(1),
+            download_limit: None,
+        };
+        assert!(rule.applies(at(5, 23, 0)));
+        assert!(rule.applies(at(6, 5, 59)));
+        assert!(!rule.applies(at(6, 6, 0)));
+        assert!(!rule.applies(at(5, 5, 0)));
+        assert!(!rule.applies(at(6, 23, 0)));
+    }
+
+    #[test]
+    fn test_rules_read_times_of_day() {
+        let rule: ScheduleRule = serde_json::from_str(
+            r#"{"days": ["Mon", "tuesday"], "start": "09:00", "end": "17:30",
+                "upload_limit": 1048576}"#,
+        )
+        .unwrap();
+        assert_eq!(rule.days, vec![Weekday::Mon, Weekday::Tue]);
+        assert_eq!(rule.end, time(17, 30));
+        assert_eq!(rule.download_limit, None);
+    }
+
+    #[test]
+    fn test_options_are_validated() {
+        assert!(SchedulerOptions::default().validate().is_ok());
+        let zero = SchedulerOptions {
+            download_limit: Some(0),
+            ..SchedulerOptions::default()
+        };
+        assert!(zero.validate().is_err());
+        let none = SchedulerOptions {
+            max_concurrent_transfers: 0,
+            ..SchedulerOptions::default()
+        };
+        assert!(none.validate().is_err());
+    }
+
+    #[test]
+    fn test_small_files_go_first() {
+        let scheduler = TransferScheduler::new(SchedulerOptions {
+            max_concurrent_transfers: 1,
+            ..SchedulerOptions::default()
+        });
+        let actions = [upload("big", 300), upload("small", 1), upload("medium", 20)];
+        let order = Mutex::new(Vec::new());
+        scheduler
+            .run(
+                &actions,
+                |action, _| {
+                    lock(&order).push(action.path().to_string());
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        assert_eq!(order.into_inner().unwrap(), ["small", "medium", "big"]);
+    }
+
+    #[test]
+    fn test_concurrency_is_capped() {
+        let scheduler = TransferScheduler::new(SchedulerOptions {
+            max_concurrent_transfers: 3,
+            ..SchedulerOptions::default()
+        });
+        let actions: Vec<SyncAction> = (0..12).map(|i| upload(&format!("f{}", i), 1)).collect();
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        scheduler
+            .run(
+                &actions,
+                |_, _| {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        assert!(peak.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_observers_follow_progress() {
+        let scheduler = TransferScheduler::new(SchedulerOptions::default());
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        scheduler.add_observer(move |progress: &SyncProgress| lock(&sink).push(progress.clone()));
+        let actions = [upload("a", 100), upload("b", 50)];
+        scheduler
+            .run(
+                &actions,
+                |_, handle| {
+                    // A delta sends less than the file size.
+                    handle.throttle(10);
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        let reports = lock(&reports);
+        let last = reports.last().unwrap();
+        assert_eq!((last.completed_files, last.total_files), (2, 2));
+        assert_eq!((last.transferred_bytes, last.total_bytes), (150, 150));
+        assert_eq!(last.eta_seconds, Some(0));
+        assert!(reports
+            .iter()
+            .any(|p| p.current_file.as_deref() == Some("a")));
+    }
+
+    #[test]
+    fn test_failures_stop_new_transfers() {
+        let scheduler = TransferScheduler::new(SchedulerOptions {
+            max_concurrent_transfers: 1,
+            ..SchedulerOptions::default()
+        });
+        let actions = [upload("a", 1), upload("b", 2), upload("c", 3)];
+        let done = Mutex::new(Vec::new());
+        let (failed, error) = scheduler
+            .run(
+                &actions,
+                |action, _| match action.path() {
+                    "b" => Err(SyncError::Network("reset".to_string())),
+                    _ => Ok(()),
+                },
+                |action| {
+                    lock(&done).push(action.path().to_string());
+                    Ok(())
+                },
+            )
+            .unwrap_err();
+        assert_eq!(failed.path(), "b");
+        assert!(matches!(error, SyncError::Network(_)));
+        assert_eq!(done.into_inner().unwrap(), ["a"]);
+    }
+
+    struct Store(Mutex<Option<BaseState>>);
+
+    impl BaseStateStore for Store {
+        fn load(&self, _: &SyncFolder) -> Result<BaseState, SyncError> {
+            Ok(lock(&self.0).clone().unwrap_or_default())
+        }
+
+        fn save(&self, _: &SyncFolder, state: &BaseState) -> Result<(), SyncError> {
+            *lock(&self.0) = Some(state.clone());
+            Ok(())
+        }
+    }
+
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl TransferExecutor for Recorder {
+        fn execute(&self, action: &SyncAction, _: &TransferHandle) -> Result<(), SyncError> {
+            lock(&self.0).push(action.path().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_scheduled_plans_record_every_action() {
+        let folder = SyncFolder {
+            local_path: "/home/me/Documents".to_string(),
+            remote_path: "/Documents".to_string(),
+            sync_direction: SyncDirection::Bidirectional,
+            is_active: true,
+            filters: SyncFilters::default(),
+        };
+        let plan = SyncPlan {
+            actions: vec![
+                SyncAction::DeleteLocal {
+                    path: "old".to_string(),
+                    hash: "h".to_string(),
+                },
+                upload("b", 20),
+                upload("a", 10),
+                SyncAction::Record {
+                    path: "same".to_string(),
+                    state: None,
+                },
+            ],
+        };
+        let store = Store(Mutex::new(None));
+        let executor = Recorder(Mutex::new(Vec::new()));
+        let scheduler = TransferScheduler::new(SchedulerOptions {
+            max_concurrent_transfers: 1,
+            ..SchedulerOptions::default()
+        });
+        let mut base = BaseState::default();
+        let count =
+            execute_scheduled(&plan, &folder, &mut base, &store, &executor, &scheduler).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(executor.0.into_inner().unwrap(), ["old", "a", "b", "same"]);
+        assert_eq!(base.entries.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(store.0.into_inner().unwrap(), Some(base));
+    }
+}